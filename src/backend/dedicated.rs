@@ -0,0 +1,194 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::{
+    processor::Processor, reconnect::ReconnectConfiguration, responses::ResponseSizeTracker, ConnectionSettings,
+};
+use common::{EnqueuedRequest, Message, MessageResponse, PendingResponse};
+use futures::{future::Either, prelude::*};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use util::{FdGuard, FdTracker, ProcessFuture};
+
+// What requests are told when their backend already has as many dedicated connections as it can.
+const DEDICATED_CONNS_EXHAUSTED: &str = "too many blocking commands in flight";
+
+/// Runs requests on connections of their own, rather than on the connections a backend shares
+/// between every client.
+///
+/// This is for requests that can hold on to a connection for as long as they like, such as
+/// blocking commands, which would otherwise hold up everything queued behind them.  Every request
+/// gets a fresh connection, which is closed as soon as it's been answered, or as soon as nobody is
+/// waiting on the answer anymore.
+///
+/// Every one of those connections is a connection the backend has to hold open, so only so many
+/// can be open at once.  Requests past that are answered with an error straight away.
+pub struct DedicatedConnector<P>
+where
+    P: Processor + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+{
+    processor: P,
+    address: SocketAddr,
+    source: Option<IpAddr>,
+    noreply: bool,
+    reconnect: ReconnectConfiguration,
+    fds: Option<Arc<FdTracker>>,
+    responses: Arc<ResponseSizeTracker>,
+    open: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl<P> DedicatedConnector<P>
+where
+    P: Processor + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+{
+    pub fn new(
+        processor: P, address: SocketAddr, settings: &ConnectionSettings, limit: usize,
+    ) -> DedicatedConnector<P> {
+        DedicatedConnector {
+            processor,
+            address,
+            source: settings.source,
+            noreply: settings.noreply,
+            reconnect: settings.reconnect.clone(),
+            fds: settings.fds.clone(),
+            responses: settings.responses.clone(),
+            open: Arc::new(AtomicUsize::new(0)),
+            limit,
+        }
+    }
+
+    /// Sets the address that connections are made to from now on.
+    pub fn set_address(&mut self, address: SocketAddr) { self.address = address; }
+
+    /// Gets the work of running the given request on a connection of its own.
+    ///
+    /// The request's response channel must already have been taken, since the response is sent
+    /// back over it by the returned future.  If there's no room for another connection, the request
+    /// is answered with an error instead, and there's nothing to run.
+    pub fn run(&self, mut request: EnqueuedRequest<P::Message>) -> Option<DedicatedRequest<P::Message>> {
+        // Taking a slot and then checking whether we're over the limit means there's nothing to
+        // race against: if we are, dropping the slot gives it straight back.
+        let slot = DedicatedSlot::acquire(&self.open);
+        if slot.taken >= self.limit {
+            request.fulfill(self.processor.get_error_message_str(DEDICATED_CONNS_EXHAUSTED));
+            return None;
+        }
+
+        // The request we actually send is a stand-in, so that we hold on to the original, and can
+        // tell when whoever is waiting on it gives up.
+        let mut relayed = EnqueuedRequest::new(request.id(), request.request().clone());
+        let relay = relayed.get_response_rx().expect("relayed request should have a response");
+
+        let fd = self.fds.as_ref().map(FdTracker::acquire);
+        let connect = self.processor.preconnect(&self.address, self.source, self.noreply);
+        let connect = self.reconnect.limit_connect(connect);
+        let work = self.processor.process(vec![relayed], Either::B(connect), self.responses.clone());
+
+        Some(DedicatedRequest {
+            request,
+            relay,
+            work,
+            _fd: fd,
+            _slot: slot,
+        })
+    }
+}
+
+/// A dedicated connection counted against its backend's limit, which is given back when dropped.
+struct DedicatedSlot {
+    open: Arc<AtomicUsize>,
+    // How many slots were already taken when this one was.
+    taken: usize,
+}
+
+impl DedicatedSlot {
+    fn acquire(open: &Arc<AtomicUsize>) -> DedicatedSlot {
+        let taken = open.fetch_add(1, Ordering::SeqCst);
+        DedicatedSlot {
+            open: open.clone(),
+            taken,
+        }
+    }
+}
+
+impl Drop for DedicatedSlot {
+    fn drop(&mut self) { self.open.fetch_sub(1, Ordering::SeqCst); }
+}
+
+/// A request running on a connection of its own.
+///
+/// The connection, and the file descriptor and slot it holds, are given up as soon as this future
+/// completes.
+pub struct DedicatedRequest<T>
+where
+    T: Message + Clone + Send + 'static,
+{
+    request: EnqueuedRequest<T>,
+    relay: PendingResponse<T>,
+    work: ProcessFuture,
+    _fd: Option<FdGuard>,
+    _slot: DedicatedSlot,
+}
+
+impl<T> Future for DedicatedRequest<T>
+where
+    T: Message + Clone + Send + 'static,
+{
+    type Error = ();
+    type Item = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Once the work is done, one way or another, the relayed request has either been answered
+        // or dropped, so there's nothing left to wait on.
+        let finished = match self.work.poll() {
+            Ok(Async::Ready(_conn)) => true,
+            Ok(Async::NotReady) => false,
+            Err(e) => {
+                debug!("[backend] dedicated connection failed: {}", e);
+                true
+            },
+        };
+
+        match self.relay.poll() {
+            Ok(Async::Ready((_, MessageResponse::Complete(response)))) => {
+                self.request.fulfill(response);
+                return Ok(Async::Ready(()));
+            },
+            Ok(Async::NotReady) if !finished => {},
+            // Dropping the original request without a response tells whoever's waiting on it that
+            // it failed.
+            _ => return Ok(Async::Ready(())),
+        }
+
+        if self.request.poll_abandoned() {
+            debug!("[backend] client went away, closing dedicated connection");
+            return Ok(Async::Ready(()));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::{
+    latency::{LatencyCounts, LatencyHistogram},
+    weights::{BackendWeights, MAX_DEMOTIONS},
+};
+use conf::PoolOptions;
+use errors::CreationError;
+use events::{self, EventKind};
+use futures::prelude::*;
+use metrics::MetricSink;
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+use util::clock::{duration_as_ms, saturating_duration_since};
+
+// How often backend latencies are checked.
+const DEMOTION_TICK_MS: u64 = 1000;
+
+// How many checks' worth of latencies a backend's p99 is figured from.
+const LATENCY_WINDOW_TICKS: usize = 5;
+
+// The fewest requests a backend's p99 is figured from: with fewer, we leave the backend be.
+const MIN_SAMPLES: usize = 10;
+
+/// Latency-based demotion settings for a pool, parsed from its options.
+#[derive(Clone, Debug)]
+pub struct DemotionConfiguration {
+    /// The p99 latency over which a backend is considered slow.
+    pub latency: Duration,
+
+    /// How long a backend has to be slow before it's demoted, or fast again before it's restored.
+    pub sustain: Duration,
+}
+
+impl DemotionConfiguration {
+    /// Extracts the demotion configuration from the given pool options, if demotion is enabled.
+    pub fn from_options(options: &PoolOptions) -> Result<Option<DemotionConfiguration>, CreationError> {
+        let latency_ms = match options.demote_latency_ms {
+            Some(0) => return Err(CreationError::InvalidParameter("options.demote_latency_ms".to_string())),
+            Some(ms) => ms,
+            None => return Ok(None),
+        };
+
+        if options.demote_sustain_secs == 0 {
+            return Err(CreationError::InvalidParameter("options.demote_sustain_secs".to_string()));
+        }
+
+        Ok(Some(DemotionConfiguration {
+            latency: Duration::from_millis(latency_ms),
+            sustain: Duration::from_secs(options.demote_sustain_secs),
+        }))
+    }
+}
+
+struct BackendLatency {
+    identifier: String,
+    histogram: Arc<LatencyHistogram>,
+    window: VecDeque<LatencyCounts>,
+
+    // When the backend was first seen to be slow, or fast again after being demoted, in a row.
+    slow_since: Option<Instant>,
+    fast_since: Option<Instant>,
+}
+
+/// Demotes the backends of a pool that are slow to answer.
+///
+/// Every so often, the p99 latency of each backend is figured from its recent requests.  When a
+/// backend's p99 stays over the configured latency for long enough, its weight is halved, and
+/// halved again for as long as it stays slow, down to an eighth of its weight.  Once it's been
+/// fast for long enough, its weight is doubled back up, one step at a time.  Weights are changed
+/// through the pool's weights, so the pool reseeds its distributor just like it would for any
+/// other weight change.  The demoter stops when `close` resolves.
+pub struct Demoter<C> {
+    pool_name: String,
+    config: DemotionConfiguration,
+    weights: Arc<BackendWeights>,
+    backends: Vec<BackendLatency>,
+    interval: Interval,
+    close: C,
+    sink: MetricSink,
+}
+
+impl<C> Demoter<C>
+where
+    C: Future,
+{
+    /// Creates a new `Demoter`.
+    ///
+    /// `latencies` holds the identifier and latency histogram of every backend in the pool, in
+    /// their configured order.
+    pub fn new(
+        pool_name: String, config: DemotionConfiguration, weights: Arc<BackendWeights>,
+        latencies: Vec<(String, Arc<LatencyHistogram>)>, close: C, sink: MetricSink,
+    ) -> Demoter<C> {
+        let backends = latencies
+            .into_iter()
+            .map(|(identifier, histogram)| {
+                BackendLatency {
+                    identifier,
+                    histogram,
+                    window: VecDeque::new(),
+                    slow_since: None,
+                    fast_since: None,
+                }
+            })
+            .collect();
+        let tick = Duration::from_millis(DEMOTION_TICK_MS);
+
+        Demoter {
+            pool_name,
+            config,
+            weights,
+            backends,
+            interval: Interval::new(Instant::now() + tick, tick),
+            close,
+            sink,
+        }
+    }
+
+    /// Checks the latency of every backend, demoting or restoring them as needed.
+    fn check(&mut self, now: Instant) {
+        for (idx, backend) in self.backends.iter_mut().enumerate() {
+            backend.window.push_back(backend.histogram.drain());
+            while backend.window.len() > LATENCY_WINDOW_TICKS {
+                backend.window.pop_front();
+            }
+
+            let mut counts = LatencyCounts::empty();
+            for tick in &backend.window {
+                counts.merge(tick);
+            }
+
+            // Without enough requests to go on, we can't say whether the backend is slow or not.
+            let p99 = match counts.percentile(99.0) {
+                Some(p99) if counts.total() >= MIN_SAMPLES => p99,
+                _ => {
+                    backend.slow_since = None;
+                    backend.fast_since = None;
+                    continue;
+                },
+            };
+
+            let demotions = self.weights.get_demotions(idx);
+            if p99 > self.config.latency {
+                backend.fast_since = None;
+                let slow_since = *backend.slow_since.get_or_insert(now);
+                if demotions < MAX_DEMOTIONS && saturating_duration_since(now, slow_since) >= self.config.sustain {
+                    warn!(
+                        "[demotion] pool '{}': demoting backend '{}', p99 of {}ms is over {}ms",
+                        self.pool_name,
+                        backend.identifier,
+                        duration_as_ms(p99),
+                        duration_as_ms(self.config.latency)
+                    );
+                    self.weights.set_demotions(idx, demotions + 1);
+                    self.sink.increment("demotions");
+                    events::publish(
+                        EventKind::BackendDemoted,
+                        Some(&self.pool_name),
+                        Some(&backend.identifier),
+                        format!("p99 of {}ms, demoted {} time(s)", duration_as_ms(p99), demotions + 1),
+                    );
+
+                    // Staying slow for just as long again gets it demoted again.
+                    backend.slow_since = Some(now);
+                }
+            } else {
+                backend.slow_since = None;
+                if demotions == 0 {
+                    backend.fast_since = None;
+                    continue;
+                }
+
+                let fast_since = *backend.fast_since.get_or_insert(now);
+                if saturating_duration_since(now, fast_since) >= self.config.sustain {
+                    info!(
+                        "[demotion] pool '{}': restoring backend '{}', p99 of {}ms is back under {}ms",
+                        self.pool_name,
+                        backend.identifier,
+                        duration_as_ms(p99),
+                        duration_as_ms(self.config.latency)
+                    );
+                    self.weights.set_demotions(idx, demotions - 1);
+                    self.sink.increment("restorations");
+                    events::publish(
+                        EventKind::BackendPromoted,
+                        Some(&self.pool_name),
+                        Some(&backend.identifier),
+                        format!("p99 of {}ms, demoted {} time(s)", duration_as_ms(p99), demotions - 1),
+                    );
+                    backend.fast_since = Some(now);
+                }
+            }
+        }
+    }
+}
+
+impl<C> Future for Demoter<C>
+where
+    C: Future,
+{
+    type Error = ();
+    type Item = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // If we've been told to close, the pool is going away, and so are its weights.
+        match self.close.poll() {
+            Ok(Async::NotReady) => {},
+            _ => return Ok(Async::Ready(())),
+        }
+
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => self.check(Instant::now()),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Err(e) => {
+                    error!("[demotion] pool '{}': timer failed: {}", self.pool_name, e);
+                    return Err(());
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{
+        distributor::{BackendDescriptor, Distributor, ModuloDistributor},
+        weights::DEFAULT_WEIGHT,
+    };
+    use futures::future::{empty, Empty};
+    use metrics::get_sink;
+
+    fn get_demoter(latency_ms: u64, sustain_secs: u64) -> (Demoter<Empty<(), ()>>, Vec<Arc<LatencyHistogram>>) {
+        let a = "127.0.0.1:6379".parse().unwrap();
+        let b = "127.0.0.1:6380".parse().unwrap();
+        let weights = Arc::new(BackendWeights::new(vec![(a, DEFAULT_WEIGHT), (b, DEFAULT_WEIGHT)]));
+        let histograms = vec![Arc::new(LatencyHistogram::new()), Arc::new(LatencyHistogram::new())];
+        let latencies = histograms
+            .iter()
+            .enumerate()
+            .map(|(idx, histogram)| (format!("backend-{}", idx), histogram.clone()))
+            .collect();
+        let config = DemotionConfiguration {
+            latency: Duration::from_millis(latency_ms),
+            sustain: Duration::from_secs(sustain_secs),
+        };
+
+        let demoter = Demoter::new("default".to_owned(), config, weights, latencies, empty(), get_sink());
+        (demoter, histograms)
+    }
+
+    // Gets the share of keys, out of 1800, that the pool would send to the second backend.  Every
+    // weighting we go through splits 1800 keys evenly, so the shares come out exact.
+    fn get_share(weights: &BackendWeights) -> usize {
+        let descriptors = (0..2)
+            .map(|idx| {
+                BackendDescriptor {
+                    idx,
+                    identifier: format!("backend-{}", idx),
+                    healthy: true,
+                    weight: weights.get_effective(idx),
+                }
+            })
+            .collect();
+        let mut distributor = ModuloDistributor::new();
+        distributor.update(descriptors);
+
+        (0..1800).filter(|point| distributor.choose(*point) == 1).count()
+    }
+
+    #[test]
+    fn test_from_options() {
+        let mut options = PoolOptions::default();
+        assert!(DemotionConfiguration::from_options(&options).unwrap().is_none());
+
+        options.demote_latency_ms = Some(250);
+        let config = DemotionConfiguration::from_options(&options).unwrap().unwrap();
+        assert_eq!(config.latency, Duration::from_millis(250));
+        assert_eq!(config.sustain, Duration::from_secs(30));
+
+        options.demote_sustain_secs = 0;
+        assert!(DemotionConfiguration::from_options(&options).is_err());
+    }
+
+    #[test]
+    fn test_share_follows_latency() {
+        let (mut demoter, histograms) = get_demoter(100, 3);
+        let start = Instant::now();
+
+        // Our second backend starts out fast, slows way down, and then recovers.  Each step of the
+        // script is one check, and how long every request to each backend took during it.
+        let mut script = Vec::new();
+        script.extend((0..5).map(|_| (5, 5)));
+        script.extend((0..20).map(|_| (5, 300)));
+        script.extend((0..40).map(|_| (5, 5)));
+
+        let mut shares = Vec::new();
+        for (tick, (fast_ms, slow_ms)) in script.into_iter().enumerate() {
+            for _ in 0..100 {
+                histograms[0].record(Duration::from_millis(fast_ms));
+                histograms[1].record(Duration::from_millis(slow_ms));
+            }
+
+            demoter.check(start + Duration::from_secs(tick as u64));
+            shares.push(get_share(&demoter.weights));
+        }
+
+        // While it's fast, it gets half the traffic.
+        assert_eq!(shares[4], 900);
+
+        // Once it's been slow for long enough, it's demoted, and keeps getting demoted for as long
+        // as it stays slow, until it bottoms out at an eighth of its weight.
+        assert_eq!(shares[7], 900);
+        assert_eq!(shares[8], 600);
+        assert_eq!(shares[11], 360);
+        assert_eq!(shares[14], 200);
+        assert_eq!(shares[24], 200);
+        assert_eq!(demoter.weights.get_demotions(1), MAX_DEMOTIONS);
+        assert_eq!(demoter.weights.get_demotions(0), 0);
+
+        // Once it's fast again, and the slow requests have aged out, it gets its share back a step
+        // at a time.
+        assert!(shares[25..32].iter().all(|share| *share == 200));
+        assert!(shares[33..].windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(shares[32], 360);
+        assert_eq!(shares[35], 600);
+        assert_eq!(shares[shares.len() - 1], 900);
+        assert_eq!(demoter.weights.get_demotions(1), 0);
+    }
+
+    #[test]
+    fn test_idle_backends_left_alone() {
+        let (mut demoter, histograms) = get_demoter(100, 1);
+        let start = Instant::now();
+
+        // A handful of slow requests isn't enough to go on.
+        for tick in 0..10 {
+            histograms[1].record(Duration::from_millis(500));
+            demoter.check(start + Duration::from_secs(tick));
+        }
+
+        assert_eq!(demoter.weights.get_demotions(1), 0);
+    }
+}
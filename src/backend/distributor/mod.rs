@@ -20,32 +20,87 @@
 mod modulo;
 mod random;
 pub use self::{modulo::ModuloDistributor, random::RandomDistributor};
+use conf::PoolOptions;
 use errors::CreationError;
 
 /// A placeholder for backends.  This lets us avoid holding references to the actual backends.
+///
+/// `idx` is the position of the backend as it was configured in the pool, and never changes for
+/// the lifetime of the backend.  Distributors hand back this position, rather than an offset into
+/// whatever list they were last seeded with, so that a reseed with the same membership always
+/// maps a given point to the same physical backend.
+///
+/// `weight` is the backend's share of requests relative to the other backends.
+#[derive(Clone, Debug)]
 pub struct BackendDescriptor {
     pub idx: usize,
     pub identifier: String,
     pub healthy: bool,
+    pub weight: usize,
 }
 
 /// Distributes items amongst a set of backends.
 pub trait Distributor {
+    /// Seeds the distributor with the given backends.
+    ///
+    /// Implementations must not depend on the order the descriptors are given in: they should be
+    /// sorted by their configured position, via `sort_descriptors`, before being used.
     fn update(&mut self, backends: Vec<BackendDescriptor>);
 
-    /// Chooses a backend based on the given point.
+    /// Chooses a backend based on the given point, returning its configured position.
     fn choose(&self, point: u64) -> usize;
 }
 
-pub fn configure_distributor(dist_type: &str) -> Result<Box<Distributor + Send + Sync>, CreationError> {
-    match dist_type {
-        "random" => Ok(Box::new(RandomDistributor::new())),
-        "modulo" => Ok(Box::new(ModuloDistributor::new())),
-        s => {
+/// Sorts the given descriptors by their configured position.
+fn sort_descriptors(backends: &mut Vec<BackendDescriptor>) { backends.sort_by_key(|backend| backend.idx); }
+
+/// Expands the given descriptors into a table of configured positions, with each backend appearing
+/// once per unit of weight.
+///
+/// Backends with a weight of zero are left out, unless every backend has a weight of zero: rather
+/// than having nowhere to send requests, they're all treated equally.
+fn weighted_positions(backends: &[BackendDescriptor]) -> Vec<usize> {
+    let positions = backends
+        .iter()
+        .flat_map(|backend| (0..backend.weight).map(move |_| backend.idx))
+        .collect::<Vec<_>>();
+
+    if positions.is_empty() {
+        backends.iter().map(|backend| backend.idx).collect()
+    } else {
+        positions
+    }
+}
+
+type DistributorBuilder = fn(&PoolOptions) -> Result<Box<Distributor + Send + Sync>, CreationError>;
+
+// Every distributor we know how to build, by the name it's configured with.
+const DISTRIBUTORS: &[(&str, DistributorBuilder)] = &[("modulo", build_modulo), ("random", build_random)];
+
+/// Gets the names of all of the distributors that can be configured.
+pub fn distributor_names() -> Vec<&'static str> { DISTRIBUTORS.iter().map(|(name, _)| *name).collect() }
+
+pub fn configure_distributor(
+    dist_type: &str, options: &PoolOptions,
+) -> Result<Box<Distributor + Send + Sync>, CreationError> {
+    match DISTRIBUTORS.iter().find(|(name, _)| *name == dist_type) {
+        Some((_, build)) => build(options),
+        None => {
             Err(CreationError::InvalidResource(format!(
                 "unknown distributor type {}",
-                s
+                dist_type
             )))
         },
     }
 }
+
+fn build_modulo(_: &PoolOptions) -> Result<Box<Distributor + Send + Sync>, CreationError> {
+    Ok(Box::new(ModuloDistributor::new()))
+}
+
+fn build_random(options: &PoolOptions) -> Result<Box<Distributor + Send + Sync>, CreationError> {
+    match options.random_seed {
+        Some(seed) => Ok(Box::new(RandomDistributor::with_seed(seed))),
+        None => Ok(Box::new(RandomDistributor::new())),
+    }
+}
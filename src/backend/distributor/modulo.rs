@@ -17,31 +17,106 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use super::{BackendDescriptor, Distributor};
+use super::{sort_descriptors, weighted_positions, BackendDescriptor, Distributor};
 
 /// Provides a modulo'd distribution of requests.
 pub struct ModuloDistributor {
-    backend_count: usize,
-    backends: Vec<BackendDescriptor>,
+    positions: Vec<usize>,
 }
 
 impl ModuloDistributor {
-    pub fn new() -> ModuloDistributor {
-        ModuloDistributor {
-            backend_count: 0,
-            backends: Vec::new(),
-        }
-    }
+    pub fn new() -> ModuloDistributor { ModuloDistributor { positions: Vec::new() } }
 }
 
 impl Distributor for ModuloDistributor {
-    fn update(&mut self, backends: Vec<BackendDescriptor>) {
-        self.backends = backends;
-        self.backend_count = self.backends.len();
+    fn update(&mut self, mut backends: Vec<BackendDescriptor>) {
+        sort_descriptors(&mut backends);
+        self.positions = weighted_positions(&backends);
     }
 
     fn choose(&self, point: u64) -> usize {
-        let idx = point as usize % self.backend_count;
-        self.backends[idx].idx
+        let idx = point as usize % self.positions.len();
+        self.positions[idx]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{seq::SliceRandom, thread_rng};
+
+    fn get_descriptors(count: usize) -> Vec<BackendDescriptor> {
+        (0..count)
+            .map(|idx| {
+                BackendDescriptor {
+                    idx,
+                    identifier: format!("backend-{}", idx),
+                    healthy: true,
+                    weight: 1,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reseed_with_shuffled_membership() {
+        let descriptors = get_descriptors(7);
+
+        let mut distributor = ModuloDistributor::new();
+        distributor.update(descriptors.clone());
+        let baseline = (0..10_000).map(|point| distributor.choose(point)).collect::<Vec<_>>();
+
+        let mut rng = thread_rng();
+        for _ in 0..10 {
+            let mut shuffled = descriptors.clone();
+            shuffled.shuffle(&mut rng);
+            distributor.update(shuffled);
+
+            let reseeded = (0..10_000).map(|point| distributor.choose(point)).collect::<Vec<_>>();
+            assert_eq!(baseline, reseeded);
+        }
+    }
+
+    #[test]
+    fn test_choose_returns_configured_position() {
+        // Only a subset of backends are healthy, so the configured positions no longer line up
+        // with list offsets.
+        let descriptors = get_descriptors(5)
+            .into_iter()
+            .filter(|backend| backend.idx % 2 == 0)
+            .collect::<Vec<_>>();
+
+        let mut distributor = ModuloDistributor::new();
+        distributor.update(descriptors);
+
+        assert_eq!(distributor.choose(0), 0);
+        assert_eq!(distributor.choose(1), 2);
+        assert_eq!(distributor.choose(2), 4);
+        assert_eq!(distributor.choose(3), 0);
+    }
+
+    #[test]
+    fn test_weighted_choose() {
+        let mut descriptors = get_descriptors(3);
+        descriptors[0].weight = 0;
+        descriptors[2].weight = 3;
+
+        let mut distributor = ModuloDistributor::new();
+        distributor.update(descriptors.clone());
+
+        let mut counts = [0; 3];
+        for point in 0..4_000 {
+            counts[distributor.choose(point)] += 1;
+        }
+        assert_eq!(counts, [0, 1_000, 3_000]);
+
+        // With every backend drained, we still have to send requests somewhere.
+        for descriptor in &mut descriptors {
+            descriptor.weight = 0;
+        }
+        distributor.update(descriptors);
+
+        let chosen = (0..3).map(|point| distributor.choose(point)).collect::<Vec<_>>();
+        assert_eq!(chosen, vec![0, 1, 2]);
     }
 }
@@ -17,33 +17,110 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use super::{BackendDescriptor, Distributor};
-use rand::{thread_rng, Rng};
+use super::{sort_descriptors, weighted_positions, BackendDescriptor, Distributor};
+use rand::random;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// The SplitMix64 increment: an odd constant derived from the golden ratio.
+const SPLITMIX_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
 
 /// Provides a randomized distribution of requests.
+///
+/// Choices come from a SplitMix64 generator whose state is advanced atomically, so concurrent
+/// callers never wait on each other.  The generator is seeded from entropy unless a seed is given,
+/// in which case the sequence of choices is fully reproducible.
 pub struct RandomDistributor {
-    backend_count: usize,
-    backends: Vec<BackendDescriptor>,
+    positions: Vec<usize>,
+    state: AtomicU64,
 }
 
 impl RandomDistributor {
-    pub fn new() -> RandomDistributor {
+    pub fn new() -> RandomDistributor { RandomDistributor::with_seed(random()) }
+
+    pub fn with_seed(seed: u64) -> RandomDistributor {
         RandomDistributor {
-            backend_count: 0,
-            backends: Vec::new(),
+            positions: Vec::new(),
+            state: AtomicU64::new(seed),
         }
     }
+
+    fn next_u64(&self) -> u64 {
+        let state = self.state.fetch_add(SPLITMIX_GAMMA, Ordering::Relaxed);
+        let mut z = state.wrapping_add(SPLITMIX_GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
 }
 
 impl Distributor for RandomDistributor {
-    fn update(&mut self, backends: Vec<BackendDescriptor>) {
-        self.backends = backends;
-        self.backend_count = self.backends.len();
+    fn update(&mut self, mut backends: Vec<BackendDescriptor>) {
+        sort_descriptors(&mut backends);
+        self.positions = weighted_positions(&backends);
     }
 
     fn choose(&self, _point: u64) -> usize {
-        let mut rng = thread_rng();
-        let idx = rng.gen_range(0, self.backend_count);
-        self.backends[idx].idx
+        // Scale the random value into our range by multiplying rather than taking the remainder,
+        // which avoids the skew towards the low positions that modulo would give us.
+        let len = self.positions.len() as u128;
+        let idx = ((u128::from(self.next_u64()) * len) >> 64) as usize;
+        self.positions[idx]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_descriptors(count: usize) -> Vec<BackendDescriptor> {
+        (0..count)
+            .map(|idx| {
+                BackendDescriptor {
+                    idx,
+                    identifier: format!("backend-{}", idx),
+                    healthy: true,
+                    weight: 1,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_seeded_determinism() {
+        let mut first = RandomDistributor::with_seed(1234);
+        let mut second = RandomDistributor::with_seed(1234);
+        let mut other = RandomDistributor::with_seed(4321);
+        first.update(get_descriptors(16));
+        second.update(get_descriptors(16));
+        other.update(get_descriptors(16));
+
+        let first_choices = (0..1_000).map(|point| first.choose(point)).collect::<Vec<_>>();
+        let second_choices = (0..1_000).map(|point| second.choose(point)).collect::<Vec<_>>();
+        let other_choices = (0..1_000).map(|point| other.choose(point)).collect::<Vec<_>>();
+        assert_eq!(first_choices, second_choices);
+        assert_ne!(first_choices, other_choices);
+    }
+
+    #[test]
+    fn test_uniformity() {
+        const BACKENDS: usize = 10;
+        const SAMPLES: usize = 100_000;
+
+        let mut distributor = RandomDistributor::with_seed(42);
+        distributor.update(get_descriptors(BACKENDS));
+
+        let mut counts = [0usize; BACKENDS];
+        for point in 0..SAMPLES {
+            counts[distributor.choose(point as u64)] += 1;
+        }
+
+        // Pearson's chi-squared test against a uniform distribution.  With nine degrees of
+        // freedom, anything above 27.88 would only happen by chance 0.1% of the time.
+        let expected = (SAMPLES / BACKENDS) as f64;
+        let chi_squared = counts
+            .iter()
+            .map(|count| (*count as f64 - expected).powi(2) / expected)
+            .sum::<f64>();
+        assert!(chi_squared < 27.88, "chi-squared too high: {} ({:?})", chi_squared, counts);
     }
 }
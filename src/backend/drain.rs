@@ -0,0 +1,564 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::weights::{find_backend_weights, BackendWeights};
+use events::{self, EventKind};
+use futures::prelude::*;
+use metrics::{get_sink, MetricSink};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+use util::clock::{duration_as_ms, saturating_duration_since};
+
+// How often a draining backend is checked on.
+const DRAIN_TICK_MS: u64 = 100;
+
+/// How long a draining backend has to go without requests, by default, before it's drained.
+pub const DEFAULT_QUIET_MS: u64 = 5000;
+
+lazy_static! {
+    static ref ACTIVITY: Mutex<HashMap<(String, String), Arc<BackendActivity>>> = Mutex::new(HashMap::new());
+    static ref DRAINS: Mutex<HashMap<(String, String, SocketAddr), Arc<BackendDrain>>> = Mutex::new(HashMap::new());
+}
+
+/// Registers the backend activity for a pool, replacing that of any previous version of the pool.
+pub fn register_backend_activity(listener: &str, pool: &str, activity: Arc<BackendActivity>) {
+    let mut registry = ACTIVITY.lock().unwrap();
+    registry.insert((listener.to_owned(), pool.to_owned()), activity);
+}
+
+/// Gets the backend activity for the given pool, if the pool exists.
+pub fn find_backend_activity(listener: &str, pool: &str) -> Option<Arc<BackendActivity>> {
+    let registry = ACTIVITY.lock().unwrap();
+    registry.get(&(listener.to_owned(), pool.to_owned())).cloned()
+}
+
+/// Gets the backend activity of every pool, along with the listener and name of each pool.
+pub fn list_backend_activity() -> Vec<(String, String, Arc<BackendActivity>)> {
+    let registry = ACTIVITY.lock().unwrap();
+    registry
+        .iter()
+        .map(|((listener, pool), activity)| (listener.clone(), pool.clone(), activity.clone()))
+        .collect()
+}
+
+/// How many requests the backends in a pool have been sent, and how many they're still working
+/// on, indexed by their configured position.
+///
+/// The counts are owned by the pool's task, and published here each time it drives its backends,
+/// so that they can be looked at from outside of the pool.
+pub struct BackendActivity {
+    identifiers: Vec<String>,
+    addresses: Mutex<Vec<SocketAddr>>,
+    requests: Vec<AtomicUsize>,
+    in_flight: Vec<AtomicUsize>,
+}
+
+impl BackendActivity {
+    pub fn new(backends: Vec<(String, SocketAddr)>) -> BackendActivity {
+        let requests = backends.iter().map(|_| AtomicUsize::new(0)).collect();
+        let in_flight = backends.iter().map(|_| AtomicUsize::new(0)).collect();
+        let (identifiers, addresses) = backends.into_iter().unzip();
+
+        BackendActivity {
+            identifiers,
+            addresses: Mutex::new(addresses),
+            requests,
+            in_flight,
+        }
+    }
+
+    /// Publishes the counts of the backend at the given configured position.
+    pub fn update(&self, idx: usize, requests: usize, in_flight: usize) {
+        self.requests[idx].store(requests, Ordering::Release);
+        self.in_flight[idx].store(in_flight, Ordering::Release);
+    }
+
+    /// Gets the identifiers of every backend, in their configured order.
+    pub fn identifiers(&self) -> &[String] { &self.identifiers }
+
+    /// Gets the address of the backend at the given configured position.
+    pub fn address(&self, idx: usize) -> SocketAddr { self.addresses.lock().unwrap()[idx] }
+
+    /// Sets the address of the backend at the given configured position, once it's moved there.
+    pub fn set_address(&self, idx: usize, address: SocketAddr) { self.addresses.lock().unwrap()[idx] = address; }
+
+    /// Gets how many requests the backend at the given configured position has been sent.
+    pub fn requests(&self, idx: usize) -> usize { self.requests[idx].load(Ordering::Acquire) }
+
+    /// Gets how many requests the backend at the given configured position is still working on.
+    pub fn in_flight(&self, idx: usize) -> usize { self.in_flight[idx].load(Ordering::Acquire) }
+
+    /// Gets the configured positions of every backend with the given address.
+    fn find_by_addr(&self, addr: &SocketAddr) -> Vec<usize> {
+        self.addresses
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| *a == addr)
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Gets the total requests sent to, and still in flight on, the backends at the given positions.
+    fn get_counts(&self, positions: &[usize]) -> (usize, usize) {
+        positions.iter().fold((0, 0), |(requests, in_flight), idx| {
+            (
+                requests.wrapping_add(self.requests[*idx].load(Ordering::Acquire)),
+                in_flight + self.in_flight[*idx].load(Ordering::Acquire),
+            )
+        })
+    }
+}
+
+/// Where a drain is at.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrainPhase {
+    /// The backend has been taken out of the distribution, but may still be getting requests.
+    Draining,
+
+    /// The backend has gone without requests for the quiet period, and is safe to remove.
+    Drained,
+
+    /// The backend was given a weight again, or its pool was replaced, before it was drained.
+    Cancelled,
+}
+
+/// The state of a drain, as reported to whoever asked for it.
+#[derive(Clone, Debug, Serialize)]
+pub struct DrainStatus {
+    pub backend: String,
+    pub phase: DrainPhase,
+    pub safe_to_remove: bool,
+    pub requests: usize,
+    pub in_flight: usize,
+    pub quiet_ms: u64,
+    pub quiet_for_ms: u64,
+}
+
+struct BackendDrain {
+    activity: Arc<BackendActivity>,
+    status: Mutex<DrainStatus>,
+}
+
+/// A drain that was underway, or finished, before a reload, to be started over against the pool
+/// that replaced its own.
+pub struct PreservedDrain {
+    listener: String,
+    pool: String,
+    addr: SocketAddr,
+    quiet: Duration,
+}
+
+impl BackendDrain {
+    fn get_status(&self) -> DrainStatus { self.status.lock().unwrap().clone() }
+
+    fn is_current(&self, activity: &Arc<BackendActivity>) -> bool {
+        Arc::ptr_eq(&self.activity, activity) && self.get_status().phase != DrainPhase::Cancelled
+    }
+}
+
+/// Drains the backends with the given address from the given pool.
+///
+/// The backends are given a weight of zero, taking them out of the distribution, and then watched
+/// until they've gone without any requests, queued or in flight, for the quiet period.  Draining
+/// a backend that's already draining, or drained, leaves the drain be and reports where it's at,
+/// so asking again is how the caller finds out whether the backend is safe to remove yet.  If the
+/// pool has been replaced since, by a reload, the drain starts over against the new pool.
+///
+/// Returns `None` if there's no such pool, or no backend in it with the given address.
+pub fn drain_backend(listener: &str, pool: &str, addr: SocketAddr, quiet: Duration) -> Option<DrainStatus> {
+    let weights = find_backend_weights(listener, pool)?;
+    let activity = find_backend_activity(listener, pool)?;
+    let positions = activity.find_by_addr(&addr);
+    if positions.is_empty() {
+        return None;
+    }
+
+    let key = (listener.to_owned(), pool.to_owned(), addr);
+    let mut drains = DRAINS.lock().unwrap();
+    if let Some(drain) = drains.get(&key) {
+        if drain.is_current(&activity) {
+            return Some(drain.get_status());
+        }
+    }
+
+    weights.set_by_addr(&addr, 0).ok()?;
+
+    let (requests, in_flight) = activity.get_counts(&positions);
+    let status = DrainStatus {
+        backend: addr.to_string(),
+        phase: DrainPhase::Draining,
+        safe_to_remove: false,
+        requests,
+        in_flight,
+        quiet_ms: duration_as_ms(quiet),
+        quiet_for_ms: 0,
+    };
+    let drain = Arc::new(BackendDrain {
+        activity: activity.clone(),
+        status: Mutex::new(status.clone()),
+    });
+    drains.insert(key.clone(), drain.clone());
+
+    info!("[drain] draining backend {} in pool '{}' on listener '{}'", addr, pool, listener);
+    events::publish(
+        EventKind::DrainStarted,
+        Some(pool),
+        Some(&status.backend),
+        format!("listener '{}', quiet period of {}ms", listener, status.quiet_ms),
+    );
+
+    let sinks = get_backend_sinks(listener, pool, &activity, &positions);
+    let watcher = DrainWatcher::new(key, drain, weights, positions, quiet, sinks);
+    tokio::spawn(watcher);
+
+    Some(status)
+}
+
+/// Restores the backends with the given address in the given pool to the weight they were
+/// configured with, putting them back into the distribution.
+///
+/// Any drain of the backends, whether it's underway or finished, is cancelled, so draining them
+/// again starts a new drain.  Returns the weight they were restored to, or `None` if there's no
+/// such pool, or no backend in it with the given address.
+pub fn restore_backend(listener: &str, pool: &str, addr: SocketAddr) -> Option<usize> {
+    let weights = find_backend_weights(listener, pool)?;
+    let weight = weights.restore_by_addr(&addr).ok()?;
+    info!(
+        "[drain] restored backend {} in pool '{}' on listener '{}' to a weight of {}",
+        addr, pool, listener, weight
+    );
+
+    let drains = DRAINS.lock().unwrap();
+    if let Some(drain) = drains.get(&(listener.to_owned(), pool.to_owned(), addr)) {
+        let mut status = drain.status.lock().unwrap();
+        if status.phase != DrainPhase::Cancelled {
+            status.phase = DrainPhase::Cancelled;
+            status.safe_to_remove = false;
+
+            let positions = drain.activity.find_by_addr(&addr);
+            for sink in get_backend_sinks(listener, pool, &drain.activity, &positions) {
+                sink.update_gauge("draining", 0);
+                sink.update_gauge("drained", 0);
+            }
+            events::publish(
+                EventKind::DrainCancelled,
+                Some(pool),
+                Some(&status.backend),
+                format!("listener '{}', backend was restored", listener),
+            );
+        }
+    }
+
+    Some(weight)
+}
+
+/// Gets every drain that's underway or finished, so that they can outlast a reload.
+pub fn preserve_drains() -> Vec<PreservedDrain> {
+    let drains = DRAINS.lock().unwrap();
+    drains
+        .iter()
+        .filter_map(|((listener, pool, addr), drain)| {
+            let status = drain.get_status();
+            if status.phase == DrainPhase::Cancelled {
+                return None;
+            }
+
+            Some(PreservedDrain {
+                listener: listener.clone(),
+                pool: pool.clone(),
+                addr: *addr,
+                quiet: Duration::from_millis(status.quiet_ms),
+            })
+        })
+        .collect()
+}
+
+/// Starts the given drains over against the current version of their pools.
+///
+/// Drains whose pool wasn't replaced carry on as they were.  Those whose backend is no longer in
+/// its pool are dropped.
+pub fn resume_drains(drains: Vec<PreservedDrain>) {
+    for drain in drains {
+        if drain_backend(&drain.listener, &drain.pool, drain.addr, drain.quiet).is_none() {
+            warn!(
+                "[drain] not resuming drain of backend {} in pool '{}' on listener '{}': no longer in the pool",
+                drain.addr, drain.pool, drain.listener
+            );
+        }
+    }
+}
+
+fn get_backend_sinks(listener: &str, pool: &str, activity: &BackendActivity, positions: &[usize]) -> Vec<MetricSink> {
+    positions
+        .iter()
+        .map(|idx| {
+            let identifier = activity.identifiers[*idx].as_str();
+            get_sink().scoped(&["listeners", listener, "pools", pool, "backends", identifier])
+        })
+        .collect()
+}
+
+/// Gets the state of the drain of the backends with the given address from the given pool, if
+/// they've ever been drained.
+pub fn get_drain_status(listener: &str, pool: &str, addr: SocketAddr) -> Option<DrainStatus> {
+    let drains = DRAINS.lock().unwrap();
+    drains.get(&(listener.to_owned(), pool.to_owned(), addr)).map(|drain| drain.get_status())
+}
+
+/// Watches a draining backend until it's been quiet for long enough to be removed.
+struct DrainWatcher {
+    key: (String, String, SocketAddr),
+    drain: Arc<BackendDrain>,
+    weights: Arc<BackendWeights>,
+    positions: Vec<usize>,
+    quiet: Duration,
+    last_requests: usize,
+    quiet_since: Instant,
+    interval: Interval,
+    sinks: Vec<MetricSink>,
+}
+
+impl DrainWatcher {
+    fn new(
+        key: (String, String, SocketAddr), drain: Arc<BackendDrain>, weights: Arc<BackendWeights>,
+        positions: Vec<usize>, quiet: Duration, sinks: Vec<MetricSink>,
+    ) -> DrainWatcher {
+        let (last_requests, _) = drain.activity.get_counts(&positions);
+        let tick = Duration::from_millis(DRAIN_TICK_MS);
+
+        let watcher = DrainWatcher {
+            key,
+            drain,
+            weights,
+            positions,
+            quiet,
+            last_requests,
+            quiet_since: Instant::now(),
+            interval: Interval::new(Instant::now() + tick, tick),
+            sinks,
+        };
+        watcher.report(1, 0);
+        watcher
+    }
+
+    fn report(&self, draining: u64, drained: u64) {
+        for sink in &self.sinks {
+            sink.update_gauge("draining", draining);
+            sink.update_gauge("drained", drained);
+        }
+    }
+
+    /// Checks on the backend, returning the phase the drain is now in.
+    fn check(&mut self, now: Instant) -> DrainPhase {
+        let (listener, pool, addr) = (&self.key.0, &self.key.1, self.key.2);
+
+        // If the backend was restored, the drain has already been cancelled, and said so.
+        if self.drain.get_status().phase == DrainPhase::Cancelled {
+            return DrainPhase::Cancelled;
+        }
+
+        // If the backend has been given a weight again, someone changed their mind.  If its pool
+        // has been replaced, whoever asked for the drain has to ask again to pick it back up.
+        let reweighted = self.positions.iter().any(|idx| self.weights.get(*idx) > 0);
+        let replaced = find_backend_activity(listener, pool)
+            .map(|activity| !Arc::ptr_eq(&activity, &self.drain.activity))
+            .unwrap_or(true);
+        if reweighted || replaced {
+            let reason = if reweighted { "weight was restored" } else { "pool was replaced" };
+            info!(
+                "[drain] stopped draining backend {} in pool '{}' on listener '{}': {}",
+                addr, pool, listener, reason
+            );
+            self.drain.status.lock().unwrap().phase = DrainPhase::Cancelled;
+            self.report(0, 0);
+            events::publish(
+                EventKind::DrainCancelled,
+                Some(pool),
+                Some(&addr.to_string()),
+                format!("listener '{}', {}", listener, reason),
+            );
+            return DrainPhase::Cancelled;
+        }
+
+        let (requests, in_flight) = self.drain.activity.get_counts(&self.positions);
+        if requests != self.last_requests || in_flight > 0 {
+            self.last_requests = requests;
+            self.quiet_since = now;
+        }
+
+        let quiet_for = saturating_duration_since(now, self.quiet_since);
+        let phase = if quiet_for >= self.quiet {
+            DrainPhase::Drained
+        } else {
+            DrainPhase::Draining
+        };
+
+        {
+            let mut status = self.drain.status.lock().unwrap();
+            status.phase = phase;
+            status.safe_to_remove = phase == DrainPhase::Drained;
+            status.requests = requests;
+            status.in_flight = in_flight;
+            status.quiet_for_ms = duration_as_ms(quiet_for);
+        }
+
+        if phase == DrainPhase::Drained {
+            info!(
+                "[drain] backend {} in pool '{}' on listener '{}' has been quiet for {}ms: safe to remove",
+                addr,
+                pool,
+                listener,
+                duration_as_ms(quiet_for)
+            );
+            self.report(0, 1);
+            events::publish(
+                EventKind::DrainCompleted,
+                Some(pool),
+                Some(&addr.to_string()),
+                format!("listener '{}', quiet for {}ms", listener, duration_as_ms(quiet_for)),
+            );
+        }
+
+        phase
+    }
+}
+
+impl Future for DrainWatcher {
+    type Error = ();
+    type Item = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => {
+                    if self.check(Instant::now()) != DrainPhase::Draining {
+                        return Ok(Async::Ready(()));
+                    }
+                },
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Err(e) => {
+                    error!("[drain] timer failed while draining backend {}: {}", self.key.2, e);
+                    return Err(());
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::weights::{register_backend_weights, DEFAULT_WEIGHT};
+
+    fn get_watcher(pool: &str, quiet_ms: u64) -> DrainWatcher {
+        let a = "127.0.0.1:6379".parse().unwrap();
+        let b = "127.0.0.1:6380".parse().unwrap();
+        let activity = Arc::new(BackendActivity::new(vec![("a".to_owned(), a), ("b".to_owned(), b)]));
+        let weights = Arc::new(BackendWeights::new(vec![(a, DEFAULT_WEIGHT), (b, DEFAULT_WEIGHT)]));
+        weights.set_by_addr(&b, 0).unwrap();
+        register_backend_activity("drain_test", pool, activity.clone());
+
+        let drain = Arc::new(BackendDrain {
+            activity,
+            status: Mutex::new(DrainStatus {
+                backend: b.to_string(),
+                phase: DrainPhase::Draining,
+                safe_to_remove: false,
+                requests: 0,
+                in_flight: 0,
+                quiet_ms,
+                quiet_for_ms: 0,
+            }),
+        });
+        let key = ("drain_test".to_owned(), pool.to_owned(), b);
+        DrainWatcher::new(key, drain, weights, vec![1], Duration::from_millis(quiet_ms), Vec::new())
+    }
+
+    #[test]
+    fn test_quiet_backend_drains() {
+        let mut watcher = get_watcher("quiet", 1000);
+        let start = watcher.quiet_since;
+
+        // Requests still trickling in, or still in flight, keep the backend from being drained.
+        watcher.drain.activity.update(1, 10, 0);
+        assert_eq!(watcher.check(start + Duration::from_millis(500)), DrainPhase::Draining);
+        watcher.drain.activity.update(1, 10, 2);
+        assert_eq!(watcher.check(start + Duration::from_millis(1000)), DrainPhase::Draining);
+        watcher.drain.activity.update(1, 10, 0);
+        assert_eq!(watcher.check(start + Duration::from_millis(1500)), DrainPhase::Draining);
+
+        // Traffic to other backends doesn't count against it.
+        watcher.drain.activity.update(0, 500, 5);
+        assert_eq!(watcher.check(start + Duration::from_millis(1999)), DrainPhase::Draining);
+        assert_eq!(watcher.check(start + Duration::from_millis(2000)), DrainPhase::Drained);
+
+        let status = watcher.drain.get_status();
+        assert!(status.safe_to_remove);
+        assert_eq!(status.requests, 10);
+        assert_eq!(status.quiet_for_ms, 1000);
+    }
+
+    #[test]
+    fn test_reweighted_backend_cancels() {
+        let mut watcher = get_watcher("reweighted", 1000);
+        let start = watcher.quiet_since;
+
+        watcher.weights.set_by_addr(&"127.0.0.1:6380".parse().unwrap(), 1).unwrap();
+        assert_eq!(watcher.check(start), DrainPhase::Cancelled);
+        assert!(!watcher.drain.get_status().safe_to_remove);
+    }
+
+    #[test]
+    fn test_restored_backend_cancels() {
+        let mut watcher = get_watcher("restored", 1000);
+        let start = watcher.quiet_since;
+        let addr = watcher.key.2;
+        register_backend_weights("drain_test", "restored", watcher.weights.clone());
+        DRAINS.lock().unwrap().insert(watcher.key.clone(), watcher.drain.clone());
+
+        // Drains that are underway are carried over a reload, along with their quiet period.
+        let preserved = preserve_drains();
+        assert!(preserved
+            .iter()
+            .any(|drain| drain.pool == "restored" && drain.addr == addr && drain.quiet == Duration::from_millis(1000)));
+
+        assert_eq!(restore_backend("drain_test", "restored", addr), Some(DEFAULT_WEIGHT));
+        assert_eq!(watcher.weights.get(1), DEFAULT_WEIGHT);
+        assert_eq!(watcher.drain.get_status().phase, DrainPhase::Cancelled);
+        assert!(preserve_drains().iter().all(|drain| drain.pool != "restored"));
+
+        // The watcher finds the drain already cancelled, and leaves it at that.
+        assert_eq!(watcher.check(start), DrainPhase::Cancelled);
+
+        // Backends that aren't in the pool can't be restored.
+        assert_eq!(restore_backend("drain_test", "restored", "127.0.0.1:1".parse().unwrap()), None);
+    }
+}
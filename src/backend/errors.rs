@@ -38,6 +38,17 @@ pub enum PoolError {
     Backend(BackendError),
 }
 
+impl BackendError {
+    /// A short name for the kind of error this is, for grouping errors of the same kind together.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BackendError::Internal(_) => "internal",
+            BackendError::Protocol(e) => e.kind(),
+            BackendError::Io(_) => "io",
+        }
+    }
+}
+
 impl From<ProcessorError> for BackendError {
     fn from(e: ProcessorError) -> Self {
         let desc = e.to_string();
@@ -0,0 +1,248 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use bytes::BytesMut;
+use conf::{PoolOptions, Secret, Zeroize};
+use errors::CreationError;
+use futures::prelude::*;
+use protocol::errors::ProtocolError;
+use tokio::io::{write_all, AsyncRead, AsyncWrite};
+
+const REDIS_OK: &[u8] = b"+OK\r\n";
+
+/// How every connection to a Redis pool's backends is set up before it's used.
+///
+/// `redis_auth`, or `redis_auth_file`, gives the password to `AUTH` with, and `redis_db` the
+/// database to `SELECT`.  Both are sent as soon as a connection is made, and the connection fails,
+/// like any other connection error, unless each of them is answered with `+OK`.
+#[derive(Clone, Debug, Default)]
+pub struct RedisHandshake {
+    auth: Option<Secret<String>>,
+    db: Option<u32>,
+}
+
+impl RedisHandshake {
+    /// Extracts the handshake from the given pool options, if connections need one.
+    pub fn from_options(options: &PoolOptions) -> Result<Option<RedisHandshake>, CreationError> {
+        let auth = options.secret("redis_auth")?;
+        if auth.as_ref().map_or(false, |auth| auth.expose().is_empty()) {
+            return Err(CreationError::InvalidParameter("options.redis_auth".to_string()));
+        }
+
+        let db = options.redis_db;
+
+        if auth.is_none() && db.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(RedisHandshake { auth, db }))
+    }
+
+    /// Runs the handshake over the given connection, handing it back once the backend has accepted
+    /// every command.
+    pub fn run<T>(&self, conn: T) -> impl Future<Item = T, Error = ProtocolError> + Send + 'static
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (request, replies) = self.get_request();
+        write_all(conn, request)
+            .map_err(ProtocolError::IoError)
+            .and_then(move |(conn, _)| HandshakeReplies::new(conn, replies))
+    }
+
+    // Builds the commands to send, along with how many replies to expect for them.  The commands
+    // aren't built as messages, so that nothing that traces messages ever gets to see the password.
+    fn get_request(&self) -> (HandshakeRequest, usize) {
+        let mut buf = Vec::new();
+        let mut replies = 0;
+
+        if let Some(ref auth) = self.auth {
+            write_command(&mut buf, &[b"AUTH", auth.expose().as_bytes()]);
+            replies += 1;
+        }
+
+        if let Some(db) = self.db {
+            write_command(&mut buf, &[b"SELECT", db.to_string().as_bytes()]);
+            replies += 1;
+        }
+
+        (HandshakeRequest(buf), replies)
+    }
+}
+
+fn write_command(buf: &mut Vec<u8>, args: &[&[u8]]) {
+    buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+// The commands making up a handshake, which are zeroed once they've been written, or given up on,
+// since they hold the password.
+struct HandshakeRequest(Vec<u8>);
+
+impl AsRef<[u8]> for HandshakeRequest {
+    fn as_ref(&self) -> &[u8] { &self.0 }
+}
+
+impl Drop for HandshakeRequest {
+    fn drop(&mut self) { self.0.zeroize(); }
+}
+
+/// Reads the replies to a handshake, making sure that every one of them is `+OK`.
+///
+/// An error reply fails the handshake with the error the backend gave, which never includes what
+/// was sent to it.
+pub struct HandshakeReplies<T> {
+    conn: Option<T>,
+    buf: BytesMut,
+    remaining: usize,
+}
+
+impl<T> HandshakeReplies<T> {
+    pub fn new(conn: T, replies: usize) -> HandshakeReplies<T> {
+        HandshakeReplies {
+            conn: Some(conn),
+            buf: BytesMut::new(),
+            remaining: replies,
+        }
+    }
+}
+
+impl<T> Future for HandshakeReplies<T>
+where
+    T: AsyncRead,
+{
+    type Error = ProtocolError;
+    type Item = T;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            while self.remaining > 0 {
+                let end = match self.buf.windows(2).position(|w| w == b"\r\n") {
+                    Some(pos) => pos + 2,
+                    None => break,
+                };
+
+                let reply = self.buf.split_to(end);
+                if &reply[..] != REDIS_OK {
+                    let reply = String::from_utf8_lossy(&reply[..end - 2]).into_owned();
+                    return Err(ProtocolError::HandshakeFailed(reply));
+                }
+                self.remaining -= 1;
+            }
+
+            if self.remaining == 0 {
+                // Nothing was asked of the backend beyond the handshake, so anything more is a
+                // sign that we're not talking about the same thing anymore.
+                if !self.buf.is_empty() {
+                    return Err(ProtocolError::BackendOutOfSync);
+                }
+
+                return Ok(Async::Ready(self.conn.take().expect("handshake polled after completion")));
+            }
+
+            self.buf.reserve(64);
+            let conn = self.conn.as_mut().expect("handshake polled after completion");
+            let n = try_ready!(conn.read_buf(&mut self.buf));
+            if n == 0 {
+                return Err(ProtocolError::BackendClosedPrematurely);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn get_options(options: &[(&str, &str)]) -> PoolOptions {
+        let raw = options
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        PoolOptions::from_map(raw).unwrap()
+    }
+
+    fn get_replies(replies: &[u8], count: usize) -> Result<Vec<u8>, ProtocolError> {
+        HandshakeReplies::new(Cursor::new(replies.to_vec()), count)
+            .wait()
+            .map(|conn| conn.into_inner())
+    }
+
+    #[test]
+    fn test_handshake_from_options() {
+        assert!(RedisHandshake::from_options(&get_options(&[])).unwrap().is_none());
+
+        let options = get_options(&[("redis_auth", "hunter2"), ("redis_db", "3")]);
+        let handshake = RedisHandshake::from_options(&options).unwrap().unwrap();
+        let (request, replies) = handshake.get_request();
+        assert_eq!(
+            request.as_ref(),
+            &b"*2\r\n$4\r\nAUTH\r\n$7\r\nhunter2\r\n*2\r\n$6\r\nSELECT\r\n$1\r\n3\r\n"[..]
+        );
+        assert_eq!(replies, 2);
+
+        // The handshake is as careful with the password as the options are.
+        assert!(!format!("{:?}", handshake).contains("hunter2"));
+
+        let options = get_options(&[("redis_db", "1")]);
+        let handshake = RedisHandshake::from_options(&options).unwrap().unwrap();
+        let (request, replies) = handshake.get_request();
+        assert_eq!(request.as_ref(), &b"*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n"[..]);
+        assert_eq!(replies, 1);
+
+        for bad in &["-1", "zero"] {
+            let raw = Some(("redis_db".to_string(), bad.to_string())).into_iter().collect();
+            assert!(PoolOptions::from_map(raw).is_err());
+        }
+        let options = get_options(&[("redis_auth", "")]);
+        assert!(RedisHandshake::from_options(&options).is_err());
+    }
+
+    #[test]
+    fn test_handshake_replies() {
+        assert!(get_replies(b"+OK\r\n+OK\r\n", 2).is_ok());
+
+        match get_replies(b"-WRONGPASS invalid username-password pair\r\n+OK\r\n", 2) {
+            Err(ProtocolError::HandshakeFailed(reply)) => {
+                assert_eq!(reply, "-WRONGPASS invalid username-password pair")
+            },
+            _ => panic!("expected handshake to fail"),
+        }
+
+        match get_replies(b"+OK\r\n-ERR DB index is out of range\r\n", 2) {
+            Err(ProtocolError::HandshakeFailed(reply)) => assert_eq!(reply, "-ERR DB index is out of range"),
+            _ => panic!("expected handshake to fail"),
+        }
+
+        match get_replies(b"+OK\r\n", 2) {
+            Err(ProtocolError::BackendClosedPrematurely) => {},
+            _ => panic!("expected backend to have closed"),
+        }
+
+        match get_replies(b"+OK\r\n+OK\r\n", 1) {
+            Err(ProtocolError::BackendOutOfSync) => {},
+            _ => panic!("expected backend to be out of sync"),
+        }
+    }
+}
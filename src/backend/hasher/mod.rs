@@ -19,7 +19,8 @@
 // SOFTWARE.
 mod fnv64a;
 mod md5;
-pub use self::{fnv64a::Fnv64aHasher, md5::MD5Hasher};
+mod murmur3;
+pub use self::{fnv64a::Fnv64aHasher, md5::MD5Hasher, murmur3::Murmur3Hasher};
 use errors::CreationError;
 
 /// Basic hashing capabilities.
@@ -30,10 +31,48 @@ pub trait KeyHasher {
     fn hash(&self, buf: &[u8]) -> u64;
 }
 
+type KeyHasherBuilder = fn() -> Box<KeyHasher + Send + Sync>;
+
+// Every hasher we know how to build, by the name it's configured with.
+const HASHERS: &[(&str, KeyHasherBuilder)] = &[
+    ("fnv1a_64", || Box::new(Fnv64aHasher::new())),
+    ("md5", || Box::new(MD5Hasher::new())),
+    ("murmur3_32", || Box::new(Murmur3Hasher::new())),
+];
+
+/// Gets the names of all of the hashers that can be configured.
+pub fn hasher_names() -> Vec<&'static str> { HASHERS.iter().map(|(name, _)| *name).collect() }
+
 pub fn configure_hasher(hash_type: &str) -> Result<Box<KeyHasher + Send + Sync>, CreationError> {
-    match hash_type {
-        "md5" => Ok(Box::new(MD5Hasher::new())),
-        "fnv1a_64" => Ok(Box::new(Fnv64aHasher::new())),
-        s => Err(CreationError::InvalidResource(format!("unknown hash type {}", s))),
+    match HASHERS.iter().find(|(name, _)| *name == hash_type) {
+        Some((_, build)) => Ok(build()),
+        None => Err(CreationError::InvalidResource(format!("unknown hash type {}", hash_type))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORPUS: &[&[u8]] = &[b"foo", b"bar", b"baz", b"user:1000", b"session:abcdef", b"", b"a", b"synchrotron"];
+
+    fn get_buckets(hash_type: &str, buckets: u64) -> Vec<u64> {
+        let hasher = configure_hasher(hash_type).unwrap();
+        CORPUS.iter().map(|key| hasher.hash(key) % buckets).collect()
+    }
+
+    #[test]
+    fn test_distribution_is_stable() {
+        // Changing where any of these keys land would move them to a different backend for every
+        // pool using that hasher, so these must never change.
+        assert_eq!(get_buckets("fnv1a_64", 7), vec![2, 0, 4, 6, 1, 2, 5, 2]);
+        assert_eq!(get_buckets("md5", 7), vec![5, 1, 6, 0, 4, 6, 6, 5]);
+        assert_eq!(get_buckets("murmur3_32", 7), vec![6, 3, 2, 6, 3, 0, 4, 6]);
+    }
+
+    #[test]
+    fn test_unknown_hasher() {
+        assert!(configure_hasher("murmur3").is_err());
+        assert!(hasher_names().contains(&"murmur3_32"));
     }
 }
@@ -0,0 +1,90 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::KeyHasher;
+
+const C1: u32 = 0xcc9e_2d51;
+const C2: u32 = 0x1b87_3593;
+
+/// The 32-bit, x86 variant of MurmurHash3, with a seed of zero.
+///
+/// This is what many memcached and Redis clients use to pick a server, so pools that are shared
+/// with those clients can put keys in the same place that they do.
+pub struct Murmur3Hasher;
+
+impl Murmur3Hasher {
+    pub fn new() -> Murmur3Hasher { Murmur3Hasher {} }
+}
+
+impl KeyHasher for Murmur3Hasher {
+    fn hash(&self, buf: &[u8]) -> u64 { u64::from(murmur3_32(buf, 0)) }
+}
+
+fn mix_block(k: u32) -> u32 { k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2) }
+
+fn murmur3_32(buf: &[u8], seed: u32) -> u32 {
+    let mut h = seed;
+
+    let (blocks, tail) = buf.split_at(buf.len() / 4 * 4);
+    for block in blocks.chunks(4) {
+        let k = u32::from(block[0])
+            | (u32::from(block[1]) << 8)
+            | (u32::from(block[2]) << 16)
+            | (u32::from(block[3]) << 24);
+        h ^= mix_block(k);
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .enumerate()
+            .fold(0u32, |k, (i, b)| k | (u32::from(*b) << (8 * i)));
+        h ^= mix_block(k);
+    }
+
+    // Only the low 32 bits of the length go into the hash, as in the reference implementation.
+    h ^= buf.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_values() {
+        let hasher = Murmur3Hasher::new();
+        assert_eq!(hasher.hash(b""), 0);
+        assert_eq!(hasher.hash(b"abc"), 0xb3dd_93fa);
+        assert_eq!(hasher.hash(b"abcd"), 0x43ed_676a);
+        assert_eq!(hasher.hash(b"hello"), 0x248b_fa47);
+        assert_eq!(hasher.hash(b"The quick brown fox jumps over the lazy dog"), 0x2e4f_f723);
+    }
+
+    #[test]
+    fn test_seed() {
+        assert_ne!(murmur3_32(b"hello", 0), murmur3_32(b"hello", 1));
+    }
+}
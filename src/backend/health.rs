@@ -17,36 +17,49 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
+use backend::reconnect::Backoff;
+use events::{self, EventKind};
 use futures::{future::ok, task, Future};
 use std::time::{Duration, Instant};
-use tokio::timer::Delay;
-use util::typeless;
+use util::{
+    clock::{duration_as_ms, SharedClock},
+    typeless,
+};
 
 pub struct BackendHealth {
+    pool: String,
+    identifier: String,
     cooloff_enabled: bool,
-    cooloff_period_ms: u64,
+    backoff: Backoff,
     error_limit: usize,
     error_count: usize,
     in_cooloff: bool,
     epoch: u64,
     cooloff_done_at: Instant,
+    clock: SharedClock,
 }
 
 impl BackendHealth {
-    pub fn new(cooloff_enabled: bool, cooloff_period_ms: u64, error_limit: usize) -> BackendHealth {
+    pub fn new(
+        pool: String, identifier: String, cooloff_enabled: bool, backoff: Backoff, error_limit: usize,
+        clock: SharedClock,
+    ) -> BackendHealth {
         debug!(
-            "[backend health] cooloff enabled: {}, cooloff period (ms): {}, error limit: {}",
-            cooloff_enabled, cooloff_period_ms, error_limit
+            "[backend health] cooloff enabled: {}, cooloff backoff: {:?}, error limit: {}",
+            cooloff_enabled, backoff, error_limit
         );
 
         BackendHealth {
+            pool,
+            identifier,
             cooloff_enabled,
-            cooloff_period_ms,
+            backoff,
             error_limit,
             error_count: 0,
             in_cooloff: false,
             epoch: 0,
-            cooloff_done_at: Instant::now(),
+            cooloff_done_at: clock.now(),
+            clock,
         }
     }
 
@@ -55,10 +68,11 @@ impl BackendHealth {
             return true;
         }
 
-        if self.cooloff_done_at < Instant::now() {
+        if self.cooloff_done_at < self.clock.now() {
             self.error_count = 0;
             self.in_cooloff = false;
             self.epoch += 1;
+            events::publish(EventKind::CooloffEnded, Some(&self.pool), Some(&self.identifier), String::new());
 
             return true;
         }
@@ -68,6 +82,28 @@ impl BackendHealth {
 
     pub fn epoch(&self) -> u64 { self.epoch }
 
+    /// Records that the backend is working, so that the next cooloff is back to being the shortest.
+    pub fn record_success(&mut self) { self.backoff.reset(); }
+
+    /// Forgets every error so far, ending any cooloff early.
+    ///
+    /// This is for when the errors no longer say anything about the backend, such as when it's
+    /// moved to a different address than the one they came from.
+    pub fn reset(&mut self) {
+        self.error_count = 0;
+        self.backoff.reset();
+        if self.in_cooloff {
+            self.in_cooloff = false;
+            self.epoch += 1;
+            events::publish(
+                EventKind::CooloffEnded,
+                Some(&self.pool),
+                Some(&self.identifier),
+                "moved to a new address".to_owned(),
+            );
+        }
+    }
+
     pub fn increment_error(&mut self) {
         if !self.cooloff_enabled {
             return;
@@ -76,23 +112,32 @@ impl BackendHealth {
         self.error_count += 1;
 
         // If we're over the error threshold, put ourselves into cooloff.
+        // Every cooloff in a row lasts longer than the last, so that a backend that's down for good
+        // isn't hammered by every pool in lockstep.
         if self.error_count >= self.error_limit && !self.in_cooloff {
-            debug!("[health] error count over limit, setting cooloff");
+            let period = self.backoff.fail();
+            debug!("[health] error count over limit, setting cooloff for {}ms", duration_as_ms(period));
             self.in_cooloff = true;
             self.epoch += 1;
-            self.fire_cooloff_check();
+            self.fire_cooloff_check(period);
+            events::publish(
+                EventKind::CooloffStarted,
+                Some(&self.pool),
+                Some(&self.identifier),
+                format!("{} errors, cooling off for {}ms", self.error_count, duration_as_ms(period)),
+            );
         }
     }
 
-    fn fire_cooloff_check(&mut self) {
+    fn fire_cooloff_check(&mut self, period: Duration) {
         // Mark when our cooloff period should be lifted, and trigger a task notification to fire
         // once that deadline has passed: our health will be checked, and thus we can reenable
         // ourselves.
-        let deadline = Instant::now() + Duration::from_millis(self.cooloff_period_ms);
+        let deadline = self.clock.now() + period;
         self.cooloff_done_at = deadline;
 
         let this = task::current();
-        let delay = Delay::new(deadline).then(move |_| {
+        let delay = self.clock.delay(deadline).then(move |_| {
             debug!("[health] resetting cooloff");
             this.notify();
             ok::<_, ()>(())
@@ -101,3 +146,73 @@ impl BackendHealth {
         tokio::spawn(typeless(delay));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::reconnect::ReconnectConfiguration;
+    use futures::future::lazy;
+    use std::sync::Arc;
+    use tokio::runtime::current_thread;
+    use util::clock::VirtualClock;
+
+    #[test]
+    fn test_cooloff() {
+        let clock = VirtualClock::new();
+        let mut health = BackendHealth::new(
+            "default".to_owned(),
+            "backend".to_owned(),
+            true,
+            Backoff::new(&ReconnectConfiguration {
+                connect_timeout: None,
+                cooloff: Duration::from_millis(10_000),
+                multiplier: 2.0,
+                max_cooloff: Duration::from_millis(60_000),
+            }),
+            2,
+            Arc::new(clock.clone()),
+        );
+
+        // Going into cooloff schedules a wakeup for when it's over, which needs a task to wake.
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        runtime
+            .block_on(lazy(|| {
+                health.increment_error();
+                assert!(health.is_healthy());
+                health.increment_error();
+                Ok::<_, ()>(())
+            }))
+            .unwrap();
+        assert!(!health.is_healthy());
+        let epoch = health.epoch();
+
+        // Cooloff lasts about as long as it was configured to, jittered down by no more than a tenth.
+        clock.advance(Duration::from_millis(9_000));
+        assert!(!health.is_healthy());
+        clock.advance(Duration::from_millis(1_001));
+        assert!(health.is_healthy());
+        assert_eq!(health.epoch(), epoch + 1);
+
+        // It takes a fresh set of errors to go back into cooloff, which then lasts twice as long.
+        runtime.block_on(lazy(|| Ok::<_, ()>(health.increment_error()))).unwrap();
+        assert!(health.is_healthy());
+        runtime.block_on(lazy(|| Ok::<_, ()>(health.increment_error()))).unwrap();
+        clock.advance(Duration::from_millis(18_000));
+        assert!(!health.is_healthy());
+        clock.advance(Duration::from_millis(2_001));
+        assert!(health.is_healthy());
+
+        // Once the backend is working again, the next cooloff is back to being the shortest.
+        health.record_success();
+        runtime
+            .block_on(lazy(|| {
+                health.increment_error();
+                health.increment_error();
+                Ok::<_, ()>(())
+            }))
+            .unwrap();
+        assert!(!health.is_healthy());
+        clock.advance(Duration::from_millis(10_001));
+        assert!(health.is_healthy());
+    }
+}
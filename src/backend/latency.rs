@@ -0,0 +1,155 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use util::clock::duration_as_us;
+
+// Every doubling of latency is split into this many buckets, which keeps any percentile we report
+// within about 10% of the real thing.
+const BUCKETS_PER_DOUBLING: usize = 8;
+
+// Latencies are tracked in microseconds, up to a little over an hour.
+const BUCKET_COUNT: usize = 32 * BUCKETS_PER_DOUBLING;
+
+/// A histogram of how long requests to a backend took.
+///
+/// Recording is lock-free, so every connection to a backend can share the same histogram.  Whoever
+/// is interested in the latencies periodically drains the histogram, and keeps whatever window of
+/// it they want to look at.
+pub struct LatencyHistogram {
+    buckets: Vec<AtomicUsize>,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: (0..BUCKET_COUNT).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    /// Records a single latency.
+    pub fn record(&self, latency: Duration) {
+        let bucket = get_bucket(duration_as_us(latency));
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes everything recorded since the last drain, leaving the histogram empty.
+    pub fn drain(&self) -> LatencyCounts {
+        LatencyCounts {
+            buckets: self.buckets.iter().map(|bucket| bucket.swap(0, Ordering::Relaxed)).collect(),
+        }
+    }
+}
+
+/// Latencies drained from a histogram.
+#[derive(Clone, Debug)]
+pub struct LatencyCounts {
+    buckets: Vec<usize>,
+}
+
+impl LatencyCounts {
+    /// Gets an empty set of counts.
+    pub fn empty() -> LatencyCounts {
+        LatencyCounts {
+            buckets: vec![0; BUCKET_COUNT],
+        }
+    }
+
+    /// Adds the given counts to these ones.
+    pub fn merge(&mut self, other: &LatencyCounts) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+    }
+
+    /// Gets how many latencies were counted.
+    pub fn total(&self) -> usize { self.buckets.iter().sum() }
+
+    /// Gets the given percentile, from 0 to 100, of the counted latencies.
+    ///
+    /// The latency reported is the upper bound of the bucket the percentile falls in.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = ((percentile / 100.0) * total as f64).ceil().max(1.0) as usize;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_micros(get_bucket_upper_bound(bucket)));
+            }
+        }
+
+        None
+    }
+}
+
+fn get_bucket(latency_us: u64) -> usize {
+    if latency_us == 0 {
+        return 0;
+    }
+
+    let bucket = ((latency_us as f64).log2() * BUCKETS_PER_DOUBLING as f64) as usize;
+    bucket.min(BUCKET_COUNT - 1)
+}
+
+fn get_bucket_upper_bound(bucket: usize) -> u64 { 2f64.powf((bucket + 1) as f64 / BUCKETS_PER_DOUBLING as f64) as u64 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let histogram = LatencyHistogram::new();
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+
+        let counts = histogram.drain();
+        assert_eq!(counts.total(), 100);
+
+        // Percentiles are only as good as the bucket they land in.
+        let p50 = counts.percentile(50.0).unwrap();
+        assert!(p50 >= Duration::from_millis(50) && p50 <= Duration::from_millis(55), "p50 was {:?}", p50);
+        let p99 = counts.percentile(99.0).unwrap();
+        assert!(p99 >= Duration::from_millis(99) && p99 <= Duration::from_millis(109), "p99 was {:?}", p99);
+
+        // Draining leaves nothing behind.
+        assert_eq!(histogram.drain().percentile(99.0), None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let histogram = LatencyHistogram::new();
+        histogram.record(Duration::from_millis(1));
+        let mut counts = histogram.drain();
+
+        histogram.record(Duration::from_secs(1));
+        counts.merge(&histogram.drain());
+        assert_eq!(counts.total(), 2);
+        assert!(counts.percentile(100.0).unwrap() >= Duration::from_secs(1));
+    }
+}
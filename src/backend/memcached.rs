@@ -0,0 +1,413 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::{
+    message_queue::MessageState,
+    processor::{Processor, ProcessorError, TcpStreamFuture},
+    responses::ResponseSizeTracker,
+    source::connect,
+    subscription::SubscriptionRequest,
+    transform::{KeyTransforms, OriginalKeys},
+};
+use bytes::BytesMut;
+use common::{EnqueuedRequests, Message};
+use conf::PoolOptions;
+use errors::CreationError;
+use futures::{future::ok, prelude::*};
+use itoa;
+use protocol::{
+    errors::ProtocolError,
+    memcached::{self, MemcachedCommand, MemcachedMessage, MemcachedTransport},
+};
+use service::ClientStats;
+use std::{
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use util::{ClientStream, ProcessFuture};
+
+// Storage commands with this expiration time never expire.
+const MEMCACHED_NO_EXPIRY: &[u8] = b"0";
+
+// Health checks look this key up.
+const MEMCACHED_HEALTH_CHECK_KEY: &[u8] = b"synchrotron:health_check";
+
+#[derive(Clone, Default)]
+pub struct MemcachedProcessor;
+
+impl MemcachedProcessor {
+    pub fn new() -> MemcachedProcessor { MemcachedProcessor }
+}
+
+impl Processor for MemcachedProcessor {
+    type Message = MemcachedMessage;
+    type Transport = MemcachedTransport<ClientStream>;
+
+    fn fragment_messages(
+        &self, msgs: Vec<Self::Message>,
+    ) -> Result<Vec<(MessageState, Self::Message)>, ProcessorError> {
+        let mut fragments = Vec::new();
+
+        for msg in msgs {
+            match msg.get_command() {
+                // Multi-key retrievals are split up by key, so that each key goes to the backend
+                // that holds it, and the values are stitched back together when they're all in.
+                Some(cmd) if cmd.is_retrieval() && msg.get_keys().len() > 1 => {
+                    let keys = msg.get_keys();
+                    let cmd_type = BytesMut::from(cmd.name().as_bytes());
+                    let total_fragments = keys.len();
+
+                    for (i, key) in keys.iter().enumerate() {
+                        let state = MessageState::Fragmented(cmd_type.clone(), i, total_fragments);
+                        fragments.push((state, MemcachedMessage::from_command(cmd, &[*key])));
+                    }
+                },
+                _ => {
+                    let state = if msg.is_inline() {
+                        MessageState::Inline
+                    } else {
+                        MessageState::Standalone
+                    };
+                    fragments.push((state, msg));
+                },
+            }
+        }
+
+        Ok(fragments)
+    }
+
+    fn get_fragment_count(&self, msg: &Self::Message) -> usize {
+        match msg.get_command() {
+            Some(cmd) if cmd.is_retrieval() => msg.get_keys().len().max(1),
+            _ => 1,
+        }
+    }
+
+    fn defragment_messages(&self, msgs: Vec<(MessageState, Self::Message)>) -> Result<Self::Message, ProcessorError> {
+        let mut values = BytesMut::new();
+
+        for (state, msg) in msgs {
+            match state {
+                MessageState::Fragmented(_, _, _) => {},
+                _ => {
+                    return Err(ProcessorError::DefragmentError(
+                        "tried to defragment messages, but got non-fragmented message in list".to_owned(),
+                    ));
+                },
+            }
+
+            // Memcached gives up on the whole lookup if it can't look up any one of the keys, so
+            // if we couldn't get one of them, we pass along why, rather than a partial response.
+            match msg.get_values() {
+                Some(buf) => values.extend_from_slice(buf),
+                None => return Ok(msg),
+            }
+        }
+
+        Ok(MemcachedMessage::from_values(&values))
+    }
+
+    fn get_read_request(&self, key: &[u8]) -> Self::Message {
+        MemcachedMessage::from_command(MemcachedCommand::Get, &[key])
+    }
+
+    fn is_missing_ttl(&self, msg: &Self::Message) -> bool {
+        match msg.get_command() {
+            Some(cmd) if cmd.is_storage() => msg.get_arguments().get(2) == Some(&MEMCACHED_NO_EXPIRY),
+            _ => false,
+        }
+    }
+
+    fn get_created_key<'a>(&self, msg: &'a Self::Message) -> &'a [u8] { msg.key() }
+
+    fn get_default_ttl_request(&self, key: &[u8], ttl_secs: u64) -> Self::Message {
+        // Memcached has no way to only set an expiration time if there isn't one, but this is only
+        // sent right after a write that didn't give one, so touching the key is close enough.
+        let mut ttl_buf = [b'\0'; 20];
+        let n = itoa::write(&mut ttl_buf[..], ttl_secs).unwrap();
+
+        MemcachedMessage::from_command(MemcachedCommand::Touch, &[key, &ttl_buf[..n]])
+    }
+
+    fn is_write(&self, msg: &Self::Message) -> bool {
+        match msg.get_command() {
+            Some(cmd) => !cmd.is_retrieval(),
+            None => false,
+        }
+    }
+
+    fn is_read_only(&self, msg: &Self::Message) -> bool {
+        match msg.get_command() {
+            Some(cmd) => cmd.is_retrieval(),
+            None => false,
+        }
+    }
+
+    // Every memcached command is answered as soon as the server gets to it.
+    fn is_blocking(&self, _msg: &Self::Message) -> bool { false }
+
+    fn get_local_response(&self, _msg: &Self::Message) -> Option<Self::Message> { None }
+
+    // Memcached has nothing to subscribe to, so nothing is ever pushed to a client.
+    fn get_subscription<'a>(&self, _msg: &'a Self::Message) -> Option<SubscriptionRequest<'a>> { None }
+
+    fn read_pushed_message(&self, _buf: &mut BytesMut) -> Result<Option<(BytesMut, Option<usize>)>, ProtocolError> {
+        Ok(None)
+    }
+
+    fn get_delete_request(&self, key: &[u8]) -> Self::Message {
+        MemcachedMessage::from_command(MemcachedCommand::Delete, &[key])
+    }
+
+    fn get_health_check_request(&self) -> Self::Message {
+        // There's no command we know of that only checks in with the server, so we look up a key
+        // instead: a miss is just as good an answer as a hit.
+        MemcachedMessage::from_command(MemcachedCommand::Get, &[MEMCACHED_HEALTH_CHECK_KEY])
+    }
+
+    fn is_error_response(&self, msg: &Self::Message) -> bool { msg.is_error() }
+
+    fn get_lookup_keys(&self, msg: &Self::Message) -> Option<usize> {
+        match msg.get_command() {
+            Some(cmd) if cmd.is_retrieval() => Some(msg.get_keys().len()),
+            _ => None,
+        }
+    }
+
+    fn count_lookup_hits(&self, keys: usize, msg: &Self::Message) -> Option<(usize, usize)> {
+        let hits = msg.count_values()?.min(keys);
+        Some((hits, keys - hits))
+    }
+
+    fn forward_keys(
+        &self, msg: Self::Message, transforms: &KeyTransforms, original: &mut OriginalKeys,
+    ) -> Self::Message {
+        msg.map_keys(|key| original.forward(transforms, key))
+    }
+
+    fn restore_keys(&self, msg: Self::Message, original: &OriginalKeys) -> Self::Message {
+        msg.map_keys(|key| original.restore(key))
+    }
+
+    fn get_recorded_frame(&self, msg: &Self::Message, redact_values: bool) -> Option<BytesMut> {
+        match msg {
+            MemcachedMessage::Request(_, buf, _) => {
+                let mut frame = buf.clone();
+                if redact_values {
+                    // The command line is kept as-is, so that a replay is routed the same way.
+                    if let Some((start, end)) = msg.get_value_range() {
+                        for b in &mut frame[start..end] {
+                            *b = b'x';
+                        }
+                    }
+                }
+                Some(frame)
+            },
+            _ => None,
+        }
+    }
+
+    fn get_client_name(&self, _msg: &Self::Message) -> Option<String> { None }
+
+    fn has_routing_hint(&self, _msg: &Self::Message) -> bool { false }
+
+    fn get_command_id(&self, msg: &Self::Message) -> Option<usize> { msg.get_command().map(|cmd| cmd.id()) }
+
+    fn get_command_name(&self, msg: &Self::Message) -> Option<&'static str> { msg.get_command().map(|cmd| cmd.name()) }
+
+    fn find_command_id(&self, name: &str) -> Option<usize> {
+        memcached::get_command_index(name.to_lowercase().as_bytes())
+    }
+
+    fn get_command_count(&self) -> usize { memcached::get_command_count() }
+
+    fn get_error_message(&self, e: Box<Error>) -> Self::Message { MemcachedMessage::from_server_error(e.description()) }
+
+    fn get_error_message_str(&self, e: &str) -> Self::Message { MemcachedMessage::from_server_error(e) }
+
+    // Memcached has nothing like a transaction, so a generic error always does.
+    fn get_failure_response(&self, _msg: &Self::Message) -> Option<Self::Message> { None }
+
+    fn get_transport(&self, client: ClientStream, _stats: Arc<ClientStats>) -> Self::Transport {
+        MemcachedTransport::new(client)
+    }
+
+    // Memcached connections need no setting up, so every pool can share the same processor.
+    fn for_pool(&self, _options: &PoolOptions) -> Result<Self, CreationError> { Ok(self.clone()) }
+
+    fn preconnect(&self, addr: &SocketAddr, source: Option<IpAddr>, _noreply: bool) -> ProcessFuture {
+        // Memcached can only be told to skip replies command by command, so there's nothing to set
+        // up for a connection that doesn't want them, and they're read like any other.
+        let inner = connect(addr, source).map_err(ProtocolError::IoError);
+        ProcessFuture::new(inner)
+    }
+
+    fn process(
+        &self, req: EnqueuedRequests<Self::Message>, stream: TcpStreamFuture, responses: Arc<ResponseSizeTracker>,
+    ) -> ProcessFuture {
+        let inner = stream
+            .and_then(move |server| memcached::write_messages(server, req))
+            .and_then(move |(server, msgs, _n)| memcached::read_messages(server, msgs, responses))
+            .and_then(move |(server, _n)| ok(server));
+        ProcessFuture::new(inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::transform::{KeyTransform, Strip};
+    use std::io::Cursor;
+
+    fn get_request(cmd: MemcachedCommand, args: &[&str]) -> MemcachedMessage {
+        let args = args.iter().map(|arg| arg.as_bytes()).collect::<Vec<_>>();
+        MemcachedMessage::from_command(cmd, &args)
+    }
+
+    fn get_client_messages(buf: &[u8]) -> Vec<MemcachedMessage> {
+        let transport = MemcachedTransport::new(Cursor::new(buf.to_vec()));
+        transport.collect().wait().expect("transport should not have failed")
+    }
+
+    fn get_response(buf: &[u8]) -> MemcachedMessage { MemcachedMessage::Response(BytesMut::from(buf)) }
+
+    #[test]
+    fn multi_key_get_round_trip() {
+        let processor = MemcachedProcessor::new();
+        let request = get_request(MemcachedCommand::Gets, &["foo", "bar", "baz"]);
+        assert_eq!(processor.get_fragment_count(&request), 3);
+
+        let fragments = processor.fragment_messages(vec![request]).unwrap();
+        let keys = fragments.iter().map(|(_, msg)| msg.key()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![&b"foo"[..], b"bar", b"baz"]);
+        assert_eq!(fragments[1].0, MessageState::Fragmented(BytesMut::from(&b"gets"[..]), 1, 3));
+        assert_eq!(&fragments[1].1.get_buf()[..], &b"gets bar\r\n"[..]);
+
+        // Each key gets its own response, and they're stitched back together in the order the keys
+        // were asked for.
+        let responses = vec![
+            get_response(b"VALUE foo 0 1 7\r\na\r\nEND\r\n"),
+            get_response(b"END\r\n"),
+            get_response(b"VALUE baz 0 1 9\r\nc\r\nEND\r\n"),
+        ];
+        let fragments = fragments
+            .into_iter()
+            .zip(responses)
+            .map(|((state, _), response)| (state, response))
+            .collect();
+        let response = processor.defragment_messages(fragments).unwrap();
+        assert_eq!(
+            response,
+            get_response(b"VALUE foo 0 1 7\r\na\r\nVALUE baz 0 1 9\r\nc\r\nEND\r\n")
+        );
+        assert_eq!(processor.count_lookup_hits(3, &response), Some((2, 1)));
+    }
+
+    #[test]
+    fn keys_are_restored_as_sent() {
+        let processor = MemcachedProcessor::new();
+        let strip = Box::new(Strip::new(b"legacy:")) as Box<KeyTransform + Send + Sync>;
+        let transforms = KeyTransforms::new(vec![strip]);
+        let mut original = OriginalKeys::default();
+
+        let request = get_request(MemcachedCommand::Get, &["legacy:foo", "bar"]);
+        let request = processor.forward_keys(request, &transforms, &mut original);
+        assert_eq!(&request.get_buf()[..], &b"get foo bar\r\n"[..]);
+
+        // Only the key that was changed on its way to the backend is given back under another name.
+        let response = get_response(b"VALUE foo 0 1\r\na\r\nVALUE bar 0 1\r\nb\r\nEND\r\n");
+        assert_eq!(
+            processor.restore_keys(response, &original),
+            get_response(b"VALUE legacy:foo 0 1\r\na\r\nVALUE bar 0 1\r\nb\r\nEND\r\n")
+        );
+    }
+
+    #[test]
+    fn multi_key_get_with_error() {
+        let processor = MemcachedProcessor::new();
+        let request = get_request(MemcachedCommand::Get, &["foo", "bar"]);
+        let fragments = processor.fragment_messages(vec![request]).unwrap();
+
+        let error = processor.get_error_message_str("backend timed out");
+        let responses = vec![get_response(b"END\r\n"), error.clone()];
+        let fragments = fragments
+            .into_iter()
+            .zip(responses)
+            .map(|((state, _), response)| (state, response))
+            .collect();
+        assert_eq!(processor.defragment_messages(fragments).unwrap(), error);
+        assert_eq!(processor.count_lookup_hits(2, &error), None);
+    }
+
+    #[test]
+    fn single_key_requests_are_not_fragmented() {
+        let processor = MemcachedProcessor::new();
+        let request = get_request(MemcachedCommand::Get, &["foo"]);
+        let fragments = processor.fragment_messages(vec![request.clone()]).unwrap();
+        assert_eq!(fragments, vec![(MessageState::Standalone, request)]);
+
+        let error = processor.get_error_message_str("nope");
+        let fragments = processor.fragment_messages(vec![error.clone()]).unwrap();
+        assert_eq!(fragments, vec![(MessageState::Inline, error)]);
+    }
+
+    #[test]
+    fn requests_without_expiry() {
+        let processor = MemcachedProcessor::new();
+        let msgs = get_client_messages(b"set foo 0 0 3\r\nbar\r\nset foo 0 60 3\r\nbar\r\n");
+
+        assert!(processor.is_write(&msgs[0]));
+        assert!(processor.is_missing_ttl(&msgs[0]));
+        assert!(!processor.is_missing_ttl(&msgs[1]));
+        assert!(!processor.is_write(&processor.get_read_request(b"foo")));
+        assert!(processor.is_read_only(&processor.get_read_request(b"foo")));
+        assert!(!processor.is_read_only(&msgs[0]));
+
+        let ttl_req = processor.get_default_ttl_request(b"foo", 3600);
+        assert_eq!(&ttl_req.get_buf()[..], &b"touch foo 3600\r\n"[..]);
+        assert_eq!(ttl_req.key(), b"foo");
+    }
+
+    #[test]
+    fn recorded_frames_are_redacted() {
+        let processor = MemcachedProcessor::new();
+        let input = b"set foo 0 0 6\r\nfoobar\r\n";
+        let msgs = get_client_messages(input);
+
+        let frame = processor.get_recorded_frame(&msgs[0], false).unwrap();
+        assert_eq!(&frame[..], &input[..]);
+
+        let frame = processor.get_recorded_frame(&msgs[0], true).unwrap();
+        assert_eq!(&frame[..], &b"set foo 0 0 6\r\nxxxxxx\r\n"[..]);
+
+        let error = processor.get_error_message_str("nope");
+        assert_eq!(processor.get_recorded_frame(&error, false), None);
+    }
+
+    #[test]
+    fn command_ids() {
+        let processor = MemcachedProcessor::new();
+        let request = get_request(MemcachedCommand::Delete, &["foo"]);
+
+        assert_eq!(processor.find_command_id("DELETE"), processor.get_command_id(&request));
+        assert!(processor.get_command_id(&request).unwrap() < processor.get_command_count());
+        assert_eq!(processor.find_command_id("flush_all"), None);
+    }
+}
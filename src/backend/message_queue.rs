@@ -20,8 +20,13 @@
 use backend::processor::{Processor, ProcessorError};
 use bytes::BytesMut;
 use common::{AssignedRequests, AssignedResponse, Message, MessageResponse};
+use futures::prelude::*;
 use slab::Slab;
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
 
 /// Message state of queued messages.
 #[derive(Debug, PartialEq)]
@@ -37,6 +42,12 @@ pub enum MessageState {
     /// to send as soon as they're enqueued.
     Inline,
 
+    /// An unfragmented, standalone message that's answered locally, but only after a delay.
+    ///
+    /// Like inline messages, these are never sent to a backend.  Their slot is filled when the
+    /// delay has passed, holding up the responses behind it, but nobody else's.
+    Delayed(Duration),
+
     /// A fragmented message.
     ///
     /// This represents a discrete fragment of a parent message.  The buffer represents arbitrary
@@ -46,23 +57,6 @@ pub enum MessageState {
     /// The integers provide the index of the given fragment and the overall count of fragments
     /// within the parent message.
     Fragmented(BytesMut, usize, usize),
-
-    /// A streaming fragmented message.
-    ///
-    /// This represents a discrete fragment of a parent message.  The key difference is that the
-    /// parent message is "streamable."  This is usually the case for get operations, where, as
-    /// long as the fragments are in order, they can be streamed back to the client as they're
-    /// available.  This is in contrast to some other fragmented messages, where the response must
-    /// be generated by the sum of all the parts.
-    ///
-    /// The optional buffer represents a header that can be sent before the actual fragment.  This
-    /// allows sending any response data that is needed to coalesce the fragments into a meaningful
-    /// response to the client.
-    ///
-    /// The boolean marks whether this streaming fragment represents the end of the response to the
-    /// client for a given input message.  For example, if the client sent in a multi-get that
-    /// asked for 10 keys, the 10th streaming fragment would be the "end" of the response.
-    StreamingFragmented(Option<BytesMut>, bool),
 }
 
 pub struct MessageQueue<P>
@@ -75,6 +69,13 @@ where
     // Holds all message slots, and stores the slot IDs in order of the messages tied to them.
     slot_order: VecDeque<(usize, MessageState)>,
     slots: Slab<Option<P::Message>>,
+
+    // Responses for slots whose messages need something more specific than a generic error if
+    // they fail.
+    failures: HashMap<usize, P::Message>,
+
+    // Delayed messages, and the slots they fill when their delay has passed.
+    delays: Vec<(usize, Delay, P::Message)>,
 }
 
 impl<P> MessageQueue<P>
@@ -87,9 +88,20 @@ where
             processor,
             slot_order: VecDeque::new(),
             slots: Slab::new(),
+            failures: HashMap::new(),
+            delays: Vec::new(),
         }
     }
 
+    /// Gets the processor used by this queue.
+    pub fn processor(&self) -> &P { &self.processor }
+
+    /// Gets the number of messages waiting on responses, or to be sent back to the client.
+    pub fn pending(&self) -> usize { self.slot_order.len() }
+
+    /// Gets the number of delayed messages whose delay has yet to pass.
+    pub fn pending_delays(&self) -> usize { self.delays.len() }
+
     fn is_slot_ready(&self, slot: usize) -> bool {
         match self.slot_order.get(slot) {
             None => false,
@@ -102,22 +114,21 @@ where
         }
     }
 
-    fn get_next_response(&mut self) -> Result<Option<(BytesMut, u64)>, ProcessorError> {
-        // If we have an immediately available response aka a standalone message or streaming
-        // fragment, just return it.
+    fn get_next_response(&mut self) -> Option<(BytesMut, u64)> {
+        // If we have an immediately available response aka a standalone message, just return it.
         let has_immediate = match self.slot_order.front() {
-            None => return Ok(None),
+            None => return None,
             Some((slot_id, state)) => {
                 match self.slots.get(*slot_id) {
                     Some(_) => {
                         match state {
                             MessageState::Standalone
                             | MessageState::Inline
-                            | MessageState::StreamingFragmented(_, _) => true,
+                            | MessageState::Delayed(_) => true,
                             MessageState::Fragmented(_, _, _) => false,
                         }
                     },
-                    None => return Ok(None),
+                    None => return None,
                 }
             },
         };
@@ -127,21 +138,11 @@ where
             let slot = self.slots.remove(slot_id).expect("failed to remove slot");
 
             let (buf, count) = match state {
-                MessageState::Standalone | MessageState::Inline => (slot.into_buf(), 1),
-                MessageState::StreamingFragmented(header, is_last) => {
-                    let count = if is_last { 1 } else { 0 };
-                    match header {
-                        Some(mut header_buf) => {
-                            header_buf.unsplit(slot.into_buf());
-                            (header_buf, count)
-                        },
-                        None => (slot.into_buf(), count),
-                    }
-                },
+                MessageState::Standalone | MessageState::Inline | MessageState::Delayed(_) => (slot.into_buf(), 1),
                 _ => unreachable!(),
             };
 
-            return Ok(Some((buf, count)));
+            return Some((buf, count));
         }
 
         // Now we know that the next slot has been fulfilled, and that it's a fragmented message.
@@ -159,7 +160,7 @@ where
 
         for index in 0..fragment_count {
             if !self.is_slot_ready(index) {
-                return Ok(None);
+                return None;
             }
         }
 
@@ -171,11 +172,20 @@ where
             fragments.push((state, msg));
         }
 
-        let msg = self.processor.defragment_messages(fragments)?;
-        Ok(Some((msg.into_buf(), 1)))
+        // If the replies can't be put back together, the client still has to get an answer for
+        // the command, or it would be left waiting on it forever.
+        let msg = match self.processor.defragment_messages(fragments) {
+            Ok(msg) => msg,
+            Err(e) => self.processor.get_error_message_str(&e.to_string()),
+        };
+        Some((msg.into_buf(), 1))
     }
 
     pub fn enqueue(&mut self, msgs: Vec<P::Message>) -> Result<AssignedRequests<P::Message>, ProcessorError> {
+        if msgs.is_empty() {
+            return Ok(Vec::new());
+        }
+
         let fmsgs = self.processor.fragment_messages(msgs)?;
 
         let mut amsgs = Vec::new();
@@ -183,9 +193,20 @@ where
             if msg_state == MessageState::Inline {
                 let slot_id = self.slots.insert(Some(msg));
                 self.slot_order.push_back((slot_id, msg_state));
+            } else if let MessageState::Delayed(delay) = msg_state {
+                let slot_id = self.slots.insert(None);
+                self.slot_order.push_back((slot_id, msg_state));
+                // Delays are capped well short of anything that could overflow, but if one ever
+                // slipped through, answering it right away beats panicking.
+                let now = Instant::now();
+                let deadline = now.checked_add(delay).unwrap_or(now);
+                self.delays.push((slot_id, Delay::new(deadline), msg));
             } else {
                 let slot_id = self.slots.insert(None);
                 self.slot_order.push_back((slot_id, msg_state));
+                if let Some(failure) = self.processor.get_failure_response(&msg) {
+                    self.failures.insert(slot_id, failure);
+                }
                 amsgs.push((slot_id, msg));
             }
         }
@@ -197,16 +218,39 @@ where
     where
         I: IntoIterator<Item = AssignedResponse<P::Message>>,
     {
-        for (slot, response) in batch.into_iter() {
-            let slot = self.slots.get_mut(slot).unwrap();
+        for (slot_id, response) in batch.into_iter() {
+            let processor = &self.processor;
+            let failure = self.failures.remove(&slot_id);
+            let slot = self.slots.get_mut(slot_id).unwrap();
             match response {
                 MessageResponse::Complete(msg) => {
                     slot.replace(msg);
                 },
                 MessageResponse::Failed => {
-                    let err = self.processor.get_error_message_str("failed to receive response");
+                    let err = failure.unwrap_or_else(|| processor.get_error_message_str("failed to receive response"));
                     slot.replace(err);
                 },
+                MessageResponse::TimedOut => {
+                    let err = failure.unwrap_or_else(|| processor.get_error_message_str("proxy timeout"));
+                    slot.replace(err);
+                },
+            }
+        }
+    }
+
+    /// Fills the slots of any delayed messages whose delay has passed.
+    ///
+    /// The current task is woken up when the next of the remaining delays passes.
+    pub fn poll_delays(&mut self) {
+        let mut i = 0;
+        while i < self.delays.len() {
+            match self.delays[i].1.poll() {
+                Ok(Async::NotReady) => i += 1,
+                // If the timer has gone away, there's no sense in making the client wait forever.
+                _ => {
+                    let (slot_id, _, msg) = self.delays.swap_remove(i);
+                    self.slots.get_mut(slot_id).unwrap().replace(msg);
+                },
             }
         }
     }
@@ -216,10 +260,113 @@ where
             return None;
         }
 
-        if let Ok(inner) = self.get_next_response() {
-            inner
-        } else {
-            None
-        }
+        self.get_next_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::redis::RedisProcessor;
+    use protocol::redis::{RedisMessage, RedisTransportConfig};
+
+    fn get_queue() -> MessageQueue<RedisProcessor> {
+        MessageQueue::new(RedisProcessor::new(RedisTransportConfig::default()))
+    }
+
+    #[test]
+    fn test_enqueue_empty() {
+        let mut queue = get_queue();
+        assert!(queue.enqueue(Vec::new()).unwrap().is_empty());
+        assert_eq!(queue.pending(), 0);
+        assert_eq!(queue.get_sendable_buf(), None);
+    }
+
+    #[test]
+    fn test_enqueue_after_empty() {
+        let mut queue = get_queue();
+        assert!(queue.enqueue(Vec::new()).unwrap().is_empty());
+
+        let assigned = queue.enqueue(vec![RedisMessage::from_inline("GET foo")]).unwrap();
+        assert_eq!(assigned.len(), 1);
+        assert_eq!(queue.pending(), 1);
+
+        let (slot, _) = assigned[0];
+        queue.fulfill(vec![(slot, MessageResponse::Complete(RedisMessage::Null))]);
+        assert_eq!(queue.get_sendable_buf(), Some((BytesMut::from(&b"$-1\r\n"[..]), 1)));
+        assert_eq!(queue.pending(), 0);
+    }
+
+    #[test]
+    fn test_failed_fragment() {
+        let mut queue = get_queue();
+        let assigned = queue
+            .enqueue(vec![RedisMessage::from_inline("MSET a 1 b 2"), RedisMessage::from_inline("GET c")])
+            .unwrap();
+        assert_eq!(assigned.len(), 3);
+
+        // A fragment whose backend never answered fails the whole command, with a single reply,
+        // and doesn't hold up the replies behind it.
+        let slots = assigned.iter().map(|(slot, _)| *slot).collect::<Vec<_>>();
+        queue.fulfill(vec![
+            (slots[2], MessageResponse::Complete(RedisMessage::Null)),
+            (slots[0], MessageResponse::Complete(RedisMessage::OK)),
+            (slots[1], MessageResponse::Failed),
+        ]);
+        assert_eq!(
+            queue.get_sendable_buf(),
+            Some((BytesMut::from(&b"-ERR failed to receive response\r\n"[..]), 1))
+        );
+        assert_eq!(queue.get_sendable_buf(), Some((BytesMut::from(&b"$-1\r\n"[..]), 1)));
+        assert_eq!(queue.pending(), 0);
+    }
+
+    #[test]
+    fn test_timed_out_fragment() {
+        let mut queue = get_queue();
+        let assigned = queue
+            .enqueue(vec![RedisMessage::from_inline("MGET a b"), RedisMessage::from_inline("GET c")])
+            .unwrap();
+        assert_eq!(assigned.len(), 3);
+
+        // Only the command with a fragment that timed out is answered with the timeout.
+        let slots = assigned.iter().map(|(slot, _)| *slot).collect::<Vec<_>>();
+        queue.fulfill(vec![
+            (slots[0], MessageResponse::Complete(RedisMessage::Null)),
+            (slots[1], MessageResponse::TimedOut),
+            (slots[2], MessageResponse::Complete(RedisMessage::Null)),
+        ]);
+        assert_eq!(
+            queue.get_sendable_buf(),
+            Some((BytesMut::from(&b"-ERR proxy timeout\r\n"[..]), 1))
+        );
+        assert_eq!(queue.get_sendable_buf(), Some((BytesMut::from(&b"$-1\r\n"[..]), 1)));
+        assert_eq!(queue.pending(), 0);
+    }
+
+    #[test]
+    fn test_failed_transaction() {
+        let mut queue = get_queue();
+        let cmds = vec![RedisMessage::from_inline("INCR foo")];
+        let transaction = RedisMessage::Transaction(BytesMut::from(&b"foo"[..]), cmds);
+        let assigned = queue.enqueue(vec![transaction, RedisMessage::from_inline("GET c")]).unwrap();
+        assert_eq!(assigned.len(), 2);
+
+        // A transaction that never made it through is reported as discarded, rather than as a
+        // generic failure, but nothing else is.
+        let slots = assigned.iter().map(|(slot, _)| *slot).collect::<Vec<_>>();
+        queue.fulfill(vec![(slots[0], MessageResponse::Failed), (slots[1], MessageResponse::Failed)]);
+        assert_eq!(
+            queue.get_sendable_buf(),
+            Some((
+                BytesMut::from(&b"-EXECABORT Transaction discarded because of previous errors.\r\n"[..]),
+                1
+            ))
+        );
+        assert_eq!(
+            queue.get_sendable_buf(),
+            Some((BytesMut::from(&b"-ERR failed to receive response\r\n"[..]), 1))
+        );
+        assert_eq!(queue.pending(), 0);
     }
 }
@@ -0,0 +1,195 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{
+    distributor::{configure_distributor, BackendDescriptor, Distributor},
+    hasher::{configure_hasher, KeyHasher},
+};
+use backend::{processor::Processor, transform::KeyTransforms, PoolError, ResponseFuture};
+use common::{AssignedResponses, EnqueuedRequest, Message, MessageResponse};
+use conf::{MigrationConfiguration, PoolOptions};
+use errors::CreationError;
+use metrics::MetricSink;
+use std::collections::HashMap;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// The placement that a pool is migrating its keys away from.
+///
+/// While migrating, requests are routed by the pool's own distributor and hasher, which give the
+/// new placement.  The old placement is only used to find keys that haven't been moved yet, and to
+/// clean up after writes.
+///
+/// If keys were stored under other names at the old placement, the migration has key transforms
+/// of its own, which take the place of the pool's for everything sent there.
+pub struct Migration {
+    distributor: Box<Distributor + Send + Sync>,
+    hasher: Box<KeyHasher + Send + Sync>,
+    transforms: Option<KeyTransforms>,
+    read_fallback: bool,
+    delete_old: bool,
+}
+
+impl Migration {
+    pub fn from_config(
+        config: &MigrationConfiguration, default_hash: &str, options: &PoolOptions,
+    ) -> Result<Migration, CreationError> {
+        let dist_type = config.from_distribution.to_lowercase();
+        let distributor = configure_distributor(&dist_type, options)?;
+
+        let hash_type = config
+            .from_hash
+            .as_ref()
+            .map(|s| s.to_lowercase())
+            .unwrap_or_else(|| default_hash.to_owned());
+        let hasher = configure_hasher(&hash_type)?;
+        debug!("[listener] migrating from distributor '{}' with hasher '{}'", dist_type, hash_type);
+
+        let transforms = match config.from_key_transforms {
+            Some(ref entries) => Some(KeyTransforms::from_entries("migration.from_key_transforms", entries)?),
+            None => None,
+        };
+
+        Ok(Migration {
+            distributor,
+            hasher,
+            transforms,
+            read_fallback: config.read_fallback.unwrap_or(true),
+            delete_old: config.delete_old.unwrap_or(false),
+        })
+    }
+
+    /// Whether or not lookups that miss should be retried against the old placement.
+    pub fn read_fallback(&self) -> bool { self.read_fallback }
+
+    /// Whether or not writes should delete the key from the old placement.
+    pub fn delete_old(&self) -> bool { self.delete_old }
+
+    /// Seeds the old placement with the given backends.
+    pub fn update(&mut self, backends: Vec<BackendDescriptor>) { self.distributor.update(backends); }
+
+    /// Gets the key transforms of the old placement, given the pool's own.
+    pub fn transforms<'a>(&'a self, pool: &'a KeyTransforms) -> &'a KeyTransforms {
+        self.transforms.as_ref().unwrap_or(pool)
+    }
+
+    /// Chooses the backend that the given key from a client was placed on before the migration.
+    pub fn choose(&self, key: &[u8], pool: &KeyTransforms) -> usize {
+        let hash_key = self.transforms(pool).hash_key(key);
+        self.distributor.choose(self.hasher.hash(&hash_key))
+    }
+}
+
+/// A lookup to send to the old placement of its key, on behalf of a pending pool response.
+pub struct FallbackRequest<T: Message + Clone> {
+    pub backend_idx: usize,
+    pub request: EnqueuedRequest<T>,
+}
+
+/// The lookups in a pool request that can fall back to the old placement if they miss.
+pub struct MigrationFallback<P>
+where
+    P: Processor,
+{
+    processor: P,
+    requests: HashMap<usize, (usize, P::Message)>,
+    tx: UnboundedSender<FallbackRequest<P::Message>>,
+    sink: MetricSink,
+}
+
+impl<P> MigrationFallback<P>
+where
+    P: Processor + Send + 'static,
+    P::Message: Message + Send + 'static,
+{
+    pub fn new(
+        processor: P, requests: HashMap<usize, (usize, P::Message)>, tx: UnboundedSender<FallbackRequest<P::Message>>,
+        sink: MetricSink,
+    ) -> MigrationFallback<P> {
+        MigrationFallback {
+            processor,
+            requests,
+            tx,
+            sink,
+        }
+    }
+
+    /// Sends a lookup to the old placement for every tracked lookup that missed.
+    ///
+    /// Returns `None` if nothing missed, otherwise a future of the responses from the old placement.
+    pub fn start(&mut self, responses: &AssignedResponses<P::Message>) -> Option<ResponseFuture<P, PoolError>> {
+        let mut pending = Vec::new();
+        for (id, response) in responses {
+            let missed = match response {
+                MessageResponse::Complete(msg) => self.processor.count_lookup_hits(1, msg) == Some((0, 1)),
+                MessageResponse::Failed | MessageResponse::TimedOut => false,
+            };
+            if !missed {
+                continue;
+            }
+
+            if let Some((backend_idx, request)) = self.requests.remove(id) {
+                let mut request = EnqueuedRequest::new(*id, request);
+                if let Some(rx) = request.get_response_rx() {
+                    pending.push((*id, rx));
+                }
+
+                // If the pool is gone, the request is dropped, which fails it, and we keep the miss.
+                let _ = self.tx.try_send(FallbackRequest { backend_idx, request });
+            }
+        }
+
+        if pending.is_empty() {
+            return None;
+        }
+
+        self.sink.update_count("migration_fallbacks", pending.len() as i64);
+        Some(ResponseFuture::new(pending))
+    }
+
+    /// Replaces the response of every lookup that found its key at the old placement.
+    pub fn finish(&self, responses: &mut AssignedResponses<P::Message>, fallbacks: AssignedResponses<P::Message>) {
+        let mut found = HashMap::new();
+        let mut misses = 0;
+        for (id, fallback) in fallbacks {
+            if let MessageResponse::Complete(msg) = fallback {
+                match self.processor.count_lookup_hits(1, &msg) {
+                    Some((1, 0)) => {
+                        found.insert(id, msg);
+                    },
+                    Some(_) => misses += 1,
+                    None => {},
+                }
+            }
+        }
+
+        // Once a migration is done, nothing is left at the old placement, so these hits drop to zero.
+        self.sink.update_count("migration_fallback_hits", found.len() as i64);
+        self.sink.update_count("migration_fallback_misses", misses);
+
+        if found.is_empty() {
+            return;
+        }
+
+        for (id, response) in responses.iter_mut() {
+            if let Some(msg) = found.remove(id) {
+                *response = MessageResponse::Complete(msg);
+            }
+        }
+    }
+}
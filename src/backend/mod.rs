@@ -17,32 +17,78 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
+mod dedicated;
+pub mod demotion;
 pub mod distributor;
+pub mod drain;
 mod errors;
+mod handshake;
 pub mod hasher;
 mod health;
+pub mod latency;
+pub mod memcached;
 pub mod message_queue;
+mod migration;
+pub mod placement;
 pub mod pool;
+pub mod probe;
 pub mod processor;
+mod reconnect;
 pub mod redis;
+pub mod resolver;
+pub mod responses;
+mod retirement;
+pub mod retry;
+mod selection;
+mod source;
+pub mod startup;
+pub mod subscription;
+#[cfg(test)]
+pub mod testing;
+pub mod transform;
+pub mod ttl;
+pub mod warmup;
+pub mod weights;
 
 pub use self::errors::{BackendError, PoolError};
 
-use backend::{distributor::BackendDescriptor, health::BackendHealth, processor::Processor};
-use common::{AssignedResponses, EnqueuedRequests, Message, PendingResponses};
+use backend::{
+    dedicated::DedicatedConnector,
+    distributor::BackendDescriptor,
+    health::BackendHealth,
+    latency::LatencyHistogram,
+    processor::Processor,
+    reconnect::{Backoff, ReconnectConfiguration},
+    responses::{ResponseSizeConfiguration, ResponseSizeTracker},
+    retirement::{RetireReason, Retirement, RetirementConfiguration},
+    selection::ConnectionSelection,
+    source::source_address_from_options,
+    weights::DEFAULT_WEIGHT,
+};
+use common::{
+    AssignedResponse, AssignedResponses, EnqueuedRequest, EnqueuedRequests, Lane, Message, MessageResponse,
+    PendingResponse,
+};
+use conf::PoolOptions;
 use errors::CreationError;
 use futures::{
-    future::{join_all, ok, Either, JoinAll},
+    future::{ok, Either},
     prelude::*,
-    Poll,
+    task, Poll,
 };
-use hotmic::Sink as MetricSink;
+use libc;
+use log::Level;
+use metrics::{LatencyBuckets, MetricSink};
+use protocol::errors::ProtocolError;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::VecDeque,
+    io,
     marker::PhantomData,
-    net::SocketAddr,
-    str::FromStr,
-    time::Duration,
+    mem,
+    net::{IpAddr, SocketAddr},
+    os::unix::io::AsRawFd,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::{
     net::tcp::TcpStream,
@@ -50,10 +96,37 @@ use tokio::{
     timer::{timeout::Error as TimeoutError, Timeout},
 };
 use tower_direct_service::DirectService;
-use util::ProcessFuture;
+use util::{
+    clock::{duration_as_ms, elapsed, request_duration, ClockDelay, SharedClock},
+    FdGuard, FdTracker, LogLimiter, ProcessFuture,
+};
 
 type MaybeTimeout<F> = Either<NotTimeout<F>, Timeout<F>>;
 
+/// Peeks at a connection that we aren't expecting to hear anything on, giving back how many bytes
+/// are waiting on it, where zero means the other end has closed it.  A connection with nothing
+/// waiting on it gives back `WouldBlock`.
+fn peek_idle(stream: &TcpStream) -> io::Result<usize> {
+    let mut buf = [0u8; 1];
+    let flags = libc::MSG_PEEK | libc::MSG_DONTWAIT;
+    let n = unsafe { libc::recv(stream.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), flags) };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+const BACKEND_CONNECTING: &str = "backend connecting, try again";
+
+// How many requests can wait in the priority lane of a connection.  Any more than this wait in the
+// normal lane, so our own requests can never crowd out those of clients.
+const PRIORITY_LANE_LIMIT: usize = 16;
+
+lazy_static! {
+    static ref BACKEND_ERRORS: LogLimiter = LogLimiter::new("backend");
+}
+
 pub struct NotTimeout<F>
 where
     F: Future,
@@ -71,14 +144,64 @@ where
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> { self.inner.poll().map_err(TimeoutError::inner) }
 }
 
+/// Where a backend connection is in establishing its connection to the backend.
+enum ConnectionState {
+    /// We have no connection, and aren't trying to get one.
+    NotConnected,
+
+    /// We're waiting on a connection that was dialed in the background.
+    Connecting(ProcessFuture),
+
+    /// We're connected, although the connection may currently be in use by a batch.
+    Ready,
+}
+
+/// A connection dialed to take over from one that's due to be retired.
+struct Replacement {
+    connect: ProcessFuture,
+    stream: Option<TcpStream>,
+    fd: Option<FdGuard>,
+    reason: RetireReason,
+}
+
+/// How the connections to a backend are made and used, whether they're shared by every client or
+/// dedicated to a single request.
+#[derive(Clone)]
+pub struct ConnectionSettings {
+    source: Option<IpAddr>,
+    timeout_ms: u64,
+    noreply: bool,
+    fail_fast: bool,
+    reconnect: ReconnectConfiguration,
+    retirement: RetirementConfiguration,
+    fds: Option<Arc<FdTracker>>,
+    responses: Arc<ResponseSizeTracker>,
+}
+
 /// A backend connection.
 ///
 /// This represents a one-to-one mapping with a TCP connection to the given backend server.  This
 /// connection will independently poll the work queue for the backend and run requests when
 /// available.
 ///
+/// Connections are only dialed once there's a request for them to run.  Normally, that request
+/// waits for the connection to be established, but in fail-fast mode, the connection is dialed in
+/// the background and any requests that arrive before it's ready are immediately answered with an
+/// error, leaving it to the client to decide whether or not to retry.
+///
 /// If a backend connection encounters an error, it will terminate and notify its backend
 /// supervisor, so that it can be replaced.
+///
+/// Requests wait in one of two lanes.  Whatever is in the priority lane goes out at the front of
+/// the very next batch, ahead of the normal lane, which is how health checks get through to a
+/// backend that has a deep queue of client requests.  The priority lane only holds a handful of
+/// requests, and every batch still takes as much from the normal lane as it would otherwise, so
+/// clients are never starved by it.
+///
+/// Connections can also be retired after being open for, or sent, about as much as the pool allows.
+/// Once that happens, a replacement is dialed while the connection carries on as usual, and it's
+/// swapped in between batches once it's connected, so that nothing in flight is lost and nothing
+/// waits on the replacement.
 pub struct BackendConnection<P>
 where
     P: Processor + Send + 'static,
@@ -86,15 +209,45 @@ where
 {
     processor: P,
     address: SocketAddr,
+    source: Option<IpAddr>,
     timeout_ms: u64,
     noreply: bool,
+    fail_fast: bool,
+    reconnect: ReconnectConfiguration,
 
+    // The file descriptor of our connection, held for as long as we have one open or opening.
+    fds: Option<Arc<FdTracker>>,
+    fd: Option<FdGuard>,
+
+    state: ConnectionState,
     stream: Option<TcpStream>,
     current: Option<MaybeTimeout<ProcessFuture>>,
+    current_started: Option<Instant>,
+    current_len: usize,
     pending: VecDeque<EnqueuedRequests<P::Message>>,
+    priority: EnqueuedRequests<P::Message>,
     pending_len: usize,
 
-    sink: MetricSink<&'static str>,
+    // A connection that failed as many times in a row as the cooloff error limit allows is passed
+    // over by its backend until this passes, or until it works again, whichever comes first.  Each
+    // time it's passed over in a row, it's passed over for longer.
+    errors: usize,
+    ejected_until: Option<Instant>,
+    backoff: Backoff,
+
+    retirement: Retirement,
+    replacement: Option<Replacement>,
+
+    // Whether or not our connection, whether open or still being used by a batch, was made to an
+    // address the backend has since moved away from.
+    stale_address: bool,
+
+    // Where we record how long each batch took, if anyone's keeping track.
+    latency: Option<Arc<LatencyHistogram>>,
+    batch_latencies: Option<Arc<LatencyBuckets>>,
+
+    responses: Arc<ResponseSizeTracker>,
+    sink: MetricSink,
 }
 
 impl<P> BackendConnection<P>
@@ -103,24 +256,301 @@ where
     P::Message: Message + Clone + Send + 'static,
 {
     pub fn new(
-        address: SocketAddr, processor: P, timeout_ms: u64, noreply: bool, sink: MetricSink<&'static str>,
+        address: SocketAddr, processor: P, settings: ConnectionSettings, sink: MetricSink,
     ) -> BackendConnection<P> {
+        let ConnectionSettings {
+            source,
+            timeout_ms,
+            noreply,
+            fail_fast,
+            reconnect,
+            retirement,
+            fds,
+            responses,
+        } = settings;
+
         BackendConnection {
             processor,
             address,
+            source,
             timeout_ms,
             noreply,
+            fail_fast,
+            backoff: Backoff::new(&reconnect),
+            reconnect,
+            fds,
+            fd: None,
+            state: ConnectionState::NotConnected,
             stream: None,
             current: None,
+            current_started: None,
+            current_len: 0,
             pending: VecDeque::new(),
+            priority: Vec::new(),
             pending_len: 0,
+            errors: 0,
+            ejected_until: None,
+            retirement: Retirement::new(retirement),
+            replacement: None,
+            stale_address: false,
+            latency: None,
+            batch_latencies: None,
+            responses,
             sink,
         }
     }
 
-    pub fn enqueue(&mut self, batch: EnqueuedRequests<P::Message>) {
+    pub fn enqueue(&mut self, mut batch: EnqueuedRequests<P::Message>) {
         self.pending_len += batch.len();
-        self.pending.push_back(batch);
+
+        // Priority requests skip the queue for as long as there's room for them in their lane.
+        if batch.iter().any(|req| req.lane() == Lane::Priority) {
+            let mut normal = Vec::new();
+            for req in batch {
+                if req.lane() == Lane::Priority && self.priority.len() < PRIORITY_LANE_LIMIT {
+                    self.priority.push(req);
+                } else {
+                    normal.push(req);
+                }
+            }
+            batch = normal;
+        }
+
+        if !batch.is_empty() {
+            self.pending.push_back(batch);
+        }
+    }
+
+    /// Whether or not this connection has nothing queued or in flight.
+    fn is_idle(&self) -> bool { self.current.is_none() && self.pending.is_empty() && self.priority.is_empty() }
+
+    /// Gets the number of requests queued or in flight on this connection.
+    fn in_flight(&self) -> usize { self.current_len + self.pending_len }
+
+    fn is_ready(&self) -> bool {
+        match self.state {
+            ConnectionState::Ready => true,
+            _ => false,
+        }
+    }
+
+    /// Counts a failure of this connection at the given time.
+    ///
+    /// Once it has failed `error_limit` times in a row, the connection is passed over for a while,
+    /// unless it starts working again sooner, and has to fail that many times again to be passed
+    /// over again.
+    fn record_error(&mut self, now: Instant, error_limit: usize) {
+        self.errors += 1;
+        if self.errors < error_limit {
+            return;
+        }
+
+        let cooloff = self.backoff.fail();
+        debug!("[backend] passing over connection to {} for {}ms", self.address, duration_as_ms(cooloff));
+        self.errors = 0;
+        self.ejected_until = Some(now + cooloff);
+    }
+
+    /// Marks this connection as working, so that it's no longer passed over.
+    fn restore(&mut self) {
+        self.errors = 0;
+        self.ejected_until = None;
+        self.backoff.reset();
+    }
+
+    /// Whether or not this connection should be sent requests, as of the given time.
+    fn is_available(&self, now: Instant) -> bool { self.ejected_until.map_or(true, |until| until <= now) }
+
+    /// Records how long a batch took, wherever batch latencies are being kept track of.
+    fn record_latency(&self, rtt: Duration) {
+        if let Some(latency) = self.latency.as_ref() {
+            latency.record(rtt);
+        }
+        if let Some(batch_latencies) = self.batch_latencies.as_ref() {
+            batch_latencies.record(rtt);
+        }
+    }
+
+    fn with_timeout(&self, inner: ProcessFuture) -> MaybeTimeout<ProcessFuture> {
+        if self.timeout_ms == 0 {
+            Either::A(NotTimeout { inner })
+        } else {
+            Either::B(Timeout::new(inner, Duration::from_millis(self.timeout_ms)))
+        }
+    }
+
+    /// Dials the backend, giving up once the connect timeout passes.
+    fn connect(&self) -> ProcessFuture {
+        debug!("[backend] connecting to {}", self.address);
+        self.sink.increment("connects");
+
+        let address = self.address;
+        let sink = self.sink.clone();
+        let connect = self.processor.preconnect(&self.address, self.source, self.noreply);
+        let connect = self.reconnect.limit_connect(connect).then(move |result| {
+            match result {
+                Ok(_) => debug!("[backend] connected to {}", address),
+                Err(ProtocolError::IoError(ref e)) if e.kind() == io::ErrorKind::TimedOut => {
+                    debug!("[backend] timed out connecting to {}", address);
+                    sink.increment("connect_timeouts");
+                },
+                Err(ref e) => {
+                    debug!("[backend] failed to connect to {}: {}", address, e);
+                    sink.increment("connect_failures");
+                },
+            }
+            result
+        });
+        ProcessFuture::new(connect)
+    }
+
+    /// Takes our idle connection to send the next batch over, if it's still fit to use.
+    ///
+    /// Responses are matched to requests purely by order, so anything the backend sent while the
+    /// connection sat idle would be handed to the next batch as its responses.  A connection like
+    /// that, or one that the backend has since closed, is thrown away so that a fresh one gets
+    /// dialed in its place.
+    fn take_stream(&mut self) -> Option<TcpStream> {
+        let stream = self.stream.take()?;
+        match peek_idle(&stream) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Some(stream),
+            Ok(0) => {
+                debug!("[backend] connection to {} was closed while idle", self.address);
+                None
+            },
+            Ok(_) => {
+                debug!("[backend] connection to {} sent unexpected responses while idle", self.address);
+                self.sink.increment("conns_out_of_sync");
+                None
+            },
+            Err(e) => {
+                debug!("[backend] connection to {} failed while idle: {}", self.address, e);
+                None
+            },
+        }
+    }
+
+    fn start_connect(&mut self) -> ProcessFuture {
+        self.fd = self.fds.as_ref().map(FdTracker::acquire);
+        self.retirement.reset(Instant::now());
+        self.connect()
+    }
+
+    /// Points this connection at the new address of its backend.
+    ///
+    /// Anything already sent over a connection to the old address is seen through, and the
+    /// connection is then retired, just as if it had been open for too long: a connection to the
+    /// new address is dialed as its replacement, and swapped in between batches.  Anything that
+    /// was only just dialing the old address is given up on.
+    fn set_address(&mut self, address: SocketAddr) {
+        if address == self.address {
+            return;
+        }
+
+        self.address = address;
+        self.restore();
+
+        if let ConnectionState::Connecting(_) = self.state {
+            self.state = ConnectionState::NotConnected;
+            self.fd = None;
+        }
+
+        // Any replacement we were already dialing is headed for the old address, too.
+        self.replacement = None;
+        self.stale_address = self.stream.is_some() || self.current.is_some();
+        self.check_retirement();
+    }
+
+    /// Dials a replacement for this connection if it's due to be retired and doesn't have one yet.
+    fn check_retirement(&mut self) {
+        if self.replacement.is_some() || !(self.stale_address || self.retirement.is_enabled()) {
+            return;
+        }
+
+        let reason = if self.stale_address {
+            Some(RetireReason::Address)
+        } else {
+            self.retirement.due(Instant::now())
+        };
+        if let Some(reason) = reason {
+            let fd = self.fds.as_ref().map(FdTracker::acquire);
+            self.replacement = Some(Replacement {
+                connect: self.connect(),
+                stream: None,
+                fd,
+                reason,
+            });
+        }
+    }
+
+    /// Drives the dialing of a replacement connection, if there is one.
+    fn poll_replacement(&mut self) -> Result<(), BackendError> {
+        let result = match self.replacement.as_mut() {
+            Some(replacement) if replacement.stream.is_none() => replacement.connect.poll(),
+            _ => return Ok(()),
+        };
+
+        match result {
+            Ok(Async::Ready(stream)) => {
+                if let Some(replacement) = self.replacement.as_mut() {
+                    replacement.stream = Some(stream);
+                }
+                Ok(())
+            },
+            Ok(Async::NotReady) => Ok(()),
+            Err(e) => {
+                // The connection we're replacing is still around, so we'll just dial another
+                // replacement once the next batch goes out over it.
+                self.replacement = None;
+                Err(e.into())
+            },
+        }
+    }
+
+    /// Swaps in the replacement connection, if there is one, closing the connection it replaces.
+    ///
+    /// If the replacement is still being dialed, we wait on it as we would any other connection.
+    fn promote_replacement(&mut self) -> Option<RetireReason> {
+        let replacement = self.replacement.take()?;
+        self.stale_address = false;
+        self.fd = replacement.fd;
+        self.retirement.reset(Instant::now());
+
+        match replacement.stream {
+            Some(stream) => {
+                self.stream = Some(stream);
+                self.state = ConnectionState::Ready;
+            },
+            None => {
+                self.stream = None;
+                self.state = ConnectionState::Connecting(replacement.connect);
+            },
+        }
+
+        Some(replacement.reason)
+    }
+
+    /// Drives a background connect, if there is one, towards completion.
+    fn poll_connect(&mut self) -> Poll<(), BackendError> {
+        let result = match self.state {
+            ConnectionState::Connecting(ref mut connect) => connect.poll(),
+            _ => return Ok(Async::Ready(())),
+        };
+
+        match result {
+            Ok(Async::Ready(stream)) => {
+                self.stream = Some(stream);
+                self.state = ConnectionState::Ready;
+                self.restore();
+                Ok(Async::Ready(()))
+            },
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                self.state = ConnectionState::NotConnected;
+                self.fd = None;
+                Err(e.into())
+            },
+        }
     }
 }
 
@@ -137,6 +567,9 @@ where
 
     fn poll_service(&mut self) -> Poll<(), Self::Error> {
         loop {
+            try_ready!(self.poll_connect());
+            self.poll_replacement()?;
+
             // First, check if we have an operation running.  If we do, poll it to drive it towards
             // completion.  If it's done, we'll reclaim the socket and then fallthrough to trying to
             // find another piece of work to run.
@@ -145,7 +578,19 @@ where
                     Ok(Async::Ready(stream)) => {
                         // The operation finished, and gave us the connection back.
                         self.stream = Some(stream);
+                        self.state = ConnectionState::Ready;
                         self.current = None;
+                        self.current_len = 0;
+                        self.restore();
+
+                        if let Some(started) = self.current_started.take() {
+                            // A batch that took over an hour means the clock misbehaved, not the
+                            // backend, so we flag it instead of reporting it.
+                            match request_duration(elapsed(started)) {
+                                Some(rtt) => self.record_latency(rtt),
+                                None => self.sink.increment("clock_anomalies"),
+                            }
+                        }
                     },
                     Ok(Async::NotReady) => return Ok(Async::NotReady),
                     Err(e) => {
@@ -156,22 +601,57 @@ where
                         // fulfilled yet, so that we can at least hand back an error saying that
                         // something broke internally.
                         self.current = None;
+                        self.current_len = 0;
+                        let started = self.current_started.take();
+
+                        // The connection was owned by the operation, so it's gone now, too, and
+                        // we'll get a new one when we go to process our next batch.  If we were
+                        // already dialing a replacement, though, that's what we carry on with.
+                        self.state = ConnectionState::NotConnected;
+                        self.stream = None;
+                        self.fd = None;
+                        self.stale_address = false;
+                        self.promote_replacement();
 
                         // If this is specifically an inner error, and not a timeout, then the
-                        // connection to the backend is also likely compromised, so we'll drop that
-                        // as well, giving us a new connection when we go to process our next
-                        // batch.
+                        // connection to the backend is also likely compromised, so let our
+                        // backend know.
                         if e.is_inner() {
-                            self.stream = None;
                             return Err(e.into_inner().unwrap().into());
                         }
+
+                        // A batch that timed out took at least as long as we were willing to wait,
+                        // which is exactly the sort of latency we want to know about.
+                        if let Some(rtt) = started.and_then(|started| request_duration(elapsed(started))) {
+                            self.record_latency(rtt);
+                        }
+
+                        // A backend that sat on a batch for that long is suspect, so our backend is
+                        // told about it, too, and passes this connection over for a while.  Whatever
+                        // we still have queued goes out on a fresh connection, so we make sure we're
+                        // polled again to send it.
+                        self.sink.increment("batch_timeouts");
+                        task::current().notify();
+                        return Err(BackendError::Internal("timed out waiting on backend".to_owned()));
                     },
                 }
             }
 
+            // Between batches is the only time we can swap out the connection, so if we're retiring
+            // it and its replacement is connected, now's the time.
+            if self.replacement.as_ref().map_or(false, |replacement| replacement.stream.is_some()) {
+                if let Some(reason) = self.promote_replacement() {
+                    self.sink.increment(reason.metric_name());
+                }
+            }
+
             // If we're here, we have no current operation to drive, so see if anything is in our work
-            // queue that we can grab.
-            let mut batch: Option<EnqueuedRequests<P::Message>> = None;
+            // queue that we can grab, starting with the priority lane.
+            let mut batch: Option<EnqueuedRequests<P::Message>> = if self.priority.is_empty() {
+                None
+            } else {
+                Some(mem::replace(&mut self.priority, Vec::new()))
+            };
             loop {
                 if let Some(batch2) = batch.as_ref() {
                     if batch2.len() > 256 {
@@ -194,27 +674,24 @@ where
             match batch {
                 Some(batch) => {
                     self.pending_len -= batch.len();
+                    self.current_len = batch.len();
 
                     // Get our stream, which we either already have or we'll just get a future for.
-                    let stream = match self.stream.take() {
+                    let stream = match self.take_stream() {
                         Some(stream) => Either::A(ok(stream)),
-                        None => {
-                            self.sink.increment("connects");
-                            Either::B(self.processor.preconnect(&self.address, self.noreply))
-                        },
+                        None => Either::B(self.start_connect()),
                     };
 
-                    // Get the response future from the processor.
-                    let inner = self.processor.process(batch, stream);
-
-                    // Wrap it up to handle any configured timeouts.
-                    let work = if self.timeout_ms == 0 {
-                        Either::A(NotTimeout { inner })
-                    } else {
-                        Either::B(Timeout::new(inner, Duration::from_millis(self.timeout_ms)))
-                    };
+                    // Get the response future from the processor, and wrap it up to handle any
+                    // configured timeouts.
+                    let inner = self.processor.process(batch, stream, self.responses.clone());
+                    let work = self.with_timeout(inner);
 
                     self.current = Some(work);
+                    self.current_started = Some(Instant::now());
+
+                    self.retirement.record(self.current_len);
+                    self.check_retirement();
                 },
                 None => return Ok(Async::Ready(())),
             }
@@ -222,7 +699,7 @@ where
     }
 
     fn poll_close(&mut self) -> Poll<(), Self::Error> {
-        if self.current.is_some() || !self.pending.is_empty() {
+        if !self.is_idle() {
             return Ok(Async::NotReady);
         }
 
@@ -243,16 +720,52 @@ where
         let response = req
             .as_mut_slice()
             .iter_mut()
-            .map(|x| x.get_response_rx())
-            .filter(|x| x.is_some())
-            .map(|x| x.unwrap())
+            .filter_map(|x| x.get_response_rx().map(|rx| (x.id(), rx)))
             .collect::<Vec<_>>();
 
+        // In fail-fast mode, nothing waits on a connection that isn't ready yet.  We answer the
+        // requests right away, and get the connection going, if it isn't already, so that it's
+        // there for whoever comes next.  Health checks are the exception, since failing them
+        // before they're even sent would say nothing about the backend.
+        let priority = req.iter().all(|msg| msg.lane() == Lane::Priority);
+        if self.fail_fast && !self.is_ready() && !priority {
+            if let ConnectionState::NotConnected = self.state {
+                self.state = ConnectionState::Connecting(self.start_connect());
+            }
+
+            self.sink.update_count("connect_fast_fails", req.len() as i64);
+            for msg in &mut req {
+                msg.fulfill(self.processor.get_error_message_str(BACKEND_CONNECTING));
+            }
+
+            return ResponseFuture::new(response);
+        }
+
         self.enqueue(req);
         ResponseFuture::new(response)
     }
 }
 
+/// Checks the options that every backend of a pool is built from, without building anything.
+pub fn check_options(options: &PoolOptions) -> Result<(), CreationError> {
+    ResponseSizeConfiguration::from_options(options)?;
+    ConnectionSelection::from_options(&options.other)?;
+    RetirementConfiguration::from_options(options)?;
+    ReconnectConfiguration::from_options(options)?;
+    Ok(())
+}
+
+/// What every backend in a pool is built with.
+#[derive(Clone)]
+pub struct BackendSettings {
+    pub pool_name: String,
+    pub options: PoolOptions,
+    pub noreply: bool,
+    pub fds: Option<Arc<FdTracker>>,
+    pub clock: SharedClock,
+    pub sink: MetricSink,
+}
+
 /// Managed connections to a backend server.
 ///
 /// This backend is serviced by a Tokio task, which processes all work requests to backend servers,
@@ -263,17 +776,35 @@ where
 /// work back and forth between the backend connections and client connections.
 ///
 /// Backends maintain a given number of connections to their underlying service, and track error
-/// states, recycling connections and pausing work when required.
+/// states, recycling connections and pausing work when required.  A connection that fails is
+/// passed over for the cooloff period, and only counts against the health of the backend as a
+/// whole once every connection has failed.
 pub struct Backend<P>
 where
     P: Processor + Clone + Send + 'static,
     P::Message: Message + Clone + Send + 'static,
 {
+    idx: usize,
     identifier: String,
+    address: SocketAddr,
     health: BackendHealth,
     conns: Vec<BackendConnection<P>>,
-    conns_index: usize,
-    sink: MetricSink<&'static str>,
+    conns_next: usize,
+    dedicated: DedicatedConnector<P>,
+    selection: ConnectionSelection,
+    // Connections are only passed over after failing if the backend as a whole would be, and after
+    // failing as many times in a row.
+    eject_conns: bool,
+    conn_error_limit: usize,
+    open_conns_reported: usize,
+    in_flight_reported: usize,
+    requests: usize,
+    request_timeout: Option<Duration>,
+    clock: SharedClock,
+    sink: MetricSink,
+
+    // Metrics for this backend alone, rather than for its pool as a whole.
+    backend_sink: MetricSink,
 }
 
 impl<P> Backend<P>
@@ -282,61 +813,203 @@ where
     P::Message: Message + Clone + Send + 'static,
 {
     pub fn new(
-        address: SocketAddr, identifier: String, processor: P, mut options: HashMap<String, String>, noreply: bool,
-        sink: MetricSink<&'static str>,
+        idx: usize, address: SocketAddr, identifier: String, processor: P, settings: &BackendSettings,
     ) -> Result<Backend<P>, CreationError>
     where
         P: Processor + Clone + Send + 'static,
         P::Message: Message + Send + 'static,
     {
+        let options = &settings.options;
+        let clock = settings.clock.clone();
+        let sink = settings.sink.clone();
+
+        // Response sizes are tracked for the pool as a whole, rather than for each backend.
+        let response_config = ResponseSizeConfiguration::from_options(options)?;
+        let responses = Arc::new(ResponseSizeTracker::new(response_config, sink.clone()));
+        let backend_sink = sink.scoped(&["backends", identifier.as_str()]);
         let sink = sink.scoped("backend");
 
-        let conn_limit_raw = options.entry("conns".to_owned()).or_insert_with(|| "1".to_owned());
-        let conn_limit = usize::from_str(conn_limit_raw.as_str())
-            .map_err(|_| CreationError::InvalidParameter("options.conns".to_string()))?;
-        debug!("[listener] using connection limit of '{}'", conn_limit);
+        let conn_limit = options.conns_per_backend;
+        let selection = ConnectionSelection::from_options(&options.other)?;
+        let retirement = RetirementConfiguration::from_options(options)?;
+        debug!("[listener] using connection limit of '{}', selected by {:?}", conn_limit, selection);
 
-        let cooloff_enabled_raw = options
-            .entry("cooloff_enabled".to_owned())
-            .or_insert_with(|| "true".to_owned());
-        let cooloff_enabled = bool::from_str(cooloff_enabled_raw.as_str())
-            .map_err(|_| CreationError::InvalidParameter("options.cooloff_enabled".to_string()))?;
+        let cooloff_enabled = options.cooloff_enabled;
+        let reconnect = ReconnectConfiguration::from_options(options)?;
+        let cooloff_error_limit = options.cooloff_error_limit;
 
-        let cooloff_timeout_ms_raw = options
-            .entry("cooloff_timeout_ms".to_owned())
-            .or_insert_with(|| "10000".to_owned());
-        let cooloff_timeout_ms = u64::from_str(cooloff_timeout_ms_raw.as_str())
-            .map_err(|_| CreationError::InvalidParameter("options.cooloff_timeout_ms".to_string()))?;
+        // Every request is given this long to be answered, and so is every batch sent to the
+        // backend, and every connection made to it.  Zero means we wait for as long as it takes.
+        let timeout_ms = options.timeout_ms;
+        let request_timeout = if timeout_ms == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(timeout_ms))
+        };
+
+        let fail_fast = options.lazy_connect_fail_fast;
+
+        let health = BackendHealth::new(
+            settings.pool_name.clone(),
+            identifier.clone(),
+            cooloff_enabled,
+            Backoff::new(&reconnect),
+            cooloff_error_limit,
+            clock.clone(),
+        );
+
+        let source = source_address_from_options(&options.other, &address)?;
+        if let Some(source) = source {
+            debug!("[listener] connecting to backend {} from local address {}", address, source);
+        }
 
-        let cooloff_error_limit_raw = options
-            .entry("cooloff_error_limit".to_owned())
-            .or_insert_with(|| "5".to_owned());
-        let cooloff_error_limit = usize::from_str(cooloff_error_limit_raw.as_str())
-            .map_err(|_| CreationError::InvalidParameter("options.cooloff_error_limit".to_string()))?;
+        let conn_settings = ConnectionSettings {
+            source,
+            timeout_ms,
+            noreply: settings.noreply,
+            fail_fast,
+            reconnect,
+            retirement,
+            fds: settings.fds.clone(),
+            responses,
+        };
 
-        let health = BackendHealth::new(cooloff_enabled, cooloff_timeout_ms, cooloff_error_limit);
+        let dedicated =
+            DedicatedConnector::new(processor.clone(), address, &conn_settings, options.max_dedicated_conns);
 
-        // TODO: where the hell did the actual backend timeout value go? can't hard-code this
         let conns = (0..conn_limit)
-            .map(|_| BackendConnection::new(address, processor.clone(), 500, noreply, sink.clone()))
+            .map(|_| BackendConnection::new(address, processor.clone(), conn_settings.clone(), sink.clone()))
             .collect();
 
+        backend_sink.update_gauge("open_conns", 0);
+        backend_sink.update_gauge("in_flight", 0);
+
         Ok(Backend {
+            idx,
             identifier,
+            address,
             health,
             conns,
-            conns_index: 0,
+            conns_next: 0,
+            dedicated,
+            selection,
+            eject_conns: cooloff_enabled,
+            conn_error_limit: cooloff_error_limit,
+            open_conns_reported: 0,
+            in_flight_reported: 0,
+            requests: 0,
+            request_timeout,
+            clock,
             sink,
+            backend_sink,
         })
     }
 
     pub fn health(&self) -> &BackendHealth { &self.health }
 
+    /// Gets the position of this backend as it was configured in its pool.
+    pub fn idx(&self) -> usize { self.idx }
+
+    /// Gets the identifier this backend was configured with.
+    pub fn identifier(&self) -> &str { &self.identifier }
+
+    /// Gets the address of this backend.
+    pub fn address(&self) -> SocketAddr { self.address }
+
+    /// Moves this backend to a new address, such as when its hostname starts resolving elsewhere.
+    ///
+    /// Connections to the old address are drained rather than dropped: each one carries on with
+    /// whatever it's already been sent, and is replaced by a connection to the new address between
+    /// batches.  Errors from the old address don't count against the new one, so any cooloff ends.
+    pub fn set_address(&mut self, address: SocketAddr) {
+        if address == self.address {
+            return;
+        }
+
+        info!("[backend] moving backend {} from {} to {}", self.identifier, self.address, address);
+        self.address = address;
+        self.health.reset();
+        self.dedicated.set_address(address);
+        for conn in &mut self.conns {
+            conn.set_address(address);
+        }
+        self.backend_sink.increment("address_changes");
+    }
+
+    /// Whether or not every connection to this backend has nothing queued or in flight.
+    pub fn is_idle(&self) -> bool { self.conns.iter().all(BackendConnection::is_idle) }
+
+    /// Gets the number of requests that have been sent to this backend on behalf of clients.
+    pub fn requests(&self) -> usize { self.requests }
+
+    /// Gets the number of requests queued or in flight on every connection to this backend.
+    pub fn in_flight(&self) -> usize { self.conns.iter().map(BackendConnection::in_flight).sum() }
+
+    /// Starts tracking the latency of every batch sent to this backend.
+    ///
+    /// Returns the histogram that latencies are recorded in.
+    pub fn track_latency(&mut self) -> Arc<LatencyHistogram> {
+        let latency = Arc::new(LatencyHistogram::new());
+        for conn in &mut self.conns {
+            conn.latency = Some(latency.clone());
+        }
+        latency
+    }
+
+    /// Records the latency of every batch sent to this backend in the given histogram.
+    pub fn record_batches(&mut self, batch_latencies: Arc<LatencyBuckets>) {
+        for conn in &mut self.conns {
+            conn.batch_latencies = Some(batch_latencies.clone());
+        }
+    }
+
+    /// Gets the number of connections to this backend that are established.
+    pub fn open_conns(&self) -> usize { self.conns.iter().filter(|conn| conn.is_ready()).count() }
+
+    fn report_open_conns(&mut self) {
+        let open_conns = self.open_conns();
+        if open_conns != self.open_conns_reported {
+            self.open_conns_reported = open_conns;
+            self.backend_sink.update_gauge("open_conns", open_conns as u64);
+        }
+    }
+
+    fn report_in_flight(&mut self) {
+        let in_flight = self.in_flight();
+        if in_flight != self.in_flight_reported {
+            self.in_flight_reported = in_flight;
+            self.backend_sink.update_gauge("in_flight", in_flight as u64);
+        }
+    }
+
+    /// Sends a request to this backend over a connection of its own, rather than over any of the
+    /// connections shared with other requests.
+    ///
+    /// The connection lasts for as long as the request does, so the request is never given up on
+    /// for taking too long, only when whoever sent it stops waiting on it.  Once the backend has
+    /// as many of these connections open as `max_dedicated_conns` allows, requests are answered
+    /// with an error instead.
+    pub fn call_dedicated(&mut self, mut req: EnqueuedRequest<P::Message>) -> ResponseFuture<P, BackendError> {
+        let id = req.id();
+        let rx = req.get_response_rx();
+        match self.dedicated.run(req) {
+            Some(work) => {
+                self.requests = self.requests.wrapping_add(1);
+                self.backend_sink.increment("requests_sent");
+                tokio::spawn(work);
+            },
+            None => self.backend_sink.increment("dedicated_rejected"),
+        }
+
+        ResponseFuture::new(rx.into_iter().map(|rx| (id, rx)).collect()).with_outcomes(self.backend_sink.clone())
+    }
+
     pub fn get_descriptor(&mut self) -> BackendDescriptor {
         BackendDescriptor {
-            idx: 0,
+            idx: self.idx,
             identifier: self.identifier.clone(),
             healthy: self.health.is_healthy(),
+            weight: DEFAULT_WEIGHT,
         }
     }
 }
@@ -359,24 +1032,73 @@ where
     }
 
     fn poll_service(&mut self) -> Poll<(), Self::Error> {
-        for conn in &mut self.conns {
-            if conn.poll_service().is_err() {
-                self.health.increment_error();
+        let now = self.clock.now();
+        for idx in 0..self.conns.len() {
+            if let Err(e) = self.conns[idx].poll_service() {
+                // The backend only takes the blame once it has no other connections to fall back on.
+                if self.eject_conns {
+                    self.conns[idx].record_error(now, self.conn_error_limit);
+                    let others_available = self
+                        .conns
+                        .iter()
+                        .enumerate()
+                        .any(|(other, conn)| other != idx && conn.is_available(now));
+                    if !others_available {
+                        self.health.increment_error();
+                    }
+                } else {
+                    self.health.increment_error();
+                }
+                self.backend_sink.increment("conn_errors");
+
+                BACKEND_ERRORS.log(
+                    Level::Error,
+                    e.kind(),
+                    format_args!("[backend] error on connection to {}: {}", self.identifier, e),
+                );
             }
         }
 
+        // A backend we're connected to is working again, as far as cooloffs are concerned.
+        if self.open_conns() > 0 {
+            self.health.record_success();
+        }
+
+        self.report_open_conns();
+        self.report_in_flight();
         Ok(Async::Ready(()))
     }
 
     fn poll_close(&mut self) -> Poll<(), Self::Error> { Ok(Async::Ready(())) }
 
     fn call(&mut self, req: EnqueuedRequests<P::Message>) -> Self::Future {
-        let result = self.conns[self.conns_index].call(req);
+        // Only requests sent on behalf of clients count, so that our own health checks don't keep
+        // a backend that's being drained from ever looking quiet.
+        let requests = req.iter().filter(|msg| msg.lane() == Lane::Normal).count();
+        self.requests = self.requests.wrapping_add(requests);
+        self.backend_sink.update_count("requests_sent", req.len() as i64);
 
-        self.conns_index += 1;
-        self.conns_index %= self.conns.len();
+        let now = self.clock.now();
+        let idx = if self.conns.len() == 1 {
+            0
+        } else {
+            let conns = self
+                .conns
+                .iter()
+                .map(|conn| (conn.is_available(now), conn.in_flight()))
+                .collect::<Vec<_>>();
+            self.selection.choose(&conns, &mut self.conns_next)
+        };
 
-        result
+        // Whatever isn't answered in time is answered with a timeout instead, so that a backend
+        // that stops responding can't hold up the clients waiting on it.
+        let response = self.conns[idx].call(req).with_outcomes(self.backend_sink.clone());
+        match self.request_timeout {
+            Some(timeout) if !response.is_empty() => {
+                response.with_deadline(self.clock.delay(now + timeout), self.sink.clone())
+            },
+            _ => response,
+        }
     }
 }
 
@@ -386,24 +1108,79 @@ where
     P::Message: Message + Send + 'static,
     E: From<oneshot::error::RecvError>,
 {
-    responses: JoinAll<PendingResponses<P::Message>>,
+    responses: Vec<AwaitedResponse<P::Message>>,
+    deadline: Option<(ClockDelay, MetricSink)>,
+    outcomes: Option<MetricSink>,
     _processor: PhantomData<P>,
     _error: PhantomData<E>,
 }
 
+/// A response that's yet to be received, along with the identifier it'll be sent back with.
+struct AwaitedResponse<T> {
+    id: usize,
+    rx: PendingResponse<T>,
+    response: Option<AssignedResponse<T>>,
+}
+
 impl<P, E> ResponseFuture<P, E>
 where
     P: Processor + Send + 'static,
     P::Message: Message + Send + 'static,
     E: From<oneshot::error::RecvError>,
 {
-    pub fn new(responses: PendingResponses<P::Message>) -> ResponseFuture<P, E> {
+    pub fn new(responses: Vec<(usize, PendingResponse<P::Message>)>) -> ResponseFuture<P, E> {
         ResponseFuture {
-            responses: join_all(responses),
+            responses: responses
+                .into_iter()
+                .map(|(id, rx)| AwaitedResponse { id, rx, response: None })
+                .collect(),
+            deadline: None,
+            outcomes: None,
             _processor: PhantomData,
             _error: PhantomData,
         }
     }
+
+    /// Gives up on any responses that haven't been received once the given deadline passes,
+    /// answering them with `MessageResponse::TimedOut` instead.
+    ///
+    /// Every response given up on is counted as a request timeout in the given sink.
+    pub fn with_deadline(mut self, deadline: ClockDelay, sink: MetricSink) -> ResponseFuture<P, E> {
+        self.deadline = Some((deadline, sink));
+        self
+    }
+
+    /// Counts how each response turned out, once they're all in, in the given sink.
+    ///
+    /// Responses that were received are counted as `responses_received`, and those that never
+    /// were as `request_errors` or `request_timeouts`, depending on why.
+    pub fn with_outcomes(mut self, sink: MetricSink) -> ResponseFuture<P, E> {
+        self.outcomes = Some(sink);
+        self
+    }
+
+    /// Whether or not there are no responses to wait on at all.
+    pub fn is_empty(&self) -> bool { self.responses.is_empty() }
+
+    /// Whether or not the deadline, if there is one, has passed.
+    ///
+    /// If our timer has gone away, we stop watching the deadline rather than give up on responses
+    /// that might still be coming.
+    fn poll_deadline(&mut self) -> bool {
+        let passed = match self.deadline {
+            Some((ref mut deadline, _)) => deadline.poll(),
+            None => return false,
+        };
+
+        match passed {
+            Ok(Async::Ready(())) => true,
+            Ok(Async::NotReady) => false,
+            Err(()) => {
+                self.deadline = None;
+                false
+            },
+        }
+    }
 }
 
 impl<P, E> Future for ResponseFuture<P, E>
@@ -415,5 +1192,634 @@ where
     type Error = E;
     type Item = AssignedResponses<P::Message>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> { self.responses.poll().map_err(|e| e.into()) }
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut waiting = false;
+        let mut failed = Vec::new();
+        for (i, awaited) in self.responses.iter_mut().enumerate() {
+            if awaited.response.is_some() {
+                continue;
+            }
+
+            match awaited.rx.poll()? {
+                Async::Ready(response) => {
+                    if let MessageResponse::Failed = response.1 {
+                        failed.push(i);
+                    }
+                    awaited.response = Some(response);
+                },
+                Async::NotReady => waiting = true,
+            }
+        }
+
+        // Anything still outstanding once the deadline passes is given up on.  Requests that were
+        // failed because the batch they went out in was given up on are reported the same way.
+        if waiting || !failed.is_empty() {
+            if self.poll_deadline() {
+                let mut timeouts = 0;
+                for (i, awaited) in self.responses.iter_mut().enumerate() {
+                    if awaited.response.is_none() || failed.contains(&i) {
+                        awaited.response = Some((awaited.id, MessageResponse::TimedOut));
+                        timeouts += 1;
+                    }
+                }
+
+                if let Some((_, ref sink)) = self.deadline {
+                    sink.update_count("request_timeouts", timeouts);
+                }
+            } else if waiting {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        let responses = self
+            .responses
+            .drain(..)
+            .map(|awaited| awaited.response.expect("response missing after being received"))
+            .collect::<Vec<_>>();
+
+        if let Some(ref sink) = self.outcomes {
+            let (mut received, mut errors, mut timeouts) = (0, 0, 0);
+            for (_, response) in &responses {
+                match response {
+                    MessageResponse::Complete(_) => received += 1,
+                    MessageResponse::Failed => errors += 1,
+                    MessageResponse::TimedOut => timeouts += 1,
+                }
+            }
+
+            for &(key, count) in &[
+                ("responses_received", received),
+                ("request_errors", errors),
+                ("request_timeouts", timeouts),
+            ] {
+                if count > 0 {
+                    sink.update_count(key, count);
+                }
+            }
+        }
+
+        Ok(Async::Ready(responses))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::redis::RedisProcessor;
+    use common::{EnqueuedRequest, MessageResponse};
+    use futures::future::{join_all, lazy, poll_fn};
+    use metrics::{capture, get_sink};
+    use net2::TcpBuilder;
+    use protocol::redis::{RedisMessage, RedisTransportConfig};
+    use std::{
+        collections::HashMap,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream as StdTcpStream},
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+    use test::Bencher;
+    use tokio::runtime::current_thread;
+    use util::clock::{system_clock, Clock, VirtualClock};
+
+    // A backend that's slow to accept connections.
+    //
+    // With a backlog of zero, the accept queue only has room for the connection we make ourselves,
+    // so anyone else trying to connect is left waiting on a retransmitted SYN until we accept it.
+    fn get_slow_backend() -> (TcpListener, StdTcpStream) {
+        let listener = TcpBuilder::new_v4()
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .listen(0)
+            .unwrap();
+        let filler = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (listener, filler)
+    }
+
+    // A backend that answers every `GET foo` with a miss, taking a little while over each one.
+    //
+    // Also hands back the number of connections it has accepted so far.
+    fn get_busy_backend() -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let accepted2 = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                accepted2.fetch_add(1, Ordering::SeqCst);
+
+                thread::spawn(move || {
+                    // `GET foo` spans five lines on the wire, so that's how we count requests.
+                    let mut buf = [0; 8192];
+                    let mut lines = 0;
+                    loop {
+                        let n = match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => n,
+                        };
+                        lines += buf[..n].iter().filter(|b| **b == b'\n').count();
+
+                        let mut replies = Vec::new();
+                        while lines >= 5 {
+                            lines -= 5;
+                            thread::sleep(Duration::from_micros(20));
+                            replies.extend_from_slice(b"$-1\r\n");
+                        }
+
+                        if stream.write_all(&replies).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (address, accepted)
+    }
+
+    // An address that nothing is listening on.
+    fn get_closed_address() -> SocketAddr { TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap() }
+
+    fn get_backend(address: SocketAddr, options: &[(&str, &str)]) -> Backend<RedisProcessor> {
+        get_backend_with_clock(address, options, system_clock())
+    }
+
+    fn get_backend_with_clock(
+        address: SocketAddr, options: &[(&str, &str)], clock: SharedClock,
+    ) -> Backend<RedisProcessor> {
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let options = options
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        let options = PoolOptions::from_map(options).unwrap();
+        let identifier = address.to_string();
+        Backend::new(0, address, identifier, processor, &get_settings(options, clock, get_sink())).unwrap()
+    }
+
+    fn get_settings(options: PoolOptions, clock: SharedClock, sink: MetricSink) -> BackendSettings {
+        BackendSettings {
+            pool_name: "test".to_owned(),
+            options,
+            noreply: false,
+            fds: None,
+            clock,
+            sink,
+        }
+    }
+
+    fn call_backend(
+        backend: &mut Backend<RedisProcessor>, requests: usize,
+    ) -> Result<Vec<AssignedResponses<RedisMessage>>, BackendError> {
+        let mut responses = join_all(
+            (0..requests)
+                .map(|_| backend.call(vec![EnqueuedRequest::new(0, RedisMessage::from_inline("GET foo"))]))
+                .collect::<Vec<_>>(),
+        );
+
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        runtime.block_on(poll_fn(|| {
+            backend.poll_service()?;
+            responses.poll()
+        }))
+    }
+
+    fn call(conn: &mut BackendConnection<RedisProcessor>) -> ResponseFuture<RedisProcessor, BackendError> {
+        let request = EnqueuedRequest::new(0, RedisMessage::from_inline("GET foo"));
+        conn.call(vec![request])
+    }
+
+    fn check_fast_failed(response: ResponseFuture<RedisProcessor, BackendError>) {
+        let mut responses = response.wait().expect("response should have been fulfilled");
+        assert_eq!(responses.len(), 1);
+
+        match responses.remove(0) {
+            (0, MessageResponse::Complete(RedisMessage::Error(buf, offset))) => {
+                assert_eq!(&buf[offset..buf.len() - 2], BACKEND_CONNECTING.as_bytes());
+            },
+            x => panic!("expected fast-fail error, got {:?}", x),
+        }
+    }
+
+    fn is_connecting(conn: &BackendConnection<RedisProcessor>) -> bool {
+        match conn.state {
+            ConnectionState::Connecting(_) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_lazy_connect_fail_fast() {
+        let (listener, _filler) = get_slow_backend();
+        let address = listener.local_addr().unwrap();
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let responses = Arc::new(ResponseSizeTracker::new(ResponseSizeConfiguration::default(), get_sink()));
+        let options = PoolOptions {
+            timeout_ms: 0,
+            ..Default::default()
+        };
+        let settings = ConnectionSettings {
+            source: None,
+            timeout_ms: 0,
+            noreply: false,
+            fail_fast: true,
+            reconnect: ReconnectConfiguration::from_options(&options).unwrap(),
+            retirement: RetirementConfiguration::default(),
+            fds: None,
+            responses,
+        };
+        let mut conn = BackendConnection::new(address, processor, settings, get_sink());
+
+        // Nothing is dialed until there's a request for us, and that request doesn't wait for the
+        // connection to be established.
+        assert!(!is_connecting(&conn));
+        check_fast_failed(call(&mut conn));
+        assert!(is_connecting(&conn));
+
+        // While the backend is sitting on our connection, everyone else is turned away, too.
+        let result = lazy(|| Ok::<_, ()>(conn.poll_service())).wait().unwrap();
+        assert!(result.unwrap().is_not_ready());
+        assert!(is_connecting(&conn));
+        check_fast_failed(call(&mut conn));
+
+        // Once the backend gets around to us, requests are queued up as usual.
+        let _ = listener.accept().unwrap();
+        poll_fn(|| conn.poll_service()).wait().unwrap();
+        assert!(conn.is_ready());
+
+        let _response = call(&mut conn);
+        assert_eq!(conn.pending_len, 1);
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        // A backend that never lets us connect is only waited on for as long as the connect timeout,
+        // even when everything else sent to it is given as long as it takes.
+        let (listener, _filler) = get_slow_backend();
+        let address = listener.local_addr().unwrap();
+        let (sink, capture) = capture();
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let options = [("timeout_ms", "0"), ("connect_timeout_ms", "100")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        let options = PoolOptions::from_map(options).unwrap();
+        let settings = get_settings(options, system_clock(), sink);
+        let mut backend = Backend::new(0, address, "slow".to_owned(), processor, &settings).unwrap();
+
+        let started = Instant::now();
+        let _ = call_backend(&mut backend, 1);
+        assert!(elapsed(started) < Duration::from_secs(5));
+
+        let counts = capture.counts();
+        assert_eq!(counts.get("backend.connects"), Some(&1));
+        assert_eq!(counts.get("backend.connect_timeouts"), Some(&1));
+        assert_eq!(counts.get("backends.slow.conn_errors"), Some(&1));
+    }
+
+    #[test]
+    fn test_connection_ejection() {
+        let address = get_closed_address();
+        let clock = VirtualClock::new();
+        let options = &[("conns_per_backend", "2"), ("cooloff_error_limit", "1"), ("cooloff_timeout_ms", "5000")];
+        let mut backend = get_backend_with_clock(address, options, Arc::new(clock.clone()));
+
+        // Losing one connection only sets that connection aside.
+        let _ = call_backend(&mut backend, 1);
+        let now = clock.now();
+        assert!(!backend.conns[0].is_available(now));
+        assert!(backend.conns[1].is_available(now));
+        assert!(backend.health.is_healthy());
+
+        // Once the other one goes, though, the backend as a whole is at fault.
+        let _ = call_backend(&mut backend, 1);
+        assert!(!backend.conns[1].is_available(now));
+        assert!(!backend.health.is_healthy());
+
+        // Both are given another chance once the cooloff is over.
+        clock.advance(Duration::from_millis(5001));
+        let now = clock.now();
+        assert!(backend.conns[0].is_available(now));
+        assert!(backend.conns[1].is_available(now));
+        assert!(backend.health.is_healthy());
+    }
+
+    #[test]
+    fn test_connection_ejection_error_limit() {
+        let address = get_closed_address();
+        let clock = VirtualClock::new();
+        let options = &[("conns_per_backend", "1"), ("cooloff_error_limit", "2"), ("cooloff_timeout_ms", "5000")];
+        let mut backend = get_backend_with_clock(address, options, Arc::new(clock.clone()));
+
+        // A connection is only set aside once it's failed as many times in a row as the limit.
+        let _ = call_backend(&mut backend, 1);
+        let now = clock.now();
+        assert!(backend.conns[0].is_available(now));
+        assert!(backend.health.is_healthy());
+
+        // With nothing else to fall back on, every failure counts against the backend, too.
+        let _ = call_backend(&mut backend, 1);
+        assert!(!backend.conns[0].is_available(now));
+        assert!(!backend.health.is_healthy());
+    }
+
+    #[test]
+    fn test_dedicated_conn_limit() {
+        // Connections to a backend that never accepts them stay open for as long as we let them.
+        let (listener, _filler) = get_slow_backend();
+        let address = listener.local_addr().unwrap();
+        let (sink, capture) = capture();
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let options = [("timeout_ms", "0"), ("max_dedicated_conns", "1")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        let options = PoolOptions::from_map(options).unwrap();
+        let settings = get_settings(options, system_clock(), sink);
+        let mut backend = Backend::new(0, address, "slow".to_owned(), processor, &settings).unwrap();
+
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        let blpop = || EnqueuedRequest::new(0, RedisMessage::from_inline("BLPOP list 0"));
+        let (first, second) = runtime
+            .block_on(lazy(|| Ok::<_, ()>((backend.call_dedicated(blpop()), backend.call_dedicated(blpop())))))
+            .unwrap();
+
+        // With the only connection we're allowed already taken, the next request doesn't get one.
+        match runtime.block_on(second).unwrap().remove(0) {
+            (0, MessageResponse::Complete(RedisMessage::Error(_, _))) => {},
+            x => panic!("expected an error, got {:?}", x),
+        }
+        assert_eq!(capture.counts().get("backends.slow.dedicated_rejected"), Some(&1));
+        assert_eq!(backend.requests(), 1);
+
+        // Once the first request is given up on, its connection makes room for another.
+        drop(first);
+        runtime.run().unwrap();
+        let third = runtime.block_on(lazy(|| Ok::<_, ()>(backend.call_dedicated(blpop())))).unwrap();
+        assert_eq!(capture.counts().get("backends.slow.dedicated_rejected"), Some(&1));
+        assert_eq!(backend.requests(), 2);
+
+        drop(third);
+        runtime.run().unwrap();
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        // Connections to a backend that never accepts them sit in its backlog, never answered.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let clock = VirtualClock::new();
+        let options = &[("timeout_ms", "1000")];
+        let mut backend = get_backend_with_clock(listener.local_addr().unwrap(), options, Arc::new(clock.clone()));
+        let mut runtime = current_thread::Runtime::new().unwrap();
+
+        let mut response = backend.call(vec![EnqueuedRequest::new(7, RedisMessage::from_inline("GET foo"))]);
+        let result = runtime
+            .block_on(lazy(|| {
+                backend.poll_service()?;
+                response.poll()
+            }))
+            .unwrap();
+        assert!(result.is_not_ready());
+
+        // Once the timeout passes, the request is answered without waiting on the backend any longer.
+        clock.advance(Duration::from_millis(1001));
+        let result = runtime
+            .block_on(lazy(|| {
+                backend.poll_service()?;
+                response.poll()
+            }))
+            .unwrap();
+        match result {
+            Async::Ready(ref responses) if responses.len() == 1 => {
+                match responses[0] {
+                    (7, MessageResponse::TimedOut) => {},
+                    ref x => panic!("expected a timeout, got {:?}", x),
+                }
+            },
+            x => panic!("expected a single response, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_backend_metrics() {
+        let (sink, capture) = capture();
+        let get_backend = |address, identifier: &str| {
+            let processor = RedisProcessor::new(RedisTransportConfig::default());
+            let identifier = identifier.to_owned();
+            let settings = get_settings(PoolOptions::default(), system_clock(), sink.clone());
+            Backend::new(0, address, identifier, processor, &settings).unwrap()
+        };
+
+        // Everything sent and answered is counted against the backend it went to.
+        let (address, _) = get_busy_backend();
+        let mut backend = get_backend(address, "redis.1");
+        call_backend(&mut backend, 3).unwrap();
+        let counts = capture.counts();
+        assert_eq!(counts.get("backends.redis_1.requests_sent"), Some(&3));
+        assert_eq!(counts.get("backends.redis_1.responses_received"), Some(&3));
+        assert_eq!(counts.get("backends.redis_1.request_errors"), None);
+
+        // As is everything that wasn't.
+        let mut backend = get_backend(get_closed_address(), "closed");
+        let _ = call_backend(&mut backend, 2);
+        let counts = capture.counts();
+        assert_eq!(counts.get("backends.closed.requests_sent"), Some(&2));
+        assert_eq!(counts.get("backends.closed.request_errors"), Some(&2));
+        assert_eq!(counts.get("backends.closed.conn_errors"), Some(&1));
+    }
+
+    #[test]
+    fn test_batch_latencies() {
+        let (address, _) = get_busy_backend();
+        let mut backend = get_backend(address, &[]);
+        let batch_latencies = Arc::new(LatencyBuckets::new(vec![1_000, 10_000, 100_000]));
+        backend.record_batches(batch_latencies.clone());
+
+        // Every batch lands in the histogram, rather than just the last one being kept.
+        for _ in 0..3 {
+            call_backend(&mut backend, 1).unwrap();
+        }
+        assert_eq!(batch_latencies.cumulative().last().unwrap().1, 3);
+        assert!(batch_latencies.max_us().unwrap() >= batch_latencies.min_us().unwrap());
+    }
+
+    #[test]
+    fn test_open_conns() {
+        let (address, _) = get_busy_backend();
+        let mut backend = get_backend(address, &[("conns_per_backend", "2")]);
+        assert_eq!(backend.open_conns(), 0);
+
+        // Connections are only opened as they're needed.
+        call_backend(&mut backend, 1).unwrap();
+        assert_eq!(backend.open_conns(), 1);
+        call_backend(&mut backend, 1).unwrap();
+        assert_eq!(backend.open_conns(), 2);
+    }
+
+    #[test]
+    fn test_drop_out_of_sync_connection() {
+        // A backend whose first connection sends an extra response once it's gone idle.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+
+                thread::spawn(move || {
+                    let mut buf = [0; 8192];
+                    loop {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => break,
+                            Ok(_) => {},
+                        }
+
+                        if stream.write_all(b"$-1\r\n").is_err() {
+                            break;
+                        }
+
+                        if i == 0 {
+                            thread::sleep(Duration::from_millis(20));
+                            let _ = stream.write_all(b"+OK\r\n");
+                        }
+                    }
+                });
+            }
+        });
+
+        let (sink, capture) = capture();
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let options = PoolOptions::default();
+        let settings = get_settings(options, system_clock(), sink);
+        let mut backend = Backend::new(0, address, "chatty".to_owned(), processor, &settings).unwrap();
+
+        // The stray response never makes it to the next request: the connection it came in on is
+        // thrown away, and the request goes out on a fresh one.
+        for _ in 0..2 {
+            let responses = call_backend(&mut backend, 1).unwrap();
+            match responses[0][0] {
+                (0, MessageResponse::Complete(RedisMessage::Null)) => {},
+                ref x => panic!("expected a miss, got {:?}", x),
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let counts = capture.counts();
+        assert_eq!(counts.get("backend.connects"), Some(&2));
+        assert_eq!(counts.get("backend.conns_out_of_sync"), Some(&1));
+    }
+
+    #[test]
+    fn test_retire_after_max_requests() {
+        let (address, accepted) = get_busy_backend();
+        let mut backend = get_backend(address, &[("backend_max_requests_per_connection", "10")]);
+
+        // Every request gets its answer, even the ones in flight as their connection is retired.
+        for _ in 0..50 {
+            for responses in call_backend(&mut backend, 4).unwrap() {
+                assert_eq!(responses.len(), 1);
+                match responses[0] {
+                    (0, MessageResponse::Complete(RedisMessage::Null)) => {},
+                    ref x => panic!("expected a miss, got {:?}", x),
+                }
+            }
+        }
+
+        // Connections are swapped once their replacement is connected, so we can't say exactly how
+        // many there were, but two hundred requests should have been plenty to go through a few.
+        assert!(accepted.load(Ordering::SeqCst) > 2);
+    }
+
+    #[test]
+    fn test_set_address() {
+        let (old, old_accepted) = get_busy_backend();
+        let (new, new_accepted) = get_busy_backend();
+        let mut backend = get_backend(old, &[]);
+        call_backend(&mut backend, 4).unwrap();
+
+        // Once the backend moves, whatever goes out while the new connection is being dialed still
+        // goes to the old address, and everything after that goes to the new one.  Nobody is left
+        // without an answer either way.
+        backend.set_address(new);
+        assert_eq!(backend.address(), new);
+        for _ in 0..200 {
+            for responses in call_backend(&mut backend, 4).unwrap() {
+                assert_eq!(responses.len(), 1);
+                match responses[0] {
+                    (0, MessageResponse::Complete(RedisMessage::Null)) => {},
+                    ref x => panic!("expected a miss, got {:?}", x),
+                }
+            }
+
+            if !backend.conns[0].stale_address {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert!(!backend.conns[0].stale_address);
+        assert_eq!(backend.conns[0].address, new);
+        assert_eq!(old_accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(new_accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_priority_lane() {
+        let (address, _) = get_busy_backend();
+        let mut backend = get_backend(address, &[]);
+        let mut runtime = current_thread::Runtime::new().unwrap();
+
+        // Get a deep queue going, and start working through it.
+        let normal = (0..2000)
+            .map(|_| backend.call(vec![EnqueuedRequest::new(0, RedisMessage::from_inline("GET foo"))]))
+            .collect::<Vec<_>>();
+        runtime.block_on(poll_fn(|| backend.poll_service())).unwrap();
+
+        // A request in the priority lane goes out with the very next batch, well ahead of the
+        // requests that were already waiting, and without counting as a client request.
+        let mut request = EnqueuedRequest::new(0, RedisMessage::from_inline("GET foo"));
+        request.set_lane(Lane::Priority);
+        let mut priority = backend.call(vec![request]);
+        let responses = runtime
+            .block_on(poll_fn(|| {
+                backend.poll_service()?;
+                priority.poll()
+            }))
+            .unwrap();
+        assert_eq!(responses.len(), 1);
+        assert!(backend.in_flight() > 1000);
+        assert_eq!(backend.requests(), 2000);
+
+        // The requests it skipped ahead of still get through.
+        let mut normal = join_all(normal);
+        let responses = runtime
+            .block_on(poll_fn(|| {
+                backend.poll_service()?;
+                normal.poll()
+            }))
+            .unwrap();
+        assert_eq!(responses.len(), 2000);
+    }
+
+    fn bench_conns(b: &mut Bencher, conns: &str) {
+        let (address, _) = get_busy_backend();
+        let mut backend = get_backend(address, &[("conns_per_backend", conns)]);
+
+        b.iter(|| call_backend(&mut backend, 64).unwrap());
+    }
+
+    #[bench]
+    fn bench_one_conn(b: &mut Bencher) { bench_conns(b, "1"); }
+
+    #[bench]
+    fn bench_two_conns(b: &mut Bencher) { bench_conns(b, "2"); }
+
+    #[bench]
+    fn bench_four_conns(b: &mut Bencher) { bench_conns(b, "4"); }
 }
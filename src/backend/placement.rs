@@ -0,0 +1,383 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::{
+    distributor::{configure_distributor, BackendDescriptor, Distributor},
+    hasher::{configure_hasher, KeyHasher},
+    migration::Migration,
+    transform::KeyTransforms,
+    weights::DEFAULT_WEIGHT,
+};
+use conf::{BackendAddress, PoolConfiguration};
+use errors::CreationError;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{collections::HashMap, fmt};
+
+/// Keys that every pool places as part of its self test.
+///
+/// These never change, so the backends they land on can be compared between configurations, or
+/// between a proxy and the one replacing it.
+pub const CANARY_KEYS: &[&str] = &[
+    "canary",
+    "canary:0",
+    "canary:1",
+    "user:1000",
+    "session:abcdef",
+    "{user:1000}:profile",
+    "{user:1000}:settings",
+    "synchrotron",
+];
+
+// How many generated keys are placed to measure the spread, and what they're generated from, so
+// that a given configuration always reports the same spread.
+const SAMPLE_KEYS: usize = 10_000;
+const SAMPLE_SEED: u64 = 0x5eed_5eed;
+
+/// How a pool maps keys to its backends.
+pub struct Placement {
+    pub distributor: Box<Distributor + Send + Sync>,
+    pub hasher: Box<KeyHasher + Send + Sync>,
+    pub transforms: KeyTransforms,
+    pub migration: Option<Migration>,
+}
+
+impl Placement {
+    /// Configures a placement from the given pool configuration: its options, the migration the
+    /// pool is in, if any, and the transforms its keys are hashed by.
+    pub fn from_config(config: &PoolConfiguration) -> Result<Placement, CreationError> {
+        let options = &config.options;
+        let migration = config.migration.as_ref();
+
+        // A migration names both placements itself, so the new one takes over from the options.
+        let dist_type = match migration {
+            Some(migration) => migration.to_distribution.to_lowercase(),
+            None => options.distribution.to_lowercase(),
+        };
+        let distributor = configure_distributor(&dist_type, options)?;
+        debug!("[listener] using distributor '{}'", dist_type);
+
+        let hash_type = options.hash.to_lowercase();
+        let (migration, hash_type) = match migration {
+            Some(migration) => {
+                let from = Migration::from_config(migration, &hash_type, options)?;
+                let to_hash = migration.to_hash.as_ref().map(|s| s.to_lowercase()).unwrap_or(hash_type);
+                (Some(from), to_hash)
+            },
+            None => (None, hash_type),
+        };
+        let hasher = configure_hasher(&hash_type)?;
+        debug!("[listener] using hasher '{}'", hash_type);
+
+        let transforms = KeyTransforms::from_config(config, &options.other)?;
+
+        Ok(Placement {
+            distributor,
+            hasher,
+            transforms,
+            migration,
+        })
+    }
+
+    /// Configures a placement like `from_config`, with every backend of the pool healthy and
+    /// carrying the weight it was configured with.
+    pub fn with_configured_backends(config: &PoolConfiguration) -> Result<Placement, CreationError> {
+        let mut placement = Placement::from_config(config)?;
+        let descriptors = config
+            .addresses
+            .iter()
+            .enumerate()
+            .map(|(idx, address)| {
+                BackendDescriptor {
+                    idx,
+                    identifier: address.identifier.clone(),
+                    healthy: true,
+                    weight: address.weight,
+                }
+            })
+            .collect::<Vec<_>>();
+        placement.distributor.update(descriptors);
+
+        Ok(placement)
+    }
+
+    /// Chooses the backend for the given key, returning its configured position.
+    pub fn choose(&self, key: &[u8]) -> usize {
+        self.distributor.choose(self.hasher.hash(&self.transforms.hash_key(key)))
+    }
+}
+
+/// Where a pool places its canary keys, and how evenly it spreads keys in general.
+///
+/// Keys are placed with the same hasher and distributor the pool itself would use, as if all of
+/// its backends were healthy and carried the weights they were configured with.
+pub struct PlacementReport {
+    addresses: Vec<BackendAddress>,
+    canaries: Vec<(String, usize)>,
+    spread: Vec<usize>,
+}
+
+impl PlacementReport {
+    /// Places the canary keys, any other keys with an expected placement, and a seeded sample of
+    /// generated keys with the given pool configuration.
+    pub fn from_config(config: &PoolConfiguration) -> Result<PlacementReport, CreationError> {
+        let placement = Placement::with_configured_backends(config)?;
+
+        let addresses = config.addresses.clone();
+        if addresses.is_empty() {
+            return Ok(PlacementReport {
+                addresses,
+                canaries: Vec::new(),
+                spread: Vec::new(),
+            });
+        }
+
+        let mut keys = CANARY_KEYS.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        if let Some(ref expected) = config.expected_placements {
+            let mut extra = expected.keys().filter(|key| !keys.contains(key)).cloned().collect::<Vec<_>>();
+            extra.sort();
+            keys.extend(extra);
+        }
+        let canaries = keys
+            .into_iter()
+            .map(|key| {
+                let idx = placement.choose(key.as_bytes());
+                (key, idx)
+            })
+            .collect();
+
+        let mut rng = StdRng::seed_from_u64(SAMPLE_SEED);
+        let mut spread = vec![0; addresses.len()];
+        for _ in 0..SAMPLE_KEYS {
+            let key = format!("key:{:016x}", rng.gen::<u64>());
+            spread[placement.choose(key.as_bytes())] += 1;
+        }
+
+        Ok(PlacementReport {
+            addresses,
+            canaries,
+            spread,
+        })
+    }
+
+    /// Gets the backend that the given canary key was placed on, if it was placed.
+    pub fn get_placement(&self, key: &str) -> Option<&BackendAddress> {
+        self.canaries
+            .iter()
+            .find(|(canary, _)| canary == key)
+            .map(|(_, idx)| &self.addresses[*idx])
+    }
+
+    /// Gets how many of the generated keys were placed on each backend, in configured order.
+    pub fn get_spread(&self) -> &[usize] { &self.spread }
+
+    /// Checks the canary placements against the expected ones, which name backends by either their
+    /// address or their identifier.
+    ///
+    /// Returns a description of every key that was placed somewhere other than expected.
+    pub fn check(&self, expected: &HashMap<String, String>) -> Vec<String> {
+        self.canaries
+            .iter()
+            .filter_map(|(key, idx)| {
+                let wanted = expected.get(key)?;
+                let actual = &self.addresses[*idx];
+                if *wanted == actual.address.to_string() || *wanted == actual.identifier {
+                    None
+                } else {
+                    Some(format!("key '{}' placed on {}, expected {}", key, actual, wanted))
+                }
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for PlacementReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.addresses.is_empty() {
+            return write!(f, "no backends to place keys on");
+        }
+
+        for (key, idx) in &self.canaries {
+            writeln!(f, "canary '{}' -> {}", key, self.addresses[*idx])?;
+        }
+
+        // Skew is how much busier the busiest backend is than it would be with a perfect spread.
+        let mean = SAMPLE_KEYS as f64 / self.spread.len() as f64;
+        let busiest = self.spread.iter().max().cloned().unwrap_or(0);
+        write!(f, "spread of {} keys (skew {:.2}):", SAMPLE_KEYS, busiest as f64 / mean)?;
+        for (address, count) in self.addresses.iter().zip(&self.spread) {
+            write!(f, " {}={}", address, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conf::PoolOptions;
+
+    fn get_config(backends: usize, options: &[(&str, &str)]) -> PoolConfiguration {
+        let addresses = (0..backends)
+            .map(|idx| {
+                let address = format!("127.0.0.1:{}", 6379 + idx).parse().unwrap();
+                BackendAddress {
+                    address,
+                    host: None,
+                    identifier: format!("cache{}", idx),
+                    weight: DEFAULT_WEIGHT,
+                }
+            })
+            .collect();
+        let options = options
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        PoolConfiguration {
+            addresses,
+            options: PoolOptions::from_map(options).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_report_is_reproducible() {
+        let config = get_config(3, &[]);
+        let first = PlacementReport::from_config(&config).unwrap();
+        let second = PlacementReport::from_config(&config).unwrap();
+
+        assert_eq!(first.to_string(), second.to_string());
+        assert_eq!(first.get_spread().iter().sum::<usize>(), SAMPLE_KEYS);
+        for key in CANARY_KEYS {
+            assert!(first.get_placement(key).is_some());
+        }
+    }
+
+    #[test]
+    fn test_report_uses_pool_placement() {
+        let config = get_config(4, &[("hash", "md5"), ("hash_tag", "{}")]);
+        let report = PlacementReport::from_config(&config).unwrap();
+
+        let mut placement = Placement::from_config(&config).unwrap();
+        placement.distributor.update(
+            (0..4)
+                .map(|idx| {
+                    BackendDescriptor {
+                        idx,
+                        identifier: format!("cache{}", idx),
+                        healthy: true,
+                        weight: DEFAULT_WEIGHT,
+                    }
+                })
+                .collect(),
+        );
+        for key in CANARY_KEYS {
+            let expected = &config.addresses[placement.choose(key.as_bytes())];
+            assert_eq!(report.get_placement(key).unwrap().identifier, expected.identifier);
+        }
+
+        // Keys sharing a hash tag always land together.
+        assert_eq!(
+            report.get_placement("{user:1000}:profile").unwrap().identifier,
+            report.get_placement("{user:1000}:settings").unwrap().identifier
+        );
+    }
+
+    #[test]
+    fn test_report_uses_key_transforms() {
+        let mut prefix = HashMap::new();
+        prefix.insert("type".to_owned(), "prefix".to_owned());
+        prefix.insert("prefix".to_owned(), "app:".to_owned());
+
+        // Placing the prefixed keys as extra keys lets us see where they'd go without the transform.
+        let mut plain = get_config(4, &[]);
+        let expected = CANARY_KEYS.iter().map(|key| (format!("app:{}", key), String::new()));
+        plain.expected_placements = Some(expected.collect());
+        let mut transformed = get_config(4, &[]);
+        transformed.key_transforms = Some(vec![prefix]);
+
+        // Keys are placed by what they're hashed as, which is what the backends see.
+        let plain = PlacementReport::from_config(&plain).unwrap();
+        let transformed = PlacementReport::from_config(&transformed).unwrap();
+        for key in CANARY_KEYS {
+            assert_eq!(
+                transformed.get_placement(key).unwrap().identifier,
+                plain.get_placement(&format!("app:{}", key)).unwrap().identifier
+            );
+        }
+    }
+
+    #[test]
+    fn test_spread_is_even() {
+        let report = PlacementReport::from_config(&get_config(4, &[])).unwrap();
+        for count in report.get_spread() {
+            assert!(*count > SAMPLE_KEYS / 8 && *count < SAMPLE_KEYS * 3 / 8, "spread: {:?}", report.get_spread());
+        }
+    }
+
+    #[test]
+    fn test_spread_follows_weights() {
+        let mut config = get_config(3, &[]);
+        config.addresses[0].weight = 0;
+        config.addresses[2].weight = 3;
+
+        let report = PlacementReport::from_config(&config).unwrap();
+        let spread = report.get_spread();
+        assert_eq!(spread[0], 0);
+        assert!(spread[2] > spread[1] * 2, "spread: {:?}", spread);
+    }
+
+    #[test]
+    fn test_check_expected_placements() {
+        let mut config = get_config(3, &[]);
+        let mut expected = HashMap::new();
+        expected.insert("not-a-canary".to_owned(), String::new());
+        config.expected_placements = Some(expected.clone());
+
+        // Keys with an expected placement are placed even if they aren't canaries.
+        let report = PlacementReport::from_config(&config).unwrap();
+        let actual = report.get_placement("not-a-canary").unwrap().clone();
+        assert_eq!(report.check(&expected).len(), 1);
+
+        // Backends can be named by address or by identifier.
+        expected.insert("not-a-canary".to_owned(), actual.address.to_string());
+        assert!(report.check(&expected).is_empty());
+        expected.insert("not-a-canary".to_owned(), actual.identifier.clone());
+        assert!(report.check(&expected).is_empty());
+
+        let canary = report.get_placement("canary").unwrap();
+        let wrong = config
+            .addresses
+            .iter()
+            .find(|address| address.identifier != canary.identifier)
+            .unwrap();
+        expected.insert("canary".to_owned(), wrong.identifier.clone());
+        assert_eq!(
+            report.check(&expected),
+            vec![format!("key 'canary' placed on {}, expected {}", canary, wrong.identifier)]
+        );
+    }
+
+    #[test]
+    fn test_empty_pool() {
+        let report = PlacementReport::from_config(&get_config(0, &[])).unwrap();
+        assert!(report.get_placement("canary").is_none());
+        assert_eq!(report.to_string(), "no backends to place keys on");
+    }
+}
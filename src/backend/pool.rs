@@ -17,22 +17,41 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use super::{
-    distributor::{configure_distributor, Distributor},
-    hasher::{configure_hasher, KeyHasher},
+use super::{distributor::Distributor, hasher::KeyHasher};
+use backend::{
+    drain::BackendActivity,
+    latency::LatencyHistogram,
+    migration::{FallbackRequest, Migration, MigrationFallback},
+    placement::Placement,
+    probe::{BackendAvailability, ProbeRequest, ProbedPool},
+    processor::Processor,
+    resolver::{refresh_interval_from_options, ResolvedAddresses, Resolver, SystemResolver},
+    retry::RetryBudget,
+    transform::{KeyTransforms, OriginalKeys},
+    ttl::TtlPolicy,
+    weights::{BackendWeights, MAX_WEIGHT},
+    Backend, BackendError, BackendSettings, PoolError, ResponseFuture,
 };
-use backend::{processor::Processor, Backend, BackendError, PoolError, ResponseFuture};
-use common::{AssignedResponses, EnqueuedRequests, Message};
+use common::{AssignedResponses, EnqueuedRequest, EnqueuedRequests, Message, MessageResponse};
 use conf::PoolConfiguration;
 use errors::CreationError;
 use futures::{
     future::{join_all, JoinAll},
     prelude::*,
 };
-use hotmic::Sink as MetricSink;
-use std::{collections::HashMap, marker::PhantomData};
+use lifecycle::{self, PhaseSignal, ShutdownHandle, ShutdownPhase};
+use metrics::{LatencyBuckets, MetricSink};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
 use tower_direct_service::DirectService;
-use util::IntegerMappedVec;
+use reload::VersionHold;
+use util::{
+    clock::{system_clock, SharedClock},
+    FdTracker, IntegerMappedVec,
+};
 
 type DistributorFutureSafe = Box<Distributor + Send + 'static>;
 type KeyHasherFutureSafe = Box<KeyHasher + Send + 'static>;
@@ -42,12 +61,86 @@ where
     P: Processor + Clone + Send + 'static,
     P::Message: Message + Send + 'static,
 {
+    processor: P,
     distributor: DistributorFutureSafe,
     key_hasher: KeyHasherFutureSafe,
+    transforms: Arc<KeyTransforms>,
     backends: Vec<Backend<P>>,
     noreply: bool,
+    ttl_policy: Option<TtlPolicy>,
+    allow_blocking: bool,
+    hit_tracker: Option<Arc<HitTracker>>,
+    retry_budget: Arc<RetryBudget>,
+    weights: Arc<BackendWeights>,
+    addresses: Arc<ResolvedAddresses>,
+    availability: Arc<BackendAvailability>,
+    activity: Arc<BackendActivity>,
+    migration: Option<Migration>,
+    fallback_tx: mpsc::UnboundedSender<FallbackRequest<P::Message>>,
+    fallback_rx: mpsc::UnboundedReceiver<FallbackRequest<P::Message>>,
+    probe_tx: mpsc::UnboundedSender<ProbeRequest<P::Message>>,
+    probe_rx: mpsc::UnboundedReceiver<ProbeRequest<P::Message>>,
     epoch: u64,
-    sink: MetricSink<&'static str>,
+    clock: SharedClock,
+    weights_generation: usize,
+    addresses_generation: usize,
+    availability_generation: usize,
+
+    // Shutting down waits for us to finish what's in flight to our backends, and then for us to go
+    // away entirely, which takes our backend connections and their health checks with us.
+    draining: Option<ShutdownHandle>,
+    drain_signal: PhaseSignal,
+    _stopping: ShutdownHandle,
+
+    // The listener version we were built for isn't released until we're gone.
+    _hold: Option<VersionHold>,
+
+    sink: MetricSink,
+}
+
+// How many lookups the hit ratio of a pool is worked out over, give or take.
+const HIT_RATIO_WINDOW: u64 = 100_000;
+
+/// Recent totals of how often lookups against a pool find what they're looking for.
+///
+/// Both totals are halved whenever they add up to a full window of lookups, so older lookups count
+/// for less and less, and the hit ratio follows how the pool is doing now rather than how it's done
+/// since it was built.
+#[derive(Default)]
+struct HitTracker {
+    totals: Mutex<(u64, u64)>,
+}
+
+impl HitTracker {
+    /// Records the given hits and misses, updating the pool's counters and hit ratio.
+    ///
+    /// The hit ratio is exposed in basis points, so 10,000 means every recent lookup was a hit.
+    fn record(&self, hits: usize, misses: usize, sink: &MetricSink) {
+        if hits == 0 && misses == 0 {
+            return;
+        }
+
+        let ratio = self.count(hits as u64, misses as u64);
+
+        sink.update_count("hits", hits as i64);
+        sink.update_count("misses", misses as i64);
+        sink.update_gauge("hit_ratio_bps", ratio);
+    }
+
+    /// Adds the given hits and misses to the totals, giving back the hit ratio in basis points.
+    fn count(&self, hits: u64, misses: u64) -> u64 {
+        let mut totals = self.totals.lock().unwrap();
+        let (total_hits, total_misses) = &mut *totals;
+        *total_hits = total_hits.saturating_add(hits);
+        *total_misses = total_misses.saturating_add(misses);
+        while total_hits.saturating_add(*total_misses) >= HIT_RATIO_WINDOW {
+            *total_hits /= 2;
+            *total_misses /= 2;
+        }
+
+        // Both totals are now well short of anything that could overflow.
+        *total_hits * 10_000 / (*total_hits + *total_misses).max(1)
+    }
 }
 
 impl<P> BackendPool<P>
@@ -55,36 +148,128 @@ where
     P: Processor + Clone + Send + 'static,
     P::Message: Message + Send + 'static,
 {
-    pub fn new(
-        backends: Vec<Backend<P>>, distributor: DistributorFutureSafe, key_hasher: KeyHasherFutureSafe, noreply: bool,
-        sink: MetricSink<&'static str>,
-    ) -> BackendPool<P> {
-        let mut pool = BackendPool {
-            distributor,
-            key_hasher,
-            backends,
-            noreply,
-            epoch: 0,
-            sink,
-        };
-        pool.regenerate_distribution();
-        pool
+    /// Gets the weights of the backends in this pool.
+    pub fn weights(&self) -> Arc<BackendWeights> { self.weights.clone() }
+
+    /// Gets the current addresses of the backends in this pool.
+    pub fn addresses(&self) -> Arc<ResolvedAddresses> { self.addresses.clone() }
+
+    /// Gets which backends in this pool have been ejected by health checks.
+    pub fn availability(&self) -> Arc<BackendAvailability> { self.availability.clone() }
+
+    /// Gets how many requests the backends in this pool have been sent, and are still working on.
+    pub fn activity(&self) -> Arc<BackendActivity> { self.activity.clone() }
+
+    /// Gets the budget that retries of requests sent to this pool are paid for out of.
+    pub fn retry_budget(&self) -> Arc<RetryBudget> { self.retry_budget.clone() }
+
+    /// Gets what's needed to health check the backends in this pool, including where to hand checks
+    /// to be sent, and the clock that this pool runs on.
+    pub fn probed(&self) -> ProbedPool<P::Message> {
+        ProbedPool {
+            availability: self.availability.clone(),
+            activity: self.activity.clone(),
+            probes: self.probe_tx.clone(),
+            clock: self.clock.clone(),
+        }
     }
 
+    /// Reseeds the distributor, and the distributor of any migration, with the currently healthy
+    /// backends.
+    ///
+    /// Backends that have been ejected by health checks are left out, unless every healthy backend
+    /// has been ejected: rather than having nowhere to send requests, we go on as if none were.
+    /// Drained backends, with a weight of zero, stay drained either way, so they don't count as
+    /// somewhere to send requests.
+    ///
+    /// Backends are always held in their configured order, which means the position carried by
+    /// each descriptor is also the index of the backend in `backends`, and the descriptors are
+    /// handed to the distributor sorted by that position.
     pub fn regenerate_distribution(&mut self) {
-        let descriptors = self
+        let weights = &self.weights;
+        let mut descriptors = self
             .backends
             .iter_mut()
-            .enumerate()
-            .map(|(idx, backend)| {
-                let mut descriptor = backend.get_descriptor();
-                descriptor.idx = idx;
-                descriptor
-            })
+            .map(|backend| backend.get_descriptor())
             .filter(|backend| backend.healthy)
-            .collect();
+            .map(|mut backend| {
+                backend.weight = weights.get_effective(backend.idx);
+                backend
+            })
+            .collect::<Vec<_>>();
+        let availability = &self.availability;
+        if descriptors
+            .iter()
+            .any(|backend| backend.weight > 0 && !availability.is_ejected(backend.idx))
+        {
+            descriptors.retain(|backend| !availability.is_ejected(backend.idx));
+        }
+        descriptors.sort_by_key(|backend| backend.idx);
+        if let Some(ref mut migration) = self.migration {
+            migration.update(descriptors.clone());
+        }
         self.distributor.update(descriptors);
     }
+
+    /// Moves any backends whose hostnames have started resolving elsewhere over to their new
+    /// addresses.
+    ///
+    /// Connections to the old addresses are drained as the backends move, rather than dropped, so
+    /// nothing already sent over them is lost.
+    fn update_addresses(&mut self) {
+        for backend in &mut self.backends {
+            let address = self.addresses.get(backend.idx());
+            if address != backend.address() {
+                backend.set_address(address);
+                self.weights.set_address(backend.idx(), address);
+                self.activity.set_address(backend.idx(), address);
+            }
+        }
+    }
+
+    /// Drives all of our backends, sending any fallback lookups and health checks that have been
+    /// handed to us.
+    fn drive_backends(&mut self) -> Poll<(), PoolError> {
+        // Fallback lookups are fulfilled directly, so there's nothing to do with what `call` returns.
+        while let Ok(Async::Ready(Some(fallback))) = self.fallback_rx.poll() {
+            let _ = self.backends[fallback.backend_idx].call(vec![fallback.request]);
+        }
+
+        // The same goes for health checks, which the checker waits on itself.
+        while let Ok(Async::Ready(Some(probe))) = self.probe_rx.poll() {
+            let _ = self.backends[probe.backend_idx].call(vec![probe.request]);
+        }
+
+        for backend in &mut self.backends {
+            // not clear if it actually makes sense to pre-emptively return notready without
+            // driving all services.. poll_ready should cover the "am i knocked out of the pool
+            // temporarily?" case but would this ever actually return notready when driving the
+            // underlying service? unclear
+            try_ready!(backend.poll_service());
+            self.activity.update(backend.idx(), backend.requests(), backend.in_flight());
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    /// Starts tracking the latency of every backend in this pool.
+    ///
+    /// Returns the identifier and latency histogram of each backend, in their configured order.
+    pub fn track_latencies(&mut self) -> Vec<(String, Arc<LatencyHistogram>)> {
+        self.backends
+            .iter_mut()
+            .map(|backend| (backend.get_descriptor().identifier, backend.track_latency()))
+            .collect()
+    }
+
+    fn report_weights(&mut self) {
+        for backend in &mut self.backends {
+            let descriptor = backend.get_descriptor();
+            let sink = self.sink.scoped(&["backends", descriptor.identifier.as_str()]);
+            sink.update_gauge("weight", self.weights.get(descriptor.idx) as u64);
+            sink.update_gauge("demotions", self.weights.get_demotions(descriptor.idx) as u64);
+        }
+    }
 }
 
 impl<P> DirectService<EnqueuedRequests<P::Message>> for BackendPool<P>
@@ -101,6 +286,13 @@ where
         // pool temporarily, but as long as one is ready, then we're ready.  If any of them are in
         // a bad enough state to throw an error, though, then something is very wrong and we need
         // to bubble that up.
+        // Backends whose hostnames have started resolving elsewhere are moved first, so that they're
+        // judged on how they're doing at their new addresses.
+        let addresses_generation = self.addresses.generation();
+        if self.addresses_generation != addresses_generation {
+            self.update_addresses();
+        }
+
         let mut any_ready = false;
         let mut epoch = 0;
         for backend in &mut self.backends {
@@ -117,25 +309,42 @@ where
             return Ok(Async::NotReady);
         }
 
-        if self.epoch != epoch {
+        // Weights, addresses and availability are changed from outside the pool, so we pick up any
+        // changes here, where we know no requests are being distributed.
+        let weights_generation = self.weights.generation();
+        let availability_generation = self.availability.generation();
+        if self.epoch != epoch
+            || self.weights_generation != weights_generation
+            || self.addresses_generation != addresses_generation
+            || self.availability_generation != availability_generation
+        {
             debug!("regenerating distribution");
             self.regenerate_distribution();
             self.epoch = epoch;
+            self.addresses_generation = addresses_generation;
+            self.availability_generation = availability_generation;
+
+            if self.weights_generation != weights_generation {
+                self.report_weights();
+                self.weights_generation = weights_generation;
+            }
         }
 
         Ok(Async::Ready(()))
     }
 
     fn poll_service(&mut self) -> Poll<(), Self::Error> {
-        for backend in &mut self.backends {
-            // not clear if it actually makes sense to pre-emptively return notready without
-            // driving all services.. poll_ready should cover the "am i knocked out of the pool
-            // temporarily?" case but would this ever actually return notready when driving the
-            // underlying service? unclear
-            try_ready!(backend.poll_service());
+        let result = self.drive_backends();
+
+        // Once we've been told to drain, let shutdown move on as soon as nothing is left in flight.
+        if self.draining.is_some()
+            && self.drain_signal.poll() == Ok(Async::Ready(()))
+            && self.backends.iter().all(Backend::is_idle)
+        {
+            self.draining = None;
         }
 
-        Ok(Async::Ready(()))
+        result
     }
 
     fn poll_close(&mut self) -> Poll<(), Self::Error> {
@@ -149,13 +358,113 @@ where
     fn call(&mut self, req: EnqueuedRequests<P::Message>) -> Self::Future {
         let mut futs = Vec::new();
         let mut batches = IntegerMappedVec::new();
+        let mut rejected = Vec::new();
+        let mut lookups = HashMap::new();
+        let mut fallbacks = HashMap::new();
+        let mut retries = HashSet::new();
+        let mut original_keys = HashMap::new();
+
+        for mut msg in req {
+            if msg.is_retry() {
+                retries.insert(msg.id());
+            }
+
+            if self.hit_tracker.is_some() {
+                if let Some(keys) = self.processor.get_lookup_keys(msg.request()) {
+                    lookups.insert(msg.id(), keys);
+                }
+            }
 
-        for msg in req {
-            let msg_key = msg.key();
-            let msg_hashed = self.key_hasher.hash(msg_key);
+            // Keys are placed by what our key transforms make of them, both now and before any
+            // migration.
+            let (msg_hashed, old_idx) = {
+                let hash_key = self.transforms.hash_key(msg.key());
+                let transforms = &self.transforms;
+                let old_idx = self.migration.as_ref().map(|migration| migration.choose(msg.key(), transforms));
+                (self.key_hasher.hash(&hash_key), old_idx)
+            };
             let backend_idx = self.distributor.choose(msg_hashed);
 
+            // Anything we send to the old placement of a key is built from the request as the client
+            // sent it, since keys there can go through different transforms.
+            let migrating = match old_idx {
+                Some(old_idx) if old_idx != backend_idx => Some((old_idx, msg.request().clone())),
+                _ => None,
+            };
+
+            // From here on out, the request carries the keys that its backend should see, and the
+            // keys the client sent are kept to be put back into its response.
+            if self.transforms.rewrites_keys() {
+                let processor = &self.processor;
+                let transforms = &self.transforms;
+                let original = original_keys.entry(msg.id()).or_insert_with(OriginalKeys::default);
+                msg.map_request(|request| processor.forward_keys(request, transforms, original));
+            }
+
+            // Blocking commands can hold a connection for as long as they like, which would hold up
+            // everything queued behind them on a shared connection, so they either get a connection
+            // of their own or never go out at all.
+            if self.processor.is_blocking(msg.request()) {
+                if self.allow_blocking {
+                    self.sink.increment("blocking_dedicated");
+                    futs.push(self.backends[backend_idx].call_dedicated(msg));
+                } else {
+                    self.sink.increment("blocking_rejected");
+                    if let Some(rx) = msg.get_response_rx() {
+                        rejected.push((msg.id(), rx));
+                    }
+                    msg.fulfill(self.processor.get_error_message_str("command not supported by proxy"));
+                }
+                continue;
+            }
+
+            let mut followup = None;
+            if let Some(policy) = self.ttl_policy {
+                if self.processor.is_missing_ttl(msg.request()) {
+                    self.sink.increment("ttl_missing");
+
+                    match policy {
+                        TtlPolicy::Reject => {
+                            // Answer the request ourselves, without ever sending it to a backend.
+                            self.sink.increment("ttl_rejected");
+                            if let Some(rx) = msg.get_response_rx() {
+                                rejected.push((msg.id(), rx));
+                            }
+                            msg.fulfill(self.processor.get_error_message_str("key must be written with a TTL"));
+                            continue;
+                        },
+                        TtlPolicy::ApplyDefault(ttl_secs) => {
+                            // The follow-up goes to the same backend, right behind the original
+                            // request, so it always runs after the key exists.  It never has a
+                            // response sent back, so the client only sees the original response.
+                            self.sink.increment("ttl_applied");
+                            let key = self.processor.get_created_key(msg.request());
+                            let ttl_req = self.processor.get_default_ttl_request(key, ttl_secs);
+                            followup = Some(EnqueuedRequest::without_response(ttl_req));
+                        },
+                        TtlPolicy::Observe => {},
+                    }
+                }
+            }
+
+            if let (Some(migration), Some((old_idx, request))) = (self.migration.as_ref(), migrating) {
+                let transforms = migration.transforms(&self.transforms);
+                if migration.read_fallback() && self.processor.get_lookup_keys(&request) == Some(1) {
+                    let original = original_keys.entry(msg.id()).or_insert_with(OriginalKeys::default);
+                    let request = self.processor.forward_keys(request, transforms, original);
+                    fallbacks.insert(msg.id(), (old_idx, request));
+                } else if migration.delete_old() && self.processor.is_write(&request) {
+                    // Deleting the old copy is fire-and-forget, just like a default TTL.
+                    self.sink.increment("migration_deletes");
+                    let delete_req = self.processor.get_delete_request(&transforms.forward(request.key()));
+                    batches.push(old_idx, EnqueuedRequest::without_response(delete_req));
+                }
+            }
+
             batches.push(backend_idx, msg);
+            if let Some(followup) = followup {
+                batches.push(backend_idx, followup);
+            }
         }
 
         // make the batch calls to each relevant backend, and collect them
@@ -164,7 +473,51 @@ where
             futs.push(fut);
         }
 
-        PoolResponse::new(futs)
+        if !rejected.is_empty() {
+            futs.push(ResponseFuture::new(rejected));
+        }
+
+        let hit_tracking = match self.hit_tracker {
+            Some(ref tracker) if !lookups.is_empty() => {
+                Some(HitTracking {
+                    processor: self.processor.clone(),
+                    tracker: tracker.clone(),
+                    lookups,
+                    sink: self.sink.clone(),
+                })
+            },
+            _ => None,
+        };
+
+        let fallback = if fallbacks.is_empty() {
+            None
+        } else {
+            Some(MigrationFallback::new(
+                self.processor.clone(),
+                fallbacks,
+                self.fallback_tx.clone(),
+                self.sink.clone(),
+            ))
+        };
+
+        original_keys.retain(|_, original| !original.is_empty());
+        let key_restoring = if original_keys.is_empty() {
+            None
+        } else {
+            Some(KeyRestoring {
+                processor: self.processor.clone(),
+                original_keys,
+            })
+        };
+
+        PoolResponse::new(
+            futs,
+            hit_tracking,
+            key_restoring,
+            fallback,
+            self.retry_budget.clone(),
+            retries,
+        )
     }
 }
 
@@ -173,10 +526,15 @@ where
     P: Processor + Clone + Send + 'static,
     P::Message: Message + Send + 'static,
 {
+    name: String,
     processor: P,
     config: PoolConfiguration,
     noreply: bool,
-    sink: MetricSink<&'static str>,
+    fds: Option<Arc<FdTracker>>,
+    batch_latencies: Option<Arc<LatencyBuckets>>,
+    hold: Option<VersionHold>,
+    resolver: Arc<Resolver>,
+    sink: MetricSink,
 }
 
 impl<P> BackendPoolBuilder<P>
@@ -185,14 +543,19 @@ where
     P::Message: Message + Send + 'static,
 {
     pub fn new(
-        name: String, processor: P, config: PoolConfiguration, sink: MetricSink<&'static str>,
+        name: String, processor: P, config: PoolConfiguration, sink: MetricSink,
     ) -> BackendPoolBuilder<P> {
         let sink = sink.scoped(&["pools", &name]);
 
         BackendPoolBuilder {
+            name,
             processor,
             config,
             noreply: false,
+            fds: None,
+            batch_latencies: None,
+            hold: None,
+            resolver: Arc::new(SystemResolver),
             sink,
         }
     }
@@ -202,51 +565,196 @@ where
         self
     }
 
+    /// Sets the tracker that the pool's backend connections count their file descriptors against.
+    pub fn set_fd_tracker(mut self, fds: Arc<FdTracker>) -> Self {
+        self.fds = Some(fds);
+        self
+    }
+
+    /// Sets the histogram that the pool's backends record the latency of every batch in.
+    pub fn set_batch_latencies(mut self, batch_latencies: Arc<LatencyBuckets>) -> Self {
+        self.batch_latencies = Some(batch_latencies);
+        self
+    }
+
+    /// Sets the hold on the listener version the pool belongs to, which is kept until the pool is
+    /// dropped.
+    pub fn set_version_hold(mut self, hold: VersionHold) -> Self {
+        self.hold = Some(hold);
+        self
+    }
+
+    /// Sets the resolver that the hostnames of the pool's backends are resolved again with.
+    #[cfg(test)]
+    pub fn set_resolver(mut self, resolver: Arc<Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
     pub fn build(self) -> Result<BackendPool<P>, CreationError>
     where
         P: Processor + Clone + Send + 'static,
         P::Message: Message + Send + 'static,
     {
-        let mut options = self.config.options.unwrap_or_else(HashMap::new);
-        let dist_type = options
-            .entry("distribution".to_owned())
-            .or_insert_with(|| "modulo".to_owned())
-            .to_lowercase();
-        let distributor = configure_distributor(&dist_type)?;
-        debug!("[listener] using distributor '{}'", dist_type);
-
-        let hash_type = options
-            .entry("hash".to_owned())
-            .or_insert_with(|| "fnv1a_64".to_owned())
-            .to_lowercase();
-        let hasher = configure_hasher(&hash_type)?;
-        debug!("[listener] using hasher '{}'", hash_type);
-
-        // Build all of our backends for this pool.
+        let Placement {
+            distributor,
+            hasher,
+            transforms,
+            migration,
+        } = Placement::from_config(&self.config)?;
+
+        let options = self.config.options;
+        let ttl_policy = TtlPolicy::from_options(&options)?;
+        if let Some(policy) = ttl_policy {
+            debug!("[listener] requiring TTLs with policy {:?}", policy);
+        }
+
+        let allow_blocking = options.allow_blocking;
+        if allow_blocking {
+            debug!("[listener] running blocking commands on dedicated connections");
+        }
+
+        // Counting hits and misses means looking at every lookup response, so it can be turned off.
+        let track_hits = options.track_hits;
+
+        let retry_budget = RetryBudget::from_options(&options)?;
+
+        // Every backend starts out with the weight it was configured with.  A weight of zero keeps a
+        // backend connected, ready to take traffic, without sending it any yet.
+        if self.config.addresses.iter().any(|address| address.weight > MAX_WEIGHT) {
+            return Err(CreationError::InvalidParameter("addresses.weight".to_string()));
+        }
+        let weights = BackendWeights::new(
+            self.config
+                .addresses
+                .iter()
+                .map(|address| (address.address, address.weight))
+                .collect(),
+        );
+
+        // Backends given by hostname have already been resolved once, but keep being resolved for as
+        // long as the pool is around, so that they follow their hostnames wherever they go.  That
+        // stops along with the pools themselves when shutting down.
+        let addresses = Arc::new(ResolvedAddresses::new(&self.config.addresses));
+        if let Some(interval) = refresh_interval_from_options(&options) {
+            let name = format!("{}.dns_refresh", self.sink.scope());
+            let refreshing = lifecycle::register(ShutdownPhase::StopPools, name);
+            let stop = refreshing.signal().map(move |_| drop(refreshing));
+            ResolvedAddresses::refresh_every(&addresses, self.resolver.clone(), interval, stop);
+        }
+
+        // Build all of our backends for this pool, all running on the same clock as the pool itself.
+        // They're held in their configured order, so the index of each is also its position.
+        let clock = system_clock();
+        let settings = BackendSettings {
+            pool_name: self.name,
+            options,
+            noreply: self.noreply,
+            fds: self.fds,
+            clock: clock.clone(),
+            sink: self.sink.clone(),
+        };
         let mut backends = Vec::new();
-        for address in &self.config.addresses {
-            let backend = Backend::new(
-                address.address,
-                address.identifier.clone(),
-                self.processor.clone(),
-                options.clone(),
-                self.noreply,
-                self.sink.clone(),
-            )?;
+        for (idx, address) in self.config.addresses.iter().enumerate() {
+            let mut backend =
+                Backend::new(idx, address.address, address.identifier.clone(), self.processor.clone(), &settings)?;
+            if let Some(batch_latencies) = self.batch_latencies.as_ref() {
+                backend.record_batches(batch_latencies.clone());
+            }
             backends.push(backend);
         }
 
-        Ok(BackendPool::new(backends, distributor, hasher, self.noreply, self.sink))
+        // Lookups that fall back to the old placement of their key are sent by pending responses,
+        // which don't have access to the backends, so they're handed back to us to send.
+        let (fallback_tx, fallback_rx) = mpsc::unbounded_channel();
+
+        // Health checks are sent the same way, so that they go over the same connections as
+        // everything else, rather than connections of their own that might not see any trouble.
+        let (probe_tx, probe_rx) = mpsc::unbounded_channel();
+
+        let sink = self.sink;
+        let draining = lifecycle::register(ShutdownPhase::DrainBackends, sink.scope());
+        let drain_signal = draining.signal();
+        let stopping = lifecycle::register(ShutdownPhase::StopPools, sink.scope());
+        let availability = Arc::new(BackendAvailability::new(backends.len()));
+        let activity = BackendActivity::new(
+            backends
+                .iter()
+                .map(|backend| (backend.identifier().to_owned(), backend.address()))
+                .collect(),
+        );
+
+        let mut pool = BackendPool {
+            processor: self.processor,
+            distributor,
+            key_hasher: hasher,
+            transforms: Arc::new(transforms),
+            backends,
+            noreply: self.noreply,
+            ttl_policy,
+            allow_blocking,
+            hit_tracker: if track_hits {
+                Some(Arc::new(HitTracker::default()))
+            } else {
+                None
+            },
+            retry_budget: Arc::new(retry_budget),
+            weights: Arc::new(weights),
+            addresses,
+            availability,
+            activity: Arc::new(activity),
+            migration,
+            fallback_tx,
+            fallback_rx,
+            probe_tx,
+            probe_rx,
+            epoch: 0,
+            clock,
+            weights_generation: 0,
+            addresses_generation: 0,
+            availability_generation: 0,
+            draining: Some(draining),
+            drain_signal,
+            _stopping: stopping,
+            _hold: self.hold,
+            sink,
+        };
+        pool.weights_generation = pool.weights.generation();
+        pool.addresses_generation = pool.addresses.generation();
+        pool.update_addresses();
+        pool.regenerate_distribution();
+        pool.report_weights();
+        Ok(pool)
     }
 }
 
+/// The lookups in a pool request whose responses should be counted as hits or misses.
+struct HitTracking<P> {
+    processor: P,
+    tracker: Arc<HitTracker>,
+    lookups: HashMap<usize, usize>,
+    sink: MetricSink,
+}
+
+/// What's needed to give the keys in the responses to a pool request back to the client as it sent
+/// them, for requests whose keys were changed by key transforms on their way to a backend.
+struct KeyRestoring<P> {
+    processor: P,
+    original_keys: HashMap<usize, OriginalKeys>,
+}
+
 pub struct PoolResponse<P>
 where
     P: Processor + Send + 'static,
     P::Message: Message + Send + 'static,
 {
     responses: JoinAll<Vec<ResponseFuture<P, BackendError>>>,
-    _processor: PhantomData<P>,
+    hit_tracking: Option<HitTracking<P>>,
+    key_restoring: Option<KeyRestoring<P>>,
+    fallback: Option<MigrationFallback<P>>,
+    pending_fallback: Option<(AssignedResponses<P::Message>, ResponseFuture<P, PoolError>)>,
+    retry_budget: Arc<RetryBudget>,
+    retries: HashSet<usize>,
 }
 
 impl<P> PoolResponse<P>
@@ -254,10 +762,56 @@ where
     P: Processor + Send + 'static,
     P::Message: Message + Send + 'static,
 {
-    pub fn new(responses: Vec<ResponseFuture<P, BackendError>>) -> PoolResponse<P> {
+    fn new(
+        responses: Vec<ResponseFuture<P, BackendError>>, hit_tracking: Option<HitTracking<P>>,
+        key_restoring: Option<KeyRestoring<P>>, fallback: Option<MigrationFallback<P>>, retry_budget: Arc<RetryBudget>,
+        retries: HashSet<usize>,
+    ) -> PoolResponse<P> {
         PoolResponse {
             responses: join_all(responses),
-            _processor: PhantomData,
+            hit_tracking,
+            key_restoring,
+            fallback,
+            pending_fallback: None,
+            retry_budget,
+            retries,
+        }
+    }
+
+    fn track_hits(&mut self, responses: &AssignedResponses<P::Message>) {
+        if let Some(tracking) = self.hit_tracking.take() {
+            let mut hits = 0;
+            let mut misses = 0;
+            for (id, response) in responses {
+                if let (Some(keys), MessageResponse::Complete(msg)) = (tracking.lookups.get(id), response) {
+                    if let Some((h, m)) = tracking.processor.count_lookup_hits(*keys, msg) {
+                        hits += h;
+                        misses += m;
+                    }
+                }
+            }
+
+            tracking.tracker.record(hits, misses, &tracking.sink);
+        }
+    }
+
+    fn restore_keys(&mut self, responses: AssignedResponses<P::Message>) -> AssignedResponses<P::Message> {
+        match self.key_restoring.take() {
+            Some(restoring) => {
+                responses
+                    .into_iter()
+                    .map(|(id, response)| {
+                        let response = match (response, restoring.original_keys.get(&id)) {
+                            (MessageResponse::Complete(msg), Some(original)) => {
+                                MessageResponse::Complete(restoring.processor.restore_keys(msg, original))
+                            },
+                            (response, _) => response,
+                        };
+                        (id, response)
+                    })
+                    .collect()
+            },
+            None => responses,
         }
     }
 }
@@ -271,8 +825,200 @@ where
     type Item = AssignedResponses<P::Message>;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let result = try_ready!(self.responses.poll());
-        let flattened = result.into_iter().flatten().collect::<Vec<_>>();
-        Ok(Async::Ready(flattened))
+        if self.pending_fallback.is_none() {
+            let result = try_ready!(self.responses.poll());
+            let flattened = result.into_iter().flatten().collect::<Vec<_>>();
+
+            // Every request that made it to a backend and back helps fund retries, as long as it wasn't
+            // a retry itself.
+            let retries = &self.retries;
+            let completed = flattened
+                .iter()
+                .filter(|(id, response)| match response {
+                    MessageResponse::Complete(_) => !retries.contains(id),
+                    MessageResponse::Failed | MessageResponse::TimedOut => false,
+                })
+                .count();
+            self.retry_budget.deposit(completed);
+
+            // Lookups that missed at the new placement of their key get a second try at the old one.
+            let fallbacks = match self.fallback {
+                Some(ref mut fallback) => fallback.start(&flattened),
+                None => None,
+            };
+            match fallbacks {
+                Some(fallbacks) => self.pending_fallback = Some((flattened, fallbacks)),
+                None => {
+                    self.track_hits(&flattened);
+                    return Ok(Async::Ready(self.restore_keys(flattened)));
+                },
+            }
+        }
+
+        let fallbacks = match self.pending_fallback {
+            Some((_, ref mut fallbacks)) => try_ready!(fallbacks.poll()),
+            None => unreachable!("fallback responses polled without pending fallbacks"),
+        };
+        let (mut flattened, _) = self.pending_fallback.take().expect("pending fallbacks disappeared");
+        if let Some(ref fallback) = self.fallback {
+            fallback.finish(&mut flattened, fallbacks);
+        }
+
+        self.track_hits(&flattened);
+        Ok(Async::Ready(self.restore_keys(flattened)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{
+        redis::RedisProcessor,
+        resolver::{resolve_backends, HostsFile},
+    };
+    use conf::PoolOptions;
+    use futures::future::poll_fn;
+    use metrics::get_sink;
+    use protocol::redis::{RedisMessage, RedisTransportConfig};
+    use serde_json;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+    use tokio::runtime::current_thread;
+
+    // A backend that answers every `GET foo` with a miss, handing back how many it has answered.
+    fn serve(listener: TcpListener) -> Arc<AtomicUsize> {
+        let served = Arc::new(AtomicUsize::new(0));
+        let served2 = served.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+
+                let served = served2.clone();
+                thread::spawn(move || {
+                    // `GET foo` spans five lines on the wire, so that's how we count requests.
+                    let mut buf = [0; 8192];
+                    let mut lines = 0;
+                    loop {
+                        let n = match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => n,
+                        };
+                        lines += buf[..n].iter().filter(|b| **b == b'\n').count();
+
+                        let mut replies = Vec::new();
+                        while lines >= 5 {
+                            lines -= 5;
+                            served.fetch_add(1, Ordering::SeqCst);
+                            replies.extend_from_slice(b"$-1\r\n");
+                        }
+
+                        if stream.write_all(&replies).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        served
+    }
+
+    fn call_pool(pool: &mut BackendPool<RedisProcessor>, runtime: &mut current_thread::Runtime) {
+        runtime.block_on(poll_fn(|| pool.poll_ready())).unwrap();
+        let mut response = pool.call(vec![EnqueuedRequest::new(0, RedisMessage::from_inline("GET foo"))]);
+        let responses = runtime
+            .block_on(poll_fn(|| {
+                pool.poll_service()?;
+                response.poll()
+            }))
+            .unwrap();
+        assert_eq!(responses.len(), 1);
+    }
+
+    #[test]
+    fn test_follows_moved_backend() {
+        // The same port on two different loopback addresses, standing in for a pod before and after
+        // it's rescheduled.
+        let old = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = old.local_addr().unwrap().port();
+        let new = TcpListener::bind(("127.0.0.2", port)).unwrap();
+        let (old_served, new_served) = (serve(old), serve(new));
+
+        let hosts = HostsFile::new("127.0.0.1 redis-0\n");
+        let resolver: Arc<Resolver> = hosts.clone();
+        let backends = vec![serde_json::from_str(&format!("\"redis-0:{}\"", port)).unwrap()];
+        let options = [("dns_refresh_ms", "10"), ("conns_per_backend", "1")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let config = PoolConfiguration {
+            addresses: resolve_backends(backends, &resolver).wait().unwrap(),
+            options: PoolOptions::from_map(options).unwrap(),
+            migration: None,
+            expected_placements: None,
+            key_transforms: None,
+        };
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let mut pool = BackendPoolBuilder::new("test".to_owned(), processor, config, get_sink())
+            .set_resolver(resolver)
+            .build()
+            .unwrap();
+
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        for _ in 0..200 {
+            call_pool(&mut pool, &mut runtime);
+            if old_served.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(old_served.load(Ordering::SeqCst) > 0);
+
+        // Once the hostname points somewhere else, the pool picks it up on its own, and requests
+        // start going to the new address.
+        hosts.rewrite("127.0.0.2 redis-0\n");
+        for _ in 0..200 {
+            call_pool(&mut pool, &mut runtime);
+            if new_served.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(new_served.load(Ordering::SeqCst) > 0);
+
+        // Nothing goes back to the old address after that.
+        let old_count = old_served.load(Ordering::SeqCst);
+        for _ in 0..10 {
+            call_pool(&mut pool, &mut runtime);
+        }
+        assert_eq!(old_served.load(Ordering::SeqCst), old_count);
+    }
+
+    #[test]
+    fn test_hit_ratio_follows_recent_lookups() {
+        let tracker = HitTracker::default();
+        assert_eq!(tracker.count(3, 1), 7_500);
+
+        // A pool that used to hit everything, and now misses everything, soon stops looking healthy.
+        for _ in 0..HIT_RATIO_WINDOW {
+            tracker.count(1, 0);
+        }
+        assert_eq!(tracker.count(0, 1), 9_999);
+        for _ in 0..HIT_RATIO_WINDOW * 2 {
+            tracker.count(0, 1);
+        }
+        assert!(tracker.count(0, 1) < 2_500);
+
+        // Counts too big to add up, or to turn into basis points, don't overflow.
+        let tracker = HitTracker::default();
+        assert_eq!(tracker.count(u64::max_value(), 0), 10_000);
+        assert_eq!(tracker.count(u64::max_value(), u64::max_value()), 5_000);
     }
 }
@@ -0,0 +1,622 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::{drain::BackendActivity, processor::Processor};
+use common::{EnqueuedRequest, Lane, Message, MessageResponse, PendingResponse};
+use conf::PoolOptions;
+use errors::CreationError;
+use events::{self, EventKind};
+use futures::prelude::*;
+use metrics::MetricSink;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use util::clock::{ClockDelay, SharedClock};
+
+// A check that's been sent, and the deadline for its response.
+type ProbeFuture<T> = (PendingResponse<T>, ClockDelay);
+
+lazy_static! {
+    static ref AVAILABILITY: Mutex<HashMap<(String, String), Arc<BackendAvailability>>> = Mutex::new(HashMap::new());
+}
+
+/// Registers the backend availability for a pool, replacing that of any previous version of the pool.
+pub fn register_backend_availability(listener: &str, pool: &str, availability: Arc<BackendAvailability>) {
+    let mut registry = AVAILABILITY.lock().unwrap();
+    registry.insert((listener.to_owned(), pool.to_owned()), availability);
+}
+
+/// Gets the backend availability for the given pool, if the pool exists.
+pub fn find_backend_availability(listener: &str, pool: &str) -> Option<Arc<BackendAvailability>> {
+    let registry = AVAILABILITY.lock().unwrap();
+    registry.get(&(listener.to_owned(), pool.to_owned())).cloned()
+}
+
+/// Active health check settings for a pool, parsed from its options.
+#[derive(Clone, Debug)]
+pub struct HealthCheckConfiguration {
+    /// How often every backend is checked.
+    pub interval: Duration,
+
+    /// How long a backend has to answer a check before it counts as a failure.
+    pub timeout: Duration,
+
+    /// How many checks in a row a backend has to fail before it's ejected.
+    pub eject_after: usize,
+
+    /// How many checks in a row an ejected backend has to pass before it's restored.
+    pub restore_after: usize,
+
+    /// How many requests a backend has to be working on for a check that times out to be put down
+    /// to the backend being busy, rather than failing.
+    pub saturation_depth: usize,
+}
+
+impl HealthCheckConfiguration {
+    /// Extracts the health check configuration from the given pool options, if health checks are
+    /// enabled.
+    pub fn from_options(options: &PoolOptions) -> Result<Option<HealthCheckConfiguration>, CreationError> {
+        let interval_ms = match options.health_check_interval_ms {
+            Some(ms) => check_positive(ms, "health_check_interval_ms")?,
+            None => return Ok(None),
+        };
+
+        let timeout_ms = match options.health_check_timeout_ms {
+            Some(ms) => check_positive(ms, "health_check_timeout_ms")?,
+            None => interval_ms,
+        };
+
+        Ok(Some(HealthCheckConfiguration {
+            interval: Duration::from_millis(interval_ms),
+            timeout: Duration::from_millis(timeout_ms),
+            eject_after: check_positive(options.health_check_eject_after, "health_check_eject_after")?,
+            restore_after: check_positive(options.health_check_restore_after, "health_check_restore_after")?,
+            saturation_depth: check_positive(options.health_check_saturation_depth, "health_check_saturation_depth")?,
+        }))
+    }
+}
+
+fn check_positive<T: Default + PartialEq>(value: T, name: &str) -> Result<T, CreationError> {
+    if value == T::default() {
+        return Err(CreationError::InvalidParameter(format!("options.{}", name)));
+    }
+    Ok(value)
+}
+
+/// Which backends in a pool have been ejected by its health checks, indexed by their configured
+/// position.
+///
+/// Ejecting or restoring a backend bumps the generation, which the pool watches for so that it can
+/// reseed its distributor on its own task, the same way it does for weight changes.
+pub struct BackendAvailability {
+    ejected: Vec<AtomicBool>,
+    generation: AtomicUsize,
+}
+
+impl BackendAvailability {
+    pub fn new(backends: usize) -> BackendAvailability {
+        BackendAvailability {
+            ejected: (0..backends).map(|_| AtomicBool::new(false)).collect(),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether or not the backend at the given configured position has been ejected.
+    pub fn is_ejected(&self, idx: usize) -> bool { self.ejected[idx].load(Ordering::Acquire) }
+
+    /// Ejects, or restores, the backend at the given configured position.
+    pub fn set_ejected(&self, idx: usize, ejected: bool) {
+        self.ejected[idx].store(ejected, Ordering::Release);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Gets how many backends are currently ejected.
+    pub fn ejected_count(&self) -> usize {
+        self.ejected.iter().filter(|ejected| ejected.load(Ordering::Acquire)).count()
+    }
+
+    /// Gets the current generation, which changes whenever a backend is ejected or restored.
+    pub fn generation(&self) -> usize { self.generation.load(Ordering::Acquire) }
+}
+
+/// A health check to send to the backend at the given configured position.
+pub struct ProbeRequest<T: Message + Clone> {
+    pub backend_idx: usize,
+    pub request: EnqueuedRequest<T>,
+}
+
+/// What a health checker needs from the pool whose backends it checks.
+pub struct ProbedPool<T: Message + Clone> {
+    /// Where backends are ejected and restored.
+    pub availability: Arc<BackendAvailability>,
+    /// Which backends there are to check, and how busy they are.
+    pub activity: Arc<BackendActivity>,
+    /// Where checks are handed to the pool to be sent.
+    pub probes: mpsc::UnboundedSender<ProbeRequest<T>>,
+    /// The clock the pool runs on.
+    pub clock: SharedClock,
+}
+
+/// How a check of a backend turned out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ProbeOutcome {
+    Passed,
+    Failed,
+
+    /// The check timed out while the backend was busy with a deep queue of requests, which says
+    /// nothing about whether or not it's healthy.
+    Saturated,
+}
+
+struct ProbedBackend<T> {
+    identifier: String,
+    probe: Option<ProbeFuture<T>>,
+
+    // How many checks the backend has passed, or failed, in a row.
+    successes: usize,
+    failures: usize,
+}
+
+/// Actively checks that the backends of a pool are answering.
+///
+/// Every interval, each backend is sent a check.  Checks are handed to the pool, which sends them
+/// over the same connections as client requests, in the priority lane, so that they go out ahead
+/// of any requests that are already waiting.  A backend that fails enough checks in a row is
+/// ejected from the pool, and it's restored once it passes enough checks in a row.  A check fails
+/// if the backend errors, if its connection fails, or if it doesn't answer in time.
+///
+/// Even a check in the priority lane has to wait for whatever batch is already in flight, though,
+/// so a check that times out while the backend has a deep queue is put down to the backend being
+/// saturated, and neither passes nor fails: ejecting a backend for being busy only piles its work
+/// onto the others.  Backends are ejected and restored through the pool's availability, so the
+/// pool reseeds its distributor just like it would for a weight change.  The checker stops when
+/// `close` resolves.
+pub struct HealthChecker<P, C>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+{
+    pool_name: String,
+    config: HealthCheckConfiguration,
+    processor: P,
+    availability: Arc<BackendAvailability>,
+    activity: Arc<BackendActivity>,
+    probes: mpsc::UnboundedSender<ProbeRequest<P::Message>>,
+    backends: Vec<ProbedBackend<P::Message>>,
+    clock: SharedClock,
+    next_check_at: Instant,
+    next_check: ClockDelay,
+    close: C,
+    sink: MetricSink,
+}
+
+impl<P, C> HealthChecker<P, C>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+    C: Future,
+{
+    /// Creates a new `HealthChecker`.
+    ///
+    /// Checks are handed to the pool through its `probes`, and the backends it checks are those that
+    /// its `activity` tracks.  The first round of checks is sent right away, and checks are timed by
+    /// the pool's clock.
+    pub fn new(
+        pool_name: String, config: HealthCheckConfiguration, processor: P, pool: ProbedPool<P::Message>, close: C,
+        sink: MetricSink,
+    ) -> HealthChecker<P, C> {
+        let ProbedPool {
+            availability,
+            activity,
+            probes,
+            clock,
+        } = pool;
+        let backends = activity
+            .identifiers()
+            .iter()
+            .map(|identifier| {
+                ProbedBackend {
+                    identifier: identifier.clone(),
+                    probe: None,
+                    successes: 0,
+                    failures: 0,
+                }
+            })
+            .collect();
+        let next_check_at = clock.now();
+        let next_check = clock.delay(next_check_at);
+
+        HealthChecker {
+            pool_name,
+            config,
+            processor,
+            availability,
+            activity,
+            probes,
+            backends,
+            clock,
+            next_check_at,
+            next_check,
+            close,
+            sink,
+        }
+    }
+
+    /// Starts checking every backend that isn't still waiting on its last check.
+    fn start_probes(&mut self) {
+        for (idx, backend) in self.backends.iter_mut().enumerate() {
+            if backend.probe.is_some() {
+                continue;
+            }
+
+            let mut request = EnqueuedRequest::new(0, self.processor.get_health_check_request());
+            request.set_lane(Lane::Priority);
+            let response = request.get_response_rx().expect("new requests always have a response");
+
+            // If the pool is gone, so are its backends, and we'll be closed soon enough.
+            let probe = ProbeRequest {
+                backend_idx: idx,
+                request,
+            };
+            if self.probes.try_send(probe).is_err() {
+                return;
+            }
+
+            let deadline = self.clock.delay(self.clock.now() + self.config.timeout);
+            backend.probe = Some((response, deadline));
+            self.sink.increment("checks");
+        }
+    }
+
+    /// Drives any checks that are in flight, recording the outcome of those that are done.
+    fn poll_probes(&mut self) {
+        for idx in 0..self.backends.len() {
+            let outcome = {
+                let (response, deadline) = match self.backends[idx].probe.as_mut() {
+                    Some(probe) => (&mut probe.0, &mut probe.1),
+                    None => continue,
+                };
+
+                match response.poll() {
+                    Ok(Async::Ready((_, MessageResponse::Complete(msg)))) => {
+                        if self.processor.is_error_response(&msg) {
+                            ProbeOutcome::Failed
+                        } else {
+                            ProbeOutcome::Passed
+                        }
+                    },
+                    // The check never got an answer, because the connection it went out on failed.
+                    Ok(Async::Ready((_, MessageResponse::Failed))) => ProbeOutcome::Failed,
+                    Ok(Async::Ready((_, MessageResponse::TimedOut))) => self.timed_out(idx),
+                    Ok(Async::NotReady) => {
+                        match deadline.poll() {
+                            Ok(Async::NotReady) => continue,
+                            _ => self.timed_out(idx),
+                        }
+                    },
+                    Err(e) => {
+                        debug!(
+                            "[health] pool '{}': check of backend '{}' failed: {:?}",
+                            self.pool_name, self.backends[idx].identifier, e
+                        );
+                        ProbeOutcome::Failed
+                    },
+                }
+            };
+
+            self.backends[idx].probe = None;
+            self.record(idx, outcome);
+        }
+    }
+
+    /// Works out what a check of the given backend that timed out says about it.
+    fn timed_out(&self, idx: usize) -> ProbeOutcome {
+        let in_flight = self.activity.in_flight(idx);
+        if in_flight >= self.config.saturation_depth {
+            debug!(
+                "[health] pool '{}': check of backend '{}' timed out behind {} requests",
+                self.pool_name, self.backends[idx].identifier, in_flight
+            );
+            ProbeOutcome::Saturated
+        } else {
+            ProbeOutcome::Failed
+        }
+    }
+
+    /// Records the outcome of a check of the given backend, ejecting or restoring it as needed.
+    fn record(&mut self, idx: usize, outcome: ProbeOutcome) {
+        let ejected = self.availability.is_ejected(idx);
+        let backend = &mut self.backends[idx];
+
+        match outcome {
+            ProbeOutcome::Passed => {
+                backend.failures = 0;
+                backend.successes += 1;
+
+                if ejected && backend.successes >= self.config.restore_after {
+                    info!(
+                        "[health] pool '{}': restoring backend '{}' after {} passed checks",
+                        self.pool_name, backend.identifier, backend.successes
+                    );
+                    self.availability.set_ejected(idx, false);
+                    self.sink.increment("backend_restored");
+                    events::publish(
+                        EventKind::BackendRestored,
+                        Some(&self.pool_name),
+                        Some(&backend.identifier),
+                        format!("passed {} checks in a row", backend.successes),
+                    );
+                }
+            },
+            ProbeOutcome::Failed => {
+                self.sink.increment("failed_checks");
+                backend.successes = 0;
+                backend.failures += 1;
+
+                if !ejected && backend.failures >= self.config.eject_after {
+                    warn!(
+                        "[health] pool '{}': ejecting backend '{}' after {} failed checks",
+                        self.pool_name, backend.identifier, backend.failures
+                    );
+                    self.availability.set_ejected(idx, true);
+                    self.sink.increment("backend_ejected");
+                    events::publish(
+                        EventKind::BackendEjected,
+                        Some(&self.pool_name),
+                        Some(&backend.identifier),
+                        format!("failed {} checks in a row", backend.failures),
+                    );
+                }
+            },
+            // A busy backend isn't any closer to being ejected, or restored, than it was before.
+            ProbeOutcome::Saturated => self.sink.increment("saturated_checks"),
+        }
+
+        self.sink.update_gauge("ejected_backends", self.availability.ejected_count() as u64);
+    }
+}
+
+impl<P, C> Future for HealthChecker<P, C>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+    C: Future,
+{
+    type Error = ();
+    type Item = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // If we've been told to close, the pool is going away, and its availability with it.
+        match self.close.poll() {
+            Ok(Async::NotReady) => {},
+            _ => return Ok(Async::Ready(())),
+        }
+
+        loop {
+            match self.next_check.poll() {
+                Ok(Async::Ready(())) => {
+                    self.start_probes();
+                    self.next_check_at += self.config.interval;
+                    self.next_check = self.clock.delay(self.next_check_at);
+                },
+                Ok(Async::NotReady) => break,
+                Err(()) => {
+                    error!("[health] pool '{}': timer failed", self.pool_name);
+                    return Err(());
+                },
+            }
+        }
+
+        self.poll_probes();
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::redis::RedisProcessor;
+    use futures::future::{empty, Empty};
+    use metrics::get_sink;
+    use futures::future::poll_fn;
+    use protocol::redis::{RedisMessage, RedisTransportConfig};
+    use util::clock::{system_clock, VirtualClock};
+
+    type TestChecker = HealthChecker<RedisProcessor, Empty<(), ()>>;
+    type TestProbes = mpsc::UnboundedReceiver<ProbeRequest<RedisMessage>>;
+
+    fn get_checker(eject_after: usize, restore_after: usize) -> TestChecker {
+        get_checker_with_clock(eject_after, restore_after, system_clock()).0
+    }
+
+    fn get_checker_with_clock(
+        eject_after: usize, restore_after: usize, clock: SharedClock,
+    ) -> (TestChecker, TestProbes) {
+        let backends = (0..2)
+            .map(|idx| (format!("backend-{}", idx), format!("127.0.0.1:{}", 6379 + idx).parse().unwrap()))
+            .collect();
+        let config = HealthCheckConfiguration {
+            interval: Duration::from_millis(100),
+            timeout: Duration::from_millis(100),
+            eject_after,
+            restore_after,
+            saturation_depth: 256,
+        };
+        let (probes, probes_rx) = mpsc::unbounded_channel();
+
+        let checker = HealthChecker::new(
+            "default".to_owned(),
+            config,
+            RedisProcessor::new(RedisTransportConfig::default()),
+            ProbedPool {
+                availability: Arc::new(BackendAvailability::new(2)),
+                activity: Arc::new(BackendActivity::new(backends)),
+                probes,
+                clock,
+            },
+            empty(),
+            get_sink(),
+        );
+        (checker, probes_rx)
+    }
+
+    // Polls the checker once, handing back every check it sent.
+    fn poll_checker(checker: &mut TestChecker, probes: &mut TestProbes) -> Vec<ProbeRequest<RedisMessage>> {
+        poll_fn(|| {
+            checker.poll().unwrap();
+
+            let mut sent = Vec::new();
+            while let Ok(Async::Ready(Some(probe))) = probes.poll() {
+                sent.push(probe);
+            }
+            Ok::<_, ()>(Async::Ready(sent))
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_options() {
+        let mut options = PoolOptions::default();
+        assert!(HealthCheckConfiguration::from_options(&options).unwrap().is_none());
+
+        options.health_check_interval_ms = Some(500);
+        let config = HealthCheckConfiguration::from_options(&options).unwrap().unwrap();
+        assert_eq!(config.interval, Duration::from_millis(500));
+        assert_eq!(config.timeout, Duration::from_millis(500));
+        assert_eq!(config.eject_after, 3);
+        assert_eq!(config.restore_after, 2);
+        assert_eq!(config.saturation_depth, 256);
+
+        options.health_check_timeout_ms = Some(100);
+        let config = HealthCheckConfiguration::from_options(&options).unwrap().unwrap();
+        assert_eq!(config.timeout, Duration::from_millis(100));
+
+        options.health_check_saturation_depth = 32;
+        let config = HealthCheckConfiguration::from_options(&options).unwrap().unwrap();
+        assert_eq!(config.saturation_depth, 32);
+
+        options.health_check_eject_after = 0;
+        assert!(HealthCheckConfiguration::from_options(&options).is_err());
+
+        options.health_check_eject_after = 1;
+        options.health_check_restore_after = 0;
+        assert!(HealthCheckConfiguration::from_options(&options).is_err());
+
+        options.health_check_restore_after = 1;
+        options.health_check_interval_ms = Some(0);
+        assert!(HealthCheckConfiguration::from_options(&options).is_err());
+    }
+
+    #[test]
+    fn test_eject_and_restore() {
+        let mut checker = get_checker(3, 2);
+        let generation = checker.availability.generation();
+
+        // A couple of failures isn't enough, and a pass in between starts the count over.
+        checker.record(1, ProbeOutcome::Failed);
+        checker.record(1, ProbeOutcome::Failed);
+        checker.record(1, ProbeOutcome::Passed);
+        checker.record(1, ProbeOutcome::Failed);
+        checker.record(1, ProbeOutcome::Failed);
+        assert!(!checker.availability.is_ejected(1));
+        assert_eq!(checker.availability.generation(), generation);
+
+        checker.record(1, ProbeOutcome::Failed);
+        assert!(checker.availability.is_ejected(1));
+        assert!(!checker.availability.is_ejected(0));
+        assert_eq!(checker.availability.ejected_count(), 1);
+
+        // Staying down doesn't eject it all over again.
+        let generation = checker.availability.generation();
+        checker.record(1, ProbeOutcome::Failed);
+        assert_eq!(checker.availability.generation(), generation);
+
+        // It has to pass enough checks in a row to come back.
+        checker.record(1, ProbeOutcome::Passed);
+        checker.record(1, ProbeOutcome::Failed);
+        checker.record(1, ProbeOutcome::Passed);
+        assert!(checker.availability.is_ejected(1));
+
+        checker.record(1, ProbeOutcome::Passed);
+        assert!(!checker.availability.is_ejected(1));
+        assert_eq!(checker.availability.ejected_count(), 0);
+    }
+
+    #[test]
+    fn test_saturated_checks() {
+        let mut checker = get_checker(2, 1);
+
+        // A check that times out behind a deep queue doesn't count against the backend, however
+        // many times it happens.
+        checker.activity.update(1, 10_000, 300);
+        assert_eq!(checker.timed_out(1), ProbeOutcome::Saturated);
+        checker.record(1, ProbeOutcome::Failed);
+        for _ in 0..5 {
+            let outcome = checker.timed_out(1);
+            checker.record(1, outcome);
+        }
+        assert!(!checker.availability.is_ejected(1));
+
+        // Once the queue is gone, though, timing out is as bad as any other failure.
+        checker.activity.update(1, 10_000, 1);
+        assert_eq!(checker.timed_out(1), ProbeOutcome::Failed);
+        checker.record(1, ProbeOutcome::Failed);
+        assert!(checker.availability.is_ejected(1));
+    }
+
+    #[test]
+    fn test_checks_on_clock() {
+        let clock = VirtualClock::new();
+        let (mut checker, mut probes) = get_checker_with_clock(2, 1, Arc::new(clock.clone()));
+
+        // The first round of checks goes out right away, and the next only once the interval is up.
+        let sent = poll_checker(&mut checker, &mut probes);
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|probe| probe.request.lane() == Lane::Priority));
+        clock.advance(Duration::from_millis(99));
+        assert!(poll_checker(&mut checker, &mut probes).is_empty());
+
+        // Answering one backend passes its check, while the other runs out of time and fails.  The
+        // next round isn't sent until then, since both were still waiting on their checks.
+        let mut sent = sent.into_iter();
+        let mut answered = sent.next().unwrap();
+        let _unanswered = sent.next().unwrap();
+        answered.request.fulfill(RedisMessage::OK);
+        clock.advance(Duration::from_millis(1));
+        assert!(poll_checker(&mut checker, &mut probes).is_empty());
+        assert_eq!(checker.backends[0].successes, 1);
+        assert_eq!(checker.backends[1].failures, 1);
+
+        // Another timeout is enough to eject it.
+        clock.advance(Duration::from_millis(100));
+        let pending = poll_checker(&mut checker, &mut probes);
+        assert_eq!(pending.len(), 2);
+        clock.advance(Duration::from_millis(100));
+        assert!(poll_checker(&mut checker, &mut probes).is_empty());
+        assert!(checker.availability.is_ejected(1));
+        assert!(!checker.availability.is_ejected(0));
+    }
+}
@@ -20,13 +20,24 @@
 mod errors;
 pub use self::errors::ProcessorError;
 
-use backend::message_queue::MessageState;
+use backend::{
+    message_queue::MessageState, responses::ResponseSizeTracker, subscription::SubscriptionRequest,
+    transform::{KeyTransforms, OriginalKeys},
+};
+use bytes::BytesMut;
 use common::{EnqueuedRequests, Message};
+use conf::PoolOptions;
+use errors::CreationError;
 use futures::future::{Either, FutureResult};
 use protocol::errors::ProtocolError;
-use std::{error::Error, net::SocketAddr};
+use service::ClientStats;
+use std::{
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 use tokio::net::tcp::TcpStream;
-use util::ProcessFuture;
+use util::{ClientStream, ProcessFuture};
 
 /// An existing or pending TcpStream.
 pub type TcpStreamFuture = Either<FutureResult<TcpStream, ProtocolError>, ProcessFuture>;
@@ -45,28 +56,154 @@ where
     /// correct backend server when routed.
     fn fragment_messages(&self, Vec<Self::Message>) -> Result<Vec<(MessageState, Self::Message)>, ProcessorError>;
 
+    /// Gets the number of subrequests that the given request will be fragmented into.
+    ///
+    /// Requests that aren't fragmented count as a single subrequest.
+    fn get_fragment_count(&self, &Self::Message) -> usize;
+
     /// Defragments a client's subrequests into a single request.
     ///
     /// This is used to do any coalesing necessary to assemble multiple subrequests -- generated by
     /// `fragment_messages` -- back into a cohesive response that the client will understand.
     fn defragment_messages(&self, Vec<(MessageState, Self::Message)>) -> Result<Self::Message, ProcessorError>;
 
+    /// Builds a simple read request for the given key.
+    ///
+    /// This is used for proxy-originated traffic, such as warming up a pool, where we need to
+    /// issue reads without a client having sent them.
+    fn get_read_request(&self, &[u8]) -> Self::Message;
+
+    /// Whether or not the given request could create a key without a TTL.
+    fn is_missing_ttl(&self, &Self::Message) -> bool;
+
+    /// Gets the key the given request creates, which is where a TTL it's missing belongs.
+    ///
+    /// This is the key the request is routed by, unless it creates some other key.
+    fn get_created_key<'a>(&self, &'a Self::Message) -> &'a [u8];
+
+    /// Builds a request that sets the given TTL, in seconds, on the given key, but only if the key
+    /// does not already have a TTL.
+    fn get_default_ttl_request(&self, &[u8], u64) -> Self::Message;
+
+    /// Whether or not the given request writes to the single key it is routed by.
+    fn is_write(&self, &Self::Message) -> bool;
+
+    /// Whether or not the given request only reads, and so can be served by a replica.
+    ///
+    /// Requests for commands we don't know are assumed to write.
+    fn is_read_only(&self, &Self::Message) -> bool;
+
+    /// Whether or not the given request can hold on to the connection it's sent over until it times
+    /// out, rather than being answered right away.
+    fn is_blocking(&self, &Self::Message) -> bool;
+
+    /// Gets the response to the given request if it's one we answer ourselves, without ever sending
+    /// it to a backend.
+    fn get_local_response(&self, &Self::Message) -> Option<Self::Message>;
+
+    /// Gets what the given request changes about the client's subscriptions, if it subscribes or
+    /// unsubscribes.
+    fn get_subscription<'a>(&self, &'a Self::Message) -> Option<SubscriptionRequest<'a>>;
+
+    /// Reads the next message a backend sent over a subscription off of the given buffer.
+    ///
+    /// Returns the message as it should be passed along to the client, along with how many
+    /// subscriptions the connection has left, if the message says, or `None` if the buffer doesn't
+    /// hold a whole message yet.
+    fn read_pushed_message(&self, &mut BytesMut) -> Result<Option<(BytesMut, Option<usize>)>, ProtocolError>;
+
+    /// Builds a request that deletes the given key.
+    fn get_delete_request(&self, &[u8]) -> Self::Message;
+
+    /// Builds a request that checks whether a backend is answering, without touching any data.
+    fn get_health_check_request(&self) -> Self::Message;
+
+    /// Whether or not the given response from a backend is an error.
+    fn is_error_response(&self, &Self::Message) -> bool;
+
+    /// Gets the number of keys looked up by the given request, if it is a lookup whose response
+    /// says whether or not those keys were found.
+    fn get_lookup_keys(&self, &Self::Message) -> Option<usize>;
+
+    /// Counts the hits and misses in the response to a lookup of the given number of keys.
+    ///
+    /// Returns `None` if the response doesn't say either way, such as when it is an error.
+    fn count_lookup_hits(&self, usize, &Self::Message) -> Option<(usize, usize)>;
+
+    /// Rewrites every key in the given request to the key its backend should see.
+    ///
+    /// Keys that can come back in a response are remembered as the client sent them, so that
+    /// `restore_keys` can put them back.
+    fn forward_keys(&self, Self::Message, &KeyTransforms, &mut OriginalKeys) -> Self::Message;
+
+    /// Rewrites every key in the given response back to the key the client sent for it.
+    fn restore_keys(&self, Self::Message, &OriginalKeys) -> Self::Message;
+
+    /// Gets the bytes of the given client request as they should be written to a recording.
+    ///
+    /// If `redact_values` is set, values are overwritten, keeping their lengths, so that a
+    /// recording still exercises the same paths without holding on to any of the data.  Returns
+    /// `None` for messages that didn't come from the client, such as locally generated errors.
+    fn get_recorded_frame(&self, &Self::Message, bool) -> Option<BytesMut>;
+
+    /// Gets the name a client is giving itself with the given request, if it is naming itself.
+    fn get_client_name(&self, &Self::Message) -> Option<String>;
+
+    /// Whether or not the client told us what to route the given request by, rather than its key.
+    fn has_routing_hint(&self, &Self::Message) -> bool;
+
+    /// Gets the identifier of the command the given request is running, if it's a command we know.
+    ///
+    /// Identifiers are dense, running from zero up to `get_command_count`, so that per-command
+    /// state can be kept in a table rather than being looked up by name for every request.
+    fn get_command_id(&self, &Self::Message) -> Option<usize>;
+
+    /// Gets the name of the command the given request is running, if it's a command we know.
+    fn get_command_name(&self, &Self::Message) -> Option<&'static str>;
+
+    /// Gets the identifier of the given command, by name, if it's a command we know.
+    fn find_command_id(&self, &str) -> Option<usize>;
+
+    /// Gets the number of commands we know, and so the upper bound on command identifiers.
+    fn get_command_count(&self) -> usize;
+
     /// Converts the given error into a corresponding format that can be sent to the client.
     fn get_error_message(&self, Box<Error>) -> Self::Message;
 
     /// Converts the given error string into a corresponding format the can be sent to the client.
     fn get_error_message_str(&self, &str) -> Self::Message;
 
-    /// Wraps the given TCP stream with a protocol-specific transport layer, allowing the caller to
-    /// extract protocol-specific messages, as well as send them, via the `Stream` and `Sink`
+    /// Gets the response to send back for the given request if it fails or times out, if it needs
+    /// something more specific than a generic error.
+    fn get_failure_response(&self, &Self::Message) -> Option<Self::Message>;
+
+    /// Wraps the given client stream with a protocol-specific transport layer, allowing the caller
+    /// to extract protocol-specific messages, as well as send them, via the `Stream` and `Sink`
     /// implementations.
-    fn get_transport(&self, TcpStream) -> Self::Transport;
+    ///
+    /// Anything about the client that only the transport knows, such as whether it's in the middle
+    /// of a transaction, is reported to the given client counters.
+    fn get_transport(&self, ClientStream, Arc<ClientStats>) -> Self::Transport;
+
+    /// Gets a processor for the pool with the given options.
+    ///
+    /// Options that change how backend connections are set up, such as credentials, are applied
+    /// here, so that every connection made by the returned processor is set up the same way.
+    fn for_pool(&self, &PoolOptions) -> Result<Self, CreationError>
+    where
+        Self: Sized;
 
     /// Connects to the given address via TCP and performs any necessary processor-specific
     /// initialization.
-    fn preconnect(&self, &SocketAddr, bool) -> ProcessFuture;
+    ///
+    /// If a source address is given, the connection is made from it rather than from whichever
+    /// local address the OS would pick.
+    fn preconnect(&self, &SocketAddr, Option<IpAddr>, bool) -> ProcessFuture;
 
     /// Processes a batch of requests, running the necessary operations against the given TCP
     /// stream.
-    fn process(&self, EnqueuedRequests<Self::Message>, TcpStreamFuture) -> ProcessFuture;
+    ///
+    /// The size of every response is recorded with the given tracker, and responses over its
+    /// maximum size fail the batch rather than being read in full.
+    fn process(&self, EnqueuedRequests<Self::Message>, TcpStreamFuture, Arc<ResponseSizeTracker>) -> ProcessFuture;
 }
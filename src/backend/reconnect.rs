@@ -0,0 +1,232 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::PoolOptions;
+use errors::CreationError;
+use futures::{future::Either, Future};
+use protocol::errors::ProtocolError;
+use rand::{thread_rng, Rng};
+use std::{io, time::Duration};
+use tokio::timer::Timeout;
+use util::{clock::duration_as_ms, ProcessFuture};
+
+// Cooloffs are jittered down by up to this fraction, so that pools which lost a backend together
+// don't all come back to it together.
+const JITTER: f64 = 0.1;
+
+// Cooloffs last up to this long, by default.
+const DEFAULT_MAX_COOLOFF_MS: u64 = 60_000;
+
+// Nothing is waited on for longer than a day, which keeps anything we wait on, jittered or not,
+// from overflowing when it's added to the current time.
+const MAX_TIMEOUT_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// How connections to a backend are made, and how long a backend that keeps failing is left alone
+/// for, parsed from the options of its pool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectConfiguration {
+    /// How long each attempt to connect is given, if there's any limit at all.
+    pub connect_timeout: Option<Duration>,
+
+    /// How long the first cooloff lasts.
+    pub cooloff: Duration,
+
+    /// How much longer each cooloff lasts than the one before it, for as long as failures keep
+    /// coming.
+    pub multiplier: f64,
+
+    /// The longest any cooloff lasts.
+    pub max_cooloff: Duration,
+}
+
+impl ReconnectConfiguration {
+    pub fn from_options(options: &PoolOptions) -> Result<ReconnectConfiguration, CreationError> {
+        // Connecting is given as long as anything else we send to a backend, unless told otherwise.
+        let connect_timeout_ms = match options.connect_timeout_ms {
+            Some(ms) => check_timeout(ms, "connect_timeout_ms")?,
+            None => check_timeout(options.timeout_ms, "timeout_ms")?,
+        };
+        let connect_timeout = if connect_timeout_ms == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(connect_timeout_ms))
+        };
+
+        let multiplier = options.cooloff_backoff_multiplier;
+        if !multiplier.is_finite() || multiplier < 1.0 {
+            return Err(CreationError::InvalidParameter("options.cooloff_backoff_multiplier".to_string()));
+        }
+
+        let cooloff_ms = check_timeout(options.cooloff_timeout_ms, "cooloff_timeout_ms")?;
+        let max_cooloff_ms = options
+            .cooloff_max_timeout_ms
+            .unwrap_or_else(|| DEFAULT_MAX_COOLOFF_MS.max(cooloff_ms));
+        if max_cooloff_ms < cooloff_ms {
+            return Err(CreationError::InvalidParameter("options.cooloff_max_timeout_ms".to_string()));
+        }
+        let max_cooloff_ms = check_timeout(max_cooloff_ms, "cooloff_max_timeout_ms")?;
+
+        Ok(ReconnectConfiguration {
+            connect_timeout,
+            cooloff: Duration::from_millis(cooloff_ms),
+            multiplier,
+            max_cooloff: Duration::from_millis(max_cooloff_ms),
+        })
+    }
+
+    /// Limits the given connection attempt to the connect timeout, if there is one.
+    ///
+    /// An attempt that runs out of time fails just as if the backend had refused it.
+    pub fn limit_connect(&self, connect: ProcessFuture) -> ProcessFuture {
+        let connect = match self.connect_timeout {
+            Some(timeout) => {
+                Either::A(Timeout::new(connect, timeout).map_err(|e| {
+                    if e.is_inner() {
+                        e.into_inner().unwrap()
+                    } else if e.is_elapsed() {
+                        ProtocolError::IoError(io::Error::new(io::ErrorKind::TimedOut, "timed out connecting"))
+                    } else {
+                        ProtocolError::IoError(io::Error::new(io::ErrorKind::Other, "connect timer failed"))
+                    }
+                }))
+            },
+            None => Either::B(connect),
+        };
+        ProcessFuture::new(connect)
+    }
+}
+
+fn check_timeout(ms: u64, name: &str) -> Result<u64, CreationError> {
+    if ms > MAX_TIMEOUT_MS {
+        return Err(CreationError::InvalidParameter(format!("options.{}", name)));
+    }
+    Ok(ms)
+}
+
+/// Tracks how long to wait out a failure, given how many failures came before it.
+///
+/// Each failure in a row is waited out for longer than the last, up to the configured maximum, and
+/// the count starts over once things are working again.
+#[derive(Debug)]
+pub struct Backoff {
+    base_ms: u64,
+    multiplier: f64,
+    max_ms: u64,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(config: &ReconnectConfiguration) -> Backoff {
+        Backoff {
+            base_ms: duration_as_ms(config.cooloff),
+            multiplier: config.multiplier,
+            max_ms: duration_as_ms(config.max_cooloff),
+            failures: 0,
+        }
+    }
+
+    /// Records a failure, getting how long it should be waited out for.
+    pub fn fail(&mut self) -> Duration {
+        let ms = (self.base_ms as f64 * self.multiplier.powi(self.failures as i32)).min(self.max_ms as f64);
+        self.failures = self.failures.saturating_add(1);
+
+        Duration::from_millis(jitter(&mut thread_rng(), ms as u64))
+    }
+
+    /// Starts counting failures over again.
+    pub fn reset(&mut self) { self.failures = 0; }
+}
+
+fn jitter<R: Rng>(rng: &mut R, limit: u64) -> u64 {
+    let spread = (limit as f64 * JITTER) as u64;
+    rng.gen_range(limit - spread, limit + 1).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_config(options: &[(&str, &str)]) -> Result<ReconnectConfiguration, CreationError> {
+        let options = options
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        ReconnectConfiguration::from_options(&PoolOptions::from_map(options).unwrap())
+    }
+
+    #[test]
+    fn test_from_options() {
+        let config = get_config(&[]).unwrap();
+        assert_eq!(config.connect_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(config.cooloff, Duration::from_millis(10_000));
+        assert!((config.multiplier - 2.0).abs() < std::f64::EPSILON);
+        assert_eq!(config.max_cooloff, Duration::from_millis(60_000));
+
+        let config = get_config(&[("timeout_ms", "0"), ("cooloff_timeout_ms", "90000")]).unwrap();
+        assert_eq!(config.connect_timeout, None);
+        assert_eq!(config.max_cooloff, Duration::from_millis(90_000));
+
+        let config = get_config(&[("timeout_ms", "0"), ("connect_timeout_ms", "250")]).unwrap();
+        assert_eq!(config.connect_timeout, Some(Duration::from_millis(250)));
+
+        assert!(get_config(&[("cooloff_backoff_multiplier", "0.5")]).is_err());
+        assert!(get_config(&[("cooloff_backoff_multiplier", "NaN")]).is_err());
+        assert!(get_config(&[("cooloff_max_timeout_ms", "5000")]).is_err());
+    }
+
+    #[test]
+    fn test_max_timeouts() {
+        let max = MAX_TIMEOUT_MS.to_string();
+        let max = max.as_str();
+        let over = (MAX_TIMEOUT_MS + 1).to_string();
+        let over = over.as_str();
+        let huge = std::u64::MAX.to_string();
+        let huge = huge.as_str();
+
+        let config = get_config(&[("connect_timeout_ms", max), ("cooloff_max_timeout_ms", max)]).unwrap();
+        assert_eq!(config.connect_timeout, Some(Duration::from_millis(MAX_TIMEOUT_MS)));
+        assert_eq!(config.max_cooloff, Duration::from_millis(MAX_TIMEOUT_MS));
+
+        assert!(get_config(&[("connect_timeout_ms", over)]).is_err());
+        assert!(get_config(&[("timeout_ms", huge)]).is_err());
+        assert!(get_config(&[("cooloff_max_timeout_ms", over)]).is_err());
+        assert!(get_config(&[("cooloff_max_timeout_ms", huge)]).is_err());
+        assert!(get_config(&[("cooloff_timeout_ms", huge)]).is_err());
+
+        // Backing off as far as we can still leaves room to jitter.
+        let config = get_config(&[("cooloff_timeout_ms", max)]).unwrap();
+        let ms = duration_as_ms(Backoff::new(&config).fail());
+        assert!(ms <= MAX_TIMEOUT_MS && ms >= MAX_TIMEOUT_MS - MAX_TIMEOUT_MS / 10);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = get_config(&[("cooloff_timeout_ms", "1000"), ("cooloff_max_timeout_ms", "5000")]).unwrap();
+        let mut backoff = Backoff::new(&config);
+
+        // Each failure in a row is waited out for about twice as long as the last, up to the maximum.
+        for expected in &[1000, 2000, 4000, 5000, 5000] {
+            let ms = duration_as_ms(backoff.fail());
+            assert!(ms <= *expected && ms >= expected - expected / 10, "{} not close to {}", ms, expected);
+        }
+
+        backoff.reset();
+        assert!(duration_as_ms(backoff.fail()) <= 1000);
+    }
+}
@@ -18,11 +18,18 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 use backend::{
+    handshake::RedisHandshake,
     message_queue::MessageState,
     processor::{Processor, ProcessorError, TcpStreamFuture},
+    responses::ResponseSizeTracker,
+    source::connect,
+    subscription::SubscriptionRequest,
+    transform::{KeyTransforms, OriginalKeys},
 };
 use bytes::BytesMut;
 use common::{EnqueuedRequests, Message};
+use conf::PoolOptions;
+use errors::CreationError;
 use futures::{
     future::{ok, Either},
     prelude::*,
@@ -30,25 +37,63 @@ use futures::{
 use itoa;
 use protocol::{
     errors::ProtocolError,
-    redis::{self, RedisMessage, RedisTransport},
+    redis::{self, KeyPositions, LookupKeys, RedisMessage, RedisTransport, RedisTransportConfig},
 };
-use std::{borrow::Borrow, error::Error, net::SocketAddr};
-use tokio::net::TcpStream;
-use util::ProcessFuture;
+use service::ClientStats;
+use std::{
+    borrow::Cow,
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    str,
+    sync::Arc,
+};
+use util::{ClientStream, ProcessFuture};
 
+const REDIS_CLIENT: &[u8] = b"client";
 const REDIS_DEL: &[u8] = b"del";
+const REDIS_EVAL: &[u8] = b"eval";
+const REDIS_GET: &[u8] = b"get";
+const REDIS_ROUTE: &[u8] = b"route";
 const REDIS_SET: &[u8] = b"set";
+const REDIS_SETNAME: &[u8] = b"setname";
+const REDIS_SYNCHROTRON: &[u8] = b"synchrotron";
+const REDIS_UNLINK: &[u8] = b"unlink";
+const REDIS_WRONGTYPE: &[u8] = b"-WRONGTYPE";
+
+// Inline commands are recorded in their full form, which is what clients usually send anyways.
+const REDIS_PING_FRAME: &[u8] = b"*1\r\n$4\r\nPING\r\n";
+const REDIS_QUIT_FRAME: &[u8] = b"*1\r\n$4\r\nQUIT\r\n";
+
+// Transactions are recorded between the commands that started and ran them.
+const REDIS_MULTI_FRAME: &[u8] = b"*1\r\n$5\r\nMULTI\r\n";
+const REDIS_EXEC_FRAME: &[u8] = b"*1\r\n$4\r\nEXEC\r\n";
+
+// Sets a TTL on a key, but only if it doesn't already have one, so that we never shorten or extend
+// a TTL that a client explicitly asked for.
+const REDIS_DEFAULT_TTL_SCRIPT: &[u8] =
+    b"if redis.call('ttl', KEYS[1]) == -1 then return redis.call('expire', KEYS[1], ARGV[1]) end return 0";
+
+// Options to SET that give the key a TTL, or keep the one it has.
+const REDIS_SET_TTL_OPTIONS: &[&[u8]] = &[b"ex", b"px", b"exat", b"pxat", b"keepttl"];
 
 #[derive(Clone)]
-pub struct RedisProcessor;
+pub struct RedisProcessor {
+    transport_config: RedisTransportConfig,
+    handshake: Option<Arc<RedisHandshake>>,
+}
 
 impl RedisProcessor {
-    pub fn new() -> RedisProcessor { RedisProcessor {} }
+    pub fn new(transport_config: RedisTransportConfig) -> RedisProcessor {
+        RedisProcessor {
+            transport_config,
+            handshake: None,
+        }
+    }
 }
 
 impl Processor for RedisProcessor {
     type Message = RedisMessage;
-    type Transport = RedisTransport<TcpStream>;
+    type Transport = RedisTransport<ClientStream>;
 
     fn fragment_messages(
         &self, msgs: Vec<Self::Message>,
@@ -56,19 +101,130 @@ impl Processor for RedisProcessor {
         redis_fragment_messages(msgs)
     }
 
+    fn get_fragment_count(&self, msg: &Self::Message) -> usize { redis_get_fragment_count(msg) }
+
     fn defragment_messages(&self, msgs: Vec<(MessageState, Self::Message)>) -> Result<Self::Message, ProcessorError> {
         redis_defragment_messages(msgs)
     }
 
+    fn get_read_request(&self, key: &[u8]) -> Self::Message {
+        redis_new_bulk_from_args(vec![redis_new_data_buffer(REDIS_GET), redis_new_data_buffer(key)])
+    }
+
+    fn is_missing_ttl(&self, msg: &Self::Message) -> bool { redis_is_missing_ttl(msg) }
+
+    fn get_created_key<'a>(&self, msg: &'a Self::Message) -> &'a [u8] { redis_get_created_key(msg) }
+
+    fn get_default_ttl_request(&self, key: &[u8], ttl_secs: u64) -> Self::Message {
+        let mut ttl_buf = [b'\0'; 20];
+        let n = itoa::write(&mut ttl_buf[..], ttl_secs).unwrap();
+
+        redis_new_bulk_from_args(vec![
+            redis_new_data_buffer(REDIS_EVAL),
+            redis_new_data_buffer(REDIS_DEFAULT_TTL_SCRIPT),
+            redis_new_data_buffer(b"1"),
+            redis_new_data_buffer(key),
+            redis_new_data_buffer(&ttl_buf[..n]),
+        ])
+    }
+
+    fn is_write(&self, msg: &Self::Message) -> bool { redis_is_write(msg) }
+
+    fn is_read_only(&self, msg: &Self::Message) -> bool {
+        msg.get_command_info().map(|info| info.is_read_only()).unwrap_or(false)
+    }
+
+    fn is_blocking(&self, msg: &Self::Message) -> bool {
+        msg.get_command_info().map(|info| info.is_blocking()).unwrap_or(false)
+    }
+
+    fn get_local_response(&self, msg: &Self::Message) -> Option<Self::Message> { redis::get_local_response(msg) }
+
+    fn get_subscription<'a>(&self, msg: &'a Self::Message) -> Option<SubscriptionRequest<'a>> {
+        redis::get_subscription(msg)
+    }
+
+    fn read_pushed_message(&self, buf: &mut BytesMut) -> Result<Option<(BytesMut, Option<usize>)>, ProtocolError> {
+        redis::read_pushed_message(buf)
+    }
+
+    fn get_delete_request(&self, key: &[u8]) -> Self::Message {
+        redis_new_bulk_from_args(vec![redis_new_data_buffer(REDIS_DEL), redis_new_data_buffer(key)])
+    }
+
+    fn get_health_check_request(&self) -> Self::Message { RedisMessage::from_inline("PING") }
+
+    fn is_error_response(&self, msg: &Self::Message) -> bool {
+        match msg {
+            RedisMessage::Error(_, _) => true,
+            _ => false,
+        }
+    }
+
+    fn get_lookup_keys(&self, msg: &Self::Message) -> Option<usize> { redis_get_lookup_keys(msg) }
+
+    fn count_lookup_hits(&self, keys: usize, msg: &Self::Message) -> Option<(usize, usize)> {
+        redis_count_lookup_hits(keys, msg)
+    }
+
+    // None of the commands we support send back keys, so there's nothing to remember or restore.
+    fn forward_keys(&self, msg: Self::Message, transforms: &KeyTransforms, _: &mut OriginalKeys) -> Self::Message {
+        redis_forward_keys(msg, transforms)
+    }
+
+    fn restore_keys(&self, msg: Self::Message, _original: &OriginalKeys) -> Self::Message { msg }
+
+    fn get_recorded_frame(&self, msg: &Self::Message, redact_values: bool) -> Option<BytesMut> {
+        redis_get_recorded_frame(msg, redact_values)
+    }
+
+    fn get_client_name(&self, msg: &Self::Message) -> Option<String> { redis_get_client_name(msg) }
+
+    fn has_routing_hint(&self, msg: &Self::Message) -> bool {
+        match msg {
+            RedisMessage::Routed(_, _) => true,
+            _ => false,
+        }
+    }
+
+    fn get_command_id(&self, msg: &Self::Message) -> Option<usize> { msg.get_command_info().map(|info| info.id()) }
+
+    fn get_command_name(&self, msg: &Self::Message) -> Option<&'static str> {
+        msg.get_command_info().map(|info| info.name())
+    }
+
+    fn find_command_id(&self, name: &str) -> Option<usize> { redis::get_command_index(name.as_bytes()) }
+
+    fn get_command_count(&self) -> usize { redis::get_command_count() }
+
     fn get_error_message(&self, e: Box<Error>) -> Self::Message { RedisMessage::from_error(e) }
 
     fn get_error_message_str(&self, e: &str) -> Self::Message { RedisMessage::from_error_str(e) }
 
-    fn get_transport(&self, client: TcpStream) -> Self::Transport { RedisTransport::new(client) }
+    fn get_failure_response(&self, msg: &Self::Message) -> Option<Self::Message> { redis::get_failure_response(msg) }
+
+    fn get_transport(&self, client: ClientStream, stats: Arc<ClientStats>) -> Self::Transport {
+        let local_addr = client.local_addr().ok();
+        RedisTransport::new(client, self.transport_config.clone(), local_addr).set_client_stats(stats)
+    }
+
+    fn for_pool(&self, options: &PoolOptions) -> Result<Self, CreationError> {
+        Ok(RedisProcessor {
+            transport_config: self.transport_config.clone(),
+            handshake: RedisHandshake::from_options(options)?.map(Arc::new),
+        })
+    }
 
-    fn preconnect(&self, addr: &SocketAddr, noreply: bool) -> ProcessFuture {
-        let inner = TcpStream::connect(addr)
+    fn preconnect(&self, addr: &SocketAddr, source: Option<IpAddr>, noreply: bool) -> ProcessFuture {
+        // Every new connection runs the handshake, so reconnecting to a backend that's come back
+        // from a cooloff, or that's being health checked, authenticates all over again.
+        let handshake = self.handshake.clone();
+        let inner = connect(addr, source)
             .map_err(ProtocolError::IoError)
+            .and_then(move |conn| match handshake {
+                Some(handshake) => Either::A(handshake.run(conn)),
+                None => Either::B(ok(conn)),
+            })
             .and_then(move |conn| {
                 if noreply {
                     let noreply_req = RedisMessage::from_inline("CLIENT REPLY OFF");
@@ -80,115 +236,140 @@ impl Processor for RedisProcessor {
         ProcessFuture::new(inner)
     }
 
-    fn process(&self, req: EnqueuedRequests<Self::Message>, stream: TcpStreamFuture) -> ProcessFuture {
+    fn process(
+        &self, req: EnqueuedRequests<Self::Message>, stream: TcpStreamFuture, responses: Arc<ResponseSizeTracker>,
+    ) -> ProcessFuture {
         let inner = stream
             .and_then(move |server| redis::write_messages(server, req))
-            .and_then(move |(server, msgs, _n)| redis::read_messages(server, msgs))
+            .and_then(move |(server, msgs, _n)| redis::read_messages(server, msgs, responses))
             .and_then(move |(server, _n)| ok(server));
         ProcessFuture::new(inner)
     }
 }
 
+/// How the replies to the fragments of a command are gathered back into a single reply.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Gather {
+    /// Every fragment replies with a value, and the values are given back in the order of the keys.
+    Values,
+
+    /// Every fragment replies with a count, and the total is given back.
+    Sum,
+
+    /// Every fragment replies with `+OK`, and so a single `+OK` is given back.
+    AllOk,
+}
+
+/// A multi-key command that is split up into a command per key, so that every key can be routed
+/// to the backend that owns it.
+struct Fragmentable {
+    // The name of the command being split up.
+    name: &'static str,
+
+    // The command each fragment is sent as, and how many arguments, starting with the key, each
+    // fragment takes.
+    command: &'static [u8],
+    arity: usize,
+
+    gather: Gather,
+}
+
+const FRAGMENTABLE: &[Fragmentable] = &[
+    Fragmentable {
+        name: "MGET",
+        command: REDIS_GET,
+        arity: 1,
+        gather: Gather::Values,
+    },
+    Fragmentable {
+        name: "MSET",
+        command: REDIS_SET,
+        arity: 2,
+        gather: Gather::AllOk,
+    },
+    Fragmentable {
+        name: "DEL",
+        command: REDIS_DEL,
+        arity: 1,
+        gather: Gather::Sum,
+    },
+    Fragmentable {
+        name: "UNLINK",
+        command: REDIS_UNLINK,
+        arity: 1,
+        gather: Gather::Sum,
+    },
+];
+
+/// Gets how the given message is split up, if it's a multi-key command that should be.
+///
+/// A command with no keys, or with a key missing its value, isn't split up: it's sent along as-is
+/// so that the backend can tell the client what's wrong with it.
+fn redis_get_fragmentable(msg: &RedisMessage) -> Option<&'static Fragmentable> {
+    let args = match msg {
+        RedisMessage::Bulk(_, args, _) => args,
+        _ => return None,
+    };
+
+    let name = msg.get_command_info()?.name();
+    FRAGMENTABLE
+        .iter()
+        .find(|f| f.name == name)
+        .filter(|f| args.len() > 1 && (args.len() - 1) % f.arity == 0)
+}
+
 fn redis_fragment_messages(msgs: Vec<RedisMessage>) -> Result<Vec<(MessageState, RedisMessage)>, ProcessorError> {
     let mut fragments = Vec::new();
 
     for msg in msgs {
-        if !redis_is_multi_message(&msg) {
-            // This message isn't fragmentable, so it passes through untouched.
-            let state = if msg.is_inline() {
-                MessageState::Inline
-            } else {
-                MessageState::Standalone
-            };
-            fragments.push((state, msg));
-        } else {
-            match msg {
-                RedisMessage::Bulk(_, mut args) => {
-                    // Split off the actual command string and figure out what the new command string
-                    // will be for our fragments.
-                    let cmd = args.remove(0);
-                    let cmd_buf = redis_get_data_buffer(&cmd);
-                    let new_cmd_buf = match cmd_buf {
-                        Some(buf) => {
-                            match buf {
-                                b"mget" => b"get",
-                                b"del" => b"del",
-                                b"mset" => b"set",
-                                x => {
-                                    return Err(ProcessorError::FragmentError(format!(
-                                        "tried to fragment command '{:?}' but command is not fragmentable!",
-                                        x
-                                    )));
-                                },
-                            }
-                        },
-                        None => {
-                            return Err(ProcessorError::FragmentError(
-                                "tried to fragment bulk message with non-data argument in position 0!".to_owned(),
-                            ));
-                        },
-                    };
-
-                    // Now we'll do the actual splitting.  We take the new command string (get for
-                    // mget, set for mset, and del for del) and build a buffer for it.  We extract
-                    // N arguments at a time from our original message, where N is either 1 or 2
-                    // depending on if this is a set operation.  With each N arguments, we build a
-                    // new message using the new command string and the arguments we extract.
-                    let cmd_arg = redis_new_data_buffer(&new_cmd_buf[..]);
-                    let mut cmd_type = BytesMut::with_capacity(new_cmd_buf.len());
-                    cmd_type.extend_from_slice(&new_cmd_buf[..]);
-
-                    let arg_take_cnt = if new_cmd_buf == b"set" { 2 } else { 1 };
-                    let total_fragments = args.len();
-
-                    // Make sure we won't be left with extra arguments.
-                    if total_fragments % arg_take_cnt != 0 {
-                        return Err(ProcessorError::FragmentError(format!(
-                            "incorrect multiple of argument count! (multiple: {}, arg count: {}, cmd type: {:?})",
-                            arg_take_cnt,
-                            args.len(),
-                            &cmd_type
-                        )));
-                    }
-
-                    // For get requests, we can stream back the fragments so long as they're in
-                    // order.  We also need to make sure we provide the proper header (aka the data
-                    // that tells the client the response is going to be multiple items) to the
-                    // first fragment so that we generate valid output.
-                    let is_streaming = new_cmd_buf == b"get";
-                    let mut streaming_hdr = if is_streaming {
-                        Some(redis_new_bulk_buffer(total_fragments))
-                    } else {
-                        None
-                    };
-
-                    let mut fragment_count = 0;
-                    while !args.is_empty() {
-                        // This is contorted but we split off the first N arguments, which leaves `args`
-                        // with those N and `new_args` with the rest.  We feed those to a function which
-                        // builds us our new message, and then finally we replace `args` with `new_args`
-                        // so that we can continue on.
-                        let new_args = args.split_off(arg_take_cnt);
-                        args.insert(0, cmd_arg.clone());
-                        let new_bulk = redis_new_bulk_from_args(args);
-                        let is_last = new_args.is_empty();
-
-                        let state = if is_streaming {
-                            MessageState::StreamingFragmented(streaming_hdr.take(), is_last)
-                        } else {
-                            // Normal fragments need to know the command they're being used for so
-                            // we can properly form a command-specific response when we ultimate
-                            // defragment these messages later on.
-                            MessageState::Fragmented(cmd_type.clone(), fragment_count, total_fragments)
-                        };
-
-                        fragments.push((state, new_bulk));
-                        fragment_count += 1;
-                        args = new_args;
-                    }
-                },
-                _ => unreachable!(),
-            }
+        let fragmentable = match redis_get_fragmentable(&msg) {
+            Some(fragmentable) => fragmentable,
+            None => {
+                // This message isn't fragmentable, so it passes through untouched.
+                let state = match msg {
+                    RedisMessage::Sleep(duration) => MessageState::Delayed(duration),
+                    _ if msg.is_inline() => MessageState::Inline,
+                    _ => MessageState::Standalone,
+                };
+                fragments.push((state, msg));
+                continue;
+            },
+        };
+
+        let mut args = match msg {
+            RedisMessage::Bulk(_, args, _) => args,
+            _ => unreachable!(),
+        };
+
+        // Split off the actual command string, since every fragment gets its own.
+        args.remove(0);
+
+        // Now we'll do the actual splitting.  We extract N arguments at a time from our original
+        // message, where N is the arity of the fragment command, and build a new message out of
+        // the fragment command and the arguments we extracted.
+        //
+        // Every fragment knows the command it's being used for, so we can properly form a
+        // command-specific response when we ultimately defragment them later on.  Fragments are
+        // routed by their own key, so the responses to them can come back in any order, but
+        // they're always reassembled in the order the keys were given in.
+        let cmd_arg = redis_new_data_buffer(fragmentable.command);
+        let cmd_type = BytesMut::from(fragmentable.command);
+        let total_fragments = args.len() / fragmentable.arity;
+
+        let mut fragment_count = 0;
+        while !args.is_empty() {
+            // This is contorted but we split off the first N arguments, which leaves `args` with
+            // those N and `new_args` with the rest.  We feed those to a function which builds us
+            // our new message, and then finally we replace `args` with `new_args` so that we can
+            // continue on.
+            let new_args = args.split_off(fragmentable.arity);
+            args.insert(0, cmd_arg.clone());
+            let new_bulk = redis_new_bulk_from_args(args);
+
+            let state = MessageState::Fragmented(cmd_type.clone(), fragment_count, total_fragments);
+            fragments.push((state, new_bulk));
+            fragment_count += 1;
+            args = new_args;
         }
     }
 
@@ -202,10 +383,14 @@ fn redis_defragment_messages(fragments: Vec<(MessageState, RedisMessage)>) -> Re
     }
 
     // Peek at the metadata buffer on the first message.  If it's not a fragmented message, then
-    // something isn't rightand we need to bomb out.
-    let first = fragments.first().unwrap();
-    let cmd_type = match first {
-        (MessageState::Fragmented(buf, _, _), _) => buf.clone(),
+    // something isn't right and we need to bomb out.
+    let fragmentable = match fragments[0].0 {
+        MessageState::Fragmented(ref cmd_type, _, _) => {
+            FRAGMENTABLE
+                .iter()
+                .find(|f| f.command == &cmd_type[..])
+                .ok_or_else(|| ProcessorError::DefragmentError(format!("unknown command type '{:?}'", cmd_type)))?
+        },
         _ => {
             return Err(ProcessorError::DefragmentError(
                 "tried to defragment messages, but got non-fragmented message in list".to_owned(),
@@ -213,26 +398,50 @@ fn redis_defragment_messages(fragments: Vec<(MessageState, RedisMessage)>) -> Re
         },
     };
 
-    // We have the command type, so let's actually defragment now.
-    match cmd_type.borrow() {
-        // DEL returns the number of keys it deleted, so we have to tally up the integer responses.
-        REDIS_DEL => {
-            let mut keys_deleted = 0;
+    // We have the command type, so let's actually defragment now.  If any fragment failed, we
+    // can't give a complete answer, so rather than a partial one, the first error we come across
+    // is all we send back.
+    match fragmentable.gather {
+        // A key holding something other than a string is just nil to MGET, though, rather than
+        // being an error.
+        Gather::Values => {
+            let mut values = Vec::with_capacity(fragments.len());
             for (_state, fragment) in fragments {
                 match fragment {
-                    RedisMessage::Integer(_, value) => keys_deleted += value,
+                    RedisMessage::Data(_, _) | RedisMessage::Null => values.push(fragment),
+                    RedisMessage::Error(ref buf, _) if buf.starts_with(REDIS_WRONGTYPE) => {
+                        values.push(RedisMessage::Null)
+                    },
                     RedisMessage::Error(_, _) => return Ok(fragment),
                     _ => {
-                        return Err(ProcessorError::DefragmentError(
-                            "non-integer response for DEL!".to_owned(),
-                        ));
+                        return Err(ProcessorError::DefragmentError(format!(
+                            "non-data response for {}!",
+                            fragmentable.name
+                        )));
                     },
                 }
             }
 
-            Ok(RedisMessage::from_integer(keys_deleted))
+            Ok(redis_new_bulk_from_args(values))
         },
-        REDIS_SET => {
+        Gather::Sum => {
+            let mut total = 0;
+            for (_state, fragment) in fragments {
+                match fragment {
+                    RedisMessage::Integer(_, value) => total += value,
+                    RedisMessage::Error(_, _) => return Ok(fragment),
+                    _ => {
+                        return Err(ProcessorError::DefragmentError(format!(
+                            "non-integer response for {}!",
+                            fragmentable.name
+                        )));
+                    },
+                }
+            }
+
+            Ok(RedisMessage::from_integer(total))
+        },
+        Gather::AllOk => {
             // MSET is funny because it says it can't fail, but really, the command has no failure
             // mode _except_ for, like, you know, the server running out of memory.  However, MSET
             // also promises to be atomic.
@@ -249,12 +458,6 @@ fn redis_defragment_messages(fragments: Vec<(MessageState, RedisMessage)>) -> Re
 
             Ok(RedisMessage::OK)
         },
-        x => {
-            Err(ProcessorError::DefragmentError(format!(
-                "unknown command type '{:?}'",
-                x
-            )))
-        },
     }
 }
 
@@ -265,27 +468,245 @@ fn redis_get_data_buffer(msg: &RedisMessage) -> Option<&[u8]> {
     }
 }
 
-fn redis_is_multi_message(msg: &RedisMessage) -> bool {
+fn redis_get_fragment_count(msg: &RedisMessage) -> usize {
+    match (redis_get_fragmentable(msg), msg) {
+        (Some(fragmentable), RedisMessage::Bulk(_, args, _)) => (args.len() - 1) / fragmentable.arity,
+        _ => 1,
+    }
+}
+
+fn redis_is_missing_ttl(msg: &RedisMessage) -> bool {
+    let args = match msg {
+        RedisMessage::Bulk(_, args, _) => args,
+        _ => return false,
+    };
+
+    // Anything without a key can't create one.
+    if args.len() < 2 {
+        return false;
+    }
+
+    let info = match msg.get_command_info() {
+        Some(info) => info,
+        None => return false,
+    };
+
+    if info.name() == "SET" {
+        // SET key value [options...]: only missing a TTL if none of the options provide one.
+        return !args
+            .iter()
+            .skip(3)
+            .filter_map(|arg| redis_get_data_buffer(arg))
+            .any(|opt| REDIS_SET_TTL_OPTIONS.iter().any(|ttl_opt| opt.eq_ignore_ascii_case(ttl_opt)));
+    }
+
+    info.creates_without_ttl()
+}
+
+fn redis_get_created_key(msg: &RedisMessage) -> &[u8] {
+    // Commands that move something from one key to another create the second of them.
+    if let (RedisMessage::Bulk(_, args, _), Some(info)) = (msg, msg.get_command_info()) {
+        if info.keys() == KeyPositions::FirstTwo {
+            if let Some(key) = args.get(2).and_then(redis_get_data_buffer) {
+                return key;
+            }
+        }
+    }
+
+    msg.key()
+}
+
+fn redis_is_write(msg: &RedisMessage) -> bool {
+    let args = match msg {
+        RedisMessage::Bulk(_, args, _) => args,
+        _ => return false,
+    };
+
+    if args.len() < 2 {
+        return false;
+    }
+
+    let info = match msg.get_command_info() {
+        Some(info) => info,
+        None => return false,
+    };
+
+    // Deleting more than one key touches keys other than the one we route by, as does setting more
+    // than one.
+    if (info.name() == "DEL" || info.name() == "UNLINK") && args.len() > 2 {
+        return false;
+    }
+    if info.name() == "MSET" && args.len() > 3 {
+        return false;
+    }
+
+    info.writes_key()
+}
+
+fn redis_get_lookup_keys(msg: &RedisMessage) -> Option<usize> {
+    let args = match msg {
+        RedisMessage::Bulk(_, args, _) => args,
+        _ => return None,
+    };
+
+    if args.len() < 2 {
+        return None;
+    }
+
+    match msg.get_command_info()?.lookup()? {
+        LookupKeys::First => Some(1),
+        LookupKeys::All => Some(args.len() - 1),
+    }
+}
+
+fn redis_count_lookup_hits(keys: usize, msg: &RedisMessage) -> Option<(usize, usize)> {
     match msg {
-        RedisMessage::Bulk(_, args) => {
-            match args.len() {
-                0 => false,
-                _ => {
-                    let arg = &args[0];
-                    match redis_get_data_buffer(arg) {
-                        Some(buf) => {
-                            match buf {
-                                b"mget" | b"mset" | b"del" => true,
-                                _ => false,
-                            }
-                        },
-                        None => false,
-                    }
+        RedisMessage::Data(_, _) => Some((1, 0)),
+        RedisMessage::Null => Some((0, 1)),
+        // EXISTS counts a key once for every time it was given, so clamp to what we asked for.
+        RedisMessage::Integer(_, value) => {
+            let hits = (*value).max(0) as usize;
+            let hits = hits.min(keys);
+            Some((hits, keys - hits))
+        },
+        RedisMessage::Bulk(_, items, _) => {
+            let misses = items.iter().filter(|item| **item == RedisMessage::Null).count();
+            Some((items.len() - misses, misses))
+        },
+        _ => None,
+    }
+}
+
+fn redis_forward_keys(msg: RedisMessage, transforms: &KeyTransforms) -> RedisMessage {
+    // Like a routing hint, the key a transaction is routed by is only ever hashed.
+    if let RedisMessage::Transaction(key, cmds) = msg {
+        let cmds = cmds.into_iter().map(|cmd| redis_forward_keys(cmd, transforms)).collect();
+        return RedisMessage::Transaction(key, cmds);
+    }
+
+    let info = match msg.get_command_info() {
+        Some(info) => info,
+        None => return msg,
+    };
+
+    let (buf, args) = match msg {
+        // The routing hint is only ever hashed, so it's the keys of the wrapped command that change.
+        RedisMessage::Routed(hint, inner) => {
+            return RedisMessage::Routed(hint, Box::new(redis_forward_keys(*inner, transforms)));
+        },
+        RedisMessage::Bulk(buf, args, _) => (buf, args),
+        msg => return msg,
+    };
+
+    // Commands that count their keys say how many there are as one of their arguments, and those
+    // that take a timeout after their keys have one key for every argument but the timeout.
+    let count = match info.keys() {
+        KeyPositions::AllButLast => Some(args.len().saturating_sub(2)),
+        _ => {
+            info
+                .counted_at()
+                .and_then(|position| args.get(position + 1))
+                .and_then(redis_get_data_buffer)
+                .and_then(|count| str::from_utf8(count).ok())
+                .and_then(|count| count.parse().ok())
+        },
+    };
+
+    let mut changed = false;
+    let args = args
+        .into_iter()
+        .enumerate()
+        .map(|(idx, arg)| {
+            // The command itself comes first, which isn't counted as an argument.
+            if idx == 0 || !info.is_key(idx - 1, count) {
+                return arg;
+            }
+
+            let key = match redis_get_data_buffer(&arg).map(|key| transforms.forward(key)) {
+                Some(Cow::Owned(key)) => Some(key),
+                _ => None,
+            };
+            match key {
+                Some(key) => {
+                    changed = true;
+                    redis_new_data_buffer(&key)
                 },
+                None => arg,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if changed {
+        redis_new_bulk_from_args(args)
+    } else {
+        RedisMessage::from_bulk(buf, args)
+    }
+}
+
+fn redis_get_recorded_frame(msg: &RedisMessage, redact_values: bool) -> Option<BytesMut> {
+    match msg {
+        RedisMessage::Ping => Some(BytesMut::from(REDIS_PING_FRAME)),
+        RedisMessage::Quit => Some(BytesMut::from(REDIS_QUIT_FRAME)),
+        RedisMessage::Bulk(buf, _, _) if !redact_values => Some(buf.clone()),
+        // The hint goes in front of the command, just as the client sent it.
+        RedisMessage::Routed(hint, inner) => {
+            let mut frame = redis_new_bulk_from_args(vec![
+                redis_new_data_buffer(REDIS_SYNCHROTRON),
+                redis_new_data_buffer(REDIS_ROUTE),
+                redis_new_data_buffer(hint),
+            ])
+            .into_resp();
+            frame.unsplit(redis_get_recorded_frame(inner, redact_values)?);
+            Some(frame)
+        },
+        // A transaction is recorded as the client sent it, so that a replay runs it the same way.
+        RedisMessage::Transaction(_, cmds) => {
+            let mut frame = BytesMut::from(REDIS_MULTI_FRAME);
+            for cmd in cmds {
+                frame.unsplit(redis_get_recorded_frame(cmd, redact_values)?);
+            }
+            frame.extend_from_slice(REDIS_EXEC_FRAME);
+            Some(frame)
+        },
+        RedisMessage::Bulk(_, args, _) => {
+            // The command and key are kept as-is, so that a replay is routed the same way.
+            let mut frame = redis_new_bulk_buffer(args.len());
+            for (i, arg) in args.iter().enumerate() {
+                match arg {
+                    RedisMessage::Data(arg_buf, offset) if i >= 2 => {
+                        let mut redacted = arg_buf.clone();
+                        let end = redacted.len() - 2;
+                        for b in &mut redacted[*offset..end] {
+                            *b = b'x';
+                        }
+                        frame.unsplit(redacted);
+                    },
+                    arg => frame.unsplit(arg.get_buf()),
+                }
             }
+            Some(frame)
         },
-        _ => false,
+        _ => None,
+    }
+}
+
+fn redis_get_client_name(msg: &RedisMessage) -> Option<String> {
+    let args = match msg {
+        RedisMessage::Bulk(_, args, _) => args,
+        _ => return None,
+    };
+
+    if args.len() != 3 {
+        return None;
+    }
+
+    let cmd = redis_get_data_buffer(&args[0])?;
+    let subcmd = redis_get_data_buffer(&args[1])?;
+    if !cmd.eq_ignore_ascii_case(REDIS_CLIENT) || !subcmd.eq_ignore_ascii_case(REDIS_SETNAME) {
+        return None;
     }
+
+    redis_get_data_buffer(&args[2]).map(|name| String::from_utf8_lossy(name).into_owned())
 }
 
 fn redis_clean_data(buf: &BytesMut, offset: usize) -> &[u8] {
@@ -329,12 +750,13 @@ fn redis_new_bulk_from_args(args: Vec<RedisMessage>) -> RedisMessage {
         new_args.push(arg);
     }
 
-    RedisMessage::Bulk(buf, new_args)
+    RedisMessage::from_bulk(buf, new_args)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use backend::transform::{HashTag, Prefix};
     use std::io::{Error, ErrorKind};
 
     const STATUS_BUF: &str = "StAtUs_BuF";
@@ -359,15 +781,309 @@ mod tests {
     }
 
     #[test]
-    fn test_is_multi_message() {
-        assert!(!redis_is_multi_message(&NULL_MSG));
-        assert!(!redis_is_multi_message(&OK_MSG));
-        assert!(!redis_is_multi_message(&STATUS_MSG));
-        assert!(!redis_is_multi_message(&ERR_MSG));
-        assert!(!redis_is_multi_message(&INT_MSG));
-        assert!(!redis_is_multi_message(&DATA_MSG));
-        assert!(!redis_is_multi_message(&BULK_MSG));
-        assert!(redis_is_multi_message(&BULK_MULTI_MSG));
+    fn test_get_fragmentable() {
+        assert!(redis_get_fragmentable(&NULL_MSG).is_none());
+        assert!(redis_get_fragmentable(&OK_MSG).is_none());
+        assert!(redis_get_fragmentable(&STATUS_MSG).is_none());
+        assert!(redis_get_fragmentable(&ERR_MSG).is_none());
+        assert!(redis_get_fragmentable(&INT_MSG).is_none());
+        assert!(redis_get_fragmentable(&DATA_MSG).is_none());
+        assert!(redis_get_fragmentable(&BULK_MSG).is_none());
+        assert!(redis_get_fragmentable(&BULK_MULTI_MSG).is_some());
+    }
+
+    fn build_command(args: &[&[u8]]) -> RedisMessage {
+        redis_new_bulk_from_args(args.iter().map(|arg| redis_new_data_buffer(arg)).collect())
+    }
+
+    #[test]
+    fn test_get_fragment_count() {
+        assert_eq!(redis_get_fragment_count(&build_command(&[b"get", b"key"])), 1);
+        assert_eq!(redis_get_fragment_count(&build_command(&[b"mget", b"a", b"b", b"c"])), 3);
+        assert_eq!(redis_get_fragment_count(&build_command(&[b"mset", b"a", b"1", b"b", b"2"])), 2);
+        assert_eq!(redis_get_fragment_count(&build_command(&[b"del", b"a", b"b"])), 2);
+        assert_eq!(redis_get_fragment_count(&build_command(&[b"unlink", b"a", b"b", b"c"])), 3);
+
+        // Commands missing arguments are left for the backend to complain about.
+        assert_eq!(redis_get_fragment_count(&build_command(&[b"mget"])), 1);
+        assert_eq!(redis_get_fragment_count(&build_command(&[b"mset", b"a", b"1", b"b"])), 1);
+        assert_eq!(redis_get_fragment_count(&NULL_MSG), 1);
+    }
+
+    #[test]
+    fn test_is_missing_ttl() {
+        assert!(redis_is_missing_ttl(&build_command(&[b"set", b"key", b"value"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"SET", b"key", b"value", b"NX"])));
+        assert!(!redis_is_missing_ttl(&build_command(&[b"set", b"key", b"value", b"ex", b"10"])));
+        assert!(!redis_is_missing_ttl(&build_command(&[b"set", b"key", b"value", b"NX", b"PX", b"10"])));
+        assert!(!redis_is_missing_ttl(&build_command(&[b"set", b"key", b"value", b"KEEPTTL"])));
+
+        // The key is named "ex", which isn't an option.
+        assert!(redis_is_missing_ttl(&build_command(&[b"set", b"ex", b"value"])));
+
+        assert!(redis_is_missing_ttl(&build_command(&[b"hset", b"key", b"field", b"value"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"LPUSH", b"key", b"value"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"sadd", b"key", b"member"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"incr", b"key"])));
+
+        // Storing a result, or moving something, into a key creates it just the same.
+        assert!(redis_is_missing_ttl(&build_command(&[b"MSET", b"key", b"value"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"sunionstore", b"dest", b"a", b"b"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"SINTERSTORE", b"dest", b"a", b"b"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"sdiffstore", b"dest", b"a", b"b"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"ZUNIONSTORE", b"dest", b"2", b"a", b"b"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"zinterstore", b"dest", b"2", b"a", b"b"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"PFMERGE", b"dest", b"a", b"b"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"smove", b"src", b"dest", b"member"])));
+        assert!(redis_is_missing_ttl(&build_command(&[b"RPOPLPUSH", b"src", b"dest"])));
+
+        assert!(!redis_is_missing_ttl(&build_command(&[b"setex", b"key", b"10", b"value"])));
+        assert!(!redis_is_missing_ttl(&build_command(&[b"get", b"key"])));
+        assert!(!redis_is_missing_ttl(&build_command(&[b"del", b"key"])));
+        assert!(!redis_is_missing_ttl(&build_command(&[b"ping"])));
+        assert!(!redis_is_missing_ttl(&NULL_MSG));
+    }
+
+    #[test]
+    fn test_get_created_key() {
+        assert_eq!(redis_get_created_key(&build_command(&[b"set", b"key", b"value"])), b"key");
+        assert_eq!(redis_get_created_key(&build_command(&[b"SUNIONSTORE", b"dest", b"a", b"b"])), b"dest");
+        assert_eq!(redis_get_created_key(&build_command(&[b"smove", b"src", b"dest", b"member"])), b"dest");
+        assert_eq!(redis_get_created_key(&build_command(&[b"RPOPLPUSH", b"src", b"dest"])), b"dest");
+    }
+
+    #[test]
+    fn test_is_write() {
+        assert!(redis_is_write(&build_command(&[b"set", b"key", b"value"])));
+        assert!(redis_is_write(&build_command(&[b"SETEX", b"key", b"10", b"value"])));
+        assert!(redis_is_write(&build_command(&[b"incr", b"key"])));
+        assert!(redis_is_write(&build_command(&[b"del", b"key"])));
+        assert!(!redis_is_write(&build_command(&[b"del", b"a", b"b"])));
+        assert!(redis_is_write(&build_command(&[b"unlink", b"key"])));
+        assert!(!redis_is_write(&build_command(&[b"unlink", b"a", b"b"])));
+        assert!(redis_is_write(&build_command(&[b"mset", b"key", b"value"])));
+        assert!(!redis_is_write(&build_command(&[b"MSET", b"a", b"1", b"b", b"2"])));
+        assert!(!redis_is_write(&build_command(&[b"get", b"key"])));
+        assert!(!redis_is_write(&build_command(&[b"set"])));
+        assert!(!redis_is_write(&NULL_MSG));
+    }
+
+    #[test]
+    fn test_is_read_only() {
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        assert!(processor.is_read_only(&build_command(&[b"get", b"key"])));
+        assert!(processor.is_read_only(&build_command(&[b"MGET", b"a", b"b"])));
+        assert!(processor.is_read_only(&build_command(&[b"exists", b"key"])));
+        assert!(processor.is_read_only(&build_command(&[b"ttl", b"key"])));
+        assert!(!processor.is_read_only(&build_command(&[b"set", b"key", b"value"])));
+        assert!(!processor.is_read_only(&build_command(&[b"del", b"key"])));
+        assert!(!processor.is_read_only(&build_command(&[b"incr", b"key"])));
+        assert!(!processor.is_read_only(&build_command(&[b"expire", b"key", b"10"])));
+        assert!(!processor.is_read_only(&build_command(&[b"info"])));
+        assert!(!processor.is_read_only(&NULL_MSG));
+    }
+
+    #[test]
+    fn test_get_delete_request() {
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        assert_eq!(processor.get_delete_request(b"key"), build_command(&[b"del", b"key"]));
+    }
+
+    #[test]
+    fn test_get_lookup_keys() {
+        assert_eq!(redis_get_lookup_keys(&build_command(&[b"get", b"key"])), Some(1));
+        assert_eq!(redis_get_lookup_keys(&build_command(&[b"HGET", b"key", b"field"])), Some(1));
+        assert_eq!(redis_get_lookup_keys(&build_command(&[b"mget", b"a", b"b", b"c"])), Some(3));
+        assert_eq!(redis_get_lookup_keys(&build_command(&[b"exists", b"a", b"b"])), Some(2));
+        assert_eq!(redis_get_lookup_keys(&build_command(&[b"set", b"key", b"value"])), None);
+        assert_eq!(redis_get_lookup_keys(&build_command(&[b"get"])), None);
+        assert_eq!(redis_get_lookup_keys(&NULL_MSG), None);
+    }
+
+    #[test]
+    fn test_count_lookup_hits() {
+        assert_eq!(redis_count_lookup_hits(1, &DATA_MSG), Some((1, 0)));
+        assert_eq!(redis_count_lookup_hits(1, &NULL_MSG), Some((0, 1)));
+        assert_eq!(redis_count_lookup_hits(3, &RedisMessage::from_integer(2)), Some((2, 1)));
+        assert_eq!(redis_count_lookup_hits(1, &RedisMessage::from_integer(2)), Some((1, 0)));
+        assert_eq!(redis_count_lookup_hits(1, &INT_MSG), Some((0, 1)));
+        assert_eq!(redis_count_lookup_hits(1, &ERR_MSG), None);
+
+        let mget_resp = redis_new_bulk_from_args(vec![DATA_MSG.clone(), RedisMessage::Null, DATA_MSG_2.clone()]);
+        assert_eq!(redis_count_lookup_hits(3, &mget_resp), Some((2, 1)));
+    }
+
+    #[test]
+    fn test_get_recorded_frame() {
+        let set = build_command(&[b"set", b"key", b"secret"]);
+        assert_eq!(
+            redis_get_recorded_frame(&set, false),
+            Some(BytesMut::from(&b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$6\r\nsecret\r\n"[..]))
+        );
+        assert_eq!(
+            redis_get_recorded_frame(&set, true),
+            Some(BytesMut::from(&b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$6\r\nxxxxxx\r\n"[..]))
+        );
+
+        let get = build_command(&[b"get", b"key"]);
+        assert_eq!(redis_get_recorded_frame(&get, true), redis_get_recorded_frame(&get, false));
+
+        assert_eq!(
+            redis_get_recorded_frame(&RedisMessage::Ping, true),
+            Some(BytesMut::from(REDIS_PING_FRAME))
+        );
+        assert_eq!(redis_get_recorded_frame(&ERR_MSG, false), None);
+
+        let routed = RedisMessage::Routed(BytesMut::from(&b"user"[..]), Box::new(set));
+        let expected = [
+            &b"*3\r\n$11\r\nsynchrotron\r\n$5\r\nroute\r\n$4\r\nuser\r\n"[..],
+            &b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$6\r\nxxxxxx\r\n"[..],
+        ]
+        .concat();
+        assert_eq!(redis_get_recorded_frame(&routed, true), Some(BytesMut::from(&expected[..])));
+    }
+
+    #[test]
+    fn test_routed_messages_are_not_fragmented() {
+        let mget = build_command(&[b"mget", b"a", b"b"]);
+        let routed = RedisMessage::Routed(BytesMut::from(&b"user"[..]), Box::new(mget.clone()));
+        assert_eq!(redis_get_fragment_count(&routed), 1);
+
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        assert!(processor.has_routing_hint(&routed));
+        assert!(!processor.has_routing_hint(&mget));
+
+        let fragments = redis_fragment_messages(vec![routed.clone()]).unwrap();
+        assert_eq!(fragments, vec![(MessageState::Standalone, routed)]);
+    }
+
+    #[test]
+    fn test_fragment_ignores_command_case() {
+        let mget = build_command(&[b"MGet", b"a", b"b"]);
+        assert!(redis_get_fragmentable(&mget).is_some());
+        assert_eq!(redis_get_fragment_count(&build_command(&[b"MSET", b"a", b"1", b"b", b"2"])), 2);
+
+        let cmd_type = BytesMut::from(REDIS_GET);
+        let fragments = redis_fragment_messages(vec![mget]).unwrap();
+        assert_eq!(
+            fragments,
+            vec![
+                (MessageState::Fragmented(cmd_type.clone(), 0, 2), build_command(&[b"get", b"a"])),
+                (MessageState::Fragmented(cmd_type, 1, 2), build_command(&[b"get", b"b"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_defragment_mget() {
+        let state = |idx| MessageState::Fragmented(BytesMut::from(REDIS_GET), idx, 3);
+        let wrongtype = RedisMessage::Error(BytesMut::from(&b"-WRONGTYPE Operation against a key\r\n"[..]), 1);
+
+        let fragments = vec![
+            (state(0), DATA_MSG.clone()),
+            (state(1), RedisMessage::Null),
+            (state(2), wrongtype),
+        ];
+        let expected = redis_new_bulk_from_args(vec![DATA_MSG.clone(), RedisMessage::Null, RedisMessage::Null]);
+        assert_eq!(redis_defragment_messages(fragments).unwrap(), expected);
+
+        // Anything else going wrong fails the whole request, rather than giving back a partial reply.
+        let fragments = vec![
+            (state(0), DATA_MSG.clone()),
+            (state(1), ERR_MSG.clone()),
+            (state(2), DATA_MSG_2.clone()),
+        ];
+        assert_eq!(redis_defragment_messages(fragments).unwrap(), ERR_MSG.clone());
+    }
+
+    #[test]
+    fn test_defragment_writes() {
+        let fragments = redis_fragment_messages(vec![build_command(&[b"MSET", b"a", b"1", b"b", b"2"])]).unwrap();
+        assert_eq!(fragments[1].1, build_command(&[b"set", b"b", b"2"]));
+
+        let state = |idx| MessageState::Fragmented(BytesMut::from(REDIS_SET), idx, 2);
+        let replies = vec![(state(0), RedisMessage::OK), (state(1), RedisMessage::OK)];
+        assert_eq!(redis_defragment_messages(replies).unwrap(), RedisMessage::OK);
+        let replies = vec![(state(0), RedisMessage::OK), (state(1), ERR_MSG.clone())];
+        assert_eq!(redis_defragment_messages(replies).unwrap(), ERR_MSG.clone());
+
+        // Deleted keys are counted up across every backend.
+        for cmd in &[&b"DEL"[..], &b"unlink"[..]] {
+            let fragments = redis_fragment_messages(vec![build_command(&[cmd, b"a", b"b", b"c"])]).unwrap();
+            let counts = vec![1, 0, 1].into_iter().map(RedisMessage::from_integer);
+            let replies = fragments.into_iter().map(|(state, _)| state).zip(counts).collect();
+            assert_eq!(redis_defragment_messages(replies).unwrap(), RedisMessage::from_integer(2));
+        }
+    }
+
+    #[test]
+    fn test_forward_keys() {
+        let transforms = KeyTransforms::new(vec![Box::new(Prefix::new(b"app:"))]);
+        let forward = |args: &[&[u8]]| redis_forward_keys(build_command(args), &transforms).into_resp();
+
+        assert_eq!(forward(&[b"get", b"a"]), build_command(&[b"get", b"app:a"]).into_resp());
+        assert_eq!(forward(&[b"set", b"a", b"1"]), build_command(&[b"set", b"app:a", b"1"]).into_resp());
+        assert_eq!(forward(&[b"del", b"a", b"b"]), build_command(&[b"del", b"app:a", b"app:b"]).into_resp());
+        assert_eq!(
+            forward(&[b"mset", b"a", b"1", b"b", b"2"]),
+            build_command(&[b"mset", b"app:a", b"1", b"app:b", b"2"]).into_resp()
+        );
+        assert_eq!(
+            forward(&[b"smove", b"a", b"b", b"m"]),
+            build_command(&[b"smove", b"app:a", b"app:b", b"m"]).into_resp()
+        );
+        assert_eq!(
+            forward(&[b"blpop", b"a", b"b", b"0"]),
+            build_command(&[b"blpop", b"app:a", b"app:b", b"0"]).into_resp()
+        );
+        assert_eq!(
+            forward(&[b"eval", b"s", b"2", b"a", b"b", b"x"]),
+            build_command(&[b"eval", b"s", b"2", b"app:a", b"app:b", b"x"]).into_resp()
+        );
+        assert_eq!(
+            forward(&[b"zunionstore", b"d", b"1", b"a", b"weights", b"2"]),
+            build_command(&[b"zunionstore", b"app:d", b"1", b"app:a", b"weights", b"2"]).into_resp()
+        );
+
+        // Commands we don't know are left alone, since we can't tell which of their arguments are keys.
+        assert_eq!(forward(&[b"info", b"a"]), build_command(&[b"info", b"a"]).into_resp());
+
+        // Transforms that only change what's hashed never show up on the wire.
+        let tag = KeyTransforms::new(vec![Box::new(HashTag::new(b'{', b'}'))]);
+        let msg = build_command(&[b"get", b"{a}:1"]);
+        assert_eq!(redis_forward_keys(msg.clone(), &tag).into_resp(), msg.into_resp());
+
+        let routed = RedisMessage::Routed(BytesMut::from(&b"hint"[..]), Box::new(build_command(&[b"get", b"a"])));
+        match redis_forward_keys(routed, &transforms) {
+            RedisMessage::Routed(hint, inner) => {
+                assert_eq!(&hint[..], b"hint");
+                assert_eq!(inner.into_resp(), build_command(&[b"get", b"app:a"]).into_resp());
+            },
+            _ => panic!("routed message lost its routing hint"),
+        }
+    }
+
+    #[test]
+    fn test_get_client_name() {
+        let setname = build_command(&[b"CLIENT", b"SETNAME", b"worker-1"]);
+        assert_eq!(redis_get_client_name(&setname), Some("worker-1".to_owned()));
+
+        let getname = build_command(&[b"client", b"getname"]);
+        assert_eq!(redis_get_client_name(&getname), None);
+        assert_eq!(redis_get_client_name(&build_command(&[b"get", b"client"])), None);
+        assert_eq!(redis_get_client_name(&RedisMessage::Ping), None);
+    }
+
+    #[test]
+    fn test_get_command_id() {
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let get = processor.find_command_id("get").unwrap();
+        assert!(get < processor.get_command_count());
+        assert_eq!(processor.get_command_id(&build_command(&[b"GET", b"key"])), Some(get));
+        assert_eq!(processor.find_command_id("GET"), Some(get));
+        assert_ne!(processor.get_command_id(&build_command(&[b"set", b"key", b"value"])), Some(get));
+        assert_eq!(processor.find_command_id("info"), None);
+        assert_eq!(processor.get_command_id(&RedisMessage::Ping), None);
     }
 
     #[test]
@@ -0,0 +1,414 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::{BackendAddress, PoolOptions};
+use futures::{
+    executor::{self, Notify, NotifyHandle},
+    future::join_all,
+    prelude::*,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+use util::runtime::run_blocking;
+
+/// Resolves a hostname and port to the addresses it currently points at.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves hostnames the same way the rest of the system does.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>> { host.to_socket_addrs().map(Iterator::collect) }
+}
+
+/// A resolver that looks hostnames up in a hosts file of its own, which can be rewritten at any
+/// time.  Each line is an IP address followed by the names it goes by.
+#[cfg(test)]
+pub struct HostsFile {
+    contents: Mutex<String>,
+}
+
+#[cfg(test)]
+impl HostsFile {
+    pub fn new(contents: &str) -> Arc<HostsFile> {
+        Arc::new(HostsFile {
+            contents: Mutex::new(contents.to_owned()),
+        })
+    }
+
+    pub fn rewrite(&self, contents: &str) { *self.contents.lock().unwrap() = contents.to_owned(); }
+}
+
+#[cfg(test)]
+impl Resolver for HostsFile {
+    fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        let split = host.rfind(':').expect("host should have a port");
+        let (name, port) = (&host[..split], host[split + 1..].parse::<u16>().unwrap());
+
+        let contents = self.contents.lock().unwrap();
+        Ok(contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let ip = fields.next()?.parse().ok()?;
+                if fields.any(|field| field == name) {
+                    Some(SocketAddr::new(ip, port))
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// Gets how often the hostnames of a pool's backends should be resolved again, from the given
+/// pool options, or `None` if they should only be resolved once.
+pub fn refresh_interval_from_options(options: &PoolOptions) -> Option<Duration> {
+    if options.dns_refresh_ms == 0 {
+        None
+    } else {
+        Some(Duration::from_millis(options.dns_refresh_ms))
+    }
+}
+
+/// Resolves the hostnames of the given backends, handing them back with the addresses they resolve
+/// to filled in.
+///
+/// Every hostname is resolved at the same time, on the blocking pool, so a pool with many of them
+/// isn't held up by each one in turn, and nothing waiting on them ties up the runtime.  A hostname
+/// that can't be resolved leaves its backend with nowhere to be reached, so it's handed back, along
+/// with why it couldn't be resolved.
+pub fn resolve_backends(
+    mut backends: Vec<BackendAddress>, resolver: &Arc<Resolver>,
+) -> impl Future<Item = Vec<BackendAddress>, Error = (String, io::Error)> + Send {
+    let hosts = get_hosts(backends.iter().map(|backend| &backend.host));
+    lookup_all(resolver, hosts).and_then(move |resolved| {
+        for backend in backends.iter_mut() {
+            let host = match backend.host {
+                Some(ref host) => host,
+                None => continue,
+            };
+
+            match resolved[host] {
+                Ok(ref addresses) => backend.address = addresses[0],
+                Err(ref e) => return Err((host.clone(), io::Error::new(e.kind(), e.to_string()))),
+            }
+        }
+
+        Ok(backends)
+    })
+}
+
+/// The current addresses of the backends in a pool, indexed by their configured position.
+///
+/// Backends given by hostname follow their hostname around: whenever it's resolved again, and
+/// points somewhere else, the backend is moved over to the new address, and the generation is
+/// bumped.  The pool watches for this, just as it does for weights, so that it can move its
+/// connections over on its own task.
+pub struct ResolvedAddresses {
+    hosts: Vec<Option<String>>,
+    addresses: Mutex<Vec<SocketAddr>>,
+    generation: AtomicUsize,
+}
+
+impl ResolvedAddresses {
+    /// Creates the addresses of the given backends, which should already have been resolved.
+    pub fn new(backends: &[BackendAddress]) -> ResolvedAddresses {
+        ResolvedAddresses {
+            hosts: backends.iter().map(|backend| backend.host.clone()).collect(),
+            addresses: Mutex::new(backends.iter().map(|backend| backend.address).collect()),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Gets the current address of the backend at the given configured position.
+    pub fn get(&self, idx: usize) -> SocketAddr { self.addresses.lock().unwrap()[idx] }
+
+    /// Gets the current generation, which changes whenever any backend moves.
+    pub fn generation(&self) -> usize { self.generation.load(Ordering::Acquire) }
+
+    /// Resolves every hostname again, moving each backend given by one to wherever it now points.
+    ///
+    /// A backend stays put for as long as its hostname still points at its current address, even
+    /// if the hostname points at others, too, so that round-robin records don't shuffle it around.
+    /// A hostname that can't be resolved leaves its backends where they were last resolved to.
+    ///
+    /// This waits on the blocking pool, so it should only ever be called from a thread of its own.
+    ///
+    /// Returns whether or not any backend moved.
+    pub fn refresh(&self, resolver: &Arc<Resolver>) -> bool {
+        let resolved = match lookup_all::<()>(resolver, get_hosts(self.hosts.iter())).wait() {
+            Ok(resolved) => resolved,
+            Err(()) => return false,
+        };
+        for (host, result) in &resolved {
+            if let Err(ref e) = result {
+                warn!("[backend] failed to resolve backend '{}', keeping its last address: {}", host, e);
+            }
+        }
+
+        let mut addresses = self.addresses.lock().unwrap();
+        let mut moved = false;
+        for (idx, host) in self.hosts.iter().enumerate() {
+            let (host, candidates) = match host.as_ref().map(|host| (host, &resolved[host])) {
+                Some((host, Ok(candidates))) => (host, candidates),
+                _ => continue,
+            };
+
+            if !candidates.contains(&addresses[idx]) {
+                info!("[backend] backend '{}' moved from {} to {}", host, addresses[idx], candidates[0]);
+                addresses[idx] = candidates[0];
+                moved = true;
+            }
+        }
+
+        if moved {
+            self.generation.fetch_add(1, Ordering::AcqRel);
+        }
+        moved
+    }
+
+    /// Resolves every hostname again on the given interval, until `stop` resolves, or until nothing
+    /// is using these addresses anymore.
+    ///
+    /// Resolving can block for a while, so it's done on a thread of its own, rather than on any
+    /// task that has better things to do.  The thread doesn't wait for the next refresh to come
+    /// around before noticing it's been told to stop.  If no backends were given by hostname,
+    /// there's nothing to do at all.
+    pub fn refresh_every<F>(addresses: &Arc<ResolvedAddresses>, resolver: Arc<Resolver>, interval: Duration, stop: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        if addresses.hosts.iter().all(Option::is_none) {
+            return;
+        }
+
+        let addresses = Arc::downgrade(addresses);
+        thread::spawn(move || {
+            let notify = NotifyHandle::from(Arc::new(ThreadNotify(thread::current())));
+            let mut stop = executor::spawn(stop);
+            loop {
+                let deadline = Instant::now() + interval;
+                loop {
+                    match stop.poll_future_notify(&notify, 0) {
+                        Ok(Async::NotReady) => {},
+                        _ => return,
+                    }
+
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+
+                match addresses.upgrade() {
+                    Some(addresses) => addresses.refresh(&resolver),
+                    None => break,
+                };
+            }
+        });
+    }
+}
+
+/// Wakes up the thread it was made on, so that a plain thread can wait on a future without a runtime.
+struct ThreadNotify(Thread);
+
+impl Notify for ThreadNotify {
+    fn notify(&self, _id: usize) { self.0.unpark(); }
+}
+
+/// Gets every distinct hostname out of the given hosts.
+fn get_hosts<'a, I>(hosts: I) -> Vec<String>
+where
+    I: Iterator<Item = &'a Option<String>>,
+{
+    let mut hosts = hosts.filter_map(Clone::clone).collect::<Vec<_>>();
+    hosts.sort();
+    hosts.dedup();
+    hosts
+}
+
+/// Resolves all of the given hostnames at once, on the blocking pool, resolving once they're all
+/// done.
+///
+/// A hostname that resolves to nothing at all is treated as having failed to resolve.  Failures are
+/// handed back alongside each hostname, so the lookups as a whole never fail.
+fn lookup_all<E>(
+    resolver: &Arc<Resolver>, hosts: Vec<String>,
+) -> impl Future<Item = HashMap<String, io::Result<Vec<SocketAddr>>>, Error = E> + Send
+where
+    E: Send + 'static,
+{
+    let lookups = hosts
+        .into_iter()
+        .map(|host| {
+            let resolver = resolver.clone();
+            let lookup_host = host.clone();
+            run_blocking(move || resolver.resolve(&lookup_host)).then(move |result| {
+                let result = result.and_then(|result| result).and_then(|addresses| {
+                    if addresses.is_empty() {
+                        Err(io::Error::new(io::ErrorKind::NotFound, "no addresses found"))
+                    } else {
+                        Ok(addresses)
+                    }
+                });
+                Ok::<_, E>((host, result))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    join_all(lookups).map(|resolved| resolved.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, sync::oneshot};
+    use serde_json;
+
+    fn get_backends(raw: &[&str]) -> Vec<BackendAddress> {
+        raw.iter()
+            .map(|raw| serde_json::from_str(&format!("\"{}\"", raw)).unwrap())
+            .collect()
+    }
+
+    fn addr(raw: &str) -> SocketAddr { raw.parse().unwrap() }
+
+    #[test]
+    fn test_refresh_interval_from_options() {
+        let mut options = PoolOptions::default();
+        assert_eq!(refresh_interval_from_options(&options), Some(Duration::from_millis(30_000)));
+        options.dns_refresh_ms = 250;
+        assert_eq!(refresh_interval_from_options(&options), Some(Duration::from_millis(250)));
+        options.dns_refresh_ms = 0;
+        assert_eq!(refresh_interval_from_options(&options), None);
+    }
+
+    #[test]
+    fn test_resolve_backends() {
+        let hosts = HostsFile::new("10.0.0.1 redis-0 redis-0.cache\n10.0.0.2 redis-1\n");
+        let resolver: Arc<Resolver> = hosts.clone();
+
+        let backends = get_backends(&["redis-0:6379", "10.0.0.9:6380", "redis-1:6381 cache1"]);
+        let backends = resolve_backends(backends, &resolver).wait().unwrap();
+        assert_eq!(backends[0].address, addr("10.0.0.1:6379"));
+        assert_eq!(backends[1].address, addr("10.0.0.9:6380"));
+        assert_eq!(backends[2].address, addr("10.0.0.2:6381"));
+        assert_eq!(backends[2].identifier, "cache1");
+
+        let missing = get_backends(&["redis-0:6379", "redis-2:6379"]);
+        let (host, _) = resolve_backends(missing, &resolver).wait().unwrap_err();
+        assert_eq!(host, "redis-2:6379");
+    }
+
+    #[test]
+    fn test_refresh() {
+        let hosts = HostsFile::new("10.0.0.1 redis-0\n10.0.0.2 redis-1\n");
+        let resolver: Arc<Resolver> = hosts.clone();
+
+        let backends = get_backends(&["redis-0:6379", "redis-1:6379", "10.0.0.9:6379"]);
+        let backends = resolve_backends(backends, &resolver).wait().unwrap();
+        let addresses = ResolvedAddresses::new(&backends);
+
+        // Nothing has changed, so nothing moves.
+        assert!(!addresses.refresh(&resolver));
+        assert_eq!(addresses.generation(), 0);
+
+        // Once a pod is rescheduled, its backend follows it to its new address, while everything
+        // else stays where it was.
+        hosts.rewrite("10.0.0.7 redis-0\n10.0.0.2 redis-1\n");
+        assert!(addresses.refresh(&resolver));
+        assert_eq!(addresses.generation(), 1);
+        assert_eq!(addresses.get(0), addr("10.0.0.7:6379"));
+        assert_eq!(addresses.get(1), addr("10.0.0.2:6379"));
+        assert_eq!(addresses.get(2), addr("10.0.0.9:6379"));
+
+        // A hostname that also points at its backend's current address doesn't move it.
+        hosts.rewrite("10.0.0.3 redis-0 redis-1\n10.0.0.7 redis-0\n10.0.0.2 redis-1\n");
+        assert!(!addresses.refresh(&resolver));
+        assert_eq!(addresses.get(0), addr("10.0.0.7:6379"));
+        assert_eq!(addresses.get(1), addr("10.0.0.2:6379"));
+
+        // A hostname that stops resolving leaves its backend where it last was.
+        hosts.rewrite("10.0.0.2 redis-1\n");
+        assert!(!addresses.refresh(&resolver));
+        assert_eq!(addresses.generation(), 1);
+        assert_eq!(addresses.get(0), addr("10.0.0.7:6379"));
+    }
+
+    #[test]
+    fn test_refresh_every() {
+        let hosts = HostsFile::new("10.0.0.1 redis-0\n");
+        let resolver: Arc<Resolver> = hosts.clone();
+
+        let backends = get_backends(&["redis-0:6379"]);
+        let backends = resolve_backends(backends, &resolver).wait().unwrap();
+        let addresses = Arc::new(ResolvedAddresses::new(&backends));
+        ResolvedAddresses::refresh_every(&addresses, resolver, Duration::from_millis(10), future::empty());
+
+        // The swap is picked up on its own, without anyone asking for it.
+        hosts.rewrite("10.0.0.2 redis-0\n");
+        for _ in 0..500 {
+            if addresses.generation() > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(addresses.get(0), addr("10.0.0.2:6379"));
+    }
+
+    #[test]
+    fn test_refresh_every_stops() {
+        let hosts = HostsFile::new("10.0.0.1 redis-0\n");
+        let resolver: Arc<Resolver> = hosts.clone();
+
+        let backends = get_backends(&["redis-0:6379"]);
+        let backends = resolve_backends(backends, &resolver).wait().unwrap();
+        let addresses = Arc::new(ResolvedAddresses::new(&backends));
+
+        // The next refresh is a long way off, but being told to stop is noticed right away, and the
+        // thread lets go of everything it was holding on to as it exits.
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let stop = stop_rx.map_err(|_| ());
+        ResolvedAddresses::refresh_every(&addresses, resolver, Duration::from_secs(3600), stop);
+        assert_eq!(Arc::strong_count(&hosts), 2);
+
+        stop_tx.send(()).unwrap();
+        for _ in 0..500 {
+            if Arc::strong_count(&hosts) == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Arc::strong_count(&hosts), 1);
+        assert_eq!(addresses.generation(), 0);
+    }
+}
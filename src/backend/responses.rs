@@ -0,0 +1,349 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::PoolOptions;
+use errors::CreationError;
+use metrics::MetricSink;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+use util::clock::{duration_as_ms, elapsed};
+
+// How often, at most, a pool warns about large responses.  Anything over the threshold in between
+// is still counted, and summed up in the next warning.
+const WARN_INTERVAL_MS: u64 = 10_000;
+
+// Response sizes are counted in these buckets, each holding the responses up to its size.
+const RESPONSE_SIZE_BUCKETS: &[(usize, &str)] = &[
+    (1024, "responses_under_1kb"),
+    (16 * 1024, "responses_1kb_to_16kb"),
+    (1024 * 1024, "responses_16kb_to_1mb"),
+    (16 * 1024 * 1024, "responses_1mb_to_16mb"),
+    (std::usize::MAX, "responses_over_16mb"),
+];
+
+// Keys are only logged up to this many bytes, so that a large key doesn't turn into a large log line.
+const MAX_LOGGED_KEY_LEN: usize = 128;
+
+/// Limits on the size of responses from the backends of a pool, parsed from its options.
+#[derive(Clone, Debug, Default)]
+pub struct ResponseSizeConfiguration {
+    /// Responses larger than this are logged.
+    pub warn_bytes: Option<usize>,
+
+    /// Responses larger than this are abandoned, along with the connection they came in on.
+    pub max_bytes: Option<usize>,
+}
+
+impl ResponseSizeConfiguration {
+    pub fn from_options(options: &PoolOptions) -> Result<ResponseSizeConfiguration, CreationError> {
+        let warn_bytes = check_size(options.warn_response_bytes, "warn_response_bytes")?;
+        let max_bytes = check_size(options.max_response_bytes, "max_response_bytes")?;
+
+        Ok(ResponseSizeConfiguration { warn_bytes, max_bytes })
+    }
+}
+
+fn check_size(bytes: Option<usize>, name: &str) -> Result<Option<usize>, CreationError> {
+    match bytes {
+        Some(0) => Err(CreationError::InvalidParameter(format!("options.{}", name))),
+        bytes => Ok(bytes),
+    }
+}
+
+/// Tracks the size of responses from the backends of a pool.
+///
+/// Every response is counted by its size, so that the stats show what sort of responses a pool is
+/// handling.  Responses over `warn_response_bytes` are logged with the command and key that asked
+/// for them, though no more than once every ten seconds.
+///
+/// If `max_response_bytes` is set, responses that declare themselves to be larger than it are never
+/// read in full: the client gets an error instead, and since there's no way to skip over the rest
+/// of the response in every case, the backend connection it was coming in on is thrown away.  This
+/// sacrifices a connection to protect the memory, and network, of the proxy as a whole.
+pub struct ResponseSizeTracker {
+    config: ResponseSizeConfiguration,
+    epoch: Instant,
+    last_warned_ms: AtomicUsize,
+    suppressed: AtomicUsize,
+    sink: MetricSink,
+}
+
+impl ResponseSizeTracker {
+    pub fn new(config: ResponseSizeConfiguration, sink: MetricSink) -> ResponseSizeTracker {
+        ResponseSizeTracker {
+            config,
+            epoch: Instant::now(),
+            last_warned_ms: AtomicUsize::new(0),
+            suppressed: AtomicUsize::new(0),
+            sink,
+        }
+    }
+
+    /// Gets the largest response, in bytes, that will be read from a backend.
+    pub fn max_bytes(&self) -> Option<usize> { self.config.max_bytes }
+
+    /// Records a push from a backend, which was dropped since no request was waiting on it.
+    pub fn record_push(&self) { self.sink.increment("pushes_dropped"); }
+
+    /// Records a response of the given size, to the given command and key.
+    pub fn record(&self, command: &[u8], key: &[u8], size: usize) {
+        let bucket = RESPONSE_SIZE_BUCKETS
+            .iter()
+            .find(|(limit, _)| size <= *limit)
+            .map(|(_, name)| *name)
+            .expect("last bucket holds everything");
+        self.sink.increment(bucket);
+        self.sink.update_count("response_bytes", size as i64);
+
+        if let Some(warn_bytes) = self.config.warn_bytes {
+            if size > warn_bytes {
+                self.sink.increment("large_responses");
+                self.warn(command, key, size, warn_bytes);
+            }
+        }
+    }
+
+    /// Records a response that was abandoned for declaring itself larger than `max_bytes`.
+    pub fn record_rejected(&self, command: &[u8], key: &[u8], declared: usize) {
+        self.sink.increment("rejected_responses");
+        error!(
+            "[backend] abandoned response to {} for key '{}': {} bytes is over the limit of {} bytes, dropping the \
+             backend connection",
+            String::from_utf8_lossy(command),
+            get_loggable_key(key),
+            declared,
+            self.config.max_bytes.unwrap_or(0)
+        );
+    }
+
+    fn warn(&self, command: &[u8], key: &[u8], size: usize, warn_bytes: usize) {
+        // The first warning always goes out; after that, only one every so often.
+        let now_ms = duration_as_ms(elapsed(self.epoch)) as usize + 1;
+        let last_warned_ms = self.last_warned_ms.load(Ordering::Relaxed);
+        let due = last_warned_ms == 0 || now_ms.saturating_sub(last_warned_ms) >= WARN_INTERVAL_MS as usize;
+        if !due
+            || self
+                .last_warned_ms
+                .compare_exchange(last_warned_ms, now_ms, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let suppressed = self.suppressed.swap(0, Ordering::Relaxed);
+        warn!(
+            "[backend] large response to {} for key '{}': {} bytes is over {} bytes ({} more since the last warning)",
+            String::from_utf8_lossy(command),
+            get_loggable_key(key),
+            size,
+            warn_bytes,
+            suppressed
+        );
+    }
+}
+
+fn get_loggable_key(key: &[u8]) -> String {
+    let len = key.len().min(MAX_LOGGED_KEY_LEN);
+    let mut loggable = String::from_utf8_lossy(&key[..len]).into_owned();
+    if len < key.len() {
+        loggable.push_str("...");
+    }
+    loggable
+}
+
+/// Figures out how large the response at the front of a buffer is, as it arrives.
+///
+/// Responses declare the length of their values, and the number of elements in their arrays, up
+/// front, so we know at least how large a response is going to be well before all of it has
+/// arrived.  The scanner picks up where it left off every time more of the response arrives, so
+/// scanning a response, no matter how many pieces it arrives in, only ever looks at each header
+/// once.
+#[derive(Default)]
+pub struct ResponseScanner {
+    // Where the next header starts, relative to the start of the response.
+    offset: usize,
+
+    // How many bytes the response has declared so far, including the headers.
+    declared: usize,
+
+    // How many elements are left to go in each of the arrays we're in the middle of.
+    remaining: Vec<usize>,
+
+    // Whether or not we've seen every header in the response.
+    complete: bool,
+}
+
+impl ResponseScanner {
+    /// Scans whatever has arrived of the response at the front of the given buffer since the last
+    /// scan, returning how large the response is known to be so far.
+    pub fn scan(&mut self, buf: &[u8]) -> usize {
+        while !self.complete && self.offset < buf.len() {
+            let header_end = match buf[self.offset..].windows(2).position(|bytes| bytes == b"\r\n") {
+                Some(pos) => self.offset + pos,
+                None => break,
+            };
+            let header_len = header_end - self.offset + 2;
+            let value = std::str::from_utf8(&buf[self.offset + 1..header_end])
+                .ok()
+                .and_then(|raw| i64::from_str(raw).ok());
+
+            // RESP3 types are counted like their RESP2 equivalents.  Maps hold a key and a value
+            // for every entry, and attributes are followed by the value they describe.
+            match (buf[self.offset], value) {
+                (b'$', Some(len)) | (b'=', Some(len)) | (b'!', Some(len)) if len >= 0 => {
+                    let len = header_len + len as usize + 2;
+                    self.declared += len;
+                    self.offset += len;
+                    self.finish_element();
+                },
+                (b'*', Some(count)) | (b'~', Some(count)) | (b'>', Some(count)) if count > 0 => {
+                    self.declared += header_len;
+                    self.offset += header_len;
+                    self.remaining.push(count as usize);
+                },
+                (b'%', Some(count)) if count > 0 => {
+                    self.declared += header_len;
+                    self.offset += header_len;
+                    self.remaining.push(count as usize * 2);
+                },
+                (b'|', Some(count)) if count >= 0 => {
+                    self.declared += header_len;
+                    self.offset += header_len;
+                    self.remaining.push(count as usize * 2 + 1);
+                },
+                (b'$', None) | (b'*', None) => {
+                    // This isn't a response we can make sense of, so we leave it to the parser to
+                    // complain about.
+                    self.complete = true;
+                },
+                _ => {
+                    self.declared += header_len;
+                    self.offset += header_len;
+                    self.finish_element();
+                },
+            }
+        }
+
+        self.declared
+    }
+
+    /// Resets the scanner, for when the response it was scanning has been taken off the buffer.
+    pub fn reset(&mut self) { *self = ResponseScanner::default(); }
+
+    fn finish_element(&mut self) {
+        loop {
+            match self.remaining.last_mut() {
+                None => {
+                    self.complete = true;
+                    return;
+                },
+                Some(remaining) => {
+                    *remaining -= 1;
+                    if *remaining > 0 {
+                        return;
+                    }
+                },
+            }
+            self.remaining.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_declared_bulk_string() {
+        let mut scanner = ResponseScanner::default();
+
+        // We know how large the value is as soon as we see its length.
+        assert_eq!(scanner.scan(b"$10"), 0);
+        assert_eq!(scanner.scan(b"$104857600\r\n"), 104_857_614);
+        assert_eq!(scanner.scan(b"$104857600\r\nabc"), 104_857_614);
+    }
+
+    #[test]
+    fn test_scan_declared_array() {
+        let mut scanner = ResponseScanner::default();
+        let response = b"*3\r\n$3\r\nfoo\r\n*2\r\n:1\r\n$-1\r\n$5\r\nhello\r\n";
+
+        // Only what's arrived of the array counts, since the rest of it hasn't declared anything.
+        assert_eq!(scanner.scan(&response[..13]), 13);
+        assert_eq!(scanner.scan(&response[..19]), 17);
+        assert_eq!(scanner.scan(&response[..21]), 21);
+        assert_eq!(scanner.scan(&response[..]), response.len());
+        assert!(scanner.complete);
+
+        // Anything after the response belongs to the next one.
+        let mut scanner = ResponseScanner::default();
+        let mut buf = response.to_vec();
+        buf.extend_from_slice(b"$100\r\n");
+        assert_eq!(scanner.scan(&buf), response.len());
+
+        scanner.reset();
+        assert_eq!(scanner.scan(b"$100\r\n"), 108);
+    }
+
+    #[test]
+    fn test_scan_simple_responses() {
+        let mut scanner = ResponseScanner::default();
+        assert_eq!(scanner.scan(b"+OK\r\n"), 5);
+
+        let mut scanner = ResponseScanner::default();
+        assert_eq!(scanner.scan(b"$-1\r\n"), 5);
+
+        let mut scanner = ResponseScanner::default();
+        assert_eq!(scanner.scan(b"*0\r\n"), 4);
+    }
+
+    #[test]
+    fn test_scan_resp3_responses() {
+        let mut scanner = ResponseScanner::default();
+        assert_eq!(scanner.scan(b"%1\r\n$3\r\nfoo\r\n~1\r\n=104857600\r\n"), 4 + 9 + 4 + 104_857_614);
+        assert!(scanner.complete);
+
+        // The value an attribute describes is part of the same response.
+        let mut scanner = ResponseScanner::default();
+        assert_eq!(scanner.scan(b"|1\r\n+a\r\n+b\r\n"), 12);
+        assert!(!scanner.complete);
+        assert_eq!(scanner.scan(b"|1\r\n+a\r\n+b\r\n$100\r\n"), 120);
+        assert!(scanner.complete);
+    }
+
+    #[test]
+    fn test_from_options() {
+        let mut options = PoolOptions::default();
+        let config = ResponseSizeConfiguration::from_options(&options).unwrap();
+        assert!(config.warn_bytes.is_none());
+        assert!(config.max_bytes.is_none());
+
+        options.warn_response_bytes = Some(1_048_576);
+        options.max_response_bytes = Some(0);
+        assert!(ResponseSizeConfiguration::from_options(&options).is_err());
+
+        options.max_response_bytes = Some(16_777_216);
+        let config = ResponseSizeConfiguration::from_options(&options).unwrap();
+        assert_eq!(config.warn_bytes, Some(1_048_576));
+        assert_eq!(config.max_bytes, Some(16_777_216));
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::PoolOptions;
+use errors::CreationError;
+use rand::{thread_rng, Rng};
+use std::time::{Duration, Instant};
+
+// Limits are jittered by up to this fraction either way, so that connections opened together
+// aren't all retired together.
+const JITTER: f64 = 0.1;
+
+// The highest limits we take, which keep jittered limits, and the deadlines worked out from them,
+// clear of overflowing, even where a usize is only 32 bits: a year, and a little under four
+// billion requests.
+const MAX_AGE_MS: u64 = 365 * 24 * 60 * 60 * 1000;
+const MAX_REQUESTS: u64 = std::u32::MAX as u64 * 9 / 10;
+
+/// Why a backend connection was retired.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetireReason {
+    /// The connection was open for as long as it was allowed to be.
+    Age,
+
+    /// The connection was sent as many requests as it was allowed to be.
+    Requests,
+
+    /// The backend moved to a different address than the connection was made to.
+    Address,
+}
+
+impl RetireReason {
+    /// Gets the name of the metric that counts retirements for this reason.
+    pub fn metric_name(self) -> &'static str {
+        match self {
+            RetireReason::Age => "retired_conns_age",
+            RetireReason::Requests => "retired_conns_requests",
+            RetireReason::Address => "retired_conns_address",
+        }
+    }
+}
+
+/// Limits on how long a backend connection is used before it's replaced, parsed from the options
+/// of its pool.
+#[derive(Clone, Debug, Default)]
+pub struct RetirementConfiguration {
+    /// Connections are retired after being open for about this long.
+    pub max_age: Option<Duration>,
+
+    /// Connections are retired after being sent about this many requests.
+    pub max_requests: Option<usize>,
+}
+
+impl RetirementConfiguration {
+    pub fn from_options(options: &PoolOptions) -> Result<RetirementConfiguration, CreationError> {
+        let max_age = check_limit(
+            options.backend_max_connection_age_ms,
+            "backend_max_connection_age_ms",
+            MAX_AGE_MS,
+        )?;
+        let max_requests = check_limit(
+            options.backend_max_requests_per_connection,
+            "backend_max_requests_per_connection",
+            MAX_REQUESTS,
+        )?;
+
+        Ok(RetirementConfiguration {
+            max_age: max_age.map(Duration::from_millis),
+            max_requests: max_requests.map(|n| n as usize),
+        })
+    }
+}
+
+fn check_limit(limit: Option<u64>, name: &str, max: u64) -> Result<Option<u64>, CreationError> {
+    match limit {
+        Some(limit) if limit == 0 || limit > max => {
+            Err(CreationError::InvalidParameter(format!("options.{}", name)))
+        },
+        limit => Ok(limit),
+    }
+}
+
+/// Tracks when a backend connection is due to be retired.
+///
+/// Each connection gets its own limits, jittered from the configured ones, every time it's
+/// replaced.  Retirement itself is up to the connection: it dials a replacement once it's due,
+/// and swaps it in between batches, so that nothing in flight is lost and nothing waits on the
+/// replacement being dialed.
+pub struct Retirement {
+    config: RetirementConfiguration,
+    deadline: Option<Instant>,
+    requests_left: Option<usize>,
+}
+
+impl Retirement {
+    pub fn new(config: RetirementConfiguration) -> Retirement {
+        Retirement {
+            config,
+            deadline: None,
+            requests_left: None,
+        }
+    }
+
+    /// Whether or not any limits are configured at all.
+    pub fn is_enabled(&self) -> bool { self.config.max_age.is_some() || self.config.max_requests.is_some() }
+
+    /// Starts counting towards the limits of a newly opened connection.
+    pub fn reset(&mut self, now: Instant) {
+        let mut rng = thread_rng();
+        self.deadline = self.config.max_age.map(|max_age| {
+            let ms = jitter(&mut rng, max_age.as_secs() * 1000 + u64::from(max_age.subsec_millis()));
+            now + Duration::from_millis(ms)
+        });
+        self.requests_left = self
+            .config
+            .max_requests
+            .map(|max_requests| jitter(&mut rng, max_requests as u64) as usize);
+    }
+
+    /// Records requests being sent over the connection.
+    pub fn record(&mut self, requests: usize) {
+        if let Some(left) = self.requests_left.as_mut() {
+            *left = left.saturating_sub(requests);
+        }
+    }
+
+    /// Checks whether the connection is due to be retired, and if so, why.
+    pub fn due(&self, now: Instant) -> Option<RetireReason> {
+        if self.requests_left == Some(0) {
+            return Some(RetireReason::Requests);
+        }
+
+        match self.deadline {
+            Some(deadline) if deadline <= now => Some(RetireReason::Age),
+            _ => None,
+        }
+    }
+}
+
+fn jitter<R: Rng>(rng: &mut R, limit: u64) -> u64 {
+    let spread = (limit as f64 * JITTER) as u64;
+    rng.gen_range(limit - spread, limit + spread + 1).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_options(options: &[(&str, &str)]) -> Result<PoolOptions, String> {
+        let options = options
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        PoolOptions::from_map(options)
+    }
+
+    fn get_retirement(options: &[(&str, &str)]) -> Retirement {
+        Retirement::new(RetirementConfiguration::from_options(&get_options(options).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_from_options() {
+        let config = RetirementConfiguration::from_options(&PoolOptions::default()).unwrap();
+        assert_eq!(config.max_age, None);
+        assert_eq!(config.max_requests, None);
+
+        for name in &["backend_max_requests_per_connection", "backend_max_connection_age_ms"] {
+            // Limits that aren't numbers at all don't get past loading the configuration.
+            for bad in &["-1", "many"] {
+                assert!(get_options(&[(*name, *bad)]).is_err());
+            }
+            for bad in &["0", "18446744073709551615"] {
+                let options = get_options(&[(*name, *bad)]).unwrap();
+                assert!(RetirementConfiguration::from_options(&options).is_err());
+            }
+        }
+
+        // The highest limits we take can still be jittered and counted towards.
+        let mut retirement = get_retirement(&[
+            ("backend_max_connection_age_ms", "31536000000"),
+            ("backend_max_requests_per_connection", "3865470565"),
+        ]);
+        let now = Instant::now();
+        retirement.reset(now);
+        assert_eq!(retirement.due(now), None);
+    }
+
+    #[test]
+    fn test_requests_jittered() {
+        let now = Instant::now();
+        let mut seen = Vec::new();
+        for _ in 0..100 {
+            let mut retirement = get_retirement(&[("backend_max_requests_per_connection", "100")]);
+            retirement.reset(now);
+
+            let mut sent = 0;
+            while retirement.due(now).is_none() {
+                retirement.record(1);
+                sent += 1;
+            }
+
+            assert_eq!(retirement.due(now), Some(RetireReason::Requests));
+            assert!(sent >= 90 && sent <= 110, "retired after {} requests", sent);
+            seen.push(sent);
+        }
+
+        // With a hundred connections, they shouldn't all have been retired at the same count.
+        seen.sort();
+        seen.dedup();
+        assert!(seen.len() > 1);
+    }
+
+    #[test]
+    fn test_age() {
+        let now = Instant::now();
+        let mut retirement = get_retirement(&[("backend_max_connection_age_ms", "1000")]);
+        assert!(retirement.is_enabled());
+        retirement.reset(now);
+
+        assert_eq!(retirement.due(now), None);
+        assert_eq!(retirement.due(now + Duration::from_millis(899)), None);
+        assert_eq!(retirement.due(now + Duration::from_millis(1100)), Some(RetireReason::Age));
+
+        // A fresh connection starts counting all over again.
+        let later = now + Duration::from_millis(1100);
+        retirement.reset(later);
+        assert_eq!(retirement.due(later), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let now = Instant::now();
+        let mut retirement = get_retirement(&[]);
+        assert!(!retirement.is_enabled());
+        retirement.reset(now);
+        retirement.record(1_000_000);
+        assert_eq!(retirement.due(now + Duration::from_secs(86400)), None);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::PoolOptions;
+use errors::CreationError;
+use metrics::MetricSink;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Tokens are tracked in thousandths of a retry, so that fractional ratios deposit exactly.
+const TOKEN_SCALE: usize = 1000;
+
+// The most retries a budget can save up, so that a long quiet period can't fund a retry storm.
+const MAX_SAVED_RETRIES: usize = 100;
+
+/// A shared budget for retrying requests against a pool.
+///
+/// Every successful request that wasn't itself a retry deposits `ratio` of a retry into the
+/// budget, and every retry withdraws a whole one.  This keeps retries to roughly `ratio` of the
+/// requests a pool is serving, so that retries can't multiply load on backends that are already
+/// struggling.
+#[derive(Debug)]
+pub struct RetryBudget {
+    deposit: usize,
+    max_balance: usize,
+    balance: AtomicUsize,
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64) -> RetryBudget {
+        let deposit = (ratio * TOKEN_SCALE as f64).round() as usize;
+
+        RetryBudget {
+            deposit,
+            max_balance: MAX_SAVED_RETRIES * TOKEN_SCALE,
+            balance: AtomicUsize::new(0),
+        }
+    }
+
+    /// Creates a retry budget from the given pool options.
+    pub fn from_options(options: &PoolOptions) -> Result<RetryBudget, CreationError> {
+        let ratio = options.retry_budget_ratio;
+        if ratio.is_nan() || ratio < 0.0 || ratio > 1.0 {
+            return Err(CreationError::InvalidParameter("options.retry_budget_ratio".to_string()));
+        }
+
+        Ok(RetryBudget::new(ratio))
+    }
+
+    /// Refills the budget for the given number of successful, non-retried requests.
+    pub fn deposit(&self, requests: usize) {
+        if requests == 0 || self.deposit == 0 {
+            return;
+        }
+
+        let amount = requests * self.deposit;
+        let mut current = self.balance.load(Ordering::Relaxed);
+        loop {
+            let new = (current + amount).min(self.max_balance);
+            match self
+                .balance
+                .compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Checks whether a request that failed with `err` may be retried.
+    ///
+    /// Every retry site goes through here.  If the budget allows it, a retry is withdrawn and the
+    /// caller may go ahead.  Otherwise, the original error is handed back so that the request can
+    /// fail immediately.
+    pub fn try_retry<E>(&self, err: E, sink: &MetricSink) -> Result<(), E> {
+        let mut current = self.balance.load(Ordering::Relaxed);
+        loop {
+            if current < TOKEN_SCALE {
+                sink.increment("retry_budget_exhausted");
+                return Err(err);
+            }
+
+            match self.balance.compare_exchange_weak(
+                current,
+                current - TOKEN_SCALE,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    sink.increment("retries");
+                    return Ok(());
+                },
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::get_sink;
+
+    #[test]
+    fn test_empty_budget_refuses_retries() {
+        let budget = RetryBudget::new(0.1);
+        let sink = get_sink();
+        assert_eq!(budget.try_retry("boom", &sink), Err("boom"));
+
+        budget.deposit(9);
+        assert_eq!(budget.try_retry("boom", &sink), Err("boom"));
+
+        budget.deposit(1);
+        assert_eq!(budget.try_retry("boom", &sink), Ok(()));
+        assert_eq!(budget.try_retry("boom", &sink), Err("boom"));
+    }
+
+    #[test]
+    fn test_budget_is_capped() {
+        let budget = RetryBudget::new(1.0);
+        let sink = get_sink();
+        budget.deposit(10 * MAX_SAVED_RETRIES);
+
+        let allowed = (0..2 * MAX_SAVED_RETRIES)
+            .filter(|_| budget.try_retry((), &sink).is_ok())
+            .count();
+        assert_eq!(allowed, MAX_SAVED_RETRIES);
+    }
+
+    #[test]
+    fn test_options() {
+        let mut options = PoolOptions::default();
+        assert_eq!(RetryBudget::from_options(&options).unwrap().deposit, 100);
+
+        options.retry_budget_ratio = 0.25;
+        assert_eq!(RetryBudget::from_options(&options).unwrap().deposit, 250);
+
+        options.retry_budget_ratio = 1.5;
+        assert!(RetryBudget::from_options(&options).is_err());
+
+        options.retry_budget_ratio = std::f64::NAN;
+        assert!(RetryBudget::from_options(&options).is_err());
+    }
+
+    #[test]
+    fn test_failing_backend_load_is_bounded() {
+        let budget = RetryBudget::new(0.1);
+        let sink = get_sink();
+
+        // Every other attempt against our backend fails, and callers retry until they either get
+        // a success or run out of budget.
+        let requests = 100_000;
+        let mut attempts = 0;
+        for _ in 0..requests {
+            let mut retried = false;
+            loop {
+                attempts += 1;
+                if attempts % 2 == 0 {
+                    if !retried {
+                        budget.deposit(1);
+                    }
+                    break;
+                }
+
+                if budget.try_retry((), &sink).is_err() {
+                    break;
+                }
+                retried = true;
+            }
+        }
+
+        // Only the requests that succeed first time fund retries, so we should land a little under
+        // the full 10% on top of the original load.
+        let load = f64::from(attempts) / f64::from(requests);
+        assert!(load > 1.02, "budget should allow some retries (load {})", load);
+        assert!(load <= 1.1, "retries should stay within budget (load {})", load);
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use errors::CreationError;
+use std::collections::HashMap;
+
+/// How requests to a backend are spread across its connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionSelection {
+    /// Each batch goes to the next connection in turn.
+    RoundRobin,
+
+    /// Each batch goes to the connection with the fewest requests queued or in flight, taking
+    /// turns between connections that are tied.
+    LeastInFlight,
+}
+
+impl ConnectionSelection {
+    /// Gets how to spread requests across the connections to each backend from the given pool
+    /// options.
+    pub fn from_options(options: &HashMap<String, String>) -> Result<ConnectionSelection, CreationError> {
+        match options.get("conn_selection").map(String::as_str) {
+            None | Some("round_robin") => Ok(ConnectionSelection::RoundRobin),
+            Some("least_in_flight") => Ok(ConnectionSelection::LeastInFlight),
+            Some(_) => Err(CreationError::InvalidParameter("options.conn_selection".to_string())),
+        }
+    }
+
+    /// Chooses a connection, given whether each connection is available and how many requests it
+    /// has queued or in flight.
+    ///
+    /// Connections that aren't available are passed over, unless none of them are: rather than
+    /// having nowhere to send requests, we go on as if every connection were available.  `next` is
+    /// where taking turns picks up from, and is moved along past the chosen connection.
+    pub fn choose(self, conns: &[(bool, usize)], next: &mut usize) -> usize {
+        let any_available = conns.iter().any(|&(available, _)| available);
+        let mut candidates = (0..conns.len())
+            .map(|offset| (*next + offset) % conns.len())
+            .filter(|idx| !any_available || conns[*idx].0);
+
+        let chosen = match self {
+            ConnectionSelection::RoundRobin => candidates.next(),
+            ConnectionSelection::LeastInFlight => candidates.min_by_key(|idx| conns[*idx].1),
+        }
+        .unwrap_or(0);
+
+        *next = (chosen + 1) % conns.len().max(1);
+        chosen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_options(options: &[(&str, &str)]) -> HashMap<String, String> {
+        options
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_options() {
+        let default = ConnectionSelection::from_options(&get_options(&[])).unwrap();
+        assert_eq!(default, ConnectionSelection::RoundRobin);
+
+        let options = get_options(&[("conn_selection", "least_in_flight")]);
+        let configured = ConnectionSelection::from_options(&options).unwrap();
+        assert_eq!(configured, ConnectionSelection::LeastInFlight);
+
+        assert!(ConnectionSelection::from_options(&get_options(&[("conn_selection", "random")])).is_err());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let selection = ConnectionSelection::RoundRobin;
+        let mut next = 0;
+        let conns = [(true, 5), (true, 0), (true, 9)];
+        let chosen = (0..4).map(|_| selection.choose(&conns, &mut next)).collect::<Vec<_>>();
+        assert_eq!(chosen, vec![0, 1, 2, 0]);
+
+        // Unavailable connections are skipped, without throwing off whose turn it is.
+        let conns = [(true, 0), (false, 0), (true, 0)];
+        let chosen = (0..4).map(|_| selection.choose(&conns, &mut next)).collect::<Vec<_>>();
+        assert_eq!(chosen, vec![2, 0, 2, 0]);
+    }
+
+    #[test]
+    fn test_least_in_flight() {
+        let selection = ConnectionSelection::LeastInFlight;
+        let mut next = 0;
+        assert_eq!(selection.choose(&[(true, 5), (true, 2), (true, 9)], &mut next), 1);
+        assert_eq!(selection.choose(&[(true, 5), (false, 2), (true, 9)], &mut next), 0);
+
+        // Ties are broken by taking turns, so idle connections all get used.
+        let idle = [(true, 0), (true, 0), (true, 0)];
+        let chosen = (0..3).map(|_| selection.choose(&idle, &mut next)).collect::<Vec<_>>();
+        assert_eq!(chosen, vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn test_nothing_available() {
+        // With every connection down, we carry on as if they were all up.
+        let mut next = 1;
+        let conns = [(false, 3), (false, 1)];
+        assert_eq!(ConnectionSelection::RoundRobin.choose(&conns, &mut next), 1);
+        assert_eq!(ConnectionSelection::LeastInFlight.choose(&conns, &mut next), 1);
+    }
+}
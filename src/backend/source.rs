@@ -0,0 +1,147 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use errors::CreationError;
+use futures::future::{err, Either, FutureResult};
+use log::Level;
+use net2::TcpBuilder;
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr, TcpStream as StdTcpStream},
+    str::FromStr,
+};
+use tokio::{
+    net::tcp::{ConnectFuture, TcpStream},
+    reactor::Handle,
+};
+use util::LogLimiter;
+
+lazy_static! {
+    static ref BIND_ERRORS: LogLimiter = LogLimiter::new("backend");
+}
+
+/// Gets the local address that connections to the given backend should be made from, if the pool
+/// has one configured.
+///
+/// The address is checked by actually binding to it, so that an address that doesn't exist on
+/// this host is caught when the configuration is loaded, rather than on every connection attempt.
+pub fn source_address_from_options(
+    options: &HashMap<String, String>, backend: &SocketAddr,
+) -> Result<Option<IpAddr>, CreationError> {
+    let source = match options.get("backend_bind_address") {
+        Some(raw) => {
+            IpAddr::from_str(raw.as_str())
+                .map_err(|_| CreationError::InvalidParameter("options.backend_bind_address".to_string()))?
+        },
+        None => return Ok(None),
+    };
+
+    if source.is_ipv4() != backend.is_ipv4() {
+        return Err(CreationError::InvalidResource(format!(
+            "backend_bind_address {} can't be used to reach backend {}",
+            source, backend
+        )));
+    }
+
+    bind_socket(source).map_err(|e| {
+        CreationError::InvalidResource(format!("backend_bind_address {} can't be bound: {}", source, e))
+    })?;
+
+    Ok(Some(source))
+}
+
+/// Connects to the given backend, from the given local address if one is specified.
+pub fn connect(addr: &SocketAddr, source: Option<IpAddr>) -> Either<ConnectFuture, FutureResult<TcpStream, io::Error>> {
+    let source = match source {
+        Some(source) => source,
+        None => return Either::A(TcpStream::connect(addr)),
+    };
+
+    match bind_socket(source) {
+        Ok(socket) => Either::A(TcpStream::connect_std(socket, addr, &Handle::default())),
+        Err(e) => {
+            BIND_ERRORS.log(
+                Level::Error,
+                "bind",
+                format_args!("[backend] failed to bind connection to {} on local address {}: {}", addr, source, e),
+            );
+            Either::B(err(e))
+        },
+    }
+}
+
+/// Creates a socket bound to the given local address, on a port picked by the OS.
+fn bind_socket(source: IpAddr) -> io::Result<StdTcpStream> { bind_builder(source)?.to_tcp_stream() }
+
+fn bind_builder(source: IpAddr) -> io::Result<TcpBuilder> {
+    let builder = match source {
+        IpAddr::V4(_) => TcpBuilder::new_v4()?,
+        IpAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.bind(SocketAddr::new(source, 0))?;
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn get_options(source: &str) -> HashMap<String, String> {
+        let mut options = HashMap::new();
+        options.insert("backend_bind_address".to_owned(), source.to_owned());
+        options
+    }
+
+    #[test]
+    fn test_source_address_from_options() {
+        let v4_backend = "127.0.0.1:6379".parse().unwrap();
+        let v6_backend = "[::1]:6379".parse().unwrap();
+
+        assert_eq!(source_address_from_options(&HashMap::new(), &v4_backend).unwrap(), None);
+        assert_eq!(
+            source_address_from_options(&get_options("127.0.0.1"), &v4_backend).unwrap(),
+            Some("127.0.0.1".parse().unwrap())
+        );
+
+        // Garbage, mismatched address families, and addresses that aren't ours are all refused.
+        assert!(source_address_from_options(&get_options("localhost"), &v4_backend).is_err());
+        assert!(source_address_from_options(&get_options("127.0.0.1"), &v6_backend).is_err());
+        assert!(source_address_from_options(&get_options("::1"), &v4_backend).is_err());
+        assert!(source_address_from_options(&get_options("192.0.2.1"), &v4_backend).is_err());
+    }
+
+    #[test]
+    fn test_bind_builder() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Not every platform routes all of 127.0.0.0/8 to the loopback interface.
+        let source = "127.0.0.2".parse().unwrap();
+        let builder = match bind_builder(source) {
+            Ok(builder) => builder,
+            Err(_) => return,
+        };
+        let _stream = builder.connect(addr).unwrap();
+
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip(), source);
+    }
+}
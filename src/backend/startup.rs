@@ -0,0 +1,203 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::{BackendAddress, PoolOptions};
+use errors::CreationError;
+use futures::{
+    future::{loop_fn, ok, Either, Loop},
+    prelude::*,
+    stream::futures_unordered::FuturesUnordered,
+};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpStream,
+    timer::{Delay, Timeout},
+};
+
+// How long to wait between attempts to connect to a backend that isn't up yet.
+const RECONNECT_INTERVAL_MS: u64 = 100;
+
+/// How many of a pool's backends must be reachable before its listener starts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StartupRequirement {
+    timeout: Duration,
+    min_available: Option<usize>,
+}
+
+impl StartupRequirement {
+    /// Extracts the startup requirement from the given pool options, if the pool has one.
+    pub fn from_options(options: &PoolOptions) -> Result<Option<StartupRequirement>, CreationError> {
+        if !options.require_backends_at_startup {
+            return Ok(None);
+        }
+
+        if options.min_available_backends == Some(0) {
+            return Err(CreationError::InvalidParameter("options.min_available_backends".to_string()));
+        }
+
+        Ok(Some(StartupRequirement {
+            timeout: Duration::from_millis(options.startup_connect_timeout_ms),
+            min_available: options.min_available_backends,
+        }))
+    }
+
+    /// Waits for enough of the given backends to accept a connection.
+    ///
+    /// A listener shouldn't even bind its socket until its backends are ready for it, so it isn't
+    /// built until this resolves.  Backends that aren't up yet are retried until the timeout runs
+    /// out, all without blocking, so that nothing else on the runtime has to wait along with us.
+    /// If not enough of them came up by then, the ones that didn't are handed back.
+    pub fn wait(&self, backends: &[BackendAddress]) -> impl Future<Item = (), Error = Vec<BackendAddress>> + Send {
+        let needed = self.min_available.unwrap_or_else(|| backends.len()).min(backends.len());
+        let deadline = Instant::now() + self.timeout;
+
+        let attempts = backends
+            .iter()
+            .enumerate()
+            .map(|(idx, backend)| connect_by(idx, backend.address, deadline))
+            .collect::<FuturesUnordered<_>>();
+        let backends = backends.to_vec();
+
+        // Once enough backends are up, the rest are left to come up on their own time.
+        attempts
+            .filter_map(|(idx, connected)| if connected { Some(idx) } else { None })
+            .take(needed as u64)
+            .collect()
+            .then(move |result| {
+                let available: Vec<usize> = result.unwrap_or_default();
+                if available.len() >= needed {
+                    return Ok(());
+                }
+
+                Err(backends
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(idx, _)| !available.contains(idx))
+                    .map(|(_, backend)| backend)
+                    .collect())
+            })
+    }
+}
+
+/// Tries to connect to the given backend until it accepts or the deadline passes, resolving to
+/// whether or not it accepted.
+fn connect_by(idx: usize, address: SocketAddr, deadline: Instant) -> impl Future<Item = (usize, bool), Error = ()> {
+    loop_fn((), move |_| {
+        let now = Instant::now();
+        if now >= deadline {
+            return Either::A(ok(Loop::Break((idx, false))));
+        }
+
+        let attempt = Timeout::new(TcpStream::connect(&address), deadline - now).then(move |result| {
+            match result {
+                Ok(_) => Either::A(ok(Loop::Break((idx, true)))),
+                Err(_) => {
+                    let retry_at = (Instant::now() + Duration::from_millis(RECONNECT_INTERVAL_MS)).min(deadline);
+                    Either::B(Delay::new(retry_at).then(|_| Ok(Loop::Continue(()))))
+                },
+            }
+        });
+        Either::B(attempt)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::weights::DEFAULT_WEIGHT;
+    use std::net::TcpListener;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn get_options(pairs: &[(&str, &str)]) -> PoolOptions {
+        PoolOptions::from_map(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()).unwrap()
+    }
+
+    fn get_backend(address: SocketAddr) -> BackendAddress {
+        BackendAddress {
+            address,
+            host: None,
+            identifier: address.to_string(),
+            weight: DEFAULT_WEIGHT,
+        }
+    }
+
+    fn get_closed_address() -> SocketAddr {
+        // Nothing will be listening once this goes away.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn test_from_options() {
+        assert_eq!(StartupRequirement::from_options(&get_options(&[])).unwrap(), None);
+
+        let requirement = StartupRequirement::from_options(&get_options(&[
+            ("require_backends_at_startup", "true"),
+            ("startup_connect_timeout_ms", "250"),
+            ("min_available_backends", "2"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            requirement,
+            Some(StartupRequirement {
+                timeout: Duration::from_millis(250),
+                min_available: Some(2),
+            })
+        );
+
+        assert!(StartupRequirement::from_options(&get_options(&[
+            ("require_backends_at_startup", "true"),
+            ("min_available_backends", "0"),
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut runtime = Runtime::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let up = get_backend(listener.local_addr().unwrap());
+        let down = get_backend(get_closed_address());
+
+        let all = StartupRequirement {
+            timeout: Duration::from_millis(300),
+            min_available: None,
+        };
+        assert!(runtime.block_on(all.wait(&[up.clone()])).is_ok());
+
+        let started = Instant::now();
+        let result = runtime.block_on(all.wait(&[up.clone(), down.clone()]));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        match result {
+            Err(unreachable) => assert_eq!(unreachable[0].address, down.address),
+            Ok(()) => panic!("should not have reached every backend"),
+        }
+
+        let one = StartupRequirement {
+            timeout: Duration::from_millis(300),
+            min_available: Some(1),
+        };
+        let started = Instant::now();
+        assert!(runtime.block_on(one.wait(&[up, down])).is_ok());
+        assert!(started.elapsed() < Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::{
+    placement::Placement, processor::Processor, reconnect::ReconnectConfiguration, resolver::ResolvedAddresses,
+    source::source_address_from_options,
+};
+use bytes::BytesMut;
+use common::Message;
+use conf::PoolConfiguration;
+use errors::CreationError;
+use futures::prelude::*;
+use metrics::MetricSink;
+use protocol::errors::ProtocolError;
+use std::{
+    io::{self, ErrorKind},
+    net::IpAddr,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::tcp::TcpStream,
+};
+use util::{FdGuard, FdTracker, ProcessFuture};
+
+pub const SUBSCRIPTIONS_UNAVAILABLE: &str = "pub/sub is not supported by this listener";
+const CHANNELS_SPLIT: &str = "channels belong to different backends, subscribe to them on separate connections";
+const PATTERNS_SPLIT: &str = "pattern subscriptions are not supported on pools with more than one backend";
+
+/// What a client is asking for with a command that changes what it's subscribed to.
+pub enum SubscriptionRequest<'a> {
+    /// Subscribing to the given channels.
+    Channels(Vec<&'a [u8]>),
+
+    /// Subscribing to the given channel patterns.
+    Patterns(Vec<&'a [u8]>),
+
+    /// Unsubscribing from channels or patterns, which can be done from any backend.
+    Unsubscribe,
+}
+
+/// Where the subscriptions of a listener's clients are relayed to.
+///
+/// Publishing to a channel is routed like any other command keyed by the channel, so subscribing
+/// to it is relayed to the backend that the same key would be placed on, as if every backend was
+/// healthy.  Every subscription gets a connection of its own, since a connection that's subscribed
+/// to anything can't be used for anything else.
+#[derive(Clone)]
+pub struct Subscriptions<P>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+{
+    processor: P,
+    addresses: Arc<ResolvedAddresses>,
+    sources: Arc<Vec<Option<IpAddr>>>,
+    placement: Arc<Placement>,
+    reconnect: ReconnectConfiguration,
+    fds: Arc<FdTracker>,
+    sink: MetricSink,
+}
+
+impl<P> Subscriptions<P>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+{
+    /// Creates the relay for subscriptions to the backends of the given pool, which are found at the
+    /// given addresses.
+    ///
+    /// Returns `None` if subscriptions can't be relayed to the pool, because it doesn't place
+    /// channels the same way every time, or because its backends see the channels differently than
+    /// its clients do.
+    pub fn from_config(
+        processor: P, config: &PoolConfiguration, addresses: Arc<ResolvedAddresses>, fds: Arc<FdTracker>,
+        sink: MetricSink,
+    ) -> Result<Option<Subscriptions<P>>, CreationError> {
+        if config.addresses.is_empty() {
+            return Ok(None);
+        }
+
+        let placement = Placement::with_configured_backends(config)?;
+        let distribution = config
+            .migration
+            .as_ref()
+            .map_or(&config.options.distribution, |migration| &migration.to_distribution);
+        let random = distribution.eq_ignore_ascii_case("random") && config.addresses.len() > 1;
+        if random || placement.transforms.rewrites_keys() {
+            return Ok(None);
+        }
+
+        let sources = config
+            .addresses
+            .iter()
+            .map(|backend| source_address_from_options(&config.options.other, &backend.address))
+            .collect::<Result<Vec<_>, CreationError>>()?;
+        let reconnect = ReconnectConfiguration::from_options(&config.options)?;
+
+        Ok(Some(Subscriptions {
+            processor,
+            addresses,
+            sources: Arc::new(sources),
+            placement: Arc::new(placement),
+            reconnect,
+            fds,
+            sink,
+        }))
+    }
+
+    /// Chooses the backend the given request has to be relayed to, returning its configured
+    /// position, or the error to answer it with if it can't be relayed to just one.
+    fn choose(&self, request: &SubscriptionRequest) -> Result<usize, &'static str> {
+        match request {
+            SubscriptionRequest::Channels(channels) => {
+                let mut placed = channels.iter().map(|channel| self.placement.choose(channel));
+                let idx = placed.next().unwrap_or(0);
+                if placed.all(|other| other == idx) {
+                    Ok(idx)
+                } else {
+                    Err(CHANNELS_SPLIT)
+                }
+            },
+            SubscriptionRequest::Patterns(_) if self.sources.len() > 1 => Err(PATTERNS_SPLIT),
+            _ => Ok(0),
+        }
+    }
+}
+
+/// A client's subscriptions, relayed over a connection of their own to a single backend.
+///
+/// Everything the client sends while subscribed is relayed as-is, and everything the backend sends
+/// back, whether it answers the client or was published to one of its channels, is passed along to
+/// the client as-is.  The connection is closed when this is dropped.
+pub struct Subscription<P>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+{
+    subscriptions: Subscriptions<P>,
+    idx: usize,
+    connecting: Option<ProcessFuture>,
+    conn: Option<TcpStream>,
+    rbuf: BytesMut,
+    wbuf: BytesMut,
+    subscribed: Option<usize>,
+    _fd: FdGuard,
+}
+
+impl<P> Subscription<P>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+{
+    /// Starts relaying subscriptions with the given request, which must be one that changes what the
+    /// client is subscribed to.
+    ///
+    /// Returns the error to answer the request with if it can't be relayed.
+    pub fn new(subscriptions: Subscriptions<P>, msg: P::Message) -> Result<Subscription<P>, &'static str> {
+        let idx = match subscriptions.processor.get_subscription(&msg) {
+            Some(request) => subscriptions.choose(&request),
+            None => Err(SUBSCRIPTIONS_UNAVAILABLE),
+        };
+        let idx = idx.map_err(|e| {
+            subscriptions.sink.increment("subscriptions_rejected");
+            e
+        })?;
+
+        let (address, source) = (subscriptions.addresses.get(idx), subscriptions.sources[idx]);
+        debug!("[backend] relaying subscriptions to {}", address);
+        subscriptions.sink.increment("subscriptions");
+
+        let fd = FdTracker::acquire(&subscriptions.fds);
+        let connecting = subscriptions.processor.preconnect(&address, source, false);
+        let connecting = subscriptions.reconnect.limit_connect(connecting);
+
+        Ok(Subscription {
+            subscriptions,
+            idx,
+            connecting: Some(connecting),
+            conn: None,
+            rbuf: BytesMut::new(),
+            wbuf: msg.into_buf(),
+            subscribed: None,
+            _fd: fd,
+        })
+    }
+
+    /// Relays the given request to the backend.
+    ///
+    /// Returns the error to answer the request with if it subscribes to something that lives on a
+    /// different backend.
+    pub fn send(&mut self, msg: P::Message) -> Result<(), &'static str> {
+        if let Some(request) = self.subscriptions.processor.get_subscription(&msg) {
+            let idx = self.subscriptions.choose(&request);
+            if idx != Ok(self.idx) {
+                self.subscriptions.sink.increment("subscriptions_rejected");
+                return Err(idx.err().unwrap_or(CHANNELS_SPLIT));
+            }
+
+            // We don't know what the client will be subscribed to until the backend tells us.
+            self.subscribed = None;
+        }
+
+        self.wbuf.unsplit(msg.into_buf());
+        Ok(())
+    }
+
+    /// Whether or not the client has unsubscribed from everything, and isn't waiting on the backend
+    /// for anything else.
+    pub fn is_idle(&self) -> bool { self.subscribed == Some(0) && self.wbuf.is_empty() && self.rbuf.is_empty() }
+
+    /// Whether or not the backend has told us the client is subscribed to something, with no changes
+    /// to that on the way.
+    pub fn is_subscribed(&self) -> bool { self.subscribed.map_or(false, |subscribed| subscribed > 0) }
+
+    /// Takes the next whole message the backend sent off of our read buffer, if there is one.
+    fn read_buffered(&mut self) -> Result<Option<BytesMut>, ProtocolError> {
+        let pushed = self.subscriptions.processor.read_pushed_message(&mut self.rbuf)?;
+        Ok(pushed.map(|(buf, subscribed)| {
+            if subscribed.is_some() {
+                self.subscribed = subscribed;
+            }
+            buf
+        }))
+    }
+}
+
+impl<P> Stream for Subscription<P>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+{
+    type Error = ProtocolError;
+    type Item = BytesMut;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(mut connecting) = self.connecting.take() {
+            match connecting.poll()? {
+                Async::Ready(conn) => self.conn = Some(conn),
+                Async::NotReady => {
+                    self.connecting = Some(connecting);
+                    return Ok(Async::NotReady);
+                },
+            }
+        }
+
+        // Whatever the client sent goes out as soon as the connection can take it.
+        {
+            let conn = self.conn.as_mut().expect("subscription polled without a connection");
+            while !self.wbuf.is_empty() {
+                match conn.poll_write(&self.wbuf)? {
+                    Async::Ready(0) => return Err(io::Error::from(ErrorKind::WriteZero).into()),
+                    Async::Ready(n) => {
+                        let _ = self.wbuf.split_to(n);
+                    },
+                    Async::NotReady => break,
+                }
+            }
+        }
+
+        loop {
+            if let Some(buf) = self.read_buffered()? {
+                return Ok(Async::Ready(Some(buf)));
+            }
+
+            self.rbuf.reserve(8192);
+            let n = try_ready!(self.conn.as_mut().unwrap().read_buf(&mut self.rbuf));
+            if n == 0 {
+                return Err(ProtocolError::BackendClosedPrematurely);
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use common::{AssignedResponses, EnqueuedRequests, MessageResponse};
+use futures::{
+    future::{ok, FutureResult},
+    prelude::*,
+};
+use protocol::redis::RedisMessage;
+use std::sync::{Arc, Mutex};
+use tower_service::Service;
+
+/// A pool that answers everything, and remembers every key it was sent.
+///
+/// A pool that isn't ready never is, and anything sent to it anyway is still answered.
+#[derive(Clone)]
+pub struct RecordingPool {
+    seen: Arc<Mutex<Vec<Vec<u8>>>>,
+    ready: bool,
+}
+
+impl RecordingPool {
+    pub fn new(ready: bool) -> RecordingPool {
+        RecordingPool {
+            seen: Arc::new(Mutex::new(Vec::new())),
+            ready,
+        }
+    }
+
+    /// Gets every key this pool has been sent, in the order it was sent them.
+    pub fn seen(&self) -> Vec<Vec<u8>> { self.seen.lock().unwrap().clone() }
+}
+
+impl Service<EnqueuedRequests<RedisMessage>> for RecordingPool {
+    type Error = ();
+    type Future = FutureResult<Self::Response, Self::Error>;
+    type Response = AssignedResponses<RedisMessage>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        if self.ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn call(&mut self, req: EnqueuedRequests<RedisMessage>) -> Self::Future {
+        let mut seen = self.seen.lock().unwrap();
+        let responses = req
+            .iter()
+            .map(|msg| {
+                seen.push(msg.key().to_vec());
+                (msg.id(), MessageResponse::Complete(RedisMessage::OK))
+            })
+            .collect();
+        ok(responses)
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::KeyTransform;
+use errors::CreationError;
+use std::{borrow::Cow, collections::HashMap};
+
+fn get_prefix(params: &HashMap<String, String>) -> Result<Vec<u8>, CreationError> {
+    match params.get("prefix") {
+        Some(prefix) if !prefix.is_empty() => Ok(prefix.as_bytes().to_vec()),
+        _ => Err(CreationError::InvalidParameter("key_transforms.prefix".to_string())),
+    }
+}
+
+fn add_prefix<'a>(prefix: &[u8], key: &'a [u8]) -> Cow<'a, [u8]> {
+    let mut prefixed = Vec::with_capacity(prefix.len() + key.len());
+    prefixed.extend_from_slice(prefix);
+    prefixed.extend_from_slice(key);
+    Cow::Owned(prefixed)
+}
+
+fn remove_prefix<'a>(prefix: &[u8], key: &'a [u8]) -> Cow<'a, [u8]> {
+    if key.starts_with(prefix) {
+        Cow::Borrowed(&key[prefix.len()..])
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+/// Puts every key in its own namespace on the backends, such as for pools shared between
+/// applications.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prefix {
+    prefix: Vec<u8>,
+}
+
+impl Prefix {
+    pub fn new(prefix: &[u8]) -> Prefix { Prefix { prefix: prefix.to_vec() } }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Prefix, CreationError> {
+        get_prefix(params).map(|prefix| Prefix { prefix })
+    }
+}
+
+impl KeyTransform for Prefix {
+    fn forward<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> { add_prefix(&self.prefix, key) }
+}
+
+/// Takes a namespace off of the keys that clients send, such as when clients still use a prefix
+/// that the backends no longer do.
+///
+/// Keys without the prefix are sent as-is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Strip {
+    prefix: Vec<u8>,
+}
+
+impl Strip {
+    pub fn new(prefix: &[u8]) -> Strip { Strip { prefix: prefix.to_vec() } }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Strip, CreationError> {
+        get_prefix(params).map(|prefix| Strip { prefix })
+    }
+}
+
+impl KeyTransform for Strip {
+    fn forward<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> { remove_prefix(&self.prefix, key) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix() {
+        let prefix = Prefix::new(b"app:");
+        assert_eq!(&prefix.forward(b"user:1")[..], b"app:user:1");
+        assert_eq!(&prefix.forward(b"app:user:1")[..], b"app:app:user:1");
+    }
+
+    #[test]
+    fn test_strip() {
+        let strip = Strip::new(b"legacy:");
+        assert_eq!(&strip.forward(b"legacy:user:1")[..], b"user:1");
+        assert_eq!(&strip.forward(b"user:1")[..], b"user:1");
+    }
+
+    #[test]
+    fn test_from_params() {
+        let mut params = HashMap::new();
+        assert!(Prefix::from_params(&params).is_err());
+        assert!(Strip::from_params(&params).is_err());
+
+        params.insert("prefix".to_owned(), String::new());
+        assert!(Prefix::from_params(&params).is_err());
+
+        params.insert("prefix".to_owned(), "app:".to_owned());
+        assert_eq!(Prefix::from_params(&params).unwrap(), Prefix::new(b"app:"));
+        assert_eq!(Strip::from_params(&params).unwrap(), Strip::new(b"app:"));
+    }
+}
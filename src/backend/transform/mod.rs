@@ -0,0 +1,331 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+mod affix;
+mod rewrite;
+mod tag;
+pub use self::{
+    affix::{Prefix, Strip},
+    rewrite::Rewrite,
+    tag::HashTag,
+};
+
+use conf::PoolConfiguration;
+use errors::CreationError;
+use std::{borrow::Cow, collections::HashMap};
+
+/// A change to the keys that a pool is sent.
+///
+/// Transforms only ever go one way.  Keys in responses are given back to clients as they sent
+/// them by way of `OriginalKeys`, so a transform never has to be undone.
+pub trait KeyTransform {
+    /// Transforms a key on its way to a backend.
+    fn forward<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]>;
+
+    /// Whether this transform only changes what's hashed, leaving the key that backends see alone.
+    fn is_hashing_only(&self) -> bool { false }
+}
+
+type KeyTransformFutureSafe = Box<KeyTransform + Send + Sync>;
+type KeyTransformBuilder = fn(&HashMap<String, String>) -> Result<KeyTransformFutureSafe, CreationError>;
+
+// Every transform we know how to build, by the type it's configured with.
+const TRANSFORMS: &[(&str, KeyTransformBuilder)] = &[
+    ("prefix", |params| Ok(Box::new(Prefix::from_params(params)?))),
+    ("strip", |params| Ok(Box::new(Strip::from_params(params)?))),
+    ("hash_tag", |params| Ok(Box::new(HashTag::from_params(params)?))),
+    ("rewrite", |params| Ok(Box::new(Rewrite::from_params(params)?))),
+];
+
+/// Gets the types of all of the key transforms that can be configured.
+pub fn transform_names() -> Vec<&'static str> { TRANSFORMS.iter().map(|(name, _)| *name).collect() }
+
+/// The transforms a pool applies to its keys, in the order they're applied.
+///
+/// Keys go through the chain at two points: to get the key that's hashed to pick a backend, which
+/// every transform applies to, and to get the key that's sent to the backend, which skips
+/// transforms that only change what's hashed.
+#[derive(Default)]
+pub struct KeyTransforms {
+    transforms: Vec<KeyTransformFutureSafe>,
+}
+
+impl KeyTransforms {
+    pub fn new(transforms: Vec<KeyTransformFutureSafe>) -> KeyTransforms { KeyTransforms { transforms } }
+
+    /// Builds the chain of transforms configured for the given pool.
+    ///
+    /// The pool's `hash_tag` option, if set, is the same as a `hash_tag` transform at the end of the
+    /// chain.
+    pub fn from_config(
+        config: &PoolConfiguration, options: &HashMap<String, String>,
+    ) -> Result<KeyTransforms, CreationError> {
+        let entries = config.key_transforms.as_ref().map(|entries| &entries[..]).unwrap_or(&[]);
+        let mut transforms = KeyTransforms::from_entries("key_transforms", entries)?;
+        if let Some(tag) = HashTag::from_options(options)? {
+            transforms.transforms.push(Box::new(tag));
+        }
+
+        Ok(transforms)
+    }
+
+    /// Builds a chain of transforms from the given entries, as configured under `field`.
+    pub fn from_entries(field: &str, entries: &[HashMap<String, String>]) -> Result<KeyTransforms, CreationError> {
+        let mut transforms = Vec::new();
+        for (idx, params) in entries.iter().enumerate() {
+            let transform_type = params
+                .get("type")
+                .ok_or_else(|| CreationError::InvalidParameter(format!("{}[{}].type", field, idx)))?;
+            let build = TRANSFORMS
+                .iter()
+                .find(|(name, _)| *name == transform_type.as_str())
+                .map(|(_, build)| build)
+                .ok_or_else(|| CreationError::InvalidResource(format!("unknown key transform {}", transform_type)))?;
+            transforms.push(build(params)?);
+        }
+
+        Ok(KeyTransforms::new(transforms))
+    }
+
+    /// Whether or not any of these transforms change the keys that backends see.
+    pub fn rewrites_keys(&self) -> bool { self.transforms.iter().any(|transform| !transform.is_hashing_only()) }
+
+    /// Gets the key to hash, in order to pick a backend, for the given key from a client.
+    pub fn hash_key<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> { apply(key, self.transforms.iter()) }
+
+    /// Gets the key to send to a backend for the given key from a client.
+    pub fn forward<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        apply(key, self.transforms.iter().filter(|transform| !transform.is_hashing_only()))
+    }
+}
+
+fn apply<'a, 'b, I>(key: &'a [u8], transforms: I) -> Cow<'a, [u8]>
+where
+    I: Iterator<Item = &'b KeyTransformFutureSafe>,
+{
+    let mut key = Cow::Borrowed(key);
+    for transform in transforms {
+        key = match key {
+            Cow::Borrowed(key) => transform.forward(key),
+            Cow::Owned(key) => Cow::Owned(transform.forward(&key).into_owned()),
+        };
+    }
+    key
+}
+
+/// The keys a client sent in a request, by the keys its backend was sent in their place.
+///
+/// Keys in a response are put back the way the client sent them by looking them up here, rather
+/// than by undoing the transforms: a key that a transform left alone has nothing to undo, and two
+/// keys can be transformed into the same one.  Keys that weren't changed on their way out aren't
+/// tracked, and come back as they are.
+#[derive(Default)]
+pub struct OriginalKeys {
+    keys: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl OriginalKeys {
+    /// Gets the key to send to a backend for the given key from a client, with the given
+    /// transforms, remembering the client's key if it changed.
+    ///
+    /// If two changed keys are sent as the same key, the first of them is what's given back.
+    pub fn forward<'a>(&mut self, transforms: &KeyTransforms, key: &'a [u8]) -> Cow<'a, [u8]> {
+        let forwarded = transforms.forward(key);
+        if &forwarded[..] != key && !self.keys.contains_key(&forwarded[..]) {
+            self.keys.insert(forwarded.to_vec(), key.to_vec());
+        }
+        forwarded
+    }
+
+    /// Gets the key the client sent for the given key from a backend.
+    pub fn restore<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.keys.get(key) {
+            Some(original) => Cow::Owned(original.clone()),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Whether or not any keys were changed on their way out.
+    pub fn is_empty(&self) -> bool { self.keys.is_empty() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Case {
+        transforms: Vec<KeyTransformFutureSafe>,
+        key: &'static [u8],
+        hashed: &'static [u8],
+        forwarded: &'static [u8],
+    }
+
+    fn get_cases() -> Vec<Case> {
+        let tag = || Box::new(HashTag::new(b'{', b'}')) as KeyTransformFutureSafe;
+        let prefix = |prefix: &[u8]| Box::new(Prefix::new(prefix)) as KeyTransformFutureSafe;
+        let strip = |prefix: &[u8]| Box::new(Strip::new(prefix)) as KeyTransformFutureSafe;
+        let rewrite = |from, to| Box::new(Rewrite::new(from, to).unwrap()) as KeyTransformFutureSafe;
+
+        vec![
+            Case {
+                transforms: vec![],
+                key: b"user:1",
+                hashed: b"user:1",
+                forwarded: b"user:1",
+            },
+            Case {
+                transforms: vec![prefix(b"app:")],
+                key: b"user:1",
+                hashed: b"app:user:1",
+                forwarded: b"app:user:1",
+            },
+            Case {
+                transforms: vec![tag()],
+                key: b"user:{1}:a",
+                hashed: b"1",
+                forwarded: b"user:{1}:a",
+            },
+            Case {
+                transforms: vec![prefix(b"app:"), tag()],
+                key: b"{u1}:a",
+                hashed: b"u1",
+                forwarded: b"app:{u1}:a",
+            },
+            Case {
+                transforms: vec![tag(), prefix(b"app:")],
+                key: b"{u1}:a",
+                hashed: b"app:u1",
+                forwarded: b"app:{u1}:a",
+            },
+            Case {
+                transforms: vec![strip(b"legacy:"), prefix(b"app:")],
+                key: b"legacy:user:1",
+                hashed: b"app:user:1",
+                forwarded: b"app:user:1",
+            },
+            Case {
+                transforms: vec![rewrite("user:*", "u:*"), prefix(b"app:")],
+                key: b"user:1",
+                hashed: b"app:u:1",
+                forwarded: b"app:u:1",
+            },
+            Case {
+                transforms: vec![prefix(b"app:"), rewrite("app:user:*", "u:*")],
+                key: b"user:1",
+                hashed: b"u:1",
+                forwarded: b"u:1",
+            },
+            Case {
+                transforms: vec![prefix(b"a:"), prefix(b"b:")],
+                key: b"k",
+                hashed: b"b:a:k",
+                forwarded: b"b:a:k",
+            },
+            Case {
+                transforms: vec![rewrite("user:*", "u:*"), tag()],
+                key: b"session:{1}",
+                hashed: b"1",
+                forwarded: b"session:{1}",
+            },
+        ]
+    }
+
+    #[test]
+    fn test_chains() {
+        for (idx, case) in get_cases().into_iter().enumerate() {
+            let transforms = KeyTransforms::new(case.transforms);
+            let mut original = OriginalKeys::default();
+            let forwarded = original.forward(&transforms, case.key);
+            assert_eq!(&transforms.hash_key(case.key)[..], case.hashed, "case {}: hashed", idx);
+            assert_eq!(&forwarded[..], case.forwarded, "case {}: forwarded", idx);
+            assert_eq!(&original.restore(&forwarded)[..], case.key, "case {}: restored", idx);
+        }
+    }
+
+    #[test]
+    fn test_restore_unmatched_keys() {
+        let strip = Box::new(Strip::new(b"legacy:")) as KeyTransformFutureSafe;
+        let rewrite = Box::new(Rewrite::new("user:*", "u:*").unwrap()) as KeyTransformFutureSafe;
+        let transforms = KeyTransforms::new(vec![strip, rewrite]);
+
+        // Keys the transforms leave alone come back as they were sent, even when they look like
+        // something a transform would have produced.
+        let mut original = OriginalKeys::default();
+        for key in &[&b"u:1"[..], b"session:1", b"legacy"] {
+            assert_eq!(&original.forward(&transforms, key)[..], *key);
+        }
+        assert!(original.is_empty());
+        assert_eq!(&original.restore(b"u:1")[..], b"u:1");
+        assert_eq!(&original.restore(b"session:1")[..], b"session:1");
+
+        // Keys that were changed come back as the client sent them.
+        let mut original = OriginalKeys::default();
+        assert_eq!(&original.forward(&transforms, b"legacy:user:1")[..], b"u:1");
+        assert_eq!(&original.forward(&transforms, b"legacy:session:2")[..], b"session:2");
+        assert_eq!(&original.restore(b"u:1")[..], b"legacy:user:1");
+        assert_eq!(&original.restore(b"session:2")[..], b"legacy:session:2");
+        assert_eq!(&original.restore(b"session:1")[..], b"session:1");
+        assert!(!original.is_empty());
+    }
+
+    fn get_params(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = PoolConfiguration {
+            key_transforms: Some(vec![
+                get_params(&[("type", "strip"), ("prefix", "legacy:")]),
+                get_params(&[("type", "prefix"), ("prefix", "app:")]),
+            ]),
+            ..Default::default()
+        };
+
+        // The hash tag option goes after everything else.
+        let options = get_params(&[("hash_tag", "{}")]);
+        let transforms = KeyTransforms::from_config(&config, &options).unwrap();
+        assert_eq!(&transforms.forward(b"legacy:{u1}:a")[..], b"app:{u1}:a");
+        assert_eq!(&transforms.hash_key(b"legacy:{u1}:a")[..], b"u1");
+        assert!(transforms.rewrites_keys());
+
+        let transforms = KeyTransforms::from_config(&Default::default(), &HashMap::new()).unwrap();
+        assert!(!transforms.rewrites_keys());
+        assert_eq!(&transforms.forward(b"key")[..], b"key");
+    }
+
+    #[test]
+    fn test_from_config_invalid() {
+        let get_config = |params| {
+            PoolConfiguration {
+                key_transforms: Some(vec![params]),
+                ..Default::default()
+            }
+        };
+
+        let options = HashMap::new();
+        assert!(KeyTransforms::from_config(&get_config(get_params(&[("prefix", "app:")])), &options).is_err());
+        assert!(KeyTransforms::from_config(&get_config(get_params(&[("type", "suffix")])), &options).is_err());
+        assert!(KeyTransforms::from_config(&get_config(get_params(&[("type", "prefix")])), &options).is_err());
+        assert!(transform_names().contains(&"rewrite"));
+    }
+}
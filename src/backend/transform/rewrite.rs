@@ -0,0 +1,171 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::KeyTransform;
+use errors::CreationError;
+use std::{borrow::Cow, collections::HashMap};
+
+const WILDCARD: u8 = b'*';
+
+/// A key pattern: a literal key, or a prefix and suffix around a single `*` that matches anything.
+#[derive(Clone, Debug, PartialEq)]
+struct Pattern {
+    prefix: Vec<u8>,
+    suffix: Option<Vec<u8>>,
+}
+
+impl Pattern {
+    fn parse(raw: &str) -> Option<Pattern> {
+        let raw = raw.as_bytes();
+        match raw.iter().filter(|b| **b == WILDCARD).count() {
+            0 if !raw.is_empty() => {
+                Some(Pattern {
+                    prefix: raw.to_vec(),
+                    suffix: None,
+                })
+            },
+            1 => {
+                let pos = raw.iter().position(|b| *b == WILDCARD).expect("wildcard was counted");
+                Some(Pattern {
+                    prefix: raw[..pos].to_vec(),
+                    suffix: Some(raw[pos + 1..].to_vec()),
+                })
+            },
+            _ => None,
+        }
+    }
+
+    /// Gets what the wildcard matched in the given key, if the key matches.
+    fn capture<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        let suffix = match self.suffix {
+            Some(ref suffix) => suffix,
+            None if key == &self.prefix[..] => return Some(&key[..0]),
+            None => return None,
+        };
+
+        let fits = key.len() >= self.prefix.len() + suffix.len();
+        if fits && key.starts_with(&self.prefix) && key.ends_with(suffix) {
+            Some(&key[self.prefix.len()..key.len() - suffix.len()])
+        } else {
+            None
+        }
+    }
+
+    /// Builds a key from this pattern, with the wildcard filled in by the given capture.
+    fn fill(&self, capture: &[u8]) -> Vec<u8> {
+        let mut key = self.prefix.clone();
+        if let Some(ref suffix) = self.suffix {
+            key.extend_from_slice(capture);
+            key.extend_from_slice(suffix);
+        }
+        key
+    }
+}
+
+/// Rewrites keys matching one pattern into another, such as `user:*` into `u:*:v2`.
+///
+/// Patterns are either a literal key, or have a single `*` that matches anything, including
+/// nothing.  Both patterns of a rule must have a wildcard, or neither may.  Keys that don't match
+/// are sent as-is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rewrite {
+    from: Pattern,
+    to: Pattern,
+}
+
+impl Rewrite {
+    pub fn new(from: &str, to: &str) -> Option<Rewrite> {
+        let from = Pattern::parse(from)?;
+        let to = Pattern::parse(to)?;
+        if from.suffix.is_some() != to.suffix.is_some() {
+            return None;
+        }
+
+        Some(Rewrite { from, to })
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Rewrite, CreationError> {
+        let from = params
+            .get("from")
+            .ok_or_else(|| CreationError::InvalidParameter("key_transforms.from".to_string()))?;
+        let to = params
+            .get("to")
+            .ok_or_else(|| CreationError::InvalidParameter("key_transforms.to".to_string()))?;
+
+        Rewrite::new(from, to).ok_or_else(|| {
+            CreationError::InvalidResource(format!(
+                "key rewrite from '{}' to '{}' must be literal keys or each have a single '*'",
+                from, to
+            ))
+        })
+    }
+}
+
+impl KeyTransform for Rewrite {
+    fn forward<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.from.capture(key) {
+            Some(capture) => Cow::Owned(self.to.fill(capture)),
+            None => Cow::Borrowed(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard() {
+        let rule = Rewrite::new("user:*", "u:*:v2").unwrap();
+        assert_eq!(&rule.forward(b"user:1000")[..], b"u:1000:v2");
+        assert_eq!(&rule.forward(b"user:")[..], b"u::v2");
+        assert_eq!(&rule.forward(b"session:1000")[..], b"session:1000");
+        assert_eq!(&rule.forward(b"u:1000:v2")[..], b"u:1000:v2");
+    }
+
+    #[test]
+    fn test_wildcard_needs_room() {
+        // The prefix and suffix can't overlap, even when the key starts and ends with them.
+        let rule = Rewrite::new("ab*ba", "*").unwrap();
+        assert_eq!(&rule.forward(b"aba")[..], b"aba");
+        assert_eq!(&rule.forward(b"abba")[..], b"");
+        assert_eq!(&rule.forward(b"abxba")[..], b"x");
+    }
+
+    #[test]
+    fn test_literal() {
+        let rule = Rewrite::new("config", "config:v2").unwrap();
+        assert_eq!(&rule.forward(b"config")[..], b"config:v2");
+        assert_eq!(&rule.forward(b"configs")[..], b"configs");
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(Rewrite::new("user:*", "u:").is_none());
+        assert!(Rewrite::new("user", "u:*").is_none());
+        assert!(Rewrite::new("*:*", "*").is_none());
+        assert!(Rewrite::new("", "u").is_none());
+
+        let mut params = HashMap::new();
+        params.insert("from".to_owned(), "user:*".to_owned());
+        assert!(Rewrite::from_params(&params).is_err());
+        params.insert("to".to_owned(), "u:*".to_owned());
+        assert!(Rewrite::from_params(&params).is_ok());
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::KeyTransform;
+use errors::CreationError;
+use std::{borrow::Cow, collections::HashMap};
+
+/// The part of a key that decides where it goes, marked out by a pair of delimiters.
+///
+/// Just as with Redis Cluster and twemproxy, only what's between the first opening delimiter and
+/// the first closing delimiter after it is hashed, so keys that share a tag, like `user:{1234}:a`
+/// and `user:{1234}:b`, always land on the same backend.  If there's no tag, or the tag is empty,
+/// the whole key is hashed.
+///
+/// As a transform, it only ever changes what's hashed: backends are sent the whole key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HashTag {
+    open: u8,
+    close: u8,
+}
+
+impl HashTag {
+    pub fn new(open: u8, close: u8) -> HashTag { HashTag { open, close } }
+
+    /// Gets the hash tag from the given pool options, if keys should be hashed by their tag.
+    ///
+    /// The tag is configured as its two delimiters, such as `"hash_tag": "{}"`.
+    pub fn from_options(options: &HashMap<String, String>) -> Result<Option<HashTag>, CreationError> {
+        match options.get("hash_tag") {
+            None => Ok(None),
+            Some(delimiters) => {
+                HashTag::from_delimiters(delimiters)
+                    .map(Some)
+                    .ok_or_else(|| CreationError::InvalidParameter("options.hash_tag".to_string()))
+            },
+        }
+    }
+
+    /// Gets the hash tag from a `hash_tag` transform, whose `delimiters` are configured the same way
+    /// as the `hash_tag` option.
+    pub fn from_params(params: &HashMap<String, String>) -> Result<HashTag, CreationError> {
+        params
+            .get("delimiters")
+            .and_then(|delimiters| HashTag::from_delimiters(delimiters))
+            .ok_or_else(|| CreationError::InvalidParameter("key_transforms.delimiters".to_string()))
+    }
+
+    fn from_delimiters(delimiters: &str) -> Option<HashTag> {
+        match delimiters.as_bytes() {
+            &[open, close] => Some(HashTag::new(open, close)),
+            _ => None,
+        }
+    }
+
+    /// Gets the part of the given key that should be hashed.
+    pub fn extract<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        let start = match key.iter().position(|b| *b == self.open) {
+            Some(pos) => pos + 1,
+            None => return key,
+        };
+
+        match key[start..].iter().position(|b| *b == self.close) {
+            Some(0) | None => key,
+            Some(len) => &key[start..start + len],
+        }
+    }
+}
+
+impl KeyTransform for HashTag {
+    fn forward<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> { Cow::Borrowed(self.extract(key)) }
+
+    fn is_hashing_only(&self) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_tag() -> HashTag { HashTag::new(b'{', b'}') }
+
+    #[test]
+    fn test_extract() {
+        let tag = get_tag();
+        assert_eq!(tag.extract(b"user:{1234}:profile"), b"1234");
+        assert_eq!(tag.extract(b"{1234}"), b"1234");
+        assert_eq!(tag.extract(b"user:1234:profile"), b"user:1234:profile");
+        assert_eq!(tag.extract(b""), b"");
+    }
+
+    #[test]
+    fn test_extract_empty_tag() {
+        let tag = get_tag();
+        assert_eq!(tag.extract(b"user:{}:profile"), b"user:{}:profile");
+
+        // Only the first tag counts, even if it's empty and a later one isn't.
+        assert_eq!(tag.extract(b"{}{1234}"), b"{}{1234}");
+    }
+
+    #[test]
+    fn test_extract_unclosed_tag() {
+        let tag = get_tag();
+        assert_eq!(tag.extract(b"user:{1234:profile"), b"user:{1234:profile");
+        assert_eq!(tag.extract(b"user:}1234{"), b"user:}1234{");
+    }
+
+    #[test]
+    fn test_extract_nested_tags() {
+        // Tags don't nest: the first closing delimiter ends the tag, wherever it is.
+        let tag = get_tag();
+        assert_eq!(tag.extract(b"{a{b}c}"), b"a{b");
+        assert_eq!(tag.extract(b"x{{b}}"), b"{b");
+        assert_eq!(tag.extract(b"{1}{2}"), b"1");
+    }
+
+    #[test]
+    fn test_custom_delimiters() {
+        let tag = HashTag::new(b'[', b']');
+        assert_eq!(tag.extract(b"user:[1234]:{profile}"), b"1234");
+        assert_eq!(tag.extract(b"user:{1234}"), b"user:{1234}");
+    }
+
+    #[test]
+    fn test_from_options() {
+        let mut options = HashMap::new();
+        assert_eq!(HashTag::from_options(&options).unwrap(), None);
+
+        options.insert("hash_tag".to_owned(), "{}".to_owned());
+        assert_eq!(HashTag::from_options(&options).unwrap(), Some(get_tag()));
+
+        options.insert("hash_tag".to_owned(), "{".to_owned());
+        assert!(HashTag::from_options(&options).is_err());
+
+        options.insert("hash_tag".to_owned(), "{{}}".to_owned());
+        assert!(HashTag::from_options(&options).is_err());
+    }
+
+    #[test]
+    fn test_from_params() {
+        let mut params = HashMap::new();
+        assert!(HashTag::from_params(&params).is_err());
+
+        params.insert("delimiters".to_owned(), "{}".to_owned());
+        assert_eq!(HashTag::from_params(&params).unwrap(), get_tag());
+
+        params.insert("delimiters".to_owned(), "{".to_owned());
+        assert!(HashTag::from_params(&params).is_err());
+    }
+
+    #[test]
+    fn test_only_hashing_is_transformed() {
+        let tag = get_tag();
+        assert!(tag.is_hashing_only());
+        assert_eq!(&tag.forward(b"user:{1234}:profile")[..], b"1234");
+    }
+}
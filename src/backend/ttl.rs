@@ -0,0 +1,72 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::PoolOptions;
+use errors::CreationError;
+
+/// How a pool handles writes that would create a key without a TTL.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TtlPolicy {
+    /// Reject the request, sending an error back to the client.
+    Reject,
+
+    /// Forward the request, followed by a request that sets the given TTL, in seconds, if the key
+    /// has no TTL of its own.
+    ApplyDefault(u64),
+
+    /// Forward the request untouched, and only count it.
+    Observe,
+}
+
+impl TtlPolicy {
+    /// Extracts the TTL policy from the given pool options, if TTLs are required.
+    pub fn from_options(options: &PoolOptions) -> Result<Option<TtlPolicy>, CreationError> {
+        if !options.require_ttl {
+            return Ok(None);
+        }
+
+        let policy = options
+            .other
+            .get("require_ttl_policy")
+            .map(|s| s.to_lowercase())
+            .unwrap_or_else(|| "reject".to_owned());
+        match policy.as_str() {
+            "reject" => Ok(Some(TtlPolicy::Reject)),
+            "observe" => Ok(Some(TtlPolicy::Observe)),
+            "apply_default" => {
+                let ttl = options
+                    .default_ttl_secs
+                    .filter(|ttl| *ttl > 0)
+                    .ok_or_else(|| {
+                        CreationError::InvalidParameter(
+                            "options.default_ttl_secs (required when require_ttl_policy is 'apply_default')"
+                                .to_string(),
+                        )
+                    })?;
+                Ok(Some(TtlPolicy::ApplyDefault(ttl)))
+            },
+            s => {
+                Err(CreationError::InvalidResource(format!(
+                    "unknown require_ttl_policy '{}'",
+                    s
+                )))
+            },
+        }
+    }
+}
@@ -0,0 +1,443 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::processor::Processor;
+use common::{AssignedResponses, EnqueuedRequest, EnqueuedRequests, Message, MessageResponse};
+use conf::PoolOptions;
+use errors::CreationError;
+use futures::{prelude::*, sync::oneshot};
+use metrics::MetricSink;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+use tower_service::Service;
+use util::clock::{duration_as_ms, elapsed};
+
+const WARMUP_TICK_MS: u64 = 100;
+const WARMUP_TICKS_PER_SEC: usize = 1000 / WARMUP_TICK_MS as usize;
+
+/// Warmup settings for a pool, parsed from its options.
+#[derive(Clone, Debug)]
+pub struct WarmupConfiguration {
+    /// Path to a newline-delimited list of keys to read.
+    pub keys_file: String,
+
+    /// Maximum number of keys to read per second.
+    pub rate: usize,
+}
+
+impl WarmupConfiguration {
+    /// Extracts the warmup configuration from the given pool options, if warmup is enabled.
+    pub fn from_options(options: &PoolOptions) -> Result<Option<WarmupConfiguration>, CreationError> {
+        let keys_file = match options.other.get("warmup_keys_file") {
+            Some(path) => path.clone(),
+            None => return Ok(None),
+        };
+
+        if options.warmup_rate == 0 {
+            return Err(CreationError::InvalidParameter("options.warmup_rate".to_string()));
+        }
+
+        Ok(Some(WarmupConfiguration {
+            keys_file,
+            rate: options.warmup_rate,
+        }))
+    }
+}
+
+/// Replays a list of keys against a pool as reads, at a bounded rate.
+///
+/// This exists purely to populate any caching layers behind the pool after a cold start, and to
+/// verify that the pool is actually functional.  The keys file is only read once the warmer is
+/// first polled, and on a thread of its own, so neither spawning a warmer nor a large keys file
+/// ever holds up the listener, or anything else on the reactor.  The warmer stops early if `close`
+/// resolves.
+pub struct Warmer<P, S, C>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>>,
+    C: Future,
+{
+    pool_name: String,
+    config: WarmupConfiguration,
+    processor: P,
+    service: S,
+    close: C,
+
+    loading: Option<oneshot::Receiver<io::Result<VecDeque<Vec<u8>>>>>,
+    keys: Option<VecDeque<Vec<u8>>>,
+    total: usize,
+    budget: TickBudget,
+    interval: Interval,
+    current: Option<(S::Future, usize)>,
+
+    warmed: usize,
+    errors: usize,
+    next_progress: usize,
+    started: Instant,
+
+    sink: MetricSink,
+}
+
+impl<P, S, C> Warmer<P, S, C>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>>,
+    C: Future,
+{
+    pub fn new(
+        pool_name: String, config: WarmupConfiguration, processor: P, service: S, close: C,
+        sink: MetricSink,
+    ) -> Warmer<P, S, C> {
+        let budget = TickBudget::new(config.rate);
+        let interval = Interval::new(Instant::now(), Duration::from_millis(WARMUP_TICK_MS));
+
+        Warmer {
+            pool_name,
+            config,
+            processor,
+            service,
+            close,
+            loading: None,
+            keys: None,
+            total: 0,
+            budget,
+            interval,
+            current: None,
+            warmed: 0,
+            errors: 0,
+            next_progress: 0,
+            started: Instant::now(),
+            sink,
+        }
+    }
+
+    fn poll_keys(&mut self) -> Async<io::Result<VecDeque<Vec<u8>>>> {
+        let path = &self.config.keys_file;
+        let loading = self.loading.get_or_insert_with(|| {
+            let (tx, rx) = oneshot::channel();
+            let path = path.clone();
+            thread::spawn(move || {
+                let _ = tx.send(load_keys(&path));
+            });
+            rx
+        });
+
+        match loading.poll() {
+            Ok(Async::Ready(result)) => Async::Ready(result),
+            Ok(Async::NotReady) => Async::NotReady,
+            Err(_) => Async::Ready(Err(io::Error::new(io::ErrorKind::Other, "keys file reader went away"))),
+        }
+    }
+
+    fn record_batch(&mut self, warmed: usize, errors: usize) {
+        self.warmed += warmed;
+        self.errors += errors;
+        self.sink.update_count("keys_warmed", warmed as i64);
+        self.sink.update_count("errors", errors as i64);
+
+        let processed = self.warmed + self.errors;
+        if processed >= self.next_progress {
+            info!(
+                "[warmup] pool '{}': {}/{} keys processed ({} errors)",
+                self.pool_name, processed, self.total, self.errors
+            );
+            self.next_progress = processed + (self.total / 10).max(1);
+        }
+    }
+
+    fn finish(&mut self, completed: bool) {
+        let elapsed_ms = duration_as_ms(elapsed(self.started));
+        self.sink.update_gauge("duration_ms", elapsed_ms);
+
+        let outcome = if completed { "completed" } else { "cancelled" };
+        info!(
+            "[warmup] pool '{}': warmup {} after {}ms: {} keys warmed, {} errors, {} keys skipped",
+            self.pool_name,
+            outcome,
+            elapsed_ms,
+            self.warmed,
+            self.errors,
+            self.keys.as_ref().map(|keys| keys.len()).unwrap_or(0)
+        );
+    }
+}
+
+impl<P, S, C> Future for Warmer<P, S, C>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>>,
+    C: Future,
+{
+    type Error = ();
+    type Item = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Shutdown always wins: if we've been told to close, there's no point in continuing to
+        // warm a pool that's going away.
+        match self.close.poll() {
+            Ok(Async::NotReady) => {},
+            _ => {
+                self.finish(false);
+                return Ok(Async::Ready(()));
+            },
+        }
+
+        if self.keys.is_none() {
+            let loaded = match self.poll_keys() {
+                Async::Ready(loaded) => loaded,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            self.loading = None;
+
+            match loaded {
+                Ok(keys) => {
+                    info!(
+                        "[warmup] pool '{}': warming {} keys from '{}' at {} keys/sec",
+                        self.pool_name,
+                        keys.len(),
+                        self.config.keys_file,
+                        self.config.rate
+                    );
+                    self.total = keys.len();
+                    self.started = Instant::now();
+                    self.keys = Some(keys);
+                },
+                Err(e) => {
+                    error!(
+                        "[warmup] pool '{}': failed to read keys file '{}': {}",
+                        self.pool_name, self.config.keys_file, e
+                    );
+                    return Ok(Async::Ready(()));
+                },
+            }
+        }
+
+        loop {
+            // Only ever have a single batch in flight, which, combined with the interval, is what
+            // keeps us under the configured rate.
+            if let Some((mut fut, batch_len)) = self.current.take() {
+                match fut.poll() {
+                    Ok(Async::Ready(responses)) => {
+                        let mut warmed = 0;
+                        let mut errors = 0;
+                        for (_, response) in responses {
+                            match response {
+                                MessageResponse::Complete(_) => warmed += 1,
+                                MessageResponse::Failed | MessageResponse::TimedOut => errors += 1,
+                            }
+                        }
+                        self.record_batch(warmed, errors);
+                    },
+                    Ok(Async::NotReady) => {
+                        self.current = Some((fut, batch_len));
+                        return Ok(Async::NotReady);
+                    },
+                    Err(_) => self.record_batch(0, batch_len),
+                }
+            }
+
+            if self.keys.as_ref().map(|keys| keys.is_empty()).unwrap_or(true) {
+                self.finish(true);
+                return Ok(Async::Ready(()));
+            }
+
+            match self.service.poll_ready() {
+                Ok(Async::Ready(())) => {},
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_) => {
+                    error!("[warmup] pool '{}': pool failed while warming", self.pool_name);
+                    self.finish(false);
+                    return Ok(Async::Ready(()));
+                },
+            }
+
+            match self.interval.poll() {
+                Ok(Async::Ready(_)) => {},
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_) => {
+                    error!("[warmup] pool '{}': timer failed while warming", self.pool_name);
+                    self.finish(false);
+                    return Ok(Async::Ready(()));
+                },
+            }
+
+            // Rates that don't divide evenly into ticks leave some ticks with nothing to send.
+            let processor = &self.processor;
+            let keys = self.keys.as_mut().expect("keys should be loaded");
+            let batch_len = self.budget.tick().min(keys.len());
+            if batch_len == 0 {
+                continue;
+            }
+
+            let batch = (0..batch_len)
+                .filter_map(|_| keys.pop_front())
+                .enumerate()
+                .map(|(id, key)| EnqueuedRequest::new(id, processor.get_read_request(&key)))
+                .collect::<Vec<_>>();
+
+            let fut = self.service.call(batch);
+            self.current = Some((fut, batch_len));
+        }
+    }
+}
+
+/// Spreads a rate, in keys per second, over the ticks of a warmup.
+///
+/// Whatever part of a key each tick can't send is carried over to the next, so that every second
+/// sends as many keys as the rate allows, however it divides into ticks.
+struct TickBudget {
+    rate: usize,
+    carried: usize,
+}
+
+impl TickBudget {
+    fn new(rate: usize) -> TickBudget { TickBudget { rate, carried: 0 } }
+
+    /// Gets how many keys the current tick can send.
+    fn tick(&mut self) -> usize {
+        // Budgets are kept in keys per second, so nothing is lost to rounding.
+        let available = self.carried + self.rate;
+        self.carried = available % WARMUP_TICKS_PER_SEC;
+        available / WARMUP_TICKS_PER_SEC
+    }
+}
+
+/// Reads a newline-delimited list of keys, skipping any blank lines.
+fn load_keys(path: &str) -> io::Result<VecDeque<Vec<u8>>> {
+    let file = File::open(path)?;
+    let mut keys = VecDeque::new();
+    for line in BufReader::new(file).split(b'\n') {
+        let mut key = line?;
+        if key.last() == Some(&b'\r') {
+            key.pop();
+        }
+
+        if !key.is_empty() {
+            keys.push_back(key);
+        }
+    }
+
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{redis::RedisProcessor, testing::RecordingPool};
+    use futures::future::{empty, ok, Empty};
+    use metrics::get_sink;
+    use protocol::redis::RedisTransportConfig;
+    use std::{env, fs, process};
+    use tokio::runtime::current_thread::Runtime;
+
+    fn get_config(name: &str, contents: Option<&str>) -> WarmupConfiguration {
+        let path = env::temp_dir().join(format!("synchrotron-warmup-{}-{}.keys", name, process::id()));
+        match contents {
+            Some(contents) => fs::write(&path, contents).unwrap(),
+            None => {
+                let _ = fs::remove_file(&path);
+            },
+        }
+
+        WarmupConfiguration {
+            keys_file: path.to_string_lossy().into_owned(),
+            rate: 1000,
+        }
+    }
+
+    fn get_warmer<C: Future>(
+        config: WarmupConfiguration, pool: &RecordingPool, close: C,
+    ) -> Warmer<RedisProcessor, RecordingPool, C> {
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        Warmer::new("default".to_owned(), config, processor, pool.clone(), close, get_sink())
+    }
+
+    #[test]
+    fn test_from_options() {
+        let mut options = PoolOptions::default();
+        assert!(WarmupConfiguration::from_options(&options).unwrap().is_none());
+
+        options.other.insert("warmup_keys_file".to_owned(), "/tmp/keys".to_owned());
+        let config = WarmupConfiguration::from_options(&options).unwrap().unwrap();
+        assert_eq!(config.keys_file, "/tmp/keys");
+        assert_eq!(config.rate, 1000);
+
+        options.warmup_rate = 0;
+        assert!(WarmupConfiguration::from_options(&options).is_err());
+    }
+
+    #[test]
+    fn test_tick_budget() {
+        // Every second's worth of ticks sends exactly the rate, even when it's under a key a tick.
+        for rate in &[1, 5, 15, 99, 1000, 1234] {
+            let mut budget = TickBudget::new(*rate);
+            for _ in 0..3 {
+                let sent = (0..WARMUP_TICKS_PER_SEC).map(|_| budget.tick()).sum::<usize>();
+                assert_eq!(sent, *rate);
+            }
+        }
+
+        // And what each tick sends is spread out as evenly as it can be.
+        let mut budget = TickBudget::new(15);
+        let sent = (0..4).map(|_| budget.tick()).collect::<Vec<_>>();
+        assert_eq!(sent, vec![1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn test_warms_every_key() {
+        let config = get_config("every", Some("one\r\ntwo\n\nthree\n"));
+        let pool = RecordingPool::new(true);
+        let warmer: Warmer<_, _, Empty<(), ()>> = get_warmer(config.clone(), &pool, empty());
+
+        Runtime::new().unwrap().block_on(warmer).unwrap();
+        assert_eq!(pool.seen(), vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]);
+        let _ = fs::remove_file(&config.keys_file);
+    }
+
+    #[test]
+    fn test_missing_keys_file() {
+        let config = get_config("missing", None);
+        let pool = RecordingPool::new(true);
+        let warmer: Warmer<_, _, Empty<(), ()>> = get_warmer(config, &pool, empty());
+
+        // A keys file we can't read just means there's nothing to warm.
+        Runtime::new().unwrap().block_on(warmer).unwrap();
+        assert!(pool.seen().is_empty());
+    }
+
+    #[test]
+    fn test_close_cancels() {
+        let config = get_config("close", Some("one\ntwo\n"));
+        let pool = RecordingPool::new(true);
+        let warmer = get_warmer(config.clone(), &pool, ok::<(), ()>(()));
+
+        Runtime::new().unwrap().block_on(warmer).unwrap();
+        assert!(pool.seen().is_empty());
+        let _ = fs::remove_file(&config.keys_file);
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// The weight every backend starts out with.
+pub const DEFAULT_WEIGHT: usize = 1;
+
+/// The largest weight a backend can be given, as a multiple of the default weight.
+pub const MAX_WEIGHT: usize = 100 * DEFAULT_WEIGHT;
+
+/// The most times a slow backend can have its weight halved.
+pub const MAX_DEMOTIONS: usize = 3;
+
+lazy_static! {
+    static ref WEIGHTS: Mutex<HashMap<(String, String), Arc<BackendWeights>>> = Mutex::new(HashMap::new());
+}
+
+/// Registers the backend weights for a pool, replacing those of any previous version of the pool.
+pub fn register_backend_weights(listener: &str, pool: &str, weights: Arc<BackendWeights>) {
+    let mut registry = WEIGHTS.lock().unwrap();
+    registry.insert((listener.to_owned(), pool.to_owned()), weights);
+}
+
+/// Gets the backend weights for the given pool, if the pool exists.
+pub fn find_backend_weights(listener: &str, pool: &str) -> Option<Arc<BackendWeights>> {
+    let registry = WEIGHTS.lock().unwrap();
+    registry.get(&(listener.to_owned(), pool.to_owned())).cloned()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WeightError {
+    UnknownBackend,
+    OutOfRange,
+}
+
+impl fmt::Display for WeightError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WeightError::UnknownBackend => write!(f, "no backend with that address in the pool"),
+            WeightError::OutOfRange => write!(f, "weight must be between 0 and {}", MAX_WEIGHT),
+        }
+    }
+}
+
+/// The weights of the backends in a pool, indexed by their configured position.
+///
+/// Weights can be changed from outside of the pool at any time.  Changing a weight bumps the
+/// generation, which the pool watches for so that it can reseed its distributor on its own task,
+/// between requests, rather than having the distribution change underneath it.
+///
+/// Separately from its weight, a backend can be demoted for being slow, which halves its weight
+/// for every demotion.  Demotions come and go on their own, so they never overwrite the weight
+/// that was asked for.
+pub struct BackendWeights {
+    addresses: Mutex<Vec<SocketAddr>>,
+    configured: Vec<usize>,
+    weights: Vec<AtomicUsize>,
+    demotions: Vec<AtomicUsize>,
+    generation: AtomicUsize,
+}
+
+impl BackendWeights {
+    /// Creates the weights for the given backends, each starting out with the weight it was
+    /// configured with.
+    pub fn new(backends: Vec<(SocketAddr, usize)>) -> BackendWeights {
+        let (addresses, configured): (Vec<_>, Vec<_>) = backends.into_iter().unzip();
+        let weights = configured.iter().map(|weight| AtomicUsize::new(*weight)).collect();
+        let demotions = addresses.iter().map(|_| AtomicUsize::new(0)).collect();
+
+        BackendWeights {
+            addresses: Mutex::new(addresses),
+            configured,
+            weights,
+            demotions,
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Gets the weight of the backend at the given configured position.
+    pub fn get(&self, idx: usize) -> usize { self.weights[idx].load(Ordering::Acquire) }
+
+    /// Gets the weight that the backend at the given configured position is distributed with.
+    ///
+    /// While any backend in the pool is demoted, every weight is scaled up, so that halving a
+    /// small weight doesn't round it down to nothing.
+    pub fn get_effective(&self, idx: usize) -> usize {
+        let any_demoted = self.demotions.iter().any(|demotions| demotions.load(Ordering::Acquire) > 0);
+        if !any_demoted {
+            return self.get(idx);
+        }
+
+        (self.get(idx) << MAX_DEMOTIONS) >> self.get_demotions(idx)
+    }
+
+    /// Gets how many times the backend at the given configured position has been demoted.
+    pub fn get_demotions(&self, idx: usize) -> usize { self.demotions[idx].load(Ordering::Acquire) }
+
+    /// Sets how many times the backend at the given configured position has been demoted, up to
+    /// `MAX_DEMOTIONS`.
+    pub fn set_demotions(&self, idx: usize, demotions: usize) {
+        self.demotions[idx].store(demotions.min(MAX_DEMOTIONS), Ordering::Release);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Gets the current generation, which changes whenever a weight does.
+    pub fn generation(&self) -> usize { self.generation.load(Ordering::Acquire) }
+
+    /// Sets the address of the backend at the given configured position, once it's moved there.
+    pub fn set_address(&self, idx: usize, address: SocketAddr) { self.addresses.lock().unwrap()[idx] = address; }
+
+    /// Sets the weight of every backend in the pool with the given address.
+    ///
+    /// A weight of zero drains the backend: it stays connected, but no longer has requests
+    /// distributed to it.
+    pub fn set_by_addr(&self, addr: &SocketAddr, weight: usize) -> Result<(), WeightError> {
+        if weight > MAX_WEIGHT {
+            return Err(WeightError::OutOfRange);
+        }
+
+        let mut found = false;
+        let addresses = self.addresses.lock().unwrap();
+        for (idx, _) in addresses.iter().enumerate().filter(|(_, a)| *a == addr) {
+            self.weights[idx].store(weight, Ordering::Release);
+            found = true;
+        }
+
+        if !found {
+            return Err(WeightError::UnknownBackend);
+        }
+
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Gives every backend in the pool with the given address back the weight it was configured
+    /// with, undoing any drain or weight change since.
+    ///
+    /// Returns the weight the first of them was given.
+    pub fn restore_by_addr(&self, addr: &SocketAddr) -> Result<usize, WeightError> {
+        let mut restored = None;
+        let addresses = self.addresses.lock().unwrap();
+        for (idx, _) in addresses.iter().enumerate().filter(|(_, a)| *a == addr) {
+            self.weights[idx].store(self.configured[idx], Ordering::Release);
+            restored = restored.or(Some(self.configured[idx]));
+        }
+
+        let restored = restored.ok_or(WeightError::UnknownBackend)?;
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_weight() {
+        let a = "127.0.0.1:6379".parse().unwrap();
+        let b = "127.0.0.1:6380".parse().unwrap();
+        let c = "127.0.0.1:6381".parse().unwrap();
+        let weights = BackendWeights::new(vec![(a, DEFAULT_WEIGHT), (b, DEFAULT_WEIGHT), (a, DEFAULT_WEIGHT)]);
+        assert_eq!(weights.get(0), DEFAULT_WEIGHT);
+        assert_eq!(weights.generation(), 0);
+
+        assert_eq!(weights.set_by_addr(&a, 0), Ok(()));
+        assert_eq!(weights.get(0), 0);
+        assert_eq!(weights.get(1), DEFAULT_WEIGHT);
+        assert_eq!(weights.get(2), 0);
+        assert_eq!(weights.generation(), 1);
+
+        // Bad updates leave everything as it was.
+        assert_eq!(weights.set_by_addr(&b, MAX_WEIGHT + 1), Err(WeightError::OutOfRange));
+        assert_eq!(weights.set_by_addr(&c, 1), Err(WeightError::UnknownBackend));
+        assert_eq!(weights.get(1), DEFAULT_WEIGHT);
+        assert_eq!(weights.generation(), 1);
+    }
+
+    #[test]
+    fn test_restore_weight() {
+        let a = "127.0.0.1:6379".parse().unwrap();
+        let b = "127.0.0.1:6380".parse().unwrap();
+        let c = "127.0.0.1:6381".parse().unwrap();
+        let weights = BackendWeights::new(vec![(a, 3), (b, DEFAULT_WEIGHT)]);
+
+        assert_eq!(weights.set_by_addr(&a, 0), Ok(()));
+        assert_eq!(weights.set_by_addr(&b, 5), Ok(()));
+        assert_eq!(weights.restore_by_addr(&a), Ok(3));
+        assert_eq!(weights.get(0), 3);
+        assert_eq!(weights.get(1), 5);
+        assert_eq!(weights.generation(), 3);
+
+        assert_eq!(weights.restore_by_addr(&c), Err(WeightError::UnknownBackend));
+        assert_eq!(weights.generation(), 3);
+    }
+
+    #[test]
+    fn test_demotions() {
+        let a = "127.0.0.1:6379".parse().unwrap();
+        let b = "127.0.0.1:6380".parse().unwrap();
+        let weights = BackendWeights::new(vec![(a, DEFAULT_WEIGHT), (b, 3)]);
+        assert_eq!(weights.get_effective(0), 1);
+        assert_eq!(weights.get_effective(1), 3);
+
+        // Demoting one backend keeps the others' shares where they were.
+        weights.set_demotions(0, 1);
+        assert_eq!(weights.get_effective(0), 4);
+        assert_eq!(weights.get_effective(1), 24);
+        assert_eq!(weights.generation(), 1);
+
+        // Demotions bottom out, and never touch the weight that was asked for.
+        weights.set_demotions(0, MAX_DEMOTIONS + 5);
+        assert_eq!(weights.get_demotions(0), MAX_DEMOTIONS);
+        assert_eq!(weights.get_effective(0), 1);
+        assert_eq!(weights.get(0), 1);
+
+        weights.set_demotions(0, 0);
+        assert_eq!(weights.get_effective(0), 1);
+        assert_eq!(weights.get_effective(1), 3);
+    }
+}
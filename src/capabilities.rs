@@ -0,0 +1,88 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::{distributor::distributor_names, hasher::hasher_names};
+use conf::ListenerConfiguration;
+use listener::{protocol_names, route_type_names};
+use metrics::ADMIN_FEATURES;
+use serde_json;
+
+/// What this build of synchrotron is able to do.
+///
+/// Everything here comes from the same registries that configuration is checked against, so
+/// deploy tooling can compare it with a configuration before rolling out a binary.
+#[derive(Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub protocols: Vec<&'static str>,
+    pub routing_types: Vec<&'static str>,
+    pub distributors: Vec<&'static str>,
+    pub hashers: Vec<&'static str>,
+    pub tls: bool,
+    pub admin: Vec<&'static str>,
+}
+
+pub fn get_capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        protocols: protocol_names(),
+        routing_types: route_type_names(),
+        distributors: distributor_names(),
+        hashers: hasher_names(),
+        tls: listeners_take_tls(),
+        admin: ADMIN_FEATURES.to_vec(),
+    }
+}
+
+/// Whether listeners can be configured to terminate TLS, going by whether their configuration has
+/// anywhere to put it.
+fn listeners_take_tls() -> bool {
+    serde_json::to_value(ListenerConfiguration::default())
+        .map(|config| config.get("tls").is_some())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{distributor::configure_distributor, hasher::configure_hasher};
+    use conf::PoolOptions;
+
+    #[test]
+    fn test_capabilities_are_configurable() {
+        let capabilities = get_capabilities();
+        assert!(capabilities.protocols.contains(&"redis"));
+        assert!(capabilities.protocols.contains(&"memcached"));
+        assert!(capabilities.routing_types.contains(&"fixed"));
+        assert!(capabilities.routing_types.contains(&"shadow"));
+        assert!(capabilities.routing_types.contains(&"split"));
+        assert!(capabilities.tls);
+
+        // Anything we say we support has to actually be usable.
+        for distributor in &capabilities.distributors {
+            assert!(configure_distributor(distributor, &PoolOptions::default()).is_ok());
+        }
+        for hasher in &capabilities.hashers {
+            assert!(configure_hasher(hasher).is_ok());
+        }
+        assert!(configure_distributor("ketama-but-not-really", &PoolOptions::default()).is_err());
+    }
+}
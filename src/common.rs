@@ -18,6 +18,7 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 use bytes::BytesMut;
+use futures::Async;
 use tokio::sync::oneshot::{channel, Receiver, Sender};
 use util::Sizable;
 
@@ -36,6 +37,21 @@ pub enum MessageResponse<T> {
 
     /// The message was processed and a response was received.
     Complete(T),
+
+    /// No response was received before the backend request timeout passed, so the message was
+    /// given up on.  The backend may still process it, but nobody will hear about it.
+    TimedOut,
+}
+
+/// Which lane a request waits in on its way out over a backend connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lane {
+    /// Requests of our own, such as health checks, which go out ahead of anything waiting in the
+    /// normal lane.
+    Priority,
+
+    /// Everything else, which goes out in the order it was sent.
+    Normal,
 }
 
 // Core types.
@@ -48,12 +64,13 @@ pub type AssignedRequests<T> = Vec<AssignedRequest<T>>;
 pub type AssignedResponses<T> = Vec<AssignedResponse<T>>;
 
 pub type PendingResponse<T> = Receiver<AssignedResponse<T>>;
-pub type PendingResponses<T> = Vec<PendingResponse<T>>;
 pub type EnqueuedRequests<T> = Vec<EnqueuedRequest<T>>;
 
 pub struct EnqueuedRequest<T: Clone + Message> {
     id: usize,
     request: Option<T>,
+    lane: Lane,
+    retry: bool,
     has_response: bool,
     done: bool,
     tx: Option<Sender<AssignedResponse<T>>>,
@@ -64,16 +81,30 @@ impl<T: Clone + Message> EnqueuedRequest<T> {
         EnqueuedRequest {
             id,
             request: Some(request),
+            lane: Lane::Normal,
+            retry: false,
             tx: None,
             has_response: true,
             done: false,
         }
     }
 
+    /// Creates a request that retries one that already failed once.
+    ///
+    /// Retries are sent like any other request, but they don't count as fresh traffic, so they never
+    /// earn a pool any more retries.
+    pub fn retry(id: usize, request: T) -> EnqueuedRequest<T> {
+        let mut req = EnqueuedRequest::new(id, request);
+        req.retry = true;
+        req
+    }
+
     pub fn without_response(request: T) -> EnqueuedRequest<T> {
         EnqueuedRequest {
             id: 0,
             request: Some(request),
+            lane: Lane::Normal,
+            retry: false,
             tx: None,
             has_response: false,
             done: true,
@@ -87,7 +118,31 @@ impl<T: Clone + Message> EnqueuedRequest<T> {
         self.request.as_ref().expect("tried to get key for empty request").key()
     }
 
-    pub fn consume(&mut self) -> T { self.request.take().unwrap() }
+    /// Gets the identifier that the response to this request will be sent back with.
+    pub fn id(&self) -> usize { self.id }
+
+    /// Gets a reference to the underlying request.
+    pub fn request(&self) -> &T { self.request.as_ref().expect("tried to get empty request") }
+
+    /// Gets the lane this request waits in.
+    pub fn lane(&self) -> Lane { self.lane }
+
+    /// Moves this request into the given lane.
+    pub fn set_lane(&mut self, lane: Lane) { self.lane = lane; }
+
+    /// Whether or not this request is a retry of one that already failed.
+    pub fn is_retry(&self) -> bool { self.retry }
+
+    /// Replaces the underlying request with whatever the given function makes of it.
+    ///
+    /// The response is still sent back with the same identifier, to the same place.
+    pub fn map_request<F>(&mut self, f: F)
+    where
+        F: FnOnce(T) -> T,
+    {
+        let request = self.request.take().expect("tried to map empty request");
+        self.request = Some(f(request));
+    }
 
     pub fn fulfill(&mut self, response: T) {
         if self.done {
@@ -102,6 +157,17 @@ impl<T: Clone + Message> EnqueuedRequest<T> {
         self.done = true;
     }
 
+    /// Whether or not whoever was waiting on the response to this request has stopped waiting.
+    ///
+    /// If they're still waiting, the current task is notified once they stop.  Requests without a
+    /// response are never abandoned, since nobody was waiting on them to begin with.
+    pub fn poll_abandoned(&mut self) -> bool {
+        match self.tx.as_mut().map(Sender::poll_close) {
+            Some(Ok(Async::NotReady)) | None => false,
+            Some(_) => true,
+        }
+    }
+
     pub fn get_response_rx(&mut self) -> Option<PendingResponse<T>> {
         if self.has_response {
             let (tx, rx) = channel();
@@ -17,17 +17,168 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use serde::de::{Deserialize, Deserializer, Error};
-use std::{fmt, net::SocketAddr};
+use backend::weights::DEFAULT_WEIGHT;
+use serde::{
+    de::{value::MapAccessDeserializer, Deserialize, Deserializer, Error, MapAccess, Visitor},
+    ser::{Serialize, Serializer},
+};
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+};
 
+// Marks the part of an address string that gives the weight.
+const WEIGHT_PREFIX: &str = "weight=";
+
+/// A backend in a pool, along with the name it's known by and its share of requests.
+///
+/// Addresses can be given as a string of the form `"<address> [identifier] [weight=<weight>]"`, or
+/// as a map with an `address` and, optionally, an `identifier` and a `weight`.  The weight is
+/// always spelled out, so that an identifier that happens to be a number is still an identifier.
+/// A weight of zero keeps the backend connected without sending it any requests, which is useful
+/// for warming it up ahead of time.
+///
+/// The address itself is either an IP address and port, or a hostname and port.  A backend given
+/// by hostname carries the unspecified address, with its port, until its hostname is resolved, and
+/// is identified by its hostname and port, rather than by whatever that resolves to, unless told
+/// otherwise.
 #[derive(Debug, Clone)]
 pub struct BackendAddress {
     pub address: SocketAddr,
+    pub host: Option<String>,
     pub identifier: String,
+    pub weight: usize,
+}
+
+impl BackendAddress {
+    /// Gets the address as it was configured, which is the hostname and port for backends given by
+    /// hostname.
+    fn configured(&self) -> String { self.host.clone().unwrap_or_else(|| self.address.to_string()) }
 }
 
 impl fmt::Display for BackendAddress {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}({})", self.address, self.identifier) }
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}({})", self.configured(), self.identifier) }
+}
+
+impl Serialize for BackendAddress {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if self.weight == DEFAULT_WEIGHT {
+            serializer.serialize_str(&format!("{} {}", self.configured(), self.identifier))
+        } else {
+            serializer.serialize_str(&format!(
+                "{} {} {}{}",
+                self.configured(),
+                self.identifier,
+                WEIGHT_PREFIX,
+                self.weight
+            ))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StructuredAddress {
+    address: String,
+    identifier: Option<String>,
+    weight: Option<usize>,
+}
+
+/// Parses an address given as either an IP address and port, or a hostname and port.
+///
+/// Hostnames are handed back alongside the unspecified address, with their port, since they
+/// haven't been resolved yet.
+fn parse_address(raw: &str) -> Result<(SocketAddr, Option<String>), String> {
+    if let Ok(address) = raw.parse::<SocketAddr>() {
+        return Ok((address, None));
+    }
+
+    let invalid = || format!("invalid address '{}'", raw);
+    let split = raw.rfind(':').ok_or_else(invalid)?;
+    let (host, port) = (&raw[..split], &raw[split + 1..]);
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    let valid_host = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+    if !valid_host {
+        return Err(invalid());
+    }
+
+    Ok((SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port), Some(raw.to_string())))
+}
+
+fn build_address(raw: &str, identifier: Option<String>, weight: Option<usize>) -> Result<BackendAddress, String> {
+    let (address, host) = parse_address(raw)?;
+    Ok(BackendAddress {
+        address,
+        identifier: identifier.unwrap_or_else(|| host.clone().unwrap_or_else(|| address.to_string())),
+        host,
+        weight: weight.unwrap_or(DEFAULT_WEIGHT),
+    })
+}
+
+/// Parses the weight out of part of an address string, if that part gives the weight.
+fn parse_weight(part: &str) -> Option<Result<usize, String>> {
+    if !part.starts_with(WEIGHT_PREFIX) {
+        return None;
+    }
+
+    let raw = &part[WEIGHT_PREFIX.len()..];
+    Some(raw.parse::<usize>().map_err(|_| format!("invalid weight '{}'", raw)))
+}
+
+struct BackendAddressVisitor;
+
+impl<'de> Visitor<'de> for BackendAddressVisitor {
+    type Value = BackendAddress;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a string of the form \"<address> [identifier] [weight=<weight>]\", or a map with an address"
+        )
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<BackendAddress, E>
+    where
+        E: Error,
+    {
+        let mut parts = s.split(' ');
+
+        let address = parts.next().ok_or_else(|| E::custom("missing address"))?;
+
+        let rest = parts.collect::<Vec<_>>();
+        let (identifier, weight) = match rest.as_slice() {
+            [] => (None, None),
+            [single] => {
+                match parse_weight(single) {
+                    Some(weight) => (None, Some(weight.map_err(E::custom)?)),
+                    None => (Some(single.to_string()), None),
+                }
+            },
+            [identifier, weight] if parse_weight(identifier).is_none() => {
+                let weight = parse_weight(weight)
+                    .unwrap_or_else(|| Err(format!("expected a weight, got '{}'", weight)))
+                    .map_err(E::custom)?;
+                (Some(identifier.to_string()), Some(weight))
+            },
+            _ => return Err(E::custom("unexpected element")),
+        };
+
+        build_address(address, identifier, weight).map_err(E::custom)
+    }
+
+    fn visit_map<M>(self, map: M) -> Result<BackendAddress, M::Error>
+    where
+        M: MapAccess<'de>,
+    {
+        let structured = StructuredAddress::deserialize(MapAccessDeserializer::new(map))?;
+        build_address(&structured.address, structured.identifier, structured.weight).map_err(M::Error::custom)
+    }
 }
 
 impl<'de> Deserialize<'de> for BackendAddress {
@@ -35,23 +186,97 @@ impl<'de> Deserialize<'de> for BackendAddress {
     where
         D: Deserializer<'de>,
     {
-        let s = String::deserialize(deserializer)?;
-        let mut parts = s.split(" ");
-
-        let address = parts
-            .next()
-            .ok_or(D::Error::custom("missing address"))?
-            .parse::<SocketAddr>()
-            .map_err(D::Error::custom)?;
-        let identifier = parts
-            .next()
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| address.to_string().clone());
-
-        if parts.next() != None {
-            return Err(D::Error::custom("unexpected element"));
+        deserializer.deserialize_any(BackendAddressVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn parse(raw: &str) -> Result<BackendAddress, serde_json::Error> { serde_json::from_str(raw) }
+
+    #[test]
+    fn test_string_forms() {
+        let plain = parse(r#""127.0.0.1:6379""#).unwrap();
+        assert_eq!(plain.identifier, "127.0.0.1:6379");
+        assert_eq!(plain.weight, 1);
+
+        let named = parse(r#""127.0.0.1:6379 cache1""#).unwrap();
+        assert_eq!(named.identifier, "cache1");
+        assert_eq!(named.weight, 1);
+
+        let weighted = parse(r#""127.0.0.1:6379 weight=2""#).unwrap();
+        assert_eq!(weighted.identifier, "127.0.0.1:6379");
+        assert_eq!(weighted.weight, 2);
+
+        let both = parse(r#""127.0.0.1:6379 cache1 weight=0""#).unwrap();
+        assert_eq!(both.identifier, "cache1");
+        assert_eq!(both.weight, 0);
+
+        // Identifiers can be numbers, without being mistaken for weights.
+        let numbered = parse(r#""127.0.0.1:6379 2""#).unwrap();
+        assert_eq!(numbered.identifier, "2");
+        assert_eq!(numbered.weight, 1);
+
+        assert!(parse(r#""127.0.0.1:6379 cache1 2""#).is_err());
+        assert!(parse(r#""127.0.0.1:6379 cache1 weight=heavy""#).is_err());
+        assert!(parse(r#""127.0.0.1:6379 weight=2 cache1""#).is_err());
+        assert!(parse(r#""127.0.0.1:6379 cache1 weight=2 extra""#).is_err());
+        assert!(parse(r#""localhost weight=2""#).is_err());
+    }
+
+    #[test]
+    fn test_hostnames() {
+        let plain = parse(r#""redis-0.cache.svc:6379""#).unwrap();
+        assert_eq!(plain.host, Some("redis-0.cache.svc:6379".to_owned()));
+        assert_eq!(plain.address, "0.0.0.0:6379".parse::<SocketAddr>().unwrap());
+        assert_eq!(plain.identifier, "redis-0.cache.svc:6379");
+
+        let named = parse(r#"{"address": "localhost:6379", "identifier": "cache1", "weight": 2}"#).unwrap();
+        assert_eq!(named.host, Some("localhost:6379".to_owned()));
+        assert_eq!(named.identifier, "cache1");
+        assert_eq!(named.weight, 2);
+
+        assert_eq!(parse(r#""127.0.0.1:6379""#).unwrap().host, None);
+        assert!(parse(r#""localhost""#).is_err());
+        assert!(parse(r#""localhost:redis""#).is_err());
+        assert!(parse(r#""localhost:70000""#).is_err());
+        assert!(parse(r#"":6379""#).is_err());
+        assert!(parse(r#""cache/1:6379""#).is_err());
+    }
+
+    #[test]
+    fn test_structured_form() {
+        let weighted = parse(r#"{"address": "127.0.0.1:6379", "weight": 3}"#).unwrap();
+        assert_eq!(weighted.identifier, "127.0.0.1:6379");
+        assert_eq!(weighted.weight, 3);
+
+        let named = parse(r#"{"address": "127.0.0.1:6379", "identifier": "cache1"}"#).unwrap();
+        assert_eq!(named.identifier, "cache1");
+        assert_eq!(named.weight, 1);
+
+        assert!(parse(r#"{"weight": 3}"#).is_err());
+        assert!(parse(r#"{"address": "127.0.0.1:6379", "wieght": 3}"#).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for raw in &[r#""127.0.0.1:6379 cache1""#, r#""127.0.0.1:6379 cache1 weight=5""#, r#""127.0.0.1:6379 7""#] {
+            let address = parse(raw).unwrap();
+            assert_eq!(serde_json::to_string(&address).unwrap(), *raw);
         }
 
-        Ok(BackendAddress { address, identifier })
+        // Structured addresses come back out as strings that parse to the same thing.
+        let structured = parse(r#"{"address": "127.0.0.1:6379", "weight": 0}"#).unwrap();
+        let serialized = serde_json::to_string(&structured).unwrap();
+        assert_eq!(serialized, r#""127.0.0.1:6379 127.0.0.1:6379 weight=0""#);
+        assert_eq!(parse(&serialized).unwrap().weight, 0);
+
+        // Hostnames are written out as they were given, rather than as whatever they resolved to.
+        let mut hostname = parse(r#""localhost:6379 cache1""#).unwrap();
+        hostname.address = "127.0.0.1:6379".parse().unwrap();
+        assert_eq!(serde_json::to_string(&hostname).unwrap(), r#""localhost:6379 cache1""#);
     }
 }
@@ -17,39 +17,221 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use super::BackendAddress;
-use config::{Config, ConfigError, File};
-use std::{collections::HashMap, env};
+use super::{BackendAddress, PoolOptions, Secret};
+use config::{Config, ConfigError, File, FileFormat, Value};
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
-#[derive(Deserialize, Default, Clone, Debug)]
+const MAX_LISTENER_NAME_LEN: usize = 64;
+
+// Where the stats and admin server listens unless told otherwise.  An empty address turns it off.
+const DEFAULT_STATS_ADDR: &str = "0.0.0.0:16161";
+
+// The formats configuration files can be written in, by the extension they're found with, in the
+// order they're looked for.
+const CONFIG_FORMATS: &[(&str, &str, FileFormat)] = &[
+    ("json", "JSON", FileFormat::Json),
+    ("toml", "TOML", FileFormat::Toml),
+    ("yaml", "YAML", FileFormat::Yaml),
+    ("yml", "YAML", FileFormat::Yaml),
+];
+
+// Environment variables that override the configuration start with this, and separate each part
+// of the path they override with `ENV_OVERRIDE_SEPARATOR`.
+const ENV_OVERRIDE_PREFIX: &str = "SYNC__";
+const ENV_OVERRIDE_SEPARATOR: &str = "__";
+
+// The keys that hold lists, whose overrides are split into items on `ENV_LIST_DELIMITER`.
+const ENV_LIST_KEYS: &[&str] = &["addresses", "latency_buckets_us"];
+const ENV_LIST_DELIMITER: char = ',';
+
+lazy_static! {
+    static ref APPLIED: Mutex<Option<AppliedConfiguration>> = Mutex::new(None);
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct Configuration {
-    pub stats_addr: String,
+    pub stats_addr: Option<String>,
+    pub stats_bind_retry_ms: Option<u64>,
+    pub stats_history_interval_ms: Option<u64>,
+    pub stats_history_secs: Option<u64>,
+    pub shutdown_timeout_ms: Option<u64>,
     pub logging: LoggingConfiguration,
+    pub runtime: Option<RuntimeConfiguration>,
+    pub metrics: Option<MetricsConfiguration>,
     pub listeners: HashMap<String, ListenerConfiguration>,
 }
 
-#[derive(Deserialize, Default, Clone, Debug)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct LoggingConfiguration {
     pub level: String,
 }
 
-#[derive(Deserialize, Default, Clone, Debug)]
+/// Tuning for the runtime that drives every listener.
+///
+/// Anything left unset keeps the default: a worker thread for every CPU, named `synchrotron-N`,
+/// and up to 16 threads for blocking work, like resolving backend hostnames.  Linux only keeps the
+/// first 15 bytes of a thread name, so a long prefix can cut off the number that tells worker
+/// threads apart.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct RuntimeConfiguration {
+    pub worker_threads: Option<usize>,
+    pub thread_name_prefix: Option<String>,
+    pub max_blocking_threads: Option<usize>,
+}
+
+/// Where metrics are pushed to, on top of being served by the stats server.
+///
+/// The only `type` there is is `statsd`, which sends metrics to the statsd agent at `address` every
+/// `flush_interval_ms`, with every name starting with `prefix`.  With `dogstatsd` set, the names of
+/// listeners, pools and backends are sent as tags rather than as part of the metric name.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct MetricsConfiguration {
+    #[serde(rename = "type")]
+    pub metrics_type: String,
+    pub address: String,
+    pub flush_interval_ms: Option<u64>,
+    pub prefix: Option<String>,
+    pub dogstatsd: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct ListenerConfiguration {
     pub protocol: String,
     pub address: String,
+    pub reuse_port: Option<bool>,
+    pub reuse_port_shared: Option<bool>,
     pub reload_timeout_ms: Option<u64>,
+    pub shutdown_timeout_ms: Option<u64>,
+    pub pretend_cluster: Option<bool>,
+    pub allow_debug_simulation: Option<bool>,
+    pub routing_hints: Option<bool>,
+    pub allow_client_kill: Option<bool>,
+    pub password: Option<Secret<String>>,
+    pub password_file: Option<String>,
+    pub max_bulk_len: Option<usize>,
+    pub max_multibulk_len: Option<usize>,
+    pub max_inline_len: Option<usize>,
+    pub max_protocol_errors: Option<usize>,
+    pub record_path: Option<String>,
+    pub record_max_bytes: Option<usize>,
+    pub record_max_connections: Option<usize>,
+    pub record_sample_rate: Option<f64>,
+    pub record_redact_values: Option<bool>,
+    pub key_sample_rate: Option<f64>,
+    pub key_sample_delimiter: Option<String>,
+    pub key_sample_max_prefixes: Option<usize>,
+    pub max_fds: Option<usize>,
+    pub max_clients: Option<usize>,
+    pub max_clients_behavior: Option<String>,
+    pub client_idle_timeout_ms: Option<u64>,
+    pub max_fragments_per_command: Option<usize>,
+    pub max_concurrent_fragments_per_client: Option<usize>,
+    pub batch_size: Option<usize>,
+    pub batch_linger_us: Option<u64>,
+    pub slo: Option<HashMap<String, u64>>,
+    pub latency_buckets_us: Option<Vec<u64>>,
+    pub tls: Option<TlsConfiguration>,
     pub pools: HashMap<String, PoolConfiguration>,
     pub routing: HashMap<String, String>,
 }
 
-#[derive(Deserialize, Default, Clone, Debug)]
+/// A pool of backends, and how requests are spread over them.
+///
+/// Options that hold secrets, like `redis_auth`, are redacted whenever the pool configuration is
+/// formatted or serialized.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct PoolConfiguration {
     pub addresses: Vec<BackendAddress>,
-    pub options: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub options: PoolOptions,
+    pub migration: Option<MigrationConfiguration>,
+    /// The backend, by address or identifier, that each of the given keys must be placed on.
+    ///
+    /// Checked at startup, and fatal when the `strict_placements` option is set.
+    pub expected_placements: Option<HashMap<String, String>>,
+    /// The transforms applied to every key sent to the pool, in order.
+    ///
+    /// Each is given by its `type`, along with whatever parameters that type of transform takes.
+    pub key_transforms: Option<Vec<HashMap<String, String>>>,
+}
+
+/// The placement a pool is moving its keys away from.
+///
+/// The pool routes everything with `to_distribution` and `to_hash`, which override the
+/// `distribution` and `hash` options.  Lookups of a single key that miss are retried against the
+/// old placement when `read_fallback` is set, which it is by default.  Writes only land on the new
+/// placement, so unless `delete_old` is set, a key that is deleted can still be found at its old
+/// placement by a fallback lookup until the old copy expires.
+///
+/// Keys at the old placement go through the pool's own key transforms, unless the migration is
+/// also moving keys to new names, in which case `from_key_transforms` gives the transforms that
+/// keys were stored under there.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct MigrationConfiguration {
+    pub from_distribution: String,
+    pub from_hash: Option<String>,
+    pub from_key_transforms: Option<Vec<HashMap<String, String>>>,
+    pub to_distribution: String,
+    pub to_hash: Option<String>,
+    pub read_fallback: Option<bool>,
+    pub delete_old: Option<bool>,
+}
+
+/// How a listener terminates TLS for its clients.
+///
+/// The certificate and key are read, in PEM format, when the listener starts.  When
+/// `client_ca_path` is set, clients must present a certificate signed by one of the authorities in
+/// it.  Clients that haven't finished their handshake within `handshake_timeout_ms` are dropped.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct TlsConfiguration {
+    pub cert_path: String,
+    pub key_path: String,
+    pub client_ca_path: Option<String>,
+    pub handshake_timeout_ms: Option<u64>,
+}
+
+/// A configuration key that was overridden by an environment variable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvOverride {
+    pub key: String,
+    pub var: String,
+}
+
+/// Logs the configuration keys that were overridden by environment variables.
+///
+/// Only the keys are logged, and not their values, since the values could well be secrets.
+pub fn log_env_overrides(overrides: &[EnvOverride]) {
+    for EnvOverride { key, var } in overrides {
+        debug!("[core] configuration key '{}' overridden by environment variable {}", key, var);
+    }
+}
+
+/// The configuration that was last applied, as reported by the admin API.
+#[derive(Serialize, Clone)]
+pub struct AppliedConfiguration {
+    pub generation: usize,
+    pub configuration: Configuration,
 }
 
 impl Configuration {
+    /// Loads the configuration, logging any keys that were overridden by the environment.
     pub fn new() -> Result<Self, ConfigError> {
+        let (configuration, overrides) = Configuration::load()?;
+        log_env_overrides(&overrides);
+        Ok(configuration)
+    }
+
+    /// Loads the configuration, along with the keys that were overridden by the environment.
+    ///
+    /// Nothing is logged, so this is what to use before logging has been configured, which needs
+    /// the configuration first.
+    pub fn load() -> Result<(Self, Vec<EnvOverride>), ConfigError> {
         let mut s = Config::new();
 
         // TODO: the hierarchy stuff doesn't work IIRC.  re-examine that and figure out if my
@@ -57,22 +239,604 @@ impl Configuration {
 
         // Set some defaults.
         s.set_default("logging.level", "info")?;
+        s.set_default("stats_addr", DEFAULT_STATS_ADDR)?;
         // how tf do we make this work?
         // s.set_default("listeners", Vec::<ListenerConfiguration>::new())?;
-        s.set_default("stats_addr", "0.0.0.0:16161")?;
 
         // Now load in any configuration files we can find.
-        s.merge(File::with_name("config/synchrotron").required(false))?;
-
         let env = env::var("ENV").unwrap_or_else(|_| "dev".into());
-        s.merge(File::with_name(&format!("config/synchrotron.{}", env)).required(false))?;
-        s.merge(File::with_name("config/synchrotron.local").required(false))?;
+        let mut names = vec![
+            "config/synchrotron".to_owned(),
+            format!("config/synchrotron.{}", env),
+            "config/synchrotron.local".to_owned(),
+        ];
+        if let Ok(path) = env::var("SYNC_CONFIG") {
+            names.push(path);
+        }
 
-        let conf_override = env::var("SYNC_CONFIG").ok();
-        if let Some(path) = conf_override {
-            s.merge(File::with_name(path.as_str()).required(false))?;
+        let mut loaded = Vec::new();
+        for name in &names {
+            if let Some(file) = merge_config_file(&mut s, name)? {
+                loaded.push(file);
+            }
         }
 
-        s.try_into()
+        // Anything overridden in the environment wins out over every file.
+        let overrides = merge_env_overrides(&mut s, env::vars())?;
+        loaded.extend(overrides.iter().map(|o| format!("environment variable {}", o.var)));
+
+        let configuration = into_configuration(s, &loaded)?;
+        configuration.validate()?;
+        Ok((configuration, overrides))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for name in self.listeners.keys() {
+            validate_listener_name(name).map_err(ConfigError::Message)?;
+        }
+
+        self.get_stats_addr()?;
+        if self.stats_bind_retry_ms == Some(0) {
+            return Err(ConfigError::Message("stats_bind_retry_ms must be greater than zero".to_owned()));
+        }
+        if self.stats_history_interval_ms == Some(0) {
+            return Err(ConfigError::Message("stats_history_interval_ms must be greater than zero".to_owned()));
+        }
+        if self.stats_history_secs == Some(0) {
+            return Err(ConfigError::Message("stats_history_secs must be greater than zero".to_owned()));
+        }
+
+        if let Some(ref runtime) = self.runtime {
+            runtime.validate()?;
+        }
+
+        if let Some(ref metrics) = self.metrics {
+            metrics.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Gets the address the stats and admin server should listen on.
+    ///
+    /// The server listens on the default address unless told otherwise, and is only disabled when
+    /// it's explicitly given an empty address.
+    pub fn get_stats_addr(&self) -> Result<Option<SocketAddr>, ConfigError> {
+        let addr = self.stats_addr.as_ref().map_or(DEFAULT_STATS_ADDR, |addr| addr.as_str());
+        if addr.is_empty() {
+            return Ok(None);
+        }
+
+        addr.parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Message(format!("stats_addr '{}' is not a valid address", addr)))
+    }
+
+    /// Checks that there is at least one listener to run.
+    ///
+    /// An empty set of listeners is almost always a templating mistake, and a process that serves
+    /// nothing still looks healthy from the outside, so this is an error unless `allow_empty` is set.
+    pub fn check_listeners(&self, allow_empty: bool) -> Result<(), ConfigError> {
+        if self.listeners.is_empty() && !allow_empty {
+            return Err(ConfigError::Message(
+                "no listeners configured; pass --allow-empty to start anyway".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds the configuration file with the given name, along with the name of its format and the
+/// format itself.
+///
+/// A name that ends with the extension of a format we know is taken as is.  Otherwise, the name is
+/// looked for with each of those extensions in turn, and the first file found is used.
+fn find_config_file(name: &str) -> Option<(PathBuf, &'static str, FileFormat)> {
+    let path = Path::new(name);
+    let explicit = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| CONFIG_FORMATS.iter().find(|(known, _, _)| *known == ext));
+
+    match explicit {
+        Some((_, format_name, format)) => {
+            if path.is_file() {
+                Some((path.to_path_buf(), *format_name, *format))
+            } else {
+                None
+            }
+        },
+        None => {
+            CONFIG_FORMATS
+                .iter()
+                .map(|(ext, format_name, format)| (PathBuf::from(format!("{}.{}", name, ext)), *format_name, *format))
+                .find(|(path, _, _)| path.is_file())
+        },
+    }
+}
+
+/// Merges the configuration file with the given name, if there is one, returning a description of
+/// the file that was merged.
+fn merge_config_file(s: &mut Config, name: &str) -> Result<Option<String>, ConfigError> {
+    let (path, format_name, format) = match find_config_file(name) {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+
+    let described = format!("'{}' ({})", path.display(), format_name);
+    s.merge(File::from(path.as_path()).format(format)).map_err(|e| {
+        ConfigError::Message(format!("failed to load configuration from {}: {}", described, e))
+    })?;
+    Ok(Some(described))
+}
+
+/// Merges any configuration overrides among the given environment variables, returning each one
+/// that was merged.
+///
+/// An override is named for the path of the key it overrides, in any case, with `__` between each
+/// part of the path: `SYNC__LISTENERS__FIXED__POOLS__DEFAULT__ADDRESSES` overrides
+/// `listeners.fixed.pools.default.addresses`.  Lists are given as comma-separated items, as in
+/// `10.0.0.1:6379,10.0.0.2:6379`.
+fn merge_env_overrides<I>(s: &mut Config, vars: I) -> Result<Vec<EnvOverride>, ConfigError>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut overrides = vars
+        .into_iter()
+        .filter(|(var, _)| var.starts_with(ENV_OVERRIDE_PREFIX))
+        .collect::<Vec<_>>();
+    overrides.sort();
+
+    let mut merged = Vec::new();
+    for (var, raw) in overrides {
+        let parts = var[ENV_OVERRIDE_PREFIX.len()..]
+            .split(ENV_OVERRIDE_SEPARATOR)
+            .map(|part| part.to_lowercase())
+            .collect::<Vec<_>>();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(ConfigError::Message(format!(
+                "environment variable {} doesn't name a configuration key",
+                var
+            )));
+        }
+
+        let key = parts.join(".");
+        let value = if ENV_LIST_KEYS.contains(&parts[parts.len() - 1].as_str()) {
+            let items = raw
+                .split(ENV_LIST_DELIMITER)
+                .map(|item| item.trim())
+                .filter(|item| !item.is_empty())
+                .map(parse_env_value)
+                .collect::<Vec<_>>();
+            Value::from(items)
+        } else {
+            parse_env_value(&raw)
+        };
+        s.set(&key, value)?;
+        merged.push(EnvOverride { key, var });
+    }
+
+    Ok(merged)
+}
+
+/// Gets the configuration value that an override stands for.
+///
+/// Booleans and numbers are taken as such only when they'd be written back out exactly as given,
+/// so that a value like `007` is still the string it was meant to be.
+fn parse_env_value(raw: &str) -> Value {
+    if let Ok(value) = raw.parse::<bool>() {
+        return Value::from(value);
+    }
+
+    if let Ok(value) = raw.parse::<i64>() {
+        if value.to_string() == raw {
+            return Value::from(value);
+        }
+    }
+
+    if let Ok(value) = raw.parse::<f64>() {
+        if value.to_string() == raw {
+            return Value::from(value);
+        }
+    }
+
+    Value::from(raw)
+}
+
+/// Turns the merged configuration files into our configuration, saying which files were involved
+/// if that fails.
+fn into_configuration(s: Config, loaded: &[String]) -> Result<Configuration, ConfigError> {
+    s.try_into().map_err(|e| {
+        if loaded.is_empty() {
+            ConfigError::Message(format!("invalid configuration, with no configuration files found: {}", e))
+        } else {
+            ConfigError::Message(format!("invalid configuration from {}: {}", loaded.join(", "), e))
+        }
+    })
+}
+
+/// Records the configuration that was applied as the given generation.
+///
+/// When only one listener was launched, only that listener's configuration is taken from the one
+/// given, since the rest of the listeners are still running whatever they were launched with.
+pub fn set_applied(generation: usize, configuration: &Configuration, only: Option<&str>) {
+    let mut applied = APPLIED.lock().unwrap();
+    let updated = match (applied.take(), only) {
+        (Some(previous), Some(name)) => {
+            let mut updated = previous.configuration;
+            if let Some(listener) = configuration.listeners.get(name) {
+                updated.listeners.insert(name.to_owned(), listener.clone());
+            }
+            updated
+        },
+        _ => configuration.clone(),
+    };
+
+    *applied = Some(AppliedConfiguration {
+        generation,
+        configuration: updated,
+    });
+}
+
+/// Gets the configuration that was last applied, if any has been yet.
+pub fn get_applied() -> Option<AppliedConfiguration> { APPLIED.lock().unwrap().clone() }
+
+impl RuntimeConfiguration {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.worker_threads == Some(0) {
+            return Err(ConfigError::Message("runtime.worker_threads must be greater than zero".to_owned()));
+        }
+
+        if self.max_blocking_threads == Some(0) {
+            return Err(ConfigError::Message("runtime.max_blocking_threads must be greater than zero".to_owned()));
+        }
+
+        if self.thread_name_prefix.as_ref().map_or(false, |prefix| prefix.is_empty()) {
+            return Err(ConfigError::Message("runtime.thread_name_prefix must not be empty".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+impl MetricsConfiguration {
+    /// Gets the address metrics are sent to.
+    pub fn get_address(&self) -> Result<SocketAddr, ConfigError> {
+        self.address
+            .parse()
+            .map_err(|_| ConfigError::Message(format!("metrics.address '{}' is not a valid address", self.address)))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.metrics_type.to_lowercase() != "statsd" {
+            return Err(ConfigError::Message(format!("unknown metrics.type '{}'", self.metrics_type)));
+        }
+
+        self.get_address()?;
+        if self.flush_interval_ms == Some(0) {
+            return Err(ConfigError::Message("metrics.flush_interval_ms must be greater than zero".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Validates that a listener name is safe to use as an identifier.
+///
+/// Listener names show up in logs, metric names, and admin paths, so we hold them to a strict
+/// character set: 1 to 64 characters of ASCII letters, digits, underscores, and dashes.
+pub fn validate_listener_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_LISTENER_NAME_LEN {
+        return Err(format!(
+            "listener name '{}' must be between 1 and {} characters long",
+            name, MAX_LISTENER_NAME_LEN
+        ));
+    }
+
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-'))
+    {
+        return Err(format!(
+            "listener name '{}' contains invalid character {:?}; only [a-zA-Z0-9_-] are allowed",
+            name, c
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_listener_names() {
+        assert!(validate_listener_name("fixed").is_ok());
+        assert!(validate_listener_name("shadow_2").is_ok());
+        assert!(validate_listener_name("app-cache-EU").is_ok());
+        assert!(validate_listener_name(&"a".repeat(64)).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_listener_names() {
+        assert!(validate_listener_name("").is_err());
+        assert!(validate_listener_name(&"a".repeat(65)).is_err());
+        assert!(validate_listener_name("has space").is_err());
+        assert!(validate_listener_name("has/slash").is_err());
+        assert!(validate_listener_name("ünicode").is_err());
+        assert!(validate_listener_name("dotted.name").is_err());
+    }
+
+    fn get_listener_config(route_type: Option<&str>, pools: &[&str]) -> ListenerConfiguration {
+        let mut config = ListenerConfiguration::default();
+        if let Some(route_type) = route_type {
+            config.routing.insert("type".to_owned(), route_type.to_owned());
+        }
+        for pool in pools {
+            config.pools.insert(pool.to_string(), PoolConfiguration::default());
+        }
+        config
+    }
+
+    #[test]
+    fn test_runtime_validation() {
+        let mut config = Configuration::default();
+        config.runtime = Some(RuntimeConfiguration::default());
+        assert!(config.validate().is_ok());
+
+        config.runtime = Some(RuntimeConfiguration {
+            worker_threads: Some(4),
+            thread_name_prefix: Some("sync-".to_owned()),
+            max_blocking_threads: Some(16),
+        });
+        assert!(config.validate().is_ok());
+
+        let invalid = vec![
+            RuntimeConfiguration {
+                worker_threads: Some(0),
+                ..Default::default()
+            },
+            RuntimeConfiguration {
+                max_blocking_threads: Some(0),
+                ..Default::default()
+            },
+            RuntimeConfiguration {
+                thread_name_prefix: Some(String::new()),
+                ..Default::default()
+            },
+        ];
+        for runtime in invalid {
+            config.runtime = Some(runtime);
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_stats_addr() {
+        let mut config = Configuration::default();
+        assert_eq!(config.get_stats_addr().unwrap(), Some("0.0.0.0:16161".parse().unwrap()));
+        assert!(config.validate().is_ok());
+
+        // Only an explicitly empty address turns the server off.
+        config.stats_addr = Some("".to_owned());
+        assert_eq!(config.get_stats_addr().unwrap(), None);
+        assert!(config.validate().is_ok());
+
+        config.stats_addr = Some("127.0.0.1:16161".to_owned());
+        assert_eq!(config.get_stats_addr().unwrap(), Some("127.0.0.1:16161".parse().unwrap()));
+        assert!(config.validate().is_ok());
+
+        config.stats_addr = Some("localhost".to_owned());
+        assert!(config.get_stats_addr().is_err());
+        assert!(config.validate().is_err());
+
+        config.stats_addr = None;
+        config.stats_bind_retry_ms = Some(0);
+        assert!(config.validate().is_err());
+
+        config.stats_bind_retry_ms = None;
+        config.stats_history_interval_ms = Some(0);
+        assert!(config.validate().is_err());
+
+        config.stats_history_interval_ms = Some(500);
+        config.stats_history_secs = Some(0);
+        assert!(config.validate().is_err());
+
+        config.stats_history_secs = Some(60);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_metrics_validation() {
+        let mut config = Configuration::default();
+        config.metrics = Some(MetricsConfiguration {
+            metrics_type: "statsd".to_owned(),
+            address: "127.0.0.1:8125".to_owned(),
+            ..Default::default()
+        });
+        assert!(config.validate().is_ok());
+
+        let invalid = vec![
+            MetricsConfiguration {
+                metrics_type: "graphite".to_owned(),
+                address: "127.0.0.1:8125".to_owned(),
+                ..Default::default()
+            },
+            MetricsConfiguration {
+                metrics_type: "statsd".to_owned(),
+                address: "localhost".to_owned(),
+                ..Default::default()
+            },
+            MetricsConfiguration {
+                metrics_type: "statsd".to_owned(),
+                address: "127.0.0.1:8125".to_owned(),
+                flush_interval_ms: Some(0),
+                ..Default::default()
+            },
+        ];
+        for metrics in invalid {
+            config.metrics = Some(metrics);
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_empty_listeners() {
+        let mut config = Configuration::default();
+        assert!(config.check_listeners(false).is_err());
+        assert!(config.check_listeners(true).is_ok());
+
+        config
+            .listeners
+            .insert("fixed".to_owned(), get_listener_config(None, &["default"]));
+        assert!(config.check_listeners(false).is_ok());
+    }
+
+    #[test]
+    fn test_pool_secrets_redacted() {
+        let mut options = HashMap::new();
+        options.insert("redis_auth".to_owned(), "hunter2".to_owned());
+        options.insert("distribution".to_owned(), "modulo".to_owned());
+
+        let mut config = get_listener_config(None, &[]);
+        config.pools.insert(
+            "default".to_owned(),
+            PoolConfiguration {
+                options: PoolOptions::from_map(options).unwrap(),
+                ..Default::default()
+            },
+        );
+
+        let debugged = format!("{:?}", config);
+        assert!(!debugged.contains("hunter2"));
+        assert!(debugged.contains("modulo"));
+
+        let serialized = serde_json::to_string(&config).unwrap();
+        assert!(!serialized.contains("hunter2"));
+        assert!(serialized.contains("modulo"));
+
+        // Redacting the copy we print doesn't touch the value the pool is built from.
+        assert_eq!(config.pools["default"].options.secrets["redis_auth"].expose(), "hunter2");
+    }
+
+    fn get_fixture(name: &str) -> String { format!("{}/src/conf/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name) }
+
+    fn load_fixture(name: &str) -> Result<Configuration, ConfigError> {
+        let mut s = Config::new();
+        let loaded = merge_config_file(&mut s, &get_fixture(name))?.into_iter().collect::<Vec<_>>();
+        into_configuration(s, &loaded)
+    }
+
+    #[test]
+    fn test_config_formats() {
+        let canonical = |config: Configuration| serde_json::to_value(&config).unwrap().to_string();
+
+        let json = load_fixture("synchrotron.json").unwrap();
+        let pool = &json.listeners["fixed"].pools["default"];
+        assert_eq!(pool.options.timeout_ms, 250);
+        assert!(!pool.options.cooloff_enabled);
+        assert_eq!(pool.options.conns_per_backend, 2);
+        assert_eq!(pool.options.redis_db, Some(3));
+        assert_eq!(pool.addresses[1].weight, 50);
+
+        // Every format has to come out exactly the same, options and all.
+        let json = canonical(json);
+        assert_eq!(canonical(load_fixture("synchrotron.toml").unwrap()), json);
+        assert_eq!(canonical(load_fixture("synchrotron.yaml").unwrap()), json);
+
+        // Without an extension, the first format found is used.
+        assert_eq!(canonical(load_fixture("synchrotron").unwrap()), json);
+    }
+
+    #[test]
+    fn test_config_errors_name_file() {
+        let write_temp = |ext: &str, contents: &str| {
+            let path = env::temp_dir().join(format!("synchrotron-broken-{}.{}", std::process::id(), ext));
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+
+        let path = write_temp("yaml", "listeners: [\n");
+        let mut s = Config::new();
+        let result = merge_config_file(&mut s, path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains(&format!("'{}' (YAML)", path.display())));
+
+        // Files that load but don't make a valid configuration are named too.
+        let path = write_temp("json", r#"{"logging": {"level": "info"}}"#);
+        let mut s = Config::new();
+        let loaded = merge_config_file(&mut s, path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let error = into_configuration(s, &loaded.into_iter().collect::<Vec<_>>()).unwrap_err().to_string();
+        assert!(error.contains(&format!("invalid configuration from '{}' (JSON)", path.display())));
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let vars = vec![
+            ("HOME", "/root"),
+            ("SYNC__LISTENERS__FIXED__POOLS__DEFAULT__ADDRESSES", "10.0.0.1:6379, 10.0.0.2:6379 cache2"),
+            ("SYNC__LISTENERS__FIXED__POOLS__DEFAULT__OPTIONS__TIMEOUT_MS", "1000"),
+            ("SYNC__LISTENERS__FIXED__LATENCY_BUCKETS_US", "50,500"),
+            ("SYNC__LISTENERS__FIXED__ROUTING_HINTS", "false"),
+            ("SYNC__LISTENERS__FIXED__KEY_SAMPLE_RATE", "0.25"),
+            ("SYNC__LISTENERS__FIXED__PASSWORD", "007"),
+            ("SYNC__LOGGING__LEVEL", "debug"),
+        ];
+
+        let mut s = Config::new();
+        let loaded = merge_config_file(&mut s, &get_fixture("synchrotron.json"))
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let vars = vars.into_iter().map(|(var, value)| (var.to_owned(), value.to_owned()));
+        let overrides = merge_env_overrides(&mut s, vars).unwrap();
+
+        // Only the overrides are merged, in order of the variables' names.
+        assert_eq!(overrides.len(), 7);
+        assert_eq!(
+            overrides[6],
+            EnvOverride {
+                key: "logging.level".to_owned(),
+                var: "SYNC__LOGGING__LEVEL".to_owned(),
+            }
+        );
+
+        let config = into_configuration(s, &loaded).unwrap();
+        assert_eq!(config.logging.level, "debug");
+
+        let listener = &config.listeners["fixed"];
+        assert_eq!(listener.routing_hints, Some(false));
+        assert_eq!(listener.key_sample_rate, Some(0.25));
+        assert_eq!(listener.latency_buckets_us, Some(vec![50, 500]));
+        assert_eq!(listener.password.as_ref().map(|password| password.expose().as_str()), Some("007"));
+
+        // Overriding one key leaves everything around it as the files had it.
+        assert_eq!(listener.reload_timeout_ms, Some(2000));
+        let pool = &listener.pools["default"];
+        assert_eq!(pool.options.timeout_ms, 1000);
+        assert_eq!(pool.options.conns_per_backend, 2);
+        assert_eq!(pool.addresses.len(), 2);
+        assert_eq!(pool.addresses[0].address, "10.0.0.1:6379".parse::<SocketAddr>().unwrap());
+        assert_eq!(pool.addresses[1].identifier, "cache2");
+    }
+
+    #[test]
+    fn test_env_override_values() {
+        assert!(parse_env_value("true").into_bool().unwrap());
+        assert_eq!(parse_env_value("42").into_int().unwrap(), 42);
+        assert_eq!(parse_env_value("0.5").into_float().unwrap(), 0.5);
+
+        // Anything that wouldn't come back out as given stays a string.
+        for raw in &["007", "1.0", "+5", "True", "10.0.0.1:6379"] {
+            assert_eq!(parse_env_value(raw).into_str().unwrap(), *raw);
+        }
+
+        let mut s = Config::new();
+        let vars = vec![("SYNC__LISTENERS____ADDRESS".to_owned(), "127.0.0.1:6379".to_owned())];
+        assert!(merge_env_overrides(&mut s, vars).is_err());
     }
 }
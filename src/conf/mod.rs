@@ -20,7 +20,17 @@
 use slog::Level;
 
 mod config;
-pub use self::config::{Configuration, ListenerConfiguration, LoggingConfiguration, PoolConfiguration};
+pub use self::config::{
+    get_applied, log_env_overrides, set_applied, AppliedConfiguration, Configuration, ListenerConfiguration,
+    LoggingConfiguration, MetricsConfiguration, MigrationConfiguration, PoolConfiguration, RuntimeConfiguration,
+    TlsConfiguration,
+};
+
+mod pool_options;
+pub use self::pool_options::{accepted_pool_options, PoolOptions};
+
+mod secret;
+pub use self::secret::{Secret, Zeroize, REDACTED};
 
 mod backend_addr;
 pub use self::backend_addr::BackendAddress;
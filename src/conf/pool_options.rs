@@ -0,0 +1,574 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::secret::Secret;
+use errors::CreationError;
+use serde::{
+    de::{self, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{collections::HashMap, fmt, str::FromStr};
+
+/// The options whose values are names, paths or addresses.
+///
+/// These are kept in their raw form, and checked by whatever they configure.
+const OTHER_OPTIONS: &[&str] = &[
+    "audit_path",
+    "audit_socket",
+    "backend_bind_address",
+    "conn_selection",
+    "hash_tag",
+    "redis_auth_file",
+    "require_ttl_policy",
+    "warmup_keys_file",
+];
+
+/// The options that hold secrets.
+///
+/// These are turned into secrets as soon as they're parsed, and can each be given in a file
+/// instead, as the option of the same name with `_file` on the end.
+const SECRET_OPTIONS: &[&str] = &["redis_auth"];
+
+/// The options that make up `PoolOptions` itself.
+const TYPED_OPTIONS: &[&str] = &[
+    "allow_blocking",
+    "audit_hash_keys",
+    "audit_max_bytes",
+    "backend_max_connection_age_ms",
+    "backend_max_requests_per_connection",
+    "connect_timeout_ms",
+    "conns",
+    "conns_per_backend",
+    "cooloff_backoff_multiplier",
+    "cooloff_enabled",
+    "cooloff_error_limit",
+    "cooloff_max_timeout_ms",
+    "cooloff_timeout_ms",
+    "default_ttl_secs",
+    "demote_latency_ms",
+    "demote_sustain_secs",
+    "distribution",
+    "dns_refresh_ms",
+    "hash",
+    "health_check_eject_after",
+    "health_check_interval_ms",
+    "health_check_restore_after",
+    "health_check_saturation_depth",
+    "health_check_timeout_ms",
+    "lazy_connect_fail_fast",
+    "max_dedicated_conns",
+    "max_response_bytes",
+    "min_available_backends",
+    "random_seed",
+    "redis_db",
+    "require_backends_at_startup",
+    "require_ttl",
+    "retry_budget_ratio",
+    "startup_connect_timeout_ms",
+    "strict_placements",
+    "timeout_ms",
+    "track_hits",
+    "warmup_rate",
+    "warn_response_bytes",
+];
+
+/// The options of a pool.
+///
+/// Every number and flag is parsed up front, along with the configuration itself, so that a value
+/// that can't be parsed stops the configuration from loading at all.  Values can be given as
+/// strings or as the JSON type they hold.  Options that are left unset, where that means something
+/// other than a default, are `None`.  Options whose values are names, paths or addresses are kept
+/// in their raw form in `other`, and checked by whatever they configure.
+///
+/// `conns_per_backend` replaces the older `conns` option, which is still honored if it's the only
+/// one given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolOptions {
+    /// How long every request, batch and connection to a backend is given.  Zero means waiting
+    /// for as long as it takes.
+    pub timeout_ms: u64,
+    pub cooloff_enabled: bool,
+    pub cooloff_timeout_ms: u64,
+    pub cooloff_error_limit: usize,
+    pub conns_per_backend: usize,
+    pub lazy_connect_fail_fast: bool,
+    pub distribution: String,
+    pub hash: String,
+    pub track_hits: bool,
+    /// Whether blocking commands are run on a connection of their own, rather than being refused.
+    pub allow_blocking: bool,
+    /// The most connections of their own each backend's blocking commands can have open at once.
+    pub max_dedicated_conns: usize,
+    /// How long each attempt to connect to a backend is given, if not `timeout_ms`.
+    pub connect_timeout_ms: Option<u64>,
+    pub cooloff_backoff_multiplier: f64,
+    pub cooloff_max_timeout_ms: Option<u64>,
+    /// How often backend hostnames are resolved again.  Zero means they're only resolved once.
+    pub dns_refresh_ms: u64,
+    pub backend_max_connection_age_ms: Option<u64>,
+    pub backend_max_requests_per_connection: Option<u64>,
+    pub warn_response_bytes: Option<usize>,
+    pub max_response_bytes: Option<usize>,
+    pub redis_db: Option<u32>,
+    pub random_seed: Option<u64>,
+    pub strict_placements: bool,
+    pub retry_budget_ratio: f64,
+    pub require_backends_at_startup: bool,
+    pub startup_connect_timeout_ms: u64,
+    pub min_available_backends: Option<usize>,
+    pub require_ttl: bool,
+    pub default_ttl_secs: Option<u64>,
+    /// How often backends are health checked, if they're checked at all.
+    pub health_check_interval_ms: Option<u64>,
+    pub health_check_timeout_ms: Option<u64>,
+    pub health_check_eject_after: usize,
+    pub health_check_restore_after: usize,
+    pub health_check_saturation_depth: usize,
+    /// The p99 latency over which backends are demoted, if they're demoted at all.
+    pub demote_latency_ms: Option<u64>,
+    pub demote_sustain_secs: u64,
+    pub warmup_rate: usize,
+    pub audit_max_bytes: Option<usize>,
+    pub audit_hash_keys: bool,
+    /// The options that hold secrets, which are only ever shown redacted.
+    pub secrets: HashMap<String, Secret<String>>,
+    pub other: HashMap<String, String>,
+}
+
+impl PoolOptions {
+    /// Parses pool options from their raw form.
+    pub fn from_map(mut raw: HashMap<String, String>) -> Result<PoolOptions, String> {
+        let defaults = PoolOptions::default();
+        let conns = take_option(&mut raw, "conns", defaults.conns_per_backend)?;
+        let secrets = SECRET_OPTIONS
+            .iter()
+            .filter_map(|name| raw.remove(*name).map(|value| (name.to_string(), Secret::new(value))))
+            .collect();
+
+        Ok(PoolOptions {
+            timeout_ms: take_option(&mut raw, "timeout_ms", defaults.timeout_ms)?,
+            cooloff_enabled: take_option(&mut raw, "cooloff_enabled", defaults.cooloff_enabled)?,
+            cooloff_timeout_ms: take_option(&mut raw, "cooloff_timeout_ms", defaults.cooloff_timeout_ms)?,
+            cooloff_error_limit: take_option(&mut raw, "cooloff_error_limit", defaults.cooloff_error_limit)?,
+            conns_per_backend: take_option(&mut raw, "conns_per_backend", conns)?,
+            lazy_connect_fail_fast: take_option(&mut raw, "lazy_connect_fail_fast", defaults.lazy_connect_fail_fast)?,
+            distribution: raw.remove("distribution").unwrap_or(defaults.distribution),
+            hash: raw.remove("hash").unwrap_or(defaults.hash),
+            track_hits: take_option(&mut raw, "track_hits", defaults.track_hits)?,
+            allow_blocking: take_option(&mut raw, "allow_blocking", defaults.allow_blocking)?,
+            max_dedicated_conns: take_option(&mut raw, "max_dedicated_conns", defaults.max_dedicated_conns)?,
+            connect_timeout_ms: take_optional(&mut raw, "connect_timeout_ms")?,
+            cooloff_backoff_multiplier: take_option(
+                &mut raw,
+                "cooloff_backoff_multiplier",
+                defaults.cooloff_backoff_multiplier,
+            )?,
+            cooloff_max_timeout_ms: take_optional(&mut raw, "cooloff_max_timeout_ms")?,
+            dns_refresh_ms: take_option(&mut raw, "dns_refresh_ms", defaults.dns_refresh_ms)?,
+            backend_max_connection_age_ms: take_optional(&mut raw, "backend_max_connection_age_ms")?,
+            backend_max_requests_per_connection: take_optional(&mut raw, "backend_max_requests_per_connection")?,
+            warn_response_bytes: take_optional(&mut raw, "warn_response_bytes")?,
+            max_response_bytes: take_optional(&mut raw, "max_response_bytes")?,
+            redis_db: take_optional(&mut raw, "redis_db")?,
+            random_seed: take_optional(&mut raw, "random_seed")?,
+            strict_placements: take_option(&mut raw, "strict_placements", defaults.strict_placements)?,
+            retry_budget_ratio: take_option(&mut raw, "retry_budget_ratio", defaults.retry_budget_ratio)?,
+            require_backends_at_startup: take_option(
+                &mut raw,
+                "require_backends_at_startup",
+                defaults.require_backends_at_startup,
+            )?,
+            startup_connect_timeout_ms: take_option(
+                &mut raw,
+                "startup_connect_timeout_ms",
+                defaults.startup_connect_timeout_ms,
+            )?,
+            min_available_backends: take_optional(&mut raw, "min_available_backends")?,
+            require_ttl: take_option(&mut raw, "require_ttl", defaults.require_ttl)?,
+            default_ttl_secs: take_optional(&mut raw, "default_ttl_secs")?,
+            health_check_interval_ms: take_optional(&mut raw, "health_check_interval_ms")?,
+            health_check_timeout_ms: take_optional(&mut raw, "health_check_timeout_ms")?,
+            health_check_eject_after: take_option(
+                &mut raw,
+                "health_check_eject_after",
+                defaults.health_check_eject_after,
+            )?,
+            health_check_restore_after: take_option(
+                &mut raw,
+                "health_check_restore_after",
+                defaults.health_check_restore_after,
+            )?,
+            health_check_saturation_depth: take_option(
+                &mut raw,
+                "health_check_saturation_depth",
+                defaults.health_check_saturation_depth,
+            )?,
+            demote_latency_ms: take_optional(&mut raw, "demote_latency_ms")?,
+            demote_sustain_secs: take_option(&mut raw, "demote_sustain_secs", defaults.demote_sustain_secs)?,
+            warmup_rate: take_option(&mut raw, "warmup_rate", defaults.warmup_rate)?,
+            audit_max_bytes: take_optional(&mut raw, "audit_max_bytes")?,
+            audit_hash_keys: take_option(&mut raw, "audit_hash_keys", defaults.audit_hash_keys)?,
+            secrets,
+            other: raw,
+        })
+    }
+
+    /// Checks that every option is within the range it can be used in, for the pool of the given
+    /// name.
+    pub fn validate(&self, pool: &str) -> Result<(), CreationError> {
+        let invalid = if self.conns_per_backend == 0 {
+            Some("conns_per_backend")
+        } else if self.cooloff_enabled && self.cooloff_timeout_ms == 0 {
+            Some("cooloff_timeout_ms")
+        } else if self.cooloff_enabled && self.cooloff_error_limit == 0 {
+            Some("cooloff_error_limit")
+        } else if self.max_dedicated_conns == 0 {
+            Some("max_dedicated_conns")
+        } else {
+            None
+        };
+
+        match invalid {
+            Some(field) => Err(CreationError::InvalidParameter(format!("pools.{}.options.{}", pool, field))),
+            None => Ok(()),
+        }
+    }
+
+    /// Gets the secret option of the given name, given either inline, as `name`, or in a file, as
+    /// `name_file`.
+    pub fn secret(&self, name: &str) -> Result<Option<Secret<String>>, CreationError> {
+        let file = self.other.get(&format!("{}_file", name));
+        Secret::resolve(name, self.secrets.get(name), file.map(String::as_str))
+    }
+
+    /// Gets the names of any options that nothing in a pool understands, in order.
+    pub fn unknown_options(&self) -> Vec<&str> {
+        let mut unknown = self
+            .other
+            .keys()
+            .map(|name| name.as_str())
+            .filter(|name| !OTHER_OPTIONS.contains(name))
+            .collect::<Vec<_>>();
+        unknown.sort();
+        unknown
+    }
+}
+
+/// Gets the names of every option a pool understands, in order.
+pub fn accepted_pool_options() -> Vec<&'static str> {
+    let mut accepted = TYPED_OPTIONS
+        .iter()
+        .chain(SECRET_OPTIONS)
+        .chain(OTHER_OPTIONS)
+        .cloned()
+        .collect::<Vec<_>>();
+    accepted.sort();
+    accepted
+}
+
+impl Default for PoolOptions {
+    fn default() -> PoolOptions {
+        PoolOptions {
+            timeout_ms: 500,
+            cooloff_enabled: true,
+            cooloff_timeout_ms: 10000,
+            cooloff_error_limit: 5,
+            conns_per_backend: 1,
+            lazy_connect_fail_fast: false,
+            distribution: "modulo".to_owned(),
+            hash: "fnv1a_64".to_owned(),
+            track_hits: true,
+            allow_blocking: false,
+            max_dedicated_conns: 64,
+            connect_timeout_ms: None,
+            cooloff_backoff_multiplier: 2.0,
+            cooloff_max_timeout_ms: None,
+            dns_refresh_ms: 30_000,
+            backend_max_connection_age_ms: None,
+            backend_max_requests_per_connection: None,
+            warn_response_bytes: None,
+            max_response_bytes: None,
+            redis_db: None,
+            random_seed: None,
+            strict_placements: false,
+            retry_budget_ratio: 0.1,
+            require_backends_at_startup: false,
+            startup_connect_timeout_ms: 5000,
+            min_available_backends: None,
+            require_ttl: false,
+            default_ttl_secs: None,
+            health_check_interval_ms: None,
+            health_check_timeout_ms: None,
+            health_check_eject_after: 3,
+            health_check_restore_after: 2,
+            // A check has to have been stuck behind at least a full batch of requests to be put
+            // down to its backend being busy.
+            health_check_saturation_depth: 256,
+            demote_latency_ms: None,
+            demote_sustain_secs: 30,
+            warmup_rate: 1000,
+            audit_max_bytes: None,
+            audit_hash_keys: false,
+            secrets: HashMap::new(),
+            other: HashMap::new(),
+        }
+    }
+}
+
+impl Serialize for PoolOptions {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // Everything is serialized side by side, just as it's configured, with secrets redacted.
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("timeout_ms", &self.timeout_ms)?;
+        map.serialize_entry("cooloff_enabled", &self.cooloff_enabled)?;
+        map.serialize_entry("cooloff_timeout_ms", &self.cooloff_timeout_ms)?;
+        map.serialize_entry("cooloff_error_limit", &self.cooloff_error_limit)?;
+        map.serialize_entry("conns_per_backend", &self.conns_per_backend)?;
+        map.serialize_entry("lazy_connect_fail_fast", &self.lazy_connect_fail_fast)?;
+        map.serialize_entry("distribution", &self.distribution)?;
+        map.serialize_entry("hash", &self.hash)?;
+        map.serialize_entry("track_hits", &self.track_hits)?;
+        map.serialize_entry("allow_blocking", &self.allow_blocking)?;
+        map.serialize_entry("max_dedicated_conns", &self.max_dedicated_conns)?;
+        map.serialize_entry("cooloff_backoff_multiplier", &self.cooloff_backoff_multiplier)?;
+        map.serialize_entry("dns_refresh_ms", &self.dns_refresh_ms)?;
+        map.serialize_entry("strict_placements", &self.strict_placements)?;
+        map.serialize_entry("retry_budget_ratio", &self.retry_budget_ratio)?;
+        map.serialize_entry("require_backends_at_startup", &self.require_backends_at_startup)?;
+        map.serialize_entry("startup_connect_timeout_ms", &self.startup_connect_timeout_ms)?;
+        map.serialize_entry("require_ttl", &self.require_ttl)?;
+        map.serialize_entry("health_check_eject_after", &self.health_check_eject_after)?;
+        map.serialize_entry("health_check_restore_after", &self.health_check_restore_after)?;
+        map.serialize_entry("health_check_saturation_depth", &self.health_check_saturation_depth)?;
+        map.serialize_entry("demote_sustain_secs", &self.demote_sustain_secs)?;
+        map.serialize_entry("warmup_rate", &self.warmup_rate)?;
+        map.serialize_entry("audit_hash_keys", &self.audit_hash_keys)?;
+
+        // Options that are left unset are left out, rather than shown as nulls.
+        let unset = [
+            ("connect_timeout_ms", self.connect_timeout_ms),
+            ("cooloff_max_timeout_ms", self.cooloff_max_timeout_ms),
+            ("backend_max_connection_age_ms", self.backend_max_connection_age_ms),
+            ("backend_max_requests_per_connection", self.backend_max_requests_per_connection),
+            ("warn_response_bytes", self.warn_response_bytes.map(|n| n as u64)),
+            ("max_response_bytes", self.max_response_bytes.map(|n| n as u64)),
+            ("redis_db", self.redis_db.map(u64::from)),
+            ("random_seed", self.random_seed),
+            ("min_available_backends", self.min_available_backends.map(|n| n as u64)),
+            ("default_ttl_secs", self.default_ttl_secs),
+            ("health_check_interval_ms", self.health_check_interval_ms),
+            ("health_check_timeout_ms", self.health_check_timeout_ms),
+            ("demote_latency_ms", self.demote_latency_ms),
+            ("audit_max_bytes", self.audit_max_bytes.map(|n| n as u64)),
+        ];
+        for (name, value) in unset.iter().filter_map(|(name, value)| value.map(|value| (name, value))) {
+            map.serialize_entry(name, &value)?;
+        }
+
+        for (name, value) in &self.secrets {
+            map.serialize_entry(name, value)?;
+        }
+        for (name, value) in &self.other {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for PoolOptions {
+    fn deserialize<D>(deserializer: D) -> Result<PoolOptions, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = HashMap::<String, OptionValue>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(name, value)| (name, value.0))
+            .collect();
+        PoolOptions::from_map(raw).map_err(de::Error::custom)
+    }
+}
+
+/// Removes an option from the given raw options and parses it, or gets the default if it's missing.
+fn take_option<T: FromStr>(raw: &mut HashMap<String, String>, name: &str, default: T) -> Result<T, String> {
+    match raw.remove(name) {
+        Some(value) => {
+            T::from_str(value.as_str()).map_err(|_| format!("pool option '{}' has an invalid value: '{}'", name, value))
+        },
+        None => Ok(default),
+    }
+}
+
+/// Removes an option from the given raw options and parses it, if it's there at all.
+fn take_optional<T: FromStr>(raw: &mut HashMap<String, String>, name: &str) -> Result<Option<T>, String> {
+    match raw.remove(name) {
+        Some(value) => T::from_str(value.as_str())
+            .map(Some)
+            .map_err(|_| format!("pool option '{}' has an invalid value: '{}'", name, value)),
+        None => Ok(None),
+    }
+}
+
+/// The raw value of an option, which may be given as a string, a number, or a boolean.
+struct OptionValue(String);
+
+impl<'de> Deserialize<'de> for OptionValue {
+    fn deserialize<D>(deserializer: D) -> Result<OptionValue, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(OptionValueVisitor)
+    }
+}
+
+struct OptionValueVisitor;
+
+impl<'de> Visitor<'de> for OptionValueVisitor {
+    type Value = OptionValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("a string, number, or boolean") }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<OptionValue, E> { Ok(OptionValue(value.to_owned())) }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<OptionValue, E> { Ok(OptionValue(value)) }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<OptionValue, E> { Ok(OptionValue(value.to_string())) }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<OptionValue, E> { Ok(OptionValue(value.to_string())) }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<OptionValue, E> { Ok(OptionValue(value.to_string())) }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<OptionValue, E> { Ok(OptionValue(value.to_string())) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let options: PoolOptions = serde_json::from_str("{}").unwrap();
+        assert_eq!(options, PoolOptions::default());
+        assert!(options.validate("default").is_ok());
+    }
+
+    #[test]
+    fn test_strings_and_numbers() {
+        let raw = r#"{"timeout_ms": "250", "cooloff_timeout_ms": 2000, "cooloff_enabled": false, "redis_db": 3}"#;
+        let options: PoolOptions = serde_json::from_str(raw).unwrap();
+        assert_eq!(options.timeout_ms, 250);
+        assert_eq!(options.cooloff_timeout_ms, 2000);
+        assert!(!options.cooloff_enabled);
+        assert_eq!(options.redis_db, Some(3));
+        assert!(options.other.is_empty());
+
+        let result = serde_json::from_str::<PoolOptions>(r#"{"timeout_ms": "soon"}"#);
+        assert!(result.unwrap_err().to_string().contains("pool option 'timeout_ms'"));
+        assert!(serde_json::from_str::<PoolOptions>(r#"{"timeout_ms": -1}"#).is_err());
+        assert!(serde_json::from_str::<PoolOptions>(r#"{"dns_refresh_ms": "often"}"#).is_err());
+        assert!(serde_json::from_str::<PoolOptions>(r#"{"connect_timeout_ms": 1.5}"#).is_err());
+        assert!(serde_json::from_str::<PoolOptions>(r#"{"strict_placements": "maybe"}"#).is_err());
+    }
+
+    #[test]
+    fn test_unset_options() {
+        let options = PoolOptions::default();
+        assert_eq!(options.connect_timeout_ms, None);
+        let serialized = serde_json::to_value(&options).unwrap();
+        assert!(serialized.get("connect_timeout_ms").is_none());
+        assert_eq!(serialized["dns_refresh_ms"], 30_000);
+
+        let raw = r#"{"connect_timeout_ms": 50, "max_dedicated_conns": "8", "retry_budget_ratio": 0.25}"#;
+        let options: PoolOptions = serde_json::from_str(raw).unwrap();
+        assert_eq!(options.connect_timeout_ms, Some(50));
+        assert_eq!(options.max_dedicated_conns, 8);
+        assert_eq!(options.retry_budget_ratio, 0.25);
+        assert_eq!(serde_json::to_value(&options).unwrap()["connect_timeout_ms"], 50);
+    }
+
+    #[test]
+    fn test_legacy_conns() {
+        let options: PoolOptions = serde_json::from_str(r#"{"conns": "3"}"#).unwrap();
+        assert_eq!(options.conns_per_backend, 3);
+
+        let options: PoolOptions = serde_json::from_str(r#"{"conns": "3", "conns_per_backend": "4"}"#).unwrap();
+        assert_eq!(options.conns_per_backend, 4);
+        assert!(options.other.is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let options: PoolOptions = serde_json::from_str(r#"{"conns_per_backend": 0}"#).unwrap();
+        match options.validate("default") {
+            Err(CreationError::InvalidParameter(field)) => assert_eq!(field, "pools.default.options.conns_per_backend"),
+            other => panic!("expected an invalid parameter, got {:?}", other),
+        }
+
+        // Cooloff settings only matter when cooloff is enabled.
+        let options: PoolOptions = serde_json::from_str(r#"{"cooloff_timeout_ms": 0}"#).unwrap();
+        assert!(options.validate("default").is_err());
+        let raw = r#"{"cooloff_timeout_ms": 0, "cooloff_enabled": false}"#;
+        let options: PoolOptions = serde_json::from_str(raw).unwrap();
+        assert!(options.validate("default").is_ok());
+
+        let options: PoolOptions = serde_json::from_str(r#"{"max_dedicated_conns": 0}"#).unwrap();
+        assert!(options.validate("default").is_err());
+    }
+
+    #[test]
+    fn test_unknown_options() {
+        let raw = r#"{"timeout_ms": 100, "redis_auth": "hunter2", "timout_ms": 100, "conn_selction": "round_robin"}"#;
+        let options: PoolOptions = serde_json::from_str(raw).unwrap();
+        assert_eq!(options.unknown_options(), vec!["conn_selction", "timout_ms"]);
+
+        let accepted = accepted_pool_options();
+        assert!(accepted.contains(&"timeout_ms"));
+        assert!(accepted.contains(&"redis_auth"));
+        assert!(!accepted.contains(&"timout_ms"));
+    }
+
+    #[test]
+    fn test_secrets_redacted() {
+        let options: PoolOptions = serde_json::from_str(r#"{"redis_auth": "hunter2"}"#).unwrap();
+        assert_eq!(options.secrets["redis_auth"].expose(), "hunter2");
+        assert!(options.other.is_empty());
+
+        let serialized = serde_json::to_value(&options).unwrap();
+        assert_eq!(serialized["redis_auth"], "<redacted>");
+        assert_eq!(serialized["timeout_ms"], 500);
+        assert!(!format!("{:?}", options).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_secret() {
+        let options: PoolOptions = serde_json::from_str(r#"{"redis_auth": "hunter2"}"#).unwrap();
+        assert_eq!(options.secret("redis_auth").unwrap().unwrap().expose(), "hunter2");
+        assert!(PoolOptions::default().secret("redis_auth").unwrap().is_none());
+
+        // Giving a secret both ways is a mistake, and the error for it mustn't give the secret away.
+        let raw = r#"{"redis_auth": "hunter2", "redis_auth_file": "/nonexistent"}"#;
+        let options: PoolOptions = serde_json::from_str(raw).unwrap();
+        let e = options.secret("redis_auth").unwrap_err();
+        assert!(!e.to_string().contains("hunter2"));
+        assert!(!format!("{:?}", e).contains("hunter2"));
+    }
+}
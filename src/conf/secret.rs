@@ -0,0 +1,193 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use errors::CreationError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt, fs, ptr,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+/// What a secret looks like whenever it's printed, logged, or serialized.
+pub const REDACTED: &str = "<redacted>";
+
+/// Something that can overwrite its own memory.
+pub trait Zeroize {
+    fn zeroize(&mut self);
+}
+
+impl Zeroize for String {
+    fn zeroize(&mut self) {
+        // Zeroes are valid UTF-8, so the string is still a string when we're done with it.
+        unsafe { zero_bytes(self.as_mut_vec()) };
+        self.clear();
+    }
+}
+
+impl Zeroize for Vec<u8> {
+    fn zeroize(&mut self) {
+        zero_bytes(self);
+        self.clear();
+    }
+}
+
+fn zero_bytes(bytes: &mut [u8]) {
+    // Volatile writes, so the compiler can't decide that nobody looks at these bytes again and
+    // skip writing them.
+    for byte in bytes.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// A configuration value that must never show up in logs, errors, or the admin API.
+///
+/// Formatting a secret, with either `Debug` or `Display`, or serializing it, only ever gives
+/// `<redacted>`, so a secret can sit in a configuration structure that's dumped or logged without
+/// leaking.  The value itself is only available through `expose`, and is zeroed once the secret is
+/// dropped.  Copies made while parsing the configuration, before the value became a secret, aren't
+/// ours to zero.
+#[derive(Clone, Default, PartialEq)]
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Secret<T> { Secret(value) }
+
+    /// Gets the value of the secret.
+    ///
+    /// Whatever it's used for, the value shouldn't be copied anywhere that outlives that use.
+    pub fn expose(&self) -> &T { &self.0 }
+}
+
+impl Secret<String> {
+    /// Reads a secret from the given file.
+    ///
+    /// Trailing newlines are trimmed, since most ways of writing a secret to a file add one.
+    pub fn from_file(path: &str) -> Result<Secret<String>, CreationError> {
+        let mut value = fs::read_to_string(path)
+            .map(Secret::new)
+            .map_err(|e| CreationError::InvalidResource(format!("failed to read secret from '{}': {}", path, e)))?;
+
+        let len = value.0.trim_end_matches(|c| c == '\n' || c == '\r').len();
+        value.0.truncate(len);
+        if value.0.is_empty() {
+            return Err(CreationError::InvalidResource(format!("secret file '{}' is empty", path)));
+        }
+
+        Ok(value)
+    }
+
+    /// Resolves a secret that can be given either inline, as `name`, or in a file, as `name_file`.
+    ///
+    /// Files are read every time this is called, so a secret kept in a file can be rotated by
+    /// updating the file and reloading.
+    pub fn resolve(
+        name: &str, inline: Option<&Secret<String>>, file: Option<&str>,
+    ) -> Result<Option<Secret<String>>, CreationError> {
+        match (inline, file) {
+            (Some(_), Some(_)) => Err(CreationError::InvalidParameter(format!(
+                "{} and {}_file can't both be set",
+                name, name
+            ))),
+            (Some(secret), None) => Ok(Some(secret.clone())),
+            (None, Some(path)) => Secret::from_file(path).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) { self.0.zeroize(); }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(REDACTED) }
+}
+
+impl<T: Zeroize> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(REDACTED) }
+}
+
+impl<T: Zeroize> Serialize for Secret<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+impl<'de, T: Zeroize + Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Secret<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        T::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn test_secret_redacted() {
+        let secret = Secret::new("hunter2".to_owned());
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(format!("{:?}", secret), REDACTED);
+        assert_eq!(format!("{}", secret), REDACTED);
+        assert_eq!(serde_json::to_string(&secret).unwrap(), "\"<redacted>\"");
+
+        let secret: Secret<String> = serde_json::from_str("\"hunter2\"").unwrap();
+        assert_eq!(secret.expose(), "hunter2");
+    }
+
+    #[test]
+    fn test_zero_bytes() {
+        let mut bytes = b"hunter2".to_vec();
+        zero_bytes(&mut bytes);
+        assert_eq!(bytes, vec![0; 7]);
+
+        let mut value = "hunter2".to_owned();
+        value.zeroize();
+        assert!(value.is_empty());
+    }
+
+    #[test]
+    fn test_secret_from_file() {
+        let path = env::temp_dir().join(format!("synchrotron-secret-{}", process::id()));
+        let path = path.to_str().unwrap().to_owned();
+
+        fs::write(&path, "hunter2\n").unwrap();
+        let secret = Secret::resolve("redis_auth", None, Some(&path)).unwrap().unwrap();
+        assert_eq!(secret.expose(), "hunter2");
+
+        // Files are read again every time, which is what lets secrets be rotated.
+        fs::write(&path, "correct horse\r\n").unwrap();
+        let secret = Secret::resolve("redis_auth", None, Some(&path)).unwrap().unwrap();
+        assert_eq!(secret.expose(), "correct horse");
+
+        fs::write(&path, "\n").unwrap();
+        assert!(Secret::resolve("redis_auth", None, Some(&path)).is_err());
+
+        fs::remove_file(&path).unwrap();
+        assert!(Secret::resolve("redis_auth", None, Some(&path)).is_err());
+    }
+}
@@ -17,7 +17,8 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use std::fmt;
+use libc;
+use std::{fmt, io};
 
 #[derive(Debug)]
 pub enum CreationError {
@@ -30,6 +31,9 @@ pub enum CreationError {
 
     /// When a listener fails to get created during the launch/reload phase.
     ListenerSpawnFailed,
+
+    /// When a pool that requires its backends at startup can't reach enough of them.
+    BackendsUnreachable(String),
 }
 
 impl fmt::Display for CreationError {
@@ -38,6 +42,58 @@ impl fmt::Display for CreationError {
             CreationError::InvalidParameter(param) => write!(f, "invalid parameter: {}", param.as_str()),
             CreationError::InvalidResource(s) => write!(f, "invalid resource: {}", s.as_str()),
             CreationError::ListenerSpawnFailed => write!(f, "listener spawn failed"),
+            CreationError::BackendsUnreachable(s) => write!(f, "backends unreachable: {}", s.as_str()),
+        }
+    }
+}
+
+/// Why a listener failed to start.
+///
+/// These are kept apart so that whatever started us can tell a failure that's likely to go away on
+/// its own, like an address still held by an instance that's draining, from one that won't.
+#[derive(Debug)]
+pub enum ListenerStartError {
+    /// The listen address couldn't be bound, usually because something else is already using it.
+    Bind(io::Error),
+
+    /// The listener couldn't be built from its configuration.
+    Construct(CreationError),
+
+    /// We ran out of something the operating system only gives us so much of, like file descriptors.
+    ResourceLimit(io::Error),
+}
+
+impl ListenerStartError {
+    /// Classifies an error from binding a listen address.
+    pub fn from_bind_error(e: io::Error) -> ListenerStartError {
+        match e.raw_os_error() {
+            Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => {
+                ListenerStartError::ResourceLimit(e)
+            },
+            _ => ListenerStartError::Bind(e),
+        }
+    }
+
+    /// Gets a short name for the kind of failure, suitable for logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ListenerStartError::Bind(_) => "bind",
+            ListenerStartError::Construct(_) => "construct",
+            ListenerStartError::ResourceLimit(_) => "resource_limit",
+        }
+    }
+}
+
+impl From<CreationError> for ListenerStartError {
+    fn from(e: CreationError) -> ListenerStartError { ListenerStartError::Construct(e) }
+}
+
+impl fmt::Display for ListenerStartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ListenerStartError::Bind(e) => write!(f, "failed to bind listen address: {}", e),
+            ListenerStartError::Construct(e) => write!(f, "{}", e),
+            ListenerStartError::ResourceLimit(e) => write!(f, "resource limit reached: {}", e),
         }
     }
 }
@@ -0,0 +1,396 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use metrics::{get_sink, MetricSink};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+use util::clock::unix_timestamp_ms;
+
+// How many events a subscriber can fall behind by before events are dropped for it.
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+// How many of the most recent events are kept for the stats endpoint.
+const RECENT_EVENTS_CAPACITY: usize = 1024;
+
+lazy_static! {
+    static ref EVENTS: EventBus = {
+        let bus = EventBus::new();
+        spawn_subscriber(&bus, "log", log_event);
+        spawn_subscriber(&bus, "metrics", {
+            let sink = get_sink().scoped("events");
+            move |event: &LifecycleEvent| count_event(&sink, event)
+        });
+        spawn_subscriber(&bus, "recent", |event| RECENT.push(event.clone()));
+        bus
+    };
+    static ref RECENT: RecentEvents = RecentEvents::new(RECENT_EVENTS_CAPACITY);
+}
+
+/// Publishes an event to the global event bus.
+pub fn publish(kind: EventKind, pool: Option<&str>, backend: Option<&str>, detail: String) {
+    EVENTS.publish(kind, pool, backend, detail);
+}
+
+/// Gets the recent events published after the event with the given ID, oldest first.
+pub fn get_recent_events(since: u64) -> Vec<LifecycleEvent> { RECENT.since(since) }
+
+/// Something that happened to a pool, a backend, or a listener.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A backend failed enough health checks to be ejected from its pool.
+    BackendEjected,
+
+    /// An ejected backend passed enough health checks to be restored to its pool.
+    BackendRestored,
+
+    /// A backend had enough errors to be put into cooloff.
+    CooloffStarted,
+
+    /// A backend came out of cooloff.
+    CooloffEnded,
+
+    /// A backend was slow for long enough to have its weight halved.
+    BackendDemoted,
+
+    /// A demoted backend was fast for long enough to have its weight doubled back up.
+    BackendPromoted,
+
+    /// A backend was given a weight of zero so that it can be removed.
+    DrainStarted,
+
+    /// A draining backend went quiet, and is safe to remove.
+    DrainCompleted,
+
+    /// A backend stopped draining before it went quiet.
+    DrainCancelled,
+
+    /// Listeners were launched, or relaunched, from the configuration.
+    ListenersReloaded,
+
+    /// Listeners couldn't be relaunched from the configuration.
+    ReloadFailed,
+}
+
+impl EventKind {
+    /// Gets the name of this kind of event, as it's logged and counted.
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::BackendEjected => "backend_ejected",
+            EventKind::BackendRestored => "backend_restored",
+            EventKind::CooloffStarted => "cooloff_started",
+            EventKind::CooloffEnded => "cooloff_ended",
+            EventKind::BackendDemoted => "backend_demoted",
+            EventKind::BackendPromoted => "backend_promoted",
+            EventKind::DrainStarted => "drain_started",
+            EventKind::DrainCompleted => "drain_completed",
+            EventKind::DrainCancelled => "drain_cancelled",
+            EventKind::ListenersReloaded => "listeners_reloaded",
+            EventKind::ReloadFailed => "reload_failed",
+        }
+    }
+}
+
+/// An event, as published to the event bus.
+///
+/// Events are numbered, starting at one, in the order they were published.
+#[derive(Clone, Debug, Serialize)]
+pub struct LifecycleEvent {
+    pub id: u64,
+    pub kind: EventKind,
+    pub pool: Option<String>,
+    pub backend: Option<String>,
+    pub timestamp_ms: u64,
+    pub detail: String,
+}
+
+impl fmt::Display for LifecycleEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind.name())?;
+        if let Some(ref pool) = self.pool {
+            write!(f, " pool '{}'", pool)?;
+        }
+        if let Some(ref backend) = self.backend {
+            write!(f, " backend '{}'", backend)?;
+        }
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+struct Subscriber {
+    tx: SyncSender<LifecycleEvent>,
+    dropped: Arc<AtomicUsize>,
+}
+
+/// Hands out lifecycle events to everyone who's subscribed to them.
+///
+/// Things like backends being ejected, demoted, or drained, and listeners being reloaded, are
+/// published as they happen.  Every subscriber gets its own copy of each event: globally, they're
+/// logged, counted, and kept around so that the most recent ones can be looked at from the stats
+/// endpoint.
+///
+/// Publishing never blocks: every subscriber has a bounded queue, and if a subscriber has fallen
+/// so far behind that its queue is full, the event is dropped for that subscriber, and counted,
+/// rather than waiting on it.
+pub struct EventBus {
+    subscribers: Mutex<Vec<Subscriber>>,
+    next_id: AtomicUsize,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            subscribers: Mutex::new(Vec::new()),
+            next_id: AtomicUsize::new(1),
+        }
+    }
+
+    /// Subscribes to every event published from now on.
+    ///
+    /// Up to `capacity` events are held for the subscriber while it catches up.
+    pub fn subscribe(&self, capacity: usize) -> Subscription {
+        let (tx, rx) = sync_channel(capacity);
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push(Subscriber {
+            tx,
+            dropped: dropped.clone(),
+        });
+
+        Subscription { rx, dropped }
+    }
+
+    /// Publishes an event to every subscriber, returning its ID.
+    pub fn publish(&self, kind: EventKind, pool: Option<&str>, backend: Option<&str>, detail: String) -> u64 {
+        let event = LifecycleEvent {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) as u64,
+            kind,
+            pool: pool.map(str::to_owned),
+            backend: backend.map(str::to_owned),
+            timestamp_ms: unix_timestamp_ms(),
+            detail,
+        };
+
+        // Subscribers that have gone away are forgotten about.
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| {
+            match subscriber.tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+                    true
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+
+        event.id
+    }
+}
+
+/// The events published to an event bus since subscribing to it.
+pub struct Subscription {
+    rx: Receiver<LifecycleEvent>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Subscription {
+    /// Waits for the next event, returning `None` once the event bus is gone.
+    pub fn recv(&self) -> Option<LifecycleEvent> { self.rx.recv().ok() }
+
+    /// Gets the next event, if there's one waiting.
+    pub fn try_recv(&self) -> Option<LifecycleEvent> { self.rx.try_recv().ok() }
+
+    /// Gets how many events have been dropped for this subscriber since this was last called.
+    pub fn take_dropped(&self) -> usize { self.dropped.swap(0, Ordering::Relaxed) }
+}
+
+/// Subscribes to the given event bus, handing every event to `handler` on a thread of its own.
+///
+/// Events that were dropped for the subscriber are counted under `events.<name>.dropped`.
+fn spawn_subscriber<F>(bus: &EventBus, name: &'static str, handler: F)
+where
+    F: Fn(&LifecycleEvent) + Send + 'static,
+{
+    let subscription = bus.subscribe(SUBSCRIBER_CAPACITY);
+    let sink = get_sink().scoped(&["events", name]);
+
+    thread::spawn(move || {
+        while let Some(event) = subscription.recv() {
+            let dropped = subscription.take_dropped();
+            if dropped > 0 {
+                sink.update_count("dropped", dropped as i64);
+            }
+
+            handler(&event);
+        }
+    });
+}
+
+/// Logs the given event, with its kind, pool, and backend attached to the log line.
+fn log_event(event: &LifecycleEvent) {
+    let logger = slog_scope::logger().new(slog_o!(
+        "event" => event.kind.name(),
+        "event_id" => event.id,
+        "pool" => event.pool.clone().unwrap_or_default(),
+        "backend" => event.backend.clone().unwrap_or_default()
+    ));
+    slog_scope::scope(&logger, || info!("[events] {}", event));
+}
+
+/// Counts the given event by its kind.
+fn count_event(sink: &MetricSink, event: &LifecycleEvent) { sink.increment(event.kind.name()) }
+
+/// The most recent events, up to a limit.
+pub struct RecentEvents {
+    capacity: usize,
+    events: Mutex<VecDeque<LifecycleEvent>>,
+}
+
+impl RecentEvents {
+    pub fn new(capacity: usize) -> RecentEvents {
+        RecentEvents {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Adds an event, making room for it by forgetting the oldest event if need be.
+    pub fn push(&self, event: LifecycleEvent) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Gets the events after the event with the given ID, oldest first.
+    pub fn since(&self, id: u64) -> Vec<LifecycleEvent> {
+        let events = self.events.lock().unwrap();
+        events.iter().filter(|event| event.id > id).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Publishes the same sequence of events every time.
+    fn publish_script(bus: &EventBus) {
+        bus.publish(EventKind::DrainStarted, Some("default"), Some("127.0.0.1:6379"), String::new());
+        bus.publish(
+            EventKind::BackendEjected,
+            Some("default"),
+            Some("127.0.0.1:6380"),
+            "failed 3 checks in a row".to_owned(),
+        );
+        bus.publish(EventKind::DrainCompleted, Some("default"), Some("127.0.0.1:6379"), String::new());
+        bus.publish(EventKind::ListenersReloaded, None, None, "token 1".to_owned());
+    }
+
+    fn drain(subscription: &Subscription) -> Vec<LifecycleEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = subscription.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn test_every_subscriber_gets_every_event() {
+        let bus = EventBus::new();
+        let log = bus.subscribe(16);
+        let recent = bus.subscribe(16);
+
+        publish_script(&bus);
+
+        let lines = drain(&log).iter().map(|event| event.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "drain_started pool 'default' backend '127.0.0.1:6379'",
+                "backend_ejected pool 'default' backend '127.0.0.1:6380': failed 3 checks in a row",
+                "drain_completed pool 'default' backend '127.0.0.1:6379'",
+                "listeners_reloaded: token 1",
+            ]
+        );
+
+        let buffer = RecentEvents::new(16);
+        for event in drain(&recent) {
+            buffer.push(event);
+        }
+        let ids = buffer.since(0).iter().map(|event| event.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+
+        let kinds = buffer.since(2).iter().map(|event| event.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![EventKind::DrainCompleted, EventKind::ListenersReloaded]);
+        assert!(buffer.since(4).is_empty());
+    }
+
+    #[test]
+    fn test_slow_subscribers_drop_events() {
+        let bus = EventBus::new();
+        let slow = bus.subscribe(2);
+        let fast = bus.subscribe(16);
+
+        publish_script(&bus);
+
+        // The slow subscriber keeps what it had room for, and finds out how much it missed.
+        let kinds = drain(&slow).iter().map(|event| event.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![EventKind::DrainStarted, EventKind::BackendEjected]);
+        assert_eq!(slow.take_dropped(), 2);
+        assert_eq!(slow.take_dropped(), 0);
+
+        // Nobody else misses out because of it.
+        assert_eq!(drain(&fast).len(), 4);
+        assert_eq!(fast.take_dropped(), 0);
+
+        // Once a subscriber goes away, it's forgotten about.
+        drop(slow);
+        bus.publish(EventKind::ReloadFailed, None, None, String::new());
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_recent_events_are_bounded() {
+        let bus = EventBus::new();
+        let subscription = bus.subscribe(16);
+        publish_script(&bus);
+
+        let buffer = RecentEvents::new(3);
+        for event in drain(&subscription) {
+            buffer.push(event);
+        }
+
+        let ids = buffer.since(0).iter().map(|event| event.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![2, 3, 4]);
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use libc;
+use std::{
+    collections::HashMap,
+    env,
+    fs::File,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT},
+        Mutex,
+    },
+    thread,
+};
+
+// Tells a new process which listening sockets it inherited, as comma-separated `address=fd` pairs.
+const LISTEN_FDS_VAR: &str = "SYNCHROTRON_LISTEN_FDS";
+
+// Tells a new process which file descriptor to signal on once its listeners are up.
+const READY_FD_VAR: &str = "SYNCHROTRON_READY_FD";
+
+// How long a new process has to get its listeners up before we give up on it.
+const HANDOFF_TIMEOUT_MS: i32 = 60_000;
+
+lazy_static! {
+    static ref INHERITED: Mutex<HashMap<SocketAddr, RawFd>> = Mutex::new(HashMap::new());
+    static ref READY: Mutex<Option<File>> = Mutex::new(None);
+    static ref LISTENING: Mutex<Vec<(usize, SocketAddr, RawFd)>> = Mutex::new(Vec::new());
+}
+
+static NEXT_REGISTRATION_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+static HANDING_OFF: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// A listening socket that can be handed off to a new process, until dropped.
+pub struct HandoffRegistration {
+    id: usize,
+}
+
+impl Drop for HandoffRegistration {
+    fn drop(&mut self) {
+        let mut listening = LISTENING.lock().unwrap();
+        listening.retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+/// Registers a listening socket as one to hand off to a new process.
+///
+/// Newer versions of a listener replace older ones, so when more than one socket is bound to the
+/// same address, the one registered last is handed off.
+pub fn register_listener<L: AsRawFd>(addr: SocketAddr, listener: &L) -> HandoffRegistration {
+    let id = NEXT_REGISTRATION_ID.fetch_add(1, Ordering::Relaxed);
+    LISTENING.lock().unwrap().push((id, addr, listener.as_raw_fd()));
+    HandoffRegistration { id }
+}
+
+/// Picks up whatever the process we're taking over from handed us, if it started us.
+///
+/// The listening sockets are held on to until a listener bound to the same address asks for them.
+pub fn inherit() {
+    if let Ok(raw) = env::var(LISTEN_FDS_VAR) {
+        let mut inherited = INHERITED.lock().unwrap();
+        for (addr, fd) in parse_listen_fds(&raw) {
+            set_cloexec(fd);
+            inherited.insert(addr, fd);
+        }
+        info!("[handoff] inherited {} listening socket(s)", inherited.len());
+    }
+
+    if let Some(fd) = env::var(READY_FD_VAR).ok().and_then(|raw| raw.parse().ok()) {
+        set_cloexec(fd);
+        *READY.lock().unwrap() = Some(unsafe { File::from_raw_fd(fd) });
+    }
+
+    // Anything we start ourselves gets its own handoff, not ours.
+    env::remove_var(LISTEN_FDS_VAR);
+    env::remove_var(READY_FD_VAR);
+}
+
+/// Takes the listening socket we inherited for the given address, if there is one.
+pub fn take_listener(addr: &SocketAddr) -> Option<TcpListener> {
+    let fd = INHERITED.lock().unwrap().remove(addr)?;
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    match listener.local_addr() {
+        Ok(ref local) if local == addr => Some(listener),
+        _ => {
+            warn!("[handoff] inherited socket for {} isn't listening on it, binding a new one", addr);
+            None
+        },
+    }
+}
+
+/// Tells the process we're taking over from that our listeners are up, so that it can stop
+/// accepting clients.
+///
+/// Any sockets we inherited that no listener asked for are closed, since nothing would ever accept
+/// on them.
+pub fn signal_ready() {
+    for (addr, fd) in INHERITED.lock().unwrap().drain() {
+        debug!("[handoff] closing inherited socket for {}: no listener is bound to it", addr);
+        drop(unsafe { TcpListener::from_raw_fd(fd) });
+    }
+
+    if let Some(mut ready) = READY.lock().unwrap().take() {
+        if let Err(e) = ready.write_all(b"1") {
+            warn!("[handoff] failed to tell the previous process that we're ready: {}", e);
+        }
+    }
+}
+
+/// Starts a new copy of ourselves, handing it our listening sockets.
+///
+/// Once it signals that its listeners are up, `on_ready` is called, which is where we stop
+/// accepting clients and shut down.  If it fails to start, or doesn't get its listeners up in
+/// time, we carry on as we were.
+pub fn begin_handoff<F>(on_ready: F) -> io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    if HANDING_OFF.swap(true, Ordering::SeqCst) {
+        warn!("[handoff] already handing off to a new process");
+        return Ok(());
+    }
+
+    match spawn_successor() {
+        Ok((child, ready)) => {
+            info!("[handoff] started process {}, waiting for its listeners", child.id());
+            thread::spawn(move || wait_for_successor(child, ready, on_ready));
+            Ok(())
+        },
+        Err(e) => {
+            HANDING_OFF.store(false, Ordering::SeqCst);
+            Err(e)
+        },
+    }
+}
+
+/// Gets the listening socket to hand off for each address, which is the one registered last.
+fn get_handoff_fds() -> HashMap<SocketAddr, RawFd> {
+    let listening = LISTENING.lock().unwrap();
+    listening.iter().map(|(_, addr, fd)| (*addr, *fd)).collect()
+}
+
+fn spawn_successor() -> io::Result<(Child, File)> {
+    // Duplicates of our sockets aren't closed on exec, which is how they make it into the new
+    // process.  Ours are closed as soon as it has them.
+    let mut handed = Vec::new();
+    for (addr, fd) in get_handoff_fds() {
+        let dup = cvt(unsafe { libc::dup(fd) })?;
+        handed.push((addr, unsafe { TcpListener::from_raw_fd(dup) }));
+    }
+    let listen_fds = handed
+        .iter()
+        .map(|(addr, listener)| format!("{}={}", addr, listener.as_raw_fd()))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut fds = [0; 2];
+    cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    let (ready_rx, ready_tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    set_cloexec(ready_rx.as_raw_fd());
+
+    let child = Command::new(env::current_exe()?)
+        .args(env::args_os().skip(1))
+        .env(LISTEN_FDS_VAR, listen_fds)
+        .env(READY_FD_VAR, ready_tx.as_raw_fd().to_string())
+        .spawn()?;
+
+    Ok((child, ready_rx))
+}
+
+fn wait_for_successor<F: FnOnce()>(mut child: Child, mut ready: File, on_ready: F) {
+    let pid = child.id();
+    let mut pollfd = libc::pollfd {
+        fd: ready.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let polled = loop {
+        let result = unsafe { libc::poll(&mut pollfd, 1, HANDOFF_TIMEOUT_MS) };
+        if result < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        break result;
+    };
+
+    // The new process closing its end without a word means it didn't make it.
+    let mut buf = [0; 1];
+    if polled > 0 && ready.read(&mut buf).map(|n| n == 1).unwrap_or(false) {
+        info!("[handoff] handed off listeners to pid {}", pid);
+        on_ready();
+        return;
+    }
+
+    error!("[handoff] process {} never got its listeners up, carrying on without it", pid);
+    let _ = child.kill();
+    let _ = child.wait();
+    HANDING_OFF.store(false, Ordering::SeqCst);
+}
+
+fn parse_listen_fds(raw: &str) -> Vec<(SocketAddr, RawFd)> {
+    raw.split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let addr = parts.next()?.parse().ok()?;
+            let fd = parts.next()?.parse().ok()?;
+            Some((addr, fd))
+        })
+        .collect()
+}
+
+fn set_cloexec(fd: RawFd) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags >= 0 {
+            libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+        }
+    }
+}
+
+fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_handoff_fds, parse_listen_fds, register_listener};
+    use std::{
+        net::{SocketAddr, TcpListener},
+        os::unix::io::AsRawFd,
+    };
+
+    #[test]
+    fn test_parse_listen_fds() {
+        let v4: SocketAddr = "127.0.0.1:6379".parse().unwrap();
+        let v6: SocketAddr = "[::1]:6380".parse().unwrap();
+        assert_eq!(parse_listen_fds("127.0.0.1:6379=5,[::1]:6380=6"), vec![(v4, 5), (v6, 6)]);
+
+        // Anything we can't make sense of is skipped, rather than taking the rest down with it.
+        assert_eq!(parse_listen_fds("127.0.0.1:6379=five,nonsense,[::1]:6380=6"), vec![(v6, 6)]);
+        assert_eq!(parse_listen_fds(""), vec![]);
+    }
+
+    #[test]
+    fn test_newest_listener_is_handed_off() {
+        let old = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = old.local_addr().unwrap();
+        let new = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let old_registration = register_listener(addr, &old);
+        let new_registration = register_listener(addr, &new);
+        assert_eq!(get_handoff_fds().get(&addr), Some(&new.as_raw_fd()));
+
+        // Once the new version is gone, the old one is all that's left to hand off.
+        drop(new_registration);
+        assert_eq!(get_handoff_fds().get(&addr), Some(&old.as_raw_fd()));
+        drop(old_registration);
+        assert_eq!(get_handoff_fds().get(&addr), None);
+    }
+}
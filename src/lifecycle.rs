@@ -0,0 +1,525 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use futures::{
+    future::Shared,
+    prelude::*,
+    stream,
+    sync::oneshot::{self, Receiver, Sender},
+};
+use slab::Slab;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+use util::clock::{duration_as_ms, elapsed};
+
+// How often a running phase checks whether everything it's waiting on has stopped.
+const PHASE_CHECK_INTERVAL_MS: u64 = 10;
+
+lazy_static! {
+    static ref COORDINATOR: ShutdownCoordinator = ShutdownCoordinator::new();
+}
+
+/// A step in shutting down.
+///
+/// Phases run one at a time, in the order they're declared in, and each one is given a bounded
+/// amount of time for everything registered with it to stop before moving on regardless, unless
+/// something registered with it asks for longer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownPhase {
+    /// Listeners stop accepting new clients.
+    StopAccepting,
+    /// Connected clients are given a chance to finish up and disconnect.
+    DrainClients,
+    /// Whatever clients remain are closed, and the routers they held along with them.
+    StopRouters,
+    /// Pools finish sending whatever requests are still queued for their backends.
+    DrainBackends,
+    /// Pools, and the backend connections and health checks they drive, are torn down.
+    StopPools,
+    /// The last metric updates are folded in, so they show up in stats.
+    FlushMetrics,
+    /// The stats and admin API, and the watchdog, are stopped.
+    StopAdmin,
+}
+
+const PHASES: [ShutdownPhase; 7] = [
+    ShutdownPhase::StopAccepting,
+    ShutdownPhase::DrainClients,
+    ShutdownPhase::StopRouters,
+    ShutdownPhase::DrainBackends,
+    ShutdownPhase::StopPools,
+    ShutdownPhase::FlushMetrics,
+    ShutdownPhase::StopAdmin,
+];
+
+impl ShutdownPhase {
+    pub fn name(self) -> &'static str {
+        match self {
+            ShutdownPhase::StopAccepting => "stop_accepting",
+            ShutdownPhase::DrainClients => "drain_clients",
+            ShutdownPhase::StopRouters => "stop_routers",
+            ShutdownPhase::DrainBackends => "drain_backends",
+            ShutdownPhase::StopPools => "stop_pools",
+            ShutdownPhase::FlushMetrics => "flush_metrics",
+            ShutdownPhase::StopAdmin => "stop_admin",
+        }
+    }
+
+    /// Gets how long this phase waits for everything registered with it to stop.
+    ///
+    /// Listeners bound how long they let clients drain for on their own, so draining clients gets
+    /// plenty of headroom over that.  Anything that needs longer than its phase allows can ask for
+    /// it when registering.
+    pub fn timeout(self) -> Duration {
+        let timeout_ms = match self {
+            ShutdownPhase::StopAccepting => 1000,
+            ShutdownPhase::DrainClients => 30_000,
+            ShutdownPhase::StopRouters => 5000,
+            ShutdownPhase::DrainBackends => 5000,
+            ShutdownPhase::StopPools => 5000,
+            ShutdownPhase::FlushMetrics => 2000,
+            ShutdownPhase::StopAdmin => 1000,
+        };
+        Duration::from_millis(timeout_ms)
+    }
+}
+
+/// Registers a long-lived component with the given shutdown phase.
+///
+/// The phase waits for the returned handle to be dropped before moving on, up to its timeout.
+pub fn register<S: Into<String>>(phase: ShutdownPhase, name: S) -> ShutdownHandle { COORDINATOR.register(phase, name) }
+
+/// Registers a long-lived component with the given shutdown phase, which needs longer to stop than
+/// the phase would usually wait.
+///
+/// The phase waits for at least the given timeout, or for as long as it takes if there isn't one.
+pub fn register_with_timeout<S: Into<String>>(
+    phase: ShutdownPhase, name: S, timeout: Option<Duration>,
+) -> ShutdownHandle {
+    COORDINATOR.register_with_timeout(phase, name, timeout)
+}
+
+/// Runs every shutdown phase, in order, resolving once the last one is done.
+pub fn shutdown() -> impl Future<Item = (), Error = ()> { COORDINATOR.shutdown() }
+
+/// Marks shutdown as having begun.
+///
+/// Shutdown only begins once: this returns `true` the first time it's called, and `false` ever
+/// after, so that whoever asks again knows shutdown is already underway.
+pub fn begin_shutdown() -> bool { COORDINATOR.begin() }
+
+/// Whether or not shutdown has begun.
+pub fn is_shutting_down() -> bool { COORDINATOR.get_progress().started.is_some() }
+
+/// Gets how far along shutdown is.
+pub fn get_progress() -> ShutdownProgress { COORDINATOR.get_progress() }
+
+/// How far along shutdown is, and what it's waiting on.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownProgress {
+    /// When shutdown began, if it has.
+    pub started: Option<Instant>,
+    /// The phase currently running, if any have started.
+    pub phase: Option<ShutdownPhase>,
+    /// Everything the current phase is still waiting on to stop.
+    pub waiting_on: Vec<String>,
+}
+
+impl fmt::Display for ShutdownProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let started = match self.started {
+            Some(started) => started,
+            None => return write!(f, "shutdown had not begun"),
+        };
+
+        let elapsed_ms = duration_as_ms(elapsed(started));
+        match self.phase {
+            Some(phase) => write!(f, "in phase '{}' after {}ms", phase.name(), elapsed_ms)?,
+            None => write!(f, "before any phase started, after {}ms", elapsed_ms)?,
+        }
+
+        if self.waiting_on.is_empty() {
+            write!(f, ", waiting on nothing")
+        } else {
+            write!(f, ", still waiting on: {}", self.waiting_on.join(", "))
+        }
+    }
+}
+
+// A component registered with a phase, and how long the phase should wait for it.
+struct PhaseTask {
+    name: String,
+    timeout: Option<Duration>,
+}
+
+struct PhaseState {
+    phase: ShutdownPhase,
+    trigger: Mutex<Option<Sender<()>>>,
+    signal: Shared<Receiver<()>>,
+    tasks: Arc<Mutex<Slab<PhaseTask>>>,
+}
+
+impl PhaseState {
+    fn new(phase: ShutdownPhase) -> PhaseState {
+        let (trigger, signal) = oneshot::channel();
+
+        PhaseState {
+            phase,
+            trigger: Mutex::new(Some(trigger)),
+            signal: signal.shared(),
+            tasks: Arc::new(Mutex::new(Slab::new())),
+        }
+    }
+
+    /// Gets how long this phase waits for everything registered with it to stop, if it gives up at
+    /// all.
+    fn timeout(&self) -> Option<Duration> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .fold(Some(self.phase.timeout()), |timeout, (_, task)| {
+                match (timeout, task.timeout) {
+                    (Some(timeout), Some(task_timeout)) => Some(timeout.max(task_timeout)),
+                    _ => None,
+                }
+            })
+    }
+
+    /// Starts this phase, and waits for everything registered with it to stop, or for the phase
+    /// to time out.
+    fn run(&self) -> impl Future<Item = (), Error = ()> {
+        let phase = self.phase;
+        let started = Instant::now();
+        let deadline = self.timeout().map(|timeout| started + timeout);
+        let tasks = self.tasks.clone();
+        let tasks2 = self.tasks.clone();
+
+        let waiting = tasks.lock().unwrap().len();
+        info!("[shutdown] phase '{}' started, waiting on {} task(s)", phase.name(), waiting);
+        if let Some(trigger) = self.trigger.lock().unwrap().take() {
+            let _ = trigger.send(());
+        }
+
+        Interval::new(started, Duration::from_millis(PHASE_CHECK_INTERVAL_MS))
+            .map_err(|e| error!("[shutdown] timer failed: {}", e))
+            .take_while(move |_| {
+                let waiting = !tasks.lock().unwrap().is_empty();
+                Ok(waiting && deadline.map_or(true, |deadline| Instant::now() < deadline))
+            })
+            .for_each(|_| Ok(()))
+            .then(move |_| {
+                let remaining = tasks2
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(_, task)| task.name.clone())
+                    .collect::<Vec<_>>();
+                let elapsed_ms = duration_as_ms(elapsed(started));
+                if remaining.is_empty() {
+                    info!("[shutdown] phase '{}' finished after {}ms", phase.name(), elapsed_ms);
+                } else {
+                    warn!(
+                        "[shutdown] phase '{}' timed out after {}ms, still waiting on: {}",
+                        phase.name(),
+                        elapsed_ms,
+                        remaining.join(", ")
+                    );
+                }
+
+                Ok(())
+            })
+    }
+}
+
+/// Sequences shutdown across everything that runs for the life of the process.
+///
+/// Components register with the phase they should stop in, and are told when that phase starts.
+/// A phase only moves on once every component registered with it has stopped, or once it times
+/// out, so that nothing is torn down while something in an earlier phase still depends on it.
+///
+/// Shutdown is a one-way latch: once it has begun, asking for it again changes nothing.
+pub struct ShutdownCoordinator {
+    phases: Vec<Arc<PhaseState>>,
+
+    // When shutdown began, and the phase it's currently in.
+    progress: Arc<Mutex<(Option<Instant>, Option<ShutdownPhase>)>>,
+}
+
+impl ShutdownCoordinator {
+    fn new() -> ShutdownCoordinator {
+        ShutdownCoordinator {
+            phases: PHASES.iter().map(|phase| Arc::new(PhaseState::new(*phase))).collect(),
+            progress: Arc::new(Mutex::new((None, None))),
+        }
+    }
+
+    pub fn begin(&self) -> bool {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.0.is_some() {
+            return false;
+        }
+
+        progress.0 = Some(Instant::now());
+        true
+    }
+
+    pub fn get_progress(&self) -> ShutdownProgress {
+        let (started, phase) = *self.progress.lock().unwrap();
+        let waiting_on = phase
+            .and_then(|phase| self.phases.iter().find(|state| state.phase == phase))
+            .map(|state| {
+                state
+                    .tasks
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(_, task)| task.name.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        ShutdownProgress {
+            started,
+            phase,
+            waiting_on,
+        }
+    }
+
+    pub fn register<S: Into<String>>(&self, phase: ShutdownPhase, name: S) -> ShutdownHandle {
+        self.register_with_timeout(phase, name, Some(phase.timeout()))
+    }
+
+    pub fn register_with_timeout<S: Into<String>>(
+        &self, phase: ShutdownPhase, name: S, timeout: Option<Duration>,
+    ) -> ShutdownHandle {
+        let state = self
+            .phases
+            .iter()
+            .find(|state| state.phase == phase)
+            .expect("every phase has state");
+        let key = state.tasks.lock().unwrap().insert(PhaseTask {
+            name: name.into(),
+            timeout,
+        });
+
+        ShutdownHandle {
+            key,
+            tasks: state.tasks.clone(),
+            signal: state.signal.clone(),
+        }
+    }
+
+    pub fn shutdown(&self) -> impl Future<Item = (), Error = ()> {
+        info!("[shutdown] shutting down");
+        self.begin();
+
+        let progress = self.progress.clone();
+        stream::iter_ok(self.phases.clone())
+            .for_each(move |state| {
+                progress.lock().unwrap().1 = Some(state.phase);
+                state.run()
+            })
+            .then(|result| {
+                info!("[shutdown] shutdown complete");
+                result
+            })
+    }
+}
+
+/// A component's registration with a shutdown phase.
+///
+/// The phase considers the component stopped once its handle is dropped.
+pub struct ShutdownHandle {
+    key: usize,
+    tasks: Arc<Mutex<Slab<PhaseTask>>>,
+    signal: Shared<Receiver<()>>,
+}
+
+impl ShutdownHandle {
+    /// Gets a future that resolves when this handle's phase starts.
+    pub fn signal(&self) -> PhaseSignal {
+        PhaseSignal {
+            inner: self.signal.clone(),
+        }
+    }
+}
+
+impl Drop for ShutdownHandle {
+    fn drop(&mut self) { self.tasks.lock().unwrap().remove(self.key); }
+}
+
+/// Resolves when a shutdown phase starts.
+#[derive(Clone)]
+pub struct PhaseSignal {
+    inner: Shared<Receiver<()>>,
+}
+
+impl Future for PhaseSignal {
+    type Error = ();
+    type Item = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // The trigger is only ever dropped without firing if the coordinator is, so either way,
+        // it's time to stop.
+        match self.inner.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            _ => Ok(Async::Ready(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::lazy;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::{runtime::current_thread, timer::Delay};
+
+    #[test]
+    fn test_phases_run_in_order() {
+        let coordinator = ShutdownCoordinator::new();
+        let stopped = Arc::new(Mutex::new(Vec::new()));
+
+        // Register backwards, so that the order things stop in can only come from the phases.
+        let phases = PHASES.iter().rev().cloned().collect::<Vec<_>>();
+        let handles = phases
+            .iter()
+            .map(|phase| (*phase, coordinator.register(*phase, phase.name())))
+            .collect::<Vec<_>>();
+
+        let stopped2 = stopped.clone();
+        current_thread::block_on_all(lazy(move || {
+            for (phase, handle) in handles {
+                let stopped = stopped2.clone();
+
+                // Each component takes a little while to stop once it's told to.
+                let task = handle.signal().and_then(move |_| {
+                    Delay::new(Instant::now() + Duration::from_millis(20))
+                        .map_err(|_| ())
+                        .map(move |_| {
+                            stopped.lock().unwrap().push(phase);
+                            drop(handle);
+                        })
+                });
+                tokio::spawn(task);
+            }
+
+            coordinator.shutdown()
+        }))
+        .unwrap();
+
+        assert_eq!(*stopped.lock().unwrap(), PHASES.to_vec());
+    }
+
+    #[test]
+    fn test_shutdown_only_begins_once() {
+        let coordinator = ShutdownCoordinator::new();
+        assert!(coordinator.get_progress().started.is_none());
+        assert_eq!(coordinator.get_progress().to_string(), "shutdown had not begun");
+
+        assert!(coordinator.begin());
+        let started = coordinator.get_progress().started;
+        assert!(started.is_some());
+
+        // Asking again doesn't restart anything.
+        assert!(!coordinator.begin());
+        assert_eq!(coordinator.get_progress().started, started);
+    }
+
+    #[test]
+    fn test_progress_names_what_is_left() {
+        let coordinator = ShutdownCoordinator::new();
+        let stuck = coordinator.register(ShutdownPhase::StopAccepting, "stuck");
+
+        // Only wait long enough for the first phase to get stuck, rather than for it to time out.
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        runtime.spawn(coordinator.shutdown());
+        runtime.block_on(Delay::new(Instant::now() + Duration::from_millis(50))).unwrap();
+
+        let progress = coordinator.get_progress();
+        assert_eq!(progress.phase, Some(ShutdownPhase::StopAccepting));
+        assert_eq!(progress.waiting_on, vec!["stuck".to_owned()]);
+        assert!(progress.to_string().contains("in phase 'stop_accepting'"));
+        assert!(progress.to_string().ends_with("still waiting on: stuck"));
+        drop(stuck);
+    }
+
+    #[test]
+    fn test_phase_timeout() {
+        let coordinator = ShutdownCoordinator::new();
+        let signaled = Arc::new(AtomicUsize::new(0));
+
+        // This one never stops, so its phase has to time out before the next one can start.
+        let stuck = coordinator.register(ShutdownPhase::StopAdmin, "stuck");
+        let last = coordinator.register(ShutdownPhase::StopAdmin, "last");
+        drop(last);
+
+        let signaled2 = signaled.clone();
+        let signal = stuck.signal();
+        let started = Instant::now();
+        current_thread::block_on_all(lazy(move || {
+            tokio::spawn(signal.map(move |_| {
+                signaled2.fetch_add(1, Ordering::SeqCst);
+            }));
+
+            coordinator.shutdown()
+        }))
+        .unwrap();
+        drop(stuck);
+
+        assert_eq!(signaled.load(Ordering::SeqCst), 1);
+        assert!(elapsed(started) >= ShutdownPhase::StopAdmin.timeout());
+    }
+
+    #[test]
+    fn test_phase_timeout_extended() {
+        let coordinator = ShutdownCoordinator::new();
+        let extended = ShutdownPhase::StopAccepting.timeout() * 3;
+
+        // One component asks for longer than the first phase allows, and one for as long as it
+        // takes, which is only as long as it takes for us to let it go.
+        let slow = coordinator.register_with_timeout(ShutdownPhase::StopAccepting, "slow", Some(extended));
+        let patient = coordinator.register_with_timeout(ShutdownPhase::StopAdmin, "patient", None);
+
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        runtime.spawn(coordinator.shutdown());
+
+        // Both are still being waited on well past when their phases would have timed out.
+        let wait = ShutdownPhase::StopAccepting.timeout() + Duration::from_millis(500);
+        runtime.block_on(Delay::new(Instant::now() + wait)).unwrap();
+        let progress = coordinator.get_progress();
+        assert_eq!(progress.phase, Some(ShutdownPhase::StopAccepting));
+        assert_eq!(progress.waiting_on, vec!["slow".to_owned()]);
+        drop(slow);
+
+        let wait = ShutdownPhase::StopAdmin.timeout() + Duration::from_millis(500);
+        runtime.block_on(Delay::new(Instant::now() + wait)).unwrap();
+        let progress = coordinator.get_progress();
+        assert_eq!(progress.phase, Some(ShutdownPhase::StopAdmin));
+        assert_eq!(progress.waiting_on, vec!["patient".to_owned()]);
+        drop(patient);
+
+        runtime.run().unwrap();
+    }
+}
@@ -18,72 +18,664 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 use backend::{
+    check_options,
+    demotion::{DemotionConfiguration, Demoter},
+    drain::register_backend_activity,
     pool::{BackendPool, BackendPoolBuilder},
+    memcached::MemcachedProcessor,
+    placement::{Placement, PlacementReport},
+    probe::{register_backend_availability, HealthCheckConfiguration, HealthChecker},
     processor::Processor,
     redis::RedisProcessor,
+    resolver::{resolve_backends, Resolver, SystemResolver},
+    retry::RetryBudget,
+    startup::StartupRequirement,
+    subscription::Subscriptions,
+    ttl::TtlPolicy,
+    warmup::{Warmer, WarmupConfiguration},
+    weights::register_backend_weights,
 };
 use bytes::BytesMut;
 use common::{AssignedRequests, AssignedResponse, EnqueuedRequests, Message};
-use conf::ListenerConfiguration;
-use errors::CreationError;
+use conf::{accepted_pool_options, ListenerConfiguration, Secret};
+use errors::{CreationError, ListenerStartError};
 use futures::{
-    future::{lazy, ok, Shared},
+    future::{self, lazy, ok, Either, Shared},
     prelude::*,
 };
 use futures_turnstyle::Waiter;
-use hotmic::Sink as MetricSink;
-use metrics::get_sink;
+use handoff;
+use lifecycle::{self, ShutdownPhase};
+use log::Level;
+use metrics::{get_sink, register_latencies, LatencyBuckets, MetricSink};
 use net2::TcpBuilder;
-use protocol::errors::ProtocolError;
-use routing::{FixedRouter, ShadowRouter};
-use service::{Pipeline, PipelineError};
-use std::{collections::HashMap, fmt::Display, net::SocketAddr};
-use tokio::{io, net::TcpListener, reactor};
+use protocol::{
+    errors::{is_disconnect, ProtocolError},
+    redis::{
+        ProtocolLimits, RedisTransportConfig, DEFAULT_MAX_BULK_LEN, DEFAULT_MAX_INLINE_LEN, DEFAULT_MAX_MULTIBULK_LEN,
+        DEFAULT_MAX_PROTOCOL_ERRORS,
+    },
+};
+use record::{Recorded, Recorder, RecorderConfiguration};
+use reload::VersionHold;
+use routing::{
+    percentage_from_options, FailoverConfiguration, FailoverRouter, FixedRouter, PercentageRouter, ShadowRouter,
+    SplitRouter,
+};
+use service::{
+    get_client_limit, get_client_registry, idle_timeout_from_config, log_key_samples, register_key_sampler,
+    AuditConfiguration, AuditLog, AuditedPool, BatchConfiguration, ClientConnection, ClientLatencies, ClientLimit,
+    ClientLimitBehavior, ClientLimitConfiguration, ClientRegistry, FragmentLimits, IdleTimeout, KeySampler,
+    KeySamplerConfiguration, Pipeline, SloTable,
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    mem,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io,
+    net::{tcp::Incoming, TcpListener, TcpStream},
+    reactor,
+    timer::Delay,
+};
 use tokio_evacuate::{Evacuate, Warden};
 use tokio_executor::DefaultExecutor;
 use tower_buffer::{Buffer, DirectServiceRef};
 use tower_service::Service;
-use util::typeless;
+use util::{
+    claim_address,
+    clock::{duration_as_ms, elapsed, system_clock},
+    get_fd_tracker, get_fingerprint, typeless,
+    watchdog::{watch, WatchedExecutor, DEFAULT_HEARTBEAT_INTERVAL_MS},
+    ClientStream, FdTracker, LogLimiter, LogScoped, TlsTerminator,
+};
 
 type GenericRuntimeFuture = Box<Future<Item = (), Error = ()> + Send + 'static>;
-type BufferedPool<T, M> = Buffer<DirectServiceRef<BackendPool<T>>, EnqueuedRequests<M>>;
 
-/// Creates a listener from the given configuration.
+// How long we wait before accepting again after failing to accept a client.
+const ACCEPT_BACKOFF_MS: u64 = 100;
+
+// How long clients get to drain for when their listener is replaced, unless configured otherwise.
+const DEFAULT_RELOAD_TIMEOUT_MS: u64 = 5000;
+
+// How long the evacuator itself waits for clients to drain.  We decide when to give up on them, so
+// this only needs to outlast any timeout we'd pick, while staying within what timers can handle.
+const EVACUATE_TIMEOUT_MS: u64 = 365 * 24 * 60 * 60 * 1000;
+
+lazy_static! {
+    static ref ACCEPT_ERRORS: LogLimiter = LogLimiter::new("listener");
+}
+type BufferedPool<T, M> = AuditedPool<T, Buffer<DirectServiceRef<BackendPool<T>>, EnqueuedRequests<M>>>;
+
+/// The cache protocols a listener can speak.
+#[derive(Clone, Copy)]
+enum Protocol {
+    Redis,
+    Memcached,
+}
+
+/// The ways a listener can route requests to its pools.
+#[derive(Clone, Copy)]
+enum RouteType {
+    Fixed,
+    Shadow,
+    Split,
+    Failover,
+    SplitPercentage,
+}
+
+// Every protocol and route type we support, by the name it's configured with.
+const PROTOCOLS: &[(&str, Protocol)] = &[("redis", Protocol::Redis), ("memcached", Protocol::Memcached)];
+const ROUTE_TYPES: &[(&str, RouteType)] = &[
+    ("fixed", RouteType::Fixed),
+    ("shadow", RouteType::Shadow),
+    ("split", RouteType::Split),
+    ("failover", RouteType::Failover),
+    ("split_percentage", RouteType::SplitPercentage),
+];
+
+/// Gets the names of all of the protocols a listener can be configured with.
+pub fn protocol_names() -> Vec<&'static str> { PROTOCOLS.iter().map(|(name, _)| *name).collect() }
+
+/// Gets the names of all of the route types a listener can be configured with.
+pub fn route_type_names() -> Vec<&'static str> { ROUTE_TYPES.iter().map(|(name, _)| *name).collect() }
+
+fn lookup<T: Copy>(registry: &[(&str, T)], name: &str) -> Option<T> {
+    registry.iter().find(|(entry, _)| *entry == name).map(|(_, value)| *value)
+}
+
+impl RouteType {
+    /// Gets the names of the pools the route type sends requests to, all of which must be configured.
+    fn pools(self) -> &'static [&'static str] {
+        match self {
+            RouteType::Fixed => &["default"],
+            RouteType::Shadow => &["default", "shadow"],
+            RouteType::Split => &["writes", "reads"],
+            RouteType::Failover => &["default", "failover"],
+            RouteType::SplitPercentage => &["old", "new"],
+        }
+    }
+}
+
+/// Gets the route type a listener is configured with, which is `fixed` unless it says otherwise.
+fn get_route_type(config: &ListenerConfiguration) -> String {
+    config
+        .routing
+        .get("type")
+        .map(|s| s.to_lowercase())
+        .unwrap_or_else(|| "fixed".to_owned())
+}
+
+/// Gets the names of any configured pools that the listener's router will never send traffic to.
+///
+/// Unknown route types are left for `check_config` to reject, so they report nothing here.
+fn unreachable_pools(config: &ListenerConfiguration) -> Vec<&str> {
+    let reachable = match lookup(ROUTE_TYPES, &get_route_type(config)) {
+        Some(route) => route.pools(),
+        None => return Vec::new(),
+    };
+
+    let mut pools = config
+        .pools
+        .keys()
+        .map(|s| s.as_str())
+        .filter(|name| !reachable.contains(name))
+        .collect::<Vec<_>>();
+    pools.sort();
+    pools
+}
+
+/// Checks the configuration of a listener for anything that would keep it from being built.
+///
+/// Nothing is bound or connected to, so this is safe to run against a configuration that's only
+/// meant to be looked at.  Every problem found is returned, each starting with the path of the
+/// configuration it's about, so that they can all be fixed in one go.
+pub fn check_config(name: &str, config: &ListenerConfiguration) -> Vec<String> {
+    let path = format!("listeners.{}", name);
+    let mut problems = Vec::new();
+
+    if config.address.parse::<SocketAddr>().is_err() {
+        problems.push(format!("{}.address: '{}' is not a valid address", path, config.address));
+    }
+
+    match lookup(PROTOCOLS, &config.protocol.to_lowercase()) {
+        Some(Protocol::Redis) => {
+            if let Err(e) = get_protocol_limits(config) {
+                problems.push(describe_problem(&path, &e));
+            }
+            if let Err(e) = get_max_protocol_errors(config) {
+                problems.push(describe_problem(&path, &e));
+            }
+            if let Err(e) = get_password(config) {
+                problems.push(describe_problem(&path, &e));
+            }
+        },
+        Some(Protocol::Memcached) => {
+            // Memcached has no way for clients to authenticate, so a password would only give the
+            // impression that the listener is protected.
+            if config.password.is_some() || config.password_file.is_some() {
+                problems.push(format!("{}.password: memcached clients can't authenticate", path));
+            }
+        },
+        None => {
+            problems.push(format!(
+                "{}.protocol: unknown protocol '{}'; expected one of: {}",
+                path,
+                config.protocol,
+                protocol_names().join(", ")
+            ))
+        },
+    }
+
+    let checks = [
+        FragmentLimits::from_config(config).map(|_| ()),
+        BatchConfiguration::from_config(config).map(|_| ()),
+        RecorderConfiguration::from_config(config).map(|_| ()),
+        KeySamplerConfiguration::from_config(config).map(|_| ()),
+        ClientLimitConfiguration::from_config(config).map(|_| ()),
+        idle_timeout_from_config(config).map(|_| ()),
+    ];
+    problems.extend(checks.iter().filter_map(|r| r.as_ref().err()).map(|e| describe_problem(&path, e)));
+    if let Some(tls_config) = config.tls.as_ref() {
+        if let Err(e) = TlsTerminator::from_config(tls_config) {
+            problems.push(describe_problem(&path, &e));
+        }
+    }
+
+    let route_type = get_route_type(config);
+    match lookup(ROUTE_TYPES, &route_type) {
+        Some(route) => {
+            for pool_name in route.pools() {
+                if !config.pools.contains_key(*pool_name) {
+                    problems.push(format!(
+                        "{}.pools.{}: required by the {} router, but not configured",
+                        path, pool_name, route_type
+                    ));
+                }
+            }
+
+            let options = match route {
+                RouteType::Failover => FailoverConfiguration::from_options(&config.routing).map(|_| ()),
+                RouteType::SplitPercentage => percentage_from_options(&config.routing).map(|_| ()),
+                _ => Ok(()),
+            };
+            if let Err(e) = options {
+                problems.push(describe_problem(&path, &e));
+            }
+        },
+        None => {
+            problems.push(format!(
+                "{}.routing.type: unknown route type '{}'; expected one of: {}",
+                path,
+                route_type,
+                route_type_names().join(", ")
+            ))
+        },
+    }
+
+    let mut pool_names = config.pools.keys().collect::<Vec<_>>();
+    pool_names.sort();
+    for pool_name in pool_names {
+        let pool_config = &config.pools[pool_name];
+        let pool_path = format!("{}.pools.{}", path, pool_name);
+
+        if pool_config.addresses.is_empty() {
+            problems.push(format!("{}.addresses: no backends configured", pool_path));
+        }
+
+        if let Err(e) = pool_config.options.validate(pool_name) {
+            problems.push(describe_problem(&path, &e));
+        }
+
+        let options = &pool_config.options;
+        let checks = [
+            Placement::from_config(pool_config).map(|_| ()),
+            check_options(options),
+            TtlPolicy::from_options(options).map(|_| ()),
+            RetryBudget::from_options(options).map(|_| ()),
+            StartupRequirement::from_options(options).map(|_| ()),
+            WarmupConfiguration::from_options(options).map(|_| ()),
+            AuditConfiguration::from_options(options).map(|_| ()),
+            DemotionConfiguration::from_options(options).map(|_| ()),
+            HealthCheckConfiguration::from_options(options).map(|_| ()),
+        ];
+        problems.extend(checks.iter().filter_map(|r| r.as_ref().err()).map(|e| describe_problem(&pool_path, e)));
+    }
+
+    problems
+}
+
+/// Describes a problem with the configuration at the given path.
+///
+/// Invalid parameters are named relative to whatever they configure, so they're tacked on to the
+/// path, while anything else carries its own description.
+fn describe_problem(path: &str, e: &CreationError) -> String {
+    match e {
+        CreationError::InvalidParameter(param) => format!("{}.{}: invalid value", path, param),
+        e => format!("{}: {}", path, e),
+    }
+}
+
+/// Gets a listener's configuration ready to build the listener from.
+///
+/// Anything wrong with the configuration is caught here, before we bind or connect to anything.
+/// Pools that would rather we not start at all than start without their backends also get to wait
+/// for them here, without holding up anything else on the runtime while they do.
+pub fn prepare(
+    name: String, config: ListenerConfiguration,
+) -> impl Future<Item = ListenerConfiguration, Error = ListenerStartError> + Send {
+    let problems = check_config(&name, &config);
+    if !problems.is_empty() {
+        return Either::B(future::err(CreationError::InvalidResource(problems.join("; ")).into()));
+    }
+
+    // Pool options that nothing understands don't stop us, but are most likely a mistake.
+    warn_unknown_pool_options(&name, &config);
+
+    // Backends can be given by hostname, so find out where they are before anything goes looking.
+    let prepared = resolve_pool_backends(name.clone(), config).and_then(move |config| {
+        // Show where every pool places its canary keys, so that a bad hasher or distributor shows up
+        // before any client does.
+        future::result(check_placements(&name, &config)).and_then(move |_| wait_for_required_backends(name, config))
+    });
+
+    Either::A(prepared.map_err(ListenerStartError::from))
+}
+
+/// Creates a listener from the given configuration, once it's been prepared.
 ///
 /// The listener will spawn a socket for accepting client connections, and when a client connects,
 /// spawn a task to process all of the messages from that client until the client disconnects or
 /// there is an unrecoverable connection/protocol error.
+///
+/// Failing to bind the listen address is reported separately from failing to build the listener
+/// from its configuration, as the former is often fixed by just trying again later.
+///
+/// Everything logged on behalf of the listener carries its version and the generation of the
+/// configuration it was built from, so that logs can be tied back to the configuration in effect.
 pub fn from_config(
-    version: usize, name: String, config: ListenerConfiguration, close: Shared<Waiter>,
-) -> Result<GenericRuntimeFuture, CreationError> {
+    version: usize, config_gen: usize, name: String, config: ListenerConfiguration, close: Shared<Waiter>,
+    hold: VersionHold,
+) -> Result<GenericRuntimeFuture, ListenerStartError> {
     // Create the actual listener proper.
     let listen_address = config.address.clone();
-    let listener = get_listener(&listen_address).expect("failed to create the TCP listener");
+    let listen_addr = listen_address
+        .parse()
+        .map_err(|_| CreationError::InvalidParameter("address".to_string()))?;
+    let reuse_port = config.reuse_port.unwrap_or(true);
+    let listener = get_listener(&listen_addr, reuse_port).map_err(ListenerStartError::from_bind_error)?;
+
+    // Anything else bound to our address splits its connections with us, which is only alright if
+    // it's running the same configuration, or sharing the address on purpose, so shout if it isn't.
+    let shared = config.reuse_port_shared.unwrap_or(false);
+    let claim = claim_address(listen_addr, &name, get_fingerprint(&config), shared);
+
+    // If we're ever upgraded, this is the socket that the new process takes over.
+    let handoff = handoff::register_listener(listen_addr, &listener);
+
+    for pool_name in unreachable_pools(&config) {
+        warn!(
+            "[listener] pool '{}' on listener '{}' is not used by its router and will never receive traffic",
+            pool_name, name
+        );
+    }
+
+    // Everything logged on behalf of this listener, including by its clients, carries its name and
+    // where it came from, even once it's been replaced and is only draining its clients.
+    let logger = slog_scope::logger().new(slog_o!(
+        "listener" => name.clone(),
+        "listener_version" => version,
+        "config_gen" => config_gen
+    ));
 
     // Now build our handler: this is what's actually going to do the real work.
     let protocol = config.protocol.to_lowercase();
-    let handler = match protocol.as_str() {
-        "redis" => routing_from_config(name, config, listener, close.clone(), RedisProcessor::new()),
-        s => Err(CreationError::InvalidResource(format!("unknown cache protocol: {}", s))),
-    }?;
+    let handler = slog_scope::scope(&logger, || {
+        match lookup(PROTOCOLS, &protocol) {
+            Some(Protocol::Redis) => {
+                // Clients only get to kill each other if we've been told they can.
+                let clients = if config.allow_client_kill.unwrap_or(false) {
+                    Some(get_client_registry(&name))
+                } else {
+                    None
+                };
+                let transport_config = RedisTransportConfig {
+                    pretend_cluster: config.pretend_cluster.unwrap_or(false),
+                    limits: get_protocol_limits(&config)?,
+                    allow_debug_simulation: config.allow_debug_simulation.unwrap_or(false),
+                    routing_hints: config.routing_hints.unwrap_or(false),
+                    password: get_password(&config)?,
+                    max_protocol_errors: get_max_protocol_errors(&config)?,
+                    listener_version: version,
+                    config_gen,
+                    clients,
+                };
+                let processor = RedisProcessor::new(transport_config);
+                routing_from_config(name.clone(), config, listener, close.clone(), processor, hold.clone())
+            },
+            Some(Protocol::Memcached) => {
+                let processor = MemcachedProcessor::new();
+                routing_from_config(name.clone(), config, listener, close.clone(), processor, hold.clone())
+            },
+            None => Err(CreationError::InvalidResource(format!("unknown cache protocol: {}", protocol))),
+        }
+    })?;
 
-    // Make sure our handlers close out when told.
-    let listen_address2 = listen_address.clone();
+    // Make sure our handlers close out when told, and that the watchdog notices if they die.  When
+    // shutting down, nothing else is stopped until we've stopped accepting clients.
+    let accepting = lifecycle::register(ShutdownPhase::StopAccepting, format!("listeners.{}.accept", name));
+    let name2 = name.clone();
+    let handler = watch(
+        format!("listeners.{}.accept", name),
+        Duration::from_millis(DEFAULT_HEARTBEAT_INTERVAL_MS),
+        handler,
+    );
+    let sink = get_sink().scoped(&["listeners", &name]);
     let wrapped = lazy(move || {
-        info!("[listener] starting listener '{}' (v{})", listen_address, version);
+        info!("[listener] starting listener '{}' on {} (v{})", name, listen_address, version);
+
+        // The stats show which version of the listener, from which configuration, took over last.
+        sink.update_gauge("version", version as u64);
+        sink.update_gauge("config_gen", config_gen as u64);
         ok(())
     })
     .and_then(|_| handler)
     .select2(close)
     .then(move |_| {
-        info!("[listener] shutting down listener '{}' (v{})", listen_address2, version);
+        info!("[listener] shutting down listener '{}' (v{})", name2, version);
+        drop(accepting);
+        drop(claim);
+        drop(handoff);
+        drop(hold);
         ok(())
     });
-    Ok(Box::new(wrapped))
+    Ok(Box::new(LogScoped::new(logger, wrapped)))
+}
+
+fn warn_unknown_pool_options(name: &str, config: &ListenerConfiguration) {
+    for (pool_name, pool_config) in &config.pools {
+        // An option nothing understands is most likely a typo of one that something does, which
+        // would otherwise leave the pool running with whatever it was meant to override.
+        let unknown = pool_config.options.unknown_options();
+        if !unknown.is_empty() {
+            warn!(
+                "[listener] pool '{}' on listener '{}' has unknown options: {}; accepted options are: {}",
+                pool_name,
+                name,
+                unknown.join(", "),
+                accepted_pool_options().join(", ")
+            );
+        }
+    }
+}
+
+fn check_placements(name: &str, config: &ListenerConfiguration) -> Result<(), CreationError> {
+    let mut pool_names = config.pools.keys().collect::<Vec<_>>();
+    pool_names.sort();
+
+    for pool_name in pool_names {
+        let pool_config = &config.pools[pool_name];
+        let report = PlacementReport::from_config(pool_config)?;
+        for line in report.to_string().lines() {
+            info!("[listener] pool '{}' on listener '{}': {}", pool_name, name, line);
+        }
+
+        let expected = match pool_config.expected_placements {
+            Some(ref expected) => expected,
+            None => continue,
+        };
+        let mismatches = report.check(expected);
+        if mismatches.is_empty() {
+            continue;
+        }
+
+        if pool_config.options.strict_placements {
+            return Err(CreationError::InvalidResource(format!(
+                "pool '{}' on listener '{}' misplaced keys: {}",
+                pool_name,
+                name,
+                mismatches.join("; ")
+            )));
+        }
+        for mismatch in mismatches {
+            warn!("[listener] pool '{}' on listener '{}': {}", pool_name, name, mismatch);
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves the backends of every pool, handing back the configuration once they've all been
+/// resolved.
+///
+/// Resolving a hostname can block for a while, so it's done on the blocking pool, rather than on
+/// whichever runtime thread happens to be preparing the listener.
+fn resolve_pool_backends(
+    name: String, mut config: ListenerConfiguration,
+) -> impl Future<Item = ListenerConfiguration, Error = CreationError> + Send {
+    let resolver: Arc<Resolver> = Arc::new(SystemResolver);
+    let resolving = config
+        .pools
+        .iter_mut()
+        .map(|(pool_name, pool_config)| {
+            let addresses = mem::replace(&mut pool_config.addresses, Vec::new());
+            let (name, pool_name, resolved_name) = (name.clone(), pool_name.clone(), pool_name.clone());
+            resolve_backends(addresses, &resolver)
+                .map(move |addresses| (resolved_name, addresses))
+                .map_err(move |(host, e)| {
+                    CreationError::InvalidResource(format!(
+                        "pool '{}' on listener '{}' could not resolve '{}': {}",
+                        pool_name, name, host, e
+                    ))
+                })
+        })
+        .collect::<Vec<_>>();
+
+    future::join_all(resolving).map(move |resolved| {
+        for (pool_name, addresses) in resolved {
+            if let Some(pool_config) = config.pools.get_mut(&pool_name) {
+                pool_config.addresses = addresses;
+            }
+        }
+        config
+    })
+}
+
+/// Waits for the backends of every pool that needs them at startup, handing back the configuration
+/// once they're all reachable.
+fn wait_for_required_backends(
+    name: String, config: ListenerConfiguration,
+) -> impl Future<Item = ListenerConfiguration, Error = CreationError> + Send {
+    let mut waits = Vec::new();
+    for (pool_name, pool_config) in &config.pools {
+        // Bad options were already caught when checking the configuration.
+        if let Ok(Some(requirement)) = StartupRequirement::from_options(&pool_config.options) {
+            info!("[listener] waiting for backends of pool '{}' on listener '{}'", pool_name, name);
+
+            let name = name.clone();
+            let pool_name = pool_name.clone();
+            waits.push(requirement.wait(&pool_config.addresses).map_err(move |unreachable| {
+                let unreachable = unreachable
+                    .iter()
+                    .map(|backend| backend.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                CreationError::BackendsUnreachable(format!(
+                    "pool '{}' on listener '{}' could not reach: {}",
+                    pool_name, name, unreachable
+                ))
+            }));
+        }
+    }
+
+    future::join_all(waits).map(move |_| config)
+}
+
+fn get_protocol_limits(config: &ListenerConfiguration) -> Result<ProtocolLimits, CreationError> {
+    if config.max_bulk_len == Some(0) {
+        return Err(CreationError::InvalidParameter("max_bulk_len".to_string()));
+    }
+
+    if config.max_multibulk_len == Some(0) {
+        return Err(CreationError::InvalidParameter("max_multibulk_len".to_string()));
+    }
+
+    if config.max_inline_len == Some(0) {
+        return Err(CreationError::InvalidParameter("max_inline_len".to_string()));
+    }
+
+    Ok(ProtocolLimits {
+        max_bulk_len: config.max_bulk_len.unwrap_or(DEFAULT_MAX_BULK_LEN),
+        max_multibulk_len: config.max_multibulk_len.unwrap_or(DEFAULT_MAX_MULTIBULK_LEN),
+        max_inline_len: config.max_inline_len.unwrap_or(DEFAULT_MAX_INLINE_LEN),
+    })
+}
+
+/// Gets how many malformed requests in a row a client of the listener can send before it's hung up on.
+fn get_max_protocol_errors(config: &ListenerConfiguration) -> Result<usize, CreationError> {
+    match config.max_protocol_errors {
+        Some(0) => Err(CreationError::InvalidParameter("max_protocol_errors".to_string())),
+        max => Ok(max.unwrap_or(DEFAULT_MAX_PROTOCOL_ERRORS)),
+    }
+}
+
+/// Gets the password clients of the listener must authenticate with, if any.
+///
+/// A password kept in a file is read every time the listener is launched, so it can be rotated by
+/// updating the file and reloading.
+fn get_password(config: &ListenerConfiguration) -> Result<Option<Arc<Secret<String>>>, CreationError> {
+    let file = config.password_file.as_ref().map(|path| path.as_str());
+    let password = Secret::resolve("password", config.password.as_ref(), file)?;
+    if password.as_ref().map_or(false, |password| password.expose().is_empty()) {
+        return Err(CreationError::InvalidParameter("password".to_string()));
+    }
+
+    Ok(password.map(Arc::new))
+}
+
+/// Waits for the clients of a listener to drain once it has been closed, giving up on them if they
+/// take too long.
+///
+/// A listener that's being replaced gives its clients `reload_timeout_ms` to finish up, and one
+/// that's shutting down gives them `shutdown_timeout_ms`, where zero means waiting for as long as
+/// it takes.  Either way, whether the clients drained or had to be given up on is logged and
+/// counted, so that the timeouts can be tuned.
+fn drain_clients<E, C>(
+    name: String, evacuate: E, close: C, reload_timeout_ms: u64, shutdown_timeout_ms: u64,
+    clients: Arc<ClientRegistry>, sink: MetricSink,
+) -> impl Future<Item = (), Error = ()>
+where
+    E: Future,
+    C: Future,
+{
+    close.then(move |_| {
+        let (reason, timeout_ms) = if lifecycle::is_shutting_down() {
+            ("shutdown", shutdown_timeout_ms)
+        } else {
+            ("reload", reload_timeout_ms)
+        };
+        let started = Instant::now();
+        let deadline = if reason == "shutdown" && timeout_ms == 0 {
+            Either::A(future::empty())
+        } else {
+            Either::B(Delay::new(started + Duration::from_millis(timeout_ms)).then(|_| Ok(())))
+        };
+
+        evacuate
+            .then(|_| Ok::<_, ()>(()))
+            .select2(deadline)
+            .then(move |result| {
+                let elapsed_ms = duration_as_ms(elapsed(started));
+                match result {
+                    Ok(Either::A(_)) => {
+                        sink.increment("clients_drained");
+                        info!(
+                            "[listener] clients of listener '{}' drained after {}ms ({})",
+                            name, elapsed_ms, reason
+                        );
+                    },
+                    _ => {
+                        sink.increment("clients_drain_timeouts");
+                        let (remaining, _) = clients.list(None, 0);
+                        warn!(
+                            "[listener] gave up on clients of listener '{}' draining after {}ms ({}), with {} \
+                             client(s) still connected",
+                            name, elapsed_ms, reason, remaining
+                        );
+                    },
+                }
+
+                Ok(())
+            })
+    })
 }
 
 fn routing_from_config<P, C>(
-    name: String, config: ListenerConfiguration, listener: TcpListener, close: C, processor: P,
+    name: String, config: ListenerConfiguration, listener: TcpListener, close: C, processor: P, hold: VersionHold,
 ) -> Result<GenericRuntimeFuture, CreationError>
 where
     P: Processor + Clone + Send + 'static,
@@ -92,51 +684,196 @@ where
         Sink<SinkItem = BytesMut, SinkError = std::io::Error> + Stream<Item = P::Message, Error = ProtocolError> + Send,
     C: Future + Clone + Send + 'static,
 {
-    let reload_timeout_ms = config.reload_timeout_ms.unwrap_or_else(|| 5000);
+    // Get our scoped metric sink, and the registry our clients are tracked in.
+    let sink = get_sink().scoped(&["listeners", &name]);
+    let clients = get_client_registry(&name);
+
+    // Clients get to drain for as long as we're configured to give them, which depends on whether
+    // we're being replaced or shut down.  Anything that used to bound the former bounds the latter,
+    // too, unless told otherwise.
+    let reload_timeout_ms = config.reload_timeout_ms.unwrap_or(DEFAULT_RELOAD_TIMEOUT_MS);
+    let shutdown_timeout_ms = config.shutdown_timeout_ms.unwrap_or(reload_timeout_ms);
 
-    // Build our evacuator and wrap it as shared.  This lets us soft close everything.
-    let (warden, evacuate) = Evacuate::new(close, reload_timeout_ms);
+    // Build our evacuator and wrap it as shared.  This lets us soft close everything.  Warmers
+    // don't hold up client draining, so they watch the raw close signal instead.
+    let warmup_close = close.clone();
+    let drain_close = close.clone();
+    let (warden, evacuate) = Evacuate::new(close, EVACUATE_TIMEOUT_MS);
+    let evacuate = drain_clients(
+        name.clone(),
+        evacuate,
+        drain_close,
+        reload_timeout_ms,
+        shutdown_timeout_ms,
+        clients.clone(),
+        sink.clone(),
+    );
     let closer = evacuate.shared();
+    let drained = closer.clone();
 
-    // Get our scoped metric sink.
-    let sink = get_sink().scoped(&["listeners", &name]);
+    // Everything our clients are served with, besides the router their requests go through.
+    let mut settings = ClientSettings::from_config(&name, &config, &processor, warden, closer, sink.clone())?;
+    let fds = settings.fds.clone();
+
+    // Every listener keeps track of how long its backends take to answer each batch sent to them.
+    let backend_latencies = register_latencies(&name, "backend_batch", LatencyBuckets::from_config(&config)?);
 
     // Extract all the configured pools and build a backend pool for them.
     let mut pools = HashMap::new();
+    let mut pool_weights = Vec::new();
+    let mut pool_addresses = HashMap::new();
+    let mut retry_budgets = HashMap::new();
     let pool_configs = config.pools.clone();
     for (pool_name, pool_config) in pool_configs {
-        debug!(
-            "[listener] configuring backend pool '{}' for address '{}'",
-            &pool_name,
-            config.address.clone()
-        );
+        debug!("[listener] configuring backend pool '{}' for listener '{}'", &pool_name, &name);
+
+        let warmup_config = WarmupConfiguration::from_options(&pool_config.options)?;
+        let audit_config = AuditConfiguration::from_options(&pool_config.options)?;
+        let demotion_config = DemotionConfiguration::from_options(&pool_config.options)?;
+        let health_check_config = HealthCheckConfiguration::from_options(&pool_config.options)?;
+
+        // Anything that builds requests for the pool's backends uses the same processor it does.
+        let pool_processor = processor.for_pool(&pool_config.options)?;
+
+        let mut pool = BackendPoolBuilder::new(pool_name.clone(), pool_processor.clone(), pool_config, sink.clone())
+            .set_fd_tracker(fds.clone())
+            .set_batch_latencies(backend_latencies.clone())
+            .set_version_hold(hold.clone())
+            .build()?;
+        pool_weights.push((pool_name.clone(), pool.weights(), pool.activity(), pool.availability()));
+        pool_addresses.insert(pool_name.clone(), pool.addresses());
+        retry_budgets.insert(pool_name.clone(), pool.retry_budget());
 
-        let pool = BackendPoolBuilder::new(pool_name.clone(), processor.clone(), pool_config, sink.clone()).build()?;
-        let buffered_pool = Buffer::new_direct(pool, 32, &DefaultExecutor::current()).map_err(|_| {
+        // If slow backends should be demoted, spawn a demoter to keep an eye on their latency.
+        if let Some(demotion_config) = demotion_config {
+            let demoter = Demoter::new(
+                pool_name.clone(),
+                demotion_config,
+                pool.weights(),
+                pool.track_latencies(),
+                warmup_close.clone(),
+                sink.scoped(&["pools", pool_name.as_str(), "demotion"]),
+            );
+            tokio::spawn(LogScoped::new(slog_scope::logger(), demoter));
+        }
+
+        // If backends should be checked on, spawn a checker to eject those that stop answering.
+        if let Some(health_check_config) = health_check_config {
+            let checker = HealthChecker::new(
+                pool_name.clone(),
+                health_check_config,
+                pool_processor.clone(),
+                pool.probed(),
+                warmup_close.clone(),
+                sink.scoped(&["pools", pool_name.as_str(), "health"]),
+            );
+            tokio::spawn(LogScoped::new(slog_scope::logger(), checker));
+        }
+
+        // The pool's task drives all of its backend connections, so it's what the watchdog keeps an
+        // eye on for them.
+        let executor = WatchedExecutor::new(
+            format!("listeners.{}.pools.{}", name, pool_name),
+            Duration::from_millis(DEFAULT_HEARTBEAT_INTERVAL_MS),
+            DefaultExecutor::current(),
+        );
+        let buffered_pool = Buffer::new_direct(pool, 32, &executor).map_err(|_| {
             CreationError::InvalidResource(format!(
                 "error while building pool '{}': failed to spawn task",
                 pool_name
             ))
         })?;
-        pools.insert(pool_name, buffered_pool);
+
+        // If the pool wants to be warmed up, spawn a warmer that runs alongside the listener.
+        if let Some(warmup_config) = warmup_config {
+            let warmer = Warmer::new(
+                pool_name.clone(),
+                warmup_config,
+                pool_processor.clone(),
+                buffered_pool.clone(),
+                warmup_close.clone(),
+                sink.scoped(&["pools", pool_name.as_str(), "warmup"]),
+            );
+            tokio::spawn(LogScoped::new(slog_scope::logger(), warmer));
+        }
+
+        // If we've been asked to audit the writes sent to the pool, open up wherever they're going.
+        let audit = match audit_config {
+            Some(audit_config) => {
+                let audit_sink = sink.scoped(&["pools", pool_name.as_str(), "audit"]);
+                Some(AuditLog::new(name.clone(), pool_name.clone(), audit_config, audit_sink)?)
+            },
+            None => None,
+        };
+
+        pools.insert(pool_name, AuditedPool::new(pool_processor, buffered_pool, audit));
     }
 
+    // Clients can subscribe to the channels that `PUBLISH` would reach through the default pool, if
+    // there is one, with their subscriptions relayed straight to its backends.
+    settings.subscriptions = match (config.pools.get("default"), pool_addresses.remove("default")) {
+        (Some(pool_config), Some(addresses)) => {
+            let pool_processor = processor.for_pool(&pool_config.options)?;
+            Subscriptions::from_config(pool_processor, pool_config, addresses, fds, sink.scoped("pubsub"))?
+        },
+        _ => None,
+    };
+
     // Figure out what sort of routing we're doing so we can grab the right handler.
     let mut routing = config.routing;
     let route_type = routing
         .entry("type".to_owned())
         .or_insert_with(|| "fixed".to_owned())
         .to_lowercase();
-    match route_type.as_str() {
-        "fixed" => get_fixed_router(listener, pools, processor, warden, closer, sink),
-        "shadow" => get_shadow_router(listener, pools, processor, warden, closer, sink),
-        x => Err(CreationError::InvalidResource(format!("unknown route type '{}'", x))),
+    let key_sampler = settings.key_sampler.clone();
+    let router = match lookup(ROUTE_TYPES, &route_type) {
+        Some(RouteType::Fixed) => get_fixed_router(listener, pools, processor, settings),
+        Some(RouteType::Shadow) => get_shadow_router(listener, pools, processor, settings),
+        Some(RouteType::Split) => get_split_router(listener, pools, processor, settings),
+        Some(RouteType::Failover) => get_failover_router(listener, pools, retry_budgets, &routing, processor, settings),
+        Some(RouteType::SplitPercentage) => {
+            get_split_percentage_router(listener, pools, &routing, processor, settings)
+        },
+        None => Err(CreationError::InvalidResource(format!("unknown route type '{}'", route_type))),
+    }?;
+
+    // When shutting down, our clients are drained once we've evacuated them, which may well take
+    // longer than the phase would usually wait.
+    let drain_timeout = match shutdown_timeout_ms {
+        0 => None,
+        timeout_ms => Some(Duration::from_millis(timeout_ms)),
+    };
+    let draining = lifecycle::register_with_timeout(
+        ShutdownPhase::DrainClients,
+        format!("listeners.{}.clients", name),
+        drain_timeout,
+    );
+    tokio::spawn(drained.then(move |_| {
+        drop(draining);
+        Ok::<(), ()>(())
+    }));
+
+    // Only expose the weights of our pools once the rest of the listener has been built, so that a
+    // listener that fails to build doesn't take over the weights of the version still running.
+    for (pool_name, weights, activity, availability) in pool_weights {
+        register_backend_weights(&name, &pool_name, weights);
+        register_backend_activity(&name, &pool_name, activity);
+        register_backend_availability(&name, &pool_name, availability);
+    }
+
+    // Now that the listener is good to go, expose what it samples, and log a summary of it now and then.
+    if let Some(key_sampler) = key_sampler.as_ref() {
+        let summary = log_key_samples(name.clone(), key_sampler.clone(), warmup_close);
+        tokio::spawn(LogScoped::new(slog_scope::logger(), summary));
     }
+    register_key_sampler(&name, key_sampler);
+
+    Ok(router)
 }
 
 fn get_fixed_router<P, C>(
-    listener: TcpListener, pools: HashMap<String, BufferedPool<P, P::Message>>, processor: P, warden: Warden, close: C,
-    sink: MetricSink<&'static str>,
+    listener: TcpListener, pools: HashMap<String, BufferedPool<P, P::Message>>, processor: P,
+    settings: ClientSettings<P, C>,
 ) -> Result<GenericRuntimeFuture, CreationError>
 where
     P: Processor + Clone + Send + 'static,
@@ -152,12 +889,12 @@ where
         .clone();
     let router = FixedRouter::new(processor.clone(), default_pool);
 
-    build_router_chain(listener, processor, router, warden, close, sink)
+    build_router_chain(listener, processor, router, settings)
 }
 
 fn get_shadow_router<P, C>(
-    listener: TcpListener, pools: HashMap<String, BufferedPool<P, P::Message>>, processor: P, warden: Warden, close: C,
-    sink: MetricSink<&'static str>,
+    listener: TcpListener, pools: HashMap<String, BufferedPool<P, P::Message>>, processor: P,
+    settings: ClientSettings<P, C>,
 ) -> Result<GenericRuntimeFuture, CreationError>
 where
     P: Processor + Clone + Send + 'static,
@@ -179,11 +916,272 @@ where
 
     let router = ShadowRouter::new(processor.clone(), default_pool, shadow_pool);
 
-    build_router_chain(listener, processor, router, warden, close, sink)
+    build_router_chain(listener, processor, router, settings)
+}
+
+fn get_split_router<P, C>(
+    listener: TcpListener, pools: HashMap<String, BufferedPool<P, P::Message>>, processor: P,
+    settings: ClientSettings<P, C>,
+) -> Result<GenericRuntimeFuture, CreationError>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+    P::Transport:
+        Sink<SinkItem = BytesMut, SinkError = std::io::Error> + Stream<Item = P::Message, Error = ProtocolError> + Send,
+    C: Future + Clone + Send + 'static,
+{
+    // Construct an instance of our router.
+    let writes_pool = pools
+        .get("writes")
+        .ok_or_else(|| CreationError::InvalidResource("no writes pool configured for split router".to_string()))?
+        .clone();
+
+    let reads_pool = pools
+        .get("reads")
+        .ok_or_else(|| CreationError::InvalidResource("no reads pool configured for split router".to_string()))?
+        .clone();
+
+    let router = SplitRouter::new(processor.clone(), writes_pool, reads_pool);
+
+    build_router_chain(listener, processor, router, settings)
+}
+
+fn get_failover_router<P, C>(
+    listener: TcpListener, pools: HashMap<String, BufferedPool<P, P::Message>>,
+    retry_budgets: HashMap<String, Arc<RetryBudget>>, routing: &HashMap<String, String>, processor: P,
+    settings: ClientSettings<P, C>,
+) -> Result<GenericRuntimeFuture, CreationError>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+    P::Transport:
+        Sink<SinkItem = BytesMut, SinkError = std::io::Error> + Stream<Item = P::Message, Error = ProtocolError> + Send,
+    C: Future + Clone + Send + 'static,
+{
+    // Construct an instance of our router.
+    let default_pool = pools
+        .get("default")
+        .ok_or_else(|| CreationError::InvalidResource("no default pool configured for failover router".to_string()))?
+        .clone();
+
+    let failover_pool = pools
+        .get("failover")
+        .ok_or_else(|| CreationError::InvalidResource("no failover pool configured for failover router".to_string()))?
+        .clone();
+
+    // Failing over is retrying what the default pool couldn't serve, so it's the default pool's
+    // budget that pays for it.
+    let retry_budget = retry_budgets
+        .get("default")
+        .ok_or_else(|| CreationError::InvalidResource("no default pool configured for failover router".to_string()))?
+        .clone();
+
+    let config = FailoverConfiguration::from_options(routing)?;
+    let router = FailoverRouter::new(
+        processor.clone(),
+        default_pool,
+        failover_pool,
+        config,
+        retry_budget,
+        settings.sink.scoped("routing"),
+    );
+
+    build_router_chain(listener, processor, router, settings)
+}
+
+fn get_split_percentage_router<P, C>(
+    listener: TcpListener, pools: HashMap<String, BufferedPool<P, P::Message>>, routing: &HashMap<String, String>,
+    processor: P, settings: ClientSettings<P, C>,
+) -> Result<GenericRuntimeFuture, CreationError>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+    P::Transport:
+        Sink<SinkItem = BytesMut, SinkError = std::io::Error> + Stream<Item = P::Message, Error = ProtocolError> + Send,
+    C: Future + Clone + Send + 'static,
+{
+    // Construct an instance of our router.
+    let old_pool = pools
+        .get("old")
+        .ok_or_else(|| {
+            CreationError::InvalidResource("no old pool configured for split_percentage router".to_string())
+        })?
+        .clone();
+
+    let new_pool = pools
+        .get("new")
+        .ok_or_else(|| {
+            CreationError::InvalidResource("no new pool configured for split_percentage router".to_string())
+        })?
+        .clone();
+
+    let percentage = percentage_from_options(routing)?;
+    let router =
+        PercentageRouter::new(processor.clone(), old_pool, new_pool, percentage, settings.sink.scoped("routing"));
+
+    build_router_chain(listener, processor, router, settings)
+}
+
+/// Everything a listener serves its clients with, besides the router their requests go through.
+struct ClientSettings<P, C>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+{
+    warden: Warden,
+    close: C,
+    clients: Arc<ClientRegistry>,
+    fds: Arc<FdTracker>,
+    client_limit: Arc<ClientLimit>,
+    limits: FragmentLimits,
+    batching: BatchConfiguration,
+    idle_timeout: Option<Duration>,
+    recorder: Option<Arc<Recorder>>,
+    key_sampler: Option<Arc<KeySampler>>,
+    slo: Option<Arc<SloTable>>,
+    latencies: Arc<ClientLatencies>,
+    tls: Option<Arc<TlsTerminator>>,
+    subscriptions: Option<Subscriptions<P>>,
+    sink: MetricSink,
+}
+
+impl<P, C> ClientSettings<P, C>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+{
+    /// Builds the settings of the given listener from its configuration.
+    ///
+    /// Clients start out unable to subscribe to anything, as subscriptions are relayed through the
+    /// listener's pools, which are built afterwards.
+    fn from_config(
+        name: &str, config: &ListenerConfiguration, processor: &P, warden: Warden, close: C, sink: MetricSink,
+    ) -> Result<ClientSettings<P, C>, CreationError> {
+        // Everything this listener opens, client and backend connections alike, counts against its
+        // file descriptor limit.
+        let fds = get_fd_tracker(name, &sink);
+        fds.set_limit(config.max_fds);
+
+        // The same goes for how many clients we serve at once, which is limited separately so that a
+        // flood of clients can be turned away before it eats into the descriptors our backends need.
+        let client_limit = get_client_limit(name, &sink);
+        client_limit.configure(ClientLimitConfiguration::from_config(config)?);
+
+        // If we've been asked to record client traffic, open up the recording.
+        let recorder = match RecorderConfiguration::from_config(config)? {
+            Some(recorder_config) => Some(Recorder::new(recorder_config)?),
+            None => None,
+        };
+
+        // If we've been asked to terminate TLS, load our certificate now, so that a bad one keeps us
+        // from starting rather than failing every client.
+        let tls = match config.tls.as_ref() {
+            Some(tls_config) => Some(Arc::new(TlsTerminator::from_config(tls_config)?)),
+            None => None,
+        };
+
+        Ok(ClientSettings {
+            warden,
+            close,
+            clients: get_client_registry(name),
+            fds,
+            client_limit,
+            // Fragmented commands fan out into many backend requests, so clients may be limited in how far.
+            limits: FragmentLimits::from_config(config)?,
+            // How many commands we read from a client at once, and whether we wait around for more.
+            batching: BatchConfiguration::from_config(config)?,
+            // Clients that go quiet for long enough are disconnected, so that they don't hold on to
+            // their connection, or hold up draining, forever.
+            idle_timeout: idle_timeout_from_config(config)?,
+            recorder,
+            // If we've been asked to sample keys, set up the sampler that our clients will feed.
+            key_sampler: KeySamplerConfiguration::from_config(config)?.map(|config| Arc::new(KeySampler::new(config))),
+            // If we've been given latency objectives, resolve them up front so clients can check
+            // against them cheaply.
+            slo: SloTable::from_config(config, processor, &sink)?.map(Arc::new),
+            // Every listener keeps track of how long its clients take to set up and to be serviced.
+            latencies: Arc::new(ClientLatencies::from_config(name, config)?),
+            tls,
+            subscriptions: None,
+            sink,
+        })
+    }
+}
+
+/// Clients accepted from a listener.
+///
+/// Failing to accept a client doesn't stop us from accepting anyone else.  A client that hung up
+/// before we got to it is just skipped, and anything else, like running out of file descriptors,
+/// is waited out before trying again, so that we degrade instead of quietly going deaf.
+///
+/// If the listener is configured to pause at its client limit, we stop accepting entirely while
+/// at the limit, leaving new clients waiting in the backlog until someone else disconnects.
+struct Accepting {
+    incoming: Incoming,
+    backoff: Option<Delay>,
+    client_limit: Arc<ClientLimit>,
+    paused: bool,
+    sink: MetricSink,
+}
+
+impl Accepting {
+    fn new(listener: TcpListener, client_limit: Arc<ClientLimit>, sink: MetricSink) -> Accepting {
+        Accepting {
+            incoming: listener.incoming(),
+            backoff: None,
+            client_limit,
+            paused: false,
+            sink,
+        }
+    }
+}
+
+impl Stream for Accepting {
+    type Error = io::Error;
+    type Item = TcpStream;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(backoff) = self.backoff.as_mut() {
+                if let Ok(Async::NotReady) = backoff.poll() {
+                    return Ok(Async::NotReady);
+                }
+            }
+            self.backoff = None;
+
+            if self.client_limit.behavior() == ClientLimitBehavior::Pause {
+                if let Async::NotReady = self.client_limit.poll_available() {
+                    if !self.paused {
+                        self.paused = true;
+                        self.sink.increment("accept_paused");
+                        debug!("[listener] client limit reached, pausing accepting clients");
+                    }
+                    return Ok(Async::NotReady);
+                }
+            }
+            self.paused = false;
+
+            match self.incoming.poll() {
+                Err(ref e) if is_disconnect(e) || e.kind() == io::ErrorKind::Interrupted => {
+                    self.sink.increment("accept_disconnects");
+                },
+                Err(e) => {
+                    self.sink.increment("accept_errors");
+                    ACCEPT_ERRORS.log(
+                        Level::Error,
+                        "accept",
+                        format_args!("[listener] error while accepting clients, backing off: {}", e),
+                    );
+                    self.backoff = Some(Delay::new(Instant::now() + Duration::from_millis(ACCEPT_BACKOFF_MS)));
+                },
+                result => return result,
+            }
+        }
+    }
 }
 
 fn build_router_chain<P, R, C>(
-    listener: TcpListener, processor: P, router: R, warden: Warden, close: C, sink: MetricSink<&'static str>,
+    listener: TcpListener, processor: P, router: R, settings: ClientSettings<P, C>,
 ) -> Result<GenericRuntimeFuture, CreationError>
 where
     P: Processor + Clone + Send + 'static,
@@ -196,51 +1194,120 @@ where
     R::Future: Future + Send,
     C: Future + Clone + Send + 'static,
 {
+    let ClientSettings {
+        warden,
+        close,
+        clients,
+        fds,
+        client_limit,
+        limits,
+        batching,
+        idle_timeout,
+        recorder,
+        key_sampler,
+        slo,
+        latencies,
+        tls,
+        subscriptions,
+        sink,
+    } = settings;
+
     let close2 = close.clone();
-    let task = listener
-        .incoming()
+    let clock = system_clock();
+    let task = Accepting::new(listener, client_limit.clone(), sink.clone())
         .for_each(move |client| {
-            warden.increment();
-            sink.increment("clients_connected");
+            // A client can hang up before we even get a look at it, in which case there's nobody
+            // left to serve.
+            let client_addr = match client.peer_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    sink.increment("accept_disconnects");
+                    debug!("[listener] client went away before it could be served: {}", e);
+                    return ok(());
+                },
+            };
+
+            // If we're already serving as many clients as we're allowed to, turn the client away.
+            // Clients using TLS haven't shaken hands yet, so they're just disconnected, but anyone
+            // else is told why first.
+            let slot = match ClientLimit::try_acquire(&client_limit) {
+                Some(slot) => slot,
+                None => {
+                    sink.increment("clients_rejected_max_clients");
+                    debug!("[listener] rejected client: client limit reached");
+                    if tls.is_none() {
+                        let reply = processor.get_error_message_str("max clients reached").into_buf();
+                        tokio::spawn(io::write_all(client, reply).then(|_| Ok::<(), ()>(())));
+                    }
+                    return ok(());
+                },
+            };
+
+            // If we're out of file descriptors, turn the client away rather than starving our
+            // backend connections, or the other listeners, of them.
+            let fd = match FdTracker::try_acquire(&fds) {
+                Some(fd) => fd,
+                None => {
+                    sink.increment("clients_rejected");
+                    debug!("[listener] rejected client: file descriptor limit reached");
+                    return ok(());
+                },
+            };
 
             let router = router.clone();
             let processor = processor.clone();
             let close = close.clone();
-            let warden2 = warden.clone();
-            let sink2 = sink.clone();
-            let client_addr = client.peer_addr().unwrap();
-            debug!("[client] {} connected", client_addr);
-
-            let transport = processor.get_transport(client);
-            let runner = Pipeline::new(transport, router, processor, sink.scoped("client"))
-                .then(move |result| {
-                    match result {
-                        Ok(_) => {
-                            debug!("[client] {} disconnected", client_addr);
-                        },
-                        Err(e) => {
-                            match e {
-                                // If we got a protocol error from a client, that's bad.  Otherwise,
-                                // clients closing their connection is a normal thing.
-                                PipelineError::TransportReceive(ie) => {
-                                    if !ie.client_closed() {
-                                        sink2.increment("client_errors");
-                                        error!("[client] transport error from {}: {}", client_addr, ie);
-                                    }
-                                },
-                                e => error!("[client] error from {}: {}", client_addr, e),
-                            }
-                        },
-                    }
+            let clients = clients.clone();
+            let warden = warden.clone();
+            let sink = sink.clone();
+            let key_sampler = key_sampler.clone();
+            let slo = slo.clone();
+            let latencies = latencies.clone();
+            let recorder = recorder.clone();
+            let subscriptions = subscriptions.clone();
+            let clock = clock.clone();
 
-                    warden2.decrement();
-                    sink2.decrement("clients_connected");
+            // Clients using TLS aren't ours to serve until they've finished their handshake, and we
+            // don't hold up accepting anyone else while they do.
+            let accepted = Instant::now();
+            let handshake = match tls.as_ref() {
+                Some(tls) => Either::A(tls.accept(client)),
+                None => Either::B(ok(ClientStream::Plain(client))),
+            };
 
-                    ok::<(), ()>(())
-                })
-                .select2(close);
+            let runner = handshake.then(move |result| {
+                let client = match result {
+                    Ok(client) => client,
+                    Err(e) => {
+                        sink.increment("tls_handshake_failures");
+                        debug!("[listener] TLS handshake with {} failed: {}", client_addr, e);
+                        return Either::B(ok(()));
+                    },
+                };
+
+                if let Some(tls_handshake) = latencies.tls_handshake.as_ref() {
+                    tls_handshake.record(accepted.elapsed());
+                }
+
+                let conn = ClientConnection::new(client_addr, &clients, fd, warden, sink.clone())
+                    .set_client_slot(slot)
+                    .set_fragment_limits(limits)
+                    .set_key_sampler(key_sampler)
+                    .set_slo_table(slo)
+                    .set_latencies(Some(latencies), client.plaintext_fd());
+
+                let recording = recorder.as_ref().and_then(Recorder::start_connection);
+                let transport = processor.get_transport(client, conn.stats());
+                let transport = Recorded::new(transport, processor.clone(), recording);
+                let transport = IdleTimeout::new(transport, idle_timeout, conn.stats(), clock, sink);
+                let runner = Pipeline::new(transport, router, processor, conn, batching)
+                    .set_subscriptions(subscriptions)
+                    .select2(close);
+                Either::A(typeless(runner))
+            });
 
-            tokio::spawn(typeless(runner));
+            let logger = slog_scope::logger().new(slog_o!("client" => client_addr.to_string()));
+            tokio::spawn(LogScoped::new(logger, runner));
 
             ok(())
         })
@@ -250,13 +1317,19 @@ where
     Ok(Box::new(typeless(task)))
 }
 
-fn get_listener(addr_str: &str) -> io::Result<TcpListener> {
-    let addr = addr_str.parse().unwrap();
+fn get_listener(addr: &SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    // A socket handed to us by the process we're taking over from is already bound and listening,
+    // and clients may already be waiting on it, so all that's left is to start accepting.
+    if let Some(inherited) = handoff::take_listener(addr) {
+        info!("[listener] taking over inherited socket for {}", addr);
+        return TcpListener::from_std(inherited, &reactor::Handle::default());
+    }
+
     let builder = match addr {
         SocketAddr::V4(_) => TcpBuilder::new_v4()?,
         SocketAddr::V6(_) => TcpBuilder::new_v6()?,
     };
-    configure_builder(&builder)?;
+    configure_builder(&builder, reuse_port)?;
     builder.reuse_address(true)?;
     builder.bind(addr)?;
     builder
@@ -264,13 +1337,141 @@ fn get_listener(addr_str: &str) -> io::Result<TcpListener> {
         .and_then(|l| TcpListener::from_std(l, &reactor::Handle::default()))
 }
 
+/// Configures the listen socket.
+///
+/// Reusing the port lets a new version of a listener bind while the old version is still draining,
+/// which is why it's on by default.  Without it, a new version can't bind until the old one is gone.
 #[cfg(unix)]
-fn configure_builder(builder: &TcpBuilder) -> io::Result<()> {
+fn configure_builder(builder: &TcpBuilder, reuse_port: bool) -> io::Result<()> {
     use net2::unix::*;
 
-    builder.reuse_port(true)?;
+    builder.reuse_port(reuse_port)?;
     Ok(())
 }
 
 #[cfg(windows)]
-fn configure_builder(_builder: &TcpBuilder) -> io::Result<()> { Ok(()) }
+fn configure_builder(_builder: &TcpBuilder, _reuse_port: bool) -> io::Result<()> { Ok(()) }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_config, unreachable_pools};
+    use conf::ListenerConfiguration;
+    use serde_json::{self, json};
+
+    fn get_config(value: serde_json::Value) -> ListenerConfiguration { serde_json::from_value(value).unwrap() }
+
+    #[test]
+    fn test_check_config_valid() {
+        let config = get_config(json!({
+            "protocol": "redis",
+            "address": "127.0.0.1:6379",
+            "pools": {
+                "default": { "addresses": ["127.0.0.1:6380"], "options": { "hash": "md5" } },
+                "failover": { "addresses": ["127.0.0.1:6381"] }
+            },
+            "routing": { "type": "failover" }
+        }));
+
+        assert!(check_config("valid", &config).is_empty());
+    }
+
+    #[test]
+    fn test_check_config_problems() {
+        let config = get_config(json!({
+            "protocol": "redis",
+            "address": "localhost:six",
+            "max_bulk_len": 0,
+            "max_protocol_errors": 0,
+            "max_clients_behavior": "sulk",
+            "pools": {
+                "shadow": { "addresses": [], "options": { "hash": "nope", "conns_per_backend": 0 } }
+            },
+            "routing": { "type": "fixed" }
+        }));
+
+        let problems = check_config("broken", &config);
+        assert_eq!(problems.len(), 8);
+        assert_eq!(problems[0], "listeners.broken.address: 'localhost:six' is not a valid address");
+        assert_eq!(problems[1], "listeners.broken.max_bulk_len: invalid value");
+        assert_eq!(problems[2], "listeners.broken.max_protocol_errors: invalid value");
+        assert_eq!(problems[3], "listeners.broken.max_clients_behavior: invalid value");
+        assert_eq!(problems[4], "listeners.broken.pools.default: required by the fixed router, but not configured");
+        assert_eq!(problems[5], "listeners.broken.pools.shadow.addresses: no backends configured");
+        assert_eq!(problems[6], "listeners.broken.pools.shadow.options.conns_per_backend: invalid value");
+        assert_eq!(problems[7], "listeners.broken.pools.shadow: invalid resource: unknown hash type nope");
+    }
+
+    #[test]
+    fn test_check_config_pool_options() {
+        let config = get_config(json!({
+            "protocol": "redis",
+            "address": "127.0.0.1:6379",
+            "pools": {
+                "default": {
+                    "addresses": ["127.0.0.1:6380"],
+                    "options": { "cooloff_backoff_multiplier": 0.5, "retry_budget_ratio": 1.5 }
+                }
+            },
+            "routing": { "type": "fixed" }
+        }));
+
+        // Options that parse, but are out of range, are caught before anything is built, too.
+        let problems = check_config("ranges", &config);
+        assert_eq!(
+            problems,
+            vec![
+                "listeners.ranges.pools.default.options.cooloff_backoff_multiplier: invalid value",
+                "listeners.ranges.pools.default.options.retry_budget_ratio: invalid value",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_config_unknown_names() {
+        let config = get_config(json!({
+            "protocol": "gopher",
+            "address": "127.0.0.1:6379",
+            "pools": {
+                "default": { "addresses": ["127.0.0.1:6380"], "options": { "distribution": "nope" } }
+            },
+            "routing": { "type": "sideways" }
+        }));
+
+        let problems = check_config("unknown", &config);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].starts_with("listeners.unknown.protocol: unknown protocol 'gopher'"));
+        assert!(problems[1].starts_with("listeners.unknown.routing.type: unknown route type 'sideways'"));
+        assert_eq!(problems[2], "listeners.unknown.pools.default: invalid resource: unknown distributor type nope");
+    }
+
+    #[test]
+    fn test_unreachable_pools() {
+        let get_routed = |route_type: Option<&str>, pools: &[&str]| {
+            let mut config = ListenerConfiguration::default();
+            if let Some(route_type) = route_type {
+                config.routing.insert("type".to_owned(), route_type.to_owned());
+            }
+            for pool in pools {
+                config.pools.insert(pool.to_string(), Default::default());
+            }
+            config
+        };
+
+        assert!(unreachable_pools(&get_routed(None, &["default"])).is_empty());
+
+        let config = get_routed(Some("fixed"), &["default", "shadow", "extra"]);
+        assert_eq!(unreachable_pools(&config), vec!["extra", "shadow"]);
+        assert!(unreachable_pools(&get_routed(Some("Shadow"), &["default", "shadow"])).is_empty());
+        let config = get_routed(Some("shadow"), &["default", "shadow", "extra"]);
+        assert_eq!(unreachable_pools(&config), vec!["extra"]);
+        let config = get_routed(Some("split"), &["default", "reads", "writes"]);
+        assert_eq!(unreachable_pools(&config), vec!["default"]);
+        let config = get_routed(Some("failover"), &["default", "failover", "extra"]);
+        assert_eq!(unreachable_pools(&config), vec!["extra"]);
+        let config = get_routed(Some("split_percentage"), &["default", "new", "old"]);
+        assert_eq!(unreachable_pools(&config), vec!["default"]);
+
+        // Unknown route types are reported by the configuration check instead.
+        assert!(unreachable_pools(&get_routed(Some("bogus"), &["default", "extra"])).is_empty());
+    }
+}
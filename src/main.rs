@@ -18,6 +18,7 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 #![feature(test)]
+#![feature(integer_atomics)]
 #![feature(nll)]
 #![feature(never_type)]
 #![feature(proc_macro_hygiene)]
@@ -37,6 +38,7 @@ extern crate warp;
 extern crate config;
 extern crate crypto;
 extern crate pruefung;
+extern crate rustls;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -48,6 +50,7 @@ extern crate signal_hook;
 extern crate tokio;
 extern crate tokio_executor;
 extern crate tokio_io_pool;
+extern crate tokio_rustls;
 extern crate tower_buffer;
 extern crate tower_direct_service;
 extern crate tower_service;
@@ -55,15 +58,23 @@ extern crate tower_service;
 extern crate futures;
 extern crate futures_turnstyle;
 extern crate net2;
+extern crate num_cpus;
 
-use futures::future::{lazy, ok};
-use futures_turnstyle::{Turnstyle, Waiter};
+use futures::future::{self, lazy, ok};
+use futures_turnstyle::Turnstyle;
 use signal_hook::iterator::Signals;
-use std::thread;
-use tokio::{
-    prelude::*,
-    sync::{mpsc, oneshot},
+use std::{
+    collections::HashMap,
+    env, process,
+    sync::{
+        atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT},
+        mpsc as std_mpsc,
+        Arc,
+    },
+    thread,
+    time::Duration,
 };
+use tokio::{prelude::*, sync::mpsc};
 
 #[macro_use]
 extern crate log;
@@ -74,6 +85,8 @@ extern crate slog_scope;
 extern crate slog_stdlog;
 extern crate slog_term;
 
+extern crate serde_json;
+
 use slog::Drain;
 
 extern crate btoi;
@@ -91,44 +104,197 @@ extern crate spectral;
 extern crate tokio_evacuate;
 
 mod backend;
+mod capabilities;
 mod common;
 mod conf;
 mod errors;
+mod events;
+mod handoff;
+mod lifecycle;
 mod listener;
 mod metrics;
 mod protocol;
+mod record;
+mod reload;
 mod routing;
 mod service;
 mod util;
 
-use conf::{Configuration, LevelExt};
-use errors::CreationError;
-use util::typeless;
-
-enum SupervisorCommand {
+use backend::{
+    drain::{preserve_drains, resume_drains},
+    placement::PlacementReport,
+};
+use conf::{Configuration, LevelExt, ListenerConfiguration, MetricsConfiguration};
+use errors::{CreationError, ListenerStartError};
+use events::EventKind;
+use lifecycle::ShutdownPhase;
+use record::ReplayOptions;
+use util::{get_fd_limit, run_log_summaries, runtime::RuntimeSettings, typeless, watchdog};
+
+/// Something for the supervisor to do.
+///
+/// Reloads carry the token they were requested with, so that whoever asked can find out when
+/// they've completed.  Reloading every listener also says whether backend drains should carry
+/// over to the pools that replace theirs.
+pub enum SupervisorCommand {
     Launch,
-    Reload,
+    Reload(usize, bool),
+    ReloadListener(String, usize),
+    DrainListener(String),
     Shutdown,
 }
 
+/// A running version of a listener, and the turnstyle that closes it.
+struct ListenerHandle {
+    version: usize,
+    turnstyle: Turnstyle,
+}
+
+impl ListenerHandle {
+    /// Closes this version of the listener.
+    ///
+    /// Closing gives up the handle, so a listener can't be told to close a second time: once it
+    /// starts draining, it drains on its own schedule until it's done.
+    fn close(self) { self.turnstyle.turn(); }
+}
+
+/// What the supervisor makes of a command, once it's done with it: the listeners it's left running.
+type SupervisorFuture = Box<Future<Item = HashMap<String, ListenerHandle>, Error = ListenerStartError> + Send>;
+
+/// A configuration, and the listeners in it that we're about to launch.
+///
+/// Each listener was either prepared to be launched, or failed to be.
+struct PreparedListeners {
+    configuration: Configuration,
+    listeners: Vec<(String, Result<ListenerConfiguration, ListenerStartError>)>,
+}
+
+/// Why the main thread stopped waiting on the runtime.
+enum Stopped {
+    /// Everything running on the runtime finished.
+    Idle,
+    /// We were asked to shut down again while already shutting down, so we stopped waiting.
+    Forced,
+}
+
+/// Exit code when a listener's configuration is invalid: trying again won't help until someone
+/// fixes it.  This is `EX_CONFIG` from sysexits.h.
+const EXIT_INVALID_CONFIG: i32 = 78;
+
+/// Exit code when a listen address couldn't be bound, usually because an old instance is still
+/// draining from it: trying again soon should work.  This is `EX_TEMPFAIL` from sysexits.h.
+const EXIT_BIND_FAILED: i32 = 75;
+
+/// Exit code when we ran out of something like file descriptors while starting a listener.  This
+/// is `EX_OSERR` from sysexits.h.
+const EXIT_RESOURCE_LIMIT: i32 = 71;
+
+/// Exit code when a second interrupt forces us to exit before shutdown has finished, following the
+/// shell convention of 128 plus the signal number.
+const EXIT_FORCED: i32 = 128 + libc::SIGINT;
+
+// How long to wait before first retrying to bind the stats address, if it's taken.
+const DEFAULT_STATS_BIND_RETRY_MS: u64 = 1000;
+
+// How often metrics are snapshotted for stats deltas, and how long those snapshots are kept.
+const DEFAULT_STATS_HISTORY_INTERVAL_MS: u64 = 1000;
+const DEFAULT_STATS_HISTORY_SECS: u64 = 300;
+
+// Set to the code to exit with when the supervisor gives up because listeners couldn't be launched.
+static LAUNCH_EXIT_CODE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// The generation of the configuration that was last applied, which only changes when a launch or
+// reload succeeds.  Zero means no configuration has been applied yet.
+static CONFIG_GENERATION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// The last generation handed out to a launch, whether or not it succeeded.  Every launch gets a
+// generation of its own, so that what's logged by one that failed is never mistaken for what's
+// logged under a configuration that was applied later.
+static LAST_LAUNCH_GENERATION: AtomicUsize = ATOMIC_USIZE_INIT;
+
 fn main() {
-    // Set up our signal handling before anything else.
+    // Replaying a recording is a standalone tool, so it doesn't need any configuration or signal
+    // handling: do it and get out.
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--replay") {
+        match ReplayOptions::from_args(&args).and_then(|options| record::replay(&options)) {
+            Ok(summary) => {
+                println!("synchrotron: {}", summary);
+                process::exit(0);
+            },
+            Err(e) => {
+                eprintln!("synchrotron: {}", e);
+                process::exit(1);
+            },
+        }
+    }
+
+    // Describing what we can do doesn't depend on any configuration either.
+    if args.iter().any(|arg| arg == "--capabilities") {
+        let capabilities = serde_json::to_string_pretty(&capabilities::get_capabilities())
+            .expect("failed to serialize capabilities");
+        println!("{}", capabilities);
+        process::exit(0);
+    }
+
+    // Checking a configuration stops short of starting anything, so it never binds a socket.
+    if args.iter().any(|arg| arg == "--check") {
+        let allow_empty = args.iter().any(|arg| arg == "--allow-empty");
+        process::exit(check_configuration(allow_empty));
+    }
+
+    // Set up our signal handling before anything else.  Writing to a client that has already hung
+    // up should show up as an error on that write, rather than taking the whole process down, so
+    // we ignore SIGPIPE ourselves instead of counting on the runtime to have done it for us.
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    }
+
     let (mut supervisor_tx, supervisor_rx) = mpsc::unbounded_channel();
-    let signals = Signals::new(&[libc::SIGINT, libc::SIGUSR1]).expect("failed to register signal handlers");
+    let admin_tx = supervisor_tx.clone();
+    let (stopped_tx, stopped_rx) = std_mpsc::channel();
+    let forced_tx = stopped_tx.clone();
+    let signals =
+        Signals::new(&[libc::SIGINT, libc::SIGUSR1, libc::SIGUSR2]).expect("failed to register signal handlers");
     thread::spawn(move || {
         // Do an initial send of the launch command to trigger actually spawning the listeners at
         // startup.
         let _ = supervisor_tx.try_send(SupervisorCommand::Launch);
 
+        // We keep listening after the first interrupt: shutting down only starts once, and if
+        // we're interrupted again while it's underway, we stop waiting for it to finish.
         for signal in signals.forever() {
             info!("[core] signal received: {:?}", signal);
 
             match signal {
+                libc::SIGUSR1 if lifecycle::is_shutting_down() => {
+                    warn!("[core] ignoring reload signal: already shutting down");
+                },
                 libc::SIGUSR1 => {
-                    let _ = supervisor_tx.try_send(SupervisorCommand::Reload);
+                    let _ = supervisor_tx.try_send(SupervisorCommand::Reload(reload::request(), false));
                 },
-                libc::SIGINT => {
+                libc::SIGUSR2 if lifecycle::is_shutting_down() => {
+                    warn!("[core] ignoring upgrade signal: already shutting down");
+                },
+                libc::SIGUSR2 => {
+                    // Upgrading hands our listening sockets to a new copy of ourselves, and once it's
+                    // accepting on them, we shut down just as if we'd been interrupted.
+                    let mut upgraded_tx = supervisor_tx.clone();
+                    let started = handoff::begin_handoff(move || {
+                        if lifecycle::begin_shutdown() {
+                            let _ = upgraded_tx.try_send(SupervisorCommand::Shutdown);
+                        }
+                    });
+                    if let Err(e) = started {
+                        error!("[core] failed to start a new process to upgrade to: {}", e);
+                    }
+                },
+                libc::SIGINT if lifecycle::begin_shutdown() => {
                     let _ = supervisor_tx.try_send(SupervisorCommand::Shutdown);
+                },
+                libc::SIGINT => {
+                    warn!("[core] interrupted again while shutting down, forcing exit");
+                    let _ = forced_tx.send(Stopped::Forced);
                     break;
                 },
                 _ => {}, // we don't care about the rest
@@ -136,7 +302,27 @@ fn main() {
         }
     });
 
-    let configuration = Configuration::new().expect("failed to parse configuration");
+    // Uptime is counted from here, now that we know we're going to be proxying.
+    metrics::mark_started();
+
+    // Logging isn't configured until we have a configuration, so anything worth logging about how
+    // the configuration was loaded waits until it is.
+    let (configuration, env_overrides) = Configuration::load().expect("failed to parse configuration");
+
+    // Refuse to start with nothing to serve, unless we've been told that's what we want.
+    let allow_empty = args.iter().any(|arg| arg == "--allow-empty");
+    if let Err(e) = configuration.check_listeners(allow_empty) {
+        eprintln!("synchrotron: {}", e);
+        process::exit(EXIT_INVALID_CONFIG);
+    }
+
+    // Showing where keys would be placed is a pre-flight check, so it doesn't start anything.
+    if args.iter().any(|arg| arg == "--explain-distribution") {
+        if explain_distribution(&configuration) {
+            process::exit(0);
+        }
+        process::exit(EXIT_INVALID_CONFIG);
+    }
 
     // Configure our logging.  This gives us fully asynchronous logging to the terminal
     // which is also level filtered.  As well, we've replaced the global std logger
@@ -152,100 +338,545 @@ fn main() {
     let _scope_guard = slog_scope::set_global_logger(logger);
     slog_stdlog::init().unwrap();
     info!("[core] logging configured");
+    conf::log_env_overrides(&env_overrides);
 
-    tokio_io_pool::run(lazy(move || {
-        let (shutdown_tx, shutdown_rx) = oneshot::channel();
-        launch_metrics(configuration.stats_addr, shutdown_rx);
-        launch_supervisor(supervisor_rx, shutdown_tx);
+    // If we were started to take over from another process, pick up the sockets it handed us.
+    handoff::inherit();
 
-        info!("[core] synchrotron running");
+    check_fd_limit(&configuration);
 
-        ok(())
-    }))
+    let settings = RuntimeSettings::from_config(configuration.runtime.as_ref());
+    info!("[core] runtime configured with {}", settings);
+    let runtime = settings.build().expect("failed to build runtime");
+
+    runtime
+        .spawn(lazy(move || {
+            launch_metrics(&configuration, admin_tx);
+            launch_watchdog();
+            launch_log_summaries();
+            launch_supervisor(supervisor_rx);
+
+            info!("[core] synchrotron running");
+
+            ok(())
+        }))
+        .expect("failed to start runtime");
+
+    // Wait on the runtime from the side, so that a forced exit doesn't have to wait on it.
+    thread::spawn(move || {
+        runtime.shutdown_on_idle();
+        let _ = stopped_tx.send(Stopped::Idle);
+    });
+
+    // Either way, dropping the scope guard before exiting lets any buffered log lines get written out.
+    if let Ok(Stopped::Forced) = stopped_rx.recv() {
+        warn!("[shutdown] forced exit {}", lifecycle::get_progress());
+        drop(_scope_guard);
+        process::exit(EXIT_FORCED);
+    }
+
+    // If we stopped because listeners couldn't be launched, make sure whoever started us knows.
+    let exit_code = LAUNCH_EXIT_CODE.load(Ordering::SeqCst);
+    if exit_code != 0 {
+        drop(_scope_guard);
+        process::exit(exit_code as i32);
+    }
 }
 
-fn launch_supervisor(supervisor_rx: mpsc::UnboundedReceiver<SupervisorCommand>, shutdown_tx: oneshot::Sender<()>) {
+fn launch_supervisor(supervisor_rx: mpsc::UnboundedReceiver<SupervisorCommand>) {
     let sink = metrics::get_sink().scoped("supervisor");
 
-    let turnstyle = Turnstyle::new();
     let supervisor = supervisor_rx
-        .map_err(|_| CreationError::ListenerSpawnFailed)
-        .fold(turnstyle, move |ts, command| {
+        .map_err(|_| ListenerStartError::from(CreationError::ListenerSpawnFailed))
+        // The admin API holds its own sender, so the command stream won't end on its own when
+        // the signal handler goes away: stop at the shutdown command instead.
+        .take_while(|command| {
+            Ok(match command {
+                SupervisorCommand::Shutdown => false,
+                _ => true,
+            })
+        })
+        .fold(HashMap::new(), move |mut listeners, command| -> SupervisorFuture {
+            // Once shutdown has begun, the listeners we have are the last ones we'll ever have.
+            if lifecycle::is_shutting_down() {
+                match command {
+                    SupervisorCommand::Reload(token, _) | SupervisorCommand::ReloadListener(_, token) => {
+                        warn!("[core] not applying reload {}: already shutting down", token);
+                        reload::failed(token);
+                        return Box::new(ok(listeners));
+                    },
+                    SupervisorCommand::Launch => {
+                        warn!("[core] not launching listeners: already shutting down");
+                        return Box::new(ok(listeners));
+                    },
+                    _ => {},
+                }
+            }
+
+            let sink = sink.clone();
             match command {
                 SupervisorCommand::Launch => {
-                    let (version, waiter) = ts.join();
-                    launch_listeners(version, waiter)?;
-                    sink.increment("configuration_loads");
+                    Box::new(prepare_listeners(None).and_then(move |prepared| {
+                        launch_listeners(&mut listeners, None, prepared)?;
+                        handoff::signal_ready();
+                        sink.increment("configuration_loads");
+                        sink.update_gauge("config_generation", get_config_generation() as u64);
+                        Ok(listeners)
+                    }))
+                },
+                SupervisorCommand::Reload(token, keep_drains) => {
+                    // Only the initial launch is all-or-nothing: a reload that fails leaves us
+                    // serving whatever configuration we were serving before it.
+                    Box::new(prepare_listeners(None).then(move |prepared| {
+                        // Reloading puts every backend back the way it's configured, unless we've
+                        // been asked to keep draining what was being drained.
+                        let drains = if keep_drains { preserve_drains() } else { Vec::new() };
+                        let result = prepared.and_then(|prepared| launch_listeners(&mut listeners, None, prepared));
+                        match result {
+                            Ok(()) => {
+                                resume_drains(drains);
+                                reload::applied(token);
+                                sink.increment("configuration_loads");
+                                sink.update_gauge("config_generation", get_config_generation() as u64);
+                                events::publish(
+                                    EventKind::ListenersReloaded,
+                                    None,
+                                    None,
+                                    format!("reload {}, config generation {}", token, get_config_generation()),
+                                );
+                            },
+                            Err(e) => {
+                                // Nothing was replaced, so whatever was draining is draining still.
+                                reload::failed(token);
+                                error!("[core] failed to reload: {}", e);
+                                events::publish(
+                                    EventKind::ReloadFailed,
+                                    None,
+                                    None,
+                                    format!("reload {}: {}", token, e),
+                                );
+                            },
+                        }
+                        Ok(listeners)
+                    }))
                 },
-                SupervisorCommand::Reload => {
-                    let (version, waiter) = ts.join();
-                    launch_listeners(version, waiter)?;
-                    ts.turn();
-                    sink.increment("configuration_loads");
+                SupervisorCommand::ReloadListener(name, token) => {
+                    // Reloading a single listener is an administrative action, so a bad
+                    // configuration shouldn't take down every other listener with it.
+                    Box::new(prepare_listeners(Some(name.clone())).then(move |prepared| {
+                        let result =
+                            prepared.and_then(|prepared| launch_listeners(&mut listeners, Some(&name), prepared));
+                        match result {
+                            Ok(()) => {
+                                reload::applied(token);
+                                sink.increment("configuration_loads");
+                                sink.update_gauge("config_generation", get_config_generation() as u64);
+                                events::publish(
+                                    EventKind::ListenersReloaded,
+                                    None,
+                                    None,
+                                    format!("reload {} of listener '{}'", token, name),
+                                );
+                            },
+                            Err(e) => {
+                                reload::failed(token);
+                                error!("[core] failed to reload listener '{}': {}", name, e);
+                                events::publish(
+                                    EventKind::ReloadFailed,
+                                    None,
+                                    None,
+                                    format!("reload {} of listener '{}': {}", token, name, e),
+                                );
+                            },
+                        }
+                        Ok(listeners)
+                    }))
                 },
-                SupervisorCommand::Shutdown => {
-                    ts.turn();
+                SupervisorCommand::DrainListener(name) => {
+                    match listeners.remove(&name) {
+                        Some(handle) => {
+                            info!("[core] draining listener '{}' (v{})", name, handle.version);
+                            reload::clear_current(&name);
+                            handle.close();
+                        },
+                        None => warn!("[core] asked to drain listener '{}', but it isn't running", name),
+                    }
+                    Box::new(ok(listeners))
                 },
+                SupervisorCommand::Shutdown => unreachable!("shutdown ends the command stream"),
             }
-
-            Ok(ts)
         })
         .then(move |result| {
-            if let Err(e) = result {
-                error!("[core supervisor] caught an error during launch/reload: {}", e);
+            match result {
+                Ok(listeners) => {
+                    // Closing our listeners is the first step of shutting down, so that everything
+                    // else can be stopped in order behind them.
+                    let shutdown = lifecycle::register(ShutdownPhase::StopAccepting, "supervisor");
+                    let close = shutdown.signal().map(move |_| {
+                        for (_, handle) in listeners {
+                            handle.close();
+                        }
+                        drop(shutdown);
+                    });
+                    tokio::spawn(close);
+                },
+                Err(e) => {
+                    let exit_code = get_exit_code(&e);
+                    error!(
+                        "[core supervisor] caught an error during launch/reload, exiting with code {}: {}",
+                        exit_code, e
+                    );
+                    LAUNCH_EXIT_CODE.store(exit_code as usize, Ordering::SeqCst);
+                },
             }
 
-            shutdown_tx.send(())
+            lifecycle::shutdown()
         });
 
     tokio::spawn(typeless(supervisor));
 }
 
-fn launch_listeners(version: usize, close: Waiter) -> Result<(), CreationError> {
-    let configuration = Configuration::new().expect("failed to parse configuration");
-    let closer = close.shared();
-    let listeners = configuration
-        .listeners
-        .into_iter()
-        .map(|(name, config)| {
-            let close = closer.clone();
+/// Loads the current configuration, and prepares the listeners in it to be launched.
+///
+/// If `only` is given, just that listener is prepared.  Preparing a listener can mean waiting on
+/// its backends, so every listener is prepared at once, and none of them block the runtime while
+/// they wait.
+fn prepare_listeners(only: Option<String>) -> impl Future<Item = PreparedListeners, Error = ListenerStartError> + Send {
+    future::result(get_listener_configs(only.as_ref().map(|name| name.as_str()))).and_then(|(configuration, configs)| {
+        let prepared = configs.into_iter().map(|(name, config)| {
+            listener::prepare(name.clone(), config).then(move |result| Ok((name, result)))
+        });
 
-            listener::from_config(version, name, config, close)
+        future::join_all(prepared).map(move |listeners| {
+            PreparedListeners {
+                configuration,
+                listeners,
+            }
         })
-        .collect::<Vec<_>>();
+    })
+}
 
+/// Loads the current configuration, along with the configuration of every listener to launch from it.
+fn get_listener_configs(
+    only: Option<&str>,
+) -> Result<(Configuration, HashMap<String, ListenerConfiguration>), ListenerStartError> {
+    let configuration = Configuration::new().map_err(|e| {
+        error!("[core] failed to load configuration: {}", e);
+        CreationError::ListenerSpawnFailed
+    })?;
+
+    let mut configs = configuration.listeners.clone();
+    if let Some(name) = only {
+        let config = configs.remove(name).ok_or_else(|| {
+            error!("[core] listener '{}' is not in the configuration", name);
+            CreationError::ListenerSpawnFailed
+        })?;
+
+        configs = HashMap::new();
+        configs.insert(name.to_owned(), config);
+    }
+
+    // Listeners that don't say how long to let their clients drain for when shutting down take
+    // whatever the configuration says for all of them.
+    for config in configs.values_mut() {
+        config.shutdown_timeout_ms = config.shutdown_timeout_ms.or(configuration.shutdown_timeout_ms);
+    }
+
+    Ok((configuration, configs))
+}
+
+/// Launches the given prepared listeners.
+///
+/// Every listener runs independently: each has its own version, and is closed by its own
+/// turnstyle, so reloading or draining one listener never disturbs the others.  If `only` is
+/// given, just that listener is (re)launched.  Otherwise, every configured listener is, and any
+/// running listeners that are no longer configured are closed.
+///
+/// Every listener that fails to start is logged, but only the failure that most needs someone to
+/// look at it is returned: bad configuration first, then resource limits, then bind failures.
+///
+/// Listeners are launched under a generation of their own, which only becomes the current one if
+/// they all start.  A failed launch's generation is never reused, so applied generations can skip
+/// numbers, but a generation always names exactly one configuration.
+fn launch_listeners(
+    listeners: &mut HashMap<String, ListenerHandle>, only: Option<&str>, prepared: PreparedListeners,
+) -> Result<(), ListenerStartError> {
+    let PreparedListeners {
+        configuration,
+        listeners: configs,
+    } = prepared;
+
+    // Build every listener before launching any of them, so that one bad listener doesn't leave
+    // us with a half-applied configuration.
+    let config_gen = LAST_LAUNCH_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    let mut launched = Vec::new();
     let mut errors = Vec::new();
-    for listener in &listeners {
-        let result = listener.as_ref();
-        if result.is_err() {
-            let error = result.err().unwrap();
-            errors.push(error.to_string());
+    for (name, config) in configs {
+        let config = match config {
+            Ok(config) => config,
+            Err(e) => {
+                errors.push((name, e));
+                continue;
+            },
+        };
+
+        let version = listeners.get(&name).map(|handle| handle.version + 1).unwrap_or(0);
+        let turnstyle = Turnstyle::new();
+        let (_, waiter) = turnstyle.join();
+        let hold = reload::hold_version(&name, version);
+
+        match listener::from_config(version, config_gen, name.clone(), config, waiter.shared(), hold) {
+            Ok(listener) => launched.push((name, ListenerHandle { version, turnstyle }, listener)),
+            Err(e) => errors.push((name, e)),
         }
     }
 
     if !errors.is_empty() {
         error!("[core] encountered errors while spawning listeners:");
-        for error in errors {
-            error!("[core] - {}", error);
+        for (name, error) in &errors {
+            let logger = slog_scope::logger().new(slog_o!(
+                "listener" => name.clone(),
+                "config_gen" => config_gen,
+                "failure" => error.kind()
+            ));
+            slog_scope::scope(&logger, || error!("[core] - listener '{}': {}", name, error));
         }
 
-        return Err(CreationError::ListenerSpawnFailed);
+        let error = errors
+            .into_iter()
+            .map(|(_, error)| error)
+            .min_by_key(|error| {
+                match error {
+                    ListenerStartError::Construct(_) => 0,
+                    ListenerStartError::ResourceLimit(_) => 1,
+                    ListenerStartError::Bind(_) => 2,
+                }
+            })
+            .expect("errors is not empty");
+        return Err(error);
+    }
+
+    // Launch all these listeners into the runtime, and close out whatever they replace.
+    let mut launched_names = Vec::new();
+    for (name, handle, listener) in launched {
+        tokio::spawn(listener);
+        reload::set_current(&name, handle.version);
+
+        if let Some(old) = listeners.insert(name.clone(), handle) {
+            old.close();
+        }
+        launched_names.push(name);
     }
 
-    // Launch all these listeners into the runtime.
-    for listener in listeners {
-        tokio::spawn(listener.unwrap());
+    if only.is_none() {
+        let removed = listeners
+            .keys()
+            .filter(|name| !launched_names.contains(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in removed {
+            if let Some(old) = listeners.remove(&name) {
+                info!("[core] listener '{}' is no longer configured, closing it", name);
+                reload::clear_current(&name);
+                old.close();
+            }
+        }
     }
 
+    CONFIG_GENERATION.store(config_gen, Ordering::SeqCst);
+    conf::set_applied(config_gen, &configuration, only);
+    info!("[core] applied configuration generation {}", config_gen);
+
     Ok(())
 }
 
-fn launch_metrics(stats_addr: String, shutdown_rx: impl Future<Item = ()> + Send + 'static) {
-    let addr = stats_addr.parse().expect("failed to parse metrics listen address");
-    let facade = metrics::get_facade();
-    let controller = facade.get_controller();
-    let http = metrics::build_with_graceful_shutdown(addr, controller, shutdown_rx);
+/// Gets the generation of the configuration that was last applied.
+fn get_config_generation() -> usize { CONFIG_GENERATION.load(Ordering::SeqCst) }
+
+/// Gets the code to exit with when a listener fails to start.
+fn get_exit_code(error: &ListenerStartError) -> i32 {
+    match error {
+        ListenerStartError::Bind(_) => EXIT_BIND_FAILED,
+        ListenerStartError::Construct(_) => EXIT_INVALID_CONFIG,
+        ListenerStartError::ResourceLimit(_) => EXIT_RESOURCE_LIMIT,
+    }
+}
+
+/// Checks the configuration, and every listener in it, without binding or connecting to anything.
+///
+/// Every problem found is printed along with where in the configuration it is.  Returns the code to
+/// exit with: zero if the configuration is good, and one otherwise.
+fn check_configuration(allow_empty: bool) -> i32 {
+    let configuration = match Configuration::new() {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            eprintln!("synchrotron: {}", e);
+            return 1;
+        },
+    };
+
+    let mut problems = Vec::new();
+    if let Err(e) = configuration.check_listeners(allow_empty) {
+        problems.push(e.to_string());
+    }
+
+    let mut listener_names = configuration.listeners.keys().collect::<Vec<_>>();
+    listener_names.sort();
+    for listener_name in listener_names {
+        problems.extend(listener::check_config(listener_name, &configuration.listeners[listener_name]));
+    }
+
+    if !problems.is_empty() {
+        for problem in problems {
+            eprintln!("synchrotron: {}", problem);
+        }
+        return 1;
+    }
+
+    println!("synchrotron: configuration is valid ({} listener(s))", configuration.listeners.len());
+    0
+}
+
+/// Prints where every pool would place its canary keys, and how evenly it spreads keys overall.
+///
+/// Returns `false` if any pool can't be configured, or places a key somewhere other than expected.
+fn explain_distribution(configuration: &Configuration) -> bool {
+    let mut ok = true;
+
+    let mut listener_names = configuration.listeners.keys().collect::<Vec<_>>();
+    listener_names.sort();
+    for listener_name in listener_names {
+        let listener = &configuration.listeners[listener_name];
+        let mut pool_names = listener.pools.keys().collect::<Vec<_>>();
+        pool_names.sort();
+
+        for pool_name in pool_names {
+            let pool = &listener.pools[pool_name];
+            println!("listener '{}', pool '{}':", listener_name, pool_name);
+
+            let report = match PlacementReport::from_config(pool) {
+                Ok(report) => report,
+                Err(e) => {
+                    println!("  error: {}", e);
+                    ok = false;
+                    continue;
+                },
+            };
+            for line in report.to_string().lines() {
+                println!("  {}", line);
+            }
+
+            if let Some(ref expected) = pool.expected_placements {
+                for mismatch in report.check(expected) {
+                    println!("  mismatch: {}", mismatch);
+                    ok = false;
+                }
+            }
+        }
+    }
+
+    ok
+}
+
+/// Logs the process file descriptor limit, warning if the listeners' own limits could add up to
+/// most of it.
+fn check_fd_limit(configuration: &Configuration) {
+    let limit = match get_fd_limit() {
+        Ok(limit) => limit,
+        Err(e) => {
+            warn!("[core] failed to get file descriptor limit: {}", e);
+            return;
+        },
+    };
+    info!("[core] file descriptor limit is {}", limit);
+
+    let configured = configuration
+        .listeners
+        .values()
+        .filter_map(|listener| listener.max_fds)
+        .sum::<usize>() as u64;
+    if configured > limit / 10 * 8 {
+        warn!(
+            "[core] listeners may use up to {} file descriptors, which is over 80% of the limit of {}",
+            configured, limit
+        );
+    }
+}
+
+fn launch_watchdog() {
+    let sink = metrics::get_sink().scoped("watchdog");
+    let shutdown = lifecycle::register(ShutdownPhase::StopAdmin, "watchdog");
+    let watchdog = watchdog::run_watchdog(sink, shutdown.signal()).map(move |_| drop(shutdown));
+    tokio::spawn(watchdog);
+}
+
+fn launch_log_summaries() {
+    let shutdown = lifecycle::register(ShutdownPhase::StopAdmin, "log_summaries");
+    let summaries = run_log_summaries(shutdown.signal()).map(move |_| drop(shutdown));
+    tokio::spawn(summaries);
+}
+
+fn launch_statsd(config: &MetricsConfiguration) {
+    // The configuration has already been validated, so the address is good.
+    let sink = metrics::get_sink().scoped("metrics");
+    match metrics::StatsdEmitter::new(config, sink) {
+        Ok(emitter) => {
+            info!("[metrics] sending metrics to statsd at {}", config.address);
+            let controller = metrics::get_facade().get_controller();
+            let shutdown = lifecycle::register(ShutdownPhase::StopAdmin, "statsd");
+            let statsd = metrics::StatsdTask::new(config, emitter, controller, shutdown.signal())
+                .map(move |_| drop(shutdown));
+            tokio::spawn(statsd);
+        },
+        Err(e) => error!("[metrics] failed to set up sending metrics to statsd: {}", e),
+    }
+}
+
+fn launch_metrics(configuration: &Configuration, admin_tx: mpsc::UnboundedSender<SupervisorCommand>) {
+    // The configuration has already been validated, so the address is good if we have one.
+    match configuration.get_stats_addr().expect("invalid stats address") {
+        Some(addr) => {
+            let retry = Duration::from_millis(configuration.stats_bind_retry_ms.unwrap_or(DEFAULT_STATS_BIND_RETRY_MS));
+            let facade = metrics::get_facade();
+            let controller = facade.get_controller();
+
+            // Stats deltas are only served by the stats server, so the history is only kept for it.
+            let interval = configuration
+                .stats_history_interval_ms
+                .unwrap_or(DEFAULT_STATS_HISTORY_INTERVAL_MS);
+            let retention = configuration.stats_history_secs.unwrap_or(DEFAULT_STATS_HISTORY_SECS);
+            let history = Arc::new(metrics::StatsHistory::new(
+                Duration::from_millis(interval),
+                Duration::from_secs(retention),
+            ));
+            let history_shutdown = lifecycle::register(ShutdownPhase::StopAdmin, "stats_history");
+            let sampler = metrics::run_history(controller.clone(), history.clone(), history_shutdown.signal())
+                .map(move |_| drop(history_shutdown));
+            tokio::spawn(sampler);
+
+            let shutdown = lifecycle::register(ShutdownPhase::StopAdmin, "admin");
+            let http =
+                metrics::build_with_graceful_shutdown(addr, controller, history, admin_tx, retry, shutdown.signal())
+                    .then(move |result| {
+                        drop(shutdown);
+                        result
+                    });
+
+            tokio::spawn(http);
+        },
+        None => warn!("[metrics] stats_addr is empty, so the stats, admin and health endpoints are disabled"),
+    }
+
+    if let Some(ref metrics) = configuration.metrics {
+        launch_statsd(metrics);
+    }
 
-    tokio::spawn(http);
-    info!("[metrics] serving metric data on {}...", stats_addr);
+    // Make sure the last of the metrics from everything we've shut down make it into the stats
+    // before we stop serving them.
+    let flush = lifecycle::register(ShutdownPhase::FlushMetrics, "metrics");
+    let flushed = flush
+        .signal()
+        .and_then(|_| metrics::get_sink().flush())
+        .map(move |_| drop(flush));
+    tokio::spawn(flushed);
 }
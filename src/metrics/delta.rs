@@ -0,0 +1,410 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::latency::{get_latencies, quantile_from_cumulative, REPORTED_QUANTILES};
+use futures::prelude::*;
+use hotmic::Controller;
+use serde_json::{self, Map, Value};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+use util::clock::{duration_as_ms, saturating_duration_since};
+
+/// Every metric, and every latency histogram, as of a given point in time.
+pub struct StatsSnapshot {
+    taken: Instant,
+    metrics: Map<String, Value>,
+    latencies: Vec<LatencySnapshot>,
+}
+
+/// A latency histogram as of a given point in time, named by the prefix it's reported under.
+struct LatencySnapshot {
+    prefix: String,
+    cumulative: Vec<(Option<u64>, usize)>,
+    sum_us: u64,
+}
+
+impl StatsSnapshot {
+    /// Captures the given metrics snapshot, taken at `taken`, along with every latency histogram.
+    pub fn capture(taken: Instant, metrics: Value) -> StatsSnapshot {
+        let latencies = get_latencies()
+            .into_iter()
+            .map(|(listener, name, histogram)| {
+                LatencySnapshot {
+                    prefix: format!("listeners.{}.client.{}_latency", listener, name),
+                    cumulative: histogram.cumulative(),
+                    sum_us: histogram.sum_us(),
+                }
+            })
+            .collect();
+        StatsSnapshot::new(taken, metrics, latencies)
+    }
+
+    fn new(taken: Instant, metrics: Value, latencies: Vec<LatencySnapshot>) -> StatsSnapshot {
+        let metrics = match metrics {
+            Value::Object(metrics) => metrics,
+            _ => Map::new(),
+        };
+
+        StatsSnapshot {
+            taken,
+            metrics,
+            latencies,
+        }
+    }
+}
+
+/// Why a delta couldn't be worked out over the requested window.
+#[derive(Debug, PartialEq)]
+pub enum DeltaError {
+    /// The window is shorter than the interval snapshots are taken at.
+    TooShort(Duration),
+
+    /// The window goes back further than the snapshots we've kept, which span the given duration.
+    TooLong(Duration),
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeltaError::TooShort(interval) => {
+                write!(f, "window must be at least the snapshot interval of {}ms", duration_as_ms(*interval))
+            },
+            DeltaError::TooLong(retained) => {
+                write!(f, "window exceeds the {}ms of snapshots retained", duration_as_ms(*retained))
+            },
+        }
+    }
+}
+
+/// How metrics changed between two snapshots.
+///
+/// Counters are given as per-second rates, every other number as its value in the newer snapshot,
+/// and latency histograms are summed up over only what was recorded between the two snapshots.
+#[derive(Debug, Serialize)]
+pub struct StatsDelta {
+    /// The window that was asked for.
+    pub window_ms: u64,
+
+    /// The time between the two snapshots the delta was worked out from.
+    pub elapsed_ms: u64,
+
+    /// How long before the delta was asked for the newer of the two snapshots was taken.
+    pub age_ms: u64,
+
+    pub rates: BTreeMap<String, f64>,
+    pub gauges: BTreeMap<String, Value>,
+    pub latencies: BTreeMap<String, u64>,
+}
+
+/// A short history of snapshots, taken periodically, that deltas are worked out from.
+///
+/// Only as many snapshots as cover the retention period are kept, with the oldest making way for
+/// the newest.
+pub struct StatsHistory {
+    interval: Duration,
+    capacity: usize,
+    snapshots: Mutex<VecDeque<StatsSnapshot>>,
+}
+
+impl StatsHistory {
+    /// Creates an empty history of snapshots taken every `interval`, kept for `retention`.
+    pub fn new(interval: Duration, retention: Duration) -> StatsHistory {
+        let interval_ms = duration_as_ms(interval).max(1);
+        let capacity = (duration_as_ms(retention) / interval_ms) as usize + 1;
+
+        StatsHistory {
+            interval,
+            capacity,
+            snapshots: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Gets the interval that snapshots are taken at.
+    pub fn interval(&self) -> Duration { self.interval }
+
+    /// Adds a snapshot to the history, dropping the oldest if the history is full.
+    pub fn push(&self, snapshot: StatsSnapshot) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        while snapshots.len() >= self.capacity {
+            snapshots.pop_front();
+        }
+        snapshots.push_back(snapshot);
+    }
+
+    /// Works out how metrics have changed over the given window, as of `now`.
+    ///
+    /// The delta runs from the newest snapshot back to the snapshot closest to `window` before it,
+    /// so the time actually covered can be off by up to half of the snapshot interval, and is given
+    /// in the delta.  Only metrics named in `counters` are given as a rate, and the rest as-is.
+    pub fn delta(&self, window: Duration, now: Instant, counters: &HashSet<String>) -> Result<StatsDelta, DeltaError> {
+        if window < self.interval {
+            return Err(DeltaError::TooShort(self.interval));
+        }
+
+        let snapshots = self.snapshots.lock().unwrap();
+        let retained = match (snapshots.front(), snapshots.back()) {
+            (Some(oldest), Some(newest)) => saturating_duration_since(newest.taken, oldest.taken),
+            _ => Duration::from_millis(0),
+        };
+        let end = snapshots.back().ok_or_else(|| DeltaError::TooLong(retained))?;
+
+        let earliest = window - self.interval / 2;
+        let start = snapshots
+            .iter()
+            .rev()
+            .find(|snapshot| saturating_duration_since(end.taken, snapshot.taken) >= earliest)
+            .ok_or_else(|| DeltaError::TooLong(retained))?;
+
+        let elapsed = saturating_duration_since(end.taken, start.taken);
+        Ok(StatsDelta {
+            window_ms: duration_as_ms(window),
+            elapsed_ms: duration_as_ms(elapsed),
+            age_ms: duration_as_ms(saturating_duration_since(now, end.taken)),
+            rates: get_rates(start, end, elapsed, counters),
+            gauges: get_gauges(end, counters),
+            latencies: get_latency_deltas(start, end),
+        })
+    }
+}
+
+fn get_rates(
+    start: &StatsSnapshot, end: &StatsSnapshot, elapsed: Duration, counters: &HashSet<String>,
+) -> BTreeMap<String, f64> {
+    let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+    if elapsed_secs == 0.0 {
+        return BTreeMap::new();
+    }
+
+    // Counters that didn't exist yet as of the older snapshot have counted up from nothing.
+    end.metrics
+        .iter()
+        .filter(|(key, _)| counters.contains(key.as_str()))
+        .filter_map(|(key, value)| {
+            let current = value.as_f64()?;
+            let previous = start.metrics.get(key).and_then(Value::as_f64).unwrap_or(0.0);
+            Some((key.clone(), (current - previous) / elapsed_secs))
+        })
+        .collect()
+}
+
+fn get_gauges(end: &StatsSnapshot, counters: &HashSet<String>) -> BTreeMap<String, Value> {
+    end.metrics
+        .iter()
+        .filter(|(key, value)| !counters.contains(key.as_str()) && value.is_number())
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+fn get_latency_deltas(start: &StatsSnapshot, end: &StatsSnapshot) -> BTreeMap<String, u64> {
+    let mut latencies = BTreeMap::new();
+    for histogram in &end.latencies {
+        // A histogram whose buckets changed was replaced, so it's counted up from nothing.
+        let previous = start
+            .latencies
+            .iter()
+            .find(|previous| previous.prefix == histogram.prefix)
+            .filter(|previous| {
+                previous.cumulative.len() == histogram.cumulative.len()
+                    && previous
+                        .cumulative
+                        .iter()
+                        .zip(&histogram.cumulative)
+                        .all(|(a, b)| a.0 == b.0)
+            });
+
+        let cumulative = histogram
+            .cumulative
+            .iter()
+            .enumerate()
+            .map(|(idx, &(bound_us, count))| {
+                let previous_count = previous.map_or(0, |previous| previous.cumulative[idx].1);
+                (bound_us, count.saturating_sub(previous_count))
+            })
+            .collect::<Vec<_>>();
+        let sum_us = histogram.sum_us.saturating_sub(previous.map_or(0, |previous| previous.sum_us));
+
+        let count = cumulative.last().map_or(0, |&(_, count)| count);
+        latencies.insert(format!("{}_count", histogram.prefix), count as u64);
+        latencies.insert(format!("{}_sum_us", histogram.prefix), sum_us);
+        for &(suffix, quantile) in REPORTED_QUANTILES {
+            if let Some(value) = quantile_from_cumulative(&cumulative, quantile) {
+                latencies.insert(format!("{}_{}", histogram.prefix, suffix), value);
+            }
+        }
+    }
+
+    latencies
+}
+
+/// Takes a snapshot of every metric at the interval of the given history, adding each to it, until
+/// `shutdown` fires.
+pub fn run_history<F: Future>(
+    control: Controller, history: Arc<StatsHistory>, shutdown: F,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), history.interval())
+        .map_err(|e| error!("[metrics] stats history timer failed: {}", e))
+        .for_each(move |taken| {
+            // Snapshots are timed by when they were due, rather than when we got around to them, so
+            // that the time between them stays true to the interval.
+            let snapshot = control
+                .get_snapshot()
+                .map_err(|e| e.to_string())
+                .and_then(|snapshot| serde_json::to_value(&snapshot).map_err(|e| e.to_string()));
+            match snapshot {
+                Ok(metrics) => history.push(StatsSnapshot::capture(taken, metrics)),
+                Err(e) => warn!("[metrics] failed to take snapshot for stats history: {}", e),
+            }
+            Ok(())
+        })
+        .select2(shutdown)
+        .then(|_| Ok::<(), ()>(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn get_snapshot(base: Instant, secs: u64, metrics: Value, latencies: &[usize]) -> StatsSnapshot {
+        let mut total = 0;
+        let mut cumulative = Vec::new();
+        for (idx, count) in latencies.iter().enumerate() {
+            total += count;
+            cumulative.push((if idx < 2 { Some(1_000 * 10u64.pow(idx as u32)) } else { None }, total));
+        }
+
+        let latencies = if latencies.is_empty() {
+            Vec::new()
+        } else {
+            vec![LatencySnapshot {
+                prefix: "listeners.a.client.batch_latency".to_owned(),
+                cumulative,
+                sum_us: total as u64 * 100,
+            }]
+        };
+        StatsSnapshot::new(base + Duration::from_secs(secs), metrics, latencies)
+    }
+
+    fn get_history(base: Instant, count: u64) -> StatsHistory {
+        let history = StatsHistory::new(Duration::from_secs(1), Duration::from_secs(5));
+        for secs in 0..count {
+            let metrics = json!({
+                "requests": secs * 10,
+                "clients_connected": secs,
+                "name": "a",
+                "request_size_value_p99": secs * 100,
+            });
+            history.push(get_snapshot(base, secs, metrics, &[]));
+        }
+        history
+    }
+
+    fn get_counters() -> HashSet<String> {
+        vec!["requests".to_owned(), "errors".to_owned()].into_iter().collect()
+    }
+
+    #[test]
+    fn test_delta_rates_and_gauges() {
+        let base = Instant::now();
+        let history = get_history(base, 4);
+
+        let now = base + Duration::from_millis(3_500);
+        let delta = history.delta(Duration::from_secs(2), now, &get_counters()).unwrap();
+        assert_eq!(delta.window_ms, 2_000);
+        assert_eq!(delta.elapsed_ms, 2_000);
+        assert_eq!(delta.age_ms, 500);
+        assert_eq!(delta.rates.get("requests"), Some(&10.0));
+        assert_eq!(delta.rates.get("clients_connected"), None);
+        assert_eq!(delta.rates.get("name"), None);
+        assert_eq!(delta.gauges.get("clients_connected"), Some(&json!(3)));
+        assert_eq!(delta.gauges.get("name"), None);
+
+        // Anything that isn't a counter is never made into a rate, even when it only goes up.
+        assert_eq!(delta.rates.get("request_size_value_p99"), None);
+        assert_eq!(delta.gauges.get("request_size_value_p99"), Some(&json!(300)));
+        assert!(delta.latencies.is_empty());
+    }
+
+    #[test]
+    fn test_delta_window_bounds() {
+        let base = Instant::now();
+        let history = StatsHistory::new(Duration::from_secs(1), Duration::from_secs(5));
+        let counters = get_counters();
+        assert_eq!(
+            history.delta(Duration::from_secs(1), base, &counters).unwrap_err(),
+            DeltaError::TooLong(Duration::from_secs(0))
+        );
+
+        let history = get_history(base, 10);
+        assert_eq!(
+            history.delta(Duration::from_millis(500), base, &counters).unwrap_err(),
+            DeltaError::TooShort(Duration::from_secs(1))
+        );
+
+        // Only the last five seconds are kept, which is six snapshots.
+        assert_eq!(history.delta(Duration::from_secs(5), base, &counters).unwrap().elapsed_ms, 5_000);
+        assert_eq!(
+            history.delta(Duration::from_secs(6), base, &counters).unwrap_err(),
+            DeltaError::TooLong(Duration::from_secs(5))
+        );
+
+        // Windows between snapshots use whichever snapshot is closest.
+        assert_eq!(history.delta(Duration::from_millis(2_400), base, &counters).unwrap().elapsed_ms, 2_000);
+        assert_eq!(history.delta(Duration::from_millis(2_600), base, &counters).unwrap().elapsed_ms, 3_000);
+    }
+
+    #[test]
+    fn test_delta_counts_new_counters_from_zero() {
+        let base = Instant::now();
+        let history = StatsHistory::new(Duration::from_secs(1), Duration::from_secs(5));
+        history.push(get_snapshot(base, 0, json!({}), &[]));
+        history.push(get_snapshot(base, 2, json!({ "errors": 4 }), &[]));
+
+        let delta = history.delta(Duration::from_secs(2), base, &get_counters()).unwrap();
+        assert_eq!(delta.rates.get("errors"), Some(&2.0));
+    }
+
+    #[test]
+    fn test_delta_latencies() {
+        let base = Instant::now();
+        let history = StatsHistory::new(Duration::from_secs(1), Duration::from_secs(5));
+        history.push(get_snapshot(base, 0, json!({}), &[100, 0, 0]));
+        history.push(get_snapshot(base, 1, json!({}), &[100, 0, 2]));
+        history.push(get_snapshot(base, 2, json!({}), &[101, 8, 2]));
+
+        // Everything recorded before the window is left out, so the fast requests from before it
+        // don't hide the slow ones during it.
+        let delta = history.delta(Duration::from_secs(2), base, &get_counters()).unwrap();
+        let prefix = "listeners.a.client.batch_latency";
+        assert_eq!(delta.latencies[&format!("{}_count", prefix)], 11);
+        assert_eq!(delta.latencies[&format!("{}_sum_us", prefix)], 1_100);
+        assert_eq!(delta.latencies[&format!("{}_p50_us", prefix)], 10_000);
+        assert_eq!(delta.latencies[&format!("{}_p99_us", prefix)], 10_000);
+        assert_eq!(delta.latencies[&format!("{}_p999_us", prefix)], 10_000);
+
+        let delta = history.delta(Duration::from_secs(1), base, &get_counters()).unwrap();
+        assert_eq!(delta.latencies[&format!("{}_count", prefix)], 9);
+        assert_eq!(delta.latencies[&format!("{}_p50_us", prefix)], 10_000);
+    }
+}
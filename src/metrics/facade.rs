@@ -17,16 +17,21 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use hotmic::{Controller, Receiver, Sink};
-use std::thread;
+use super::sink::{self, MetricSink};
+use hotmic::{Controller, Receiver};
+use std::{sync::Mutex, thread};
+
+// How many metric updates can be waiting on the aggregator before we start dropping them.
+const METRICS_CHANNEL_CAPACITY: usize = 65536;
 
 lazy_static! {
     static ref METRICS: MetricsFacade = {
         let mut receiver = Receiver::builder().build();
-        let facade = MetricsFacade::new(&receiver);
+        let (facade, aggregator) = MetricsFacade::new(&receiver);
 
-        // Spawn our actual processing loop.
+        // Spawn our actual processing loop, and the aggregator that feeds it.
         thread::spawn(move || receiver.run());
+        thread::spawn(move || aggregator.run());
 
         facade
     };
@@ -34,25 +39,28 @@ lazy_static! {
 
 pub fn get_facade() -> &'static MetricsFacade { &METRICS }
 
-pub fn get_sink() -> Sink<&'static str> {
+pub fn get_sink() -> MetricSink {
     let facade = get_facade();
     facade.get_sink()
 }
 
 pub struct MetricsFacade {
-    sink: Sink<&'static str>,
+    sink: Mutex<MetricSink>,
     controller: Controller,
 }
 
 impl MetricsFacade {
-    pub fn new(receiver: &Receiver<&'static str>) -> MetricsFacade {
-        MetricsFacade {
-            sink: receiver.get_sink(),
+    pub fn new(receiver: &Receiver<&'static str>) -> (MetricsFacade, sink::MetricAggregator) {
+        let (sink, aggregator) = sink::channel(receiver.get_sink(), METRICS_CHANNEL_CAPACITY);
+        let facade = MetricsFacade {
+            sink: Mutex::new(sink),
             controller: receiver.get_controller(),
-        }
+        };
+
+        (facade, aggregator)
     }
 
-    pub fn get_sink(&self) -> Sink<&'static str> { self.sink.clone() }
+    pub fn get_sink(&self) -> MetricSink { self.sink.lock().unwrap().clone() }
 
     pub fn get_controller(&self) -> Controller { self.controller.clone() }
 }
@@ -0,0 +1,415 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::ListenerConfiguration;
+use errors::CreationError;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use util::clock::duration_as_us;
+
+// The upper bounds, in microseconds, of the buckets latencies are counted in by default.
+const DEFAULT_LATENCY_BUCKETS_US: &[u64] = &[
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+/// The quantiles latencies are summed up with in stats, by the suffix they're reported under.
+pub const REPORTED_QUANTILES: &[(&str, f64)] =
+    &[("p50_us", 0.5), ("p95_us", 0.95), ("p99_us", 0.99), ("p999_us", 0.999)];
+
+lazy_static! {
+    static ref LATENCIES: Mutex<HashMap<(String, &'static str), Arc<LatencyBuckets>>> = Mutex::new(HashMap::new());
+}
+
+/// A latency that was recorded, along with the request it was recorded for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exemplar {
+    /// The identifier of the request.
+    pub request_id: usize,
+
+    /// The hash of the key the request was for.
+    pub key_hash: u64,
+
+    /// The latency, in microseconds.
+    pub value_us: u64,
+}
+
+// The most recent exemplar of a bucket.  A request identifier of zero means there isn't one yet.
+//
+// The fields are stored separately, and without any ordering, so a reader racing a writer can see
+// a mix of two exemplars.  That's fine for pointing someone in the right direction, and keeps the
+// cost of recording one down to a few stores.
+struct ExemplarSlot {
+    request_id: AtomicUsize,
+    key_hash: AtomicU64,
+    value_us: AtomicU64,
+}
+
+impl ExemplarSlot {
+    fn new() -> ExemplarSlot {
+        ExemplarSlot {
+            request_id: AtomicUsize::new(0),
+            key_hash: AtomicU64::new(0),
+            value_us: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self) -> Option<Exemplar> {
+        match self.request_id.load(Ordering::Relaxed) {
+            0 => None,
+            request_id => {
+                Some(Exemplar {
+                    request_id,
+                    key_hash: self.key_hash.load(Ordering::Relaxed),
+                    value_us: self.value_us.load(Ordering::Relaxed),
+                })
+            },
+        }
+    }
+}
+
+/// A histogram of latencies, counted into fixed buckets.
+///
+/// Recording is lock-free, so every client of a listener can share the same histogram.  Each
+/// bucket also keeps the most recent latency recorded in it for a known request, if any, so that
+/// a slow bucket can be traced back to something that landed in it.  The smallest and largest
+/// latencies ever recorded are kept exactly, since buckets can only bound them.
+pub struct LatencyBuckets {
+    bounds_us: Vec<u64>,
+    counts: Vec<AtomicUsize>,
+    exemplars: Vec<ExemplarSlot>,
+    sum_us: AtomicUsize,
+    min_us: AtomicUsize,
+    max_us: AtomicUsize,
+}
+
+impl LatencyBuckets {
+    /// Creates a histogram with a bucket for each of the given upper bounds, plus one for anything
+    /// over the last of them.
+    pub fn new(bounds_us: Vec<u64>) -> LatencyBuckets {
+        let counts = (0..=bounds_us.len()).map(|_| AtomicUsize::new(0)).collect();
+        let exemplars = (0..=bounds_us.len()).map(|_| ExemplarSlot::new()).collect();
+        LatencyBuckets {
+            bounds_us,
+            counts,
+            exemplars,
+            sum_us: AtomicUsize::new(0),
+            min_us: AtomicUsize::new(usize::max_value()),
+            max_us: AtomicUsize::new(0),
+        }
+    }
+
+    /// Gets the bucket bounds a listener wants its latencies counted in.
+    ///
+    /// Bounds are in microseconds, and must be given in increasing order.
+    pub fn from_config(config: &ListenerConfiguration) -> Result<Vec<u64>, CreationError> {
+        let bounds_us = match config.latency_buckets_us {
+            Some(ref bounds_us) => bounds_us.clone(),
+            None => return Ok(DEFAULT_LATENCY_BUCKETS_US.to_vec()),
+        };
+
+        let increasing = bounds_us.windows(2).all(|pair| pair[0] < pair[1]);
+        if bounds_us.is_empty() || bounds_us[0] == 0 || !increasing {
+            return Err(CreationError::InvalidParameter("latency_buckets_us".to_string()));
+        }
+
+        Ok(bounds_us)
+    }
+
+    pub fn record(&self, latency: Duration) { self.record_us(duration_as_us(latency), 1); }
+
+    /// Records a latency for the given request, keeping it as the exemplar of its bucket.
+    ///
+    /// Request identifiers start at one, since zero is how a bucket says it has no exemplar.
+    pub fn record_exemplar(&self, latency: Duration, request_id: usize, key_hash: u64) {
+        self.record_many_exemplar(latency, 1, request_id, key_hash);
+    }
+
+    /// Records the same latency `count` times over for the given request, as cheaply as recording
+    /// it once, keeping it as the exemplar of its bucket.
+    pub fn record_many_exemplar(&self, latency: Duration, count: usize, request_id: usize, key_hash: u64) {
+        if count == 0 {
+            return;
+        }
+
+        let latency_us = duration_as_us(latency);
+        let idx = self.record_us(latency_us, count);
+
+        let slot = &self.exemplars[idx];
+        slot.key_hash.store(key_hash, Ordering::Relaxed);
+        slot.value_us.store(latency_us, Ordering::Relaxed);
+        slot.request_id.store(request_id, Ordering::Relaxed);
+    }
+
+    fn record_us(&self, latency_us: u64, count: usize) -> usize {
+        let idx = self
+            .bounds_us
+            .iter()
+            .position(|bound| latency_us <= *bound)
+            .unwrap_or_else(|| self.bounds_us.len());
+
+        self.counts[idx].fetch_add(count, Ordering::Relaxed);
+        self.sum_us.fetch_add(latency_us as usize * count, Ordering::Relaxed);
+
+        // The extremes rarely move once there's been some traffic, so this is almost always just
+        // the two loads.
+        let latency_us = latency_us as usize;
+        update_extreme(&self.min_us, latency_us, |current| latency_us < current);
+        update_extreme(&self.max_us, latency_us, |current| latency_us > current);
+        idx
+    }
+
+    /// Gets the upper bound of each bucket, and how many latencies were at or below it.
+    ///
+    /// The last bucket has no upper bound, and counts every latency recorded.
+    pub fn cumulative(&self) -> Vec<(Option<u64>, usize)> {
+        let mut total = 0;
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(idx, count)| {
+                total += count.load(Ordering::Relaxed);
+                (self.bounds_us.get(idx).cloned(), total)
+            })
+            .collect()
+    }
+
+    /// Gets the exemplar of each bucket, in the same order as `cumulative`.
+    pub fn exemplars(&self) -> Vec<Option<Exemplar>> { self.exemplars.iter().map(ExemplarSlot::get).collect() }
+
+    /// Gets the sum of every latency recorded, in microseconds.
+    pub fn sum_us(&self) -> u64 { self.sum_us.load(Ordering::Relaxed) as u64 }
+
+    /// Gets the smallest latency recorded, in microseconds, if anything has been recorded.
+    pub fn min_us(&self) -> Option<u64> {
+        match self.min_us.load(Ordering::Relaxed) {
+            min_us if min_us == usize::max_value() => None,
+            min_us => Some(min_us as u64),
+        }
+    }
+
+    /// Gets the largest latency recorded, in microseconds, if anything has been recorded.
+    pub fn max_us(&self) -> Option<u64> { self.min_us().map(|_| self.max_us.load(Ordering::Relaxed) as u64) }
+
+    /// Estimates the given quantile, in microseconds, as the upper bound of the bucket it falls in.
+    ///
+    /// Quantiles that fall past the last bound are given as the last bound.  If nothing has been
+    /// recorded, there's nothing to estimate.
+    pub fn quantile_us(&self, quantile: f64) -> Option<u64> { quantile_from_cumulative(&self.cumulative(), quantile) }
+}
+
+// Swaps `value` into `extreme` for as long as it's more extreme than what's there.
+fn update_extreme<F>(extreme: &AtomicUsize, value: usize, more_extreme: F)
+where
+    F: Fn(usize) -> bool,
+{
+    let mut current = extreme.load(Ordering::Relaxed);
+    while more_extreme(current) {
+        match extreme.compare_exchange_weak(current, value, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+/// Estimates the given quantile, in microseconds, from cumulative bucket counts like those given by
+/// `LatencyBuckets::cumulative`.
+///
+/// This works just as well for the difference between two sets of counts from the same histogram,
+/// which gives the quantile over the time between them.
+pub fn quantile_from_cumulative(buckets: &[(Option<u64>, usize)], quantile: f64) -> Option<u64> {
+    let total = buckets.last().map_or(0, |&(_, count)| count);
+    if total == 0 {
+        return None;
+    }
+
+    let rank = (quantile * total as f64).ceil().max(1.0) as usize;
+    buckets
+        .iter()
+        .find(|&&(_, count)| count >= rank)
+        .and_then(|&(bound_us, _)| bound_us)
+        .or_else(|| buckets.iter().rev().filter_map(|&(bound_us, _)| bound_us).next())
+}
+
+/// Gets the histogram that the given listener records the latency called `name` in.
+///
+/// A reloaded listener carries on with the histograms of the version it replaces, unless their
+/// buckets have changed.
+pub fn register_latencies(listener: &str, name: &'static str, bounds_us: Vec<u64>) -> Arc<LatencyBuckets> {
+    let mut latencies = LATENCIES.lock().unwrap();
+    let key = (listener.to_owned(), name);
+    if let Some(existing) = latencies.get(&key) {
+        if existing.bounds_us == bounds_us {
+            return existing.clone();
+        }
+    }
+
+    let histogram = Arc::new(LatencyBuckets::new(bounds_us));
+    latencies.insert(key, histogram.clone());
+    histogram
+}
+
+/// Gets every registered histogram, along with the listener it belongs to and its name.
+///
+/// Histograms are sorted by name, and then by listener.
+pub fn get_latencies() -> Vec<(String, &'static str, Arc<LatencyBuckets>)> {
+    let mut latencies = LATENCIES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&(ref listener, name), histogram)| (listener.clone(), name, histogram.clone()))
+        .collect::<Vec<_>>();
+    latencies.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    latencies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use test::Bencher;
+
+    #[test]
+    fn test_latency_buckets() {
+        let histogram = LatencyBuckets::new(vec![1_000, 10_000]);
+        assert_eq!(histogram.quantile_us(0.5), None);
+        assert_eq!(histogram.min_us(), None);
+        assert_eq!(histogram.max_us(), None);
+
+        histogram.record(Duration::from_micros(500));
+        histogram.record(Duration::from_micros(1_000));
+        histogram.record(Duration::from_millis(5));
+        histogram.record(Duration::from_secs(1));
+
+        assert_eq!(histogram.cumulative(), vec![(Some(1_000), 2), (Some(10_000), 3), (None, 4)]);
+        assert_eq!(histogram.sum_us(), 1_006_500);
+        assert_eq!(histogram.quantile_us(0.5), Some(1_000));
+        assert_eq!(histogram.quantile_us(0.75), Some(10_000));
+        assert_eq!(histogram.quantile_us(0.99), Some(10_000));
+        assert_eq!(histogram.min_us(), Some(500));
+        assert_eq!(histogram.max_us(), Some(1_000_000));
+    }
+
+    #[test]
+    fn test_latency_buckets_record_many() {
+        let histogram = LatencyBuckets::new(vec![1_000, 10_000]);
+        histogram.record_many_exemplar(Duration::from_millis(5), 0, 1, 0);
+        assert_eq!(histogram.cumulative(), vec![(Some(1_000), 0), (Some(10_000), 0), (None, 0)]);
+        assert_eq!(histogram.min_us(), None);
+
+        histogram.record_many_exemplar(Duration::from_millis(5), 3, 1, 0);
+        histogram.record(Duration::from_micros(200));
+        assert_eq!(histogram.cumulative(), vec![(Some(1_000), 1), (Some(10_000), 4), (None, 4)]);
+        assert_eq!(histogram.sum_us(), 15_200);
+        assert_eq!(histogram.min_us(), Some(200));
+        assert_eq!(histogram.max_us(), Some(5_000));
+    }
+
+    #[test]
+    fn test_latency_buckets_exemplars() {
+        let histogram = LatencyBuckets::new(vec![1_000, 10_000]);
+        assert_eq!(histogram.exemplars(), vec![None, None, None]);
+
+        // Only latencies recorded for a request are kept, and only the latest one in each bucket.
+        histogram.record_exemplar(Duration::from_micros(500), 1, 0xfeed);
+        histogram.record_exemplar(Duration::from_micros(750), 2, 0xbeef);
+        histogram.record(Duration::from_micros(900));
+        histogram.record_exemplar(Duration::from_secs(1), 3, 0xcafe);
+
+        // Key hashes are kept in full, wherever we're running.
+        histogram.record_many_exemplar(Duration::from_millis(5), 0, 4, 0xdead);
+        histogram.record_many_exemplar(Duration::from_millis(5), 2, 5, std::u64::MAX);
+
+        let exemplar = |request_id, key_hash, value_us| {
+            Some(Exemplar {
+                request_id,
+                key_hash,
+                value_us,
+            })
+        };
+        assert_eq!(
+            histogram.exemplars(),
+            vec![
+                exemplar(2, 0xbeef, 750),
+                exemplar(5, std::u64::MAX, 5_000),
+                exemplar(3, 0xcafe, 1_000_000),
+            ]
+        );
+        assert_eq!(histogram.cumulative(), vec![(Some(1_000), 3), (Some(10_000), 5), (None, 6)]);
+    }
+
+    #[test]
+    fn test_latency_buckets_from_config() {
+        let mut config = ListenerConfiguration::default();
+        assert_eq!(LatencyBuckets::from_config(&config).unwrap(), DEFAULT_LATENCY_BUCKETS_US.to_vec());
+
+        config.latency_buckets_us = Some(vec![100, 1_000]);
+        assert_eq!(LatencyBuckets::from_config(&config).unwrap(), vec![100, 1_000]);
+
+        for bad in vec![vec![], vec![0, 100], vec![100, 100], vec![1_000, 100]] {
+            config.latency_buckets_us = Some(bad);
+            assert!(LatencyBuckets::from_config(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_reregistering_keeps_histogram() {
+        let first = register_latencies("reregistered", "batch", vec![100, 1_000]);
+        let second = register_latencies("reregistered", "batch", vec![100, 1_000]);
+        let third = register_latencies("reregistered", "batch", vec![100, 5_000]);
+        let other = register_latencies("reregistered", "first_byte", vec![100, 5_000]);
+
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert!(!Arc::ptr_eq(&third, &other));
+    }
+    #[bench]
+    fn bench_record(b: &mut Bencher) {
+        let histogram = LatencyBuckets::new(DEFAULT_LATENCY_BUCKETS_US.to_vec());
+        let latency = Duration::from_micros(3_000);
+        b.iter(|| histogram.record(latency));
+    }
+
+    #[bench]
+    fn bench_record_shared(b: &mut Bencher) {
+        // Every client of a listener records into the same histogram, so this is what it costs
+        // when they're all at it at once.
+        let histogram = Arc::new(LatencyBuckets::new(DEFAULT_LATENCY_BUCKETS_US.to_vec()));
+        b.iter(|| {
+            let threads = (0..4)
+                .map(|i| {
+                    let histogram = histogram.clone();
+                    thread::spawn(move || {
+                        for j in 0..1_000 {
+                            histogram.record(Duration::from_micros(100 * i + j));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        });
+    }
+}
@@ -18,7 +18,26 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 mod web;
-pub use self::web::build_with_graceful_shutdown;
+pub use self::web::{build_with_graceful_shutdown, ADMIN_FEATURES};
 
 mod facade;
 pub use self::facade::{get_facade, get_sink};
+
+mod sink;
+pub use self::sink::MetricSink;
+#[cfg(test)]
+pub use self::sink::{capture, MetricCapture};
+
+mod latency;
+pub use self::latency::{register_latencies, LatencyBuckets};
+
+mod prometheus;
+
+mod statsd;
+pub use self::statsd::{StatsdEmitter, StatsdTask};
+
+mod delta;
+pub use self::delta::{run_history, StatsHistory};
+
+mod overview;
+pub use self::overview::mark_started;
@@ -0,0 +1,297 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::{
+    drain::{get_drain_status, list_backend_activity, BackendActivity, DrainPhase},
+    probe::find_backend_availability,
+    weights::find_backend_weights,
+};
+use metrics::latency::{get_latencies, LatencyBuckets, REPORTED_QUANTILES};
+use serde_json::Value;
+use std::{collections::BTreeMap, time::Instant};
+use util::clock::elapsed;
+
+lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+}
+
+/// Marks the process as started, which is what its uptime is counted from.
+pub fn mark_started() { ::lazy_static::initialize(&STARTED); }
+
+/// Everything there is to know about the process at a glance: how long it's been up, and what
+/// each of its listeners and pools is up to.
+#[derive(Serialize)]
+pub struct Overview {
+    version: &'static str,
+    uptime_secs: u64,
+    listeners: BTreeMap<String, ListenerOverview>,
+}
+
+#[derive(Default, Serialize)]
+struct ListenerOverview {
+    clients_connected: u64,
+    messages_received: u64,
+    messages_sent: u64,
+    latencies: BTreeMap<&'static str, LatencySummary>,
+    pools: BTreeMap<String, PoolOverview>,
+}
+
+#[derive(Serialize)]
+struct LatencySummary {
+    count: usize,
+    sum_us: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_us: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_us: Option<u64>,
+    #[serde(flatten)]
+    quantiles: BTreeMap<&'static str, u64>,
+}
+
+/// The backends of a pool, in their configured order.
+#[derive(Serialize)]
+pub struct PoolOverview {
+    backends: Vec<BackendOverview>,
+}
+
+#[derive(Serialize)]
+struct BackendOverview {
+    identifier: String,
+    address: String,
+    state: BackendState,
+    weight: usize,
+    effective_weight: usize,
+    requests: usize,
+    in_flight: usize,
+}
+
+/// Whether a backend is taking its share of requests, and if not, why not.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BackendState {
+    /// The backend is in the distribution, as far as its health checks are concerned.
+    Healthy,
+
+    /// The backend failed enough health checks in a row to be taken out of the distribution.
+    Ejected,
+
+    /// The backend is being drained, but may still be getting requests.
+    Draining,
+
+    /// The backend has been drained, and is safe to remove.
+    Drained,
+}
+
+/// Builds an overview of the process from a metrics snapshot.
+///
+/// Client and message counts come from the snapshot, while latencies and backend states are read
+/// straight out of the atomics they're recorded into, so that putting the overview together never
+/// holds up anything recording them.
+pub fn get_overview(snapshot: &Value) -> Overview {
+    let mut listeners = BTreeMap::new();
+    for (listener, name, histogram) in get_latencies() {
+        listeners
+            .entry(listener)
+            .or_insert_with(ListenerOverview::default)
+            .latencies
+            .insert(name, LatencySummary::from_histogram(&histogram));
+    }
+
+    for (listener, pool, activity) in list_backend_activity() {
+        let overview = get_pool_overview(&listener, &pool, &activity);
+        listeners
+            .entry(listener)
+            .or_insert_with(ListenerOverview::default)
+            .pools
+            .insert(pool, overview);
+    }
+
+    for (name, listener) in &mut listeners {
+        let get_count = |key: &str| {
+            snapshot
+                .get(&format!("listeners.{}.{}", name, key))
+                .and_then(Value::as_u64)
+                .unwrap_or(0)
+        };
+        listener.clients_connected = get_count("clients_connected");
+        listener.messages_received = get_count("client.messages_received");
+        listener.messages_sent = get_count("client.messages_sent");
+    }
+
+    Overview {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: elapsed(*STARTED).as_secs(),
+        listeners,
+    }
+}
+
+/// Gets an overview of every pool with the given name, keyed by the listener it belongs to.
+pub fn get_pool_overviews(name: &str) -> BTreeMap<String, PoolOverview> {
+    list_backend_activity()
+        .into_iter()
+        .filter(|(_, pool, _)| pool == name)
+        .map(|(listener, pool, activity)| {
+            let overview = get_pool_overview(&listener, &pool, &activity);
+            (listener, overview)
+        })
+        .collect()
+}
+
+fn get_pool_overview(listener: &str, pool: &str, activity: &BackendActivity) -> PoolOverview {
+    let weights = find_backend_weights(listener, pool);
+    let availability = find_backend_availability(listener, pool);
+
+    let backends = activity
+        .identifiers()
+        .iter()
+        .enumerate()
+        .map(|(idx, identifier)| {
+            let address = activity.address(idx);
+            let state = if availability.as_ref().map_or(false, |a| a.is_ejected(idx)) {
+                BackendState::Ejected
+            } else {
+                match get_drain_status(listener, pool, address).map(|status| status.phase) {
+                    Some(DrainPhase::Draining) => BackendState::Draining,
+                    Some(DrainPhase::Drained) => BackendState::Drained,
+                    _ => BackendState::Healthy,
+                }
+            };
+
+            BackendOverview {
+                identifier: identifier.clone(),
+                address: address.to_string(),
+                state,
+                weight: weights.as_ref().map_or(0, |w| w.get(idx)),
+                effective_weight: weights.as_ref().map_or(0, |w| w.get_effective(idx)),
+                requests: activity.requests(idx),
+                in_flight: activity.in_flight(idx),
+            }
+        })
+        .collect();
+
+    PoolOverview { backends }
+}
+
+impl LatencySummary {
+    fn from_histogram(histogram: &LatencyBuckets) -> LatencySummary {
+        let quantiles = REPORTED_QUANTILES
+            .iter()
+            .filter_map(|&(suffix, quantile)| histogram.quantile_us(quantile).map(|value| (suffix, value)))
+            .collect();
+
+        LatencySummary {
+            count: histogram.cumulative().last().map_or(0, |&(_, count)| count),
+            sum_us: histogram.sum_us(),
+            min_us: histogram.min_us(),
+            max_us: histogram.max_us(),
+            quantiles,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_overview, get_pool_overviews};
+    use backend::{
+        drain::{register_backend_activity, BackendActivity},
+        probe::{register_backend_availability, BackendAvailability},
+        weights::{register_backend_weights, BackendWeights},
+    };
+    use metrics::register_latencies;
+    use serde_json::json;
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    fn register_pool(listener: &str, pool: &str) -> (Arc<BackendActivity>, Arc<BackendAvailability>) {
+        let first: SocketAddr = "127.0.0.1:16379".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:16380".parse().unwrap();
+        let activity = Arc::new(BackendActivity::new(vec![
+            ("127.0.0.1:16379".to_owned(), first),
+            ("127.0.0.1:16380".to_owned(), second),
+        ]));
+        let availability = Arc::new(BackendAvailability::new(2));
+        register_backend_weights(listener, pool, Arc::new(BackendWeights::new(vec![(first, 1), (second, 3)])));
+        register_backend_activity(listener, pool, activity.clone());
+        register_backend_availability(listener, pool, availability.clone());
+        (activity, availability)
+    }
+
+    #[test]
+    fn test_overview() {
+        let histogram = register_latencies("overview_test", "batch", vec![1_000, 10_000]);
+        histogram.record(Duration::from_micros(500));
+        histogram.record(Duration::from_millis(5));
+        let (activity, availability) = register_pool("overview_test", "default");
+        activity.update(0, 12, 2);
+        availability.set_ejected(1, true);
+
+        let snapshot = json!({
+            "listeners.overview_test.clients_connected": 3,
+            "listeners.overview_test.client.messages_received": 40,
+            "listeners.overview_test.client.messages_sent": 38,
+        });
+        let overview = serde_json::to_value(&get_overview(&snapshot)).unwrap();
+        assert_eq!(overview["version"], env!("CARGO_PKG_VERSION"));
+        assert!(overview["uptime_secs"].is_u64());
+
+        let listener = &overview["listeners"]["overview_test"];
+        assert_eq!(listener["clients_connected"], 3);
+        assert_eq!(listener["messages_received"], 40);
+        assert_eq!(listener["messages_sent"], 38);
+        assert_eq!(
+            listener["latencies"]["batch"],
+            json!({
+                "count": 2,
+                "sum_us": 5_500,
+                "min_us": 500,
+                "max_us": 5_000,
+                "p50_us": 1_000,
+                "p95_us": 10_000,
+                "p99_us": 10_000,
+                "p999_us": 10_000,
+            })
+        );
+
+        let backends = &listener["pools"]["default"]["backends"];
+        assert_eq!(backends[0]["identifier"], "127.0.0.1:16379");
+        assert_eq!(backends[0]["state"], "healthy");
+        assert_eq!(backends[0]["weight"], 1);
+        assert_eq!(backends[0]["requests"], 12);
+        assert_eq!(backends[0]["in_flight"], 2);
+        assert_eq!(backends[1]["state"], "ejected");
+        assert_eq!(backends[1]["weight"], 3);
+    }
+
+    #[test]
+    fn test_pool_overviews() {
+        register_pool("pool_overview_a", "shared");
+        register_pool("pool_overview_b", "shared");
+        register_pool("pool_overview_b", "unshared");
+
+        let shared = get_pool_overviews("shared");
+        assert!(shared.contains_key("pool_overview_a"));
+        assert!(shared.contains_key("pool_overview_b"));
+        assert_eq!(shared["pool_overview_a"].backends.len(), 2);
+
+        let unshared = get_pool_overviews("unshared");
+        assert!(!unshared.contains_key("pool_overview_a"));
+        assert!(unshared.contains_key("pool_overview_b"));
+
+        assert!(get_pool_overviews("missing").is_empty());
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::latency::{get_latencies, Exemplar, LatencyBuckets};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+};
+
+// Every metric we expose is prefixed with this.
+const METRIC_PREFIX: &str = "synchrotron";
+
+// Scopes that are followed by the name of something, and the label that name is exposed as.
+pub const LABELED_SCOPES: &[(&str, &str)] = &[("listeners", "listener"), ("pools", "pool"), ("backends", "backend")];
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// The exposition formats metrics can be rendered in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// The Prometheus text format.
+    Prometheus,
+
+    /// The OpenMetrics text format, which is the only one that can carry exemplars.
+    OpenMetrics,
+}
+
+impl Format {
+    /// Picks the format to render in for a scraper that sent the given `Accept` header.
+    ///
+    /// OpenMetrics is only used if it's asked for, since older scrapers can't parse it.
+    pub fn from_accept(accept: Option<&str>) -> Format {
+        let wants_openmetrics = accept.map_or(false, |accept| {
+            accept
+                .split(',')
+                .any(|media| media.trim().starts_with("application/openmetrics-text"))
+        });
+
+        if wants_openmetrics {
+            Format::OpenMetrics
+        } else {
+            Format::Prometheus
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => PROMETHEUS_CONTENT_TYPE,
+            Format::OpenMetrics => OPENMETRICS_CONTENT_TYPE,
+        }
+    }
+}
+
+/// Renders a metrics snapshot, and the latency histograms of every listener, in the given text
+/// exposition format.
+///
+/// Metric names are flattened, with the names of listeners, pools and backends pulled out into
+/// labels, so `listeners.fixed.pools.default.hits` becomes `synchrotron_hits_total` with the
+/// `listener` and `pool` labels.  A metric is exposed as a counter if it's in `counters` under any
+/// set of labels, and as a gauge otherwise.
+///
+/// In OpenMetrics, each latency bucket also carries the request that most recently landed in it,
+/// as an exemplar.
+pub fn render(snapshot: &Value, counters: &HashSet<String>, format: Format) -> String {
+    let mut samples = Vec::new();
+    let mut counter_names = HashSet::new();
+    if let Value::Object(ref metrics) = *snapshot {
+        for (key, value) in metrics {
+            if let Value::Number(ref value) = *value {
+                let (name, labels) = split_metric_name(key);
+                if counters.contains(key) {
+                    counter_names.insert(name.clone());
+                }
+                samples.push((name, labels, value.to_string()));
+            }
+        }
+    }
+
+    let mut families: BTreeMap<String, (bool, Vec<(String, String)>)> = BTreeMap::new();
+    for (name, labels, value) in samples {
+        let gauge = !counter_names.contains(&name);
+        let name = if gauge { name } else { format!("{}_total", name) };
+        families
+            .entry(name)
+            .or_insert_with(|| (gauge, Vec::new()))
+            .1
+            .push((labels, value));
+    }
+
+    let mut out = String::new();
+    for (name, (gauge, mut samples)) in families {
+        // OpenMetrics names a counter without the suffix its samples have.
+        if gauge {
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+        } else if format == Format::OpenMetrics {
+            let _ = writeln!(out, "# TYPE {} counter", name.trim_end_matches("_total"));
+        } else {
+            let _ = writeln!(out, "# TYPE {} counter", name);
+        }
+
+        samples.sort();
+        for (labels, value) in samples {
+            if labels.is_empty() {
+                let _ = writeln!(out, "{} {}", name, value);
+            } else {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+
+    // Each kind of latency is its own histogram, labeled by the listener it was recorded on.
+    let mut last_name = None;
+    for (listener, name, histogram) in get_latencies() {
+        let name = format!("{}_client_{}_latency_seconds", METRIC_PREFIX, name);
+        if last_name.as_ref() != Some(&name) {
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+        }
+
+        let labels = format!("listener=\"{}\"", escape_label_value(&listener));
+        render_histogram(&histogram, &name, &labels, format, &mut out);
+        last_name = Some(name);
+    }
+
+    if format == Format::OpenMetrics {
+        out.push_str("# EOF\n");
+    }
+
+    out
+}
+
+/// Writes out a histogram as a histogram named `name`, with the given labels.
+fn render_histogram(histogram: &LatencyBuckets, name: &str, labels: &str, format: Format, out: &mut String) {
+    let exemplars = match format {
+        Format::OpenMetrics => histogram.exemplars(),
+        Format::Prometheus => Vec::new(),
+    };
+
+    let mut total = 0;
+    for (idx, (bound_us, count)) in histogram.cumulative().into_iter().enumerate() {
+        let bound = match bound_us {
+            Some(bound_us) => (bound_us as f64 / 1_000_000.0).to_string(),
+            None => "+Inf".to_owned(),
+        };
+        let _ = write!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+        if let Some(Some(exemplar)) = exemplars.get(idx) {
+            render_exemplar(exemplar, out);
+        }
+        out.push('\n');
+        total = count;
+    }
+
+    let sum = histogram.sum_us() as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, total);
+}
+
+/// Writes out an exemplar, to go on the end of the bucket it belongs to.
+///
+/// Its labels are well under the 128 characters OpenMetrics allows, with the key hash in hex.
+fn render_exemplar(exemplar: &Exemplar, out: &mut String) {
+    let _ = write!(
+        out,
+        " # {{request_id=\"{}\",key_hash=\"{:016x}\"}} {}",
+        exemplar.request_id,
+        exemplar.key_hash,
+        exemplar.value_us as f64 / 1_000_000.0
+    );
+}
+
+/// Splits a full metric name into a Prometheus metric name, and the labels that go with it.
+fn split_metric_name(key: &str) -> (String, String) {
+    let mut name = METRIC_PREFIX.to_owned();
+    let mut labels = Vec::new();
+
+    let mut parts = key.split('.');
+    while let Some(part) = parts.next() {
+        if let Some(&(_, label)) = LABELED_SCOPES.iter().find(|&&(scope, _)| scope == part) {
+            if let Some(value) = parts.next() {
+                labels.push(format!("{}=\"{}\"", label, escape_label_value(value)));
+                continue;
+            }
+        }
+
+        name.push('_');
+        name.extend(part.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }));
+    }
+
+    (name, labels.join(","))
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::register_latencies;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn test_split_metric_name() {
+        let (name, labels) = split_metric_name("supervisor.configuration_loads");
+        assert_eq!(name, "synchrotron_supervisor_configuration_loads");
+        assert_eq!(labels, "");
+
+        let (name, labels) = split_metric_name("listeners.fixed.pools.default.backends.redis-1.backend.errors");
+        assert_eq!(name, "synchrotron_backend_errors");
+        assert_eq!(labels, "listener=\"fixed\",pool=\"default\",backend=\"redis-1\"");
+
+        let (name, labels) = split_metric_name("listeners.fixed.pools.default.backends.127_0_0_1:6379.requests_sent");
+        assert_eq!(name, "synchrotron_requests_sent");
+        assert_eq!(labels, "listener=\"fixed\",pool=\"default\",backend=\"127_0_0_1:6379\"");
+
+        // A scope that should be followed by a name, but isn't, is kept as part of the name.
+        let (name, labels) = split_metric_name("admin.listeners");
+        assert_eq!(name, "synchrotron_admin_listeners");
+        assert_eq!(labels, "");
+    }
+
+    #[test]
+    fn test_render_snapshot() {
+        let snapshot = json!({
+            "listeners.alpha.client.messages_received": 10,
+            "listeners.beta.client.messages_received": 3,
+            "listeners.alpha.clients_connected": 2,
+            "listeners.beta.clients_connected": 1,
+            "supervisor.config_generation": 4,
+            "listeners.alpha.version": "not a number",
+        });
+        let counters = vec!["listeners.alpha.client.messages_received"]
+            .into_iter()
+            .map(|s| s.to_owned())
+            .collect();
+
+        let rendered = render(&snapshot, &counters, Format::Prometheus);
+        let expected = "# TYPE synchrotron_client_messages_received_total counter\n\
+                        synchrotron_client_messages_received_total{listener=\"alpha\"} 10\n\
+                        synchrotron_client_messages_received_total{listener=\"beta\"} 3\n\
+                        # TYPE synchrotron_clients_connected gauge\n\
+                        synchrotron_clients_connected{listener=\"alpha\"} 2\n\
+                        synchrotron_clients_connected{listener=\"beta\"} 1\n\
+                        # TYPE synchrotron_supervisor_config_generation gauge\n\
+                        synchrotron_supervisor_config_generation 4\n";
+        assert!(rendered.starts_with(expected), "unexpected output: {}", rendered);
+    }
+
+    #[test]
+    fn test_render_histogram() {
+        let histogram = LatencyBuckets::new(vec![1_000, 10_000]);
+        histogram.record(Duration::from_micros(500));
+        histogram.record(Duration::from_micros(1_000));
+        histogram.record(Duration::from_millis(5));
+        histogram.record(Duration::from_secs(1));
+
+        let mut out = String::new();
+        render_histogram(&histogram, "latency", "listener=\"fixed\"", Format::Prometheus, &mut out);
+        assert_eq!(
+            out,
+            "latency_bucket{listener=\"fixed\",le=\"0.001\"} 2\n\
+             latency_bucket{listener=\"fixed\",le=\"0.01\"} 3\n\
+             latency_bucket{listener=\"fixed\",le=\"+Inf\"} 4\n\
+             latency_sum{listener=\"fixed\"} 1.0065\n\
+             latency_count{listener=\"fixed\"} 4\n"
+        );
+    }
+
+    #[test]
+    fn test_format_from_accept() {
+        assert_eq!(Format::from_accept(None), Format::Prometheus);
+        assert_eq!(Format::from_accept(Some("text/plain;version=0.0.4")), Format::Prometheus);
+        assert_eq!(Format::from_accept(Some("*/*")), Format::Prometheus);
+
+        // This is what Prometheus itself sends, when it's able to parse OpenMetrics.
+        let accept = "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,\
+                      text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+        assert_eq!(Format::from_accept(Some(accept)), Format::OpenMetrics);
+        assert!(Format::OpenMetrics.content_type().starts_with("application/openmetrics-text; version=1.0.0"));
+    }
+
+    // Checks an exemplar against the OpenMetrics grammar: a label set of no more than 128
+    // characters, a value, and an optional timestamp.
+    fn check_exemplar(exemplar: &str) {
+        assert!(exemplar.starts_with('{'), "exemplar has no labels: {}", exemplar);
+        let end = exemplar.find('}').expect("exemplar labels aren't closed");
+        let labels = &exemplar[1..end];
+
+        let mut length = 0;
+        for label in labels.split(',') {
+            let mut parts = label.splitn(2, '=');
+            let name = parts.next().unwrap();
+            let value = parts.next().expect("exemplar label has no value");
+            assert!(!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+            assert!(value.len() >= 2 && value.starts_with('"') && value.ends_with('"'));
+            length += name.chars().count() + value.chars().count() - 2;
+        }
+        assert!(length <= 128, "exemplar labels are {} characters long", length);
+
+        let rest = exemplar[end + 1..].trim_start_matches(' ').split(' ').collect::<Vec<_>>();
+        assert!(rest.len() == 1 || rest.len() == 2, "bad exemplar value: {}", exemplar);
+        for number in rest {
+            assert!(number.parse::<f64>().is_ok(), "bad exemplar value: {}", exemplar);
+        }
+    }
+
+    #[test]
+    fn test_render_openmetrics() {
+        let histogram = register_latencies("openmetrics", "exemplified", vec![1_000, 10_000]);
+        histogram.record_exemplar(Duration::from_micros(500), 42, 0xdead_beef);
+        histogram.record_exemplar(Duration::from_secs(1), 43, std::u64::MAX);
+
+        let snapshot = json!({
+            "listeners.openmetrics.client.messages_received": 10,
+            "listeners.openmetrics.clients_connected": 2,
+        });
+        let counters = vec!["listeners.openmetrics.client.messages_received".to_owned()].into_iter().collect();
+        let rendered = render(&snapshot, &counters, Format::OpenMetrics);
+
+        // Counter families are named without their suffix, and the exposition has to be terminated.
+        assert!(rendered.starts_with(
+            "# TYPE synchrotron_client_messages_received counter\n\
+             synchrotron_client_messages_received_total{listener=\"openmetrics\"} 10\n\
+             # TYPE synchrotron_clients_connected gauge\n"
+        ));
+        assert!(rendered.ends_with("\n# EOF\n"));
+
+        let name = "synchrotron_client_exemplified_latency_seconds_bucket{listener=\"openmetrics\"";
+        let buckets = rendered.lines().filter(|line| line.starts_with(name)).collect::<Vec<_>>();
+        assert_eq!(
+            buckets,
+            vec![
+                format!("{},le=\"0.001\"}} 1 # {{request_id=\"42\",key_hash=\"00000000deadbeef\"}} 0.0005", name),
+                format!("{},le=\"0.01\"}} 1", name),
+                format!("{},le=\"+Inf\"}} 2 # {{request_id=\"43\",key_hash=\"ffffffffffffffff\"}} 1", name),
+            ]
+        );
+
+        // Exemplars are only ever on buckets, and always by the book.
+        for line in rendered.lines().filter(|line| !line.starts_with('#')) {
+            if let Some(idx) = line.find(" # ") {
+                assert!(line[..idx].contains("_bucket{"), "exemplar on a non-bucket: {}", line);
+                check_exemplar(&line[idx + 3..]);
+            }
+        }
+
+        // Plain Prometheus gets none of this.
+        let rendered = render(&snapshot, &counters, Format::Prometheus);
+        assert!(!rendered.contains(" # {"));
+        assert!(!rendered.contains("# EOF"));
+        assert!(rendered.contains("# TYPE synchrotron_client_messages_received_total counter\n"));
+    }
+}
@@ -0,0 +1,471 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use futures::{prelude::*, sync::oneshot};
+use hotmic::Sink;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use util::watchdog;
+
+const AGGREGATOR_FLUSH_INTERVAL_MS: u64 = 1000;
+
+// How long the aggregator can go without looping before the watchdog considers it stalled.
+const AGGREGATOR_HEARTBEAT_INTERVAL_MS: u64 = AGGREGATOR_FLUSH_INTERVAL_MS * 5;
+
+/// A single metric update, as sent from the data path to the aggregator.
+enum MetricUpdate {
+    Count(usize, &'static str, i64),
+    Level(usize, &'static str, i64),
+    Gauge(usize, &'static str, u64),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Scopes that have been handed out so far, and the real sinks that back them.
+///
+/// We also keep the full name of every metric that has been recorded as a count, so that exporters
+/// can tell counters apart from everything else in a snapshot, which they give as-is.
+struct ScopeRegistry {
+    ids: HashMap<String, usize>,
+    names: Vec<String>,
+    sinks: Vec<Sink<&'static str>>,
+    counters: HashSet<String>,
+}
+
+/// Something that can be used to scope a metric sink.
+pub trait AsScope {
+    fn as_scope(&self) -> Vec<&str>;
+}
+
+impl AsScope for str {
+    fn as_scope(&self) -> Vec<&str> { vec![self] }
+}
+
+impl<'a> AsScope for [&'a str] {
+    fn as_scope(&self) -> Vec<&str> { self.to_vec() }
+}
+
+macro_rules! impl_as_scope_for_array {
+    ($($n:expr),*) => {
+        $(
+            impl<'a> AsScope for [&'a str; $n] {
+                fn as_scope(&self) -> Vec<&str> { self.to_vec() }
+            }
+        )*
+    };
+}
+
+impl_as_scope_for_array!(1, 2, 3, 4, 5, 6);
+
+/// A handle for recording metrics from the data path.
+///
+/// Recording a metric never blocks: updates are pushed onto a bounded channel and folded into the
+/// real sinks by a dedicated aggregator.  If the aggregator falls behind and the channel fills up,
+/// the update is dropped and counted instead.
+#[derive(Clone)]
+pub struct MetricSink {
+    tx: SyncSender<MetricUpdate>,
+    registry: Arc<Mutex<ScopeRegistry>>,
+    dropped: Arc<AtomicUsize>,
+    scope: Arc<String>,
+    scope_id: usize,
+}
+
+impl MetricSink {
+    /// Creates a sink scoped to the given scope, relative to the scope of this sink.
+    ///
+    /// Dots separate the parts of a scope, so any dots in a part itself, like those in a backend
+    /// identified by its address, are turned into underscores.
+    pub fn scoped<S: AsScope + ?Sized>(&self, scope: &S) -> MetricSink {
+        let parts = scope
+            .as_scope()
+            .into_iter()
+            .map(|part| part.replace('.', "_"))
+            .collect::<Vec<_>>();
+
+        let mut full_scope = self.scope.as_ref().clone();
+        for part in &parts {
+            if !full_scope.is_empty() {
+                full_scope.push('.');
+            }
+            full_scope.push_str(part);
+        }
+
+        let scope_id = {
+            let mut registry = self.registry.lock().unwrap();
+            match registry.ids.get(&full_scope) {
+                Some(id) => *id,
+                None => {
+                    let mut sink = registry.sinks[self.scope_id].clone();
+                    for part in &parts {
+                        sink = sink.scoped(part.as_str());
+                    }
+
+                    let id = registry.sinks.len();
+                    registry.sinks.push(sink);
+                    registry.names.push(full_scope.clone());
+                    registry.ids.insert(full_scope.clone(), id);
+                    id
+                },
+            }
+        };
+
+        MetricSink {
+            tx: self.tx.clone(),
+            registry: self.registry.clone(),
+            dropped: self.dropped.clone(),
+            scope: Arc::new(full_scope),
+            scope_id,
+        }
+    }
+
+    /// Gets the full scope of this sink, such as `listeners.fixed.client`.
+    pub fn scope(&self) -> &str { self.scope.as_str() }
+
+    /// Gets the full name of every metric that has been recorded as a count.
+    ///
+    /// Levels, gauges, and anything else in a snapshot, like the percentiles of a histogram, aren't
+    /// counters, no matter how they've changed.
+    pub fn counters(&self) -> HashSet<String> { self.registry.lock().unwrap().counters.clone() }
+
+    pub fn increment(&self, key: &'static str) { self.update_count(key, 1) }
+
+    pub fn update_count(&self, key: &'static str, delta: i64) {
+        self.send(MetricUpdate::Count(self.scope_id, key, delta))
+    }
+
+    /// Moves a level, such as the number of connected clients, up or down by the given amount.
+    ///
+    /// Levels are kept like counts, but are reported as gauges.
+    pub fn update_level(&self, key: &'static str, delta: i64) {
+        self.send(MetricUpdate::Level(self.scope_id, key, delta))
+    }
+
+    pub fn update_gauge(&self, key: &'static str, value: u64) {
+        self.send(MetricUpdate::Gauge(self.scope_id, key, value))
+    }
+
+    /// Waits for the aggregator to fold in every update recorded before now.
+    ///
+    /// If the aggregator is too far behind to even take the request, this resolves right away.
+    pub fn flush(&self) -> impl Future<Item = (), Error = ()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        let _ = self.tx.try_send(MetricUpdate::Flush(done_tx));
+        done_rx.then(|_| Ok(()))
+    }
+
+    fn send(&self, update: MetricUpdate) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(update) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Folds metric updates from the data path into the real sinks.
+pub struct MetricAggregator {
+    rx: Receiver<MetricUpdate>,
+    registry: Arc<Mutex<ScopeRegistry>>,
+    dropped: Arc<AtomicUsize>,
+    sinks: Vec<Sink<&'static str>>,
+    counters: HashSet<(usize, &'static str)>,
+    root: Sink<&'static str>,
+}
+
+impl MetricAggregator {
+    /// Runs the aggregator until every sink feeding it has been dropped.
+    pub fn run(mut self) {
+        let flush_interval = Duration::from_millis(AGGREGATOR_FLUSH_INTERVAL_MS);
+        let heartbeat_interval = Duration::from_millis(AGGREGATOR_HEARTBEAT_INTERVAL_MS);
+        let heartbeat = watchdog::register("metrics.aggregator", heartbeat_interval);
+
+        loop {
+            heartbeat.beat();
+
+            match self.rx.recv_timeout(flush_interval) {
+                Ok(update) => self.apply(update),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            let dropped = self.dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                self.root.update_count("dropped_updates", dropped as i64);
+            }
+        }
+    }
+
+    fn apply(&mut self, update: MetricUpdate) {
+        let scope_id = match update {
+            MetricUpdate::Count(id, _, _) => id,
+            MetricUpdate::Level(id, _, _) => id,
+            MetricUpdate::Gauge(id, _, _) => id,
+            MetricUpdate::Flush(done) => {
+                // Updates are applied in the order they're sent, so everything before this is in.
+                let _ = done.send(());
+                return;
+            },
+        };
+
+        // Scopes are only ever appended, so we just need to catch up on any new ones.
+        if scope_id >= self.sinks.len() {
+            let registry = self.registry.lock().unwrap();
+            let known = self.sinks.len();
+            self.sinks.extend_from_slice(&registry.sinks[known..]);
+        }
+
+        if let MetricUpdate::Count(_, key, _) = update {
+            self.mark_counter(scope_id, key);
+        }
+
+        let sink = &self.sinks[scope_id];
+        match update {
+            MetricUpdate::Count(_, key, delta) | MetricUpdate::Level(_, key, delta) => sink.update_count(key, delta),
+            MetricUpdate::Gauge(_, key, value) => sink.update_gauge(key, value),
+            MetricUpdate::Flush(_) => {},
+        }
+    }
+
+    fn mark_counter(&mut self, scope_id: usize, key: &'static str) {
+        if self.counters.insert((scope_id, key)) {
+            let mut registry = self.registry.lock().unwrap();
+            let name = match registry.names[scope_id].as_str() {
+                "" => key.to_owned(),
+                scope => format!("{}.{}", scope, key),
+            };
+            registry.counters.insert(name);
+        }
+    }
+}
+
+/// Creates a root metric sink, and the aggregator that feeds its updates into `sink`.
+///
+/// The channel between them holds up to `capacity` pending updates.
+pub fn channel(sink: Sink<&'static str>, capacity: usize) -> (MetricSink, MetricAggregator) {
+    let (tx, rx) = sync_channel(capacity);
+    let registry = Arc::new(Mutex::new(ScopeRegistry {
+        ids: HashMap::new(),
+        names: vec![String::new()],
+        sinks: vec![sink.clone()],
+        counters: HashSet::new(),
+    }));
+    let dropped = Arc::new(AtomicUsize::new(0));
+
+    let metric_sink = MetricSink {
+        tx,
+        registry: registry.clone(),
+        dropped: dropped.clone(),
+        scope: Arc::new(String::new()),
+        scope_id: 0,
+    };
+
+    let aggregator = MetricAggregator {
+        rx,
+        registry,
+        dropped,
+        sinks: Vec::new(),
+        counters: HashSet::new(),
+        root: sink.scoped("metrics"),
+    };
+
+    (metric_sink, aggregator)
+}
+
+/// Creates a root metric sink whose updates are captured, rather than folded into a real sink.
+#[cfg(test)]
+pub fn capture() -> (MetricSink, MetricCapture) {
+    use hotmic::Receiver as MetricReceiver;
+
+    let receiver = MetricReceiver::builder().build();
+    let (sink, aggregator) = channel(receiver.get_sink(), 1024);
+    let capture = MetricCapture {
+        rx: aggregator.rx,
+        registry: aggregator.registry,
+    };
+    (sink, capture)
+}
+
+/// Metric updates captured from a sink, for checking what was recorded in tests.
+#[cfg(test)]
+pub struct MetricCapture {
+    rx: Receiver<MetricUpdate>,
+    registry: Arc<Mutex<ScopeRegistry>>,
+}
+
+#[cfg(test)]
+impl MetricCapture {
+    /// Sums up every count recorded since the last call, by the full name of the metric.
+    pub fn counts(&self) -> HashMap<String, i64> {
+        let registry = self.registry.lock().unwrap();
+        let scopes = registry
+            .ids
+            .iter()
+            .map(|(scope, id)| (*id, scope.clone()))
+            .collect::<HashMap<_, _>>();
+
+        let mut counts = HashMap::new();
+        while let Ok(update) = self.rx.try_recv() {
+            if let MetricUpdate::Count(id, key, delta) | MetricUpdate::Level(id, key, delta) = update {
+                let name = match scopes.get(&id) {
+                    Some(scope) => format!("{}.{}", scope, key),
+                    None => key.to_owned(),
+                };
+                *counts.entry(name).or_insert(0) += delta;
+            }
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::lazy;
+    use hotmic::Receiver as MetricReceiver;
+    use test::Bencher;
+
+    fn get_channel(capacity: usize) -> (MetricReceiver<&'static str>, MetricSink, MetricAggregator) {
+        let receiver = MetricReceiver::builder().build();
+        let (sink, aggregator) = channel(receiver.get_sink(), capacity);
+        (receiver, sink, aggregator)
+    }
+
+    #[test]
+    fn test_scopes_are_shared() {
+        let (_receiver, sink, _aggregator) = get_channel(16);
+
+        let listener = sink.scoped(&["listeners", "fixed"]);
+        let client1 = listener.scoped("client");
+        let client2 = listener.scoped("client");
+        let other = sink.scoped(&["listeners", "fixed", "client"]);
+
+        assert_eq!(listener.scope.as_str(), "listeners.fixed");
+        assert_eq!(client1.scope.as_str(), "listeners.fixed.client");
+        assert_eq!(client1.scope_id, client2.scope_id);
+        assert_eq!(client1.scope_id, other.scope_id);
+        assert_ne!(client1.scope_id, listener.scope_id);
+
+        // Parts of a scope can't be split up by dots of their own.
+        let backend = listener.scoped(&["backends", "127.0.0.1:6379"]);
+        assert_eq!(backend.scope.as_str(), "listeners.fixed.backends.127_0_0_1:6379");
+    }
+
+    #[test]
+    fn test_full_channel_drops_updates() {
+        let (_receiver, sink, aggregator) = get_channel(2);
+
+        sink.increment("foo");
+        sink.increment("foo");
+        sink.increment("foo");
+        sink.update_gauge("bar", 42);
+        assert_eq!(aggregator.dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_aggregator_applies_updates() {
+        let (_receiver, sink, mut aggregator) = get_channel(16);
+
+        let scoped = sink.scoped("client");
+        scoped.increment("foo");
+        scoped.update_gauge("bar", 42);
+
+        while let Ok(update) = aggregator.rx.try_recv() {
+            aggregator.apply(update);
+        }
+
+        // We should have caught up on the root scope plus the one we created.
+        assert_eq!(aggregator.sinks.len(), 2);
+    }
+
+    #[test]
+    fn test_aggregator_tracks_counters() {
+        let (_receiver, sink, mut aggregator) = get_channel(16);
+
+        // A level is never a counter, even if it's only ever gone up so far, and a count is always
+        // one, whatever it's been updated by.
+        let scoped = sink.scoped(&["listeners", "fixed"]);
+        scoped.increment("messages");
+        scoped.update_count("adjusted", -2);
+        scoped.update_level("clients", 1);
+        scoped.update_gauge("queue_depth", 3);
+        sink.update_gauge("uptime", 10);
+        sink.increment("reloads");
+
+        while let Ok(update) = aggregator.rx.try_recv() {
+            aggregator.apply(update);
+        }
+
+        let mut counters = sink.counters().into_iter().collect::<Vec<_>>();
+        counters.sort();
+        assert_eq!(counters, vec!["listeners.fixed.adjusted", "listeners.fixed.messages", "reloads"]);
+    }
+
+    #[test]
+    fn test_flush_waits_for_earlier_updates() {
+        let (_receiver, sink, mut aggregator) = get_channel(16);
+
+        sink.increment("foo");
+        let mut flushed = sink.flush();
+        sink.increment("foo");
+
+        // The flush shouldn't be done until the aggregator gets to it, and should be done as soon
+        // as it has, regardless of what came after it.
+        let update = aggregator.rx.try_recv().unwrap();
+        aggregator.apply(update);
+        assert!(!lazy(|| flushed.poll()).wait().unwrap().is_ready());
+
+        let update = aggregator.rx.try_recv().unwrap();
+        aggregator.apply(update);
+        assert!(lazy(|| flushed.poll()).wait().unwrap().is_ready());
+    }
+
+    #[test]
+    fn test_flush_with_full_channel() {
+        let (_receiver, sink, _aggregator) = get_channel(1);
+
+        // There's no room to ask the aggregator to flush, so we don't wait on it.
+        sink.increment("foo");
+        assert!(sink.flush().wait().is_ok());
+    }
+
+    #[bench]
+    fn bench_update_count(b: &mut Bencher) {
+        let (_receiver, sink, aggregator) = get_channel(1024);
+
+        // Keep the channel drained so that we're measuring the send, and not the drop path.
+        let rx = aggregator.rx;
+        b.iter(|| {
+            sink.update_count("foo", 1);
+            let _ = rx.try_recv();
+        });
+    }
+
+    #[bench]
+    fn bench_update_count_full(b: &mut Bencher) {
+        let (_receiver, sink, _aggregator) = get_channel(1);
+        sink.update_count("foo", 1);
+
+        b.iter(|| sink.update_count("foo", 1));
+    }
+}
@@ -0,0 +1,433 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{
+    latency::{get_latencies, LatencyBuckets},
+    prometheus::LABELED_SCOPES,
+    sink::MetricSink,
+};
+use conf::MetricsConfiguration;
+use futures::prelude::*;
+use hotmic::Controller;
+use serde_json::{self, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+
+// How often metrics are sent when no flush interval is configured.
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 10_000;
+
+// The most we put in a single datagram, which keeps it within the MTU of a typical network, headers
+// and all.  A line longer than this still goes out, in a datagram of its own.
+const MAX_DATAGRAM_LEN: usize = 1432;
+
+/// Sends metrics to a statsd agent.
+///
+/// Metrics are aggregated in-process as usual, and every flush sends what changed since the last
+/// one: counters as `|c` with how much they went up, gauges as `|g` with their current value, and
+/// latencies as `|ms`.  Latencies are only kept in buckets, so each bucket that anything landed in
+/// is sent as a single timing at its upper bound, sampled at a rate that makes it count once for
+/// everything that landed in it.
+///
+/// The socket never blocks, and a datagram that can't be sent is dropped and counted, so a missing
+/// or overwhelmed agent costs us nothing but the metrics themselves.
+pub struct StatsdEmitter {
+    socket: UdpSocket,
+    target: SocketAddr,
+    prefix: Option<String>,
+    dogstatsd: bool,
+    counters: HashMap<String, i64>,
+    latencies: HashMap<String, Vec<(Option<u64>, usize)>>,
+    sink: MetricSink,
+}
+
+impl StatsdEmitter {
+    pub fn new(config: &MetricsConfiguration, sink: MetricSink) -> io::Result<StatsdEmitter> {
+        let target = config
+            .get_address()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let local: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(StatsdEmitter {
+            socket,
+            target,
+            prefix: config.prefix.clone().filter(|prefix| !prefix.is_empty()),
+            dogstatsd: config.dogstatsd.unwrap_or(false),
+            counters: HashMap::new(),
+            latencies: HashMap::new(),
+            sink,
+        })
+    }
+
+    /// Sends everything that changed since the last flush, as of the given snapshot.
+    ///
+    /// Metrics named in `counters` are sent as counters, and every other number as a gauge.
+    pub fn flush(
+        &mut self, snapshot: &Value, counters: &HashSet<String>,
+        latencies: &[(String, &'static str, Arc<LatencyBuckets>)],
+    ) {
+        let mut lines = Vec::new();
+        if let Value::Object(ref metrics) = *snapshot {
+            for (key, value) in metrics {
+                if !counters.contains(key) {
+                    if let Value::Number(ref value) = *value {
+                        lines.push(self.format_line(key, &value.to_string(), "g", None));
+                    }
+                    continue;
+                }
+
+                // A counter that went backwards was reset, so everything it has now is new.
+                if let Some(current) = value.as_i64() {
+                    let previous = self.counters.insert(key.clone(), current).unwrap_or(0);
+                    let delta = if current < previous { current } else { current - previous };
+                    if delta != 0 {
+                        lines.push(self.format_line(key, &delta.to_string(), "c", None));
+                    }
+                }
+            }
+        }
+
+        for (listener, name, histogram) in latencies {
+            let key = format!("listeners.{}.client.{}_latency", listener, name);
+            let cumulative = histogram.cumulative();
+
+            // A histogram whose buckets changed was replaced, so it's counted up from nothing.
+            let previous = self
+                .latencies
+                .insert(key.clone(), cumulative.clone())
+                .filter(|previous| {
+                    previous.len() == cumulative.len() && previous.iter().zip(&cumulative).all(|(a, b)| a.0 == b.0)
+                })
+                .map_or_else(|| vec![0; cumulative.len()], |previous| get_bucket_counts(&previous));
+
+            // Anything past the last bound is at least as slow as it, which is the best we can say.
+            let mut last_bound_us = 0;
+            for (idx, count) in get_bucket_counts(&cumulative).into_iter().enumerate() {
+                let bound_us = cumulative[idx].0.unwrap_or(last_bound_us);
+                last_bound_us = bound_us;
+
+                let count = count.saturating_sub(previous[idx]);
+                if count > 0 {
+                    let value = (bound_us as f64 / 1000.0).to_string();
+                    lines.push(self.format_line(&key, &value, "ms", Some(count)));
+                }
+            }
+        }
+
+        self.send(&lines);
+    }
+
+    /// Formats a single metric as a statsd line.
+    fn format_line(&self, key: &str, value: &str, kind: &str, samples: Option<usize>) -> String {
+        let (name, tags) = if self.dogstatsd {
+            split_tags(key)
+        } else {
+            (sanitize(key), Vec::new())
+        };
+
+        let mut line = String::new();
+        if let Some(ref prefix) = self.prefix {
+            line.push_str(prefix);
+            line.push('.');
+        }
+        line.push_str(&name);
+        line.push(':');
+        line.push_str(value);
+        line.push('|');
+        line.push_str(kind);
+        if let Some(samples) = samples.filter(|samples| *samples > 1) {
+            line.push_str(&format!("|@{}", 1.0 / samples as f64));
+        }
+        if !tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        line
+    }
+
+    /// Sends the given lines, packing as many into each datagram as will fit.
+    fn send(&self, lines: &[String]) {
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_LEN {
+                self.send_datagram(&datagram);
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(line);
+        }
+        if !datagram.is_empty() {
+            self.send_datagram(&datagram);
+        }
+    }
+
+    fn send_datagram(&self, datagram: &str) {
+        if let Err(e) = self.socket.send_to(datagram.as_bytes(), self.target) {
+            debug!("[metrics] failed to send metrics to statsd at {}: {}", self.target, e);
+            self.sink.increment("statsd_send_errors");
+        }
+    }
+}
+
+/// Turns the cumulative counts of a histogram back into the count of each bucket on its own.
+fn get_bucket_counts(cumulative: &[(Option<u64>, usize)]) -> Vec<usize> {
+    let mut below = 0;
+    cumulative
+        .iter()
+        .map(|&(_, total)| {
+            let count = total.saturating_sub(below);
+            below = total;
+            count
+        })
+        .collect()
+}
+
+/// Splits a full metric name into the name sent to DogStatsD, and the tags that go with it.
+///
+/// The names of listeners, pools and backends are pulled out into tags, the same way they're pulled
+/// out into labels for Prometheus.
+fn split_tags(key: &str) -> (String, Vec<String>) {
+    let mut name = Vec::new();
+    let mut tags = Vec::new();
+
+    let mut parts = key.split('.');
+    while let Some(part) = parts.next() {
+        if let Some(&(_, tag)) = LABELED_SCOPES.iter().find(|&&(scope, _)| scope == part) {
+            if let Some(value) = parts.next() {
+                tags.push(format!("{}:{}", tag, sanitize_tag(value)));
+                continue;
+            }
+        }
+
+        name.push(sanitize(part));
+    }
+
+    (name.join("."), tags)
+}
+
+// Colons, pipes and at signs separate the parts of a statsd line, so they can't be in a name.
+fn sanitize(name: &str) -> String { name.replace(|c| c == ':' || c == '|' || c == '@' || c == '#', "_") }
+
+// Commas separate tags, but a colon past the first is just part of the value.
+fn sanitize_tag(value: &str) -> String { value.replace(|c| c == ',' || c == '|' || c == '#', "_") }
+
+/// Sends metrics to statsd every flush interval until `shutdown` fires, sending them one last time
+/// as it does.
+pub struct StatsdTask<F: Future> {
+    emitter: StatsdEmitter,
+    control: Controller,
+    interval: Interval,
+    shutdown: F,
+}
+
+impl<F: Future> StatsdTask<F> {
+    pub fn new(config: &MetricsConfiguration, emitter: StatsdEmitter, control: Controller, shutdown: F) -> Self {
+        let interval = Duration::from_millis(config.flush_interval_ms.unwrap_or(DEFAULT_FLUSH_INTERVAL_MS));
+        StatsdTask {
+            emitter,
+            control,
+            interval: Interval::new(Instant::now() + interval, interval),
+            shutdown,
+        }
+    }
+
+    fn flush(&mut self) {
+        let snapshot = self
+            .control
+            .get_snapshot()
+            .map_err(|e| e.to_string())
+            .and_then(|snapshot| serde_json::to_value(&snapshot).map_err(|e| e.to_string()));
+        match snapshot {
+            Ok(metrics) => {
+                let counters = self.emitter.sink.counters();
+                self.emitter.flush(&metrics, &counters, &get_latencies());
+            },
+            Err(e) => warn!("[metrics] failed to take snapshot for statsd: {}", e),
+        }
+    }
+}
+
+impl<F: Future> Future for StatsdTask<F> {
+    type Error = ();
+    type Item = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Whatever was recorded on the way down is only in by the time we're told to stop.
+        match self.shutdown.poll() {
+            Ok(Async::NotReady) => {},
+            _ => {
+                self.flush();
+                return Ok(Async::Ready(()));
+            },
+        }
+
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => self.flush(),
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    error!("[metrics] statsd timer failed: {}", e);
+                    return Err(());
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::get_sink;
+    use serde_json::json;
+
+    fn get_emitter(dogstatsd: bool) -> (StatsdEmitter, UdpSocket) {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let config = MetricsConfiguration {
+            metrics_type: "statsd".to_owned(),
+            address: agent.local_addr().unwrap().to_string(),
+            prefix: Some("synchrotron".to_owned()),
+            dogstatsd: Some(dogstatsd),
+            ..Default::default()
+        };
+        (StatsdEmitter::new(&config, get_sink()).unwrap(), agent)
+    }
+
+    fn receive(agent: &UdpSocket) -> Vec<String> {
+        let mut buf = [0; 65536];
+        let n = agent.recv(&mut buf).unwrap();
+        let mut lines = String::from_utf8_lossy(&buf[..n])
+            .lines()
+            .map(|line| line.to_owned())
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn test_line_format() {
+        let (mut emitter, agent) = get_emitter(false);
+        let histogram = Arc::new(LatencyBuckets::new(vec![1_000, 10_000]));
+        histogram.record(Duration::from_micros(500));
+        histogram.record(Duration::from_micros(700));
+        histogram.record(Duration::from_millis(5));
+        let latencies = vec![("fixed".to_owned(), "read", histogram.clone())];
+
+        let counters = vec![
+            "listeners.fixed.client.messages_received".to_owned(),
+            "listeners.fixed.pools.default.backends.127_0_0_1:6379.requests_sent".to_owned(),
+        ]
+        .into_iter()
+        .collect();
+        let snapshot = json!({
+            "listeners.fixed.client.messages_received": 10,
+            "listeners.fixed.clients_connected": 2,
+            "listeners.fixed.pools.default.backends.127_0_0_1:6379.requests_sent": 4,
+        });
+        emitter.flush(&snapshot, &counters, &latencies);
+        assert_eq!(
+            receive(&agent),
+            vec![
+                "synchrotron.listeners.fixed.client.messages_received:10|c",
+                "synchrotron.listeners.fixed.client.read_latency:10|ms",
+                "synchrotron.listeners.fixed.client.read_latency:1|ms|@0.5",
+                "synchrotron.listeners.fixed.clients_connected:2|g",
+                "synchrotron.listeners.fixed.pools.default.backends.127_0_0_1_6379.requests_sent:4|c",
+            ]
+        );
+
+        // Counters are sent as what they went up by, and only if they went up at all, while gauges
+        // are always sent as they are.
+        histogram.record(Duration::from_secs(1));
+        let snapshot = json!({
+            "listeners.fixed.client.messages_received": 15,
+            "listeners.fixed.clients_connected": 1,
+            "listeners.fixed.pools.default.backends.127_0_0_1:6379.requests_sent": 4,
+        });
+        emitter.flush(&snapshot, &counters, &latencies);
+        assert_eq!(
+            receive(&agent),
+            vec![
+                "synchrotron.listeners.fixed.client.messages_received:5|c",
+                "synchrotron.listeners.fixed.client.read_latency:10|ms",
+                "synchrotron.listeners.fixed.clients_connected:1|g",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dogstatsd_tags() {
+        let (mut emitter, agent) = get_emitter(true);
+        let snapshot = json!({
+            "listeners.fixed.pools.default.backends.127_0_0_1:6379.requests_sent": 4,
+            "supervisor.configuration_loads": 1,
+        });
+        let counters = snapshot.as_object().unwrap().keys().cloned().collect();
+        emitter.flush(&snapshot, &counters, &[]);
+        assert_eq!(
+            receive(&agent),
+            vec![
+                "synchrotron.requests_sent:4|c|#listener:fixed,pool:default,backend:127_0_0_1:6379",
+                "synchrotron.supervisor.configuration_loads:1|c",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_datagrams_are_split() {
+        let (emitter, agent) = get_emitter(false);
+        let lines = (0..300).map(|i| format!("metric.{}:1|c", i)).collect::<Vec<_>>();
+        emitter.send(&lines);
+
+        let mut received = Vec::new();
+        while received.len() < lines.len() {
+            let datagram = receive(&agent);
+            assert!(datagram.join("\n").len() <= MAX_DATAGRAM_LEN);
+            received.extend(datagram);
+        }
+        received.sort();
+
+        let mut expected = lines.clone();
+        expected.sort();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_unreachable_agent() {
+        // Nobody listening on the other end is nobody's problem but ours.
+        let (emitter, agent) = get_emitter(false);
+        drop(agent);
+        emitter.send(&["metric:1|c".to_owned()]);
+        emitter.send(&["metric:1|c".to_owned()]);
+    }
+}
@@ -17,18 +17,1023 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use futures::prelude::*;
+use backend::{
+    drain::{drain_backend, get_drain_status, restore_backend, DrainStatus, DEFAULT_QUIET_MS},
+    weights::find_backend_weights,
+};
+use capabilities::get_capabilities;
+use conf::{get_applied, AppliedConfiguration};
+use events::{get_recent_events, LifecycleEvent};
+use futures::{
+    future::{err, Either},
+    prelude::*,
+};
 use hotmic::Controller;
-use std::net::SocketAddr;
-use warp::Filter;
-
-pub fn build_with_graceful_shutdown(
-    addr: SocketAddr, control: Controller, signal: impl Future<Item = ()> + Send + 'static,
-) -> impl Future<Item = (), Error = ()> {
-    let chain = warp::path("stats")
-        .and_then(move || control.get_snapshot().map_err(warp::reject::custom))
-        .map(|val| warp::reply::json(&val));
-
-    let (_, server) = warp::serve(chain).bind_with_graceful_shutdown(addr, signal);
-    server
+use lifecycle;
+use metrics::{
+    delta::StatsDelta,
+    get_sink,
+    latency::{get_latencies, REPORTED_QUANTILES},
+    overview::{get_overview, get_pool_overviews, PoolOverview},
+    prometheus, MetricSink, StatsHistory,
+};
+use reload::{self, ReloadOutcome};
+use serde_json::Value;
+use service::{find_client_registry, get_key_samplers, ClientInfo, KeySample};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpListener, sync::mpsc::UnboundedSender, timer::Delay};
+use util::{clock::duration_as_ms, watchdog::get_task_registry};
+use warp::{http::StatusCode, reject, Filter, Rejection, Reply};
+use SupervisorCommand;
+
+// How many clients are listed when no limit is given, and the most that can be asked for at once.
+const DEFAULT_CLIENT_LIST_LIMIT: usize = 100;
+const MAX_CLIENT_LIST_LIMIT: usize = 1000;
+
+// The longest we'll wait between attempts to bind, no matter how many have failed.
+const MAX_BIND_RETRY_MS: u64 = 30_000;
+
+// The longest a reload request can wait for the reload to complete.
+const MAX_RELOAD_WAIT_MS: u64 = 300_000;
+
+// The window stats deltas are worked out over when none is given.
+const DEFAULT_DELTA_WINDOW_MS: u64 = 10_000;
+
+// Every route served by the admin endpoint, in the order they're matched, along with the
+// operations each of them provides.  The table is handed to another macro to work with, so that
+// the routes we serve and the operations we report can't drift apart.
+macro_rules! admin_routes {
+    ($m:ident $(, $arg:expr)*) => {
+        $m! {
+            ($($arg),*)
+            overview_route => ["overview"],
+            pool_overview_route => ["pool_overview"],
+            // These have to be matched before `stats`, which takes anything under its path.
+            key_prefixes_route => ["key_prefixes"],
+            stats_delta_route => ["stats_delta"],
+            stats_route => ["stats"],
+            prometheus_metrics_route => ["prometheus_metrics", "openmetrics_exemplars"],
+            capabilities_route => ["capabilities"],
+            config_route => ["config"],
+            health_route => ["health"],
+            listener_stats_route => ["listener_stats"],
+            list_clients_route => ["list_clients"],
+            kill_clients_route => ["kill_clients"],
+            listener_command_route => ["reload_listener", "drain_listener"],
+            backend_weight_route => ["backend_weight"],
+            drain_backend_route => ["drain_backend"],
+            restore_backend_route => ["restore_backend"],
+            drain_status_route => ["drain_backend_status"],
+            events_route => ["events"],
+            reload_status_route => ["reload_status"],
+            reload_route => ["reload"],
+        }
+    };
+}
+
+// Lists the operations of every route.
+macro_rules! admin_features {
+    (() $($route:ident => [$($feature:expr),*],)*) => { &[$($($feature,)*)*] };
+}
+
+// Builds every route with the given context, and chains them together in order.
+macro_rules! chain_routes {
+    (($ctx:expr) $first:ident => [$($_first:expr),*], $($route:ident => [$($_feature:expr),*],)*) => {
+        $first(&$ctx)$(.or($route(&$ctx)))*
+    };
+}
+
+/// The operations served by the admin endpoint, as reported in our capabilities.
+pub const ADMIN_FEATURES: &[&str] = admin_routes!(admin_features);
+
+#[derive(Deserialize)]
+struct KillClientsQuery {
+    addr: Option<String>,
+    id: Option<u64>,
+    name: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct ListClientsQuery {
+    after: Option<u64>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ListClientsResponse {
+    listener: String,
+    total: usize,
+    clients: Vec<ClientInfo>,
+}
+
+#[derive(Serialize)]
+struct KillClientsResponse {
+    killed: usize,
+}
+
+#[derive(Deserialize)]
+struct BackendWeightQuery {
+    weight: usize,
+}
+
+#[derive(Serialize)]
+struct BackendWeightResponse {
+    backend: String,
+    weight: usize,
+}
+
+#[derive(Default, Deserialize)]
+struct DrainBackendQuery {
+    quiet_ms: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+struct EventsQuery {
+    since: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct EventsResponse {
+    events: Vec<LifecycleEvent>,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    healthy: bool,
+    stalled_tasks: usize,
+    stalled: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ListenerCommandResponse {
+    listener: String,
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+struct StatsDeltaQuery {
+    window: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct ReloadQuery {
+    wait: Option<String>,
+    preserve_drains: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct ReloadResponse {
+    token: usize,
+    completed: bool,
+    failed: bool,
+}
+
+/// The stats and admin HTTP server, which keeps itself bound to its address.
+///
+/// If the address can't be bound, or the server stops accepting connections, binding is retried
+/// with backoff until it works, or until the shutdown signal fires.  Nothing else waits on the
+/// server, so the data plane carries on whether or not stats are being served.
+pub struct AdminServer<F> {
+    addr: SocketAddr,
+    control: Controller,
+    history: Arc<StatsHistory>,
+    supervisor: UnboundedSender<SupervisorCommand>,
+    signal: F,
+    retry: Duration,
+    failures: u32,
+    state: AdminState,
+    sink: MetricSink,
+}
+
+enum AdminState {
+    Binding(Delay),
+    Serving(Box<Future<Item = (), Error = ()> + Send>),
+}
+
+impl<F> AdminServer<F>
+where
+    F: Future<Item = ()>,
+{
+    fn bind(&mut self) -> AdminState {
+        match TcpListener::bind(&self.addr) {
+            Ok(listener) => {
+                if self.failures > 0 {
+                    info!(
+                        "[metrics] serving metric data on {} after {} failed attempt(s) to bind",
+                        self.addr, self.failures
+                    );
+                } else {
+                    info!("[metrics] serving metric data on {}...", self.addr);
+                }
+
+                self.failures = 0;
+                self.sink.increment("binds");
+                self.sink.update_gauge("listening", 1);
+
+                let server = serve(
+                    listener,
+                    self.control.clone(),
+                    self.history.clone(),
+                    self.supervisor.clone(),
+                );
+                AdminState::Serving(Box::new(server))
+            },
+            Err(e) => {
+                self.failures += 1;
+                self.sink.increment("bind_failures");
+
+                let backoff = get_bind_backoff(self.retry, self.failures);
+                warn!(
+                    "[metrics] failed to bind stats server to {}: {} (attempt {}, retrying in {}ms)",
+                    self.addr,
+                    e,
+                    self.failures,
+                    duration_as_ms(backoff)
+                );
+                AdminState::Binding(Delay::new(Instant::now() + backoff))
+            },
+        }
+    }
+}
+
+impl<F> Future for AdminServer<F>
+where
+    F: Future<Item = ()>,
+{
+    type Error = ();
+    type Item = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Dropping the server stops it from accepting new connections, and leaves the ones it
+        // already has to finish up on their own.
+        match self.signal.poll() {
+            Ok(Async::NotReady) => {},
+            _ => {
+                self.sink.update_gauge("listening", 0);
+                return Ok(Async::Ready(()));
+            },
+        }
+
+        loop {
+            let stopped = match self.state {
+                // If the timer has gone away, there's no sense in waiting on it.
+                AdminState::Binding(ref mut delay) => {
+                    if let Ok(Async::NotReady) = delay.poll() {
+                        return Ok(Async::NotReady);
+                    }
+                    false
+                },
+                AdminState::Serving(ref mut server) => {
+                    if let Ok(Async::NotReady) = server.poll() {
+                        return Ok(Async::NotReady);
+                    }
+                    true
+                },
+            };
+
+            self.state = if stopped {
+                warn!("[metrics] stats server on {} stopped accepting connections, rebinding", self.addr);
+                self.sink.update_gauge("listening", 0);
+                AdminState::Binding(Delay::new(Instant::now() + self.retry))
+            } else {
+                self.bind()
+            };
+        }
+    }
+}
+
+/// Builds the stats and admin HTTP server, which runs until `signal` fires.
+///
+/// Failing to bind `addr`, now or later on, is retried after `retry`, backing off as failures
+/// pile up.  Stats deltas are worked out from the snapshots in `history`.
+pub fn build_with_graceful_shutdown<F>(
+    addr: SocketAddr, control: Controller, history: Arc<StatsHistory>, supervisor: UnboundedSender<SupervisorCommand>,
+    retry: Duration, signal: F,
+) -> AdminServer<F>
+where
+    F: Future<Item = ()> + Send + 'static,
+{
+    AdminServer {
+        addr,
+        control,
+        history,
+        supervisor,
+        signal,
+        retry,
+        failures: 0,
+        state: AdminState::Binding(Delay::new(Instant::now())),
+        sink: get_sink().scoped("admin"),
+    }
+}
+
+/// Gets how long to wait before trying to bind again, after `failures` attempts in a row failed.
+fn get_bind_backoff(retry: Duration, failures: u32) -> Duration {
+    let max = Duration::from_millis(MAX_BIND_RETRY_MS).max(retry);
+    let factor = 1u32 << failures.saturating_sub(1).min(16);
+    (retry * factor).min(max)
+}
+
+/// What the admin routes need to serve their requests.
+#[derive(Clone)]
+struct AdminContext {
+    control: Controller,
+    history: Arc<StatsHistory>,
+    supervisor: UnboundedSender<SupervisorCommand>,
+}
+
+fn serve(
+    listener: TcpListener, control: Controller, history: Arc<StatsHistory>,
+    supervisor: UnboundedSender<SupervisorCommand>,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let ctx = AdminContext {
+        control,
+        history,
+        supervisor,
+    };
+    let routes = admin_routes!(chain_routes, ctx);
+    warp::serve(routes).serve_incoming(listener.incoming())
+}
+
+/// The root of the server gives an overview of everything, laid out by listener and pool.
+fn overview_route(ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let control = ctx.control.clone();
+    warp::get2()
+        .and(warp::path::end())
+        .and_then(move || {
+            control
+                .get_snapshot()
+                .map_err(warp::reject::custom)
+                .and_then(|snapshot| serde_json::to_value(&snapshot).map_err(warp::reject::custom))
+                .map(|snapshot| get_overview(&snapshot))
+        })
+        .map(|val| warp::reply::json(&val))
+}
+
+fn pool_overview_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get2()
+        .and(warp::path("pools"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(|pool: String| get_pool_overview(&pool))
+        .map(|val| warp::reply::json(&val))
+}
+
+fn stats_route(ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let control = ctx.control.clone();
+    warp::path("stats")
+        .and_then(move || {
+            control
+                .get_snapshot()
+                .map_err(warp::reject::custom)
+                .and_then(|snapshot| serde_json::to_value(&snapshot).map_err(warp::reject::custom))
+                .map(add_latency_stats)
+        })
+        .map(|val| warp::reply::json(&val))
+}
+
+fn key_prefixes_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get2()
+        .and(warp::path("stats"))
+        .and(warp::path("key_prefixes"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&get_key_prefixes()))
+}
+
+fn stats_delta_route(ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let history = ctx.history.clone();
+    warp::get2()
+        .and(warp::path("stats"))
+        .and(warp::path("delta"))
+        .and(warp::path::end())
+        .and(
+            warp::query::<StatsDeltaQuery>()
+                .or(warp::any().map(StatsDeltaQuery::default))
+                .unify(),
+        )
+        .and_then(move |query: StatsDeltaQuery| get_stats_delta(&history, &query))
+        .map(|val| warp::reply::json(&val))
+}
+
+fn prometheus_metrics_route(ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let control = ctx.control.clone();
+    warp::get2()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(move |accept: Option<String>| {
+            let format = prometheus::Format::from_accept(accept.as_ref().map(|s| s.as_str()));
+            control
+                .get_snapshot()
+                .map_err(warp::reject::custom)
+                .and_then(|snapshot| serde_json::to_value(&snapshot).map_err(warp::reject::custom))
+                .map(|snapshot| (prometheus::render(&snapshot, &get_sink().gauges(), format), format))
+        })
+        .map(|(body, format): (String, prometheus::Format)| {
+            warp::reply::with_header(body, "content-type", format.content_type())
+        })
+}
+
+fn listener_stats_route(ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let control = ctx.control.clone();
+    warp::get2()
+        .and(warp::path("listeners"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and_then(move |listener: String| {
+            control
+                .get_snapshot()
+                .map_err(warp::reject::custom)
+                .and_then(|snapshot| serde_json::to_value(&snapshot).map_err(warp::reject::custom))
+                .map(|snapshot| filter_listener_stats(add_latency_stats(snapshot), &listener))
+        })
+        .map(|val| warp::reply::json(&val))
+}
+
+fn listener_command_route(ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let supervisor = ctx.supervisor.clone();
+    warp::post2()
+        .and(warp::path("listeners"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(move |listener: String, action: String| send_listener_command(supervisor.clone(), listener, &action))
+        .map(|val| warp::reply::json(&val))
+}
+
+fn reload_status_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get2()
+        .and(warp::path("reload"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&reload::get_status()))
+}
+
+fn reload_route(ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let supervisor = ctx.supervisor.clone();
+    warp::post2()
+        .and(warp::path("reload"))
+        .and(warp::path::end())
+        .and(
+            warp::query::<ReloadQuery>()
+                .or(warp::any().map(ReloadQuery::default))
+                .unify(),
+        )
+        .and_then(move |query: ReloadQuery| request_reload(supervisor.clone(), &query))
+}
+
+fn list_clients_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get2()
+        .and(warp::path("listeners"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("clients"))
+        .and(warp::path::end())
+        .and(
+            warp::query::<ListClientsQuery>()
+                .or(warp::any().map(ListClientsQuery::default))
+                .unify(),
+        )
+        .and_then(|listener: String, query: ListClientsQuery| list_clients(listener, &query))
+        .map(|val| warp::reply::json(&val))
+}
+
+fn kill_clients_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post2()
+        .and(warp::path("listeners"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("clients"))
+        .and(warp::path("kill"))
+        .and(warp::path::end())
+        .and(warp::query::<KillClientsQuery>())
+        .and_then(|listener: String, query: KillClientsQuery| kill_clients(&listener, &query))
+        .map(|val| warp::reply::json(&val))
+}
+
+fn backend_weight_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post2()
+        .and(warp::path("pools"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path("backends"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("weight"))
+        .and(warp::path::end())
+        .and(warp::query::<BackendWeightQuery>())
+        .and_then(|listener: String, pool: String, backend: String, query: BackendWeightQuery| {
+            set_backend_weight(&listener, &pool, backend, &query)
+        })
+        .map(|val| warp::reply::json(&val))
+}
+
+fn drain_backend_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post2()
+        .and(warp::path("pools"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path("backends"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("drain"))
+        .and(warp::path::end())
+        .and(
+            warp::query::<DrainBackendQuery>()
+                .or(warp::any().map(DrainBackendQuery::default))
+                .unify(),
+        )
+        .and_then(|listener: String, pool: String, backend: String, query: DrainBackendQuery| {
+            start_backend_drain(&listener, &pool, &backend, &query)
+        })
+        .map(|val| warp::reply::json(&val))
+}
+
+fn restore_backend_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post2()
+        .and(warp::path("pools"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path("backends"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("restore"))
+        .and(warp::path::end())
+        .and_then(|listener: String, pool: String, backend: String| {
+            restore_backend_weight(&listener, &pool, backend)
+        })
+        .map(|val| warp::reply::json(&val))
+}
+
+fn drain_status_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get2()
+        .and(warp::path("pools"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path("backends"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("drain"))
+        .and(warp::path::end())
+        .and_then(|listener: String, pool: String, backend: String| get_backend_drain(&listener, &pool, &backend))
+        .map(|val| warp::reply::json(&val))
+}
+
+fn events_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get2()
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(
+            warp::query::<EventsQuery>()
+                .or(warp::any().map(EventsQuery::default))
+                .unify(),
+        )
+        .map(|query: EventsQuery| {
+            warp::reply::json(&EventsResponse {
+                events: get_recent_events(query.since.unwrap_or(0)),
+            })
+        })
+}
+
+fn capabilities_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get2()
+        .and(warp::path("capabilities"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&get_capabilities()))
+}
+
+fn config_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get2()
+        .and(warp::path("config"))
+        .and(warp::path::end())
+        .and_then(get_config)
+        .map(|val| warp::reply::json(&val))
+}
+
+fn health_route(_ctx: &AdminContext) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get2().and(warp::path("health")).and(warp::path::end()).map(|| {
+        let health = get_health();
+        let status = if health.healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        warp::reply::with_status(warp::reply::json(&health), status)
+    })
+}
+
+/// Gets the most common key prefixes seen by each listener that samples keys.
+fn get_key_prefixes() -> HashMap<String, KeySample> {
+    get_key_samplers()
+        .into_iter()
+        .map(|(listener, sampler)| (listener, sampler.get_sample()))
+        .collect()
+}
+
+/// Gets an overview of every pool with the given name.
+///
+/// Pools are named within their listener, so there's an overview for each listener with a pool by
+/// that name, keyed by the listener.
+fn get_pool_overview(pool: &str) -> Result<BTreeMap<String, PoolOverview>, Rejection> {
+    let overviews = get_pool_overviews(pool);
+    if overviews.is_empty() {
+        return Err(reject::not_found());
+    }
+
+    Ok(overviews)
+}
+
+/// Reports whether every long-lived task is still making progress.
+fn get_health() -> HealthResponse {
+    let stalled = get_task_registry().stalled();
+    HealthResponse {
+        healthy: stalled.is_empty(),
+        stalled_tasks: stalled.len(),
+        stalled,
+    }
+}
+
+/// Gets the configuration that was last applied.
+///
+/// Secrets are redacted whenever a configuration is serialized, so they never make it out.
+fn get_config() -> Result<AppliedConfiguration, Rejection> { get_applied().ok_or_else(reject::not_found) }
+
+fn list_clients(listener: String, query: &ListClientsQuery) -> Result<ListClientsResponse, Rejection> {
+    let registry = find_client_registry(&listener).ok_or_else(reject::not_found)?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_CLIENT_LIST_LIMIT)
+        .min(MAX_CLIENT_LIST_LIMIT);
+
+    let (total, clients) = registry.list(query.after, limit);
+    Ok(ListClientsResponse {
+        listener,
+        total,
+        clients,
+    })
+}
+
+fn kill_clients(listener: &str, query: &KillClientsQuery) -> Result<KillClientsResponse, Rejection> {
+    let registry = find_client_registry(listener).ok_or_else(reject::not_found)?;
+
+    // Clients can be picked out individually, by the ID they're listed with, or all at once by
+    // the address they're connecting from, or the name they've given themselves.
+    if let Some(id) = query.id {
+        let killed = if registry.kill_by_id(id) { 1 } else { 0 };
+        info!("[admin] killed {} client(s) with id {} on listener '{}'", killed, id, listener);
+
+        return Ok(KillClientsResponse { killed });
+    }
+
+    if let Some(name) = query.name.as_ref() {
+        let killed = registry.kill_by_name(name);
+        info!("[admin] killed {} client(s) named '{}' on listener '{}'", killed, name, listener);
+
+        return Ok(KillClientsResponse { killed });
+    }
+
+    let addr = query
+        .addr
+        .as_ref()
+        .ok_or_else(|| reject::custom("client address, id or name required"))?
+        .parse::<SocketAddr>()
+        .map_err(|_| reject::custom("invalid client address"))?;
+
+    let killed = registry.kill_by_addr(&addr);
+    info!("[admin] killed {} client(s) from {} on listener '{}'", killed, addr, listener);
+
+    Ok(KillClientsResponse { killed })
+}
+
+fn set_backend_weight(
+    listener: &str, pool: &str, backend: String, query: &BackendWeightQuery,
+) -> Result<BackendWeightResponse, Rejection> {
+    let weights = find_backend_weights(listener, pool).ok_or_else(reject::not_found)?;
+    let addr = backend
+        .parse::<SocketAddr>()
+        .map_err(|_| reject::custom("invalid backend address"))?;
+
+    weights
+        .set_by_addr(&addr, query.weight)
+        .map_err(|e| reject::custom(e.to_string()))?;
+    info!(
+        "[admin] set weight of backend {} in pool '{}' on listener '{}' to {}",
+        addr, pool, listener, query.weight
+    );
+
+    Ok(BackendWeightResponse {
+        backend,
+        weight: query.weight,
+    })
+}
+
+/// Drains a backend from a pool, or reports on a drain that's already underway.
+fn start_backend_drain(
+    listener: &str, pool: &str, backend: &str, query: &DrainBackendQuery,
+) -> Result<DrainStatus, Rejection> {
+    let addr = backend
+        .parse::<SocketAddr>()
+        .map_err(|_| reject::custom("invalid backend address"))?;
+    let quiet = Duration::from_millis(query.quiet_ms.unwrap_or(DEFAULT_QUIET_MS));
+
+    drain_backend(listener, pool, addr, quiet).ok_or_else(reject::not_found)
+}
+
+/// Puts a backend back into its pool's distribution, with the weight it was configured with.
+fn restore_backend_weight(listener: &str, pool: &str, backend: String) -> Result<BackendWeightResponse, Rejection> {
+    let addr = backend
+        .parse::<SocketAddr>()
+        .map_err(|_| reject::custom("invalid backend address"))?;
+
+    let weight = restore_backend(listener, pool, addr).ok_or_else(reject::not_found)?;
+    Ok(BackendWeightResponse { backend, weight })
+}
+
+fn get_backend_drain(listener: &str, pool: &str, backend: &str) -> Result<DrainStatus, Rejection> {
+    let addr = backend
+        .parse::<SocketAddr>()
+        .map_err(|_| reject::custom("invalid backend address"))?;
+
+    get_drain_status(listener, pool, addr).ok_or_else(reject::not_found)
+}
+
+fn send_listener_command(
+    mut supervisor: UnboundedSender<SupervisorCommand>, listener: String, action: &str,
+) -> Result<ListenerCommandResponse, Rejection> {
+    let (command, action, token) = match action {
+        "reload" if lifecycle::is_shutting_down() => return Err(reject::custom("shutting down")),
+        "reload" => {
+            let token = reload::request();
+            (SupervisorCommand::ReloadListener(listener.clone(), token), "reload", Some(token))
+        },
+        "drain" => (SupervisorCommand::DrainListener(listener.clone()), "drain", None),
+        _ => return Err(reject::not_found()),
+    };
+
+    supervisor
+        .try_send(command)
+        .map_err(|_| reject::custom("supervisor is not running"))?;
+    info!("[admin] requested {} of listener '{}'", action, listener);
+
+    Ok(ListenerCommandResponse {
+        listener,
+        action,
+        token,
+    })
+}
+
+/// Reloads every listener, optionally waiting for the reload to complete.
+///
+/// Without a wait, the reload is accepted and left to complete in the background.  With one, the
+/// response says whether it completed in time: a gateway timeout means it's still going.
+fn request_reload(
+    mut supervisor: UnboundedSender<SupervisorCommand>, query: &ReloadQuery,
+) -> impl Future<Item = impl warp::Reply, Error = Rejection> {
+    let wait = match query.wait {
+        Some(ref raw) => {
+            match parse_wait(raw) {
+                Some(wait) => Some(wait),
+                None => return Either::A(err(reject::custom("invalid wait"))),
+            }
+        },
+        None => None,
+    };
+
+    // Once we're shutting down, there's nothing left to reload.
+    if lifecycle::is_shutting_down() {
+        return Either::A(err(reject::custom("shutting down")));
+    }
+
+    let token = reload::request();
+    let preserve_drains = query.preserve_drains.unwrap_or(false);
+    if supervisor.try_send(SupervisorCommand::Reload(token, preserve_drains)).is_err() {
+        return Either::A(err(reject::custom("supervisor is not running")));
+    }
+    info!("[admin] requested reload {}", token);
+
+    let waiting = reload::wait(token, wait.unwrap_or_else(|| Duration::from_millis(0))).then(move |result| {
+        let outcome = result.unwrap_or(ReloadOutcome::Pending);
+        let status = match (outcome, wait) {
+            (ReloadOutcome::Completed, _) => StatusCode::OK,
+            (ReloadOutcome::Failed, _) => StatusCode::INTERNAL_SERVER_ERROR,
+            (ReloadOutcome::Pending, Some(_)) => StatusCode::GATEWAY_TIMEOUT,
+            (ReloadOutcome::Pending, None) => StatusCode::ACCEPTED,
+        };
+        let response = ReloadResponse {
+            token,
+            completed: outcome == ReloadOutcome::Completed,
+            failed: outcome == ReloadOutcome::Failed,
+        };
+        Ok(warp::reply::with_status(warp::reply::json(&response), status))
+    });
+    Either::B(waiting)
+}
+
+/// Works out how metrics have changed over the window asked for, from the stats history.
+fn get_stats_delta(history: &StatsHistory, query: &StatsDeltaQuery) -> Result<StatsDelta, Rejection> {
+    let window = match query.window {
+        Some(ref raw) => parse_duration(raw).ok_or_else(|| reject::custom("invalid window"))?,
+        None => Duration::from_millis(DEFAULT_DELTA_WINDOW_MS),
+    };
+
+    history
+        .delta(window, Instant::now(), &get_sink().gauges())
+        .map_err(|e| reject::custom(e.to_string()))
+}
+
+/// Parses how long to wait for a reload, capped at five minutes.
+fn parse_wait(raw: &str) -> Option<Duration> {
+    parse_duration(raw).map(|wait| wait.min(Duration::from_millis(MAX_RELOAD_WAIT_MS)))
+}
+
+/// Parses a duration given in seconds or, with an `ms` suffix, milliseconds.
+///
+/// A trailing `s` is allowed on seconds.
+fn parse_duration(raw: &str) -> Option<Duration> {
+    let ms = if raw.ends_with("ms") {
+        raw[..raw.len() - 2].parse::<u64>().ok()?
+    } else {
+        raw.trim_right_matches('s').parse::<u64>().ok()?.checked_mul(1000)?
+    };
+    Some(Duration::from_millis(ms))
+}
+
+/// Adds a summary of every listener's latency histograms to a metrics snapshot.
+///
+/// Each histogram is summed up under its listener's client metrics, such as
+/// `listeners.fixed.client.first_byte_latency_p99_us`.  Percentiles are estimated from the buckets
+/// they fall in, while the smallest and largest latencies are exact, and all of them are left out
+/// until there's something to report.
+fn add_latency_stats(snapshot: Value) -> Value {
+    let mut metrics = match snapshot {
+        Value::Object(metrics) => metrics,
+        other => return other,
+    };
+
+    for (listener, name, histogram) in get_latencies() {
+        let prefix = format!("listeners.{}.client.{}_latency", listener, name);
+        let count = histogram.cumulative().last().map_or(0, |&(_, count)| count);
+        metrics.insert(format!("{}_count", prefix), count.into());
+        metrics.insert(format!("{}_sum_us", prefix), histogram.sum_us().into());
+
+        for &(suffix, quantile) in REPORTED_QUANTILES {
+            if let Some(value) = histogram.quantile_us(quantile) {
+                metrics.insert(format!("{}_{}", prefix, suffix), value.into());
+            }
+        }
+        if let Some(min_us) = histogram.min_us() {
+            metrics.insert(format!("{}_min_us", prefix), min_us.into());
+        }
+        if let Some(max_us) = histogram.max_us() {
+            metrics.insert(format!("{}_max_us", prefix), max_us.into());
+        }
+    }
+
+    Value::Object(metrics)
+}
+
+/// Narrows a metrics snapshot down to the metrics belonging to the given listener.
+///
+/// Listener metrics are all scoped under `listeners.<name>.`, so the prefix is stripped from the
+/// metrics that are kept, giving every listener the same stats layout.
+fn filter_listener_stats(snapshot: Value, listener: &str) -> Value {
+    let prefix = format!("listeners.{}.", listener);
+    match snapshot {
+        Value::Object(metrics) => {
+            Value::Object(
+                metrics
+                    .into_iter()
+                    .filter_map(|(key, value)| {
+                        if key.starts_with(&prefix) {
+                            Some((key[prefix.len()..].to_owned(), value))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect(),
+            )
+        },
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_latency_stats, filter_listener_stats, get_bind_backoff, get_config, parse_wait, ADMIN_FEATURES,
+        MAX_RELOAD_WAIT_MS,
+    };
+    use conf::{set_applied, Configuration, ListenerConfiguration, PoolConfiguration, PoolOptions, REDACTED};
+    use metrics::register_latencies;
+    use serde_json::json;
+    use std::{
+        collections::{HashMap, HashSet},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_admin_features() {
+        // Every route's operations are reported, and no two routes claim the same one.
+        let features = ADMIN_FEATURES.iter().collect::<HashSet<_>>();
+        assert_eq!(features.len(), ADMIN_FEATURES.len());
+        for feature in &["overview", "stats", "reload", "reload_listener", "drain_backend_status", "reload_status"] {
+            assert!(features.contains(feature), "missing {}", feature);
+        }
+    }
+
+    #[test]
+    fn test_bind_backoff() {
+        let retry = Duration::from_millis(500);
+        assert_eq!(get_bind_backoff(retry, 1), Duration::from_millis(500));
+        assert_eq!(get_bind_backoff(retry, 2), Duration::from_millis(1000));
+        assert_eq!(get_bind_backoff(retry, 4), Duration::from_millis(4000));
+        assert_eq!(get_bind_backoff(retry, 7), Duration::from_millis(30_000));
+        assert_eq!(get_bind_backoff(retry, 1000), Duration::from_millis(30_000));
+
+        // A retry interval longer than our usual cap is never shortened.
+        let retry = Duration::from_secs(60);
+        assert_eq!(get_bind_backoff(retry, 1), Duration::from_secs(60));
+        assert_eq!(get_bind_backoff(retry, 5), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_filter_listener_stats() {
+        let snapshot = json!({
+            "listeners.alpha.clients_connected": 3,
+            "listeners.alpha.pool.default.hits": 10,
+            "listeners.alphabet.clients_connected": 7,
+            "listeners.beta.clients_connected": 1,
+            "supervisor.configuration_loads": 2,
+        });
+
+        let alpha = filter_listener_stats(snapshot.clone(), "alpha");
+        assert_eq!(alpha, json!({ "clients_connected": 3, "pool.default.hits": 10 }));
+
+        let gamma = filter_listener_stats(snapshot, "gamma");
+        assert_eq!(gamma, json!({}));
+    }
+
+    #[test]
+    fn test_add_latency_stats() {
+        let histogram = register_latencies("summarized", "first_byte", vec![1_000, 10_000]);
+        let idle = register_latencies("summarized", "first_response", vec![1_000, 10_000]);
+        histogram.record(Duration::from_micros(500));
+        histogram.record(Duration::from_millis(5));
+
+        let snapshot = add_latency_stats(json!({ "listeners.summarized.clients_connected": 1 }));
+        let summarized = filter_listener_stats(snapshot, "summarized");
+        assert_eq!(summarized["clients_connected"], 1);
+        assert_eq!(summarized["client.first_byte_latency_count"], 2);
+        assert_eq!(summarized["client.first_byte_latency_sum_us"], 5_500);
+        assert_eq!(summarized["client.first_byte_latency_p50_us"], 1_000);
+        assert_eq!(summarized["client.first_byte_latency_p95_us"], 10_000);
+        assert_eq!(summarized["client.first_byte_latency_p99_us"], 10_000);
+        assert_eq!(summarized["client.first_byte_latency_p999_us"], 10_000);
+        assert_eq!(summarized["client.first_byte_latency_min_us"], 500);
+        assert_eq!(summarized["client.first_byte_latency_max_us"], 5_000);
+
+        // Nothing's been recorded yet, so there's nothing to estimate percentiles from.
+        assert_eq!(idle.sum_us(), 0);
+        assert_eq!(summarized["client.first_response_latency_count"], 0);
+        assert!(summarized.get("client.first_response_latency_p50_us").is_none());
+        assert!(summarized.get("client.first_response_latency_max_us").is_none());
+    }
+
+    #[test]
+    fn test_parse_wait() {
+        assert_eq!(parse_wait("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_wait("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_wait("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_wait("0"), Some(Duration::from_millis(0)));
+        assert_eq!(parse_wait("1000000s"), Some(Duration::from_millis(MAX_RELOAD_WAIT_MS)));
+        assert_eq!(parse_wait("soon"), None);
+        assert_eq!(parse_wait("-1s"), None);
+        assert_eq!(parse_wait(""), None);
+    }
+
+    #[test]
+    fn test_config_redacts_secrets() {
+        let mut options = HashMap::new();
+        options.insert("redis_auth".to_owned(), "hunter2".to_owned());
+
+        let mut listener = ListenerConfiguration::default();
+        listener.pools.insert(
+            "default".to_owned(),
+            PoolConfiguration {
+                options: PoolOptions::from_map(options).unwrap(),
+                ..Default::default()
+            },
+        );
+
+        let mut configuration = Configuration::default();
+        configuration.listeners.insert("secretive".to_owned(), listener);
+        set_applied(1, &configuration, None);
+
+        let dump = serde_json::to_value(&get_config().ok().unwrap()).unwrap();
+        assert!(!dump.to_string().contains("hunter2"));
+        assert_eq!(
+            dump["configuration"]["listeners"]["secretive"]["pools"]["default"]["options"]["redis_auth"],
+            json!(REDACTED)
+        );
+    }
 }
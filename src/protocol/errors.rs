@@ -20,33 +20,155 @@
 use std::{error, fmt, io};
 use tokio::sync::oneshot;
 
+/// Number of bytes captured from each end of a malformed buffer.
+const CONTEXT_LEN: usize = 32;
+
+/// Details about where, and how, a frame failed to parse.
+#[derive(Debug)]
+pub struct ParseError {
+    offset: usize,
+    expected: &'static str,
+    context: Option<ParseContext>,
+}
+
+/// A bounded copy of the buffer a parse error was found in.
+///
+/// Only the first and last `CONTEXT_LEN` bytes are kept, so a malformed multi-megabyte frame costs
+/// no more to report than a small one.
+#[derive(Debug)]
+struct ParseContext {
+    len: usize,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl ParseError {
+    pub fn new(offset: usize, expected: &'static str) -> ParseError {
+        ParseError {
+            offset,
+            expected,
+            context: None,
+        }
+    }
+
+    /// Byte offset, from the start of the frame, where parsing failed.
+    pub fn offset(&self) -> usize { self.offset }
+
+    /// What the parser expected to find at the offset.
+    pub fn expected(&self) -> &'static str { self.expected }
+
+    /// A description of the error that's safe to hand back to a client.
+    ///
+    /// This leaves out the captured buffer, as it may contain data from other requests.
+    pub fn client_detail(&self) -> String { format!("expected {} at offset {}", self.expected, self.offset) }
+
+    fn shifted(mut self, by: usize) -> ParseError {
+        self.offset += by;
+        self
+    }
+
+    fn with_context(mut self, buf: &[u8]) -> ParseError {
+        let (head, tail) = if buf.len() > CONTEXT_LEN * 2 {
+            (&buf[..CONTEXT_LEN], &buf[buf.len() - CONTEXT_LEN..])
+        } else {
+            (buf, &buf[buf.len()..])
+        };
+
+        self.context = Some(ParseContext {
+            len: buf.len(),
+            head: head.to_vec(),
+            tail: tail.to_vec(),
+        });
+        self
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {} at offset {}", self.expected, self.offset)?;
+        if let Some(ref context) = self.context {
+            write!(f, " ({} bytes: {}", context.len, to_hex(&context.head))?;
+            if !context.tail.is_empty() {
+                write!(f, " .. {}", to_hex(&context.tail))?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+fn to_hex(buf: &[u8]) -> String { buf.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ") }
+
 #[derive(Debug)]
 pub enum ProtocolError {
     IoError(io::Error),
-    InvalidProtocol,
+    InvalidProtocol(ParseError),
+    LimitExceeded(&'static str),
     BackendClosedPrematurely,
+    BackendOutOfSync,
+    HandshakeFailed(String),
 }
 
 impl ProtocolError {
+    /// Moves the offset of a parse error along by `by` bytes.
+    ///
+    /// Parsers for nested values only see the part of the frame they're parsing, so their parent
+    /// uses this to make the offset relative to the start of the whole frame.
+    pub fn shifted(self, by: usize) -> ProtocolError {
+        match self {
+            ProtocolError::InvalidProtocol(e) => ProtocolError::InvalidProtocol(e.shifted(by)),
+            e => e,
+        }
+    }
+
+    /// Captures context for a parse error from the buffer holding the frame.
+    pub fn with_context(self, buf: &[u8]) -> ProtocolError {
+        match self {
+            ProtocolError::InvalidProtocol(e) => ProtocolError::InvalidProtocol(e.with_context(buf)),
+            e => e,
+        }
+    }
+
+    /// A short name for the kind of error this is, for grouping errors of the same kind together.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProtocolError::IoError(_) => "io",
+            ProtocolError::InvalidProtocol(_) => "invalid_protocol",
+            ProtocolError::LimitExceeded(_) => "limit_exceeded",
+            ProtocolError::BackendClosedPrematurely => "backend_closed",
+            ProtocolError::BackendOutOfSync => "backend_out_of_sync",
+            ProtocolError::HandshakeFailed(_) => "handshake_failed",
+        }
+    }
+
     pub fn client_closed(&self) -> bool {
         match self {
-            ProtocolError::IoError(e) => {
-                match e.kind() {
-                    io::ErrorKind::ConnectionReset => true,
-                    _ => false,
-                }
-            },
+            ProtocolError::IoError(e) => is_disconnect(e),
             _ => false,
         }
     }
 }
 
+/// Whether or not the given error just means that the other end of the connection went away.
+///
+/// Writing to a peer that has already closed its end fails with a broken pipe, or with a reset if
+/// it has told us to stop sending, and neither is anything more than the peer hanging up on us.
+pub fn is_disconnect(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe => true,
+        _ => false,
+    }
+}
+
 impl error::Error for ProtocolError {
     fn description(&self) -> &str {
         match *self {
             ProtocolError::IoError(ref e) => e.description(),
-            ProtocolError::InvalidProtocol => "invalid protocol",
+            ProtocolError::InvalidProtocol(_) => "invalid protocol",
+            ProtocolError::LimitExceeded(_) => "protocol limit exceeded",
             ProtocolError::BackendClosedPrematurely => "backend closed prematurely",
+            ProtocolError::BackendOutOfSync => "backend sent unexpected responses",
+            ProtocolError::HandshakeFailed(_) => "backend rejected connection handshake",
         }
     }
 
@@ -57,8 +179,11 @@ impl fmt::Display for ProtocolError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ProtocolError::IoError(ref ie) => fmt::Display::fmt(ie, f),
-            ProtocolError::InvalidProtocol => write!(f, "invalid protocol"),
+            ProtocolError::InvalidProtocol(ref pe) => write!(f, "invalid protocol: {}", pe),
+            ProtocolError::LimitExceeded(detail) => write!(f, "protocol limit exceeded: {}", detail),
             ProtocolError::BackendClosedPrematurely => write!(f, "backend closed prematurely"),
+            ProtocolError::BackendOutOfSync => write!(f, "backend sent unexpected responses"),
+            ProtocolError::HandshakeFailed(ref reply) => write!(f, "backend rejected connection handshake: {}", reply),
         }
     }
 }
@@ -0,0 +1,1132 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::responses::ResponseSizeTracker;
+use btoi::btoi;
+use bytes::BytesMut;
+use common::{EnqueuedRequests, Message};
+use futures::prelude::*;
+use protocol::errors::{ParseError, ProtocolError};
+use std::{borrow::Cow, sync::Arc};
+use tokio::io::{write_all, AsyncRead, AsyncWrite, Error, ErrorKind};
+use util::Sizable;
+
+const MAX_OUTSTANDING_WBUF: usize = 8192;
+
+// Memcached hangs up on clients whose command lines run on much longer than this, and so do we, as
+// there's no telling where the next command starts if this one never ends.
+const MAX_LINE_LEN: usize = 2048;
+
+// The longest key memcached will store.
+const MAX_KEY_LEN: usize = 250;
+
+// The largest value we'll buffer for a storage command, which is memcached's own default limit.
+const MAX_VALUE_LEN: usize = 1024 * 1024;
+
+const MEMCACHED_CRLF: &[u8] = b"\r\n";
+const MEMCACHED_END: &[u8] = b"END\r\n";
+const MEMCACHED_ERROR: &[u8] = b"ERROR\r\n";
+const MEMCACHED_VALUE: &[u8] = b"VALUE ";
+const MEMCACHED_NOREPLY: &[u8] = b"noreply";
+const MEMCACHED_CLIENT_ERROR: &str = "CLIENT_ERROR ";
+const MEMCACHED_SERVER_ERROR: &str = "SERVER_ERROR ";
+const MEMCACHED_ERROR_PREFIXES: &[&[u8]] = &[b"ERROR", b"CLIENT_ERROR", b"SERVER_ERROR"];
+
+const CLIENT_BAD_FORMAT: &str = "bad command line format";
+const CLIENT_BAD_DATA: &str = "bad data chunk";
+const CLIENT_BAD_DELTA: &str = "invalid numeric delta argument";
+const CLIENT_LINE_TOO_LONG: &str = "line too long";
+const CLIENT_NOREPLY: &str = "noreply is not supported";
+const SERVER_TOO_LARGE: &str = "object too large for cache";
+const BACKEND_CLOSED: &str = "backend closed prematurely";
+const RESPONSE_TOO_LARGE: &str = "response from backend is over max_response_bytes";
+
+/// A command that we know how to route.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemcachedCommand {
+    Get,
+    Gets,
+    Set,
+    Add,
+    Replace,
+    Delete,
+    Incr,
+    Decr,
+    Touch,
+}
+
+// Every command we know, by name.  A command's position here is its identifier.
+const COMMANDS: &[(&str, MemcachedCommand)] = &[
+    ("get", MemcachedCommand::Get),
+    ("gets", MemcachedCommand::Gets),
+    ("set", MemcachedCommand::Set),
+    ("add", MemcachedCommand::Add),
+    ("replace", MemcachedCommand::Replace),
+    ("delete", MemcachedCommand::Delete),
+    ("incr", MemcachedCommand::Incr),
+    ("decr", MemcachedCommand::Decr),
+    ("touch", MemcachedCommand::Touch),
+];
+
+impl MemcachedCommand {
+    /// Looks up a command by the name clients send it with.
+    pub fn from_name(name: &[u8]) -> Option<MemcachedCommand> {
+        COMMANDS
+            .iter()
+            .find(|(entry, _)| entry.as_bytes() == name)
+            .map(|(_, cmd)| *cmd)
+    }
+
+    /// Gets the identifier of this command.
+    pub fn id(self) -> usize {
+        COMMANDS
+            .iter()
+            .position(|(_, cmd)| *cmd == self)
+            .expect("every command is listed")
+    }
+
+    /// Gets the name of this command, as clients send it.
+    pub fn name(self) -> &'static str { COMMANDS[self.id()].0 }
+
+    /// Whether or not this command looks keys up, and so can take more than one of them.
+    pub fn is_retrieval(self) -> bool {
+        match self {
+            MemcachedCommand::Get | MemcachedCommand::Gets => true,
+            _ => false,
+        }
+    }
+
+    /// Whether or not this command stores a value, which follows the command line as a data block.
+    pub fn is_storage(self) -> bool {
+        match self {
+            MemcachedCommand::Set | MemcachedCommand::Add | MemcachedCommand::Replace => true,
+            _ => false,
+        }
+    }
+}
+
+/// Gets the identifier of the command with the given name, if it's a command we know.
+pub fn get_command_index(name: &[u8]) -> Option<usize> { MemcachedCommand::from_name(name).map(|cmd| cmd.id()) }
+
+/// Gets the number of commands we know.
+pub fn get_command_count() -> usize { COMMANDS.len() }
+
+/// A client/server message for memcached's text protocol.
+///
+/// `Request` holds a command from a client in full, including the data block of storage commands,
+/// along with the command it runs and where each of its keys sit in the buffer, so that it can be
+/// routed and fragmented without being parsed again.
+///
+/// `Response` holds a response from a backend in full, which is sent to the client as-is.
+///
+/// `Error` is an error that we generated ourselves, and is also sent to the client as-is.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MemcachedMessage {
+    Request(MemcachedCommand, BytesMut, Vec<(usize, usize)>),
+    Response(BytesMut),
+    Error(BytesMut),
+}
+
+impl MemcachedMessage {
+    /// Builds a request for the given command from the given arguments.
+    ///
+    /// The first argument is the key, or, for retrievals, every argument is a key.  Storage
+    /// commands can't be built this way, as they need a data block.
+    pub fn from_command(cmd: MemcachedCommand, args: &[&[u8]]) -> MemcachedMessage {
+        let mut buf = BytesMut::new();
+        let mut keys = Vec::new();
+
+        buf.extend_from_slice(cmd.name().as_bytes());
+        for (i, arg) in args.iter().enumerate() {
+            buf.extend_from_slice(b" ");
+            if i == 0 || cmd.is_retrieval() {
+                keys.push((buf.len(), buf.len() + arg.len()));
+            }
+            buf.extend_from_slice(arg);
+        }
+        buf.extend_from_slice(MEMCACHED_CRLF);
+
+        MemcachedMessage::Request(cmd, buf, keys)
+    }
+
+    /// Builds an error blaming the client for the given reason.
+    pub fn from_client_error(detail: &str) -> MemcachedMessage { new_error(MEMCACHED_CLIENT_ERROR, detail) }
+
+    /// Builds an error blaming the server, which is us, for the given reason.
+    pub fn from_server_error(detail: &str) -> MemcachedMessage { new_error(MEMCACHED_SERVER_ERROR, detail) }
+
+    /// Builds the error memcached sends for a command it doesn't know.
+    pub fn unknown_command() -> MemcachedMessage { MemcachedMessage::Error(BytesMut::from(MEMCACHED_ERROR)) }
+
+    /// Gets the command this request runs.
+    pub fn get_command(&self) -> Option<MemcachedCommand> {
+        match self {
+            MemcachedMessage::Request(cmd, _, _) => Some(*cmd),
+            _ => None,
+        }
+    }
+
+    /// Gets every key this request is for.
+    pub fn get_keys(&self) -> Vec<&[u8]> {
+        match self {
+            MemcachedMessage::Request(_, buf, keys) => keys.iter().map(|(start, end)| &buf[*start..*end]).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Gets the arguments of this request, not including the command itself or any data block.
+    pub fn get_arguments(&self) -> Vec<&[u8]> {
+        match self {
+            MemcachedMessage::Request(_, buf, _) => {
+                let line_end = find_line(buf).unwrap_or_else(|| buf.len());
+                let line = trim_line(&buf[..line_end]);
+                tokenize(line)
+                    .into_iter()
+                    .skip(1)
+                    .map(|(start, end)| &line[start..end])
+                    .collect()
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// Gets where the value of this storage request sits in its buffer.
+    pub fn get_value_range(&self) -> Option<(usize, usize)> {
+        match self {
+            MemcachedMessage::Request(cmd, buf, _) if cmd.is_storage() => {
+                find_line(buf).map(|line_end| (line_end, buf.len() - MEMCACHED_CRLF.len()))
+            },
+            _ => None,
+        }
+    }
+
+    /// Gets the values in this response to a retrieval, without the `END` that follows them.
+    ///
+    /// Returns `None` if this isn't a response to a retrieval, such as when it's an error.
+    pub fn get_values(&self) -> Option<&[u8]> {
+        match self {
+            MemcachedMessage::Response(buf) if buf.ends_with(MEMCACHED_END) && !is_error(buf) => {
+                Some(&buf[..buf.len() - MEMCACHED_END.len()])
+            },
+            _ => None,
+        }
+    }
+
+    /// Counts the values in this response to a retrieval.
+    ///
+    /// Returns `None` if this isn't a response to a retrieval, such as when it's an error.
+    pub fn count_values(&self) -> Option<usize> {
+        let values = self.get_values()?;
+        scan_response(values).ok().map(|scan| scan.values)
+    }
+
+    /// Builds a response to a retrieval out of the given values.
+    pub fn from_values(values: &[u8]) -> MemcachedMessage {
+        let mut buf = BytesMut::with_capacity(values.len() + MEMCACHED_END.len());
+        buf.extend_from_slice(values);
+        buf.extend_from_slice(MEMCACHED_END);
+        MemcachedMessage::Response(buf)
+    }
+
+    /// Rewrites every key in this message with the given function.
+    ///
+    /// For requests, these are the keys the request is for.  For responses, these are the keys of
+    /// the values sent back for a retrieval.  Errors don't have keys, and are left as-is.
+    pub fn map_keys<F>(self, mut f: F) -> MemcachedMessage
+    where
+        F: for<'a> FnMut(&'a [u8]) -> Cow<'a, [u8]>,
+    {
+        match self {
+            MemcachedMessage::Request(cmd, buf, keys) => {
+                let mut new_buf = BytesMut::with_capacity(buf.len());
+                let mut new_keys = Vec::with_capacity(keys.len());
+                let mut pos = 0;
+                for (start, end) in keys {
+                    new_buf.extend_from_slice(&buf[pos..start]);
+                    let key = f(&buf[start..end]);
+                    new_keys.push((new_buf.len(), new_buf.len() + key.len()));
+                    new_buf.extend_from_slice(&key);
+                    pos = end;
+                }
+                new_buf.extend_from_slice(&buf[pos..]);
+
+                MemcachedMessage::Request(cmd, new_buf, new_keys)
+            },
+            MemcachedMessage::Response(ref buf) if !is_error(buf) => MemcachedMessage::Response(map_value_keys(buf, f)),
+            msg => msg,
+        }
+    }
+
+    /// Whether or not this is an error, either one we generated or one sent back by the server.
+    pub fn is_error(&self) -> bool {
+        match self {
+            MemcachedMessage::Request(_, _, _) => false,
+            MemcachedMessage::Response(buf) => is_error(buf),
+            MemcachedMessage::Error(_) => true,
+        }
+    }
+
+    pub fn get_buf(&self) -> BytesMut {
+        match self {
+            MemcachedMessage::Request(_, buf, _) => buf.clone(),
+            MemcachedMessage::Response(buf) => buf.clone(),
+            MemcachedMessage::Error(buf) => buf.clone(),
+        }
+    }
+}
+
+fn new_error(prefix: &str, detail: &str) -> MemcachedMessage {
+    let mut buf = BytesMut::with_capacity(prefix.len() + detail.len() + MEMCACHED_CRLF.len());
+    buf.extend_from_slice(prefix.as_bytes());
+    buf.extend_from_slice(detail.as_bytes());
+    buf.extend_from_slice(MEMCACHED_CRLF);
+    MemcachedMessage::Error(buf)
+}
+
+fn is_error(buf: &[u8]) -> bool { MEMCACHED_ERROR_PREFIXES.iter().any(|prefix| buf.starts_with(prefix)) }
+
+impl Sizable for MemcachedMessage {
+    fn size(&self) -> usize {
+        match self {
+            MemcachedMessage::Request(_, ref buf, _) => buf.len(),
+            MemcachedMessage::Response(ref buf) => buf.len(),
+            MemcachedMessage::Error(ref buf) => buf.len(),
+        }
+    }
+}
+
+impl Message for MemcachedMessage {
+    fn key(&self) -> &[u8] {
+        match self {
+            // Retrievals are routed by their first key, unless they've been fragmented by key.
+            MemcachedMessage::Request(_, buf, keys) => {
+                let (start, end) = keys[0];
+                &buf[start..end]
+            },
+            _ => panic!("message should be a request!"),
+        }
+    }
+
+    fn is_inline(&self) -> bool {
+        match self {
+            MemcachedMessage::Request(_, _, _) => false,
+            _ => true,
+        }
+    }
+
+    fn into_buf(self) -> BytesMut {
+        match self {
+            MemcachedMessage::Request(_, buf, _) => buf,
+            MemcachedMessage::Response(buf) => buf,
+            MemcachedMessage::Error(buf) => buf,
+        }
+    }
+}
+
+/// A memcached-specific transport.
+///
+/// Commands are only handed over once they've arrived in full, along with their data block if
+/// they're storage commands.  Commands we don't know, or that are malformed, are answered with an
+/// error, as memcached would, and the connection carries on with whatever the client sends next.
+pub struct MemcachedTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    transport: T,
+    rbuf: BytesMut,
+    wbuf: BytesMut,
+    closed: bool,
+    discard: usize,
+}
+
+impl<T> MemcachedTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    pub fn new(transport: T) -> Self {
+        MemcachedTransport {
+            transport,
+            rbuf: BytesMut::new(),
+            wbuf: BytesMut::new(),
+            closed: false,
+            discard: 0,
+        }
+    }
+
+    fn fill_read_buf(&mut self) -> Poll<(), ProtocolError> {
+        loop {
+            self.rbuf.reserve(8192);
+
+            let n = try_ready!(self.transport.read_buf(&mut self.rbuf));
+            if n == 0 {
+                return Ok(Async::Ready(()));
+            }
+        }
+    }
+}
+
+impl<T> Stream for MemcachedTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type Error = ProtocolError;
+    type Item = MemcachedMessage;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.closed {
+            return Ok(Async::Ready(None));
+        }
+
+        let socket_closed = self.fill_read_buf()?.is_ready();
+
+        // Throw away whatever is left of a value we've already refused to store.
+        if self.discard > 0 {
+            let n = self.discard.min(self.rbuf.len());
+            let _ = self.rbuf.split_to(n);
+            self.discard -= n;
+        }
+
+        if self.discard == 0 {
+            skip_empty_lines(&mut self.rbuf);
+
+            match read_request(&mut self.rbuf) {
+                Ok(Async::Ready((msg, discard))) => {
+                    trace!("[protocol] got message from client!");
+                    self.discard = discard;
+                    return Ok(Async::Ready(Some(msg)));
+                },
+                Err(ProtocolError::LimitExceeded(detail)) => {
+                    // We can't find the end of the command, so we can't find the start of the next
+                    // one either: all we can do is tell the client why we're hanging up on it.
+                    debug!("[protocol] closing client after oversized command line");
+                    self.closed = true;
+                    return Ok(Async::Ready(Some(MemcachedMessage::from_client_error(detail))));
+                },
+                Err(e) => return Err(e),
+                Ok(Async::NotReady) => {},
+            }
+        }
+
+        if socket_closed {
+            // If the socket is closed, let's also close up shop.
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<T> Sink for MemcachedTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type SinkError = Error;
+    type SinkItem = BytesMut;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.wbuf.len() >= MAX_OUTSTANDING_WBUF {
+            self.poll_complete()?;
+
+            if self.wbuf.len() >= MAX_OUTSTANDING_WBUF {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        self.wbuf.unsplit(item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        while !self.wbuf.is_empty() {
+            let n = try_ready!(self.transport.poll_write(&self.wbuf));
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+            let _ = self.wbuf.split_to(n);
+        }
+
+        try_ready!(self.transport.poll_flush());
+
+        Ok(Async::Ready(()))
+    }
+}
+
+pub struct MemcachedMultipleMessages<T>
+where
+    T: AsyncRead,
+{
+    transport: Option<T>,
+    rbuf: BytesMut,
+    bytes_read: usize,
+    msgs: EnqueuedRequests<MemcachedMessage>,
+    responses: Arc<ResponseSizeTracker>,
+}
+
+impl<T> MemcachedMultipleMessages<T>
+where
+    T: AsyncRead,
+{
+    pub fn new(transport: T, msgs: EnqueuedRequests<MemcachedMessage>, responses: Arc<ResponseSizeTracker>) -> Self {
+        MemcachedMultipleMessages {
+            transport: Some(transport),
+            rbuf: BytesMut::new(),
+            bytes_read: 0,
+            msgs,
+            responses,
+        }
+    }
+
+    fn fill_read_buf(&mut self) -> Poll<(), ProtocolError> {
+        loop {
+            self.rbuf.reserve(16384);
+
+            let n = try_ready!(self.transport.as_mut().unwrap().read_buf(&mut self.rbuf));
+            self.bytes_read += n;
+
+            if n == 0 {
+                return Ok(Async::Ready(()));
+            }
+
+            // There's no sense in reading any more of a response that we're going to abandon.
+            if let Ok(Some(_)) = self.get_oversized_response() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+
+    /// Gets the size of the largest value the response at the front of the read buffer has
+    /// declared, if it's over the maximum response size.
+    fn get_oversized_response(&self) -> Result<Option<usize>, ProtocolError> {
+        let max_bytes = match self.responses.max_bytes() {
+            Some(max_bytes) => max_bytes,
+            None => return Ok(None),
+        };
+
+        let scan = scan_response(&self.rbuf)?;
+        if scan.largest > max_bytes {
+            Ok(Some(scan.largest))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T> Future for MemcachedMultipleMessages<T>
+where
+    T: AsyncRead,
+{
+    type Error = ProtocolError;
+    type Item = (T, usize);
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let socket_closed = self.fill_read_buf()?.is_ready();
+
+        loop {
+            // We've collected all the messages, time to return.
+            if self.msgs.is_empty() {
+                // Responses are matched to requests purely by order, so anything beyond the
+                // responses we asked for means the connection can't be trusted anymore.
+                if !self.rbuf.is_empty() {
+                    return Err(ProtocolError::BackendOutOfSync);
+                }
+
+                return Ok(Async::Ready((self.transport.take().unwrap(), self.bytes_read)));
+            }
+
+            // If a value is too large to read, the rest of it is still on its way, so the
+            // connection has to go.
+            if let Some(declared) = self.get_oversized_response().map_err(|e| e.with_context(&self.rbuf))? {
+                let mut qmsg = self.msgs.remove(0);
+                {
+                    let command = get_command_name(qmsg.request());
+                    self.responses.record_rejected(command, qmsg.key(), declared);
+                }
+
+                qmsg.fulfill(MemcachedMessage::from_server_error(RESPONSE_TOO_LARGE));
+                return Err(ProtocolError::LimitExceeded("response too large"));
+            }
+
+            let scan = scan_response(&self.rbuf).map_err(|e| e.with_context(&self.rbuf))?;
+            match scan.len {
+                Some(len) => {
+                    trace!("[protocol] got message from server! ({} bytes)", len);
+                    let buf = self.rbuf.split_to(len);
+
+                    let mut qmsg = self.msgs.remove(0);
+                    {
+                        let command = get_command_name(qmsg.request());
+                        self.responses.record(command, qmsg.key(), len);
+                    }
+                    qmsg.fulfill(MemcachedMessage::Response(buf))
+                },
+                None => {
+                    return if socket_closed {
+                        // If the socket is closed, let's also close up shop after responding to
+                        // the client with errors.
+                        let err = MemcachedMessage::from_server_error(BACKEND_CLOSED);
+                        while let Some(mut qmsg) = self.msgs.pop() {
+                            qmsg.fulfill(err.clone())
+                        }
+
+                        Err(ProtocolError::BackendClosedPrematurely)
+                    } else {
+                        Ok(Async::NotReady)
+                    };
+                },
+            }
+        }
+    }
+}
+
+fn get_command_name(msg: &MemcachedMessage) -> &'static [u8] {
+    msg.get_command().map(|cmd| cmd.name().as_bytes()).unwrap_or(&[])
+}
+
+pub fn read_messages<T>(
+    rx: T, msgs: EnqueuedRequests<MemcachedMessage>, responses: Arc<ResponseSizeTracker>,
+) -> MemcachedMultipleMessages<T>
+where
+    T: AsyncRead,
+{
+    MemcachedMultipleMessages::new(rx, msgs, responses)
+}
+
+pub fn write_messages<T>(
+    transport: T, msgs: EnqueuedRequests<MemcachedMessage>,
+) -> impl Future<Item = (T, EnqueuedRequests<MemcachedMessage>, usize), Error = ProtocolError>
+where
+    T: AsyncWrite,
+{
+    let mut buf = BytesMut::new();
+    for msg in &msgs {
+        buf.extend_from_slice(&msg.request().get_buf()[..]);
+    }
+
+    let buf_len = buf.len();
+    write_all(transport, buf)
+        .map(move |(transport, _buf)| (transport, msgs, buf_len))
+        .map_err(|e| e.into())
+}
+
+/// Why a command from a client wasn't passed along.
+#[derive(Debug, PartialEq)]
+enum Rejection {
+    /// The command isn't one we know.
+    Unknown,
+
+    /// The command is one we know, but it was malformed.
+    Malformed(&'static str),
+}
+
+impl Rejection {
+    fn into_message(self) -> MemcachedMessage {
+        match self {
+            Rejection::Unknown => MemcachedMessage::unknown_command(),
+            Rejection::Malformed(detail) => MemcachedMessage::from_client_error(detail),
+        }
+    }
+}
+
+/// A parsed command line: the command, where its keys are, and how long its data block is, if it
+/// has one.
+type CommandLine = (MemcachedCommand, Vec<(usize, usize)>, Option<usize>);
+
+/// Reads a request from the front of the buffer, once the whole of it has arrived.
+///
+/// Commands that we don't know, or that are malformed, are consumed and answered with an error,
+/// since we can still tell where the next command starts.  Storage commands with values too large
+/// to buffer are answered the same way, but as their values may still be on their way, the number
+/// of bytes yet to arrive, which need to be thrown away, is returned along with the answer.
+fn read_request(rd: &mut BytesMut) -> Poll<(MemcachedMessage, usize), ProtocolError> {
+    let line_end = match find_line(&rd[..rd.len().min(MAX_LINE_LEN)]) {
+        Some(line_end) => line_end,
+        None if rd.len() >= MAX_LINE_LEN => return Err(ProtocolError::LimitExceeded(CLIENT_LINE_TOO_LONG)),
+        None => return Ok(Async::NotReady),
+    };
+
+    let (cmd, keys, data_len) = match parse_command_line(trim_line(&rd[..line_end])) {
+        Ok(parsed) => parsed,
+        Err(rejection) => {
+            let _ = rd.split_to(line_end);
+            return Ok(Async::Ready((rejection.into_message(), 0)));
+        },
+    };
+
+    let data_len = match data_len {
+        Some(data_len) => data_len,
+        None => {
+            let buf = rd.split_to(line_end);
+            return Ok(Async::Ready((MemcachedMessage::Request(cmd, buf, keys), 0)));
+        },
+    };
+
+    // Values are followed by a CRLF of their own.
+    let block_len = data_len.saturating_add(MEMCACHED_CRLF.len());
+    if data_len > MAX_VALUE_LEN {
+        let buffered = (rd.len() - line_end).min(block_len);
+        let _ = rd.split_to(line_end + buffered);
+        let emsg = MemcachedMessage::from_server_error(SERVER_TOO_LARGE);
+        return Ok(Async::Ready((emsg, block_len - buffered)));
+    }
+
+    let total_len = line_end + block_len;
+    if rd.len() < total_len {
+        return Ok(Async::NotReady);
+    }
+
+    let buf = rd.split_to(total_len);
+    if !buf.ends_with(MEMCACHED_CRLF) {
+        let emsg = MemcachedMessage::from_client_error(CLIENT_BAD_DATA);
+        return Ok(Async::Ready((emsg, 0)));
+    }
+
+    Ok(Async::Ready((MemcachedMessage::Request(cmd, buf, keys), 0)))
+}
+
+fn parse_command_line(line: &[u8]) -> Result<CommandLine, Rejection> {
+    let tokens = tokenize(line);
+    let cmd = tokens
+        .first()
+        .and_then(|(start, end)| MemcachedCommand::from_name(&line[*start..*end]))
+        .ok_or(Rejection::Unknown)?;
+
+    let args = &tokens[1..];
+    let arg = |i: usize| args.get(i).map(|(start, end)| &line[*start..*end]).unwrap_or(&[]);
+
+    // Every request gets exactly one response from us, and that's how responses are matched up to
+    // requests, so we can't let a client ask for there to be none.
+    if !cmd.is_retrieval() && args.len() > 1 && arg(args.len() - 1) == MEMCACHED_NOREPLY {
+        return Err(Rejection::Malformed(CLIENT_NOREPLY));
+    }
+
+    let bad_format = || Rejection::Malformed(CLIENT_BAD_FORMAT);
+    let (key_count, data_len) = match cmd {
+        MemcachedCommand::Get | MemcachedCommand::Gets if !args.is_empty() => (args.len(), None),
+        MemcachedCommand::Set | MemcachedCommand::Add | MemcachedCommand::Replace if args.len() == 4 => {
+            btoi::<u32>(arg(1)).map_err(|_| bad_format())?;
+            btoi::<i64>(arg(2)).map_err(|_| bad_format())?;
+            let data_len = btoi::<usize>(arg(3)).map_err(|_| bad_format())?;
+            (1, Some(data_len))
+        },
+        MemcachedCommand::Delete if args.len() == 1 => (1, None),
+        MemcachedCommand::Incr | MemcachedCommand::Decr if args.len() == 2 => {
+            btoi::<u64>(arg(1)).map_err(|_| Rejection::Malformed(CLIENT_BAD_DELTA))?;
+            (1, None)
+        },
+        MemcachedCommand::Touch if args.len() == 2 => {
+            btoi::<i64>(arg(1)).map_err(|_| bad_format())?;
+            (1, None)
+        },
+        _ => return Err(bad_format()),
+    };
+
+    let keys = args[..key_count].to_vec();
+    if keys.iter().any(|(start, end)| end - start > MAX_KEY_LEN) {
+        return Err(bad_format());
+    }
+
+    Ok((cmd, keys, data_len))
+}
+
+/// How far a scan of a response from a backend got.
+#[derive(Debug, Default, PartialEq)]
+struct ResponseScan {
+    /// The length of the response, if it has arrived in full.
+    len: Option<usize>,
+
+    /// The number of values in the response so far.
+    values: usize,
+
+    /// The size of the largest value in the response so far, including any that haven't arrived
+    /// in full yet.
+    largest: usize,
+}
+
+/// Scans the response at the front of the buffer.
+///
+/// Responses to retrievals are any number of values, each a `VALUE` line followed by a data block,
+/// and then an `END` line.  Every other response, errors included, is a single line, so anything
+/// that isn't a `VALUE` line ends the response, without us needing to know what it was for.
+fn scan_response(rd: &[u8]) -> Result<ResponseScan, ProtocolError> {
+    let mut scan = ResponseScan::default();
+    let mut pos = 0;
+
+    loop {
+        let line_end = match find_line(&rd[pos..]) {
+            Some(n) => pos + n,
+            None => return Ok(scan),
+        };
+
+        let line = trim_line(&rd[pos..line_end]);
+        if !line.starts_with(MEMCACHED_VALUE) {
+            scan.len = Some(line_end);
+            return Ok(scan);
+        }
+
+        // VALUE <key> <flags> <bytes> [<cas unique>]
+        let data_len = tokenize(line)
+            .get(3)
+            .and_then(|(start, end)| btoi::<usize>(&line[*start..*end]).ok())
+            .ok_or_else(|| invalid(pos, "value length"))?;
+        scan.values += 1;
+        scan.largest = scan.largest.max(data_len);
+
+        let value_end = line_end.saturating_add(data_len).saturating_add(MEMCACHED_CRLF.len());
+        if rd.len() < value_end {
+            return Ok(scan);
+        }
+
+        if &rd[value_end - MEMCACHED_CRLF.len()..value_end] != MEMCACHED_CRLF {
+            return Err(invalid(value_end - MEMCACHED_CRLF.len(), "CRLF after value"));
+        }
+        pos = value_end;
+    }
+}
+
+/// Rewrites the key of every value in the given response with the given function.
+///
+/// Values are walked the same way as `scan_response` walks them, and anything after the values,
+/// such as the `END` that follows them, is copied as-is.
+fn map_value_keys<F>(rd: &[u8], mut f: F) -> BytesMut
+where
+    F: for<'a> FnMut(&'a [u8]) -> Cow<'a, [u8]>,
+{
+    let mut buf = BytesMut::with_capacity(rd.len());
+    let mut pos = 0;
+
+    while let Some(n) = find_line(&rd[pos..]) {
+        let line_end = pos + n;
+        let line = trim_line(&rd[pos..line_end]);
+        if !line.starts_with(MEMCACHED_VALUE) {
+            break;
+        }
+
+        // VALUE <key> <flags> <bytes> [<cas unique>]
+        let tokens = tokenize(line);
+        let data_len = tokens.get(3).and_then(|(start, end)| btoi::<usize>(&line[*start..*end]).ok());
+        let (key_start, key_end, data_len) = match (tokens.get(1), data_len) {
+            (Some((start, end)), Some(data_len)) => (*start, *end, data_len),
+            _ => break,
+        };
+
+        buf.extend_from_slice(&line[..key_start]);
+        buf.extend_from_slice(&f(&line[key_start..key_end]));
+
+        let value_end = line_end
+            .saturating_add(data_len)
+            .saturating_add(MEMCACHED_CRLF.len())
+            .min(rd.len());
+        buf.extend_from_slice(&rd[pos + key_end..value_end]);
+        pos = value_end;
+    }
+    buf.extend_from_slice(&rd[pos..]);
+
+    buf
+}
+
+fn invalid(offset: usize, expected: &'static str) -> ProtocolError {
+    ProtocolError::InvalidProtocol(ParseError::new(offset, expected))
+}
+
+/// Finds the end of the line at the front of the buffer, just past its newline.
+///
+/// Like memcached, we take a bare LF as the end of a line, and not just a CRLF.
+fn find_line(rd: &[u8]) -> Option<usize> { rd.iter().position(|b| *b == b'\n').map(|pos| pos + 1) }
+
+/// Trims the line ending off of the given line.
+fn trim_line(line: &[u8]) -> &[u8] {
+    let mut len = line.len();
+    if len > 0 && line[len - 1] == b'\n' {
+        len -= 1;
+    }
+    if len > 0 && line[len - 1] == b'\r' {
+        len -= 1;
+    }
+    &line[..len]
+}
+
+/// Splits the given line into its space-separated tokens, returning where each of them sits.
+fn tokenize(line: &[u8]) -> Vec<(usize, usize)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+
+    for (i, b) in line.iter().enumerate() {
+        match (*b == b' ', start) {
+            (true, Some(token_start)) => {
+                tokens.push((token_start, i));
+                start = None;
+            },
+            (false, None) => start = Some(i),
+            _ => {},
+        }
+    }
+    if let Some(token_start) = start {
+        tokens.push((token_start, line.len()));
+    }
+
+    tokens
+}
+
+/// Consumes any empty lines at the front of the buffer, returning how many bytes were consumed.
+fn skip_empty_lines(rd: &mut BytesMut) -> usize {
+    let n = rd.iter().take_while(|b| **b == b'\r' || **b == b'\n').count();
+    if n > 0 {
+        let _ = rd.split_to(n);
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::responses::ResponseSizeConfiguration;
+    use common::{EnqueuedRequest, MessageResponse};
+    use metrics::get_sink;
+    use std::io::Cursor;
+
+    fn get_client_messages(buf: &[u8]) -> Vec<MemcachedMessage> {
+        let transport = MemcachedTransport::new(Cursor::new(buf.to_vec()));
+        transport.collect().wait().expect("transport should not have failed")
+    }
+
+    fn get_bufs(msgs: Vec<MemcachedMessage>) -> Vec<BytesMut> { msgs.into_iter().map(|msg| msg.into_buf()).collect() }
+
+    fn get_tracker(max_bytes: Option<usize>) -> Arc<ResponseSizeTracker> {
+        let config = ResponseSizeConfiguration {
+            warn_bytes: None,
+            max_bytes,
+        };
+        Arc::new(ResponseSizeTracker::new(config, get_sink()))
+    }
+
+    fn read_response(request: MemcachedMessage, response: &[u8]) -> (Result<usize, ProtocolError>, MemcachedMessage) {
+        let mut request = EnqueuedRequest::new(0, request);
+        let rx = request.get_response_rx().unwrap();
+        let result = read_messages(Cursor::new(response.to_vec()), vec![request], get_tracker(Some(1024)))
+            .wait()
+            .map(|(_, n)| n);
+        match rx.wait().unwrap() {
+            (0, MessageResponse::Complete(msg)) => (result, msg),
+            _ => panic!("request should have gotten a response"),
+        }
+    }
+
+    #[test]
+    fn parse_commands() {
+        let msgs = get_client_messages(b"get foo\r\nset foo 5 0 3\r\nbar\r\ndelete foo\r\nincr n 2\r\ntouch foo 10\n");
+        assert_eq!(msgs.len(), 5);
+
+        let commands = msgs.iter().map(|msg| msg.get_command()).collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            vec![
+                Some(MemcachedCommand::Get),
+                Some(MemcachedCommand::Set),
+                Some(MemcachedCommand::Delete),
+                Some(MemcachedCommand::Incr),
+                Some(MemcachedCommand::Touch),
+            ]
+        );
+        assert!(msgs.iter().all(|msg| msg.key() == b"foo" || msg.key() == b"n"));
+
+        // Storage commands come with their data block.
+        assert_eq!(&msgs[1].get_buf()[..], &b"set foo 5 0 3\r\nbar\r\n"[..]);
+        assert_eq!(msgs[1].get_value_range(), Some((15, 18)));
+        assert_eq!(msgs[1].get_arguments(), vec![&b"foo"[..], b"5", b"0", b"3"]);
+    }
+
+    #[test]
+    fn parse_multi_key_get() {
+        let msgs = get_client_messages(b"gets  foo bar   baz\r\n");
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].get_keys(), vec![&b"foo"[..], b"bar", b"baz"]);
+
+        // Multi-key retrievals are routed by their first key, if they aren't split up.
+        assert_eq!(msgs[0].key(), b"foo");
+    }
+
+    #[test]
+    fn parse_waits_for_data_block() {
+        let mut rd = BytesMut::from(&b"set foo 0 0 6\r\nfoo"[..]);
+        match read_request(&mut rd) {
+            Ok(Async::NotReady) => {},
+            x => panic!("should have waited for the rest of the value, got {:?}", x),
+        }
+
+        rd.extend_from_slice(b"bar\r\n");
+        match read_request(&mut rd) {
+            Ok(Async::Ready((MemcachedMessage::Request(MemcachedCommand::Set, buf, _), 0))) => {
+                assert_eq!(&buf[..], &b"set foo 0 0 6\r\nfoobar\r\n"[..]);
+            },
+            x => panic!("should have had request, got {:?}", x),
+        }
+        assert!(rd.is_empty());
+    }
+
+    #[test]
+    fn malformed_commands_get_errors() {
+        let input = [
+            &b"get\r\n"[..],
+            b"set foo bar 0 3\r\nabc\r\n",
+            b"set foo 0 0 3\r\nabcd\r\n",
+            b"incr foo x\r\n",
+            b"delete foo noreply\r\n",
+            b"flush_all\r\n",
+            b"get foo\r\n",
+        ]
+        .concat();
+        let responses = get_bufs(get_client_messages(&input));
+
+        // Malformed commands don't cost the client its connection: it carries on with whatever it
+        // sends next, which is why the data block of the bad SET is an unknown command of its own.
+        let expected = vec![
+            &b"CLIENT_ERROR bad command line format\r\n"[..],
+            b"CLIENT_ERROR bad command line format\r\n",
+            b"ERROR\r\n",
+            b"CLIENT_ERROR bad data chunk\r\n",
+            b"CLIENT_ERROR invalid numeric delta argument\r\n",
+            b"CLIENT_ERROR noreply is not supported\r\n",
+            b"ERROR\r\n",
+            b"get foo\r\n",
+        ];
+        assert_eq!(responses, expected.into_iter().map(BytesMut::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn long_keys_are_rejected() {
+        let mut input = b"get ".to_vec();
+        for _ in 0..=MAX_KEY_LEN {
+            input.push(b'k');
+        }
+        input.extend_from_slice(b"\r\n");
+
+        let responses = get_bufs(get_client_messages(&input));
+        assert_eq!(responses, vec![BytesMut::from(&b"CLIENT_ERROR bad command line format\r\n"[..])]);
+    }
+
+    #[test]
+    fn oversized_values_are_skipped() {
+        let mut input = format!("set foo 0 0 {}\r\n", MAX_VALUE_LEN + 1).into_bytes();
+        for _ in 0..=MAX_VALUE_LEN {
+            input.push(b'x');
+        }
+        input.extend_from_slice(b"\r\nget foo\r\n");
+
+        let responses = get_bufs(get_client_messages(&input));
+        assert_eq!(
+            responses,
+            vec![
+                BytesMut::from(&b"SERVER_ERROR object too large for cache\r\n"[..]),
+                BytesMut::from(&b"get foo\r\n"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn endless_lines_close_the_connection() {
+        let mut input = Vec::new();
+        for _ in 0..MAX_LINE_LEN {
+            input.push(b'k');
+        }
+        input.extend_from_slice(b"\r\nget foo\r\n");
+
+        let responses = get_bufs(get_client_messages(&input));
+        assert_eq!(responses, vec![BytesMut::from(&b"CLIENT_ERROR line too long\r\n"[..])]);
+    }
+
+    #[test]
+    fn empty_lines_are_ignored() {
+        let responses = get_bufs(get_client_messages(b"\r\n\r\nget foo\r\n\n\r\nget bar\r\n"));
+        assert_eq!(
+            responses,
+            vec![BytesMut::from(&b"get foo\r\n"[..]), BytesMut::from(&b"get bar\r\n"[..])]
+        );
+    }
+
+    #[test]
+    fn keys_are_mapped() {
+        fn prefix(key: &[u8]) -> Cow<[u8]> { Cow::Owned([&b"app:"[..], key].concat()) }
+
+        let msgs = get_client_messages(b"gets foo bar\r\nset foo 5 0 3\r\nbar\r\ndelete foo\r\n");
+        let mapped = msgs.into_iter().map(|msg| msg.map_keys(prefix)).collect::<Vec<_>>();
+        assert_eq!(mapped[0].get_keys(), vec![&b"app:foo"[..], b"app:bar"]);
+        assert_eq!(&mapped[0].get_buf()[..], &b"gets app:foo app:bar\r\n"[..]);
+        assert_eq!(&mapped[1].get_buf()[..], &b"set app:foo 5 0 3\r\nbar\r\n"[..]);
+        assert_eq!(mapped[1].get_value_range(), Some((19, 22)));
+        assert_eq!(&mapped[2].get_buf()[..], &b"delete app:foo\r\n"[..]);
+
+        // Values that happen to look like a VALUE line are left alone.
+        let values = b"VALUE foo 0 15\r\nVALUE bar 0 3\r\n\r\nVALUE bar 1 0\r\n\r\n";
+        let response = MemcachedMessage::from_values(values);
+        let mapped_values = b"VALUE app:foo 0 15\r\nVALUE bar 0 3\r\n\r\nVALUE app:bar 1 0\r\n\r\n";
+        let expected = MemcachedMessage::from_values(mapped_values);
+        assert_eq!(response.map_keys(prefix), expected);
+
+        let error = MemcachedMessage::from_server_error("VALUE foo");
+        assert_eq!(error.clone().map_keys(prefix), error);
+        let stored = MemcachedMessage::Response(BytesMut::from(&b"STORED\r\n"[..]));
+        assert_eq!(stored.clone().map_keys(prefix), stored);
+    }
+
+    #[test]
+    fn read_retrieval_response() {
+        let request = MemcachedMessage::from_command(MemcachedCommand::Get, &[&b"foo"[..], &b"bar"[..]]);
+        let response = b"VALUE foo 0 3\r\nabc\r\nVALUE bar 0 5\r\nEND\r\n\r\nEND\r\n";
+        let (result, msg) = read_response(request, response);
+
+        assert_eq!(result.unwrap(), response.len());
+        assert_eq!(msg.count_values(), Some(2));
+        assert_eq!(msg.get_values(), Some(&response[..response.len() - 5]));
+    }
+
+    #[test]
+    fn read_single_line_responses() {
+        let request = || MemcachedMessage::from_command(MemcachedCommand::Delete, &[&b"foo"[..]]);
+        for response in &[&b"DELETED\r\n"[..], b"NOT_FOUND\r\n", b"SERVER_ERROR out of memory\r\n"] {
+            let (result, msg) = read_response(request(), response);
+            assert!(result.is_ok());
+            assert_eq!(msg, MemcachedMessage::Response(BytesMut::from(*response)));
+            assert_eq!(msg.count_values(), None);
+        }
+    }
+
+    #[test]
+    fn read_response_out_of_sync() {
+        let request = MemcachedMessage::from_command(MemcachedCommand::Get, &[&b"foo"[..]]);
+        let (result, _) = read_response(request, b"END\r\nSTORED\r\n");
+        match result {
+            Err(ProtocolError::BackendOutOfSync) => {},
+            x => panic!("extra response should have been caught, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn read_response_too_large() {
+        let request = MemcachedMessage::from_command(MemcachedCommand::Get, &[&b"foo"[..]]);
+        let (result, msg) = read_response(request, b"VALUE foo 0 209715200\r\nxxxx");
+        match result {
+            Err(ProtocolError::LimitExceeded(_)) => {},
+            x => panic!("oversized response should have been abandoned, got {:?}", x),
+        }
+        assert_eq!(
+            msg,
+            MemcachedMessage::from_server_error("response from backend is over max_response_bytes")
+        );
+    }
+}
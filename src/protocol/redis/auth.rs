@@ -0,0 +1,106 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::RedisMessage;
+use bytes::BytesMut;
+use conf::Secret;
+use crypto::util::fixed_time_eq;
+
+const NOAUTH: &[u8] = b"-NOAUTH Authentication required.\r\n";
+const INVALID_PASSWORD: &str = "invalid password";
+const WRONG_ARGUMENTS: &str = "wrong number of arguments for 'auth' command";
+
+/// Answers `AUTH` locally, and turns away everything else a client sends until it has
+/// authenticated.
+///
+/// Returns the response to send the client, or `None` if the command should be handled like any
+/// other.  Clients can always `QUIT`.  As with Redis, a failed `AUTH` leaves the client
+/// unauthenticated, even if it had authenticated before.
+pub fn check_auth(cmd: &RedisMessage, password: &Secret<String>, authenticated: &mut bool) -> Option<RedisMessage> {
+    let is_auth = cmd.get_command().map_or(false, |cmd| cmd.eq_ignore_ascii_case(b"auth"));
+
+    match cmd {
+        RedisMessage::Bulk(_, ref args, _) if is_auth => Some(handle_auth_command(args, password, authenticated)),
+        RedisMessage::Quit => None,
+        _ if *authenticated => None,
+        _ => Some(RedisMessage::Raw(BytesMut::from(NOAUTH))),
+    }
+}
+
+fn handle_auth_command(args: &[RedisMessage], password: &Secret<String>, authenticated: &mut bool) -> RedisMessage {
+    let candidate = match args.get(1) {
+        Some(RedisMessage::Data(buf, offset)) if args.len() == 2 => &buf[*offset..buf.len() - 2],
+        _ => return RedisMessage::from_error_str(WRONG_ARGUMENTS),
+    };
+
+    // Only the length of the password can be learned from how long this takes.
+    *authenticated = fixed_time_eq(candidate, password.expose().as_bytes());
+    if *authenticated {
+        RedisMessage::OK
+    } else {
+        RedisMessage::from_error_str(INVALID_PASSWORD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_password() -> Secret<String> { Secret::new("hunter2".to_owned()) }
+
+    fn check(cmd: &str, authenticated: &mut bool) -> Option<RedisMessage> {
+        check_auth(&RedisMessage::from_inline(cmd), &get_password(), authenticated)
+    }
+
+    #[test]
+    fn test_requires_auth() {
+        let mut authenticated = false;
+        assert_eq!(check("GET foo", &mut authenticated), Some(RedisMessage::Raw(BytesMut::from(NOAUTH))));
+        assert!(check_auth(&RedisMessage::Ping, &get_password(), &mut authenticated).is_some());
+        assert_eq!(check_auth(&RedisMessage::Quit, &get_password(), &mut authenticated), None);
+        assert!(!authenticated);
+    }
+
+    #[test]
+    fn test_auth() {
+        let mut authenticated = false;
+        assert_eq!(check("AUTH hunter2", &mut authenticated), Some(RedisMessage::OK));
+        assert!(authenticated);
+        assert_eq!(check("GET foo", &mut authenticated), None);
+
+        // Getting it wrong later on undoes having gotten it right.
+        assert_eq!(
+            check("auth hunter3", &mut authenticated),
+            Some(RedisMessage::from_error_str(INVALID_PASSWORD))
+        );
+        assert!(!authenticated);
+        assert!(check("GET foo", &mut authenticated).is_some());
+    }
+
+    #[test]
+    fn test_auth_invalid() {
+        let mut authenticated = false;
+        let wrong_arguments = Some(RedisMessage::from_error_str(WRONG_ARGUMENTS));
+        assert_eq!(check("AUTH", &mut authenticated), wrong_arguments);
+        assert_eq!(check("AUTH hunter2 hunter2", &mut authenticated), wrong_arguments);
+        assert_eq!(check("AUTH hunter", &mut authenticated), Some(RedisMessage::from_error_str(INVALID_PASSWORD)));
+        assert_eq!(check("AUTH hunter22", &mut authenticated), Some(RedisMessage::from_error_str(INVALID_PASSWORD)));
+        assert!(!authenticated);
+    }
+}
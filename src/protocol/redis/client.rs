@@ -0,0 +1,213 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::RedisMessage;
+use service::{ClientRegistry, ClientStats};
+use std::{net::SocketAddr, str};
+
+const CLIENT_KILL_DISABLED: &str = "CLIENT KILL is not allowed on this listener";
+const INVALID_CLIENT_NAME: &str = "Client names cannot contain spaces, newlines or special characters.";
+const NO_SUCH_CLIENT: &str = "No such client";
+const SYNTAX_ERROR: &str = "syntax error";
+
+/// Answers a `CLIENT` command locally.
+///
+/// Every client of a listener talks to us, not to any one backend, so `CLIENT` commands are about
+/// our clients, and never make it to a backend.  Clients can name themselves with `SETNAME`, and,
+/// if we've been given the registry of the listener's clients, kill each other with `KILL`, either
+/// by address, the way older versions of Redis do, or by a single `ADDR`, `ID` or `NAME` filter.
+/// Unlike Redis, the client sending the command isn't spared.
+pub fn handle_client_command(
+    args: &[RedisMessage], stats: Option<&ClientStats>, clients: Option<&ClientRegistry>,
+) -> RedisMessage {
+    let subcommand = match args.get(1).and_then(get_arg) {
+        Some(subcommand) => subcommand.to_ascii_lowercase(),
+        None => return RedisMessage::from_error_str("wrong number of arguments for 'client' command"),
+    };
+
+    match subcommand.as_slice() {
+        b"setname" => handle_setname(args, stats),
+        b"kill" => {
+            match clients {
+                Some(clients) => handle_kill(args, clients),
+                None => RedisMessage::from_error_str(CLIENT_KILL_DISABLED),
+            }
+        },
+        _ => RedisMessage::from_error_str("unsupported CLIENT subcommand"),
+    }
+}
+
+fn handle_setname(args: &[RedisMessage], stats: Option<&ClientStats>) -> RedisMessage {
+    let name = match args.get(2).and_then(get_arg) {
+        Some(name) if args.len() == 3 => name,
+        _ => return RedisMessage::from_error_str("wrong number of arguments for 'client setname' command"),
+    };
+
+    // Like Redis, we only take names that can be listed without any quoting.
+    if name.iter().any(|b| !(b'!'..=b'~').contains(b)) {
+        return RedisMessage::from_error_str(INVALID_CLIENT_NAME);
+    }
+
+    if let Some(stats) = stats {
+        stats.set_name(String::from_utf8_lossy(name).into_owned());
+    }
+    RedisMessage::OK
+}
+
+fn handle_kill(args: &[RedisMessage], clients: &ClientRegistry) -> RedisMessage {
+    let args = args[2..].iter().map(get_arg).collect::<Option<Vec<_>>>();
+    match args.as_ref().map(|args| args.as_slice()) {
+        // The old form kills by address, and only says whether or not anyone was killed.
+        Some([addr]) => {
+            match parse_addr(addr) {
+                Some(addr) if clients.kill_by_addr(&addr) > 0 => RedisMessage::OK,
+                _ => RedisMessage::from_error_str(NO_SUCH_CLIENT),
+            }
+        },
+        // The new form says how many clients were killed.
+        Some([filter, value]) => {
+            let killed = match filter.to_ascii_lowercase().as_slice() {
+                b"addr" => parse_addr(value).map(|addr| clients.kill_by_addr(&addr)),
+                b"id" => {
+                    str::from_utf8(value)
+                        .ok()
+                        .and_then(|id| id.parse::<u64>().ok())
+                        .map(|id| if clients.kill_by_id(id) { 1 } else { 0 })
+                },
+                b"name" => Some(clients.kill_by_name(&String::from_utf8_lossy(value))),
+                _ => None,
+            };
+
+            match killed {
+                Some(killed) => RedisMessage::from_integer(killed as i64),
+                None => RedisMessage::from_error_str(SYNTAX_ERROR),
+            }
+        },
+        _ => RedisMessage::from_error_str(SYNTAX_ERROR),
+    }
+}
+
+fn get_arg(arg: &RedisMessage) -> Option<&[u8]> {
+    match arg {
+        RedisMessage::Data(buf, offset) => Some(&buf[*offset..buf.len() - 2]),
+        _ => None,
+    }
+}
+
+fn parse_addr(addr: &[u8]) -> Option<SocketAddr> { str::from_utf8(addr).ok()?.parse().ok() }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use std::sync::Arc;
+
+    fn run_command(cmd: &str, stats: Option<&ClientStats>, clients: Option<&ClientRegistry>) -> RedisMessage {
+        match RedisMessage::from_inline(cmd) {
+            RedisMessage::Bulk(_, args, _) => handle_client_command(&args, stats, clients),
+            _ => unreachable!(),
+        }
+    }
+
+    fn get_closing(clients: &ClientRegistry) -> Vec<u64> {
+        let (_, page) = clients.list(None, 100);
+        page.into_iter()
+            .filter(|client| client.flags.contains(&"closing"))
+            .map(|client| client.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_client_setname() {
+        let stats = ClientStats::default();
+        assert_eq!(run_command("CLIENT SETNAME worker-1", Some(&stats), None), RedisMessage::OK);
+        assert_eq!(stats.name(), Some("worker-1".to_owned()));
+
+        // Names that would need quoting to be listed are turned away, leaving the old name in place.
+        let args = vec![
+            RedisMessage::Data(BytesMut::from(&b"$6\r\nCLIENT\r\n"[..]), 4),
+            RedisMessage::Data(BytesMut::from(&b"$7\r\nSETNAME\r\n"[..]), 4),
+            RedisMessage::Data(BytesMut::from(&b"$3\r\na b\r\n"[..]), 4),
+        ];
+        assert_eq!(
+            handle_client_command(&args, Some(&stats), None),
+            RedisMessage::from_error_str(INVALID_CLIENT_NAME)
+        );
+        assert_eq!(
+            run_command("CLIENT SETNAME", Some(&stats), None),
+            RedisMessage::from_error_str("wrong number of arguments for 'client setname' command")
+        );
+        assert_eq!(stats.name(), Some("worker-1".to_owned()));
+    }
+
+    #[test]
+    fn test_client_kill() {
+        let clients = Arc::new(ClientRegistry::new());
+        let addr1 = "127.0.0.1:5000".parse().unwrap();
+        let addr2 = "127.0.0.1:5001".parse().unwrap();
+        let regs = vec![
+            ClientRegistry::register(&clients, addr1),
+            ClientRegistry::register(&clients, addr2),
+            ClientRegistry::register(&clients, addr2),
+            ClientRegistry::register(&clients, addr2),
+        ];
+        regs[3].0.stats().set_name("worker".to_owned());
+
+        // Killing by address alone only says whether anyone was killed.
+        assert_eq!(run_command("CLIENT KILL 127.0.0.1:5000", None, Some(&clients)), RedisMessage::OK);
+        assert_eq!(
+            run_command("CLIENT KILL 127.0.0.1:5000", None, Some(&clients)),
+            RedisMessage::from_error_str(NO_SUCH_CLIENT)
+        );
+        assert_eq!(get_closing(&clients), vec![0]);
+
+        // Filters say how many.
+        assert_eq!(run_command("CLIENT KILL ID 1", None, Some(&clients)), RedisMessage::from_integer(1));
+        assert_eq!(run_command("client kill name worker", None, Some(&clients)), RedisMessage::from_integer(1));
+        assert_eq!(get_closing(&clients), vec![0, 1, 3]);
+        assert_eq!(run_command("CLIENT KILL ADDR 127.0.0.1:5001", None, Some(&clients)), RedisMessage::from_integer(1));
+        assert_eq!(run_command("CLIENT KILL ID 42", None, Some(&clients)), RedisMessage::from_integer(0));
+        assert_eq!(get_closing(&clients), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_client_kill_invalid() {
+        let clients = ClientRegistry::new();
+        let syntax_error = RedisMessage::from_error_str(SYNTAX_ERROR);
+        assert_eq!(run_command("CLIENT KILL", None, Some(&clients)), syntax_error);
+        assert_eq!(run_command("CLIENT KILL ADDR nowhere", None, Some(&clients)), syntax_error);
+        assert_eq!(run_command("CLIENT KILL ID first", None, Some(&clients)), syntax_error);
+        assert_eq!(run_command("CLIENT KILL TYPE normal", None, Some(&clients)), syntax_error);
+        assert_eq!(run_command("CLIENT KILL ID 1 NAME worker", None, Some(&clients)), syntax_error);
+        assert_eq!(
+            run_command("CLIENT KILL nowhere", None, Some(&clients)),
+            RedisMessage::from_error_str(NO_SUCH_CLIENT)
+        );
+
+        // Listeners that don't allow it don't let anyone be killed, and nothing else is passed along.
+        assert_eq!(
+            run_command("CLIENT KILL ID 0", None, None),
+            RedisMessage::from_error_str(CLIENT_KILL_DISABLED)
+        );
+        assert_eq!(
+            run_command("CLIENT LIST", None, Some(&clients)),
+            RedisMessage::from_error_str("unsupported CLIENT subcommand")
+        );
+    }
+}
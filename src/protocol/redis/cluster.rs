@@ -0,0 +1,331 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::RedisMessage;
+use bytes::BytesMut;
+use crypto::{digest::Digest, sha1::Sha1};
+use itoa;
+use std::net::SocketAddr;
+
+const CLUSTER_SLOT_MIN: i64 = 0;
+const CLUSTER_SLOT_MAX: i64 = 16383;
+const CLUSTER_SLOT_COUNT: usize = 16384;
+const CLUSTER_BUS_PORT_OFFSET: u32 = 10000;
+const CLUSTER_DISABLED: &str = "This instance has cluster support disabled";
+
+/// Answers a `CLUSTER` command locally.
+///
+/// When `pretend_cluster` is enabled, we describe ourselves as a single-node cluster that owns the
+/// entire slot range at `local_addr`, so that cluster-aware clients send every request through us.
+/// Otherwise, we answer the way a Redis instance with cluster support disabled would.
+pub fn handle_cluster_command(
+    args: &[RedisMessage], local_addr: Option<SocketAddr>, pretend_cluster: bool,
+) -> RedisMessage {
+    let subcommand = match args.get(1) {
+        Some(RedisMessage::Data(buf, offset)) => buf[*offset..buf.len() - 2].to_ascii_lowercase(),
+        _ => return RedisMessage::from_error_str("wrong number of arguments for 'cluster' command"),
+    };
+
+    if !pretend_cluster {
+        return match subcommand.as_slice() {
+            b"info" => RedisMessage::Raw(get_cluster_info(false)),
+            _ => RedisMessage::from_error_str(CLUSTER_DISABLED),
+        };
+    }
+
+    let addr = match local_addr {
+        Some(addr) => addr,
+        None => return RedisMessage::from_error_str("unable to determine cluster address"),
+    };
+
+    match subcommand.as_slice() {
+        b"info" => RedisMessage::Raw(get_cluster_info(true)),
+        b"slots" => RedisMessage::Raw(get_cluster_slots(&addr)),
+        b"shards" => RedisMessage::Raw(get_cluster_shards(&addr)),
+        b"nodes" => RedisMessage::Raw(get_cluster_nodes(&addr)),
+        b"myid" => RedisMessage::Raw(write_data(BytesMut::new(), get_node_id(&addr).as_bytes())),
+        _ => RedisMessage::from_error_str("unsupported CLUSTER subcommand"),
+    }
+}
+
+/// Gets a stable node ID for the given address.
+///
+/// Clients use node IDs to tell nodes apart across topology refreshes, so the ID is derived from
+/// the address rather than generated, and stays the same for the lifetime of the listener.
+fn get_node_id(addr: &SocketAddr) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input_str(&addr.to_string());
+    hasher.result_str()
+}
+
+fn get_cluster_info(enabled: bool) -> BytesMut {
+    let info = if enabled {
+        format!(
+            "cluster_enabled:1\r\ncluster_state:ok\r\ncluster_slots_assigned:{0}\r\ncluster_slots_ok:{0}\r\n\
+             cluster_slots_pfail:0\r\ncluster_slots_fail:0\r\ncluster_known_nodes:1\r\ncluster_size:1\r\n\
+             cluster_current_epoch:0\r\ncluster_my_epoch:0\r\n",
+            CLUSTER_SLOT_COUNT
+        )
+    } else {
+        "cluster_enabled:0\r\n".to_owned()
+    };
+
+    write_data(BytesMut::new(), info.as_bytes())
+}
+
+fn get_cluster_slots(addr: &SocketAddr) -> BytesMut {
+    let ip = addr.ip().to_string();
+    let node_id = get_node_id(addr);
+
+    let mut buf = write_array_header(BytesMut::new(), 1);
+    buf = write_array_header(buf, 3);
+    buf = write_integer(buf, CLUSTER_SLOT_MIN);
+    buf = write_integer(buf, CLUSTER_SLOT_MAX);
+    buf = write_array_header(buf, 3);
+    buf = write_data(buf, ip.as_bytes());
+    buf = write_integer(buf, i64::from(addr.port()));
+    write_data(buf, node_id.as_bytes())
+}
+
+fn get_cluster_shards(addr: &SocketAddr) -> BytesMut {
+    let ip = addr.ip().to_string();
+    let node_id = get_node_id(addr);
+
+    let mut buf = write_array_header(BytesMut::new(), 1);
+    buf = write_array_header(buf, 4);
+    buf = write_data(buf, b"slots");
+    buf = write_array_header(buf, 2);
+    buf = write_integer(buf, CLUSTER_SLOT_MIN);
+    buf = write_integer(buf, CLUSTER_SLOT_MAX);
+    buf = write_data(buf, b"nodes");
+    buf = write_array_header(buf, 1);
+    buf = write_array_header(buf, 14);
+    buf = write_data(buf, b"id");
+    buf = write_data(buf, node_id.as_bytes());
+    buf = write_data(buf, b"port");
+    buf = write_integer(buf, i64::from(addr.port()));
+    buf = write_data(buf, b"ip");
+    buf = write_data(buf, ip.as_bytes());
+    buf = write_data(buf, b"endpoint");
+    buf = write_data(buf, ip.as_bytes());
+    buf = write_data(buf, b"role");
+    buf = write_data(buf, b"master");
+    buf = write_data(buf, b"replication-offset");
+    buf = write_integer(buf, 0);
+    buf = write_data(buf, b"health");
+    write_data(buf, b"online")
+}
+
+fn get_cluster_nodes(addr: &SocketAddr) -> BytesMut {
+    let bus_port = u32::from(addr.port()) + CLUSTER_BUS_PORT_OFFSET;
+    let nodes = format!(
+        "{} {}@{} myself,master - 0 0 0 connected {}-{}\n",
+        get_node_id(addr),
+        addr,
+        bus_port,
+        CLUSTER_SLOT_MIN,
+        CLUSTER_SLOT_MAX
+    );
+
+    write_data(BytesMut::new(), nodes.as_bytes())
+}
+
+pub fn write_array_header(mut buf: BytesMut, len: usize) -> BytesMut {
+    let mut cnt_buf = [b'\0'; 20];
+    let n = itoa::write(&mut cnt_buf[..], len).unwrap();
+    buf.extend_from_slice(b"*");
+    buf.extend_from_slice(&cnt_buf[..n]);
+    buf.extend_from_slice(b"\r\n");
+    buf
+}
+
+pub fn write_integer(mut buf: BytesMut, value: i64) -> BytesMut {
+    let mut value_buf = [b'\0'; 20];
+    let n = itoa::write(&mut value_buf[..], value).unwrap();
+    buf.extend_from_slice(b":");
+    buf.extend_from_slice(&value_buf[..n]);
+    buf.extend_from_slice(b"\r\n");
+    buf
+}
+
+pub fn write_data(mut buf: BytesMut, data: &[u8]) -> BytesMut {
+    let mut cnt_buf = [b'\0'; 20];
+    let n = itoa::write(&mut cnt_buf[..], data.len()).unwrap();
+    buf.extend_from_slice(b"$");
+    buf.extend_from_slice(&cnt_buf[..n]);
+    buf.extend_from_slice(b"\r\n");
+    buf.extend_from_slice(data);
+    buf.extend_from_slice(b"\r\n");
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use protocol::redis::{read_message, UNLIMITED};
+
+    // Replies of a single-node Redis cluster on 127.0.0.1:7000, with the node ID standing in for the
+    // one we derive from that address.  See the README alongside them for where they come from.
+    static FIXTURE_SLOTS: &[u8] =
+        include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/synchrotron-test/fixtures/cluster/slots.resp"));
+    static FIXTURE_SHARDS: &[u8] =
+        include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/synchrotron-test/fixtures/cluster/shards.resp"));
+
+    fn get_addr() -> SocketAddr { "127.0.0.1:7000".parse().unwrap() }
+
+    fn get_fixture(fixture: &[u8]) -> Vec<u8> {
+        let node_id = get_node_id(&get_addr());
+        String::from_utf8_lossy(fixture).replace("NODE_ID", &node_id).into_bytes()
+    }
+
+    // Just enough of RESP to walk a reply, empty arrays included, which our own parser won't take.
+    #[derive(Debug, PartialEq)]
+    enum Reply {
+        Integer(i64),
+        Data(String),
+        Array(Vec<Reply>),
+    }
+
+    fn read_reply(buf: &mut &[u8]) -> Reply {
+        let line_end = buf.windows(2).position(|bytes| bytes == b"\r\n").expect("reply should be complete");
+        let line = String::from_utf8_lossy(&buf[1..line_end]).to_string();
+        let sigil = buf[0];
+        *buf = &buf[line_end + 2..];
+
+        match sigil {
+            b':' => Reply::Integer(line.parse().unwrap()),
+            b'$' => {
+                let len = line.parse::<usize>().unwrap();
+                let data = String::from_utf8_lossy(&buf[..len]).to_string();
+                *buf = &buf[len + 2..];
+                Reply::Data(data)
+            },
+            b'*' => Reply::Array((0..line.parse::<usize>().unwrap()).map(|_| read_reply(buf)).collect()),
+            x => panic!("unexpected reply type '{}'", x as char),
+        }
+    }
+
+    // Gets every slot range in a `CLUSTER SLOTS` reply, along with the IP, port and ID of each node
+    // serving it.  Redis 7.0 and later follow those with the node's networking metadata, which we
+    // don't send, so anything past them is left out.
+    fn get_slot_ranges(buf: &[u8]) -> Vec<Reply> {
+        let mut buf = buf;
+        let ranges = match read_reply(&mut buf) {
+            Reply::Array(ranges) => ranges,
+            x => panic!("expected slot ranges, got {:?}", x),
+        };
+        assert!(buf.is_empty(), "nothing should follow the reply");
+
+        ranges
+            .into_iter()
+            .map(|range| {
+                match range {
+                    Reply::Array(mut fields) => {
+                        for node in fields.iter_mut().skip(2) {
+                            if let Reply::Array(node) = node {
+                                node.truncate(3);
+                            }
+                        }
+                        Reply::Array(fields)
+                    },
+                    x => panic!("expected a slot range, got {:?}", x),
+                }
+            })
+            .collect()
+    }
+
+    fn run_command(subcommand: &str, pretend_cluster: bool) -> RedisMessage {
+        let cmd = RedisMessage::from_inline(&format!("CLUSTER {}", subcommand));
+        match cmd {
+            RedisMessage::Bulk(_, args, _) => handle_cluster_command(&args, Some(get_addr()), pretend_cluster),
+            _ => panic!("inline command should be multi-bulk"),
+        }
+    }
+
+    fn get_raw(msg: RedisMessage) -> BytesMut {
+        match msg {
+            RedisMessage::Raw(buf) => buf,
+            x => panic!("expected raw response, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_node_id_is_stable() {
+        let node_id = get_node_id(&get_addr());
+        assert_eq!(node_id.len(), 40);
+        assert_eq!(node_id, get_node_id(&get_addr()));
+        assert_ne!(node_id, get_node_id(&"127.0.0.1:7001".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_cluster_slots_matches_fixture() {
+        let buf = get_raw(run_command("SLOTS", true));
+        let ranges = get_slot_ranges(&buf[..]);
+        assert_eq!(ranges, get_slot_ranges(&get_fixture(FIXTURE_SLOTS)[..]));
+        assert_eq!(
+            ranges,
+            vec![Reply::Array(vec![
+                Reply::Integer(0),
+                Reply::Integer(16383),
+                Reply::Array(vec![
+                    Reply::Data("127.0.0.1".to_string()),
+                    Reply::Integer(7000),
+                    Reply::Data(get_node_id(&get_addr())),
+                ]),
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_cluster_shards_matches_fixture() {
+        let buf = get_raw(run_command("shards", true));
+        assert_eq!(&buf[..], &get_fixture(FIXTURE_SHARDS)[..]);
+    }
+
+    #[test]
+    fn test_cluster_responses_parse() {
+        for subcommand in &["slots", "shards", "nodes", "info", "myid"] {
+            let mut buf = get_raw(run_command(subcommand, true));
+            let len = buf.len();
+            match read_message(&mut buf, &UNLIMITED) {
+                Ok(Async::Ready((n, _))) => assert_eq!(n, len),
+                x => panic!("response for {} did not parse: {:?}", subcommand, x),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cluster_nodes_lists_self() {
+        let buf = get_raw(run_command("nodes", true));
+        let nodes = String::from_utf8_lossy(&buf[..]).to_string();
+        assert!(nodes.contains(" 127.0.0.1:7000@17000 myself,master - 0 0 0 connected 0-16383\n"));
+    }
+
+    #[test]
+    fn test_cluster_disabled() {
+        let buf = get_raw(run_command("info", false));
+        assert_eq!(&buf[..], &b"$19\r\ncluster_enabled:0\r\n\r\n"[..]);
+
+        match run_command("slots", false) {
+            RedisMessage::Error(_, _) => {},
+            x => panic!("expected error, got {:?}", x),
+        }
+    }
+}
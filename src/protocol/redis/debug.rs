@@ -0,0 +1,107 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::RedisMessage;
+use std::{str, time::Duration};
+
+const INVALID_SLEEP: &str = "invalid sleep duration";
+const SLEEP_TOO_LONG: &str = "sleep duration is longer than the limit of 60 seconds";
+
+// The longest we'll hold a client's response for, in seconds.
+const MAX_SLEEP_SECS: f64 = 60.0;
+
+/// Answers a `DEBUG` command locally, if it's one we can simulate.
+///
+/// `DEBUG SLEEP` would block the backend connection it's sent over, and with it every other client
+/// sharing that connection, so we simulate it instead: the client gets a `Sleep` that's answered
+/// once the requested number of seconds, up to a minute, has passed.  Anything else is left alone,
+/// and so is subject to the same checks as every other command.
+pub fn handle_debug_command(args: &[RedisMessage]) -> Option<RedisMessage> {
+    let subcommand = match args.get(1) {
+        Some(RedisMessage::Data(buf, offset)) => &buf[*offset..buf.len() - 2],
+        _ => return None,
+    };
+
+    if !subcommand.eq_ignore_ascii_case(b"sleep") {
+        return None;
+    }
+
+    let secs = match args.get(2) {
+        Some(RedisMessage::Data(buf, offset)) if args.len() == 3 => {
+            str::from_utf8(&buf[*offset..buf.len() - 2])
+                .ok()
+                .and_then(|secs| secs.parse::<f64>().ok())
+        },
+        _ => return Some(RedisMessage::from_error_str("wrong number of arguments for 'debug sleep' command")),
+    };
+
+    match secs {
+        Some(secs) if secs > MAX_SLEEP_SECS => Some(RedisMessage::from_error_str(SLEEP_TOO_LONG)),
+        Some(secs) if secs.is_finite() && secs >= 0.0 => {
+            let whole = secs.trunc();
+            let nanos = ((secs - whole) * 1_000_000_000.0) as u32;
+            Some(RedisMessage::Sleep(Duration::new(whole as u64, nanos)))
+        },
+        _ => Some(RedisMessage::from_error_str(INVALID_SLEEP)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Message;
+
+    fn get_debug_response(cmd: &str) -> Option<RedisMessage> {
+        match RedisMessage::from_inline(cmd) {
+            RedisMessage::Bulk(_, args, _) => handle_debug_command(&args),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_debug_sleep() {
+        assert_eq!(get_debug_response("DEBUG SLEEP 2"), Some(RedisMessage::Sleep(Duration::from_secs(2))));
+        assert_eq!(get_debug_response("debug sleep 0.25"), Some(RedisMessage::Sleep(Duration::from_millis(250))));
+        assert_eq!(get_debug_response("DEBUG SLEEP 0"), Some(RedisMessage::Sleep(Duration::from_secs(0))));
+    }
+
+    #[test]
+    fn test_debug_sleep_invalid() {
+        assert_eq!(get_debug_response("DEBUG SLEEP -1"), Some(RedisMessage::from_error_str(INVALID_SLEEP)));
+        assert_eq!(get_debug_response("DEBUG SLEEP forever"), Some(RedisMessage::from_error_str(INVALID_SLEEP)));
+        assert_eq!(get_debug_response("DEBUG SLEEP nan"), Some(RedisMessage::from_error_str(INVALID_SLEEP)));
+        assert!(get_debug_response("DEBUG SLEEP").unwrap().is_inline());
+        assert!(get_debug_response("DEBUG SLEEP 1 2").unwrap().is_inline());
+    }
+
+    #[test]
+    fn test_debug_sleep_too_long() {
+        assert_eq!(get_debug_response("DEBUG SLEEP 60"), Some(RedisMessage::Sleep(Duration::from_secs(60))));
+        assert_eq!(get_debug_response("DEBUG SLEEP 60.5"), Some(RedisMessage::from_error_str(SLEEP_TOO_LONG)));
+        assert_eq!(get_debug_response("DEBUG SLEEP 1e300"), Some(RedisMessage::from_error_str(SLEEP_TOO_LONG)));
+        assert_eq!(get_debug_response("DEBUG SLEEP inf"), Some(RedisMessage::from_error_str(SLEEP_TOO_LONG)));
+    }
+
+    #[test]
+    fn test_debug_other_subcommands() {
+        assert_eq!(get_debug_response("DEBUG SEGFAULT"), None);
+        assert_eq!(get_debug_response("DEBUG OBJECT foo"), None);
+        assert_eq!(get_debug_response("DEBUG"), None);
+    }
+}
@@ -0,0 +1,482 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::invalid;
+use btoi::btoi;
+use bytes::BytesMut;
+use futures::prelude::*;
+use itoa;
+use protocol::errors::ProtocolError;
+
+const RESP2_NULL: &[u8] = b"$-1\r\n";
+const RESP2_TRUE: &[u8] = b":1\r\n";
+const RESP2_FALSE: &[u8] = b":0\r\n";
+
+/// The header of a single value in a response.
+enum Header {
+    /// A value that's entirely on its header line, which ends at the given offset.
+    Line(u8, usize),
+
+    /// A value whose length is given up front.  Its data starts at the first offset, after the
+    /// header line, and the value ends at the second, after the CRLF that follows the data.
+    Blob(u8, usize, usize),
+
+    /// An aggregate of the given number of values, whose header line ends at the given offset.
+    ///
+    /// Maps and attributes are counted by their keys and values, so a map of three entries is an
+    /// aggregate of six values.
+    Aggregate(u8, usize, usize),
+}
+
+fn is_resp3(sigil: u8) -> bool {
+    match sigil {
+        b'+' | b'-' | b':' | b'$' | b'*' => false,
+        _ => true,
+    }
+}
+
+fn read_header(buf: &[u8], offset: usize) -> Poll<Header, ProtocolError> {
+    let line_end = match buf[offset..].windows(2).position(|bytes| bytes == b"\r\n") {
+        Some(pos) => offset + pos,
+        None => return Ok(Async::NotReady),
+    };
+    let sigil = buf[offset];
+    let body = &buf[offset + 1..line_end];
+    let header_end = line_end + 2;
+
+    match sigil {
+        b'+' | b'-' | b':' | b'_' | b',' | b'#' | b'(' => Ok(Async::Ready(Header::Line(sigil, header_end))),
+        b'$' | b'*' if body == b"-1" => Ok(Async::Ready(Header::Line(sigil, header_end))),
+        b'$' | b'=' | b'!' => {
+            let len = btoi::<usize>(body).map_err(|_| invalid(offset + 1, "bulk length"))?;
+            Ok(Async::Ready(Header::Blob(sigil, header_end, header_end + len + 2)))
+        },
+        b'*' | b'~' | b'>' | b'%' | b'|' => {
+            let count = btoi::<usize>(body)
+                .ok()
+                .and_then(|count| {
+                    match sigil {
+                        b'%' | b'|' => count.checked_mul(2),
+                        _ => Some(count),
+                    }
+                })
+                .ok_or_else(|| invalid(offset + 1, "aggregate length"))?;
+            Ok(Async::Ready(Header::Aggregate(sigil, count, header_end)))
+        },
+        _ => Err(invalid(offset, "type sigil")),
+    }
+}
+
+/// Keeps track of where we are in the aggregates of a response.
+///
+/// This is kept apart from the call stack, so a deeply nested response can't overflow it, and so
+/// we can stop partway through a response and pick up where we left off when more of it arrives.
+#[derive(Default)]
+struct Nesting {
+    // How many values are left in each aggregate we're in the middle of, and whether or not it's
+    // an attribute.
+    levels: Vec<(usize, bool)>,
+}
+
+impl Nesting {
+    fn depth(&self) -> usize { self.levels.len() }
+
+    /// Opens an aggregate of the given number of values, returning whether that finished the
+    /// response.
+    fn open(&mut self, sigil: u8, count: usize) -> bool {
+        let attribute = sigil == b'|';
+        if count > 0 {
+            self.levels.push((count, attribute));
+            false
+        } else {
+            // An empty attribute still comes before the value it describes.
+            !attribute && self.finish()
+        }
+    }
+
+    /// Finishes a value, returning whether that finished the response.
+    fn finish(&mut self) -> bool {
+        loop {
+            match self.levels.last_mut() {
+                None => return true,
+                Some((remaining, _)) => {
+                    *remaining -= 1;
+                    if *remaining > 0 {
+                        return false;
+                    }
+                },
+            }
+
+            // Attributes describe the value that comes after them, so finishing one doesn't finish
+            // a value of the aggregate it's in.
+            let (_, attribute) = self.levels.pop().expect("level was just looked at");
+            if attribute {
+                return false;
+            }
+        }
+    }
+}
+
+/// What was done to the response at the front of the buffer.
+#[derive(Debug, PartialEq)]
+pub enum Downgraded {
+    /// The response was already RESP2, so it was left alone.
+    Unchanged,
+
+    /// The response had RESP3 types in it, and was rewritten as RESP2.
+    Translated,
+
+    /// The response was a push, which isn't a response to anything, and has no RESP2 equivalent,
+    /// so it was dropped.
+    Push,
+}
+
+/// Translates responses from backends that speak RESP3 into RESP2, for our clients.
+///
+/// Clients can't negotiate RESP3 with us, so they all expect RESP2 responses, but some backends
+/// send RESP3 types regardless.  Each response is scanned, as it arrives, for RESP3 types, picking
+/// up where the last scan left off, so every header is only looked at once, no matter how many
+/// pieces the response arrives in.  Responses that turn out to be RESP2 already are left as they
+/// are, and the rest are rewritten in place, once they've fully arrived:
+///
+/// - maps are flattened into arrays of their keys and values, and sets become arrays
+/// - doubles, big numbers, and verbatim strings become bulk strings
+/// - booleans become the integers 1 and 0, and nulls become null bulk strings
+/// - blob errors become simple errors
+/// - attributes are dropped, leaving the value they describe
+///
+/// Pushes aren't responses to any request, so they're dropped entirely.
+#[derive(Default)]
+pub struct ResponseDowngrader {
+    offset: usize,
+    nesting: Nesting,
+    resp3: bool,
+    push: bool,
+    complete: bool,
+}
+
+impl ResponseDowngrader {
+    /// Scans whatever has arrived of the response at the front of the given buffer since the last
+    /// call, translating the response once all of it has arrived.
+    pub fn poll(&mut self, rd: &mut BytesMut) -> Poll<Downgraded, ProtocolError> {
+        while !self.complete {
+            if self.offset >= rd.len() {
+                return Ok(Async::NotReady);
+            }
+
+            let (sigil, end) = match try_ready!(read_header(rd, self.offset)) {
+                Header::Line(sigil, end) => {
+                    self.complete = self.nesting.finish();
+                    (sigil, end)
+                },
+                Header::Blob(sigil, _, end) => {
+                    self.complete = self.nesting.finish();
+                    (sigil, end)
+                },
+                Header::Aggregate(sigil, count, end) => {
+                    self.push |= sigil == b'>' && self.offset == 0;
+                    self.complete = self.nesting.open(sigil, count);
+                    (sigil, end)
+                },
+            };
+            self.resp3 |= is_resp3(sigil);
+            self.offset = end;
+        }
+
+        // The last value in the response may still be on its way.
+        if rd.len() < self.offset {
+            return Ok(Async::NotReady);
+        }
+
+        let end = self.offset;
+        let (resp3, push) = (self.resp3, self.push);
+        *self = ResponseDowngrader::default();
+
+        if push {
+            let _ = rd.split_to(end);
+            return Ok(Async::Ready(Downgraded::Push));
+        }
+
+        if !resp3 {
+            return Ok(Async::Ready(Downgraded::Unchanged));
+        }
+
+        let response = rd.split_to(end);
+        let mut translated = translate(&response)?;
+        translated.unsplit(rd.take());
+        *rd = translated;
+
+        Ok(Async::Ready(Downgraded::Translated))
+    }
+}
+
+/// Translates a complete response into RESP2.
+fn translate(response: &[u8]) -> Result<BytesMut, ProtocolError> {
+    let mut out = BytesMut::with_capacity(response.len());
+    let mut nesting = Nesting::default();
+    let mut offset = 0;
+
+    // While we're inside an attribute, this is how deep the attribute is, and nothing is written.
+    let mut skipping: Option<usize> = None;
+
+    loop {
+        let header = match read_header(response, offset)? {
+            Async::Ready(header) => header,
+            Async::NotReady => return Err(invalid(offset, "complete response")),
+        };
+
+        let writing = skipping.is_none();
+        let complete = match header {
+            Header::Line(sigil, end) => {
+                if writing {
+                    write_line(&mut out, sigil, &response[offset + 1..end - 2], offset)?;
+                }
+                offset = end;
+                nesting.finish()
+            },
+            Header::Blob(sigil, start, end) => {
+                if end > response.len() || &response[end - 2..end] != b"\r\n" {
+                    return Err(invalid(end - 2, "CRLF after bulk data"));
+                }
+                if writing {
+                    write_blob(&mut out, sigil, &response[start..end - 2]);
+                }
+                offset = end;
+                nesting.finish()
+            },
+            Header::Aggregate(sigil, count, end) => {
+                if sigil == b'|' {
+                    skipping = skipping.or_else(|| Some(nesting.depth()));
+                } else if writing {
+                    write_header(&mut out, b'*', count);
+                }
+                offset = end;
+                nesting.open(sigil, count)
+            },
+        };
+
+        if skipping.map_or(false, |depth| nesting.depth() <= depth) {
+            skipping = None;
+        }
+
+        if complete {
+            return Ok(out);
+        }
+    }
+}
+
+fn write_line(out: &mut BytesMut, sigil: u8, body: &[u8], offset: usize) -> Result<(), ProtocolError> {
+    match sigil {
+        b'_' => out.extend_from_slice(RESP2_NULL),
+        b',' | b'(' => write_bulk(out, body),
+        b'#' => {
+            match body {
+                b"t" => out.extend_from_slice(RESP2_TRUE),
+                b"f" => out.extend_from_slice(RESP2_FALSE),
+                _ => return Err(invalid(offset + 1, "boolean")),
+            }
+        },
+        _ => {
+            out.extend_from_slice(&[sigil]);
+            out.extend_from_slice(body);
+            out.extend_from_slice(b"\r\n");
+        },
+    }
+
+    Ok(())
+}
+
+fn write_blob(out: &mut BytesMut, sigil: u8, data: &[u8]) {
+    match sigil {
+        b'=' => {
+            // Verbatim strings start with their format, like `txt:`, which RESP2 has no room for.
+            let data = if data.len() >= 4 && data[3] == b':' { &data[4..] } else { data };
+            write_bulk(out, data);
+        },
+        b'!' => {
+            // Simple errors are a single line, so any line breaks in the error have to go.
+            out.extend_from_slice(b"-");
+            for byte in data {
+                out.extend_from_slice(&[if *byte == b'\r' || *byte == b'\n' { b' ' } else { *byte }]);
+            }
+            out.extend_from_slice(b"\r\n");
+        },
+        _ => write_bulk(out, data),
+    }
+}
+
+fn write_bulk(out: &mut BytesMut, data: &[u8]) {
+    write_header(out, b'$', data.len());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_header(out: &mut BytesMut, sigil: u8, len: usize) {
+    let mut len_buf = [b'\0'; 20];
+    let n = itoa::write(&mut len_buf[..], len).unwrap();
+    out.extend_from_slice(&[sigil]);
+    out.extend_from_slice(&len_buf[..n]);
+    out.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn downgrade(response: &[u8]) -> Result<(Downgraded, Vec<u8>), ProtocolError> {
+        let mut rd = BytesMut::from(response);
+        match ResponseDowngrader::default().poll(&mut rd)? {
+            Async::Ready(downgraded) => Ok((downgraded, rd.to_vec())),
+            Async::NotReady => panic!("response should have been complete"),
+        }
+    }
+
+    fn assert_translated(response: &[u8], expected: &[u8]) {
+        let (downgraded, translated) = downgrade(response).unwrap();
+        assert_eq!(downgraded, Downgraded::Translated);
+        assert_eq!(String::from_utf8_lossy(&translated), String::from_utf8_lossy(expected));
+    }
+
+    #[test]
+    fn test_resp2_unchanged() {
+        let responses: &[&[u8]] = &[
+            b"+OK\r\n",
+            b"-ERR nope\r\n",
+            b":42\r\n",
+            b"$3\r\nfoo\r\n",
+            b"$-1\r\n",
+            b"*-1\r\n",
+            b"*2\r\n$3\r\nfoo\r\n*1\r\n:1\r\n",
+        ];
+        for response in responses {
+            assert_eq!(downgrade(response).unwrap(), (Downgraded::Unchanged, response.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_map() {
+        assert_translated(b"%2\r\n+a\r\n:1\r\n+b\r\n:2\r\n", b"*4\r\n+a\r\n:1\r\n+b\r\n:2\r\n");
+        assert_translated(b"%0\r\n", b"*0\r\n");
+    }
+
+    #[test]
+    fn test_set() { assert_translated(b"~2\r\n+a\r\n+b\r\n", b"*2\r\n+a\r\n+b\r\n"); }
+
+    #[test]
+    fn test_null() { assert_translated(b"_\r\n", b"$-1\r\n"); }
+
+    #[test]
+    fn test_double() {
+        assert_translated(b",3.14\r\n", b"$4\r\n3.14\r\n");
+        assert_translated(b",-inf\r\n", b"$4\r\n-inf\r\n");
+    }
+
+    #[test]
+    fn test_boolean() {
+        assert_translated(b"#t\r\n", b":1\r\n");
+        assert_translated(b"#f\r\n", b":0\r\n");
+        assert!(downgrade(b"#x\r\n").is_err());
+    }
+
+    #[test]
+    fn test_big_number() {
+        assert_translated(
+            b"(3492890328409238509324850943850943825024385\r\n",
+            b"$43\r\n3492890328409238509324850943850943825024385\r\n",
+        );
+    }
+
+    #[test]
+    fn test_verbatim_string() { assert_translated(b"=15\r\ntxt:Some string\r\n", b"$11\r\nSome string\r\n"); }
+
+    #[test]
+    fn test_blob_error() {
+        assert_translated(b"!21\r\nSYNTAX invalid syntax\r\n", b"-SYNTAX invalid syntax\r\n");
+        assert_translated(b"!10\r\nERR two\r\nl\r\n", b"-ERR two  l\r\n");
+    }
+
+    #[test]
+    fn test_attribute() {
+        let response = b"|1\r\n+key-popularity\r\n%1\r\n$1\r\na\r\n,0.19\r\n*2\r\n:2039123\r\n:9543892\r\n";
+        assert_translated(response, b"*2\r\n:2039123\r\n:9543892\r\n");
+
+        // An attribute on a value in an aggregate doesn't count as a value of its own.
+        assert_translated(b"*2\r\n|1\r\n+a\r\n+b\r\n:1\r\n:2\r\n", b"*2\r\n:1\r\n:2\r\n");
+    }
+
+    #[test]
+    fn test_push() {
+        let (downgraded, rest) = downgrade(b">3\r\n$7\r\nmessage\r\n$3\r\nfoo\r\n$3\r\nbar\r\n+OK\r\n").unwrap();
+        assert_eq!(downgraded, Downgraded::Push);
+        assert_eq!(rest, b"+OK\r\n".to_vec());
+
+        // Pushes inside of a response are just another aggregate.
+        assert_translated(b"*1\r\n>1\r\n+a\r\n", b"*1\r\n*1\r\n+a\r\n");
+    }
+
+    #[test]
+    fn test_nested() {
+        assert_translated(
+            b"*2\r\n%1\r\n+a\r\n#t\r\n~1\r\n_\r\n",
+            b"*2\r\n*2\r\n+a\r\n:1\r\n*1\r\n$-1\r\n",
+        );
+    }
+
+    #[test]
+    fn test_rest_of_buffer_kept() {
+        let (downgraded, translated) = downgrade(b"#t\r\n%1\r\n+a\r\n+b\r\n").unwrap();
+        assert_eq!(downgraded, Downgraded::Translated);
+        assert_eq!(translated, b":1\r\n%1\r\n+a\r\n+b\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_partial_response() {
+        let response = b"*3\r\n%1\r\n$3\r\nfoo\r\n,1.5\r\n=8\r\ntxt:abcd\r\n#f\r\n";
+        let mut downgrader = ResponseDowngrader::default();
+        let mut rd = BytesMut::new();
+
+        // Nothing happens until every last byte of the response has arrived.
+        for byte in &response[..response.len() - 1] {
+            rd.extend_from_slice(&[*byte]);
+            assert_eq!(downgrader.poll(&mut rd).unwrap(), Async::NotReady);
+        }
+        rd.extend_from_slice(&response[response.len() - 1..]);
+        assert_eq!(downgrader.poll(&mut rd).unwrap(), Async::Ready(Downgraded::Translated));
+        assert_eq!(&rd[..], &b"*3\r\n*2\r\n$3\r\nfoo\r\n$3\r\n1.5\r\n$4\r\nabcd\r\n:0\r\n"[..]);
+    }
+
+    #[test]
+    fn test_deeply_nested() {
+        let depth = 100_000;
+        let mut response = b"*1\r\n".repeat(depth);
+        response.extend_from_slice(b"#t\r\n");
+
+        let (downgraded, translated) = downgrade(&response).unwrap();
+        assert_eq!(downgraded, Downgraded::Translated);
+        assert_eq!(translated.len(), response.len());
+        assert!(translated.ends_with(b"*1\r\n:1\r\n"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(downgrade(b"?1\r\n").is_err());
+        assert!(downgrade(b"%x\r\n").is_err());
+        assert!(downgrade(b"=3\r\nabcd\r\n#t\r\n").is_err());
+    }
+}
@@ -17,9 +17,14 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use phf::phf_set;
+use phf::phf_ordered_set;
 
-static VALID_COMMANDS: phf::Set<&'static str> = phf_set! {
+// Anything longer than the longest command we support can't be one of them.
+const MAX_COMMAND_LEN: usize = 32;
+
+// The set is ordered so that every command has a stable index, which lets per-command state live
+// in a plain table rather than being looked up by name.
+static VALID_COMMANDS: phf::OrderedSet<&'static str> = phf_ordered_set! {
     "DEL",
     "DUMP",
     "EXISTS",
@@ -33,6 +38,7 @@ static VALID_COMMANDS: phf::Set<&'static str> = phf_set! {
     "SORT",
     "TTL",
     "TYPE",
+    "UNLINK",
     "APPEND",
     "BITCOUNT",
     "BITPOS",
@@ -82,6 +88,9 @@ static VALID_COMMANDS: phf::Set<&'static str> = phf_set! {
     "RPOPLPUSH",
     "RPUSH",
     "RPUSHX",
+    "BLPOP",
+    "BRPOP",
+    "BRPOPLPUSH",
     "SADD",
     "SCARD",
     "SDIFF",
@@ -122,34 +131,354 @@ static VALID_COMMANDS: phf::Set<&'static str> = phf_set! {
     "PFMERGE",
     "EVAL",
     "EVALSHA",
+    "WAIT",
+    "PUBLISH",
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PSUBSCRIBE",
+    "PUNSUBSCRIBE",
     "PING",
+    "ECHO",
+    "COMMAND",
     "QUIT",
 };
 
-pub fn check_command_validity(cmd: &[u8]) -> bool {
+// Commands that can create a key but have no way to also give it a TTL.  Commands that store their
+// result, or move something, into another key create that key, rather than the one they're routed by.
+const TTL_LESS_CREATORS: &[&str] = &[
+    "SETNX",
+    "GETSET",
+    "MSET",
+    "APPEND",
+    "INCR",
+    "INCRBY",
+    "INCRBYFLOAT",
+    "DECR",
+    "DECRBY",
+    "SETRANGE",
+    "SETBIT",
+    "HSET",
+    "HSETNX",
+    "HMSET",
+    "HINCRBY",
+    "HINCRBYFLOAT",
+    "LPUSH",
+    "RPUSH",
+    "RPOPLPUSH",
+    "SADD",
+    "SMOVE",
+    "SUNIONSTORE",
+    "SINTERSTORE",
+    "SDIFFSTORE",
+    "ZADD",
+    "ZINCRBY",
+    "ZUNIONSTORE",
+    "ZINTERSTORE",
+    "PFADD",
+    "PFMERGE",
+];
+
+// Commands, other than the ones that can create a key without a TTL, that write to a single key.
+const KEYED_WRITERS: &[&str] = &["SET", "SETEX", "PSETEX", "DEL", "UNLINK", "EXPIRE", "PEXPIRE", "PERSIST"];
+
+// Commands that never write, and so can be answered by a replica.
+const READ_ONLY: &[&str] = &[
+    "EXISTS",
+    "TTL",
+    "PTTL",
+    "TYPE",
+    "DUMP",
+    "GET",
+    "MGET",
+    "GETBIT",
+    "GETRANGE",
+    "STRLEN",
+    "BITCOUNT",
+    "BITPOS",
+    "HEXISTS",
+    "HGET",
+    "HGETALL",
+    "HKEYS",
+    "HLEN",
+    "HMGET",
+    "HVALS",
+    "HSCAN",
+    "LINDEX",
+    "LLEN",
+    "LRANGE",
+    "SCARD",
+    "SDIFF",
+    "SINTER",
+    "SISMEMBER",
+    "SMEMBERS",
+    "SRANDMEMBER",
+    "SUNION",
+    "SSCAN",
+    "ZCARD",
+    "ZCOUNT",
+    "ZLEXCOUNT",
+    "ZRANGE",
+    "ZRANGEBYLEX",
+    "ZRANGEBYSCORE",
+    "ZRANK",
+    "ZREVRANGE",
+    "ZREVRANGEBYSCORE",
+    "ZREVRANK",
+    "ZSCORE",
+    "ZSCAN",
+    "PFCOUNT",
+];
+
+// Commands that look up the value of the key they're routed by.
+const SINGLE_KEY_LOOKUPS: &[&str] = &["GET", "HGET"];
+
+// Commands that look up the value of every key they're given.
+const MULTI_KEY_LOOKUPS: &[&str] = &["MGET", "EXISTS"];
+
+// Commands that don't take a key at all.
+const KEYLESS: &[&str] = &["PING", "ECHO", "COMMAND", "QUIT", "WAIT"];
+
+// Commands whose first two arguments are keys: a source and a destination.
+const TWO_KEYS: &[&str] = &["RPOPLPUSH", "BRPOPLPUSH", "SMOVE"];
+
+// Commands where every argument is a key.
+const ALL_KEYS: &[&str] = &[
+    "DEL",
+    "EXISTS",
+    "UNLINK",
+    "MGET",
+    "SDIFF",
+    "SDIFFSTORE",
+    "SINTER",
+    "SINTERSTORE",
+    "SUNION",
+    "SUNIONSTORE",
+    "PFCOUNT",
+    "PFMERGE",
+];
+
+// Commands whose arguments alternate between a key and its value.
+const PAIRED_KEYS: &[&str] = &["MSET"];
+
+// Commands where every argument but the last, which is a timeout, is a key.
+const ALL_BUT_LAST_KEYS: &[&str] = &["BLPOP", "BRPOP"];
+
+// Commands that give the number of keys they take, followed by the keys themselves.
+const COUNTED_KEYS: &[&str] = &["EVAL", "EVALSHA"];
+
+// Commands that take a destination key, and then the number of source keys followed by them.
+const DESTINATION_AND_COUNTED_KEYS: &[&str] = &["ZINTERSTORE", "ZUNIONSTORE"];
+
+// Commands that can hold on to the connection they're sent over until they time out, keeping
+// everything queued behind them from being answered.
+const BLOCKING: &[&str] = &["BLPOP", "BRPOP", "BRPOPLPUSH", "WAIT"];
+
+// Commands that change what the connection they're sent over is subscribed to, after which it's
+// pushed messages rather than answered once per command.
+const PUBSUB: &[&str] = &["SUBSCRIBE", "UNSUBSCRIBE", "PSUBSCRIBE", "PUNSUBSCRIBE"];
+
+lazy_static! {
+    // Indexed the same as the set of valid commands, so that resolving a command is one lookup.
+    static ref COMMAND_INFO: Vec<CommandInfo> = VALID_COMMANDS
+        .iter()
+        .enumerate()
+        .map(|(id, name)| CommandInfo::new(id, *name))
+        .collect();
+}
+
+/// How many keys a command looks up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LookupKeys {
+    /// Only the key it's routed by.
+    First,
+    /// Every argument is a key.
+    All,
+}
+
+/// Which arguments of a command are keys.
+///
+/// Positions are counted from the first argument after the command itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyPositions {
+    /// No arguments are keys.
+    None,
+    /// Only the first argument is a key.
+    First,
+    /// The first two arguments are keys.
+    FirstTwo,
+    /// Every argument is a key.
+    All,
+    /// Every other argument, starting with the first, is a key.
+    Paired,
+    /// Every argument but the last is a key.
+    AllButLast,
+    /// The first argument is the number of keys, which immediately follow it.
+    Counted,
+    /// The first argument is a key, the second is the number of keys, which immediately follow it.
+    DestinationAndCounted,
+}
+
+/// Everything we need to know about a command in order to route it, account for it, and enforce
+/// policies on it.
+#[derive(Debug, PartialEq)]
+pub struct CommandInfo {
+    id: usize,
+    name: &'static str,
+    writes_key: bool,
+    creates_without_ttl: bool,
+    read_only: bool,
+    lookup: Option<LookupKeys>,
+    keys: KeyPositions,
+    blocking: bool,
+    pubsub: bool,
+}
+
+impl CommandInfo {
+    fn new(id: usize, name: &'static str) -> CommandInfo {
+        let creates_without_ttl = TTL_LESS_CREATORS.contains(&name);
+        let lookup = if SINGLE_KEY_LOOKUPS.contains(&name) {
+            Some(LookupKeys::First)
+        } else if MULTI_KEY_LOOKUPS.contains(&name) {
+            Some(LookupKeys::All)
+        } else {
+            None
+        };
+        let keys = if KEYLESS.contains(&name) {
+            KeyPositions::None
+        } else if TWO_KEYS.contains(&name) {
+            KeyPositions::FirstTwo
+        } else if ALL_KEYS.contains(&name) {
+            KeyPositions::All
+        } else if PAIRED_KEYS.contains(&name) {
+            KeyPositions::Paired
+        } else if ALL_BUT_LAST_KEYS.contains(&name) {
+            KeyPositions::AllButLast
+        } else if COUNTED_KEYS.contains(&name) {
+            KeyPositions::Counted
+        } else if DESTINATION_AND_COUNTED_KEYS.contains(&name) {
+            KeyPositions::DestinationAndCounted
+        } else {
+            KeyPositions::First
+        };
+
+        CommandInfo {
+            id,
+            name,
+            writes_key: creates_without_ttl || KEYED_WRITERS.contains(&name),
+            creates_without_ttl,
+            read_only: READ_ONLY.contains(&name),
+            lookup,
+            keys,
+            blocking: BLOCKING.contains(&name),
+            pubsub: PUBSUB.contains(&name),
+        }
+    }
+
+    /// Gets the index of this command in the set of commands we support.
+    pub fn id(&self) -> usize { self.id }
+
+    /// Gets the name of this command, in uppercase.
+    pub fn name(&self) -> &'static str { self.name }
+
+    /// Whether or not this command writes to the key it's routed by.
+    pub fn writes_key(&self) -> bool { self.writes_key }
+
+    /// Whether or not this command can create a key without giving it a TTL.
+    pub fn creates_without_ttl(&self) -> bool { self.creates_without_ttl }
+
+    /// Whether or not this command never writes anything.
+    pub fn is_read_only(&self) -> bool { self.read_only }
+
+    /// Gets which keys this command looks up, if it looks any up.
+    pub fn lookup(&self) -> Option<LookupKeys> { self.lookup }
+
+    /// Gets which arguments of this command are keys.
+    pub fn keys(&self) -> KeyPositions { self.keys }
+
+    /// Whether or not this command can block the connection it's sent over.
+    pub fn is_blocking(&self) -> bool { self.blocking }
+
+    /// Whether or not this command changes what the connection it's sent over is subscribed to.
+    pub fn is_pubsub(&self) -> bool { self.pubsub }
+
+    /// Whether or not the argument at the given position, counted from the first argument after the
+    /// command, is a key.
+    ///
+    /// Commands that count their keys need the count, which is the argument at the position given
+    /// by `counted_at`, to know where their keys stop.  Commands whose keys are every argument but
+    /// the last need the number of keys, which is one less than their number of arguments.
+    pub fn is_key(&self, position: usize, count: Option<usize>) -> bool {
+        match self.keys {
+            KeyPositions::None => false,
+            KeyPositions::First => position == 0,
+            KeyPositions::FirstTwo => position < 2,
+            KeyPositions::All => true,
+            KeyPositions::Paired => position % 2 == 0,
+            KeyPositions::AllButLast => position < count.unwrap_or(0),
+            KeyPositions::Counted => position > 0 && position <= count.unwrap_or(0),
+            KeyPositions::DestinationAndCounted => {
+                position == 0 || (position > 1 && position <= count.unwrap_or(0) + 1)
+            },
+        }
+    }
+
+    /// Gets the position of the argument that holds the number of keys, for commands that count
+    /// their keys.
+    pub fn counted_at(&self) -> Option<usize> {
+        match self.keys {
+            KeyPositions::Counted => Some(0),
+            KeyPositions::DestinationAndCounted => Some(1),
+            _ => None,
+        }
+    }
+}
+
+pub fn check_command_validity(cmd: &[u8]) -> bool { get_command_index(cmd).is_some() }
+
+/// Gets everything we know about the given command, if we support it.
+///
+/// Commands are matched case-insensitively, without allocating.
+pub fn get_command_info(cmd: &[u8]) -> Option<&'static CommandInfo> {
+    get_command_index(cmd).map(|id| &COMMAND_INFO[id])
+}
+
+/// Gets everything we know about every command we support, in the order of their indexes.
+pub fn get_commands() -> impl Iterator<Item = &'static CommandInfo> { COMMAND_INFO.iter() }
+
+/// Gets the index of the given command in the set of commands we support, if we support it.
+///
+/// Commands are matched case-insensitively, and indexes are always less than `get_command_count`.
+pub fn get_command_index(cmd: &[u8]) -> Option<usize> {
+    let count = cmd.len();
+    if count > MAX_COMMAND_LEN {
+        return None;
+    }
+
     // This is goofy but redis only supports commands with ASCII characters, so we munge
     // these bytes to make sure that, if they were lowercase ASCII, they now become
     // uppercase ASCII... and we do it by hand instead of using str::to_uppercase because
     // this is 2x as fast. Really feels stupid to pay a constant perf penalty if we don't
     // really have to.  Could probably unroll this to work on 8-byte chunks, 4-byte chunks,
     // etc, but that'd require full on pointers and this is good enough for now, I think.
-    let mut c = cmd.to_owned();
-    let m = c.as_mut_slice();
+    let mut c = [0u8; MAX_COMMAND_LEN];
+    let m = &mut c[..count];
 
-    let count = m.len();
     let mut offset = 0;
 
     while offset < count {
         // This is just a neat invariant of ASCII where lower/uppercase letters are only
         // separated by 64 so we can mask it out to force uppercase.
-        m[offset] = m[offset] & 0b11011111;
+        m[offset] = cmd[offset] & 0b11011111;
         offset += 1;
     }
 
     let as_str = unsafe { std::str::from_utf8_unchecked(m) };
-    VALID_COMMANDS.contains(as_str)
+    VALID_COMMANDS.get_index(as_str)
 }
 
+/// Gets the number of commands we support.
+pub fn get_command_count() -> usize { VALID_COMMANDS.len() }
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -168,6 +497,102 @@ mod tests {
         assert!(!check_command_validity(invalid_cmd_2.as_bytes()));
     }
 
+    #[test]
+    fn test_command_index() {
+        let get = get_command_index(b"GET").unwrap();
+        assert_eq!(get_command_index(b"get"), Some(get));
+        assert_eq!(get_command_index(b"GeT"), Some(get));
+        assert!(get < get_command_count());
+        assert_ne!(get_command_index(b"SET"), Some(get));
+        assert_eq!(get_command_index(b"INFO"), None);
+        assert_eq!(get_command_index(&[b'G'; 64]), None);
+    }
+
+    #[test]
+    fn test_command_info() {
+        let set = get_command_info(b"SET").unwrap();
+        assert_eq!(get_command_info(b"set"), Some(set));
+        assert_eq!(get_command_info(b"sEt"), Some(set));
+        assert_eq!(set.id(), get_command_index(b"SET").unwrap());
+        assert_eq!(set.name(), "SET");
+        assert!(set.writes_key());
+        assert!(!set.creates_without_ttl());
+        assert_eq!(set.lookup(), None);
+
+        let incr = get_command_info(b"Incr").unwrap();
+        assert!(incr.writes_key());
+        assert!(incr.creates_without_ttl());
+        assert!(!incr.is_read_only());
+        assert!(get_command_info(b"ttl").unwrap().is_read_only());
+        assert!(!get_command_info(b"EVAL").unwrap().is_read_only());
+
+        assert_eq!(get_command_info(b"hget").unwrap().lookup(), Some(LookupKeys::First));
+        assert_eq!(get_command_info(b"EXISTS").unwrap().lookup(), Some(LookupKeys::All));
+        assert!(!get_command_info(b"ping").unwrap().writes_key());
+
+        assert_eq!(get_command_info(b"ping").unwrap().keys(), KeyPositions::None);
+        assert_eq!(get_command_info(b"get").unwrap().keys(), KeyPositions::First);
+        assert_eq!(get_command_info(b"SMOVE").unwrap().keys(), KeyPositions::FirstTwo);
+        assert_eq!(get_command_info(b"mget").unwrap().keys(), KeyPositions::All);
+        assert_eq!(get_command_info(b"MSET").unwrap().keys(), KeyPositions::Paired);
+        assert_eq!(get_command_info(b"blpop").unwrap().keys(), KeyPositions::AllButLast);
+        assert_eq!(get_command_info(b"eval").unwrap().keys(), KeyPositions::Counted);
+        assert_eq!(get_command_info(b"ZUNIONSTORE").unwrap().keys(), KeyPositions::DestinationAndCounted);
+
+        let mset = get_command_info(b"MSET").unwrap();
+        assert!(mset.is_key(0, None) && !mset.is_key(1, None) && mset.is_key(2, None));
+        let blpop = get_command_info(b"BLPOP").unwrap();
+        assert!(blpop.is_key(0, Some(2)) && blpop.is_key(1, Some(2)) && !blpop.is_key(2, Some(2)));
+        let eval = get_command_info(b"EVAL").unwrap();
+        assert_eq!(eval.counted_at(), Some(0));
+        assert!(!eval.is_key(0, Some(2)) && eval.is_key(2, Some(2)) && !eval.is_key(3, Some(2)));
+        let zunionstore = get_command_info(b"ZUNIONSTORE").unwrap();
+        assert_eq!(zunionstore.counted_at(), Some(1));
+        assert!(zunionstore.is_key(0, Some(1)) && !zunionstore.is_key(1, Some(1)));
+        assert!(zunionstore.is_key(2, Some(1)) && !zunionstore.is_key(3, Some(1)));
+
+        assert!(blpop.is_blocking());
+        assert!(get_command_info(b"wait").unwrap().is_blocking());
+        assert!(!get_command_info(b"LPOP").unwrap().is_blocking());
+
+        assert!(get_command_info(b"subscribe").unwrap().is_pubsub());
+        assert!(get_command_info(b"PUNSUBSCRIBE").unwrap().is_pubsub());
+        assert!(!get_command_info(b"PUBLISH").unwrap().is_pubsub());
+
+        assert_eq!(get_command_info(b"INFO"), None);
+        assert_eq!(get_command_info(b"sett"), None);
+        assert_eq!(get_command_info(b""), None);
+        assert_eq!(get_command_info(&[b'G'; 64]), None);
+    }
+
+    #[test]
+    fn test_command_info_covers_classifications() {
+        // A typo in one of the classification lists would silently leave a command unclassified.
+        let classified = TTL_LESS_CREATORS
+            .iter()
+            .chain(KEYED_WRITERS)
+            .chain(SINGLE_KEY_LOOKUPS)
+            .chain(MULTI_KEY_LOOKUPS)
+            .chain(KEYLESS)
+            .chain(TWO_KEYS)
+            .chain(ALL_KEYS)
+            .chain(PAIRED_KEYS)
+            .chain(ALL_BUT_LAST_KEYS)
+            .chain(COUNTED_KEYS)
+            .chain(DESTINATION_AND_COUNTED_KEYS)
+            .chain(BLOCKING)
+            .chain(PUBSUB);
+        for name in classified {
+            assert!(VALID_COMMANDS.contains(name), "{} is not a valid command", name);
+        }
+    }
+
+    #[bench]
+    fn bench_command_info(b: &mut Bencher) {
+        let cmd = "hIncrByFloat".as_bytes();
+        b.iter(|| get_command_info(cmd));
+    }
+
     #[bench]
     fn bench_valid_lookup(b: &mut Bencher) {
         let valid_cmd = "PFCOUNT".as_bytes();
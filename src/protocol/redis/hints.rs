@@ -0,0 +1,85 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::RedisMessage;
+use bytes::BytesMut;
+
+pub const HINT_NOT_FOLLOWED: &str = "routing hint must be followed by exactly one command";
+const HINT_USAGE: &str = "wrong number of arguments for 'synchrotron route' command";
+const HINT_UNKNOWN: &str = "unknown subcommand for 'synchrotron' command";
+
+/// Parses a routing hint, if the given message is one.
+///
+/// A client that can't put hash tags in its keys sends `SYNCHROTRON ROUTE <key>` right before a
+/// command to have that command routed as if its key was `<key>`.  The hint itself is answered
+/// locally, and never makes it to a backend.  Returns `None` if the message isn't a hint at all, or
+/// the error to answer it with if it's a malformed one.
+pub fn parse_routing_hint(msg: &RedisMessage) -> Option<Result<BytesMut, &'static str>> {
+    let args = match msg {
+        RedisMessage::Bulk(_, args, _) => args,
+        _ => return None,
+    };
+
+    match args.get(0) {
+        Some(RedisMessage::Data(buf, offset)) if buf[*offset..buf.len() - 2].eq_ignore_ascii_case(b"synchrotron") => {},
+        _ => return None,
+    }
+
+    match args.get(1) {
+        Some(RedisMessage::Data(buf, offset)) if buf[*offset..buf.len() - 2].eq_ignore_ascii_case(b"route") => {},
+        Some(_) => return Some(Err(HINT_UNKNOWN)),
+        None => return Some(Err(HINT_USAGE)),
+    }
+
+    match args.get(2) {
+        Some(RedisMessage::Data(buf, offset)) if args.len() == 3 => {
+            Some(Ok(BytesMut::from(&buf[*offset..buf.len() - 2])))
+        },
+        _ => Some(Err(HINT_USAGE)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_hint(cmd: &str) -> Option<Result<BytesMut, &'static str>> {
+        parse_routing_hint(&RedisMessage::from_inline(cmd))
+    }
+
+    #[test]
+    fn test_routing_hint() {
+        assert_eq!(get_hint("SYNCHROTRON ROUTE user:1"), Some(Ok(BytesMut::from(&b"user:1"[..]))));
+        assert_eq!(get_hint("synchrotron route {a}"), Some(Ok(BytesMut::from(&b"{a}"[..]))));
+    }
+
+    #[test]
+    fn test_routing_hint_invalid() {
+        assert_eq!(get_hint("SYNCHROTRON ROUTE"), Some(Err(HINT_USAGE)));
+        assert_eq!(get_hint("SYNCHROTRON ROUTE a b"), Some(Err(HINT_USAGE)));
+        assert_eq!(get_hint("SYNCHROTRON"), Some(Err(HINT_USAGE)));
+        assert_eq!(get_hint("SYNCHROTRON STEER a"), Some(Err(HINT_UNKNOWN)));
+    }
+
+    #[test]
+    fn test_not_routing_hint() {
+        assert_eq!(get_hint("GET synchrotron"), None);
+        assert_eq!(parse_routing_hint(&RedisMessage::Ping), None);
+    }
+}
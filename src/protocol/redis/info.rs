@@ -0,0 +1,83 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{cluster::write_data, RedisMessage};
+use bytes::BytesMut;
+
+/// Answers an `INFO` command locally, with a section describing the proxy itself.
+///
+/// Clients, and the people debugging them, use this to find out which version of the listener
+/// they're talking to, and which configuration generation it was built from.  Sections we don't
+/// have are answered with nothing, like Redis does for sections it doesn't know about.
+pub fn handle_info_command(args: &[RedisMessage], listener_version: usize, config_gen: usize) -> RedisMessage {
+    let section = match args.len() {
+        1 => None,
+        2 => {
+            match args[1] {
+                RedisMessage::Data(ref buf, offset) => Some(buf[offset..buf.len() - 2].to_ascii_lowercase()),
+                _ => return RedisMessage::from_error_str("syntax error"),
+            }
+        },
+        _ => return RedisMessage::from_error_str("wrong number of arguments for 'info' command"),
+    };
+
+    let info = match section.as_ref().map(|s| s.as_slice()) {
+        None | Some(b"proxy") | Some(b"default") | Some(b"all") | Some(b"everything") => {
+            format!("# Proxy\r\nlistener_version:{}\r\nconfig_gen:{}\r\n", listener_version, config_gen)
+        },
+        Some(_) => String::new(),
+    };
+
+    RedisMessage::Raw(write_data(BytesMut::new(), info.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_info_response(cmd: &str) -> RedisMessage {
+        match RedisMessage::from_inline(cmd) {
+            RedisMessage::Bulk(_, args, _) => handle_info_command(&args, 3, 7),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_info_proxy_section() {
+        let expected = b"$43\r\n# Proxy\r\nlistener_version:3\r\nconfig_gen:7\r\n\r\n";
+        for cmd in &["INFO", "info proxy", "INFO ALL"] {
+            match get_info_response(cmd) {
+                RedisMessage::Raw(buf) => assert_eq!(&buf[..], &expected[..]),
+                _ => panic!("expected a raw response to '{}'", cmd),
+            }
+        }
+    }
+
+    #[test]
+    fn test_info_other_section() {
+        match get_info_response("INFO replication") {
+            RedisMessage::Raw(buf) => assert_eq!(&buf[..], b"$0\r\n\r\n"),
+            _ => panic!("expected a raw response"),
+        }
+        assert_eq!(
+            get_info_response("INFO a b"),
+            RedisMessage::from_error_str("wrong number of arguments for 'info' command")
+        );
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{
+    cluster::{write_array_header, write_data, write_integer},
+    filtering::{get_command_count, get_commands, CommandInfo, KeyPositions},
+    RedisMessage,
+};
+use bytes::BytesMut;
+
+/// Answers the commands that never need a backend: `PING`, `ECHO`, `QUIT`, and `COMMAND`.
+///
+/// Returns `None` if the command isn't one of them, or if it was sent with a routing hint, since
+/// the client has asked for it to go to a backend.
+pub fn get_local_response(cmd: &RedisMessage) -> Option<RedisMessage> {
+    let args = match cmd {
+        RedisMessage::Ping => return Some(RedisMessage::Ping),
+        RedisMessage::Quit => return Some(RedisMessage::Quit),
+        RedisMessage::Bulk(_, ref args, _) => args,
+        _ => return None,
+    };
+
+    let name = cmd.get_command()?;
+    let resp = if name.eq_ignore_ascii_case(b"ping") {
+        match args.len() {
+            1 => RedisMessage::Ping,
+            2 => args[1].clone(),
+            _ => RedisMessage::from_error_str("wrong number of arguments for 'ping' command"),
+        }
+    } else if name.eq_ignore_ascii_case(b"echo") {
+        match args.len() {
+            2 => args[1].clone(),
+            _ => RedisMessage::from_error_str("wrong number of arguments for 'echo' command"),
+        }
+    } else if name.eq_ignore_ascii_case(b"quit") {
+        RedisMessage::Quit
+    } else if name.eq_ignore_ascii_case(b"command") {
+        handle_command_command(args)
+    } else {
+        return None;
+    };
+
+    Some(resp)
+}
+
+fn handle_command_command(args: &[RedisMessage]) -> RedisMessage {
+    let subcommand = match args.get(1) {
+        None => return RedisMessage::Raw(get_command_table()),
+        Some(RedisMessage::Data(buf, offset)) => buf[*offset..buf.len() - 2].to_ascii_lowercase(),
+        Some(_) => return RedisMessage::from_error_str("unsupported COMMAND subcommand"),
+    };
+
+    match (subcommand.as_slice(), args.len()) {
+        (b"count", 2) => RedisMessage::from_integer(get_command_count() as i64),
+        _ => RedisMessage::from_error_str("unsupported COMMAND subcommand"),
+    }
+}
+
+/// Describes every command we support, the way Redis does, so that clients know what they can send.
+///
+/// We don't track the arity of commands, only which of their arguments are keys, so the arity
+/// given is the fewest arguments a command with those keys could take.
+fn get_command_table() -> BytesMut {
+    let mut buf = write_array_header(BytesMut::new(), get_command_count());
+    for info in get_commands() {
+        let (arity, first, last, step) = match info.keys() {
+            KeyPositions::None => (-1, 0, 0, 0),
+            KeyPositions::First => (-2, 1, 1, 1),
+            KeyPositions::FirstTwo => (-3, 1, 2, 1),
+            KeyPositions::All => (-2, 1, -1, 1),
+            KeyPositions::Paired => (-3, 1, -1, 2),
+            KeyPositions::AllButLast => (-3, 1, -2, 1),
+            KeyPositions::Counted => (-3, 0, 0, 0),
+            KeyPositions::DestinationAndCounted => (-4, 1, 1, 1),
+        };
+
+        let flags = get_command_flags(info);
+        buf = write_array_header(buf, 6);
+        buf = write_data(buf, info.name().to_ascii_lowercase().as_bytes());
+        buf = write_integer(buf, arity);
+        buf = write_array_header(buf, flags.len());
+        for flag in flags {
+            buf = write_status(buf, flag);
+        }
+        buf = write_integer(buf, first);
+        buf = write_integer(buf, last);
+        buf = write_integer(buf, step);
+    }
+    buf
+}
+
+fn get_command_flags(info: &CommandInfo) -> Vec<&'static str> {
+    let mut flags = Vec::new();
+    if info.writes_key() {
+        flags.push("write");
+    }
+    if info.is_read_only() {
+        flags.push("readonly");
+    }
+    if info.is_pubsub() {
+        flags.push("pubsub");
+    }
+    if info.counted_at().is_some() {
+        flags.push("movablekeys");
+    }
+    flags
+}
+
+fn write_status(mut buf: BytesMut, status: &str) -> BytesMut {
+    buf.extend_from_slice(b"+");
+    buf.extend_from_slice(status.as_bytes());
+    buf.extend_from_slice(b"\r\n");
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use protocol::redis::{read_message, UNLIMITED};
+
+    fn run_command(cmd: &str) -> Option<RedisMessage> { get_local_response(&RedisMessage::from_inline(cmd)) }
+
+    fn get_data(msg: RedisMessage) -> Vec<u8> {
+        match msg {
+            RedisMessage::Data(buf, offset) => buf[offset..buf.len() - 2].to_vec(),
+            msg => panic!("expected data, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_ping_and_echo() {
+        assert_eq!(get_local_response(&RedisMessage::Ping), Some(RedisMessage::Ping));
+        assert_eq!(run_command("PING"), Some(RedisMessage::Ping));
+        assert_eq!(run_command("ping"), Some(RedisMessage::Ping));
+        assert_eq!(get_data(run_command("Ping hello").unwrap()), b"hello");
+        assert_eq!(get_data(run_command("ECHO hello").unwrap()), b"hello");
+
+        let too_many = RedisMessage::from_error_str("wrong number of arguments for 'ping' command");
+        assert_eq!(run_command("PING hello there"), Some(too_many));
+        let too_few = RedisMessage::from_error_str("wrong number of arguments for 'echo' command");
+        assert_eq!(run_command("echo"), Some(too_few));
+    }
+
+    #[test]
+    fn test_quit() {
+        assert_eq!(get_local_response(&RedisMessage::Quit), Some(RedisMessage::Quit));
+        assert_eq!(run_command("quit"), Some(RedisMessage::Quit));
+    }
+
+    #[test]
+    fn test_not_local() {
+        assert_eq!(run_command("GET foo"), None);
+        assert_eq!(run_command("PUBLISH news hello"), None);
+
+        // Anything the client has asked to send somewhere in particular goes there.
+        let hinted = RedisMessage::Routed(BytesMut::from(&b"user"[..]), Box::new(RedisMessage::from_inline("PING")));
+        assert_eq!(get_local_response(&hinted), None);
+    }
+
+    #[test]
+    fn test_command() {
+        assert_eq!(run_command("COMMAND COUNT"), Some(RedisMessage::from_integer(get_command_count() as i64)));
+
+        let unsupported = RedisMessage::from_error_str("unsupported COMMAND subcommand");
+        assert_eq!(run_command("COMMAND INFO get"), Some(unsupported.clone()));
+        assert_eq!(run_command("command count extra"), Some(unsupported));
+
+        // The whole table has to parse as a single reply, with an entry for every command.
+        let mut buf = match run_command("command") {
+            Some(RedisMessage::Raw(buf)) => buf,
+            resp => panic!("expected raw response, got {:?}", resp),
+        };
+        let entries = match read_message(&mut buf, &UNLIMITED) {
+            Ok(Async::Ready((_, RedisMessage::Bulk(_, entries, _)))) => entries,
+            resp => panic!("expected command table, got {:?}", resp),
+        };
+        assert!(buf.is_empty());
+        assert_eq!(entries.len(), get_command_count());
+
+        let expected = b"*6\r\n$3\r\nget\r\n:-2\r\n*1\r\n+readonly\r\n:1\r\n:1\r\n:1\r\n";
+        let get = entries
+            .iter()
+            .find(|entry| entry.get_command() == Some(&b"get"[..]))
+            .expect("no entry for get");
+        assert_eq!(&get.get_buf()[..], &expected[..]);
+    }
+}
@@ -17,17 +17,45 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
+use backend::responses::{ResponseScanner, ResponseSizeTracker};
 use btoi::btoi;
 use bytes::{BufMut, BytesMut};
 use common::{EnqueuedRequests, Message};
+use conf::Secret;
 use futures::prelude::*;
 use itoa;
-use protocol::errors::ProtocolError;
+use protocol::errors::{ParseError, ProtocolError};
+use service::{ClientRegistry, ClientStats};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::io::{write_all, AsyncRead, AsyncWrite, Error, ErrorKind};
 use util::Sizable;
 
+mod auth;
+use self::auth::check_auth;
+mod client;
+use self::client::handle_client_command;
+mod cluster;
+use self::cluster::handle_cluster_command;
+mod debug;
+use self::debug::handle_debug_command;
+mod downgrade;
+use self::downgrade::{Downgraded, ResponseDowngrader};
 mod filtering;
+mod pubsub;
+mod hints;
+mod info;
+use self::info::handle_info_command;
+mod local;
+use self::hints::{parse_routing_hint, HINT_NOT_FOLLOWED};
 use self::filtering::check_command_validity;
+mod transaction;
+use self::transaction::{
+    get_reply_count, get_transaction_buf, get_transaction_size, handle_transaction_command, Transaction,
+};
+pub use self::transaction::get_failure_response;
+pub use self::pubsub::{get_subscription, read_pushed_message};
+pub use self::local::get_local_response;
+pub use self::filtering::{get_command_count, get_command_index, get_command_info, CommandInfo, KeyPositions, LookupKeys};
 
 const MAX_OUTSTANDING_WBUF: usize = 8192;
 
@@ -45,6 +73,100 @@ const REDIS_ERR_BUF: [u8; 5] = [b'-', b'E', b'R', b'R', b' '];
 const REDIS_INT_BUF: [u8; 1] = [REDIS_COMMAND_INTEGER];
 const REDIS_CRLF: [u8; 2] = [b'\r', b'\n'];
 const REDIS_BACKEND_CLOSED: &str = "backend closed prematurely";
+const REDIS_RESPONSE_TOO_LARGE: &str = "response from backend is over max_response_bytes";
+
+// These match the defaults Redis itself uses for `proto-max-bulk-len` and the multibulk limit.
+pub const DEFAULT_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+pub const DEFAULT_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+// This matches the longest inline request Redis accepts, which it also uses as the limit on how long
+// the size at the start of a bulk string or multibulk can be.
+pub const DEFAULT_MAX_INLINE_LEN: usize = 64 * 1024;
+
+// How many malformed requests in a row we put up with before deciding that a client is never going
+// to send us anything we can make sense of.
+pub const DEFAULT_MAX_PROTOCOL_ERRORS: usize = 8;
+
+// Responses from backends have already been through the backend's own limits, so we don't apply
+// any of our own when reading them.
+const UNLIMITED: ProtocolLimits = ProtocolLimits {
+    max_bulk_len: std::usize::MAX,
+    max_multibulk_len: std::usize::MAX,
+    max_inline_len: std::usize::MAX,
+};
+
+/// Listener-level settings for client-facing Redis transports.
+#[derive(Clone, Debug)]
+pub struct RedisTransportConfig {
+    /// Whether to describe ourselves as a single-node cluster when asked `CLUSTER` commands.
+    pub pretend_cluster: bool,
+
+    /// Size limits applied to requests from clients.
+    pub limits: ProtocolLimits,
+
+    /// Whether `DEBUG SLEEP` is simulated for the client that sent it, rather than being refused
+    /// like every other `DEBUG` command.
+    pub allow_debug_simulation: bool,
+
+    /// Whether clients can use `SYNCHROTRON ROUTE` to choose what their next command is routed by.
+    pub routing_hints: bool,
+
+    /// The password clients must `AUTH` with before they can do anything else, if any.
+    pub password: Option<Arc<Secret<String>>>,
+
+    /// How many malformed requests in a row a client can send before we hang up on it.
+    pub max_protocol_errors: usize,
+
+    /// The version of the listener, and the configuration generation it was built from, which are
+    /// reported to clients that ask for `INFO`.
+    pub listener_version: usize,
+    pub config_gen: usize,
+
+    /// The clients of the listener, which clients can kill with `CLIENT KILL`, if allowed.
+    pub clients: Option<Arc<ClientRegistry>>,
+}
+
+impl Default for RedisTransportConfig {
+    fn default() -> RedisTransportConfig {
+        RedisTransportConfig {
+            pretend_cluster: false,
+            limits: ProtocolLimits::default(),
+            allow_debug_simulation: false,
+            routing_hints: false,
+            password: None,
+            max_protocol_errors: DEFAULT_MAX_PROTOCOL_ERRORS,
+            listener_version: 0,
+            config_gen: 0,
+            clients: None,
+        }
+    }
+}
+
+/// The largest sizes a client is allowed to declare in a request.
+///
+/// These are checked as soon as the declared size has been parsed, so a client can't make us
+/// buffer a value, or allocate room for arguments, that we'd only reject once it had all arrived.
+#[derive(Clone, Debug)]
+pub struct ProtocolLimits {
+    /// The most bytes a single bulk string can hold.
+    pub max_bulk_len: usize,
+
+    /// The most arguments a single multibulk request can hold.
+    pub max_multibulk_len: usize,
+
+    /// The most bytes a single line, such as the size at the start of a bulk string, can hold.
+    pub max_inline_len: usize,
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> ProtocolLimits {
+        ProtocolLimits {
+            max_bulk_len: DEFAULT_MAX_BULK_LEN,
+            max_multibulk_len: DEFAULT_MAX_MULTIBULK_LEN,
+            max_inline_len: DEFAULT_MAX_INLINE_LEN,
+        }
+    }
+}
 
 /// A Redis-specific transport.
 pub struct RedisTransport<T>
@@ -55,6 +177,13 @@ where
     rbuf: BytesMut,
     wbuf: BytesMut,
     closed: bool,
+    config: RedisTransportConfig,
+    local_addr: Option<SocketAddr>,
+    routing_hint: Option<BytesMut>,
+    authenticated: bool,
+    transaction: Option<Transaction>,
+    protocol_errors: usize,
+    stats: Option<Arc<ClientStats>>,
 }
 
 pub struct RedisMultipleMessages<T>
@@ -65,6 +194,10 @@ where
     rbuf: BytesMut,
     bytes_read: usize,
     msgs: EnqueuedRequests<RedisMessage>,
+    replies_skipped: usize,
+    responses: Arc<ResponseSizeTracker>,
+    scanner: ResponseScanner,
+    downgrader: ResponseDowngrader,
 }
 
 /// A RESP-based client/server message for Redis.
@@ -79,6 +212,22 @@ where
 ///
 /// This means that callers themselves must chop off any remaining data, such as the trailing CRLF
 /// for data values.
+///
+/// `Bulk` also carries everything we know about its command, if it's one we support, which is
+/// looked up once when the message is built, rather than every time something asks about it.
+///
+/// `Raw` holds a complete, pre-encoded response that we generated ourselves, and is sent to the
+/// client as-is.
+///
+/// `Sleep` is a `DEBUG SLEEP` that we're simulating.  It's never sent to a backend: the client is
+/// sent `+OK` for it once the given duration has passed.
+///
+/// `Routed` is a command that the client gave us a routing hint for, along with the hint.  It's
+/// routed as if the hint was its key, but sent to the backend as-is.
+///
+/// `Transaction` is every command a client queued between `MULTI` and `EXEC`, along with the key
+/// the transaction is routed by.  It's sent to a single backend, wrapped in `MULTI` and `EXEC`, and
+/// only the reply to `EXEC` is sent back to the client.
 #[derive(Clone, Debug, PartialEq)]
 pub enum RedisMessage {
     Null,
@@ -89,7 +238,11 @@ pub enum RedisMessage {
     Error(BytesMut, usize),
     Integer(BytesMut, i64),
     Data(BytesMut, usize),
-    Bulk(BytesMut, Vec<RedisMessage>),
+    Bulk(BytesMut, Vec<RedisMessage>, Option<&'static CommandInfo>),
+    Raw(BytesMut),
+    Sleep(Duration),
+    Routed(BytesMut, Box<RedisMessage>),
+    Transaction(BytesMut, Vec<RedisMessage>),
 }
 
 impl RedisMessage {
@@ -125,7 +278,17 @@ impl RedisMessage {
             buf.unsplit(arg_buf);
         }
 
-        RedisMessage::Bulk(buf, args)
+        RedisMessage::from_bulk(buf, args)
+    }
+
+    /// Builds a bulk message from its buffer and arguments, looking up its command as we go.
+    pub fn from_bulk(buf: BytesMut, args: Vec<RedisMessage>) -> RedisMessage {
+        let info = match args.get(0) {
+            Some(RedisMessage::Data(cmd, offset)) => get_command_info(&cmd[*offset..cmd.len() - 2]),
+            _ => None,
+        };
+
+        RedisMessage::Bulk(buf, args, info)
     }
 
     pub fn from_status(status_str: &str) -> RedisMessage {
@@ -174,7 +337,8 @@ impl RedisMessage {
 
     pub fn get_command(&self) -> Option<&[u8]> {
         match self {
-            RedisMessage::Bulk(_, ref args) => {
+            RedisMessage::Routed(_, inner) => inner.get_command(),
+            RedisMessage::Bulk(_, ref args, _) => {
                 match args.get(0) {
                     Some(RedisMessage::Data(buf, offset)) => {
                         let end = buf.len() - 2;
@@ -187,6 +351,15 @@ impl RedisMessage {
         }
     }
 
+    /// Gets everything we know about this message's command, if it's a command we support.
+    pub fn get_command_info(&self) -> Option<&'static CommandInfo> {
+        match self {
+            RedisMessage::Routed(_, inner) => inner.get_command_info(),
+            RedisMessage::Bulk(_, _, info) => *info,
+            _ => None,
+        }
+    }
+
     pub fn into_resp(self) -> BytesMut {
         match self {
             RedisMessage::Null => BytesMut::from(&REDIS_NULL_BUF[..]),
@@ -197,7 +370,11 @@ impl RedisMessage {
             RedisMessage::Error(buf, _) => buf,
             RedisMessage::Integer(buf, _) => buf,
             RedisMessage::Data(buf, _) => buf,
-            RedisMessage::Bulk(buf, _) => buf,
+            RedisMessage::Bulk(buf, _, _) => buf,
+            RedisMessage::Raw(buf) => buf,
+            RedisMessage::Sleep(_) => BytesMut::from(&REDIS_OK_BUF[..]),
+            RedisMessage::Routed(_, inner) => inner.into_resp(),
+            RedisMessage::Transaction(_, cmds) => get_transaction_buf(&cmds),
         }
     }
 
@@ -211,7 +388,11 @@ impl RedisMessage {
             RedisMessage::Error(ref buf, _) => buf.clone(),
             RedisMessage::Integer(ref buf, _) => buf.clone(),
             RedisMessage::Data(ref buf, _) => buf.clone(),
-            RedisMessage::Bulk(ref buf, _) => buf.clone(),
+            RedisMessage::Bulk(ref buf, _, _) => buf.clone(),
+            RedisMessage::Raw(ref buf) => buf.clone(),
+            RedisMessage::Sleep(_) => BytesMut::from(&REDIS_OK_BUF[..]),
+            RedisMessage::Routed(_, ref inner) => inner.get_buf(),
+            RedisMessage::Transaction(_, ref cmds) => get_transaction_buf(cmds),
         }
     }
 }
@@ -227,7 +408,11 @@ impl Sizable for RedisMessage {
             RedisMessage::Error(ref buf, _) => buf.len(),
             RedisMessage::Integer(ref buf, _) => buf.len(),
             RedisMessage::Data(ref buf, _) => buf.len(),
-            RedisMessage::Bulk(ref buf, _) => buf.len(),
+            RedisMessage::Bulk(ref buf, _, _) => buf.len(),
+            RedisMessage::Raw(ref buf) => buf.len(),
+            RedisMessage::Sleep(_) => REDIS_OK_BUF[..].len(),
+            RedisMessage::Routed(_, ref inner) => inner.size(),
+            RedisMessage::Transaction(_, ref cmds) => get_transaction_size(cmds),
         }
     }
 }
@@ -235,7 +420,7 @@ impl Sizable for RedisMessage {
 impl Message for RedisMessage {
     fn key(&self) -> &[u8] {
         match self {
-            RedisMessage::Bulk(_, ref args) => {
+            RedisMessage::Bulk(_, ref args, _) => {
                 let arg_pos = if args.len() < 2 { 0 } else { 1 };
 
                 match args.get(arg_pos) {
@@ -252,6 +437,9 @@ impl Message for RedisMessage {
             },
             RedisMessage::Ping => b"ping",
             RedisMessage::Quit => b"quit",
+            RedisMessage::Sleep(_) => b"debug",
+            RedisMessage::Routed(ref hint, _) => &hint[..],
+            RedisMessage::Transaction(ref key, _) => &key[..],
             _ => panic!("message should be multi-bulk or data!"),
         }
     }
@@ -259,7 +447,9 @@ impl Message for RedisMessage {
     fn is_inline(&self) -> bool {
         match self {
             RedisMessage::Data(_, _) => false,
-            RedisMessage::Bulk(_, _) => false,
+            RedisMessage::Bulk(_, _, _) => false,
+            RedisMessage::Routed(_, _) => false,
+            RedisMessage::Transaction(_, _) => false,
             _ => true,
         }
     }
@@ -271,15 +461,28 @@ impl<T> RedisTransport<T>
 where
     T: AsyncRead + AsyncWrite,
 {
-    pub fn new(transport: T) -> Self {
+    pub fn new(transport: T, config: RedisTransportConfig, local_addr: Option<SocketAddr>) -> Self {
         RedisTransport {
             transport,
             rbuf: BytesMut::new(),
             wbuf: BytesMut::new(),
             closed: false,
+            config,
+            local_addr,
+            routing_hint: None,
+            authenticated: false,
+            transaction: None,
+            protocol_errors: 0,
+            stats: None,
         }
     }
 
+    /// Sets the counters of the client on the other end, which show whether it's in a transaction.
+    pub fn set_client_stats(mut self, stats: Arc<ClientStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
     fn fill_read_buf(&mut self) -> Poll<(), ProtocolError> {
         loop {
             self.rbuf.reserve(8192);
@@ -306,9 +509,48 @@ where
 
         let socket_closed = self.fill_read_buf()?.is_ready();
 
-        match read_message(&mut self.rbuf) {
+        // Like Redis, we let clients send empty lines, such as keepalives, and just ignore them.
+        skip_empty_lines(&mut self.rbuf);
+
+        match read_message(&mut self.rbuf, &self.config.limits) {
             Ok(Async::Ready((bytes_read, cmd))) => {
                 trace!("[protocol] got message from client! ({} bytes)", bytes_read);
+                self.protocol_errors = 0;
+
+                // Clients that haven't authenticated yet can't do anything else, so nothing they
+                // send is looked at any further.
+                if let Some(ref password) = self.config.password {
+                    if let Some(resp) = check_auth(&cmd, password, &mut self.authenticated) {
+                        return Ok(Async::Ready(Some(resp)));
+                    }
+                }
+
+                // Routing hints are answered right away, and apply to whatever the client sends
+                // next, so long as it's a command that we'd otherwise send to a backend.
+                if self.config.routing_hints {
+                    if let Some(hint) = parse_routing_hint(&cmd) {
+                        // Each hint is meant for exactly one command, so a second hint in a row
+                        // means something's gone wrong, and neither of them should be used.
+                        if self.routing_hint.take().is_some() {
+                            return Ok(Async::Ready(Some(RedisMessage::from_error_str(HINT_NOT_FOLLOWED))));
+                        }
+
+                        let resp = match hint {
+                            Ok(hint) => {
+                                self.routing_hint = Some(hint);
+                                RedisMessage::OK
+                            },
+                            Err(e) => RedisMessage::from_error_str(e),
+                        };
+                        return Ok(Async::Ready(Some(resp)));
+                    }
+                }
+                let routing_hint = self.routing_hint.take();
+
+                // Clients can spell `QUIT` however they like, and it means the same thing no matter
+                // what else they send with it.
+                let is_quit = cmd.get_command().map_or(false, |name| name.eq_ignore_ascii_case(b"quit"));
+                let cmd = if is_quit { RedisMessage::Quit } else { cmd };
 
                 // If client has quit, mark the stream closed so that we return Ready(None) on the
                 // next call to poll.  This is the easiest way to ensure that all messages before
@@ -318,10 +560,58 @@ where
                     self.closed = true;
                 }
 
+                // Transactions are put together here, and only sent on to a backend once the client
+                // has asked for them to be run.
+                let resp = handle_transaction_command(&cmd, &mut self.transaction);
+                if let Some(stats) = self.stats.as_ref() {
+                    stats.set_multi(self.transaction.is_some());
+                }
+                if let Some(resp) = resp {
+                    return Ok(Async::Ready(Some(resp)));
+                }
+
                 // If this command is invalid, kill the transport.  We also give the transport
                 // owner an error message, which is inlined and so we can kill the transport while
                 // still sending an error back to the client themselves.
                 if let Some(cmd_key) = cmd.get_command() {
+                    // Cluster-aware clients ask about the topology before anything else, so we
+                    // answer those locally rather than passing them through to a backend.
+                    if cmd_key.eq_ignore_ascii_case(b"cluster") {
+                        if let RedisMessage::Bulk(_, ref args, _) = cmd {
+                            let resp = handle_cluster_command(args, self.local_addr, self.config.pretend_cluster);
+                            return Ok(Async::Ready(Some(resp)));
+                        }
+                    }
+
+                    // So are `CLIENT` commands, which are about the client's connection to us.
+                    if cmd_key.eq_ignore_ascii_case(b"client") {
+                        if let RedisMessage::Bulk(_, ref args, _) = cmd {
+                            let stats = self.stats.as_ref().map(|stats| stats.as_ref());
+                            let clients = self.config.clients.as_ref().map(|clients| clients.as_ref());
+                            let resp = handle_client_command(args, stats, clients);
+                            return Ok(Async::Ready(Some(resp)));
+                        }
+                    }
+
+                    // `INFO` is about whoever the client is talking to, which is us, not a backend.
+                    if cmd_key.eq_ignore_ascii_case(b"info") {
+                        if let RedisMessage::Bulk(_, ref args, _) = cmd {
+                            let config = &self.config;
+                            let resp = handle_info_command(args, config.listener_version, config.config_gen);
+                            return Ok(Async::Ready(Some(resp)));
+                        }
+                    }
+
+                    // Developers use `DEBUG SLEEP` to test their timeouts, and we can do that for
+                    // them without tying up a backend connection everyone else is using, too.
+                    if self.config.allow_debug_simulation && cmd_key.eq_ignore_ascii_case(b"debug") {
+                        if let RedisMessage::Bulk(_, ref args, _) = cmd {
+                            if let Some(resp) = handle_debug_command(args) {
+                                return Ok(Async::Ready(Some(resp)));
+                            }
+                        }
+                    }
+
                     if !check_command_validity(cmd_key) {
                         self.closed = true;
 
@@ -330,8 +620,73 @@ where
                     }
                 }
 
+                let cmd = match (routing_hint, cmd) {
+                    (Some(hint), cmd @ RedisMessage::Bulk(_, _, _)) => RedisMessage::Routed(hint, Box::new(cmd)),
+                    (Some(_), RedisMessage::Quit) => RedisMessage::Quit,
+                    (Some(_), _) => RedisMessage::from_error_str(HINT_NOT_FOLLOWED),
+                    (None, cmd) => cmd,
+                };
+
+                let cmd = match self.transaction {
+                    Some(ref mut transaction) => transaction.queue(cmd),
+                    None => cmd,
+                };
+
                 Ok(Async::Ready(Some(cmd)))
             },
+            Err(ProtocolError::InvalidProtocol(e)) => {
+                // Let the client know what was wrong with what it sent us, but keep the captured
+                // bytes to ourselves.  We throw away everything up to the end of the line the error
+                // was found on, and carry on from there in the hope that it's where the next request
+                // starts.  If that line hasn't ended yet, or the client keeps sending us things we
+                // can't parse, we hang up, like Redis does.
+                //
+                // A multibulk, though, spans many lines, and once any of them can't be trusted,
+                // neither can any guess at where it ends.  Carrying on from the next line could
+                // well mean running part of its arguments as a request of their own, so we hang up
+                // on those straight away.
+                error!("[protocol] malformed request from client: {}", e);
+                self.protocol_errors += 1;
+                let multibulk = self.rbuf.first() == Some(&REDIS_COMMAND_BULK);
+                match find_line_end(&self.rbuf, e.offset()) {
+                    Some(end) if !multibulk && self.protocol_errors < self.config.max_protocol_errors => {
+                        let _ = self.rbuf.split_to(end);
+                        debug!("[protocol] skipped {} bytes of malformed request from client", end);
+                    },
+                    _ => {
+                        debug!("[protocol] closing client after {} malformed requests", self.protocol_errors);
+                        self.closed = true;
+                    },
+                }
+                self.routing_hint = None;
+                if let Some(ref mut transaction) = self.transaction {
+                    transaction.abort();
+                }
+
+                let emsg = RedisMessage::from_error_str(&format!("Protocol error: {}", e.client_detail()));
+                Ok(Async::Ready(Some(emsg)))
+            },
+            Err(ProtocolError::LimitExceeded(detail)) => {
+                // If the whole offending request is already buffered, we know exactly where it ends,
+                // so we can throw it away and carry on with whatever comes after it.  Otherwise,
+                // we'd have to guess where the next request starts, so we hang up, like Redis does.
+                match read_message_internal(&mut self.rbuf, &UNLIMITED) {
+                    Ok(Async::Ready((bytes_skipped, _))) => {
+                        debug!("[protocol] skipped {} bytes of oversized request from client", bytes_skipped);
+                    },
+                    _ => {
+                        debug!("[protocol] closing client after oversized request: {}", detail);
+                        self.closed = true;
+                    },
+                }
+                self.routing_hint = None;
+                if let Some(ref mut transaction) = self.transaction {
+                    transaction.abort();
+                }
+
+                let emsg = RedisMessage::from_error_str(&format!("Protocol error: {}", detail));
+                Ok(Async::Ready(Some(emsg)))
+            },
             Err(e) => Err(e),
             _ => {
                 if socket_closed {
@@ -384,12 +739,16 @@ impl<T> RedisMultipleMessages<T>
 where
     T: AsyncRead,
 {
-    pub fn new(transport: T, msgs: EnqueuedRequests<RedisMessage>) -> Self {
+    pub fn new(transport: T, msgs: EnqueuedRequests<RedisMessage>, responses: Arc<ResponseSizeTracker>) -> Self {
         RedisMultipleMessages {
             transport: Some(transport),
             rbuf: BytesMut::new(),
             bytes_read: 0,
             msgs,
+            replies_skipped: 0,
+            responses,
+            scanner: ResponseScanner::default(),
+            downgrader: ResponseDowngrader::default(),
         }
     }
 
@@ -403,6 +762,23 @@ where
             if n == 0 {
                 return Ok(Async::Ready(()));
             }
+
+            // There's no sense in reading any more of a response that we're going to abandon.
+            if self.get_oversized_response().is_some() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+
+    /// Gets the size the response at the front of the read buffer has declared, if it's over the
+    /// maximum response size.
+    fn get_oversized_response(&mut self) -> Option<usize> {
+        let max_bytes = self.responses.max_bytes()?;
+        let declared = self.scanner.scan(&self.rbuf);
+        if declared > max_bytes {
+            Some(declared)
+        } else {
+            None
         }
     }
 }
@@ -418,17 +794,65 @@ where
         let socket_closed = self.fill_read_buf()?.is_ready();
 
         loop {
+            // Our clients only speak RESP2, so anything a backend sends using RESP3 types has to be
+            // translated before it's parsed, and pushes, which answer nothing, are dropped.
+            match self.downgrader.poll(&mut self.rbuf)? {
+                Async::Ready(Downgraded::Push) => {
+                    self.scanner.reset();
+                    self.responses.record_push();
+                    continue;
+                },
+                Async::Ready(Downgraded::Translated) => self.scanner.reset(),
+                Async::Ready(Downgraded::Unchanged) | Async::NotReady => {},
+            }
+
             // We've collected all the messages, time to return.
             if self.msgs.is_empty() {
+                // Responses are matched to requests purely by order, so if the backend sent us
+                // anything beyond the responses we asked for, the next batch on this connection
+                // would be handed responses meant for someone else.  The connection can't be
+                // trusted anymore, so make sure it gets thrown away.
+                if !self.rbuf.is_empty() {
+                    return Err(ProtocolError::BackendOutOfSync);
+                }
+
                 return Ok(Async::Ready((self.transport.take().unwrap(), self.bytes_read)));
             }
 
-            let result = read_message(&mut self.rbuf);
+            // If the response is too large to read, the rest of it is still on its way, and we can't
+            // count on being able to skip over it, so the connection has to go.
+            if let Some(declared) = self.get_oversized_response() {
+                let mut qmsg = self.msgs.remove(0);
+                {
+                    let request = qmsg.request();
+                    let command = request.get_command().unwrap_or(&[]);
+                    self.responses.record_rejected(command, qmsg.key(), declared);
+                }
+
+                qmsg.fulfill(RedisMessage::from_error_str(REDIS_RESPONSE_TOO_LARGE));
+                return Err(ProtocolError::LimitExceeded("response too large"));
+            }
+
+            let result = read_message(&mut self.rbuf, &UNLIMITED);
             match result {
                 Ok(Async::Ready((bytes_read, msg))) => {
                     trace!("[protocol] got message from server! ({} bytes)", bytes_read);
+                    self.scanner.reset();
+
+                    // Some requests are answered more than once, but only the last reply is the one
+                    // that goes back to the client.
+                    if self.replies_skipped + 1 < get_reply_count(self.msgs[0].request()) {
+                        self.replies_skipped += 1;
+                        continue;
+                    }
+                    self.replies_skipped = 0;
 
                     let mut qmsg = self.msgs.remove(0);
+                    {
+                        let request = qmsg.request();
+                        let command = request.get_command().unwrap_or(&[]);
+                        self.responses.record(command, qmsg.key(), bytes_read);
+                    }
                     qmsg.fulfill(msg)
                 },
                 Err(e) => return Err(e),
@@ -438,7 +862,8 @@ where
                         // the client with errors.
                         let err = RedisMessage::from_error_str(REDIS_BACKEND_CLOSED);
                         while let Some(mut qmsg) = self.msgs.pop() {
-                            qmsg.fulfill(err.clone())
+                            let resp = get_failure_response(qmsg.request()).unwrap_or_else(|| err.clone());
+                            qmsg.fulfill(resp)
                         }
 
                         Err(ProtocolError::BackendClosedPrematurely)
@@ -451,14 +876,16 @@ where
     }
 }
 
-pub fn read_messages<T>(rx: T, msgs: EnqueuedRequests<RedisMessage>) -> RedisMultipleMessages<T>
+pub fn read_messages<T>(
+    rx: T, msgs: EnqueuedRequests<RedisMessage>, responses: Arc<ResponseSizeTracker>,
+) -> RedisMultipleMessages<T>
 where
     T: AsyncRead,
 {
-    RedisMultipleMessages::new(rx, msgs)
+    RedisMultipleMessages::new(rx, msgs, responses)
 }
 
-fn read_message(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+fn read_message(rd: &mut BytesMut, limits: &ProtocolLimits) -> Poll<(usize, RedisMessage), ProtocolError> {
     // Check to see if we got any inline commands.
     //
     // This is either shortform commands -- like PING or QUIT -- or hard-coded responses like an OK
@@ -468,7 +895,29 @@ fn read_message(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError>
         return Ok(Async::Ready(msg_tuple));
     }
 
-    read_message_internal(rd)
+    read_message_internal(rd, limits).map_err(|e| e.with_context(&rd[..]))
+}
+
+/// Consumes any empty lines at the front of the buffer, returning how many bytes were consumed.
+fn skip_empty_lines(rd: &mut BytesMut) -> usize {
+    let n = rd.iter().take_while(|b| **b == b'\r' || **b == b'\n').count();
+    if n > 0 {
+        let _ = rd.split_to(n);
+    }
+    n
+}
+
+/// Finds where the line containing the given offset ends, including its CRLF, if it has ended yet.
+fn find_line_end(rd: &BytesMut, offset: usize) -> Option<usize> {
+    let offset = offset.min(rd.len());
+    rd[offset..]
+        .windows(2)
+        .position(|bytes| bytes == b"\r\n")
+        .map(|pos| offset + pos + 2)
+}
+
+fn invalid(offset: usize, expected: &'static str) -> ProtocolError {
+    ProtocolError::InvalidProtocol(ParseError::new(offset, expected))
 }
 
 fn read_inline_messages(rd: &mut BytesMut) -> Option<(usize, RedisMessage)> {
@@ -510,7 +959,7 @@ fn read_inline_messages(rd: &mut BytesMut) -> Option<(usize, RedisMessage)> {
     None
 }
 
-fn read_message_internal(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+fn read_message_internal(rd: &mut BytesMut, limits: &ProtocolLimits) -> Poll<(usize, RedisMessage), ProtocolError> {
     // Try reading a single byte to see if we have a message.  Match it against known
     // message types, and process accordingly.
     let first = match rd.len() {
@@ -522,51 +971,49 @@ fn read_message_internal(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), Proto
         None => Ok(Async::NotReady),
         Some(t) => {
             match &t {
-                &REDIS_COMMAND_BULK => read_bulk(rd),
-                &REDIS_COMMAND_DATA => read_data(rd),
-                &REDIS_COMMAND_STATUS => read_status(rd),
-                &REDIS_COMMAND_ERROR => read_error(rd),
-                &REDIS_COMMAND_INTEGER => read_integer(rd),
-                x => {
-                    debug!("got unknown type sigil: {:?}", x);
-                    Ok(Async::NotReady)
-                },
+                &REDIS_COMMAND_BULK => read_bulk(rd, limits),
+                &REDIS_COMMAND_DATA => read_data(rd, limits),
+                &REDIS_COMMAND_STATUS => read_status(rd, limits),
+                &REDIS_COMMAND_ERROR => read_error(rd, limits),
+                &REDIS_COMMAND_INTEGER => read_integer(rd, limits),
+                _ => Err(invalid(0, "type sigil")),
             }
         },
     }
 }
 
-fn read_line(rd: &BytesMut) -> Poll<usize, ProtocolError> {
-    let result = rd
-        .windows(2)
-        .enumerate()
-        .find(|&(_, bytes)| bytes == b"\r\n")
-        .map(|(i, _)| i);
+fn read_line(rd: &BytesMut, limits: &ProtocolLimits) -> Poll<usize, ProtocolError> {
+    // There's no need to look any further for the end of the line than the longest line we'd accept,
+    // which also stops a client from making us buffer a line that never ends.
+    let max_scan_len = limits.max_inline_len.saturating_add(2);
+    let scan_len = rd.len().min(max_scan_len);
+    let result = rd[..scan_len].windows(2).position(|bytes| bytes == b"\r\n");
 
     match result {
         Some(v) => Ok(Async::Ready(v)),
+        None if scan_len == max_scan_len => Err(ProtocolError::LimitExceeded("too big inline request")),
         None => Ok(Async::NotReady),
     }
 }
 
-fn read_bulk_count(rd: &mut BytesMut) -> Poll<(usize, usize), ProtocolError> {
+fn read_bulk_count(rd: &mut BytesMut, limits: &ProtocolLimits) -> Poll<(usize, usize), ProtocolError> {
     // Make sure there's at least a CRLF-terminated line in the buffer.
-    let pos = try_ready!(read_line(rd));
+    let pos = try_ready!(read_line(rd, limits));
 
     // Try to extract the bulk count integer, leaving the rest.
     let buf = rd.split_to(pos + 2);
     match btoi::<usize>(&buf[1..pos]) {
         Ok(count) => Ok(Async::Ready((pos + 2, count))),
-        Err(_) => Err(ProtocolError::InvalidProtocol),
+        Err(_) => Err(invalid(1, "multibulk length")),
     }
 }
 
-fn read_integer(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+fn read_integer(rd: &mut BytesMut, limits: &ProtocolLimits) -> Poll<(usize, RedisMessage), ProtocolError> {
     // Make sure there's at least a CRLF-terminated line in the buffer.
-    let crlf_pos = try_ready!(read_line(rd));
+    let crlf_pos = try_ready!(read_line(rd, limits));
 
     // Try to extract the integer, leaving the rest.
-    let value = btoi::<i64>(&rd[1..crlf_pos]).map_err(|_| ProtocolError::InvalidProtocol)?;
+    let value = btoi::<i64>(&rd[1..crlf_pos]).map_err(|_| invalid(1, "integer"))?;
 
     // Slice off the entire message.
     let total = crlf_pos + 2;
@@ -575,9 +1022,9 @@ fn read_integer(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError>
     Ok(Async::Ready((total, RedisMessage::Integer(buf, value))))
 }
 
-fn read_status(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+fn read_status(rd: &mut BytesMut, limits: &ProtocolLimits) -> Poll<(usize, RedisMessage), ProtocolError> {
     // Make sure there's at least a CRLF-terminated line in the buffer.
-    let crlf_pos = try_ready!(read_line(rd));
+    let crlf_pos = try_ready!(read_line(rd, limits));
 
     // Slice off the entire message.
     let total = crlf_pos + 2;
@@ -586,9 +1033,9 @@ fn read_status(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError>
     Ok(Async::Ready((total, RedisMessage::Status(buf, 1))))
 }
 
-fn read_error(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+fn read_error(rd: &mut BytesMut, limits: &ProtocolLimits) -> Poll<(usize, RedisMessage), ProtocolError> {
     // Make sure there's at least a CRLF-terminated line in the buffer.
-    let crlf_pos = try_ready!(read_line(rd));
+    let crlf_pos = try_ready!(read_line(rd, limits));
 
     // Slice off the entire message.
     let total = crlf_pos + 2;
@@ -597,29 +1044,41 @@ fn read_error(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
     Ok(Async::Ready((total, RedisMessage::Error(buf, 1))))
 }
 
-fn read_data(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+fn read_data(rd: &mut BytesMut, limits: &ProtocolLimits) -> Poll<(usize, RedisMessage), ProtocolError> {
     // Make sure there's at least a CRLF-terminated line in the buffer.
-    let len_crlf_pos = try_ready!(read_line(rd));
+    let len_crlf_pos = try_ready!(read_line(rd, limits));
 
     match &rd[1] {
         b'-' => {
             // See if this is just a null datum.
-            let null_len = btoi::<i8>(&rd[1..len_crlf_pos]).map_err(|_| ProtocolError::InvalidProtocol)?;
+            let null_len = btoi::<i8>(&rd[1..len_crlf_pos]).map_err(|_| invalid(1, "bulk length"))?;
 
             match null_len {
-                -1 => Ok(Async::Ready((len_crlf_pos + 2, RedisMessage::Null))),
-                _ => Err(ProtocolError::InvalidProtocol),
+                -1 => {
+                    let _ = rd.split_to(len_crlf_pos + 2);
+                    Ok(Async::Ready((len_crlf_pos + 2, RedisMessage::Null)))
+                },
+                _ => Err(invalid(1, "null bulk length of -1")),
             }
         },
         _ => {
             // Try to extract the data length integer, leaving the rest.
-            let len = btoi::<usize>(&rd[1..len_crlf_pos]).map_err(|_| ProtocolError::InvalidProtocol)?;
+            let len = btoi::<usize>(&rd[1..len_crlf_pos]).map_err(|_| invalid(1, "bulk length"))?;
+            if len > limits.max_bulk_len {
+                return Err(ProtocolError::LimitExceeded("invalid bulk length"));
+            }
 
             // See if the actual data is available in the buffer.
             if rd.len() < len_crlf_pos + 2 + len + 2 {
                 return Ok(Async::NotReady);
             }
 
+            // Make sure the data is actually as long as we were told it would be.
+            let data_end = len_crlf_pos + 2 + len;
+            if &rd[data_end..data_end + 2] != b"\r\n" {
+                return Err(invalid(data_end, "CRLF after bulk data"));
+            }
+
             // Slice off the entire message.
             let total = len_crlf_pos + 2 + len + 2;
             let buf = rd.split_to(total);
@@ -629,14 +1088,17 @@ fn read_data(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
     }
 }
 
-fn read_bulk(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+fn read_bulk(rd: &mut BytesMut, limits: &ProtocolLimits) -> Poll<(usize, RedisMessage), ProtocolError> {
     let mut total = 0;
     let mut buf = rd.clone();
 
     // Get the number of items in the command.
-    let (n, count) = try_ready!(read_bulk_count(&mut buf));
+    let (n, count) = try_ready!(read_bulk_count(&mut buf, limits));
     if count < 1 {
-        return Err(ProtocolError::InvalidProtocol);
+        return Err(invalid(1, "positive multibulk length"));
+    }
+    if count > limits.max_multibulk_len {
+        return Err(ProtocolError::LimitExceeded("invalid multibulk length"));
     }
     total += n;
 
@@ -644,7 +1106,7 @@ fn read_bulk(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
     // This can legitimately fail because, at this point, buf might not contain the full message.
     let mut args = Vec::new();
     for _ in 0..count {
-        let (n, msg) = try_ready!(read_message_internal(&mut buf));
+        let (n, msg) = try_ready!(read_message_internal(&mut buf, limits).map_err(|e| e.shifted(total)));
         total += n;
 
         args.push(msg);
@@ -652,7 +1114,7 @@ fn read_bulk(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
 
     // Slice off all the bytes we "read", updating the original in the process, and pass them along.
     let buf = rd.split_to(total);
-    Ok(Async::Ready((total, RedisMessage::Bulk(buf, args))))
+    Ok(Async::Ready((total, RedisMessage::from_bulk(buf, args))))
 }
 
 pub fn write_raw_message<T>(tx: T, msg: RedisMessage) -> impl Future<Item = (T, usize), Error = ProtocolError>
@@ -667,22 +1129,20 @@ where
 }
 
 pub fn write_messages<T>(
-    transport: T, mut msgs: EnqueuedRequests<RedisMessage>,
+    transport: T, msgs: EnqueuedRequests<RedisMessage>,
 ) -> impl Future<Item = (T, EnqueuedRequests<RedisMessage>, usize), Error = ProtocolError>
 where
     T: AsyncWrite,
 {
+    // The requests stay with their responses, so that the responses can be accounted for against
+    // the command and key that asked for them.
     let msgs_len = msgs.len();
     let buf = match msgs_len {
-        1 => {
-            let msg = &mut msgs[0];
-            msg.consume().into_resp()
-        },
+        1 => msgs[0].request().get_buf(),
         _ => {
             let mut buf = BytesMut::new();
-            for msg in &mut msgs {
-                let msg_buf = msg.consume().into_resp();
-                buf.extend_from_slice(&msg_buf[..]);
+            for msg in &msgs {
+                buf.extend_from_slice(&msg.request().get_buf()[..]);
             }
             buf
         },
@@ -697,7 +1157,11 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use backend::responses::ResponseSizeConfiguration;
+    use common::{EnqueuedRequest, MessageResponse};
+    use metrics::get_sink;
     use spectral::prelude::*;
+    use std::io::Cursor;
     use test::Bencher;
 
     static DATA_GET_SIMPLE: &[u8] = b"*2\r\n$3\r\nget\r\n$6\r\nfoobar\r\n";
@@ -706,6 +1170,7 @@ mod tests {
     static DATA_ERROR: &[u8] = b"-ERR warning limit exceeded\r\n";
     static DATA_NULL: &[u8] = b"$-1\r\n";
     static DATA_BULK_WITH_NULL: &[u8] = b"*2\r\n$3\r\nboo\r\n$-1\r\n";
+    static DATA_BULK_WITH_NULL_FIRST: &[u8] = b"*3\r\n$-1\r\n$3\r\nboo\r\n:0\r\n";
     static DATA_INTEGER_1337: &[u8] = b":1337\r\n";
     static DATA_SHORT_CIRCUIT_ZERO_DATA: &[u8] = b"";
     static DATA_SHORT_CIRCUIT_NO_ARRAY_CRLF: &[u8] = b"*2";
@@ -721,11 +1186,58 @@ mod tests {
     static DATA_QUIT_UPPER: &[u8] = b"QUIT\r\n";
     static DATA_QUIT_FULL_LOWER: &[u8] = b"*1\r\n$4\r\nquit\r\n";
     static DATA_QUIT_FULL_UPPER: &[u8] = b"*1\r\n$4\r\nQUIT\r\n";
+    static DATA_INVALID_BULK_COUNT: &[u8] = b"*x\r\nget\r\n";
+    static DATA_INVALID_EMPTY_BULK: &[u8] = b"*0\r\n";
+    static DATA_INVALID_ARG_SIGIL: &[u8] = b"*2\r\n$3\r\nget\r\n!6\r\nfoobar\r\n";
+    static DATA_INVALID_ARG_TOO_LONG: &[u8] = b"*1\r\n$3\r\ngetx\r\n";
+    static DATA_INVALID_INTEGER: &[u8] = b":12a\r\n";
+    static DATA_INVALID_NULL: &[u8] = b"$-2\r\n";
 
     fn get_message_from_buf(buf: &[u8]) -> Poll<RedisMessage, ProtocolError> {
         let mut rd = BytesMut::with_capacity(buf.len());
         rd.put_slice(&buf[..]);
-        read_message(&mut rd).map(|res| res.map(|(_, msg)| msg))
+        read_message(&mut rd, &UNLIMITED).map(|res| res.map(|(_, msg)| msg))
+    }
+
+    fn get_limited_message_from_buf(buf: &[u8], limits: &ProtocolLimits) -> Poll<RedisMessage, ProtocolError> {
+        let mut rd = BytesMut::with_capacity(buf.len());
+        rd.put_slice(&buf[..]);
+        read_message(&mut rd, limits).map(|res| res.map(|(_, msg)| msg))
+    }
+
+    fn get_limit_error_from_buf(buf: &[u8], limits: &ProtocolLimits) -> &'static str {
+        match get_limited_message_from_buf(buf, limits) {
+            Err(ProtocolError::LimitExceeded(detail)) => detail,
+            x => panic!("should have had limit error, got {:?}", x),
+        }
+    }
+
+    fn get_test_limits() -> ProtocolLimits {
+        ProtocolLimits {
+            max_bulk_len: 6,
+            max_multibulk_len: 2,
+            max_inline_len: 8,
+        }
+    }
+
+    fn get_client_responses(buf: &[u8], limits: ProtocolLimits) -> Vec<RedisMessage> {
+        let config = RedisTransportConfig {
+            limits,
+            ..Default::default()
+        };
+        get_client_responses_with_config(buf, config)
+    }
+
+    fn get_client_responses_with_config(buf: &[u8], config: RedisTransportConfig) -> Vec<RedisMessage> {
+        let transport = RedisTransport::new(Cursor::new(buf.to_vec()), config, None);
+        transport.collect().wait().expect("transport should not have failed")
+    }
+
+    fn get_parse_error_from_buf(buf: &[u8]) -> ParseError {
+        match get_message_from_buf(buf) {
+            Err(ProtocolError::InvalidProtocol(e)) => e,
+            _ => panic!("should have had parse error"),
+        }
     }
 
     fn check_data_matches(msg: RedisMessage, data: &[u8]) {
@@ -767,7 +1279,7 @@ mod tests {
 
     fn check_bulk_matches(mut msg: RedisMessage, values: Vec<&[u8]>) {
         match msg {
-            RedisMessage::Bulk(_, ref mut args) => {
+            RedisMessage::Bulk(_, ref mut args, _) => {
                 assert_that(args).has_length(values.len());
                 for value in &values {
                     check_data_matches(args.remove(0), value);
@@ -788,6 +1300,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_resolves_command_info() {
+        match get_message_from_buf(b"*2\r\n$3\r\ngEt\r\n$6\r\nfoobar\r\n") {
+            Ok(Async::Ready(msg)) => assert_eq!(msg.get_command_info(), get_command_info(b"GET")),
+            _ => panic!("should have had message"),
+        }
+
+        match get_message_from_buf(b"*1\r\n$5\r\nbogus\r\n") {
+            Ok(Async::Ready(msg)) => assert_eq!(msg.get_command_info(), None),
+            _ => panic!("should have had message"),
+        }
+    }
+
     #[test]
     fn parse_ok() {
         let res = get_message_from_buf(&DATA_OK);
@@ -840,7 +1365,7 @@ mod tests {
         match res.unwrap() {
             Async::Ready(mut msg) => {
                 match msg {
-                    RedisMessage::Bulk(_, ref mut args) => {
+                    RedisMessage::Bulk(_, ref mut args, _) => {
                         assert_that(args).has_length(2);
                         let arg0 = args.remove(0);
                         let arg1 = args.remove(0);
@@ -855,6 +1380,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_bulk_with_null_first() {
+        let res = get_message_from_buf(&DATA_BULK_WITH_NULL_FIRST);
+        assert_that(&res).is_ok().matches(|val| val.is_ready());
+
+        match res.unwrap() {
+            Async::Ready(mut msg) => {
+                match msg {
+                    RedisMessage::Bulk(_, ref mut args, _) => {
+                        assert_that(args).has_length(3);
+                        assert_eq!(args.remove(0), RedisMessage::Null);
+                        check_data_matches(args.remove(0), b"boo");
+                        check_integer_matches(args.remove(0), 0);
+                    },
+                    _ => panic!("message is not bulk"),
+                }
+            },
+            _ => panic!("should have had message"),
+        }
+    }
+
     #[test]
     fn parse_integer() {
         let res = get_message_from_buf(&DATA_INTEGER_1337);
@@ -902,6 +1448,371 @@ mod tests {
         assert_that(&res).is_ok().matches(|val| val.is_not_ready());
     }
 
+    #[test]
+    fn parse_invalid_offsets() {
+        let cases: Vec<(&[u8], usize, &str)> = vec![
+            (DATA_INVALID_BULK_COUNT, 1, "multibulk length"),
+            (DATA_INVALID_EMPTY_BULK, 1, "positive multibulk length"),
+            (DATA_INVALID_ARG_SIGIL, 13, "type sigil"),
+            (DATA_INVALID_ARG_TOO_LONG, 11, "CRLF after bulk data"),
+            (DATA_INVALID_INTEGER, 1, "integer"),
+            (DATA_INVALID_NULL, 1, "null bulk length of -1"),
+        ];
+
+        for (buf, offset, expected) in cases {
+            let e = get_parse_error_from_buf(buf);
+            assert_eq!(e.offset(), offset);
+            assert_eq!(e.expected(), expected);
+        }
+    }
+
+    #[test]
+    fn parse_invalid_context() {
+        // Small frames are captured whole.
+        let e = get_parse_error_from_buf(&DATA_INVALID_ARG_TOO_LONG);
+        assert_eq!(
+            e.to_string(),
+            "expected CRLF after bulk data at offset 11 (14 bytes: 2a 31 0d 0a 24 33 0d 0a 67 65 74 78 0d 0a)"
+        );
+        assert_eq!(e.client_detail(), "expected CRLF after bulk data at offset 11");
+
+        // Large frames only have their ends captured.
+        let mut buf = b"*1\r\n$100\r\n".to_vec();
+        buf.extend_from_slice(&[b'a'; 100]);
+        buf.extend_from_slice(b"XX");
+
+        let e = get_parse_error_from_buf(&buf);
+        assert_eq!(e.offset(), 110);
+
+        let msg = e.to_string();
+        let head = "2a 31 0d 0a 24 31 30 30 0d 0a 61";
+        let tail = "61 61 58 58)";
+        assert!(msg.starts_with("expected CRLF after bulk data at offset 110 (112 bytes: "));
+        assert!(msg.contains(head), "missing head in '{}'", msg);
+        assert!(msg.ends_with(tail), "missing tail in '{}'", msg);
+        assert_eq!(msg.matches(" .. ").count(), 1);
+    }
+
+    #[test]
+    fn parse_limits_at_boundary() {
+        let limits = get_test_limits();
+
+        match get_limited_message_from_buf(&DATA_GET_SIMPLE, &limits) {
+            Ok(Async::Ready(msg)) => check_bulk_matches(msg, vec![b"get", b"foobar"]),
+            x => panic!("should have had message, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn parse_limits_just_over() {
+        let limits = get_test_limits();
+
+        let e = get_limit_error_from_buf(b"*2\r\n$3\r\nget\r\n$7\r\nfoobarx\r\n", &limits);
+        assert_eq!(e, "invalid bulk length");
+
+        let e = get_limit_error_from_buf(b"*3\r\n$3\r\ndel\r\n$1\r\na\r\n$1\r\nb\r\n", &limits);
+        assert_eq!(e, "invalid multibulk length");
+
+        let e = get_limit_error_from_buf(b"*2\r\n$3\r\nget\r\n$000000006\r\nfoobar\r\n", &limits);
+        assert_eq!(e, "too big inline request");
+    }
+
+    #[test]
+    fn parse_limits_before_buffering() {
+        let limits = get_test_limits();
+
+        // Only the declarations have arrived, which is enough to know the request is too big.
+        let e = get_limit_error_from_buf(b"*2\r\n$3\r\nget\r\n$1000000\r\n", &limits);
+        assert_eq!(e, "invalid bulk length");
+
+        let e = get_limit_error_from_buf(b"*1000000\r\n", &limits);
+        assert_eq!(e, "invalid multibulk length");
+
+        // A line that's already longer than we'd accept is too long no matter where it ends.
+        let e = get_limit_error_from_buf(b"*2\r\n$3\r\nget\r\n$0000000000", &limits);
+        assert_eq!(e, "too big inline request");
+    }
+
+    #[test]
+    fn transport_recovers_after_buffered_oversized_request() {
+        let mut buf = b"*2\r\n$3\r\nget\r\n$7\r\nfoobarx\r\n".to_vec();
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        let mut responses = get_client_responses(&buf, get_test_limits());
+        assert_that(&responses).has_length(2);
+        check_error_matches(responses.remove(0), b"Protocol error: invalid bulk length");
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+    }
+
+    #[test]
+    fn transport_closes_after_partial_oversized_request() {
+        let mut buf = b"*1000000\r\n$3\r\nget\r\n".to_vec();
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        let mut responses = get_client_responses(&buf, get_test_limits());
+        assert_that(&responses).has_length(1);
+        check_error_matches(responses.remove(0), b"Protocol error: invalid multibulk length");
+    }
+
+    #[test]
+    fn transport_closes_after_huge_declared_bulk() {
+        // 600MB is more than we'd accept by default, and we should find that out without making any
+        // room for it.
+        let buf = b"*3\r\n$3\r\nset\r\n$6\r\nfoobar\r\n$629145600\r\nfoo".to_vec();
+        let mut transport = RedisTransport::new(Cursor::new(buf), RedisTransportConfig::default(), None);
+
+        match transport.poll() {
+            Ok(Async::Ready(Some(msg))) => check_error_matches(msg, b"Protocol error: invalid bulk length"),
+            x => panic!("expected error for huge bulk, got {:?}", x),
+        }
+        assert!(transport.rbuf.capacity() < 1024 * 1024);
+        assert_eq!(transport.poll().expect("transport should not have failed"), Async::Ready(None));
+    }
+
+    #[test]
+    fn transport_resyncs_after_malformed_request() {
+        let mut buf = b"hello\r\n".to_vec();
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+        buf.extend_from_slice(b"world\r\n");
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        let mut responses = get_client_responses_with_config(&buf, RedisTransportConfig::default());
+        assert_that(&responses).has_length(4);
+        check_error_matches(responses.remove(0), b"Protocol error: expected type sigil at offset 0");
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+        check_error_matches(responses.remove(0), b"Protocol error: expected type sigil at offset 0");
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+    }
+
+    #[test]
+    fn transport_closes_after_malformed_multibulk() {
+        let mut buf = DATA_INVALID_ARG_TOO_LONG.to_vec();
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        let mut responses = get_client_responses_with_config(&buf, RedisTransportConfig::default());
+        assert_that(&responses).has_length(1);
+        check_error_matches(responses.remove(0), b"Protocol error: expected CRLF after bulk data at offset 11");
+
+        // Whatever follows an argument that isn't as long as it says it is might be a whole request
+        // of its own, which mustn't ever be run as one.
+        let buf = b"*2\r\n$3\r\nget\r\n$4\r\nfoo\r\n*1\r\n$8\r\nflushall\r\n";
+        let responses = get_client_responses_with_config(buf, RedisTransportConfig::default());
+        assert_that(&responses).has_length(1);
+    }
+
+    #[test]
+    fn transport_closes_after_too_many_protocol_errors() {
+        let mut buf = b"hello\r\n".to_vec();
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+        buf.extend_from_slice(b"x\r\ny\r\n");
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        let config = RedisTransportConfig {
+            max_protocol_errors: 2,
+            ..Default::default()
+        };
+        let mut responses = get_client_responses_with_config(&buf, config);
+        assert_that(&responses).has_length(4);
+        check_error_matches(responses.remove(0), b"Protocol error: expected type sigil at offset 0");
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+        check_error_matches(responses.remove(0), b"Protocol error: expected type sigil at offset 0");
+        check_error_matches(responses.remove(0), b"Protocol error: expected type sigil at offset 0");
+    }
+
+    #[test]
+    fn transport_closes_after_unfinished_malformed_request() {
+        let mut buf = DATA_GET_SIMPLE.to_vec();
+        buf.extend_from_slice(b"hello");
+
+        let mut responses = get_client_responses_with_config(&buf, RedisTransportConfig::default());
+        assert_that(&responses).has_length(2);
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+        check_error_matches(responses.remove(0), b"Protocol error: expected type sigil at offset 0");
+    }
+
+    #[test]
+    fn transport_simulates_debug_sleep() {
+        let mut buf = b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$1\r\n1\r\n".to_vec();
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        let config = RedisTransportConfig {
+            allow_debug_simulation: true,
+            ..Default::default()
+        };
+        let mut responses = get_client_responses_with_config(&buf, config);
+        assert_that(&responses).has_length(2);
+        assert_eq!(responses.remove(0), RedisMessage::Sleep(Duration::from_secs(1)));
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+    }
+
+    #[test]
+    fn transport_refuses_debug_sleep_by_default() {
+        let mut buf = b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$1\r\n1\r\n".to_vec();
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        let mut responses = get_client_responses_with_config(&buf, RedisTransportConfig::default());
+        assert_that(&responses).has_length(1);
+        check_error_matches(responses.remove(0), b"command not valid");
+    }
+
+    #[test]
+    fn transport_answers_client_commands() {
+        let mut buf = b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$6\r\nworker\r\n".to_vec();
+        buf.extend_from_slice(b"*4\r\n$6\r\nCLIENT\r\n$4\r\nKILL\r\n$4\r\nNAME\r\n$5\r\nother\r\n");
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        // Clients can kill each other, by the names they've given themselves, if allowed to.
+        let clients = Arc::new(ClientRegistry::new());
+        let (other, _other_rx) = ClientRegistry::register(&clients, "127.0.0.1:5000".parse().unwrap());
+        other.stats().set_name("other".to_owned());
+
+        let stats = Arc::new(ClientStats::default());
+        let config = RedisTransportConfig {
+            clients: Some(clients.clone()),
+            ..Default::default()
+        };
+        let transport = RedisTransport::new(Cursor::new(buf.clone()), config, None).set_client_stats(stats.clone());
+        let mut responses = transport.collect().wait().expect("transport should not have failed");
+        assert_that(&responses).has_length(3);
+        assert_eq!(responses.remove(0), RedisMessage::OK);
+        check_integer_matches(responses.remove(0), 1);
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+        assert_eq!(stats.name(), Some("worker".to_owned()));
+
+        // Otherwise, naming still works, but killing doesn't, and neither ends the connection.
+        let mut responses = get_client_responses_with_config(&buf, RedisTransportConfig::default());
+        assert_that(&responses).has_length(3);
+        assert_eq!(responses.remove(0), RedisMessage::OK);
+        check_error_matches(responses.remove(0), b"CLIENT KILL is not allowed on this listener");
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+    }
+
+    #[test]
+    fn transport_applies_routing_hints() {
+        let mut buf = b"*3\r\n$11\r\nSYNCHROTRON\r\n$5\r\nROUTE\r\n$4\r\nuser\r\n".to_vec();
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        let config = RedisTransportConfig {
+            routing_hints: true,
+            ..Default::default()
+        };
+        let mut responses = get_client_responses_with_config(&buf, config);
+        assert_that(&responses).has_length(3);
+        assert_eq!(responses.remove(0), RedisMessage::OK);
+
+        // Only the command right after the hint is routed by it.
+        let routed = responses.remove(0);
+        assert_eq!(routed.key(), b"user");
+        assert_eq!(routed.get_command(), Some(&b"get"[..]));
+        assert_eq!(&routed.into_resp()[..], &DATA_GET_SIMPLE[..]);
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+    }
+
+    #[test]
+    fn transport_rejects_unfollowed_routing_hints() {
+        let hint = b"*3\r\n$11\r\nsynchrotron\r\n$5\r\nroute\r\n$4\r\nuser\r\n";
+        let mut buf = hint.to_vec();
+        buf.extend_from_slice(hint);
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+        buf.extend_from_slice(hint);
+        buf.extend_from_slice(&DATA_PING_UPPER);
+
+        let config = RedisTransportConfig {
+            routing_hints: true,
+            ..Default::default()
+        };
+        let mut responses = get_client_responses_with_config(&buf, config);
+        assert_that(&responses).has_length(5);
+        assert_eq!(responses.remove(0), RedisMessage::OK);
+        check_error_matches(responses.remove(0), HINT_NOT_FOLLOWED.as_bytes());
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+        assert_eq!(responses.remove(0), RedisMessage::OK);
+        check_error_matches(responses.remove(0), HINT_NOT_FOLLOWED.as_bytes());
+    }
+
+    #[test]
+    fn transport_refuses_routing_hints_by_default() {
+        let mut buf = b"*3\r\n$11\r\nSYNCHROTRON\r\n$5\r\nROUTE\r\n$4\r\nuser\r\n".to_vec();
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        let mut responses = get_client_responses_with_config(&buf, RedisTransportConfig::default());
+        assert_that(&responses).has_length(1);
+        check_error_matches(responses.remove(0), b"command not valid");
+    }
+
+    #[test]
+    fn transport_queues_transactions() {
+        let mut buf = b"*1\r\n$5\r\nMULTI\r\n".to_vec();
+        buf.extend_from_slice(b"*1\r\n$5\r\nMULTI\r\n");
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+        buf.extend_from_slice(&DATA_PING_FULL_UPPER);
+        buf.extend_from_slice(b"*1\r\n$4\r\nEXEC\r\n");
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+
+        let mut responses = get_client_responses(&buf, ProtocolLimits::default());
+        assert_that(&responses).has_length(6);
+        assert_eq!(responses.remove(0), RedisMessage::OK);
+
+        // A nested MULTI is refused, but leaves the transaction it was sent in alone.
+        check_error_matches(responses.remove(0), b"MULTI calls can not be nested");
+        check_status_matches(responses.remove(0), b"QUEUED");
+        check_status_matches(responses.remove(0), b"QUEUED");
+
+        let transaction = responses.remove(0);
+        assert_eq!(transaction.key(), b"foobar");
+        let mut expected = b"*1\r\n$5\r\nMULTI\r\n".to_vec();
+        expected.extend_from_slice(&DATA_GET_SIMPLE);
+        expected.extend_from_slice(&DATA_PING_FULL_UPPER);
+        expected.extend_from_slice(b"*1\r\n$4\r\nEXEC\r\n");
+        assert_eq!(transaction.size(), expected.len());
+        assert_eq!(&transaction.into_resp()[..], &expected[..]);
+
+        // Once the transaction has been run, commands go back to being sent on their own.
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+    }
+
+    #[test]
+    fn transport_aborts_transactions_after_oversized_requests() {
+        let mut buf = b"*1\r\n$5\r\nMULTI\r\n".to_vec();
+        buf.extend_from_slice(b"*2\r\n$3\r\nGET\r\n$9\r\nfoobarbaz\r\n");
+        buf.extend_from_slice(b"*1\r\n$4\r\nEXEC\r\n");
+
+        let mut responses = get_client_responses(&buf, get_test_limits());
+        assert_that(&responses).has_length(3);
+        assert_eq!(responses.remove(0), RedisMessage::OK);
+        check_error_matches(responses.remove(0), b"Protocol error: invalid bulk length");
+        assert_eq!(
+            &responses.remove(0).into_resp()[..],
+            &b"-EXECABORT Transaction discarded because of previous errors.\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn transport_skips_empty_lines() {
+        let mut buf = b"\r\n\r\n\n".to_vec();
+        buf.extend_from_slice(&DATA_GET_SIMPLE);
+        buf.extend_from_slice(b"\r\n");
+        buf.extend_from_slice(&DATA_PING_UPPER);
+        buf.extend_from_slice(b"\r\n\r\n");
+
+        let mut responses = get_client_responses_with_config(&buf, RedisTransportConfig::default());
+        assert_that(&responses).has_length(2);
+        check_bulk_matches(responses.remove(0), vec![b"get", b"foobar"]);
+        assert_eq!(responses.remove(0), RedisMessage::Ping);
+    }
+
+    #[test]
+    fn skip_empty_lines_only_skips_line_endings() {
+        let mut rd = BytesMut::from(&b"\r\n\n\r\nPING\r\n"[..]);
+        assert_eq!(skip_empty_lines(&mut rd), 5);
+        assert_eq!(&rd[..], &b"PING\r\n"[..]);
+        assert_eq!(skip_empty_lines(&mut rd), 0);
+
+        let mut rd = BytesMut::new();
+        assert_eq!(skip_empty_lines(&mut rd), 0);
+    }
+
     #[test]
     fn parse_ping() {
         match get_message_from_buf(&DATA_PING_LOWER) {
@@ -948,6 +1859,129 @@ mod tests {
         }
     }
 
+    #[test]
+    fn read_messages_out_of_sync() {
+        let get_request = || {
+            let mut request = EnqueuedRequest::new(0, RedisMessage::from_inline("GET foo"));
+            let rx = request.get_response_rx().unwrap();
+            (request, rx)
+        };
+
+        // One response for one request is what we expect.
+        let (request, _rx) = get_request();
+        let result = read_messages(Cursor::new(b"$3\r\nbar\r\n".to_vec()), vec![request], get_tracker(None)).wait();
+        assert!(result.is_ok());
+
+        // Anything beyond that means the connection is no longer in step with its requests.
+        let (request, _rx) = get_request();
+        let response = b"$3\r\nbar\r\n+OK\r\n".to_vec();
+        let result = read_messages(Cursor::new(response), vec![request], get_tracker(None)).wait();
+        match result {
+            Err(ProtocolError::BackendOutOfSync) => {},
+            _ => panic!("extra response should have been caught"),
+        }
+    }
+
+    fn get_tracker(max_bytes: Option<usize>) -> Arc<ResponseSizeTracker> {
+        let config = ResponseSizeConfiguration {
+            warn_bytes: Some(16),
+            max_bytes,
+        };
+        Arc::new(ResponseSizeTracker::new(config, get_sink()))
+    }
+
+    #[test]
+    fn read_messages_response_too_large() {
+        // The backend says it's about to send us 200MB, which is far more than we're willing to read.
+        let mut request = EnqueuedRequest::new(0, RedisMessage::from_inline("LRANGE foo 0 -1"));
+        let rx = request.get_response_rx().unwrap();
+        let mut response = b"*2\r\n$3\r\nbar\r\n$209715200\r\n".to_vec();
+        response.extend_from_slice(&[b'x'; 4096]);
+
+        let result = read_messages(Cursor::new(response), vec![request], get_tracker(Some(1024 * 1024))).wait();
+        match result {
+            Err(ProtocolError::LimitExceeded(_)) => {},
+            _ => panic!("oversized response should have been abandoned"),
+        }
+
+        // The client hears about it, rather than just getting a generic failure.
+        match rx.wait().unwrap() {
+            (0, MessageResponse::Complete(RedisMessage::Error(buf, _))) => {
+                assert_eq!(&buf[..], &b"-ERR response from backend is over max_response_bytes\r\n"[..]);
+            },
+            _ => panic!("client should have gotten an error"),
+        }
+    }
+
+    #[test]
+    fn read_messages_downgrades_resp3() {
+        let mut request = EnqueuedRequest::new(0, RedisMessage::from_inline("HGETALL foo"));
+        let rx = request.get_response_rx().unwrap();
+
+        // The push comes in ahead of the response, and isn't mistaken for it.
+        let response = b">2\r\n$7\r\nmessage\r\n$3\r\nbar\r\n%1\r\n$1\r\na\r\n,1.5\r\n".to_vec();
+        let result = read_messages(Cursor::new(response), vec![request], get_tracker(None)).wait();
+        assert!(result.is_ok());
+        match rx.wait().unwrap() {
+            (0, MessageResponse::Complete(msg)) => {
+                assert_eq!(&msg.into_resp()[..], &b"*2\r\n$1\r\na\r\n$3\r\n1.5\r\n"[..]);
+            },
+            _ => panic!("client should have gotten the translated response"),
+        }
+    }
+
+    #[test]
+    fn read_messages_response_under_limit() {
+        // Responses over the warning threshold, but under the limit, are read like any other.
+        let mut request = EnqueuedRequest::new(0, RedisMessage::from_inline("GET foo"));
+        let rx = request.get_response_rx().unwrap();
+        let response = b"$32\r\nxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx\r\n".to_vec();
+
+        let result = read_messages(Cursor::new(response), vec![request], get_tracker(Some(1024))).wait();
+        assert!(result.is_ok());
+        match rx.wait().unwrap() {
+            (0, MessageResponse::Complete(RedisMessage::Data(buf, _))) => assert_eq!(buf.len(), 39),
+            _ => panic!("client should have gotten the value"),
+        }
+    }
+
+    #[test]
+    fn read_messages_transaction() {
+        let cmds = vec![RedisMessage::from_inline("SET foo 1"), RedisMessage::from_inline("INCR foo")];
+        let mut request = EnqueuedRequest::new(0, RedisMessage::Transaction(BytesMut::from(&b"foo"[..]), cmds));
+        let rx = request.get_response_rx().unwrap();
+
+        // Only the reply to EXEC makes it back, but every reply before it still has to be read.
+        let response = b"+OK\r\n+QUEUED\r\n+QUEUED\r\n*2\r\n+OK\r\n:2\r\n".to_vec();
+        let result = read_messages(Cursor::new(response), vec![request], get_tracker(None)).wait();
+        assert!(result.is_ok());
+        match rx.wait().unwrap() {
+            (0, MessageResponse::Complete(msg)) => assert_eq!(&msg.into_resp()[..], &b"*2\r\n+OK\r\n:2\r\n"[..]),
+            _ => panic!("client should have gotten the reply to EXEC"),
+        }
+    }
+
+    #[test]
+    fn read_messages_transaction_backend_closed() {
+        let cmds = vec![RedisMessage::from_inline("SET foo 1")];
+        let mut request = EnqueuedRequest::new(0, RedisMessage::Transaction(BytesMut::from(&b"foo"[..]), cmds));
+        let rx = request.get_response_rx().unwrap();
+
+        let response = b"+OK\r\n+QUEUED\r\n".to_vec();
+        let result = read_messages(Cursor::new(response), vec![request], get_tracker(None)).wait();
+        match result {
+            Err(ProtocolError::BackendClosedPrematurely) => {},
+            _ => panic!("backend should have closed before answering EXEC"),
+        }
+        match rx.wait().unwrap() {
+            (0, MessageResponse::Complete(msg)) => {
+                let expected = b"-EXECABORT Transaction discarded because of previous errors.\r\n";
+                assert_eq!(&msg.into_resp()[..], &expected[..]);
+            },
+            _ => panic!("client should have been told the transaction was aborted"),
+        }
+    }
+
     #[bench]
     fn bench_parse_get_simple(b: &mut Bencher) { b.iter(|| get_message_from_buf(&DATA_GET_SIMPLE)); }
 
@@ -0,0 +1,155 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{read_message_internal, RedisMessage, UNLIMITED};
+use backend::subscription::SubscriptionRequest;
+use bytes::BytesMut;
+use futures::Async;
+use protocol::errors::ProtocolError;
+
+// Replies that say how many subscriptions a connection has left, once it's changed them.
+const SUBSCRIPTION_REPLIES: &[&[u8]] = &[b"subscribe", b"unsubscribe", b"psubscribe", b"punsubscribe"];
+
+/// Gets what the given command changes about the client's subscriptions, if it subscribes or
+/// unsubscribes.
+pub fn get_subscription(cmd: &RedisMessage) -> Option<SubscriptionRequest> {
+    let args = match cmd {
+        RedisMessage::Bulk(_, ref args, _) => args,
+        RedisMessage::Routed(_, ref inner) => return get_subscription(inner),
+        _ => return None,
+    };
+
+    let info = cmd.get_command_info().filter(|info| info.is_pubsub())?;
+    let channels = args[1..]
+        .iter()
+        .filter_map(|arg| {
+            match arg {
+                RedisMessage::Data(buf, offset) => Some(&buf[*offset..buf.len() - 2]),
+                _ => None,
+            }
+        })
+        .collect();
+
+    match info.name() {
+        "SUBSCRIBE" => Some(SubscriptionRequest::Channels(channels)),
+        "PSUBSCRIBE" => Some(SubscriptionRequest::Patterns(channels)),
+        _ => Some(SubscriptionRequest::Unsubscribe),
+    }
+}
+
+/// Reads the next message a backend sent over a subscription off of the given buffer.
+///
+/// Returns the message as it was sent, along with how many subscriptions the connection has left,
+/// if the message is the reply to a change in its subscriptions, or `None` if the buffer doesn't
+/// hold a whole message yet.
+pub fn read_pushed_message(buf: &mut BytesMut) -> Result<Option<(BytesMut, Option<usize>)>, ProtocolError> {
+    let msg = match read_message_internal(buf, &UNLIMITED)? {
+        Async::Ready((_, msg)) => msg,
+        Async::NotReady => return Ok(None),
+    };
+
+    let subscribed = match msg {
+        RedisMessage::Bulk(_, ref args, _) if args.len() == 3 => {
+            match (&args[0], &args[2]) {
+                (RedisMessage::Data(kind, offset), RedisMessage::Integer(_, count))
+                    if SUBSCRIPTION_REPLIES
+                        .iter()
+                        .any(|reply| kind[*offset..kind.len() - 2].eq_ignore_ascii_case(reply)) =>
+                {
+                    Some(*count as usize)
+                },
+                _ => None,
+            }
+        },
+        _ => None,
+    };
+
+    Ok(Some((msg.into_resp(), subscribed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(buf: &[u8]) -> Vec<(BytesMut, Option<usize>)> {
+        let mut buf = BytesMut::from(buf);
+        let mut msgs = Vec::new();
+        while let Some(msg) = read_pushed_message(&mut buf).unwrap() {
+            msgs.push(msg);
+        }
+        assert!(buf.is_empty());
+        msgs
+    }
+
+    #[test]
+    fn test_get_subscription() {
+        match get_subscription(&RedisMessage::from_inline("subscribe news weather")) {
+            Some(SubscriptionRequest::Channels(channels)) => assert_eq!(channels, vec![&b"news"[..], &b"weather"[..]]),
+            _ => panic!("expected a channel subscription"),
+        }
+        match get_subscription(&RedisMessage::from_inline("PSUBSCRIBE news.*")) {
+            Some(SubscriptionRequest::Patterns(patterns)) => assert_eq!(patterns, vec![&b"news.*"[..]]),
+            _ => panic!("expected a pattern subscription"),
+        }
+        match get_subscription(&RedisMessage::from_inline("UNSUBSCRIBE")) {
+            Some(SubscriptionRequest::Unsubscribe) => {},
+            _ => panic!("expected an unsubscribe"),
+        }
+        match get_subscription(&RedisMessage::from_inline("punsubscribe news.*")) {
+            Some(SubscriptionRequest::Unsubscribe) => {},
+            _ => panic!("expected an unsubscribe"),
+        }
+
+        assert!(get_subscription(&RedisMessage::from_inline("PUBLISH news hello")).is_none());
+        assert!(get_subscription(&RedisMessage::from_inline("GET news")).is_none());
+        assert!(get_subscription(&RedisMessage::Ping).is_none());
+    }
+
+    #[test]
+    fn test_read_pushed_messages() {
+        let subscribed = b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n";
+        let message = b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n";
+        let pmessage = b"*4\r\n$8\r\npmessage\r\n$1\r\n*\r\n$4\r\nnews\r\n$5\r\nhello\r\n";
+        let unsubscribed = b"*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:0\r\n";
+        let nothing_left = b"*3\r\n$12\r\npunsubscribe\r\n$-1\r\n:0\r\n";
+
+        let msgs = read_all(&[&subscribed[..], message, pmessage, unsubscribed, nothing_left].concat());
+        assert_eq!(
+            msgs,
+            vec![
+                (BytesMut::from(&subscribed[..]), Some(1)),
+                (BytesMut::from(&message[..]), None),
+                (BytesMut::from(&pmessage[..]), None),
+                (BytesMut::from(&unsubscribed[..]), Some(0)),
+                (BytesMut::from(&nothing_left[..]), Some(0)),
+            ]
+        );
+
+        // Anything else the backend says, like errors, is passed along as-is.
+        let msgs = read_all(b"-ERR wrong number of arguments\r\n+PONG\r\n");
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].1, None);
+        assert_eq!(msgs[1].0, BytesMut::from(&b"+PONG\r\n"[..]));
+
+        // Partial messages are left where they are until the rest of them arrives.
+        let mut buf = BytesMut::from(&message[..message.len() - 3]);
+        assert_eq!(read_pushed_message(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), message.len() - 3);
+    }
+}
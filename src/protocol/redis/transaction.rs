@@ -0,0 +1,249 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{KeyPositions, RedisMessage};
+use bytes::BytesMut;
+use common::Message;
+use util::Sizable;
+
+const MULTI_FRAME: &[u8] = b"*1\r\n$5\r\nMULTI\r\n";
+const EXEC_FRAME: &[u8] = b"*1\r\n$4\r\nEXEC\r\n";
+const EMPTY_EXEC_REPLY: &[u8] = b"*0\r\n";
+const EXECABORT: &[u8] = b"-EXECABORT Transaction discarded because of previous errors.\r\n";
+const QUEUED: &str = "QUEUED";
+const NESTED_MULTI: &str = "MULTI calls can not be nested";
+const EXEC_WITHOUT_MULTI: &str = "EXEC without MULTI";
+const DISCARD_WITHOUT_MULTI: &str = "DISCARD without MULTI";
+const PUBSUB_IN_MULTI: &str = "pub/sub commands can not be used in a transaction";
+
+// Transactions without any keys in them still have to go somewhere, so they're routed the same way
+// as a lone `MULTI` would be.
+const KEYLESS_ROUTE: &[u8] = b"multi";
+
+/// A transaction a client has started with `MULTI`, along with the commands it has queued since.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    queued: Vec<RedisMessage>,
+    aborted: bool,
+}
+
+impl Transaction {
+    /// Queues the given command, giving back what the client should be sent for it.
+    ///
+    /// Commands headed for a backend are held on to until `EXEC`, and the client is told they've
+    /// been queued.  Anything else passes through as-is, but if it's an error, the transaction is
+    /// aborted, just as Redis would abort it.  Subscribing changes what the connection is used for,
+    /// which can't happen in the middle of a transaction, so pub/sub commands abort it, too.
+    pub fn queue(&mut self, cmd: RedisMessage) -> RedisMessage {
+        match cmd {
+            RedisMessage::Bulk(_, _, _) | RedisMessage::Routed(_, _) => {
+                if cmd.get_command_info().map(|info| info.is_pubsub()).unwrap_or(false) {
+                    self.abort();
+                    return RedisMessage::from_error_str(PUBSUB_IN_MULTI);
+                }
+                self.queued.push(cmd)
+            },
+            // We'd normally answer these ourselves, but inside a transaction, the reply has to come
+            // back as part of the reply to `EXEC`.
+            RedisMessage::Ping => self.queued.push(RedisMessage::from_inline("PING")),
+            RedisMessage::Error(_, _) => {
+                self.abort();
+                return cmd;
+            },
+            cmd => return cmd,
+        }
+
+        RedisMessage::from_status(QUEUED)
+    }
+
+    /// Aborts the transaction, so that `EXEC` discards it rather than running it.
+    pub fn abort(&mut self) { self.aborted = true; }
+}
+
+/// Answers `MULTI`, `EXEC`, and `DISCARD`.
+///
+/// Returns the response to send the client, or `None` if the command should be handled like any
+/// other.  A successful `EXEC` gives back the whole transaction as a single message, which is sent
+/// to one backend in one go, so that nobody else's commands can end up in the middle of it.
+pub fn handle_transaction_command(cmd: &RedisMessage, transaction: &mut Option<Transaction>) -> Option<RedisMessage> {
+    let name = cmd.get_command()?;
+
+    if name.eq_ignore_ascii_case(b"multi") {
+        if transaction.is_some() {
+            return Some(RedisMessage::from_error_str(NESTED_MULTI));
+        }
+        *transaction = Some(Transaction::default());
+        return Some(RedisMessage::OK);
+    }
+
+    if name.eq_ignore_ascii_case(b"exec") {
+        let resp = match transaction.take() {
+            None => RedisMessage::from_error_str(EXEC_WITHOUT_MULTI),
+            Some(ref t) if t.aborted => RedisMessage::Raw(BytesMut::from(EXECABORT)),
+            Some(ref t) if t.queued.is_empty() => RedisMessage::Raw(BytesMut::from(EMPTY_EXEC_REPLY)),
+            Some(t) => RedisMessage::Transaction(get_transaction_key(&t.queued), t.queued),
+        };
+        return Some(resp);
+    }
+
+    if name.eq_ignore_ascii_case(b"discard") {
+        let resp = match transaction.take() {
+            None => RedisMessage::from_error_str(DISCARD_WITHOUT_MULTI),
+            Some(_) => RedisMessage::OK,
+        };
+        return Some(resp);
+    }
+
+    None
+}
+
+/// Gets the key a transaction made up of the given commands is routed by.
+///
+/// This is the key of the first command that has one, or the routing hint given for it.
+fn get_transaction_key(cmds: &[RedisMessage]) -> BytesMut {
+    let key = cmds.iter().find_map(|cmd| {
+        match cmd {
+            RedisMessage::Routed(hint, _) => Some(&hint[..]),
+            RedisMessage::Bulk(_, args, _) if args.len() > 1 => {
+                cmd.get_command_info()
+                    .filter(|info| info.keys() != KeyPositions::None)
+                    .map(|_| cmd.key())
+            },
+            _ => None,
+        }
+    });
+
+    BytesMut::from(key.unwrap_or(KEYLESS_ROUTE))
+}
+
+/// Gets the buffer that runs the given commands as a transaction on a backend.
+pub fn get_transaction_buf(cmds: &[RedisMessage]) -> BytesMut {
+    let mut buf = BytesMut::from(MULTI_FRAME);
+    for cmd in cmds {
+        buf.extend_from_slice(&cmd.get_buf()[..]);
+    }
+    buf.extend_from_slice(EXEC_FRAME);
+    buf
+}
+
+/// Gets the size of the buffer that runs the given commands as a transaction on a backend.
+pub fn get_transaction_size(cmds: &[RedisMessage]) -> usize {
+    MULTI_FRAME.len() + cmds.iter().map(|cmd| cmd.size()).sum::<usize>() + EXEC_FRAME.len()
+}
+
+/// Gets the number of replies a backend sends back for the given request.
+///
+/// A transaction is answered once for `MULTI`, once for every command in it, and once for `EXEC`.
+/// Only the reply to `EXEC` means anything to the client, since it holds the replies to everything
+/// else.
+pub fn get_reply_count(msg: &RedisMessage) -> usize {
+    match msg {
+        RedisMessage::Transaction(_, cmds) => cmds.len() + 2,
+        _ => 1,
+    }
+}
+
+/// Gets the response to send back for the given request if its backend fails before answering it.
+///
+/// A transaction is answered the way Redis answers one that it discarded, rather than with a generic
+/// error, so that clients handle it like any other transaction that didn't run.
+pub fn get_failure_response(msg: &RedisMessage) -> Option<RedisMessage> {
+    match msg {
+        RedisMessage::Transaction(_, _) => Some(RedisMessage::Raw(BytesMut::from(EXECABORT))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(cmd: &str, transaction: &mut Option<Transaction>) -> Option<RedisMessage> {
+        handle_transaction_command(&RedisMessage::from_inline(cmd), transaction)
+    }
+
+    fn get_key(cmds: &[&str]) -> BytesMut {
+        let cmds = cmds.iter().map(|cmd| RedisMessage::from_inline(cmd)).collect::<Vec<_>>();
+        get_transaction_key(&cmds)
+    }
+
+    #[test]
+    fn test_transaction_commands() {
+        let mut transaction = None;
+        assert_eq!(handle("GET foo", &mut transaction), None);
+        assert_eq!(handle("EXEC", &mut transaction), Some(RedisMessage::from_error_str(EXEC_WITHOUT_MULTI)));
+        assert_eq!(handle("DISCARD", &mut transaction), Some(RedisMessage::from_error_str(DISCARD_WITHOUT_MULTI)));
+
+        assert_eq!(handle("multi", &mut transaction), Some(RedisMessage::OK));
+        assert_eq!(handle("MULTI", &mut transaction), Some(RedisMessage::from_error_str(NESTED_MULTI)));
+        assert!(transaction.is_some());
+        assert_eq!(handle("DISCARD", &mut transaction), Some(RedisMessage::OK));
+        assert!(transaction.is_none());
+    }
+
+    #[test]
+    fn test_transaction_exec() {
+        let mut transaction = None;
+        assert_eq!(handle("MULTI", &mut transaction), Some(RedisMessage::OK));
+        assert_eq!(handle("EXEC", &mut transaction), Some(RedisMessage::Raw(BytesMut::from(EMPTY_EXEC_REPLY))));
+
+        assert_eq!(handle("MULTI", &mut transaction), Some(RedisMessage::OK));
+        let queued = transaction.as_mut().unwrap().queue(RedisMessage::from_inline("INCR foo"));
+        assert_eq!(queued, RedisMessage::from_status(QUEUED));
+        match handle("EXEC", &mut transaction) {
+            Some(RedisMessage::Transaction(key, cmds)) => {
+                assert_eq!(&key[..], b"foo");
+                assert_eq!(cmds, vec![RedisMessage::from_inline("INCR foo")]);
+            },
+            resp => panic!("expected transaction, got {:?}", resp),
+        }
+        assert!(transaction.is_none());
+    }
+
+    #[test]
+    fn test_transaction_aborted() {
+        let mut transaction = None;
+        assert_eq!(handle("MULTI", &mut transaction), Some(RedisMessage::OK));
+
+        let error = RedisMessage::from_error_str("command not valid");
+        assert_eq!(transaction.as_mut().unwrap().queue(error.clone()), error);
+        assert_eq!(handle("EXEC", &mut transaction), Some(RedisMessage::Raw(BytesMut::from(EXECABORT))));
+
+        assert_eq!(handle("MULTI", &mut transaction), Some(RedisMessage::OK));
+        let resp = transaction.as_mut().unwrap().queue(RedisMessage::from_inline("SUBSCRIBE news"));
+        assert_eq!(resp, RedisMessage::from_error_str(PUBSUB_IN_MULTI));
+        assert_eq!(handle("EXEC", &mut transaction), Some(RedisMessage::Raw(BytesMut::from(EXECABORT))));
+    }
+
+    #[test]
+    fn test_transaction_key() {
+        assert_eq!(&get_key(&["PING", "SET foo 1", "GET bar"])[..], b"foo");
+        assert_eq!(&get_key(&["PING"])[..], KEYLESS_ROUTE);
+
+        let hinted = RedisMessage::Routed(BytesMut::from(&b"user"[..]), Box::new(RedisMessage::from_inline("GET a")));
+        assert_eq!(&get_transaction_key(&[hinted])[..], b"user");
+    }
+
+    #[test]
+    fn test_reply_count() {
+        let cmds = vec![RedisMessage::from_inline("SET foo 1"), RedisMessage::from_inline("GET foo")];
+        assert_eq!(get_reply_count(&RedisMessage::Transaction(BytesMut::from(&b"foo"[..]), cmds)), 4);
+        assert_eq!(get_reply_count(&RedisMessage::from_inline("GET foo")), 1);
+    }
+}
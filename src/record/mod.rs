@@ -0,0 +1,190 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+mod recorder;
+pub use self::recorder::{Recorded, Recorder, RecorderConfiguration};
+
+mod replay;
+pub use self::replay::{replay, ReplayOptions};
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::io::Cursor;
+
+const MAGIC: &[u8] = b"SYNREC01";
+
+const RECORD_OPEN: u8 = 0;
+const RECORD_FRAME: u8 = 1;
+const RECORD_CLOSE: u8 = 2;
+
+// Tag, connection ID, and timestamp.
+const RECORD_HEADER_LEN: usize = 1 + 4 + 8;
+
+/// An entry in a recording of client traffic.
+///
+/// Recordings are a compact binary log: a magic header, followed by a record for every client
+/// connection opening, every request frame it sent, and the connection closing.  Every record
+/// carries the connection it belongs to and when it happened, in microseconds since the recording
+/// started, so that traffic can be replayed with the same timing and interleaving.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    Open { conn: u32, at_us: u64 },
+    Frame { conn: u32, at_us: u64, data: BytesMut },
+    Close { conn: u32, at_us: u64 },
+}
+
+impl Record {
+    pub fn conn(&self) -> u32 {
+        match *self {
+            Record::Open { conn, .. } => conn,
+            Record::Frame { conn, .. } => conn,
+            Record::Close { conn, .. } => conn,
+        }
+    }
+
+    pub fn at_us(&self) -> u64 {
+        match *self {
+            Record::Open { at_us, .. } => at_us,
+            Record::Frame { at_us, .. } => at_us,
+            Record::Close { at_us, .. } => at_us,
+        }
+    }
+
+    /// Gets the number of bytes this record takes up when encoded.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Record::Frame { data, .. } => RECORD_HEADER_LEN + 4 + data.len(),
+            _ => RECORD_HEADER_LEN,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.reserve(self.encoded_len());
+        let tag = match self {
+            Record::Open { .. } => RECORD_OPEN,
+            Record::Frame { .. } => RECORD_FRAME,
+            Record::Close { .. } => RECORD_CLOSE,
+        };
+        buf.put_u8(tag);
+        buf.put_u32_be(self.conn());
+        buf.put_u64_be(self.at_us());
+
+        if let Record::Frame { data, .. } = self {
+            buf.put_u32_be(data.len() as u32);
+            buf.put_slice(&data[..]);
+        }
+    }
+}
+
+/// Decodes all of the records in a recording.
+///
+/// A recording that ends partway through a record, which happens if we were stopped while writing
+/// it, is read up to the last complete record.
+pub fn decode_records(buf: &[u8]) -> Result<Vec<Record>, String> {
+    if !buf.starts_with(MAGIC) {
+        return Err("not a synchrotron recording".to_owned());
+    }
+
+    let mut cursor = Cursor::new(&buf[MAGIC.len()..]);
+    let mut records = Vec::new();
+    while cursor.remaining() >= RECORD_HEADER_LEN {
+        let offset = MAGIC.len() + cursor.position() as usize;
+        let tag = cursor.get_u8();
+        let conn = cursor.get_u32_be();
+        let at_us = cursor.get_u64_be();
+
+        let record = match tag {
+            RECORD_OPEN => Record::Open { conn, at_us },
+            RECORD_CLOSE => Record::Close { conn, at_us },
+            RECORD_FRAME => {
+                if cursor.remaining() < 4 {
+                    break;
+                }
+
+                let len = cursor.get_u32_be() as usize;
+                if cursor.remaining() < len {
+                    break;
+                }
+
+                let mut data = BytesMut::with_capacity(len);
+                data.put_slice(&cursor.bytes()[..len]);
+                cursor.advance(len);
+
+                Record::Frame { conn, at_us, data }
+            },
+            x => return Err(format!("unknown record type {} at offset {}", x, offset)),
+        };
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_records() -> Vec<Record> {
+        vec![
+            Record::Open { conn: 1, at_us: 10 },
+            Record::Frame {
+                conn: 1,
+                at_us: 25,
+                data: BytesMut::from(&b"*2\r\n$3\r\nget\r\n$3\r\nfoo\r\n"[..]),
+            },
+            Record::Open { conn: 2, at_us: 30 },
+            Record::Close { conn: 1, at_us: 1_000_000 },
+        ]
+    }
+
+    fn encode_records(records: &[Record]) -> BytesMut {
+        let mut buf = BytesMut::from(MAGIC);
+        for record in records {
+            record.encode(&mut buf);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let records = get_records();
+        let buf = encode_records(&records);
+        assert_eq!(buf.len(), MAGIC.len() + records.iter().map(Record::encoded_len).sum::<usize>());
+        assert_eq!(decode_records(&buf), Ok(records));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let records = get_records();
+        let buf = encode_records(&records[..2]);
+
+        // Cutting into the frame record leaves us with just the open record.
+        let truncated = &buf[..buf.len() - 5];
+        assert_eq!(decode_records(truncated), Ok(records[..1].to_vec()));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(decode_records(b"*1\r\n$4\r\nPING\r\n").is_err());
+
+        let mut buf = encode_records(&get_records());
+        let len = buf.len();
+        buf[len - RECORD_HEADER_LEN] = 9;
+        assert!(decode_records(&buf).is_err());
+    }
+}
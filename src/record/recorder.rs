@@ -0,0 +1,490 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{decode_records, Record, MAGIC};
+use backend::processor::Processor;
+use bytes::BytesMut;
+use conf::ListenerConfiguration;
+use errors::CreationError;
+use futures::prelude::*;
+use rand::{thread_rng, Rng};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use util::clock::{duration_as_us, elapsed};
+
+// How many records can be waiting to be written before we start dropping them.
+const RECORD_QUEUE_CAPACITY: usize = 8192;
+
+lazy_static! {
+    static ref RECORDINGS: Mutex<HashMap<String, Arc<Recording>>> = Mutex::new(HashMap::new());
+}
+
+/// How, and how much, a listener should record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecorderConfiguration {
+    pub path: String,
+    pub max_bytes: usize,
+    pub max_connections: usize,
+    pub sample_rate: f64,
+    pub redact_values: bool,
+}
+
+impl RecorderConfiguration {
+    /// Gets the recording configuration for the given listener, if it wants to record.
+    ///
+    /// Recordings are bounded by default, so that turning recording on in production doesn't need
+    /// any other settings to be safe.
+    pub fn from_config(config: &ListenerConfiguration) -> Result<Option<RecorderConfiguration>, CreationError> {
+        let path = match config.record_path {
+            Some(ref path) => path.clone(),
+            None => return Ok(None),
+        };
+
+        let sample_rate = config.record_sample_rate.unwrap_or(1.0);
+        if sample_rate <= 0.0 || sample_rate > 1.0 {
+            return Err(CreationError::InvalidParameter("record_sample_rate".to_string()));
+        }
+
+        Ok(Some(RecorderConfiguration {
+            path,
+            max_bytes: config.record_max_bytes.unwrap_or(64 * 1024 * 1024),
+            max_connections: config.record_max_connections.unwrap_or(100),
+            sample_rate,
+            redact_values: config.record_redact_values.unwrap_or(false),
+        }))
+    }
+}
+
+/// What's been recorded to a recording so far, shared by everything recording to it.
+///
+/// Every version of a listener records to the same file, so connections are numbered, timestamps
+/// taken and limits enforced across all of them, and the file replays as a single recording.
+struct Recording {
+    started: Instant,
+    offset_us: u64,
+    next_conn: AtomicUsize,
+    connections: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl Recording {
+    /// Picks up after the given records, which were already recorded.
+    fn resume(records: &[Record]) -> Recording {
+        let conns = records.iter().map(|record| record.conn() as usize + 1).max().unwrap_or(0);
+
+        Recording {
+            started: Instant::now(),
+            offset_us: records.last().map_or(0, Record::at_us),
+            next_conn: AtomicUsize::new(conns),
+            connections: AtomicUsize::new(conns),
+            bytes: AtomicUsize::new(records.iter().map(Record::encoded_len).sum()),
+        }
+    }
+
+    fn now(&self) -> u64 { self.offset_us + duration_as_us(elapsed(self.started)) }
+}
+
+/// Opens the recording at the given path to be appended to, creating it if need be.
+///
+/// Anything already recorded there is kept, so that reloading a listener doesn't throw away what
+/// it recorded so far.  The first time we open a recording, we read it to find out where to carry
+/// on from, and cut off any record that was only partly written, as it would garble everything
+/// written after it.
+fn open_recording(path: &str) -> io::Result<(File, Arc<Recording>)> {
+    let mut recordings = RECORDINGS.lock().unwrap();
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+
+    let existing = recordings.get(path).cloned();
+    let recording = match existing {
+        Some(recording) => recording,
+        None => {
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf)?;
+            let records = if buf.is_empty() {
+                Vec::new()
+            } else {
+                decode_records(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            };
+
+            let recording = Arc::new(Recording::resume(&records));
+            if !buf.is_empty() {
+                file.set_len((MAGIC.len() + recording.bytes.load(Ordering::Relaxed)) as u64)?;
+            }
+            recordings.insert(path.to_owned(), recording.clone());
+            recording
+        },
+    };
+
+    if file.metadata()?.len() == 0 {
+        file.write_all(MAGIC)?;
+    }
+
+    Ok((file, recording))
+}
+
+/// Records the traffic of a sample of a listener's client connections to a file.
+///
+/// Records are handed off to a dedicated writer thread, so recording never blocks a client.  If
+/// the writer falls behind, records are dropped rather than queued without bound.
+pub struct Recorder {
+    config: RecorderConfiguration,
+    tx: Mutex<SyncSender<Record>>,
+    recording: Arc<Recording>,
+    exhausted: AtomicBool,
+    dropped: AtomicUsize,
+}
+
+impl Recorder {
+    /// Creates a recorder, adding to any existing recording at the configured path.
+    ///
+    /// Connection and byte limits cover the whole recording, including whatever was recorded to it
+    /// before.
+    pub fn new(config: RecorderConfiguration) -> Result<Arc<Recorder>, CreationError> {
+        let (file, recording) = open_recording(&config.path).map_err(|e| {
+            CreationError::InvalidResource(format!("failed to open recording '{}': {}", config.path, e))
+        })?;
+        let mut file = BufWriter::new(file);
+
+        let (tx, rx) = sync_channel(RECORD_QUEUE_CAPACITY);
+        let path = config.path.clone();
+        thread::spawn(move || write_records(rx, &mut file, &path));
+
+        info!(
+            "[recorder] recording up to {} connections, and {} bytes, to '{}'",
+            config.max_connections, config.max_bytes, config.path
+        );
+
+        Ok(Arc::new(Recorder {
+            config,
+            tx: Mutex::new(tx),
+            recording,
+            exhausted: AtomicBool::new(false),
+            dropped: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Starts recording a new connection, if it's sampled and there's room left to record it.
+    pub fn start_connection(recorder: &Arc<Recorder>) -> Option<ConnectionRecorder> {
+        if recorder.config.sample_rate < 1.0 && !thread_rng().gen_bool(recorder.config.sample_rate) {
+            return None;
+        }
+
+        let recorded = recorder.recording.connections.fetch_add(1, Ordering::Relaxed);
+        if recorded >= recorder.config.max_connections {
+            return None;
+        }
+
+        let tx = recorder.tx.lock().unwrap().clone();
+        let conn = recorder.recording.next_conn.fetch_add(1, Ordering::Relaxed) as u32;
+        let at_us = recorder.now();
+        if !recorder.record(&tx, Record::Open { conn, at_us }) {
+            return None;
+        }
+
+        Some(ConnectionRecorder {
+            recorder: recorder.clone(),
+            tx,
+            conn,
+        })
+    }
+
+    fn now(&self) -> u64 { self.recording.now() }
+
+    /// Queues the given record to be written, returning whether or not it will be.
+    fn record(&self, tx: &SyncSender<Record>, record: Record) -> bool {
+        let len = record.encoded_len();
+        if self.recording.bytes.fetch_add(len, Ordering::Relaxed) + len > self.config.max_bytes {
+            if !self.exhausted.swap(true, Ordering::Relaxed) {
+                info!("[recorder] reached the limit of {} bytes, recording stopped", self.config.max_bytes);
+            }
+            return false;
+        }
+
+        if tx.try_send(record).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed);
+            if dropped == 0 {
+                warn!("[recorder] writer is falling behind, dropping records");
+            }
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Records the frames of a single client connection.
+///
+/// The connection is recorded as closed when this is dropped.
+pub struct ConnectionRecorder {
+    recorder: Arc<Recorder>,
+    tx: SyncSender<Record>,
+    conn: u32,
+}
+
+impl ConnectionRecorder {
+    pub fn redact_values(&self) -> bool { self.recorder.config.redact_values }
+
+    pub fn record_frame(&self, data: BytesMut) {
+        let at_us = self.recorder.now();
+        self.recorder.record(
+            &self.tx,
+            Record::Frame {
+                conn: self.conn,
+                at_us,
+                data,
+            },
+        );
+    }
+}
+
+impl Drop for ConnectionRecorder {
+    fn drop(&mut self) {
+        let at_us = self.recorder.now();
+        self.recorder.record(&self.tx, Record::Close { conn: self.conn, at_us });
+    }
+}
+
+fn write_records<W: Write>(rx: Receiver<Record>, writer: &mut W, path: &str) {
+    let mut buf = BytesMut::new();
+    loop {
+        // Flush whenever things go quiet, so a recording is usable without stopping the listener.
+        let record = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(record) => record,
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = writer.flush();
+                continue;
+            },
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        buf.clear();
+        record.encode(&mut buf);
+        if let Err(e) = writer.write_all(&buf) {
+            error!("[recorder] failed to write to recording '{}': {}", path, e);
+            return;
+        }
+    }
+
+    let _ = writer.flush();
+}
+
+/// Wraps a client transport, recording every request that comes through it.
+pub struct Recorded<T, P> {
+    inner: T,
+    processor: P,
+    recorder: Option<ConnectionRecorder>,
+}
+
+impl<T, P> Recorded<T, P> {
+    pub fn new(inner: T, processor: P, recorder: Option<ConnectionRecorder>) -> Recorded<T, P> {
+        Recorded {
+            inner,
+            processor,
+            recorder,
+        }
+    }
+}
+
+impl<T, P> Stream for Recorded<T, P>
+where
+    T: Stream<Item = P::Message>,
+    P: Processor,
+{
+    type Error = T::Error;
+    type Item = T::Item;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let result = self.inner.poll();
+        if let Ok(Async::Ready(Some(ref msg))) = result {
+            if let Some(ref recorder) = self.recorder {
+                if let Some(frame) = self.processor.get_recorded_frame(msg, recorder.redact_values()) {
+                    recorder.record_frame(frame);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<T, P> Sink for Recorded<T, P>
+where
+    T: Sink,
+{
+    type SinkError = T::SinkError;
+    type SinkItem = T::SinkItem;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> { self.inner.poll_complete() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use record::decode_records;
+    use std::{env, fs, process};
+
+    fn wait_for_records(path: &str, count: usize) -> Vec<Record> {
+        let mut records = Vec::new();
+        for _ in 0..50 {
+            thread::sleep(Duration::from_millis(20));
+            records = decode_records(&fs::read(path).unwrap()).unwrap();
+            if records.len() >= count {
+                break;
+            }
+        }
+        records
+    }
+
+    fn get_config(name: &str, max_bytes: usize, max_connections: usize) -> RecorderConfiguration {
+        let path = env::temp_dir().join(format!("synchrotron-{}-{}.rec", name, process::id()));
+        RecorderConfiguration {
+            path: path.to_string_lossy().into_owned(),
+            max_bytes,
+            max_connections,
+            sample_rate: 1.0,
+            redact_values: false,
+        }
+    }
+
+    fn read_recording(recorder: Arc<Recorder>) -> Vec<Record> {
+        let path = recorder.config.path.clone();
+        drop(recorder);
+
+        // Wait for the writer thread to notice we're gone and finish writing.
+        let mut records = Vec::new();
+        for _ in 0..50 {
+            thread::sleep(Duration::from_millis(20));
+            records = decode_records(&fs::read(&path).unwrap()).unwrap();
+            if records.iter().any(|record| record.conn() == 0) {
+                break;
+            }
+        }
+        let _ = fs::remove_file(&path);
+        records
+    }
+
+    #[test]
+    fn test_from_config() {
+        let mut config = ListenerConfiguration::default();
+        assert_eq!(RecorderConfiguration::from_config(&config).unwrap(), None);
+
+        config.record_path = Some("/tmp/recording".to_owned());
+        let recorder_config = RecorderConfiguration::from_config(&config).unwrap().unwrap();
+        assert_eq!(recorder_config.max_connections, 100);
+        assert!(!recorder_config.redact_values);
+
+        config.record_sample_rate = Some(0.0);
+        assert!(RecorderConfiguration::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let recorder = Recorder::new(get_config("connections", 1024 * 1024, 2)).unwrap();
+
+        let first = Recorder::start_connection(&recorder).unwrap();
+        let second = Recorder::start_connection(&recorder).unwrap();
+        assert!(Recorder::start_connection(&recorder).is_none());
+
+        first.record_frame(BytesMut::from(&b"*1\r\n$4\r\nPING\r\n"[..]));
+        drop(first);
+        drop(second);
+
+        let records = read_recording(recorder);
+        assert_eq!(records.len(), 5);
+        match records[2] {
+            Record::Frame { conn: 0, ref data, .. } => assert_eq!(&data[..], b"*1\r\n$4\r\nPING\r\n"),
+            ref r => panic!("unexpected record: {:?}", r),
+        }
+        assert_eq!(records.iter().filter(|r| r.conn() == 1).count(), 2);
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        // Enough for the open record and a single frame, but nothing more.
+        let frame = BytesMut::from(&b"*2\r\n$3\r\nget\r\n$3\r\nfoo\r\n"[..]);
+        let max_bytes = 13 + 13 + 4 + frame.len();
+        let recorder = Recorder::new(get_config("bytes", max_bytes, 10)).unwrap();
+
+        let conn = Recorder::start_connection(&recorder).unwrap();
+        conn.record_frame(frame.clone());
+        conn.record_frame(frame.clone());
+        drop(conn);
+
+        // No room left to even open another connection.
+        assert!(Recorder::start_connection(&recorder).is_none());
+
+        let records = read_recording(recorder);
+        assert_eq!(records.len(), 2);
+        match records[1] {
+            Record::Frame { ref data, .. } => assert_eq!(data, &frame),
+            ref r => panic!("unexpected record: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_reopen_appends() {
+        let config = get_config("reopen", 1024 * 1024, 10);
+
+        // Reloading opens the recording again while the previous version may still be recording.
+        let first = Recorder::new(config.clone()).unwrap();
+        let second = Recorder::new(config.clone()).unwrap();
+        drop(Recorder::start_connection(&first).unwrap());
+        drop(Recorder::start_connection(&second).unwrap());
+        drop(first);
+        drop(second);
+
+        let records = wait_for_records(&config.path, 4);
+        let mut conns = records.iter().map(Record::conn).collect::<Vec<_>>();
+        conns.sort();
+        assert_eq!(conns, vec![0, 0, 1, 1]);
+
+        // Coming back to a recording we haven't seen before, such as after a restart, carries on
+        // after what's there, leaving off anything that was only partly written.
+        RECORDINGS.lock().unwrap().remove(&config.path);
+        let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+        file.write_all(&[1, 0, 0]).unwrap();
+        drop(file);
+
+        let third = Recorder::new(config.clone()).unwrap();
+        drop(Recorder::start_connection(&third).unwrap());
+        drop(third);
+
+        let records = wait_for_records(&config.path, 6);
+        let _ = fs::remove_file(&config.path);
+        assert_eq!(records.len(), 6);
+        match records[4] {
+            Record::Open { conn: 2, .. } => {},
+            ref r => panic!("unexpected record: {:?}", r),
+        }
+        assert!(records[4].at_us() >= records[3].at_us());
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{decode_records, Record};
+use bytes::BytesMut;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write},
+    net::{Shutdown, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How to replay a recording.
+#[derive(Debug, PartialEq)]
+pub struct ReplayOptions {
+    pub path: String,
+    pub target: String,
+    pub timing: bool,
+}
+
+impl ReplayOptions {
+    /// Parses replay options from the command line: `--replay <file> <target> [--no-timing]`.
+    pub fn from_args(args: &[String]) -> Result<ReplayOptions, String> {
+        let usage = || "usage: synchrotron --replay <file> <target> [--no-timing]".to_owned();
+
+        let pos = args.iter().position(|arg| arg == "--replay").ok_or_else(usage)?;
+        let path = args.get(pos + 1).ok_or_else(usage)?.clone();
+        let target = args.get(pos + 2).ok_or_else(usage)?.clone();
+        let timing = !args.iter().any(|arg| arg == "--no-timing");
+
+        Ok(ReplayOptions { path, target, timing })
+    }
+}
+
+/// The outcome of replaying a recording.
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub connections: usize,
+    pub frames: usize,
+    pub bytes: usize,
+    pub errors: usize,
+}
+
+impl fmt::Display for ReplaySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "replayed {} frames ({} bytes) over {} connections, with {} connection errors",
+            self.frames, self.bytes, self.connections, self.errors
+        )
+    }
+}
+
+enum ReplayEvent {
+    Frame(u64, BytesMut),
+    Close(u64),
+}
+
+struct ReplayConnection {
+    open_at_us: u64,
+    events: Vec<ReplayEvent>,
+}
+
+/// Replays a recording against the given target.
+///
+/// Every recorded connection gets its own connection to the target, opened and fed its frames at
+/// the same points, relative to the start of the recording, that they originally happened at.  If
+/// timing is turned off, connections are all opened at once and their frames sent as fast as
+/// possible, although each connection still sends its own frames in order.
+///
+/// Responses are read and thrown away: the point of a replay is to reproduce what the target
+/// sees.
+pub fn replay(options: &ReplayOptions) -> Result<ReplaySummary, String> {
+    let buf = fs::read(&options.path).map_err(|e| format!("failed to read '{}': {}", options.path, e))?;
+    let records = decode_records(&buf)?;
+    let base_us = records.iter().map(Record::at_us).min().unwrap_or(0);
+
+    // Group everything by connection, keeping the order each connection's records were written in.
+    let mut connections = HashMap::new();
+    for record in records {
+        match record {
+            Record::Open { conn, at_us } => {
+                connections.insert(
+                    conn,
+                    ReplayConnection {
+                        open_at_us: at_us.saturating_sub(base_us),
+                        events: Vec::new(),
+                    },
+                );
+            },
+            Record::Frame { conn, at_us, data } => {
+                if let Some(connection) = connections.get_mut(&conn) {
+                    connection.events.push(ReplayEvent::Frame(at_us.saturating_sub(base_us), data));
+                }
+            },
+            Record::Close { conn, at_us } => {
+                if let Some(connection) = connections.get_mut(&conn) {
+                    connection.events.push(ReplayEvent::Close(at_us.saturating_sub(base_us)));
+                }
+            },
+        }
+    }
+
+    let start = Instant::now();
+    let handles = connections
+        .into_iter()
+        .map(|(_, connection)| {
+            let target = options.target.clone();
+            let timing = options.timing;
+            thread::spawn(move || replay_connection(connection, &target, start, timing))
+        })
+        .collect::<Vec<_>>();
+
+    let mut summary = ReplaySummary::default();
+    for handle in handles {
+        summary.connections += 1;
+        match handle.join() {
+            Ok(Ok((frames, bytes))) => {
+                summary.frames += frames;
+                summary.bytes += bytes;
+            },
+            _ => summary.errors += 1,
+        }
+    }
+
+    Ok(summary)
+}
+
+fn replay_connection(
+    connection: ReplayConnection, target: &str, start: Instant, timing: bool,
+) -> io::Result<(usize, usize)> {
+    let wait_until = |at_us: u64| {
+        if timing {
+            let at = start + Duration::from_micros(at_us);
+            let now = Instant::now();
+            if at > now {
+                thread::sleep(at - now);
+            }
+        }
+    };
+
+    wait_until(connection.open_at_us);
+    let mut conn = TcpStream::connect(target)?;
+
+    // Drain responses on the side, so the target never blocks on us reading them.
+    let mut reader = conn.try_clone()?;
+    reader.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let drainer = thread::spawn(move || io::copy(&mut reader, &mut io::sink()));
+
+    let mut frames = 0;
+    let mut bytes = 0;
+    for event in connection.events {
+        match event {
+            ReplayEvent::Frame(at_us, data) => {
+                wait_until(at_us);
+                conn.write_all(&data)?;
+                frames += 1;
+                bytes += data.len();
+            },
+            ReplayEvent::Close(at_us) => {
+                wait_until(at_us);
+                break;
+            },
+        }
+    }
+
+    conn.shutdown(Shutdown::Write)?;
+    let _ = drainer.join();
+
+    Ok((frames, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use record::MAGIC;
+    use std::{env, io::Read, net::TcpListener, process};
+
+    fn args(args: &[&str]) -> Vec<String> { args.iter().map(|s| s.to_string()).collect() }
+
+    #[test]
+    fn test_from_args() {
+        let options = ReplayOptions::from_args(&args(&["--replay", "traffic.rec", "127.0.0.1:6379"])).unwrap();
+        assert_eq!(
+            options,
+            ReplayOptions {
+                path: "traffic.rec".to_owned(),
+                target: "127.0.0.1:6379".to_owned(),
+                timing: true,
+            }
+        );
+
+        let options = ReplayOptions::from_args(&args(&["--no-timing", "--replay", "a.rec", "b:1"])).unwrap();
+        assert!(!options.timing);
+
+        assert!(ReplayOptions::from_args(&args(&["--replay", "traffic.rec"])).is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            conn.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let records = vec![
+            Record::Open { conn: 7, at_us: 1_000 },
+            Record::Frame {
+                conn: 7,
+                at_us: 2_000,
+                data: BytesMut::from(&b"*2\r\n$3\r\nget\r\n$3\r\nfoo\r\n"[..]),
+            },
+            Record::Frame {
+                conn: 7,
+                at_us: 52_000,
+                data: BytesMut::from(&b"*1\r\n$4\r\nPING\r\n"[..]),
+            },
+            Record::Close { conn: 7, at_us: 53_000 },
+        ];
+
+        let mut buf = BytesMut::from(MAGIC);
+        for record in &records {
+            record.encode(&mut buf);
+        }
+        let path = env::temp_dir().join(format!("synchrotron-replay-{}.rec", process::id()));
+        fs::write(&path, &buf).unwrap();
+
+        let options = ReplayOptions {
+            path: path.to_string_lossy().into_owned(),
+            target,
+            timing: true,
+        };
+        let started = Instant::now();
+        let summary = replay(&options).unwrap();
+        let _ = fs::remove_file(&path);
+
+        // The second frame was recorded 50ms after the first, so it should be replayed that way.
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(summary.connections, 1);
+        assert_eq!(summary.frames, 2);
+        assert_eq!(summary.errors, 0);
+
+        let received = server.join().unwrap();
+        assert_eq!(&received[..], &b"*2\r\n$3\r\nget\r\n$3\r\nfoo\r\n*1\r\n$4\r\nPING\r\n"[..]);
+    }
+}
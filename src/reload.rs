@@ -0,0 +1,311 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use futures::{future::Either, prelude::*};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+
+// How often someone waiting on a reload checks whether it has completed.
+const RELOAD_CHECK_INTERVAL_MS: u64 = 10;
+
+lazy_static! {
+    static ref RELOADS: ReloadTracker = ReloadTracker::new();
+}
+
+/// A hold on a version of a listener.
+///
+/// Everything a listener version builds that can outlive its accept loop -- its pools, and so the
+/// clients routing through them -- keeps a clone of its hold.  The version has been released once
+/// every clone is dropped.
+#[derive(Clone)]
+pub struct VersionHold {
+    _inner: Arc<()>,
+}
+
+/// Where reloads stand, as reported by the admin API.
+#[derive(Serialize)]
+pub struct ReloadStatus {
+    pub last_reload_requested: usize,
+    pub last_reload_completed: usize,
+    pub last_reload_failed: usize,
+    pub listeners: HashMap<String, usize>,
+}
+
+/// What became of a reload.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReloadOutcome {
+    /// The reload is still being applied, or is waiting on older versions to be released.
+    Pending,
+    /// The reload was applied, and every version it replaced has been released.
+    Completed,
+    /// The reload couldn't be applied, and whatever was running before it still is.
+    Failed,
+}
+
+struct TrackedVersion {
+    listener: String,
+    version: usize,
+    released: Weak<()>,
+}
+
+#[derive(Default)]
+struct ReloadState {
+    versions: Vec<TrackedVersion>,
+    current: HashMap<String, usize>,
+    pending: Vec<(usize, Vec<Weak<()>>)>,
+    completed: usize,
+    failed: HashSet<usize>,
+    last_failed: usize,
+}
+
+/// Tracks reloads from being requested through to the versions they replaced being released.
+///
+/// Every reload is given a token, which goes up by one with every request.  A reload is complete
+/// once the listeners it launched are running, and every older version of every listener has
+/// finished draining its clients and dropped its pools.  Since a reload waits on every version
+/// older than the ones it launched, reloads always complete in the order they were requested.
+///
+/// A reload that fails launches nothing, so it never holds up the ones after it, but it's never
+/// counted as completed either.
+pub struct ReloadTracker {
+    requested: AtomicUsize,
+    state: Mutex<ReloadState>,
+}
+
+impl ReloadTracker {
+    fn new() -> ReloadTracker {
+        ReloadTracker {
+            requested: AtomicUsize::new(0),
+            state: Mutex::new(ReloadState::default()),
+        }
+    }
+
+    fn request(&self) -> usize { self.requested.fetch_add(1, Ordering::SeqCst) + 1 }
+
+    fn hold_version(&self, listener: &str, version: usize) -> VersionHold {
+        let inner = Arc::new(());
+        self.state.lock().unwrap().versions.push(TrackedVersion {
+            listener: listener.to_owned(),
+            version,
+            released: Arc::downgrade(&inner),
+        });
+
+        VersionHold { _inner: inner }
+    }
+
+    fn set_current(&self, listener: &str, version: Option<usize>) {
+        let mut state = self.state.lock().unwrap();
+        match version {
+            Some(version) => state.current.insert(listener.to_owned(), version),
+            None => state.current.remove(listener),
+        };
+    }
+
+    fn applied(&self, token: usize) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let retired = {
+                let current = &state.current;
+                state
+                    .versions
+                    .iter()
+                    .filter(|tracked| current.get(&tracked.listener) != Some(&tracked.version))
+                    .map(|tracked| tracked.released.clone())
+                    .collect()
+            };
+            state.pending.push((token, retired));
+        }
+
+        self.check();
+    }
+
+    fn failed(&self, token: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.failed.insert(token);
+        state.last_failed = state.last_failed.max(token);
+    }
+
+    fn check(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.versions.retain(|tracked| tracked.released.upgrade().is_some());
+
+        let mut completed = state.completed;
+        state.pending.retain(|(token, retired)| {
+            if retired.iter().all(|released| released.upgrade().is_none()) {
+                completed = completed.max(*token);
+                false
+            } else {
+                true
+            }
+        });
+        state.completed = completed;
+        completed
+    }
+
+    fn outcome(&self, token: usize) -> ReloadOutcome {
+        let completed = self.check();
+        if self.state.lock().unwrap().failed.contains(&token) {
+            ReloadOutcome::Failed
+        } else if completed >= token {
+            ReloadOutcome::Completed
+        } else {
+            ReloadOutcome::Pending
+        }
+    }
+
+    fn status(&self) -> ReloadStatus {
+        let last_reload_completed = self.check();
+        let state = self.state.lock().unwrap();
+        ReloadStatus {
+            last_reload_requested: self.requested.load(Ordering::SeqCst),
+            last_reload_completed,
+            last_reload_failed: state.last_failed,
+            listeners: state.current.clone(),
+        }
+    }
+}
+
+/// Requests a reload, returning the token it's tracked by.
+pub fn request() -> usize { RELOADS.request() }
+
+/// Starts tracking a new version of the given listener, returning the hold that keeps it running.
+pub fn hold_version(listener: &str, version: usize) -> VersionHold { RELOADS.hold_version(listener, version) }
+
+/// Marks the given version of a listener as the one now running.
+pub fn set_current(listener: &str, version: usize) { RELOADS.set_current(listener, Some(version)) }
+
+/// Marks the given listener as no longer running any version.
+pub fn clear_current(listener: &str) { RELOADS.set_current(listener, None) }
+
+/// Marks the reload with the given token as applied.
+///
+/// It completes once every listener version that isn't current has been released.
+pub fn applied(token: usize) { RELOADS.applied(token) }
+
+/// Marks the reload with the given token as having failed, which leaves nothing to wait on.
+///
+/// Failed reloads are never counted as completed, even once a later reload completes.
+pub fn failed(token: usize) { RELOADS.failed(token) }
+
+/// Gets where reloads stand.
+pub fn get_status() -> ReloadStatus { RELOADS.status() }
+
+/// Waits up to the given timeout for the reload with the given token to complete or fail.
+///
+/// Resolves to what had become of it by then.
+pub fn wait(token: usize, timeout: Duration) -> impl Future<Item = ReloadOutcome, Error = ()> {
+    let outcome = RELOADS.outcome(token);
+    if outcome != ReloadOutcome::Pending {
+        return Either::A(Ok(outcome).into_future());
+    }
+
+    let started = Instant::now();
+    let deadline = started + timeout;
+    let waiting = Interval::new(started, Duration::from_millis(RELOAD_CHECK_INTERVAL_MS))
+        .map_err(|e| error!("[reload] timer failed: {}", e))
+        .take_while(move |_| Ok(RELOADS.outcome(token) == ReloadOutcome::Pending && Instant::now() < deadline))
+        .for_each(|_| Ok(()))
+        .map(move |_| RELOADS.outcome(token));
+    Either::B(waiting)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_waits_for_old_versions() {
+        let tracker = ReloadTracker::new();
+
+        let v0 = tracker.hold_version("foo", 0);
+        tracker.set_current("foo", Some(0));
+        let other = tracker.hold_version("bar", 0);
+        tracker.set_current("bar", Some(0));
+
+        let first = tracker.request();
+        let v1 = tracker.hold_version("foo", 1);
+        tracker.set_current("foo", Some(1));
+        tracker.applied(first);
+        assert_eq!(tracker.check(), 0);
+
+        // A later reload can't complete before an earlier one does.
+        let second = tracker.request();
+        let v2 = tracker.hold_version("foo", 2);
+        tracker.set_current("foo", Some(2));
+        drop(v1);
+        tracker.applied(second);
+        assert_eq!(tracker.check(), 0);
+
+        drop(v0);
+        assert_eq!(tracker.check(), second);
+
+        // Current versions never hold up a reload, however long they stay up.
+        let status = tracker.status();
+        assert_eq!(status.last_reload_requested, second);
+        assert_eq!(status.last_reload_completed, second);
+        assert_eq!(status.listeners.get("foo"), Some(&2));
+        assert_eq!(status.listeners.get("bar"), Some(&0));
+        drop((v2, other));
+    }
+
+    #[test]
+    fn test_removed_listener_holds_reload() {
+        let tracker = ReloadTracker::new();
+        let v0 = tracker.hold_version("foo", 0);
+        tracker.set_current("foo", Some(0));
+
+        let token = tracker.request();
+        tracker.set_current("foo", None);
+        tracker.applied(token);
+        assert_eq!(tracker.check(), 0);
+        assert!(tracker.status().listeners.is_empty());
+
+        drop(v0);
+        assert_eq!(tracker.check(), token);
+    }
+
+    #[test]
+    fn test_failed_reload() {
+        let tracker = ReloadTracker::new();
+        let token = tracker.request();
+        tracker.failed(token);
+
+        let status = tracker.status();
+        assert_eq!(status.last_reload_completed, 0);
+        assert_eq!(status.last_reload_failed, token);
+        assert_eq!(tracker.outcome(token), ReloadOutcome::Failed);
+
+        // A failed reload doesn't hold up the next one, and stays failed once that completes.
+        let next = tracker.request();
+        tracker.applied(next);
+        assert_eq!(tracker.outcome(next), ReloadOutcome::Completed);
+        assert_eq!(tracker.outcome(token), ReloadOutcome::Failed);
+
+        let status = tracker.status();
+        assert_eq!(status.last_reload_completed, next);
+        assert_eq!(status.last_reload_failed, token);
+    }
+}
@@ -0,0 +1,464 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::{processor::Processor, retry::RetryBudget};
+use common::{AssignedRequests, AssignedResponses, EnqueuedRequest, EnqueuedRequests, Message, MessageResponse};
+use errors::CreationError;
+use futures::prelude::*;
+use metrics::MetricSink;
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+use tower_service::Service;
+
+/// How the failover router decides what to retry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FailoverConfiguration {
+    /// How many times a request that failed is retried against the failover pool.
+    pub max_retries: usize,
+
+    /// Whether only read commands are retried, leaving writes to fail where they were sent.
+    ///
+    /// A write that failed or timed out may well have been applied anyway, and retrying one that
+    /// isn't idempotent, like `INCR`, applies it twice, so only reads are retried unless this is
+    /// turned off.
+    pub reads_only: bool,
+}
+
+impl FailoverConfiguration {
+    /// Extracts the failover configuration from the given routing options.
+    pub fn from_options(options: &HashMap<String, String>) -> Result<FailoverConfiguration, CreationError> {
+        let max_retries = match options.get("max_retries") {
+            Some(raw) => {
+                usize::from_str(raw.as_str())
+                    .map_err(|_| CreationError::InvalidParameter("routing.max_retries".to_string()))?
+            },
+            None => 1,
+        };
+
+        let reads_only = match options.get("failover_reads_only") {
+            Some(raw) => {
+                bool::from_str(raw.as_str())
+                    .map_err(|_| CreationError::InvalidParameter("routing.failover_reads_only".to_string()))?
+            },
+            None => true,
+        };
+
+        Ok(FailoverConfiguration { max_retries, reads_only })
+    }
+}
+
+/// Routes everything to a default pool, retrying what it fails on against a failover pool.
+///
+/// A read is retried when it never got a response, either because its backend failed or because it
+/// timed out.  Writes are only retried if `failover_reads_only` is turned off.  Retries carry the
+/// position of the request they retry, so their responses land in the same place the original
+/// response would have, and the client gets them back in order.
+///
+/// Retries are paid for out of the default pool's retry budget, so that a struggling default pool
+/// can't double the load on the failover pool.  Once the budget runs dry, failed requests fail
+/// with their original error.
+///
+/// If the default pool stops taking requests altogether, such as when it has no healthy backends
+/// left, whole batches go straight to the failover pool until it starts taking them again.  Those
+/// batches were never sent anywhere, so writes go along with them no matter what.
+#[derive(Clone)]
+pub struct FailoverRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>> + Clone,
+{
+    processor: P,
+    default_inner: S,
+    failover_inner: S,
+    default_ready: bool,
+    config: FailoverConfiguration,
+    retry_budget: Arc<RetryBudget>,
+    sink: MetricSink,
+}
+
+impl<P, S> FailoverRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>> + Clone,
+{
+    pub fn new(
+        processor: P, default_inner: S, failover_inner: S, config: FailoverConfiguration,
+        retry_budget: Arc<RetryBudget>, sink: MetricSink,
+    ) -> FailoverRouter<P, S> {
+        FailoverRouter {
+            processor,
+            default_inner,
+            failover_inner,
+            default_ready: false,
+            config,
+            retry_budget,
+            sink,
+        }
+    }
+}
+
+/// The responses to a batch, including those to any of its requests that had to be retried.
+pub struct FailoverResponse<M, S>
+where
+    M: Message + Clone,
+    S: Service<EnqueuedRequests<M>, Response = AssignedResponses<M>>,
+{
+    current: Option<S::Future>,
+    failover_inner: S,
+
+    // Copies of the requests that can still be retried, by position, and the retries they have left.
+    retryable: HashMap<usize, M>,
+    retries_left: usize,
+    retry_budget: Arc<RetryBudget>,
+
+    // Retries that are waiting on the failover pool to be ready for them.
+    pending: Option<EnqueuedRequests<M>>,
+
+    responses: AssignedResponses<M>,
+    sink: MetricSink,
+}
+
+impl<M, S> FailoverResponse<M, S>
+where
+    M: Message + Clone,
+    S: Service<EnqueuedRequests<M>, Response = AssignedResponses<M>>,
+{
+    /// Sorts the given responses into those that are final and the requests that should be retried.
+    fn collect(&mut self, responses: AssignedResponses<M>) {
+        let mut retries = Vec::new();
+        for (id, response) in responses {
+            let failed = match response {
+                MessageResponse::Failed | MessageResponse::TimedOut => self.retries_left > 0,
+                MessageResponse::Complete(_) => false,
+            };
+
+            match self.retryable.get(&id) {
+                Some(msg) if failed => {
+                    match self.retry_budget.try_retry(response, &self.sink) {
+                        Ok(()) => retries.push(EnqueuedRequest::retry(id, msg.clone())),
+                        Err(response) => self.responses.push((id, response)),
+                    }
+                },
+                _ => self.responses.push((id, response)),
+            }
+        }
+
+        if !retries.is_empty() {
+            self.retries_left -= 1;
+            self.sink.update_count("failovers", retries.len() as i64);
+            self.pending = Some(retries);
+        }
+    }
+}
+
+impl<M, S> Future for FailoverResponse<M, S>
+where
+    M: Message + Clone,
+    S: Service<EnqueuedRequests<M>, Response = AssignedResponses<M>>,
+{
+    type Error = S::Error;
+    type Item = AssignedResponses<M>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                let responses = try_ready!(current.poll());
+                self.current = None;
+                self.collect(responses);
+            }
+
+            match self.pending.take() {
+                Some(retries) => {
+                    if let Async::NotReady = self.failover_inner.poll_ready()? {
+                        self.pending = Some(retries);
+                        return Ok(Async::NotReady);
+                    }
+                    self.current = Some(self.failover_inner.call(retries));
+                },
+                None => {
+                    let responses = self.responses.drain(..).collect();
+                    return Ok(Async::Ready(responses));
+                },
+            }
+        }
+    }
+}
+
+impl<P, S> Service<AssignedRequests<P::Message>> for FailoverRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>> + Clone,
+{
+    type Error = S::Error;
+    type Future = FailoverResponse<P::Message, S>;
+    type Response = AssignedResponses<P::Message>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        // As long as either pool can take a batch, so can we.
+        self.default_ready = self.default_inner.poll_ready()?.is_ready();
+        if self.default_ready {
+            return Ok(Async::Ready(()));
+        }
+
+        self.failover_inner.poll_ready()
+    }
+
+    fn call(&mut self, req: AssignedRequests<P::Message>) -> Self::Future {
+        let processor = &self.processor;
+        let config = self.config;
+        let retryable = if config.max_retries > 0 {
+            req.iter()
+                .filter(|(_, msg)| !config.reads_only || processor.is_read_only(msg))
+                .cloned()
+                .collect()
+        } else {
+            HashMap::new()
+        };
+
+        let batch = req.into_iter().map(|(id, msg)| EnqueuedRequest::new(id, msg)).collect::<Vec<_>>();
+        let current = if self.default_ready {
+            self.default_inner.call(batch)
+        } else {
+            self.sink.update_count("failovers", batch.len() as i64);
+            self.failover_inner.call(batch)
+        };
+        self.default_ready = false;
+
+        FailoverResponse {
+            current: Some(current),
+            failover_inner: self.failover_inner.clone(),
+            retryable,
+            retries_left: config.max_retries,
+            retry_budget: self.retry_budget.clone(),
+            pending: None,
+            responses: Vec::new(),
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::redis::RedisProcessor;
+    use futures::future::{ok, FutureResult};
+    use metrics::get_sink;
+    use protocol::redis::{RedisMessage, RedisTransportConfig};
+    use std::sync::{Arc, Mutex};
+
+    /// A pool that fails every request for a key with the given prefix, if it has one, and
+    /// remembers every key it was sent, and which of them were retries.
+    #[derive(Clone)]
+    struct ScriptedPool {
+        seen: Arc<Mutex<Vec<Vec<u8>>>>,
+        retried: Arc<Mutex<Vec<Vec<u8>>>>,
+        ready: bool,
+        failing: Option<&'static str>,
+    }
+
+    impl ScriptedPool {
+        fn new(ready: bool, failing: Option<&'static str>) -> ScriptedPool {
+            ScriptedPool {
+                seen: Arc::new(Mutex::new(Vec::new())),
+                retried: Arc::new(Mutex::new(Vec::new())),
+                ready,
+                failing,
+            }
+        }
+
+        fn seen(&self) -> Vec<String> {
+            let seen = self.seen.lock().unwrap();
+            seen.iter().map(|key| String::from_utf8_lossy(key).into_owned()).collect()
+        }
+
+        fn retried(&self) -> Vec<String> {
+            let retried = self.retried.lock().unwrap();
+            retried.iter().map(|key| String::from_utf8_lossy(key).into_owned()).collect()
+        }
+    }
+
+    impl Service<EnqueuedRequests<RedisMessage>> for ScriptedPool {
+        type Error = ();
+        type Future = FutureResult<Self::Response, Self::Error>;
+        type Response = AssignedResponses<RedisMessage>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            if self.ready {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+
+        fn call(&mut self, req: EnqueuedRequests<RedisMessage>) -> Self::Future {
+            let mut seen = self.seen.lock().unwrap();
+            let mut retried = self.retried.lock().unwrap();
+            let failing = self.failing;
+            let responses = req
+                .iter()
+                .map(|msg| {
+                    seen.push(msg.key().to_vec());
+                    if msg.is_retry() {
+                        retried.push(msg.key().to_vec());
+                    }
+                    if failing.map_or(false, |prefix| msg.key().starts_with(prefix.as_bytes())) {
+                        (msg.id(), MessageResponse::Failed)
+                    } else {
+                        (msg.id(), MessageResponse::Complete(RedisMessage::OK))
+                    }
+                })
+                .collect();
+            ok(responses)
+        }
+    }
+
+    fn get_router(
+        default: &ScriptedPool, failover: &ScriptedPool, options: &[(&str, &str)],
+    ) -> FailoverRouter<RedisProcessor, ScriptedPool> {
+        // Plenty of budget, so that only the router's own options decide what gets retried.
+        let budget = Arc::new(RetryBudget::new(1.0));
+        budget.deposit(100);
+        get_budgeted_router(default, failover, options, budget)
+    }
+
+    fn get_budgeted_router(
+        default: &ScriptedPool, failover: &ScriptedPool, options: &[(&str, &str)], budget: Arc<RetryBudget>,
+    ) -> FailoverRouter<RedisProcessor, ScriptedPool> {
+        let options = options
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        let config = FailoverConfiguration::from_options(&options).unwrap();
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        FailoverRouter::new(processor, default.clone(), failover.clone(), config, budget, get_sink())
+    }
+
+    fn call(router: &mut FailoverRouter<RedisProcessor, ScriptedPool>, cmds: &[&str]) -> Vec<(usize, bool)> {
+        let req = cmds
+            .iter()
+            .enumerate()
+            .map(|(id, cmd)| (id, RedisMessage::from_inline(cmd)))
+            .collect();
+        assert!(router.poll_ready().unwrap().is_ready());
+        let mut responses = router
+            .call(req)
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|(id, response)| {
+                match response {
+                    MessageResponse::Complete(_) => (id, true),
+                    _ => (id, false),
+                }
+            })
+            .collect::<Vec<_>>();
+        responses.sort();
+        responses
+    }
+
+    #[test]
+    fn test_from_options() {
+        let config = FailoverConfiguration::from_options(&HashMap::new()).unwrap();
+        assert_eq!(
+            config,
+            FailoverConfiguration {
+                max_retries: 1,
+                reads_only: true,
+            }
+        );
+
+        let mut options = HashMap::new();
+        options.insert("max_retries".to_owned(), "-1".to_owned());
+        assert!(FailoverConfiguration::from_options(&options).is_err());
+    }
+
+    #[test]
+    fn test_retries_failed_requests() {
+        let default = ScriptedPool::new(true, Some("down"));
+        let failover = ScriptedPool::new(true, None);
+        let mut router = get_router(&default, &failover, &[]);
+
+        // Only the request that failed is retried, and it answers in its original position.
+        let responses = call(&mut router, &["GET up", "GET down", "SET up2 1"]);
+        assert_eq!(responses, vec![(0, true), (1, true), (2, true)]);
+        assert_eq!(default.seen(), vec!["up", "down", "up2"]);
+        assert_eq!(failover.seen(), vec!["down"]);
+        assert_eq!(failover.retried(), vec!["down"]);
+
+        // Requests are only retried as many times as we're allowed to.
+        let failover = ScriptedPool::new(true, Some("down"));
+        let mut router = get_router(&default, &failover, &[("max_retries", "2")]);
+        let responses = call(&mut router, &["GET down"]);
+        assert_eq!(responses, vec![(0, false)]);
+        assert_eq!(failover.seen(), vec!["down", "down"]);
+    }
+
+    #[test]
+    fn test_retries_limited_by_budget() {
+        let default = ScriptedPool::new(true, Some("down"));
+        let failover = ScriptedPool::new(true, None);
+        let budget = Arc::new(RetryBudget::new(0.5));
+        let mut router = get_budgeted_router(&default, &failover, &[], budget.clone());
+
+        // Without any budget saved up, failures stand.
+        let responses = call(&mut router, &["GET down1", "GET up"]);
+        assert_eq!(responses, vec![(0, false), (1, true)]);
+        assert!(failover.seen().is_empty());
+
+        // Once successful requests have paid for a retry, there's exactly one to go around.
+        budget.deposit(2);
+        let responses = call(&mut router, &["GET down1", "GET down2"]);
+        assert_eq!(responses, vec![(0, true), (1, false)]);
+        assert_eq!(failover.seen(), vec!["down1"]);
+    }
+
+    #[test]
+    fn test_reads_only() {
+        let default = ScriptedPool::new(true, Some("down"));
+        let failover = ScriptedPool::new(true, None);
+        let mut router = get_router(&default, &failover, &[]);
+
+        // Writes are left to fail where they were sent.
+        let responses = call(&mut router, &["GET down1", "SET down2 1", "INCR down3"]);
+        assert_eq!(responses, vec![(0, true), (1, false), (2, false)]);
+        assert_eq!(failover.seen(), vec!["down1"]);
+
+        // Unless we're told that writes can be retried, too.
+        let failover = ScriptedPool::new(true, None);
+        let mut router = get_router(&default, &failover, &[("failover_reads_only", "false")]);
+        let responses = call(&mut router, &["GET down1", "SET down2 1"]);
+        assert_eq!(responses, vec![(0, true), (1, true)]);
+        assert_eq!(failover.seen(), vec!["down1", "down2"]);
+    }
+
+    #[test]
+    fn test_default_not_ready() {
+        let default = ScriptedPool::new(false, None);
+        let failover = ScriptedPool::new(true, Some("down"));
+        let mut router = get_router(&default, &failover, &[("max_retries", "0")]);
+
+        // With nowhere else to go, everything goes to the failover pool.
+        let responses = call(&mut router, &["GET up", "GET down"]);
+        assert_eq!(responses, vec![(0, true), (1, false)]);
+        assert!(default.seen().is_empty());
+        assert_eq!(failover.seen(), vec!["up", "down"]);
+    }
+}
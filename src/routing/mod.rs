@@ -20,6 +20,15 @@
 mod errors;
 pub use self::errors::RouterError;
 
+mod failover;
 mod fixed;
+mod percentage;
 mod shadow;
-pub use self::{fixed::FixedRouter, shadow::ShadowRouter};
+mod split;
+pub use self::{
+    failover::{FailoverConfiguration, FailoverRouter},
+    fixed::FixedRouter,
+    percentage::{percentage_from_options, PercentageRouter},
+    shadow::ShadowRouter,
+    split::SplitRouter,
+};
@@ -0,0 +1,244 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::split::SplitResponse;
+use backend::{
+    hasher::{Fnv64aHasher, KeyHasher},
+    processor::Processor,
+};
+use common::{AssignedRequests, EnqueuedRequest, EnqueuedRequests, Message};
+use errors::CreationError;
+use futures::prelude::*;
+use metrics::MetricSink;
+use std::{collections::HashMap, str::FromStr};
+use tower_service::Service;
+
+/// Extracts the percentage of keys to send to the new pool from the given routing options.
+pub fn percentage_from_options(options: &HashMap<String, String>) -> Result<u64, CreationError> {
+    match options.get("percentage") {
+        Some(raw) => {
+            match u64::from_str(raw.as_str()) {
+                Ok(percentage) if percentage <= 100 => Ok(percentage),
+                _ => Err(CreationError::InvalidParameter("routing.percentage".to_string())),
+            }
+        },
+        None => Ok(0),
+    }
+}
+
+/// Which pool a key belongs to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Side {
+    Old,
+    New,
+}
+
+/// Routes a percentage of keys to a new pool, and the rest to an old one.
+///
+/// Keys are assigned by hash rather than per request, so a key stays on the same side for as long
+/// as the percentage does.  Raising the percentage only ever moves keys from the old pool to the
+/// new one, which lets a migration be dialed up across reloads without keys bouncing back and
+/// forth.
+///
+/// Commands with many keys are fragmented before they get here, so each fragment follows the
+/// assignment of its own key, and a single command can end up being served by both pools.
+#[derive(Clone)]
+pub struct PercentageRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    processor: P,
+    old_inner: S,
+    new_inner: S,
+    percentage: u64,
+    sink: MetricSink,
+}
+
+impl<P, S> PercentageRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    pub fn new(processor: P, old_inner: S, new_inner: S, percentage: u64, sink: MetricSink) -> PercentageRouter<P, S> {
+        PercentageRouter {
+            processor,
+            old_inner,
+            new_inner,
+            percentage,
+            sink,
+        }
+    }
+
+    fn get_side(&self, key: &[u8]) -> Side {
+        // Pools hash keys to pick a backend, so we bucket on the upper half of the hash to keep the
+        // keys we send to a pool from all landing on the same few backends in it.
+        let bucket = (Fnv64aHasher::new().hash(key) >> 32) % 100;
+        if bucket < self.percentage {
+            Side::New
+        } else {
+            Side::Old
+        }
+    }
+}
+
+impl<P, S> Service<AssignedRequests<P::Message>> for PercentageRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+    S::Response: IntoIterator,
+{
+    type Error = S::Error;
+    type Future = SplitResponse<S::Future>;
+    type Response = Vec<<S::Response as IntoIterator>::Item>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        // A pool that can't be sent any keys at this percentage doesn't have to be ready, so either
+        // end of a migration can be dialed all the way over before its pool is taken away.
+        let old_ready = self.percentage == 100 || self.old_inner.poll_ready()?.is_ready();
+        let new_ready = self.percentage == 0 || self.new_inner.poll_ready()?.is_ready();
+        if old_ready && new_ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn call(&mut self, req: AssignedRequests<P::Message>) -> Self::Future {
+        let (new, old): (Vec<_>, Vec<_>) = req.into_iter().partition(|(_, msg)| self.get_side(msg.key()) == Side::New);
+
+        let mut inner = Vec::new();
+        if !old.is_empty() {
+            self.sink.update_count("old_requests", old.len() as i64);
+            let old = old.into_iter().map(|(id, msg)| EnqueuedRequest::new(id, msg)).collect();
+            inner.push(self.old_inner.call(old));
+        }
+        if !new.is_empty() {
+            self.sink.update_count("new_requests", new.len() as i64);
+            let new = new.into_iter().map(|(id, msg)| EnqueuedRequest::new(id, msg)).collect();
+            inner.push(self.new_inner.call(new));
+        }
+
+        SplitResponse::new(inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{redis::RedisProcessor, testing::RecordingPool};
+    use metrics::get_sink;
+    use protocol::redis::{RedisMessage, RedisTransportConfig};
+
+    fn get_router(
+        old: &RecordingPool, new: &RecordingPool, percentage: u64,
+    ) -> PercentageRouter<RedisProcessor, RecordingPool> {
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        PercentageRouter::new(processor, old.clone(), new.clone(), percentage, get_sink())
+    }
+
+    fn get_keys() -> Vec<Vec<u8>> { (0..1000).map(|i| format!("key:{}", i).into_bytes()).collect() }
+
+    #[test]
+    fn test_percentage_from_options() {
+        assert_eq!(percentage_from_options(&HashMap::new()).unwrap(), 0);
+
+        let mut options = HashMap::new();
+        options.insert("percentage".to_owned(), "25".to_owned());
+        assert_eq!(percentage_from_options(&options).unwrap(), 25);
+
+        options.insert("percentage".to_owned(), "101".to_owned());
+        assert!(percentage_from_options(&options).is_err());
+
+        options.insert("percentage".to_owned(), "half".to_owned());
+        assert!(percentage_from_options(&options).is_err());
+    }
+
+    #[test]
+    fn test_keys_only_move_forward() {
+        let pool = RecordingPool::new(true);
+        let keys = get_keys();
+
+        let mut previous = vec![Side::Old; keys.len()];
+        for percentage in (0..=100).step_by(10) {
+            let router = get_router(&pool, &pool, percentage);
+            let sides = keys.iter().map(|key| router.get_side(key)).collect::<Vec<_>>();
+
+            // Nothing that made it over to the new pool ever goes back to the old one.
+            for (before, after) in previous.iter().zip(sides.iter()) {
+                assert!(!(*before == Side::New && *after == Side::Old));
+            }
+
+            // And roughly as many keys made it over as we asked for.
+            let moved = sides.iter().filter(|side| **side == Side::New).count() as u64;
+            assert!(moved * 100 / keys.len() as u64 + 5 >= percentage);
+            assert!(moved * 100 / keys.len() as u64 <= percentage + 5);
+
+            previous = sides;
+        }
+        assert!(previous.iter().all(|side| *side == Side::New));
+    }
+
+    #[test]
+    fn test_fragments_follow_their_own_keys() {
+        let old = RecordingPool::new(true);
+        let new = RecordingPool::new(true);
+        let mut router = get_router(&old, &new, 50);
+
+        let keys = get_keys().into_iter().take(20).collect::<Vec<_>>();
+        let cmd = keys.iter().fold("MGET".to_owned(), |cmd, key| {
+            format!("{} {}", cmd, String::from_utf8_lossy(key))
+        });
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let fragments = processor
+            .fragment_messages(vec![RedisMessage::from_inline(&cmd)])
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .map(|(id, (_, msg))| (id, msg))
+            .collect::<Vec<_>>();
+        assert_eq!(fragments.len(), keys.len());
+
+        assert!(router.poll_ready().unwrap().is_ready());
+        let mut responses = router.call(fragments).wait().unwrap();
+        responses.sort_by_key(|(id, _)| *id);
+        assert_eq!(responses.iter().map(|(id, _)| *id).collect::<Vec<_>>(), (0..keys.len()).collect::<Vec<_>>());
+
+        // Each key went to its own side, so this command was served by both pools.
+        let (expected_new, expected_old): (Vec<_>, Vec<_>) =
+            keys.into_iter().partition(|key| router.get_side(key) == Side::New);
+        assert!(!expected_old.is_empty() && !expected_new.is_empty());
+        assert_eq!(old.seen(), expected_old);
+        assert_eq!(new.seen(), expected_new);
+    }
+
+    #[test]
+    fn test_idle_pool_need_not_be_ready() {
+        let ready = RecordingPool::new(true);
+        let stalled = RecordingPool::new(false);
+
+        assert!(get_router(&ready, &stalled, 0).poll_ready().unwrap().is_ready());
+        assert!(!get_router(&ready, &stalled, 50).poll_ready().unwrap().is_ready());
+        assert!(get_router(&stalled, &ready, 100).poll_ready().unwrap().is_ready());
+        assert!(!get_router(&stalled, &ready, 99).poll_ready().unwrap().is_ready());
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::processor::Processor;
+use common::{AssignedRequests, EnqueuedRequest, EnqueuedRequests, Message};
+use futures::{
+    future::{join_all, JoinAll},
+    prelude::*,
+};
+use tower_service::Service;
+
+/// Routes reads to one pool and everything else to another.
+///
+/// A batch holding both reads and writes is split in two, with each half sent to its own pool.
+/// Responses carry the position of the request they answer, so the client still gets them back
+/// in the order it sent them.
+#[derive(Clone)]
+pub struct SplitRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    processor: P,
+    writes_inner: S,
+    reads_inner: S,
+}
+
+impl<P, S> SplitRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    pub fn new(processor: P, writes_inner: S, reads_inner: S) -> SplitRouter<P, S> {
+        SplitRouter {
+            processor,
+            writes_inner,
+            reads_inner,
+        }
+    }
+}
+
+/// The responses to a batch that may have been split across both pools.
+pub struct SplitResponse<F: Future> {
+    inner: JoinAll<Vec<F>>,
+}
+
+impl<F: Future> SplitResponse<F> {
+    pub fn new(inner: Vec<F>) -> SplitResponse<F> { SplitResponse { inner: join_all(inner) } }
+}
+
+impl<F, T> Future for SplitResponse<F>
+where
+    F: Future,
+    F::Item: IntoIterator<Item = T>,
+{
+    type Error = F::Error;
+    type Item = Vec<T>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let responses = try_ready!(self.inner.poll());
+        Ok(Async::Ready(responses.into_iter().flat_map(|rsp| rsp.into_iter()).collect()))
+    }
+}
+
+impl<P, S> Service<AssignedRequests<P::Message>> for SplitRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+    S::Response: IntoIterator,
+{
+    type Error = S::Error;
+    type Future = SplitResponse<S::Future>;
+    type Response = Vec<<S::Response as IntoIterator>::Item>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        // We don't know which pools a batch needs until we see it, so both have to be ready.
+        let writes_ready = self.writes_inner.poll_ready()?.is_ready();
+        let reads_ready = self.reads_inner.poll_ready()?.is_ready();
+        if writes_ready && reads_ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn call(&mut self, req: AssignedRequests<P::Message>) -> Self::Future {
+        let processor = &self.processor;
+        let (reads, writes): (Vec<_>, Vec<_>) = req.into_iter().partition(|(_, msg)| processor.is_read_only(msg));
+
+        let mut inner = Vec::new();
+        if !writes.is_empty() {
+            let writes = writes.into_iter().map(|(id, msg)| EnqueuedRequest::new(id, msg)).collect();
+            inner.push(self.writes_inner.call(writes));
+        }
+        if !reads.is_empty() {
+            let reads = reads.into_iter().map(|(id, msg)| EnqueuedRequest::new(id, msg)).collect();
+            inner.push(self.reads_inner.call(reads));
+        }
+
+        SplitResponse::new(inner)
+    }
+}
@@ -0,0 +1,733 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::processor::Processor;
+use bytes::{BufMut, BytesMut};
+use common::{EnqueuedRequests, Message};
+use conf::PoolOptions;
+use errors::CreationError;
+use futures::prelude::*;
+use metrics::{get_sink, MetricSink};
+use pruefung::fnv::fnv64::Fnv64a;
+use service::ClientStats;
+use std::{
+    ascii,
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    hash::Hasher,
+    io::{self, BufWriter, Write},
+    os::unix::net::UnixDatagram,
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+};
+use tower_service::Service;
+use util::clock::unix_timestamp_ms;
+
+// How many records can be waiting to be written before we start dropping them.
+const AUDIT_QUEUE_CAPACITY: usize = 8192;
+
+// How big an audit file can grow before it's rotated, unless the pool says otherwise.
+const DEFAULT_AUDIT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+// What's written in place of a client name when the client hasn't given one, or in place of a
+// request identifier when a write wasn't sent on behalf of a client at all.
+const NO_CLIENT_NAME: &str = "-";
+const NO_REQUEST_ID: &str = "-";
+
+lazy_static! {
+    // Every destination records are being written to, by where they're going.  A destination is
+    // written to by a single thread for as long as we're running, no matter how many pools, or
+    // versions of the same pool, send it records, so that nothing else ever rotates its file.
+    static ref STREAMS: Mutex<HashMap<String, Arc<AuditStream>>> = Mutex::new(HashMap::new());
+}
+
+thread_local! {
+    // The client whose requests are being sent on to their pools, while they're being sent, and the
+    // identifier of the request they came in with.
+    static CURRENT_CLIENT: RefCell<Option<(Arc<ClientStats>, usize)>> = RefCell::new(None);
+}
+
+/// Where a pool's audit records are sent.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuditDestination {
+    /// Appended to a file, which is rotated once it would grow past the given number of bytes.
+    ///
+    /// Only the most recently rotated file is kept, next to the current one with `.1` appended to
+    /// its name.
+    File { path: String, max_bytes: usize },
+
+    /// Sent, one record per datagram, to the Unix datagram socket at the given path.
+    Socket(String),
+}
+
+impl AuditDestination {
+    fn path(&self) -> &str {
+        match self {
+            AuditDestination::File { path, .. } => path,
+            AuditDestination::Socket(path) => path,
+        }
+    }
+}
+
+/// Whether, and where, a pool should audit the keys its clients write to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditConfiguration {
+    pub destination: AuditDestination,
+    pub hash_keys: bool,
+}
+
+impl AuditConfiguration {
+    /// Extracts the audit configuration from the given pool options, if the pool wants to audit writes.
+    pub fn from_options(options: &PoolOptions) -> Result<Option<AuditConfiguration>, CreationError> {
+        let destination = match (options.other.get("audit_path"), options.other.get("audit_socket")) {
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => {
+                return Err(CreationError::InvalidParameter(
+                    "options.audit_socket (only one of audit_path and audit_socket can be set)".to_string(),
+                ));
+            },
+            (Some(path), None) => {
+                let max_bytes = match options.audit_max_bytes {
+                    Some(0) => return Err(CreationError::InvalidParameter("options.audit_max_bytes".to_string())),
+                    Some(max_bytes) => max_bytes,
+                    None => DEFAULT_AUDIT_MAX_BYTES,
+                };
+                AuditDestination::File {
+                    path: path.clone(),
+                    max_bytes,
+                }
+            },
+            (None, Some(path)) => AuditDestination::Socket(path.clone()),
+        };
+
+        Ok(Some(AuditConfiguration {
+            destination,
+            hash_keys: options.audit_hash_keys,
+        }))
+    }
+}
+
+/// A single write to a key, as it's reported to the audit log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    pub at_ms: u64,
+    pub listener: Arc<String>,
+    pub pool: Arc<String>,
+    pub command: &'static str,
+    pub key: String,
+    pub client_name: Option<String>,
+    pub request_id: Option<usize>,
+}
+
+impl AuditRecord {
+    /// Encodes this record as a single line of tab-separated fields: when the write happened, in
+    /// milliseconds since the Unix epoch, the listener, the pool, the client's name, the identifier
+    /// of the request the write came in with, the command, and the key.
+    fn encode(&self, buf: &mut BytesMut) {
+        let at_ms = self.at_ms.to_string();
+        let client_name = self.client_name.as_ref().map(|s| s.as_str()).unwrap_or(NO_CLIENT_NAME);
+        let request_id = self.request_id.map(|id| id.to_string());
+        let fields = [
+            at_ms.as_str(),
+            self.listener.as_str(),
+            self.pool.as_str(),
+            client_name,
+            request_id.as_ref().map(|s| s.as_str()).unwrap_or(NO_REQUEST_ID),
+            self.command,
+            self.key.as_str(),
+        ];
+
+        buf.reserve(fields.iter().map(|field| field.len() + 1).sum());
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                buf.put_u8(b'\t');
+            }
+            buf.put_slice(field.as_bytes());
+        }
+        buf.put_u8(b'\n');
+    }
+}
+
+/// Runs the given function on behalf of the given client, for the request with the given
+/// identifier, so that any writes it sends on to an audited pool are attributed to them.
+pub fn with_client<F, R>(client: &Arc<ClientStats>, request_id: usize, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let previous = CURRENT_CLIENT.with(|current| current.replace(Some((client.clone(), request_id))));
+    let result = f();
+    CURRENT_CLIENT.with(|current| current.replace(previous));
+    result
+}
+
+/// Gets the name of the current client, if they've given one, and the identifier of their request.
+fn get_client() -> (Option<String>, Option<usize>) {
+    CURRENT_CLIENT.with(|current| {
+        match current.borrow().as_ref() {
+            Some((client, request_id)) => (client.name(), Some(*request_id)),
+            None => (None, None),
+        }
+    })
+}
+
+/// The single writer of everything sent to a destination.
+struct AuditStream {
+    destination: AuditDestination,
+    tx: Mutex<SyncSender<AuditRecord>>,
+}
+
+impl AuditStream {
+    /// Gets the stream for the given destination, starting it if nothing has written to it yet.
+    fn get(destination: &AuditDestination, capacity: usize) -> io::Result<Arc<AuditStream>> {
+        let mut streams = STREAMS.lock().unwrap();
+        if let Some(stream) = streams.get(destination.path()) {
+            if stream.destination != *destination {
+                warn!(
+                    "[audit] audit log {} is already open as {:?}, which takes precedence over {:?}",
+                    destination.path(),
+                    stream.destination,
+                    destination
+                );
+            }
+            return Ok(stream.clone());
+        }
+
+        let writer = AuditWriter::new(destination)?;
+        let (tx, rx) = sync_channel(capacity);
+        let path = destination.path().to_owned();
+        thread::spawn(move || write_records(rx, writer, &path, &get_sink().scoped("audit")));
+
+        let stream = Arc::new(AuditStream {
+            destination: destination.clone(),
+            tx: Mutex::new(tx),
+        });
+        streams.insert(destination.path().to_owned(), stream.clone());
+        Ok(stream)
+    }
+}
+
+/// Reports which keys are written to through a pool, but never what's written to them.
+///
+/// Records are handed off to the writer thread of their destination, so auditing never blocks a
+/// client.  If the writer falls behind, or whatever is on the other end of it stops reading,
+/// records are dropped and counted rather than queued without bound.
+pub struct AuditLog {
+    listener: Arc<String>,
+    pool: Arc<String>,
+    hash_keys: bool,
+    stream: Arc<AuditStream>,
+    sink: MetricSink,
+}
+
+impl AuditLog {
+    pub fn new(
+        listener: String, pool: String, config: AuditConfiguration, sink: MetricSink,
+    ) -> Result<Arc<AuditLog>, CreationError> {
+        AuditLog::with_capacity(listener, pool, config, sink, AUDIT_QUEUE_CAPACITY)
+    }
+
+    fn with_capacity(
+        listener: String, pool: String, config: AuditConfiguration, sink: MetricSink, capacity: usize,
+    ) -> Result<Arc<AuditLog>, CreationError> {
+        let stream = AuditStream::get(&config.destination, capacity).map_err(|e| {
+            CreationError::InvalidResource(format!("failed to open audit log {:?}: {}", config.destination, e))
+        })?;
+
+        Ok(Arc::new(AuditLog {
+            listener: Arc::new(listener),
+            pool: Arc::new(pool),
+            hash_keys: config.hash_keys,
+            stream,
+            sink,
+        }))
+    }
+
+    /// Gets an auditor that records writes to this log.
+    pub fn auditor(log: &Arc<AuditLog>) -> Auditor {
+        Auditor {
+            log: log.clone(),
+            tx: log.stream.tx.lock().unwrap().clone(),
+        }
+    }
+
+    fn get_key(&self, key: &[u8]) -> String {
+        if self.hash_keys {
+            let mut hasher = Fnv64a::default();
+            hasher.write(key);
+            format!("{:016x}", hasher.finish())
+        } else {
+            // Keys can be anything, so they're escaped to keep every record on its own line.
+            let escaped = key.iter().cloned().flat_map(ascii::escape_default).collect();
+            String::from_utf8(escaped).expect("escaped keys are always ASCII")
+        }
+    }
+}
+
+/// Records writes to an audit log, without contending with anything else recording to it.
+pub struct Auditor {
+    log: Arc<AuditLog>,
+    tx: SyncSender<AuditRecord>,
+}
+
+impl Auditor {
+    /// Records a write of the given command to the given key, by the given client and request.
+    pub fn record(&self, command: &'static str, key: &[u8], client_name: Option<String>, request_id: Option<usize>) {
+        let record = AuditRecord {
+            at_ms: unix_timestamp_ms(),
+            listener: self.log.listener.clone(),
+            pool: self.log.pool.clone(),
+            command,
+            key: self.log.get_key(key),
+            client_name,
+            request_id,
+        };
+
+        match self.tx.try_send(record) {
+            Ok(()) => self.log.sink.increment("records"),
+            Err(_) => self.log.sink.increment("dropped"),
+        }
+    }
+}
+
+impl Clone for Auditor {
+    fn clone(&self) -> Auditor { AuditLog::auditor(&self.log) }
+}
+
+/// Audits the writes sent to a pool, on their way to it.
+///
+/// Every fragment of a command is audited on its own, with the key it's routed by, and attributed
+/// to whichever client is sending it.
+#[derive(Clone)]
+pub struct AuditedPool<P, S> {
+    processor: P,
+    inner: S,
+    auditor: Option<Auditor>,
+}
+
+impl<P, S> AuditedPool<P, S> {
+    pub fn new(processor: P, inner: S, log: Option<Arc<AuditLog>>) -> AuditedPool<P, S> {
+        AuditedPool {
+            processor,
+            inner,
+            auditor: log.as_ref().map(AuditLog::auditor),
+        }
+    }
+}
+
+impl<P, S> Service<EnqueuedRequests<P::Message>> for AuditedPool<P, S>
+where
+    P: Processor,
+    P::Message: Message + Clone,
+    S: Service<EnqueuedRequests<P::Message>>,
+{
+    type Error = S::Error;
+    type Future = S::Future;
+    type Response = S::Response;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> { self.inner.poll_ready() }
+
+    fn call(&mut self, req: EnqueuedRequests<P::Message>) -> Self::Future {
+        if let Some(auditor) = self.auditor.as_ref() {
+            // Only look up who the client is once we know they've written something.
+            let mut client = None;
+            for msg in &req {
+                if self.processor.is_write(msg.request()) {
+                    let command = self.processor.get_command_name(msg.request()).unwrap_or("unknown");
+                    let (name, request_id) = client.get_or_insert_with(get_client).clone();
+                    auditor.record(command, msg.key(), name, request_id);
+                }
+            }
+        }
+
+        self.inner.call(req)
+    }
+}
+
+enum AuditWriter {
+    File {
+        path: String,
+        max_bytes: usize,
+        written: usize,
+        file: BufWriter<File>,
+    },
+    Socket {
+        path: String,
+        socket: UnixDatagram,
+    },
+}
+
+impl AuditWriter {
+    fn new(destination: &AuditDestination) -> io::Result<AuditWriter> {
+        match destination {
+            AuditDestination::File { path, max_bytes } => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let written = file.metadata()?.len() as usize;
+                Ok(AuditWriter::File {
+                    path: path.clone(),
+                    max_bytes: *max_bytes,
+                    written,
+                    file: BufWriter::new(file),
+                })
+            },
+            AuditDestination::Socket(path) => {
+                Ok(AuditWriter::Socket {
+                    path: path.clone(),
+                    socket: UnixDatagram::unbound()?,
+                })
+            },
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            AuditWriter::File {
+                path,
+                max_bytes,
+                written,
+                file,
+            } => {
+                if *written > 0 && *written + buf.len() > *max_bytes {
+                    file.flush()?;
+                    fs::rename(&path, format!("{}.1", path))?;
+                    *file = BufWriter::new(File::create(&path)?);
+                    *written = 0;
+                }
+
+                file.write_all(buf)?;
+                *written += buf.len();
+                Ok(())
+            },
+            AuditWriter::Socket { path, socket } => socket.send_to(buf, &path).map(|_| ()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            AuditWriter::File { file, .. } => file.flush(),
+            AuditWriter::Socket { .. } => Ok(()),
+        }
+    }
+}
+
+fn write_records(rx: Receiver<AuditRecord>, mut writer: AuditWriter, path: &str, sink: &MetricSink) {
+    let mut buf = BytesMut::new();
+    let mut failing = false;
+    loop {
+        // Flush whenever we've caught up, so records show up without having to wait for more.
+        let record = match rx.try_recv() {
+            Ok(record) => record,
+            Err(TryRecvError::Empty) => {
+                let _ = writer.flush();
+                match rx.recv() {
+                    Ok(record) => record,
+                    Err(_) => break,
+                }
+            },
+            Err(TryRecvError::Disconnected) => break,
+        };
+
+        buf.clear();
+        record.encode(&mut buf);
+
+        // Whatever is reading the records may come and go, so we keep trying, but only complain
+        // about it when it first goes away.
+        match writer.write(&buf) {
+            Ok(()) => failing = false,
+            Err(e) => {
+                sink.increment("write_errors");
+                if !failing {
+                    warn!("[audit] failed to write audit record to {}: {}", path, e);
+                    failing = true;
+                }
+            },
+        }
+    }
+
+    let _ = writer.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::redis::RedisProcessor;
+    use common::EnqueuedRequest;
+    use futures::future::{ok, FutureResult};
+    use metrics::capture;
+    use protocol::redis::{RedisMessage, RedisTransportConfig};
+    use std::{env, process, time::Duration};
+
+    fn get_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("synchrotron-audit-{}-{}", name, process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.1", path));
+        path
+    }
+
+    fn get_file_config(path: &str, max_bytes: usize, hash_keys: bool) -> AuditConfiguration {
+        AuditConfiguration {
+            destination: AuditDestination::File {
+                path: path.to_owned(),
+                max_bytes,
+            },
+            hash_keys,
+        }
+    }
+
+    fn read_lines(path: &str, expected: usize) -> Vec<String> {
+        // Wait for the writer thread to notice we're gone and finish writing.
+        let mut lines = Vec::new();
+        for _ in 0..50 {
+            thread::sleep(Duration::from_millis(20));
+            let contents = fs::read_to_string(path).unwrap_or_default();
+            lines = contents.lines().map(|s| s.to_owned()).collect();
+            if lines.len() >= expected {
+                break;
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn test_from_options() {
+        let mut options = PoolOptions::default();
+        assert_eq!(AuditConfiguration::from_options(&options).unwrap(), None);
+
+        options.other.insert("audit_path".to_owned(), "/tmp/audit".to_owned());
+        let audit_config = AuditConfiguration::from_options(&options).unwrap().unwrap();
+        assert_eq!(audit_config, get_file_config("/tmp/audit", 64 * 1024 * 1024, false));
+
+        options.audit_max_bytes = Some(0);
+        assert!(AuditConfiguration::from_options(&options).is_err());
+        options.audit_max_bytes = None;
+
+        options.other.insert("audit_socket".to_owned(), "/tmp/audit.sock".to_owned());
+        assert!(AuditConfiguration::from_options(&options).is_err());
+
+        options.other.remove("audit_path");
+        options.audit_hash_keys = true;
+        let audit_config = AuditConfiguration::from_options(&options).unwrap().unwrap();
+        assert_eq!(audit_config.destination, AuditDestination::Socket("/tmp/audit.sock".to_owned()));
+        assert!(audit_config.hash_keys);
+    }
+
+    #[test]
+    fn test_records_written_to_file() {
+        let path = get_path("file");
+        let (sink, _capture) = capture();
+        let config = get_file_config(&path, 1024 * 1024, false);
+        let log = AuditLog::new("golden".to_owned(), "default".to_owned(), config, sink).unwrap();
+
+        let auditor = AuditLog::auditor(&log);
+        auditor.record("SET", b"user:1", Some("billing".to_owned()), Some(7));
+        auditor.record("DEL", b"odd\tkey\n", None, None);
+        drop(auditor);
+        drop(log);
+
+        let lines = read_lines(&path, 2);
+        let fields = lines.iter().map(|line| line.split('\t').collect::<Vec<_>>()).collect::<Vec<_>>();
+        assert_eq!(fields.len(), 2);
+        assert!(fields[0][0].parse::<u64>().unwrap() > 0);
+        assert_eq!(&fields[0][1..], &["golden", "default", "billing", "7", "SET", "user:1"]);
+        assert_eq!(&fields[1][1..], &["golden", "default", "-", "-", "DEL", "odd\\tkey\\n"]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_hashed_keys() {
+        let path = get_path("hashed");
+        let (sink, _capture) = capture();
+        let config = get_file_config(&path, 1024 * 1024, true);
+        let log = AuditLog::new("golden".to_owned(), "default".to_owned(), config, sink).unwrap();
+
+        let auditor = AuditLog::auditor(&log);
+        auditor.record("SET", b"secret", None, None);
+        auditor.record("SET", b"secret", None, None);
+        drop(auditor);
+        drop(log);
+
+        let lines = read_lines(&path, 2);
+        let keys = lines.iter().map(|line| line.rsplit('\t').next().unwrap()).collect::<Vec<_>>();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].len(), 16);
+        assert_eq!(keys[0], keys[1]);
+        assert!(!lines[0].contains("secret"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_file_rotation() {
+        let path = get_path("rotation");
+        let (sink, _capture) = capture();
+        let config = get_file_config(&path, 256, false);
+        let log = AuditLog::new("golden".to_owned(), "default".to_owned(), config, sink).unwrap();
+
+        let auditor = AuditLog::auditor(&log);
+        for i in 0..20 {
+            auditor.record("SET", format!("key-{}", i).as_bytes(), None, None);
+        }
+        drop(auditor);
+        drop(log);
+
+        // Nothing is lost until a rotated file is rotated out itself, and neither file is ever
+        // bigger than we allow.
+        let current = read_lines(&path, 1);
+        let rotated = read_lines(&format!("{}.1", path), 1);
+        assert!(!rotated.is_empty());
+        assert!(current.last().unwrap().ends_with("\tkey-19"));
+        assert!(fs::metadata(&path).unwrap().len() <= 256);
+        assert!(fs::metadata(format!("{}.1", path)).unwrap().len() <= 256);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.1", path));
+    }
+
+    #[test]
+    fn test_records_sent_to_socket() {
+        let path = get_path("socket");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let (sink, _capture) = capture();
+        let config = AuditConfiguration {
+            destination: AuditDestination::Socket(path.clone()),
+            hash_keys: false,
+        };
+        let log = AuditLog::new("golden".to_owned(), "default".to_owned(), config, sink).unwrap();
+        AuditLog::auditor(&log).record("INCR", b"counter", Some("worker".to_owned()), Some(12));
+
+        let mut buf = [0; 1024];
+        let n = receiver.recv(&mut buf).unwrap();
+        let record = String::from_utf8_lossy(&buf[..n]);
+        assert!(record.ends_with("\tgolden\tdefault\tworker\t12\tINCR\tcounter\n"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_stalled_socket_drops_records() {
+        // Nobody ever reads from this socket, so once its buffer fills up the writer is stuck, and
+        // the queue behind it fills up, too.  Clients still never wait on any of it.
+        let path = get_path("stalled");
+        let _receiver = UnixDatagram::bind(&path).unwrap();
+
+        let (sink, capture) = capture();
+        let sink = sink.scoped(&["listeners", "golden", "pools", "default", "audit"]);
+        let config = AuditConfiguration {
+            destination: AuditDestination::Socket(path.clone()),
+            hash_keys: false,
+        };
+        let log = AuditLog::with_capacity("golden".to_owned(), "default".to_owned(), config, sink, 16).unwrap();
+
+        let auditor = AuditLog::auditor(&log);
+        for _ in 0..10_000 {
+            auditor.record("SET", b"key", None, None);
+        }
+
+        let counts = capture.counts();
+        let get = |name: &str| counts.get(name).cloned().unwrap_or(0);
+        let records = get("listeners.golden.pools.default.audit.records");
+        let dropped = get("listeners.golden.pools.default.audit.dropped");
+        assert!(dropped > 0);
+        assert_eq!(records + dropped, 10_000);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_destinations_are_shared() {
+        // Every pool writing to the same file, including the old and new versions of a pool across
+        // a reload, goes through the same writer, so only one thing ever rotates it.
+        let path = get_path("shared");
+        let (sink, _capture) = capture();
+        let config = get_file_config(&path, 256, false);
+        let old = AuditLog::new("golden".to_owned(), "default".to_owned(), config.clone(), sink.clone()).unwrap();
+        let new = AuditLog::new("golden".to_owned(), "default".to_owned(), config.clone(), sink.clone()).unwrap();
+        let other = AuditLog::new("silver".to_owned(), "writes".to_owned(), config, sink).unwrap();
+        assert!(Arc::ptr_eq(&old.stream, &new.stream));
+        assert!(Arc::ptr_eq(&old.stream, &other.stream));
+
+        let (old, new) = (AuditLog::auditor(&old), AuditLog::auditor(&new));
+        for i in 0..20 {
+            old.record("SET", format!("old-{}", i).as_bytes(), None, None);
+            new.record("SET", format!("new-{}", i).as_bytes(), None, None);
+        }
+
+        // Rotation only ever moves whole records, written in the order they were sent.
+        let current = read_lines(&path, 1);
+        assert!(current.last().unwrap().ends_with("\tnew-19"));
+        for path in &[path.clone(), format!("{}.1", path)] {
+            let contents = fs::read_to_string(path).unwrap();
+            assert!(contents.len() <= 256);
+            assert!(contents.lines().all(|line| line.split('\t').count() == 7));
+        }
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.1", path));
+    }
+
+    struct NullPool;
+
+    impl Service<EnqueuedRequests<RedisMessage>> for NullPool {
+        type Error = ();
+        type Future = FutureResult<(), ()>;
+        type Response = ();
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> { Ok(Async::Ready(())) }
+
+        fn call(&mut self, _req: EnqueuedRequests<RedisMessage>) -> Self::Future { ok(()) }
+    }
+
+    #[test]
+    fn test_audited_pool() {
+        let path = get_path("pool");
+        let (sink, _capture) = capture();
+        let config = get_file_config(&path, 1024 * 1024, false);
+        let log = AuditLog::new("golden".to_owned(), "writes".to_owned(), config, sink).unwrap();
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let mut pool = AuditedPool::new(processor, NullPool, Some(log));
+
+        // Only writes are audited, each key on its own, and by whoever sent them, in whichever request.
+        let client = Arc::new(ClientStats::default());
+        client.set_name("billing".to_owned());
+        let requests = vec![
+            EnqueuedRequest::new(0, RedisMessage::from_inline("GET user:1")),
+            EnqueuedRequest::new(1, RedisMessage::from_inline("SET user:1 a")),
+            EnqueuedRequest::new(2, RedisMessage::from_inline("DEL user:2")),
+        ];
+        with_client(&client, 42, || pool.call(requests)).wait().unwrap();
+
+        // Anything sent outside of a client, like warming up a pool, has nobody to attribute it to.
+        pool.call(vec![EnqueuedRequest::new(3, RedisMessage::from_inline("SET user:3 b"))])
+            .wait()
+            .unwrap();
+
+        let lines = read_lines(&path, 3);
+        let fields = lines.iter().map(|line| line.split('\t').skip(1).collect::<Vec<_>>()).collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                vec!["golden", "writes", "billing", "42", "SET", "user:1"],
+                vec!["golden", "writes", "billing", "42", "DEL", "user:2"],
+                vec!["golden", "writes", "-", "-", "SET", "user:3"],
+            ]
+        );
+        let _ = fs::remove_file(&path);
+    }
+}
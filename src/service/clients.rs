@@ -0,0 +1,421 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::SocketAddr,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::oneshot::{channel, Receiver, Sender};
+use util::clock::unix_timestamp_secs;
+
+lazy_static! {
+    static ref REGISTRIES: Mutex<HashMap<String, Arc<ClientRegistry>>> = Mutex::new(HashMap::new());
+}
+
+/// Gets the client registry for the given listener, creating it if it doesn't exist yet.
+///
+/// Registries are keyed by listener name, so every version of a listener across reloads shares
+/// the same registry, and operations on it cover clients that are still draining.
+pub fn get_client_registry(listener: &str) -> Arc<ClientRegistry> {
+    let mut registries = REGISTRIES.lock().unwrap();
+    registries
+        .entry(listener.to_owned())
+        .or_insert_with(|| Arc::new(ClientRegistry::new()))
+        .clone()
+}
+
+/// Gets the client registry for the given listener, if the listener exists.
+pub fn find_client_registry(listener: &str) -> Option<Arc<ClientRegistry>> {
+    let registries = REGISTRIES.lock().unwrap();
+    registries.get(listener).cloned()
+}
+
+struct ClientEntry {
+    addr: SocketAddr,
+    connected_at: u64,
+    stats: Arc<ClientStats>,
+    close: Option<Sender<()>>,
+}
+
+/// Counters for a single client connection, updated by its pipeline as it runs.
+#[derive(Default)]
+pub struct ClientStats {
+    commands: AtomicUsize,
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+    queue_depth: AtomicUsize,
+    subscribed: AtomicBool,
+    multi: AtomicBool,
+    name: Mutex<Option<String>>,
+}
+
+impl ClientStats {
+    /// Records a batch of requests read from the client.
+    pub fn record_received(&self, commands: usize, bytes: usize) {
+        self.commands.fetch_add(commands, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records responses written back to the client.
+    pub fn record_sent(&self, bytes: usize) { self.bytes_out.fetch_add(bytes, Ordering::Relaxed); }
+
+    /// Sets the number of requests the client is waiting on responses for.
+    pub fn set_queue_depth(&self, depth: usize) { self.queue_depth.store(depth, Ordering::Relaxed); }
+
+    /// Sets the name the client has given itself.
+    pub fn set_name(&self, name: String) { *self.name.lock().unwrap() = Some(name); }
+
+    /// Gets the name the client has given itself, if any.
+    pub fn name(&self) -> Option<String> { self.name.lock().unwrap().clone() }
+
+    /// Sets whether or not the client is subscribed to any channels.
+    pub fn set_subscribed(&self, subscribed: bool) { self.subscribed.store(subscribed, Ordering::Relaxed); }
+
+    /// Sets whether or not the client is queueing up a transaction.
+    pub fn set_multi(&self, multi: bool) { self.multi.store(multi, Ordering::Relaxed); }
+
+    /// Whether or not the client is subscribed to anything, or waiting on responses to anything, and
+    /// so has good reason to be quiet.
+    pub fn is_busy(&self) -> bool {
+        self.subscribed.load(Ordering::Relaxed) || self.queue_depth.load(Ordering::Relaxed) > 0
+    }
+}
+
+/// A point-in-time view of a connected client, as reported by the admin API.
+#[derive(Debug, Serialize)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: String,
+    pub name: Option<String>,
+    pub connected_at: u64,
+    pub commands: usize,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub queue_depth: usize,
+    pub flags: Vec<&'static str>,
+}
+
+impl ClientInfo {
+    fn from_entry(id: u64, entry: &ClientEntry) -> ClientInfo {
+        let mut flags = Vec::new();
+        if entry.close.is_none() {
+            flags.push("closing");
+        }
+        if entry.stats.subscribed.load(Ordering::Relaxed) {
+            flags.push("subscribed");
+        }
+        if entry.stats.multi.load(Ordering::Relaxed) {
+            flags.push("multi");
+        }
+
+        ClientInfo {
+            id,
+            addr: entry.addr.to_string(),
+            name: entry.stats.name.lock().unwrap().clone(),
+            connected_at: entry.connected_at,
+            commands: entry.stats.commands.load(Ordering::Relaxed),
+            bytes_in: entry.stats.bytes_in.load(Ordering::Relaxed),
+            bytes_out: entry.stats.bytes_out.load(Ordering::Relaxed),
+            queue_depth: entry.stats.queue_depth.load(Ordering::Relaxed),
+            flags,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Clients {
+    next_id: u64,
+    entries: BTreeMap<u64, ClientEntry>,
+}
+
+/// The connected clients of a listener.
+///
+/// Each client gets a close handle when it registers, which fires when the client has been asked
+/// to disconnect.  Clients are given IDs in the order they connect, and an ID is never handed out
+/// twice, so an ID taken from a listing can't end up naming some other client that connected
+/// after the one it named went away.
+#[derive(Default)]
+pub struct ClientRegistry {
+    clients: Mutex<Clients>,
+}
+
+impl ClientRegistry {
+    pub fn new() -> ClientRegistry { ClientRegistry::default() }
+
+    /// Registers a client, returning its registration and the close handle for its connection.
+    ///
+    /// The client stays in the registry until the registration is dropped.
+    pub fn register(registry: &Arc<ClientRegistry>, addr: SocketAddr) -> (ClientRegistration, Receiver<()>) {
+        let (tx, rx) = channel();
+        let stats = Arc::new(ClientStats::default());
+        let connected_at = unix_timestamp_secs();
+        let id = {
+            let mut clients = registry.clients.lock().unwrap();
+            let id = clients.next_id;
+            clients.next_id += 1;
+            clients.entries.insert(
+                id,
+                ClientEntry {
+                    addr,
+                    connected_at,
+                    stats: stats.clone(),
+                    close: Some(tx),
+                },
+            );
+            id
+        };
+        let registration = ClientRegistration {
+            registry: registry.clone(),
+            id,
+            stats,
+        };
+        (registration, rx)
+    }
+
+    fn deregister(&self, id: u64) { self.clients.lock().unwrap().entries.remove(&id); }
+
+    /// Asks every client connected from the given address to disconnect.
+    ///
+    /// Clients stop reading new requests, but finish sending responses for any requests they
+    /// already have in flight.  Returns the number of clients that were asked to disconnect.
+    pub fn kill_by_addr(&self, addr: &SocketAddr) -> usize { self.kill_matching(|client| client.addr == *addr) }
+
+    /// Asks every client that has named itself the given name, with `CLIENT SETNAME`, to disconnect.
+    ///
+    /// Returns the number of clients that were asked to disconnect.
+    pub fn kill_by_name(&self, name: &str) -> usize {
+        self.kill_matching(|client| client.stats.name.lock().unwrap().as_ref().map(|n| n.as_str()) == Some(name))
+    }
+
+    fn kill_matching<F>(&self, matches: F) -> usize
+    where
+        F: Fn(&ClientEntry) -> bool,
+    {
+        let mut clients = self.clients.lock().unwrap();
+        let mut killed = 0;
+        for client in clients.entries.values_mut() {
+            if matches(client) {
+                if let Some(close) = client.close.take() {
+                    let _ = close.send(());
+                    killed += 1;
+                }
+            }
+        }
+
+        killed
+    }
+
+    /// Asks the client with the given ID to disconnect.
+    ///
+    /// Returns `true` if the client was connected and hadn't already been asked to disconnect.
+    pub fn kill_by_id(&self, id: u64) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        match clients.entries.get_mut(&id).and_then(|client| client.close.take()) {
+            Some(close) => {
+                let _ = close.send(());
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Lists the connected clients, in order of their IDs.
+    ///
+    /// Only clients with an ID greater than `after` are listed, if given, and at most `limit` of
+    /// them, so that large listeners can be paged through.  Returns the total number of connected
+    /// clients alongside the page.
+    pub fn list(&self, after: Option<u64>, limit: usize) -> (usize, Vec<ClientInfo>) {
+        let clients = self.clients.lock().unwrap();
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        let page = clients
+            .entries
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(id, entry)| ClientInfo::from_entry(*id, entry))
+            .collect();
+
+        (clients.entries.len(), page)
+    }
+}
+
+impl fmt::Debug for ClientRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let clients = self.clients.lock().unwrap();
+        f.debug_struct("ClientRegistry")
+            .field("clients", &clients.entries.len())
+            .finish()
+    }
+}
+
+/// A client's place in a registry.
+pub struct ClientRegistration {
+    registry: Arc<ClientRegistry>,
+    id: u64,
+    stats: Arc<ClientStats>,
+}
+
+impl ClientRegistration {
+    /// Gets the counters for this client.
+    pub fn stats(&self) -> Arc<ClientStats> { self.stats.clone() }
+}
+
+impl Drop for ClientRegistration {
+    fn drop(&mut self) { self.registry.deregister(self.id) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        future::{lazy, ok},
+        prelude::*,
+    };
+
+    #[test]
+    fn test_kill_by_addr() {
+        let registry = Arc::new(ClientRegistry::new());
+        let addr1 = "127.0.0.1:5000".parse().unwrap();
+        let addr2 = "127.0.0.1:5001".parse().unwrap();
+
+        let (reg1, mut rx1) = ClientRegistry::register(&registry, addr1);
+        let (reg2, mut rx2) = ClientRegistry::register(&registry, addr2);
+        assert_eq!(registry.kill_by_addr(&addr1), 1);
+
+        lazy(|| {
+            match rx1.poll() {
+                Ok(Async::Ready(())) => {},
+                _ => panic!("killed client should have been signalled"),
+            }
+            match rx2.poll() {
+                Ok(Async::NotReady) => {},
+                _ => panic!("other client should not have been signalled"),
+            }
+            ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        // Killing a client twice only signals it once.
+        assert_eq!(registry.kill_by_addr(&addr1), 0);
+        assert_eq!(registry.kill_by_addr(&"127.0.0.1:5002".parse().unwrap()), 0);
+
+        // Once deregistered, a client can't be killed.
+        drop(reg1);
+        drop(reg2);
+        assert_eq!(registry.kill_by_addr(&addr2), 0);
+    }
+
+    #[test]
+    fn test_list_and_kill_by_id() {
+        let registry = Arc::new(ClientRegistry::new());
+        let addr = "127.0.0.1:5000".parse().unwrap();
+
+        let regs = (0..5)
+            .map(|_| ClientRegistry::register(&registry, addr))
+            .collect::<Vec<_>>();
+        regs[1].0.stats().record_received(3, 42);
+        regs[1].0.stats().record_sent(7);
+        regs[1].0.stats().set_queue_depth(2);
+        regs[1].0.stats().set_name("worker".to_owned());
+
+        let (total, page) = registry.list(None, 2);
+        assert_eq!(total, 5);
+        assert_eq!(page.iter().map(|c| c.id).collect::<Vec<_>>(), vec![0, 1]);
+
+        let client = &page[1];
+        assert_eq!(client.addr, "127.0.0.1:5000");
+        assert_eq!(client.name, Some("worker".to_owned()));
+        assert_eq!((client.commands, client.bytes_in, client.bytes_out), (3, 42, 7));
+        assert_eq!(client.queue_depth, 2);
+        assert!(client.flags.is_empty());
+
+        // Paging picks up after the last ID we saw.
+        let (_, page) = registry.list(Some(1), 10);
+        assert_eq!(page.iter().map(|c| c.id).collect::<Vec<_>>(), vec![2, 3, 4]);
+
+        // Killed clients are flagged until they actually go away, and can only be killed once.
+        assert!(registry.kill_by_id(3));
+        assert!(!registry.kill_by_id(3));
+        assert!(!registry.kill_by_id(42));
+        let (_, page) = registry.list(Some(2), 1);
+        assert_eq!(page[0].flags, vec!["closing"]);
+
+        // So are subscribed clients, and those in the middle of a transaction.
+        regs[4].0.stats().set_subscribed(true);
+        regs[4].0.stats().set_multi(true);
+        let (_, page) = registry.list(Some(3), 1);
+        assert_eq!(page[0].flags, vec!["subscribed", "multi"]);
+    }
+
+    #[test]
+    fn test_kill_by_name() {
+        let registry = Arc::new(ClientRegistry::new());
+        let addr = "127.0.0.1:5000".parse().unwrap();
+
+        let regs = (0..3)
+            .map(|_| ClientRegistry::register(&registry, addr))
+            .collect::<Vec<_>>();
+        regs[0].0.stats().set_name("worker".to_owned());
+        regs[2].0.stats().set_name("worker".to_owned());
+        regs[1].0.stats().set_name("web".to_owned());
+
+        // Every client going by the name is killed, and only once.
+        assert_eq!(registry.kill_by_name("worker"), 2);
+        assert_eq!(registry.kill_by_name("worker"), 0);
+        assert_eq!(registry.kill_by_name("cron"), 0);
+
+        let (_, page) = registry.list(None, 10);
+        let flags = page.iter().map(|c| c.flags.clone()).collect::<Vec<_>>();
+        assert_eq!(flags, vec![vec!["closing"], vec![], vec!["closing"]]);
+    }
+
+    #[test]
+    fn test_ids_are_never_reused() {
+        let registry = Arc::new(ClientRegistry::new());
+        let addr = "127.0.0.1:5000".parse().unwrap();
+
+        let (first, _rx1) = ClientRegistry::register(&registry, addr);
+        drop(first);
+
+        // Whoever connects next can't be killed by the ID of the client that went away.
+        let (_second, mut rx2) = ClientRegistry::register(&registry, addr);
+        assert!(!registry.kill_by_id(0));
+        let (_, page) = registry.list(None, 10);
+        assert_eq!(page.iter().map(|c| c.id).collect::<Vec<_>>(), vec![1]);
+
+        lazy(|| {
+            match rx2.poll() {
+                Ok(Async::NotReady) => {},
+                _ => panic!("new client should not have been signalled"),
+            }
+            ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
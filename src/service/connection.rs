@@ -0,0 +1,462 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::processor::Processor;
+use common::Message;
+use futures::prelude::*;
+use lifecycle::{self, ShutdownHandle, ShutdownPhase};
+use log::Level;
+use metrics::MetricSink;
+use protocol::errors::{is_disconnect, ProtocolError};
+use pruefung::fnv::fnv64::Fnv64a;
+use service::{
+    ClientLatencies, ClientRegistration, ClientRegistry, ClientSlot, ClientStats, ConnectionSetup, FragmentLimits,
+    KeySampler, PipelineError, SloTable,
+};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    hash::Hasher,
+    io,
+    net::SocketAddr,
+    os::unix::io::RawFd,
+    sync::{
+        atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT},
+        Arc,
+    },
+    time::Instant,
+};
+use tokio::sync::oneshot::Receiver;
+use tokio_evacuate::Warden;
+use tower_service::Service;
+use util::{
+    clock::{duration_as_us, elapsed, saturating_duration_since},
+    FdGuard, LogLimiter,
+};
+
+lazy_static! {
+    static ref CLIENT_ERRORS: LogLimiter = LogLimiter::new("client");
+}
+
+// Every batch read from any client gets the next of these as its request identifier, which is how
+// the latency exemplars and audit records of a batch can be found in the logs.
+static NEXT_REQUEST_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A batch that's still waiting on some of its responses.
+struct PendingBatch {
+    received: Instant,
+    remaining: usize,
+    request_id: usize,
+    key_hash: u64,
+}
+
+/// Where a client connection is in its life.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ConnectionState {
+    /// Requests are being read from the client.
+    Open,
+
+    /// The client is done sending requests, or has been asked to disconnect, so only the
+    /// responses it's still owed are being sent.
+    Closing,
+
+    /// The connection has gone away, for better or worse.
+    Closed,
+}
+
+/// Things that have to be held for exactly as long as the client is connected.
+struct ConnectionGuards {
+    _registration: ClientRegistration,
+    _fd: FdGuard,
+    _slot: Option<ClientSlot>,
+    _routing: ShutdownHandle,
+}
+
+/// A single client connection, and all of the state that goes along with it.
+///
+/// The pipeline drives the client's transport and the requests flowing through it, and tells the
+/// connection about each event in its life as it happens: batches of requests coming in, responses
+/// going out, and the connection closing, cleanly or otherwise.  Everything that lasts as long as
+/// the client does lives here, rather than being threaded through the pipeline.
+pub struct ClientConnection {
+    addr: SocketAddr,
+    state: ConnectionState,
+    guards: Option<ConnectionGuards>,
+    stats: Arc<ClientStats>,
+    close: Option<Receiver<()>>,
+    warden: Warden,
+
+    limits: FragmentLimits,
+    throttled_since: Option<Instant>,
+    key_sampler: Option<Arc<KeySampler>>,
+
+    // The identifier of the latest batch read from the client, which is the one being sent on.
+    request_id: usize,
+
+    // When each request still waiting on a response was read, and the objective it's held to.
+    slo: Option<Arc<SloTable>>,
+    slo_pending: VecDeque<(Instant, Option<usize>)>,
+
+    // Each batch still waiting on responses, along with how far along the client is in setting up
+    // its connection.
+    latencies: Option<Arc<ClientLatencies>>,
+    batch_pending: VecDeque<PendingBatch>,
+    setup: Option<ConnectionSetup>,
+
+    listener_sink: MetricSink,
+    sink: MetricSink,
+}
+
+impl ClientConnection {
+    /// Creates a new `ClientConnection` for a client that was just accepted by a listener.
+    ///
+    /// The client is added to the registry, and holds up both the listener's reload and the
+    /// shutdown of the router it sends requests to until it's closed.
+    pub fn new(
+        addr: SocketAddr, registry: &Arc<ClientRegistry>, fd: FdGuard, warden: Warden, sink: MetricSink,
+    ) -> ClientConnection {
+        let (registration, close) = ClientRegistry::register(registry, addr);
+        let stats = registration.stats();
+
+        // Every client holds on to our router, so shutting down waits for them to be gone
+        // before stopping anything the router depends on.
+        let routing = lifecycle::register(ShutdownPhase::StopRouters, format!("clients.{}", addr));
+
+        warden.increment();
+        sink.update_level("clients_connected", 1);
+        debug!("[client] {} connected", addr);
+
+        ClientConnection {
+            addr,
+            state: ConnectionState::Open,
+            guards: Some(ConnectionGuards {
+                _registration: registration,
+                _fd: fd,
+                _slot: None,
+                _routing: routing,
+            }),
+            stats,
+            close: Some(close),
+            warden,
+            limits: FragmentLimits::default(),
+            throttled_since: None,
+            key_sampler: None,
+            request_id: 0,
+            slo: None,
+            slo_pending: VecDeque::new(),
+            latencies: None,
+            batch_pending: VecDeque::new(),
+            setup: None,
+            sink: sink.scoped("client"),
+            listener_sink: sink,
+        }
+    }
+
+    /// Sets the slot the client takes up in its listener's client limit, given back once it disconnects.
+    pub fn set_client_slot(mut self, slot: ClientSlot) -> Self {
+        if let Some(guards) = self.guards.as_mut() {
+            guards._slot = Some(slot);
+        }
+        self
+    }
+
+    /// Sets the limits on how many backend requests the client's commands can fan out into.
+    pub fn set_fragment_limits(mut self, limits: FragmentLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets the sampler that the keys of the client's commands are fed to.
+    pub fn set_key_sampler(mut self, key_sampler: Option<Arc<KeySampler>>) -> Self {
+        self.key_sampler = key_sampler;
+        self
+    }
+
+    /// Sets the latency objectives that the client's requests are held to.
+    pub fn set_slo_table(mut self, slo: Option<Arc<SloTable>>) -> Self {
+        self.slo = slo;
+        self
+    }
+
+    /// Sets the histograms that the client's latencies are recorded in.
+    ///
+    /// If given, `fd` is the client's socket, which is watched for the first byte the client sends.
+    pub fn set_latencies(mut self, latencies: Option<Arc<ClientLatencies>>, fd: Option<RawFd>) -> Self {
+        self.setup = latencies.as_ref().map(|_| ConnectionSetup::new(fd));
+        self.latencies = latencies;
+        self
+    }
+
+    /// Gets the counters for the client, as listed by its registry.
+    pub fn stats(&self) -> Arc<ClientStats> { self.stats.clone() }
+
+    /// Sets whether or not the client is subscribed to any channels.
+    pub fn set_subscribed(&self, subscribed: bool) { self.stats.set_subscribed(subscribed); }
+
+    /// Whether or not the client is done sending requests.
+    pub fn is_closing(&self) -> bool { self.state != ConnectionState::Open }
+
+    /// Checks whether the client has been asked to disconnect.
+    ///
+    /// If the other side of the close handle went away without asking, we just stop listening to it.
+    pub fn poll_close(&mut self) {
+        if let Some(mut close) = self.close.take() {
+            match close.poll() {
+                Ok(Async::Ready(())) => self.on_closing(),
+                Ok(Async::NotReady) => self.close = Some(close),
+                Err(_) => {},
+            }
+        }
+    }
+
+    /// Handles the client signalling that it has no more requests to send.
+    pub fn on_eof(&mut self) {
+        assert!(!self.is_closing());
+        self.on_closing();
+    }
+
+    /// Checks whether a client that hasn't sent anything yet has started to.
+    pub fn poll_first_byte(&mut self) {
+        if let (Some(setup), Some(latencies)) = (self.setup.as_mut(), self.latencies.as_ref()) {
+            setup.poll_first_byte(latencies);
+        }
+    }
+
+    fn on_closing(&mut self) {
+        if self.state == ConnectionState::Open {
+            self.state = ConnectionState::Closing;
+        }
+    }
+
+    /// Handles a batch of requests read from the client.
+    ///
+    /// Every request is answered, in order, so the time each one was read is kept until its
+    /// response is sent to see how it measured up against its latency objective.
+    pub fn on_batch<P>(&mut self, processor: &P, batch: &[P::Message], batch_size: usize)
+    where
+        P: Processor,
+    {
+        self.sink.update_count("messages_received", batch.len() as i64);
+        self.sink.update_count("bytes_received", batch_size as i64);
+        self.stats.record_received(batch.len(), batch_size);
+
+        let now = Instant::now();
+        let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed).wrapping_add(1).max(1);
+        self.request_id = request_id;
+        trace!("[client] {} sent request {} with {} commands", self.addr, request_id, batch.len());
+
+        if let Some(latencies) = self.latencies.as_ref() {
+            // The batch is known by its first key, which is good enough to find it by.
+            let mut hasher = Fnv64a::default();
+            hasher.write(batch.first().map_or(&[][..], |msg| msg.key()));
+            let key_hash = hasher.finish();
+
+            self.batch_pending.push_back(PendingBatch {
+                received: now,
+                remaining: batch.len(),
+                request_id,
+                key_hash,
+            });
+            if let Some(setup) = self.setup.as_mut() {
+                setup.on_first_command(latencies, request_id, key_hash);
+            }
+        }
+
+        for msg in batch {
+            if let Some(slo) = self.slo.as_ref() {
+                let class = slo.get_class(processor.get_command_id(msg));
+                self.slo_pending.push_back((now, class));
+            }
+
+            if processor.has_routing_hint(msg) {
+                self.sink.increment("routing_hints");
+            }
+
+            if let Some(name) = processor.get_client_name(msg) {
+                self.stats.set_name(name);
+            }
+
+            if let Some(sampler) = self.key_sampler.as_ref() {
+                if !msg.is_inline() {
+                    sampler.sample(msg.key());
+                }
+            }
+        }
+    }
+
+    /// Checks a command against the client's per-command fragment limit.
+    ///
+    /// If the command has too many fragments, the error it should be answered with, locally, is
+    /// returned.
+    pub fn on_command(&self, fragments: usize) -> Option<String> {
+        match self.limits.max_per_command {
+            Some(max) if fragments > max => {
+                self.sink.increment("fragment_rejections");
+                Some(format!("command has {} keys, which is more than the limit of {}", fragments, max))
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether or not a command can be sent while `in_flight` fragments are already outstanding.
+    ///
+    /// A command is always let through on its own, no matter its size, so that a client can never
+    /// get stuck behind the limit.
+    pub fn can_dispatch(&self, in_flight: usize, fragments: usize) -> bool {
+        match self.limits.max_concurrent {
+            Some(max) => in_flight == 0 || in_flight + fragments <= max,
+            None => true,
+        }
+    }
+
+    /// Handles a command being answered by us, rather than by a backend.
+    pub fn on_local_command(&self) { self.sink.increment("local_commands"); }
+
+    /// Handles the client having too many fragments outstanding to send anything else.
+    pub fn on_throttled(&mut self) {
+        if self.throttled_since.is_none() {
+            self.throttled_since = Some(Instant::now());
+            self.sink.increment("fragment_throttles");
+        }
+    }
+
+    /// Handles commands being sent on behalf of the client.
+    pub fn on_dispatched(&mut self) {
+        if let Some(since) = self.throttled_since.take() {
+            self.sink.update_count("fragment_throttle_us", duration_as_us(elapsed(since)) as i64);
+        }
+    }
+
+    /// Handles responses being written back to the client.
+    pub fn on_sent(&mut self, msgs: u64, bytes: usize) {
+        self.sink.update_count("messages_sent", msgs as i64);
+        self.sink.update_count("bytes_sent", bytes as i64);
+        self.stats.record_sent(bytes);
+
+        if let Some(slo) = self.slo.as_ref() {
+            let now = Instant::now();
+            for _ in 0..msgs {
+                match self.slo_pending.pop_front() {
+                    Some((received, Some(class))) => slo.record(class, saturating_duration_since(now, received)),
+                    Some((_, None)) => {},
+                    None => break,
+                }
+            }
+        }
+
+        // Each command has been serviced once its own response has been sent, and a batch once the
+        // last of its responses has.
+        if let Some(latencies) = self.latencies.as_ref() {
+            if msgs > 0 {
+                if let Some(mut setup) = self.setup.take() {
+                    setup.on_first_response(latencies);
+                }
+            }
+
+            let now = Instant::now();
+            let mut sent = msgs as usize;
+            while let Some(mut pending) = self.batch_pending.pop_front() {
+                let latency = saturating_duration_since(now, pending.received);
+                if pending.remaining > sent {
+                    latencies
+                        .message
+                        .record_many_exemplar(latency, sent, pending.request_id, pending.key_hash);
+                    pending.remaining -= sent;
+                    self.batch_pending.push_front(pending);
+                    break;
+                }
+
+                latencies
+                    .message
+                    .record_many_exemplar(latency, pending.remaining, pending.request_id, pending.key_hash);
+                sent -= pending.remaining;
+                latencies
+                    .batch
+                    .record_exemplar(latency, pending.request_id, pending.key_hash);
+            }
+        }
+    }
+
+    /// Gets the identifier of the latest batch of requests read from the client.
+    pub fn request_id(&self) -> usize { self.request_id }
+
+    /// Sets the number of requests the client is waiting on responses for.
+    pub fn set_queue_depth(&self, depth: usize) { self.stats.set_queue_depth(depth); }
+
+    /// Handles the connection being closed after every response was sent.
+    pub fn on_close(&mut self) {
+        self.release();
+        debug!("[client] {} disconnected", self.addr);
+    }
+
+    /// Handles the connection being closed by an error.
+    ///
+    /// Anything still on its way back from the backends for the client is thrown away when the
+    /// pipeline goes, just as if the client had been asked to disconnect.
+    pub fn on_error<T, S, R>(&mut self, e: &PipelineError<T, S, R>)
+    where
+        T: Sink<SinkError = io::Error> + Stream<Error = ProtocolError>,
+        S: Service<R>,
+        S::Error: Display,
+    {
+        self.release();
+        match e {
+            // If we got a protocol error from a client, that's bad.  Otherwise, clients closing
+            // their connection is a normal thing, even if they do it before we've answered them.
+            PipelineError::TransportReceive(ie) if ie.client_closed() => self.on_hangup(ie),
+            PipelineError::TransportReceive(ie) => {
+                self.listener_sink.increment("client_errors");
+                CLIENT_ERRORS.log(
+                    Level::Error,
+                    ie.kind(),
+                    format_args!("[client] transport error from {}: {}", self.addr, ie),
+                );
+            },
+            PipelineError::TransportSend(ie) if is_disconnect(ie) => self.on_hangup(ie),
+            e => {
+                let kind = match e {
+                    PipelineError::Service(_) => "service",
+                    PipelineError::Subscription(_) => "subscription",
+                    _ => "transport_send",
+                };
+                CLIENT_ERRORS.log(
+                    Level::Error,
+                    kind,
+                    format_args!("[client] error from {} on request {}: {}", self.addr, self.request_id, e),
+                );
+            },
+        }
+    }
+
+    fn on_hangup<E: Display>(&self, e: &E) {
+        self.sink.increment("hangups");
+        debug!("[client] {} hung up: {}", self.addr, e);
+    }
+
+    fn release(&mut self) {
+        if self.state == ConnectionState::Closed {
+            return;
+        }
+
+        self.state = ConnectionState::Closed;
+        self.guards = None;
+        self.warden.decrement();
+        self.listener_sink.update_level("clients_connected", -1);
+    }
+}
@@ -18,6 +18,7 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 use backend::processor::ProcessorError;
+use protocol::errors::ProtocolError;
 use futures::prelude::*;
 use std::fmt;
 use tower_service::Service;
@@ -36,6 +37,9 @@ where
 
     /// The underlying service failed to process a request.
     Service(S::Error),
+
+    /// The connection relaying the client's subscriptions failed.
+    Subscription(ProtocolError),
 }
 
 impl<T, S, R> fmt::Display for PipelineError<T, S, R>
@@ -51,6 +55,7 @@ where
             PipelineError::TransportReceive(ref se) => fmt::Display::fmt(se, f),
             PipelineError::TransportSend(ref se) => fmt::Display::fmt(se, f),
             PipelineError::Service(ref se) => fmt::Display::fmt(se, f),
+            PipelineError::Subscription(ref se) => fmt::Display::fmt(se, f),
         }
     }
 }
@@ -68,6 +73,7 @@ where
             PipelineError::TransportReceive(ref se) => write!(f, "TransportRecv({:?})", se),
             PipelineError::TransportSend(ref se) => write!(f, "TransportSend({:?})", se),
             PipelineError::Service(ref se) => write!(f, "Service({:?})", se),
+            PipelineError::Subscription(ref se) => write!(f, "Subscription({:?})", se),
         }
     }
 }
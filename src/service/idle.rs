@@ -0,0 +1,294 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::ListenerConfiguration;
+use errors::CreationError;
+use futures::prelude::*;
+use metrics::MetricSink;
+use service::ClientStats;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use util::clock::{duration_as_ms, ClockDelay, SharedClock};
+
+// Clients can be left idle for at most a day, which keeps the deadline we wait on from overflowing
+// when it's added to the current time.
+const MAX_IDLE_TIMEOUT_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Gets how long clients of the given listener can go without sending anything, or `None` if they
+/// can stay quiet forever.
+///
+/// A timeout of zero means never timing out.
+pub fn idle_timeout_from_config(config: &ListenerConfiguration) -> Result<Option<Duration>, CreationError> {
+    match config.client_idle_timeout_ms {
+        Some(0) | None => Ok(None),
+        Some(timeout_ms) if timeout_ms > MAX_IDLE_TIMEOUT_MS => {
+            Err(CreationError::InvalidParameter("client_idle_timeout_ms".to_string()))
+        },
+        Some(timeout_ms) => Ok(Some(Duration::from_millis(timeout_ms))),
+    }
+}
+
+/// Wraps a client transport, ending it once the client has gone too long without sending anything.
+///
+/// Ending the stream looks, to the pipeline, just like the client hanging up: nothing new is read,
+/// but responses to anything already in flight are still sent before the connection is closed.
+/// Rather than starting a new timer for every request, we only track when the client was last
+/// heard from, and check it again whenever the timer fires.
+///
+/// Clients that are subscribed to anything, or still waiting on responses, are quiet for good
+/// reason, so the clock only runs once they have nothing going on.
+pub struct IdleTimeout<T> {
+    inner: T,
+    timeout: Option<Duration>,
+    stats: Arc<ClientStats>,
+    clock: SharedClock,
+    last_active: Instant,
+    delay: Option<(Instant, ClockDelay)>,
+    busy: bool,
+    timed_out: bool,
+    sink: MetricSink,
+}
+
+impl<T> IdleTimeout<T> {
+    /// Creates a transport that ends after `timeout` without a message from the client, if given.
+    ///
+    /// Whether or not the client is busy is read from the given stats.
+    pub fn new(
+        inner: T, timeout: Option<Duration>, stats: Arc<ClientStats>, clock: SharedClock, sink: MetricSink,
+    ) -> IdleTimeout<T> {
+        IdleTimeout {
+            inner,
+            timeout,
+            stats,
+            last_active: clock.now(),
+            clock,
+            delay: None,
+            busy: false,
+            timed_out: false,
+            sink,
+        }
+    }
+
+    fn poll_timeout(&mut self) -> Async<()> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Async::NotReady,
+        };
+
+        // A busy client counts as active for as long as it stays busy, so there's nothing to wait on
+        // until it isn't.  Whatever it's busy with wakes us up again once it's done.
+        if self.stats.is_busy() {
+            self.busy = true;
+            self.delay = None;
+            return Async::NotReady;
+        }
+        if self.busy {
+            self.busy = false;
+            self.last_active = self.clock.now();
+        }
+
+        loop {
+            let deadline = self.last_active + timeout;
+            if self.clock.now() >= deadline {
+                return Async::Ready(());
+            }
+
+            // The client may have been heard from since the timer was set, in which case we wait
+            // for the new deadline instead.
+            if self.delay.as_ref().map_or(true, |(set_for, _)| *set_for != deadline) {
+                self.delay = Some((deadline, self.clock.delay(deadline)));
+            }
+
+            match self.delay.as_mut().map(|(_, delay)| delay.poll()) {
+                Some(Ok(Async::Ready(()))) => self.delay = None,
+                _ => return Async::NotReady,
+            }
+        }
+    }
+}
+
+impl<T> Stream for IdleTimeout<T>
+where
+    T: Stream,
+{
+    type Error = T::Error;
+    type Item = T::Item;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.timed_out {
+            return Ok(Async::Ready(None));
+        }
+
+        match self.inner.poll()? {
+            Async::Ready(Some(item)) => {
+                self.last_active = self.clock.now();
+                return Ok(Async::Ready(Some(item)));
+            },
+            Async::Ready(None) => return Ok(Async::Ready(None)),
+            Async::NotReady => {},
+        }
+
+        match self.poll_timeout() {
+            Async::Ready(()) => {
+                self.timed_out = true;
+                self.sink.increment("clients_idle_closed");
+                debug!(
+                    "[client] closing connection after {}ms idle",
+                    duration_as_ms(self.clock.now() - self.last_active)
+                );
+                Ok(Async::Ready(None))
+            },
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl<T> Sink for IdleTimeout<T>
+where
+    T: Sink,
+{
+    type SinkError = T::SinkError;
+    type SinkItem = T::SinkItem;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> { self.inner.poll_complete() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        future::{lazy, ok},
+        sync::mpsc,
+    };
+    use metrics::get_sink;
+    use serde_json::{self, json};
+    use util::clock::VirtualClock;
+
+    #[test]
+    fn test_idle_timeout() {
+        let clock = VirtualClock::new();
+        let (tx, rx) = mpsc::unbounded::<usize>();
+        let stats = Arc::new(ClientStats::default());
+        let timeout = Some(Duration::from_millis(100));
+        let mut idle = IdleTimeout::new(rx, timeout, stats, Arc::new(clock.clone()), get_sink());
+
+        lazy(|| {
+            assert_eq!(idle.poll(), Ok(Async::NotReady));
+
+            // Hearing from the client pushes the deadline back.
+            clock.advance(Duration::from_millis(60));
+            tx.unbounded_send(1).unwrap();
+            assert_eq!(idle.poll(), Ok(Async::Ready(Some(1))));
+            clock.advance(Duration::from_millis(60));
+            assert_eq!(idle.poll(), Ok(Async::NotReady));
+
+            // Once the client has been quiet for long enough, it's as if they hung up, even if they
+            // send something afterwards.
+            clock.advance(Duration::from_millis(40));
+            assert_eq!(idle.poll(), Ok(Async::Ready(None)));
+            tx.unbounded_send(2).unwrap();
+            assert_eq!(idle.poll(), Ok(Async::Ready(None)));
+            ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_idle_timeout_disabled() {
+        let clock = VirtualClock::new();
+        let (_tx, rx) = mpsc::unbounded::<usize>();
+        let stats = Arc::new(ClientStats::default());
+        let mut idle = IdleTimeout::new(rx, None, stats, Arc::new(clock.clone()), get_sink());
+
+        lazy(|| {
+            clock.advance(Duration::from_secs(3600));
+            assert_eq!(idle.poll(), Ok(Async::NotReady));
+            ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_idle_timeout_busy() {
+        let clock = VirtualClock::new();
+        let (_tx, rx) = mpsc::unbounded::<usize>();
+        let stats = Arc::new(ClientStats::default());
+        let timeout = Some(Duration::from_millis(100));
+        let mut idle = IdleTimeout::new(rx, timeout, stats.clone(), Arc::new(clock.clone()), get_sink());
+
+        lazy(|| {
+            // A client waiting on a slow response isn't idle, no matter how long it waits.
+            stats.set_queue_depth(1);
+            assert_eq!(idle.poll(), Ok(Async::NotReady));
+            clock.advance(Duration::from_millis(500));
+            assert_eq!(idle.poll(), Ok(Async::NotReady));
+
+            // Neither is a client that's subscribed to something, and only listening.
+            stats.set_queue_depth(0);
+            stats.set_subscribed(true);
+            clock.advance(Duration::from_millis(500));
+            assert_eq!(idle.poll(), Ok(Async::NotReady));
+
+            // The clock only starts once the client has nothing going on.
+            stats.set_subscribed(false);
+            assert_eq!(idle.poll(), Ok(Async::NotReady));
+            clock.advance(Duration::from_millis(60));
+            assert_eq!(idle.poll(), Ok(Async::NotReady));
+            clock.advance(Duration::from_millis(40));
+            assert_eq!(idle.poll(), Ok(Async::Ready(None)));
+            ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_idle_timeout_from_config() {
+        let get_config = |timeout_ms: Option<u64>| -> ListenerConfiguration {
+            serde_json::from_value(json!({
+                "protocol": "redis",
+                "address": "127.0.0.1:6379",
+                "client_idle_timeout_ms": timeout_ms,
+                "pools": {}
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(idle_timeout_from_config(&get_config(None)).unwrap(), None);
+        assert_eq!(idle_timeout_from_config(&get_config(Some(0))).unwrap(), None);
+        assert_eq!(
+            idle_timeout_from_config(&get_config(Some(250))).unwrap(),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            idle_timeout_from_config(&get_config(Some(MAX_IDLE_TIMEOUT_MS))).unwrap(),
+            Some(Duration::from_millis(MAX_IDLE_TIMEOUT_MS))
+        );
+        assert!(idle_timeout_from_config(&get_config(Some(MAX_IDLE_TIMEOUT_MS + 1))).is_err());
+        assert!(idle_timeout_from_config(&get_config(Some(std::u64::MAX))).is_err());
+    }
+}
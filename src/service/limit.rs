@@ -0,0 +1,288 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use errors::CreationError;
+use conf::ListenerConfiguration;
+use futures::{prelude::*, task::AtomicTask};
+use metrics::MetricSink;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+lazy_static! {
+    static ref LIMITS: Mutex<HashMap<String, Arc<ClientLimit>>> = Mutex::new(HashMap::new());
+}
+
+/// Gets the client limit for the given listener, creating it if it doesn't exist yet.
+///
+/// Like file descriptor trackers, limits are keyed by listener name, so that clients still
+/// draining from a previous version of a listener count against the limit of the new one.
+pub fn get_client_limit(listener: &str, sink: &MetricSink) -> Arc<ClientLimit> {
+    let mut limits = LIMITS.lock().unwrap();
+    limits
+        .entry(listener.to_owned())
+        .or_insert_with(|| Arc::new(ClientLimit::new(sink.clone())))
+        .clone()
+}
+
+const BEHAVIORS: &[(&str, ClientLimitBehavior)] =
+    &[("reject", ClientLimitBehavior::Reject), ("pause", ClientLimitBehavior::Pause)];
+
+/// What a listener does with new clients once it's serving as many as it's allowed to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientLimitBehavior {
+    /// New clients are accepted, told that the limit was reached, and disconnected.
+    Reject,
+
+    /// New clients are left waiting to be accepted until another client disconnects.
+    Pause,
+}
+
+/// How many clients a listener serves at once, and what happens to anyone past that.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientLimitConfiguration {
+    /// The most clients served at once, if limited at all.
+    pub max_clients: Option<usize>,
+
+    /// What happens to clients that show up once we're at the limit.
+    pub behavior: ClientLimitBehavior,
+}
+
+impl ClientLimitConfiguration {
+    pub fn from_config(config: &ListenerConfiguration) -> Result<ClientLimitConfiguration, CreationError> {
+        if config.max_clients == Some(0) {
+            return Err(CreationError::InvalidParameter("max_clients".to_string()));
+        }
+
+        let behavior = match config.max_clients_behavior.as_ref() {
+            Some(behavior) => {
+                let behavior = behavior.to_lowercase();
+                BEHAVIORS
+                    .iter()
+                    .find(|(name, _)| *name == behavior)
+                    .map(|(_, behavior)| *behavior)
+                    .ok_or_else(|| CreationError::InvalidParameter("max_clients_behavior".to_string()))?
+            },
+            None => ClientLimitBehavior::Reject,
+        };
+
+        Ok(ClientLimitConfiguration {
+            max_clients: config.max_clients,
+            behavior,
+        })
+    }
+}
+
+/// Tracks the clients being served by a listener, against an optional limit.
+///
+/// Clients hold on to a slot from the moment they're accepted until they disconnect.  The number
+/// of slots in use, and the limit, are reported as the `clients` and `max_clients` gauges.
+pub struct ClientLimit {
+    used: AtomicUsize,
+    limit: AtomicUsize,
+    pause: AtomicBool,
+    waiting: AtomicTask,
+    sink: MetricSink,
+}
+
+impl ClientLimit {
+    pub fn new(sink: MetricSink) -> ClientLimit {
+        ClientLimit {
+            used: AtomicUsize::new(0),
+            limit: AtomicUsize::new(0),
+            pause: AtomicBool::new(false),
+            waiting: AtomicTask::new(),
+            sink,
+        }
+    }
+
+    /// Sets the most clients that may be served at once, and what happens to anyone past that.
+    pub fn configure(&self, config: ClientLimitConfiguration) {
+        let limit = config.max_clients.unwrap_or(0);
+        self.limit.store(limit, Ordering::SeqCst);
+        self.pause.store(config.behavior == ClientLimitBehavior::Pause, Ordering::SeqCst);
+        self.sink.update_gauge("max_clients", limit as u64);
+
+        // A higher limit may well have made room for whoever's waiting.
+        self.waiting.notify();
+    }
+
+    /// Gets what happens to clients that show up once we're at the limit.
+    pub fn behavior(&self) -> ClientLimitBehavior {
+        if self.pause.load(Ordering::SeqCst) {
+            ClientLimitBehavior::Pause
+        } else {
+            ClientLimitBehavior::Reject
+        }
+    }
+
+    /// Gets the number of clients being served.
+    pub fn used(&self) -> usize { self.used.load(Ordering::SeqCst) }
+
+    fn is_full(&self) -> bool {
+        let limit = self.limit.load(Ordering::SeqCst);
+        limit != 0 && self.used() >= limit
+    }
+
+    /// Checks whether there's room for another client.
+    ///
+    /// If there isn't, the current task is notified once a client disconnects.  Only one task is
+    /// notified, so this is meant for the task accepting clients.
+    pub fn poll_available(&self) -> Async<()> {
+        if !self.is_full() {
+            return Async::Ready(());
+        }
+
+        // Check again once registered, in case a client disconnected in the meantime.
+        self.waiting.register();
+        if self.is_full() {
+            Async::NotReady
+        } else {
+            Async::Ready(())
+        }
+    }
+
+    /// Takes a slot for a client, if we're under our limit.
+    pub fn try_acquire(limit: &Arc<ClientLimit>) -> Option<ClientSlot> {
+        let mut used = limit.used.load(Ordering::SeqCst);
+        loop {
+            let max = limit.limit.load(Ordering::SeqCst);
+            if max != 0 && used >= max {
+                return None;
+            }
+
+            match limit
+                .used
+                .compare_exchange_weak(used, used + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(actual) => used = actual,
+            }
+        }
+
+        limit.sink.update_gauge("clients", (used + 1) as u64);
+        Some(ClientSlot { limit: limit.clone() })
+    }
+}
+
+/// A slot taken by a client, which is given back when dropped.
+pub struct ClientSlot {
+    limit: Arc<ClientLimit>,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let used = self.limit.used.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.limit.sink.update_gauge("clients", used as u64);
+        self.limit.waiting.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{lazy, ok};
+    use metrics::get_sink;
+    use serde_json::{self, json};
+
+    fn get_config(value: serde_json::Value) -> ListenerConfiguration { serde_json::from_value(value).unwrap() }
+
+    #[test]
+    fn test_from_config() {
+        let config = get_config(json!({ "protocol": "redis", "address": "127.0.0.1:6379", "pools": {} }));
+        let limit = ClientLimitConfiguration::from_config(&config).unwrap();
+        assert_eq!(limit.max_clients, None);
+        assert_eq!(limit.behavior, ClientLimitBehavior::Reject);
+
+        let config = get_config(json!({
+            "protocol": "redis",
+            "address": "127.0.0.1:6379",
+            "max_clients": 10,
+            "max_clients_behavior": "Pause",
+            "pools": {}
+        }));
+        let limit = ClientLimitConfiguration::from_config(&config).unwrap();
+        assert_eq!(limit.max_clients, Some(10));
+        assert_eq!(limit.behavior, ClientLimitBehavior::Pause);
+
+        let config = get_config(json!({
+            "protocol": "redis",
+            "address": "127.0.0.1:6379",
+            "max_clients": 0,
+            "pools": {}
+        }));
+        match ClientLimitConfiguration::from_config(&config) {
+            Err(CreationError::InvalidParameter(param)) => assert_eq!(param, "max_clients"),
+            _ => panic!("a limit of zero clients should be rejected"),
+        }
+
+        let config = get_config(json!({
+            "protocol": "redis",
+            "address": "127.0.0.1:6379",
+            "max_clients_behavior": "sulk",
+            "pools": {}
+        }));
+        match ClientLimitConfiguration::from_config(&config) {
+            Err(CreationError::InvalidParameter(param)) => assert_eq!(param, "max_clients_behavior"),
+            _ => panic!("an unknown behavior should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_limit() {
+        let limit = Arc::new(ClientLimit::new(get_sink()));
+        limit.configure(ClientLimitConfiguration {
+            max_clients: Some(2),
+            behavior: ClientLimitBehavior::Pause,
+        });
+        assert_eq!(limit.behavior(), ClientLimitBehavior::Pause);
+
+        let client1 = ClientLimit::try_acquire(&limit).unwrap();
+        let _client2 = ClientLimit::try_acquire(&limit).unwrap();
+        assert_eq!(limit.used(), 2);
+        assert!(ClientLimit::try_acquire(&limit).is_none());
+
+        lazy(|| {
+            assert!(limit.poll_available().is_not_ready());
+
+            // Whoever was waiting can carry on once a client disconnects.
+            drop(client1);
+            assert_eq!(limit.used(), 1);
+            assert!(limit.poll_available().is_ready());
+            ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        let _client3 = ClientLimit::try_acquire(&limit).unwrap();
+        assert!(ClientLimit::try_acquire(&limit).is_none());
+
+        // Without a limit, anything goes.
+        limit.configure(ClientLimitConfiguration {
+            max_clients: None,
+            behavior: ClientLimitBehavior::Reject,
+        });
+        let _client4 = ClientLimit::try_acquire(&limit).unwrap();
+        assert_eq!(limit.used(), 3);
+    }
+}
@@ -17,7 +17,28 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
+mod audit;
+mod clients;
+mod connection;
 mod errors;
+mod idle;
+mod limit;
 mod pipeline;
+mod sampler;
+mod setup;
+mod slo;
 
-pub use self::{errors::PipelineError, pipeline::Pipeline};
+pub use self::{
+    audit::{with_client, AuditConfiguration, AuditLog, AuditedPool},
+    clients::{
+        find_client_registry, get_client_registry, ClientInfo, ClientRegistration, ClientRegistry, ClientStats,
+    },
+    connection::ClientConnection,
+    errors::PipelineError,
+    idle::{idle_timeout_from_config, IdleTimeout},
+    limit::{get_client_limit, ClientLimit, ClientLimitBehavior, ClientLimitConfiguration, ClientSlot},
+    pipeline::{BatchConfiguration, FragmentLimits, Pipeline},
+    sampler::{get_key_samplers, log_key_samples, register_key_sampler, KeySample, KeySampler, KeySamplerConfiguration},
+    setup::{ClientLatencies, ConnectionSetup},
+    slo::SloTable,
+};
@@ -17,21 +17,133 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use backend::{message_queue::MessageQueue, processor::Processor};
+use backend::{
+    message_queue::MessageQueue,
+    processor::{Processor, ProcessorError},
+    subscription::{Subscription, Subscriptions, SUBSCRIPTIONS_UNAVAILABLE},
+};
 use bytes::BytesMut;
 use common::{AssignedRequests, AssignedResponse, Message};
+use conf::ListenerConfiguration;
+use errors::CreationError;
 use futures::prelude::*;
-use hotmic::Sink as MetricSink;
-use service::PipelineError;
-use std::collections::VecDeque;
+use protocol::errors::ProtocolError;
+use service::{with_client, ClientConnection, PipelineError};
+use std::{collections::VecDeque, fmt::Display, io, time::Duration};
 use tower_service::Service;
 use util::Batch;
 
+/// Limits on how many backend requests a client's commands can fan out into.
+///
+/// Commands that aren't fragmented count as a single fragment.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FragmentLimits {
+    /// The most fragments a single command can have before it's rejected.
+    pub max_per_command: Option<usize>,
+
+    /// The most fragments a client can have outstanding before we stop reading its commands.
+    pub max_concurrent: Option<usize>,
+}
+
+impl FragmentLimits {
+    pub fn from_config(config: &ListenerConfiguration) -> Result<FragmentLimits, CreationError> {
+        if config.max_fragments_per_command == Some(0) {
+            return Err(CreationError::InvalidParameter("max_fragments_per_command".to_string()));
+        }
+
+        if config.max_concurrent_fragments_per_client == Some(0) {
+            return Err(CreationError::InvalidParameter("max_concurrent_fragments_per_client".to_string()));
+        }
+
+        Ok(FragmentLimits {
+            max_per_command: config.max_fragments_per_command,
+            max_concurrent: config.max_concurrent_fragments_per_client,
+        })
+    }
+}
+
+// How many commands are read from a client, at most, before they're sent along, by default.
+const DEFAULT_BATCH_SIZE: usize = 128;
+
+/// How commands read from a client are gathered into batches before being sent along.
+#[derive(Clone, Copy, Debug)]
+pub struct BatchConfiguration {
+    /// The most commands in a single batch.
+    pub size: usize,
+
+    /// How long a batch that isn't full is held on to in case more commands arrive, if at all.
+    pub linger: Option<Duration>,
+}
+
+impl Default for BatchConfiguration {
+    fn default() -> BatchConfiguration {
+        BatchConfiguration {
+            size: DEFAULT_BATCH_SIZE,
+            linger: None,
+        }
+    }
+}
+
+impl BatchConfiguration {
+    pub fn from_config(config: &ListenerConfiguration) -> Result<BatchConfiguration, CreationError> {
+        let size = config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+        if size == 0 {
+            return Err(CreationError::InvalidParameter("batch_size".to_string()));
+        }
+
+        // Not lingering at all is the same as not being asked to.
+        let linger = match config.batch_linger_us {
+            Some(0) | None => None,
+            Some(linger_us) => Some(Duration::from_micros(linger_us)),
+        };
+
+        Ok(BatchConfiguration { size, linger })
+    }
+}
+
+/// What came of sending a client's backlog along.
+enum Dispatched {
+    /// Everything that could be sent was, including when there was nothing to send at all.
+    Sent,
+
+    /// Nothing could be sent because the client already has too many fragments outstanding.
+    Throttled,
+
+    /// Nothing could be sent because the next command has to wait for the client's other commands
+    /// to be answered first.
+    Waiting,
+}
+
+/// What came of relaying a command over the client's subscription.
+enum Relayed<T> {
+    /// The command was relayed.
+    Sent,
+
+    /// The command has nothing to do with subscriptions, and should be sent along as normal.
+    NotRelayed(T),
+
+    /// The command can't be relayed, and should be answered with the given error.
+    Refused(&'static str),
+
+    /// The command can't be relayed, or sent along as normal, until whatever the client is waiting
+    /// on is answered.
+    Waiting(T),
+}
+
 /// Pipeline-capable service base.
 ///
 /// `Pipeline` can simultaenously drive a `Transport` and an underlying `Service`,
 /// opportunistically batching messages from the client transport and handing them off for
 /// processing while waiting to send back to the responses.
+///
+/// When the client is asked to disconnect, the pipeline stops taking new requests from it, finishes
+/// sending responses for the requests it already has, and then completes.  Everything about the
+/// client itself, rather than the requests in flight, is owned by its `ClientConnection`, which
+/// the pipeline keeps up to date as it goes.
+///
+/// Once a client subscribes to something, everything it sends is relayed over a connection of its
+/// own to a single backend, and everything that backend sends back is passed along as-is, until the
+/// client unsubscribes from everything.
 pub struct Pipeline<T, S, P>
 where
     T: Sink + Stream<Item = P::Message>,
@@ -40,15 +152,21 @@ where
     P: Processor,
     P::Message: Message + Clone,
 {
-    responses: VecDeque<S::Future>,
+    responses: VecDeque<(S::Future, usize)>,
     transport: Batch<T>,
     service: S,
     queue: MessageQueue<P>,
 
+    // Commands we've read from the client but haven't sent yet, and how many fragments we're
+    // waiting on responses for.
+    backlog: VecDeque<P::Message>,
+    outstanding: usize,
+
     send_buf: Option<(BytesMut, u64)>,
-    finish: bool,
+    conn: ClientConnection,
 
-    sink: MetricSink<&'static str>,
+    subscriptions: Option<Subscriptions<P>>,
+    subscription: Option<Subscription<P>>,
 }
 
 impl<T, S, P> Pipeline<T, S, P>
@@ -56,46 +174,172 @@ where
     T: Sink<SinkItem = BytesMut> + Stream<Item = P::Message>,
     S: Service<AssignedRequests<P::Message>>,
     S::Response: IntoIterator<Item = AssignedResponse<P::Message>>,
-    P: Processor,
+    P: Processor + Clone,
     P::Message: Message + Clone,
 {
-    /// Creates a new `Pipeline`.
-    pub fn new(transport: T, service: S, processor: P, sink: MetricSink<&'static str>) -> Self {
+    /// Creates a new `Pipeline`, reading from the client in batches as configured.
+    pub fn new(transport: T, service: S, processor: P, conn: ClientConnection, batching: BatchConfiguration) -> Self {
         Pipeline {
             responses: VecDeque::new(),
-            transport: Batch::new(transport, 128),
+            transport: Batch::with_linger(transport, batching.size, batching.linger),
             service,
             queue: MessageQueue::new(processor),
+            backlog: VecDeque::new(),
+            outstanding: 0,
             send_buf: None,
-            finish: false,
-            sink,
+            conn,
+            subscriptions: None,
+            subscription: None,
         }
     }
-}
 
-impl<T, S, P> Future for Pipeline<T, S, P>
-where
-    T: Sink<SinkItem = BytesMut> + Stream<Item = P::Message>,
-    S: Service<AssignedRequests<P::Message>>,
-    S::Response: IntoIterator<Item = AssignedResponse<P::Message>>,
-    P: Processor,
-    P::Message: Message + Clone,
-{
-    type Error = PipelineError<T, S, AssignedRequests<P::Message>>;
-    type Item = ();
+    /// Sets where the client's subscriptions are relayed to, if they can be relayed anywhere.
+    pub fn set_subscriptions(mut self, subscriptions: Option<Subscriptions<P>>) -> Self {
+        self.subscriptions = subscriptions;
+        self
+    }
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    /// Relays the given command over the client's subscription, if it has one, or if the command
+    /// starts one.
+    ///
+    /// Subscriptions can only be started once the client has nothing else outstanding, so that
+    /// none of the client's responses can end up out of order.
+    fn relay(&mut self, msg: P::Message, idle: bool) -> Relayed<P::Message> {
+        if msg.is_inline() {
+            return Relayed::NotRelayed(msg);
+        }
+
+        let changes_subscriptions = self.queue.processor().get_subscription(&msg).is_some();
+        match self.subscription.take() {
+            Some(mut subscription) => {
+                // Anything sent while subscribed goes to the backend, which answers it the way it
+                // answers any subscribed client.  Once the client has unsubscribed from everything,
+                // commands go back to being sent along as normal.
+                let relayed = if changes_subscriptions || subscription.is_subscribed() {
+                    match subscription.send(msg) {
+                        Ok(()) => Relayed::Sent,
+                        Err(e) => Relayed::Refused(e),
+                    }
+                } else if subscription.is_idle() {
+                    return Relayed::NotRelayed(msg);
+                } else {
+                    Relayed::Waiting(msg)
+                };
+
+                self.subscription = Some(subscription);
+                relayed
+            },
+            None if !changes_subscriptions => Relayed::NotRelayed(msg),
+            None if !idle => Relayed::Waiting(msg),
+            None => {
+                let subscriptions = match self.subscriptions {
+                    Some(ref subscriptions) => subscriptions.clone(),
+                    None => return Relayed::Refused(SUBSCRIPTIONS_UNAVAILABLE),
+                };
+
+                match Subscription::new(subscriptions, msg) {
+                    Ok(subscription) => {
+                        self.subscription = Some(subscription);
+                        Relayed::Sent
+                    },
+                    Err(e) => Relayed::Refused(e),
+                }
+            },
+        }
+    }
+
+    /// Sends as much of the backlog to the service as our fragment limits allow.
+    ///
+    /// Commands with too many fragments are answered with an error in place.  Commands relayed
+    /// over the client's subscription are sent one at a time, so that the subscription is driven
+    /// before anything else is sent over it.
+    fn dispatch(&mut self) -> Result<Dispatched, ProcessorError> {
+        let mut msgs = Vec::new();
+        let mut fragments = 0;
+        let mut relayed = false;
+        let mut waiting = false;
+        while let Some(msg) = self.backlog.pop_front() {
+            // Commands we can answer ourselves are answered in place, so they stay in order.
+            if let Some(resp) = self.queue.processor().get_local_response(&msg) {
+                self.conn.on_local_command();
+                msgs.push(resp);
+                continue;
+            }
+
+            let idle = msgs.is_empty() && self.outstanding == 0 && self.queue.pending() == 0;
+            let msg = match self.relay(msg, idle) {
+                Relayed::NotRelayed(msg) => msg,
+                Relayed::Sent => {
+                    self.conn.on_sent(1, 0);
+                    relayed = true;
+                    break;
+                },
+                Relayed::Refused(e) => {
+                    msgs.push(self.queue.processor().get_error_message_str(e));
+                    continue;
+                },
+                Relayed::Waiting(msg) => {
+                    self.backlog.push_front(msg);
+                    waiting = true;
+                    break;
+                },
+            };
+
+            let count = self.queue.processor().get_fragment_count(&msg);
+            if let Some(err) = self.conn.on_command(count) {
+                msgs.push(self.queue.processor().get_error_message_str(&err));
+                continue;
+            }
+
+            if !self.conn.can_dispatch(self.outstanding + fragments, count) {
+                self.backlog.push_front(msg);
+                break;
+            }
+
+            fragments += count;
+            msgs.push(msg);
+        }
+
+        if msgs.is_empty() {
+            if relayed || self.backlog.is_empty() {
+                return Ok(Dispatched::Sent);
+            }
+            return Ok(if waiting { Dispatched::Waiting } else { Dispatched::Throttled });
+        }
+
+        let batch = self.queue.enqueue(msgs)?;
+        self.conn.set_queue_depth(self.queue.pending());
+        if !batch.is_empty() {
+            let count = batch.len();
+            let client = self.conn.stats();
+            let service = &mut self.service;
+            let fut = with_client(&client, self.conn.request_id(), || service.call(batch));
+            self.responses.push_back((fut, count));
+            self.outstanding += count;
+        }
+
+        Ok(Dispatched::Sent)
+    }
+
+    /// Drives the transport and the service until the client is done, or something fails.
+    fn drive(&mut self) -> Poll<(), PipelineError<T, S, AssignedRequests<P::Message>>> {
         loop {
+            // See if we've been asked to close, and whether a new client has started talking to us.
+            self.conn.poll_close();
+            self.conn.poll_first_byte();
+
             // In order, drive the response futures we're waiting on.  Keep pulling from the
             // front to keep things in order, and as soon as we hit something that isn't ready or
             // isn't ready to flush to the message queue.
-            while let Some(mut f) = self.responses.pop_front() {
+            while let Some((mut f, count)) = self.responses.pop_front() {
                 match f.poll() {
                     Ok(Async::Ready(rsp)) => {
                         self.queue.fulfill(rsp);
+                        self.conn.set_queue_depth(self.queue.pending());
+                        self.outstanding -= count;
                     },
                     Ok(Async::NotReady) => {
-                        self.responses.push_front(f);
+                        self.responses.push_front((f, count));
                         break;
                     },
                     Err(e) => {
@@ -117,10 +361,12 @@ where
                     return Ok(Async::NotReady);
                 }
 
-                self.sink.update_count("messages_sent", count as i64);
-                self.sink.update_count("bytes_sent", buf_len as i64);
+                self.conn.on_sent(count, buf_len);
             }
 
+            // Anything we're answering ourselves after a delay may be ready to go, too.
+            self.queue.poll_delays();
+
             let mut msgs_sent = 0;
             let mut bytes_sent = 0;
 
@@ -130,8 +376,8 @@ where
                     self.transport.start_send(buf).map_err(PipelineError::from_sink_error)?
                 {
                     self.send_buf = Some((buf, count));
-                    self.sink.update_count("messages_sent", msgs_sent as i64);
-                    self.sink.update_count("bytes_sent", bytes_sent as i64);
+                    self.conn.on_sent(msgs_sent, bytes_sent);
+                    self.conn.set_queue_depth(self.queue.pending());
                     return Ok(Async::NotReady);
                 }
 
@@ -139,44 +385,627 @@ where
                 bytes_sent += buf_len;
             }
 
-            self.sink.update_count("messages_sent", msgs_sent as i64);
-            self.sink.update_count("bytes_sent", bytes_sent as i64);
+            self.conn.on_sent(msgs_sent, bytes_sent);
+            self.conn.set_queue_depth(self.queue.pending());
+
+            // Anything the backend sends over the client's subscription is passed along as soon as
+            // it arrives, and isn't the response to any particular command.
+            if let Some(subscription) = self.subscription.as_mut() {
+                while let Async::Ready(Some(buf)) = subscription.poll().map_err(PipelineError::Subscription)? {
+                    let buf_len = buf.len();
+                    if let AsyncSink::NotReady(buf) =
+                        self.transport.start_send(buf).map_err(PipelineError::from_sink_error)?
+                    {
+                        self.send_buf = Some((buf, 0));
+                        return Ok(Async::NotReady);
+                    }
+
+                    self.conn.on_sent(0, buf_len);
+                }
+            }
+            self.conn.set_subscribed(self.subscription.as_ref().map_or(false, Subscription::is_subscribed));
 
             // Drive our transport to flush any buffers we have.
             if let Async::Ready(()) = self.transport.poll_complete().map_err(PipelineError::from_sink_error)? {
                 // If we're finished and have nothing else to send, then we're done!
-                if self.finish && self.responses.is_empty() {
+                if self.conn.is_closing()
+                    && self.responses.is_empty()
+                    && self.backlog.is_empty()
+                    && self.queue.pending_delays() == 0
+                {
                     return Ok(Async::Ready(()));
                 }
             }
 
             // Don't try and grab anything else from the transport if we're finished, we just need
-            // to flush the rest of our responses and that's it.
-            if self.finish {
+            // to send what we've held back, flush the rest of our responses, and that's it.
+            if self.conn.is_closing() && self.backlog.is_empty() {
                 return Ok(Async::NotReady);
             }
 
             // Make sure the underlying service is ready to be called.
             try_ready!(self.service.poll_ready().map_err(PipelineError::from_service_error));
 
-            // See if we can pull a batch from the transport.
-            match try_ready!(self.transport.poll().map_err(PipelineError::from_stream_error)) {
-                Some((batch, batch_size)) => {
-                    self.sink.update_count("messages_received", batch.len() as i64);
-                    self.sink.update_count("bytes_received", batch_size as i64);
-                    let batch = self.queue.enqueue(batch)?;
-                    if !batch.is_empty() {
-                        let fut = self.service.call(batch);
-                        self.responses.push_back(fut);
-                    }
-                },
-                None => {
-                    // Our transport has signalled no more messages are going to come in, so mark
-                    // ourselves as finished so we can begin the closing process.
-                    assert!(!self.finish);
-                    self.finish = true;
+            // Anything we held back goes out before we read anything new, so that commands are
+            // always sent in the order the client gave them to us.
+            if self.backlog.is_empty() {
+                match try_ready!(self.transport.poll().map_err(PipelineError::from_stream_error)) {
+                    // The batch combinator never gives us an empty batch, but if it somehow did,
+                    // there'd be nothing to do for it.
+                    Some((ref batch, _)) if batch.is_empty() => continue,
+                    Some((batch, batch_size)) => {
+                        self.conn.on_batch(self.queue.processor(), &batch, batch_size);
+                        self.backlog.extend(batch);
+                    },
+                    None => {
+                        // Our transport has signalled no more messages are going to come in, so
+                        // we can begin the closing process.
+                        self.conn.on_eof();
+                        continue;
+                    },
+                }
+            }
+
+            match self.dispatch()? {
+                Dispatched::Sent => self.conn.on_dispatched(),
+                Dispatched::Throttled => {
+                    // The client has too many fragments outstanding, so we stop reading from it
+                    // until some of them come back, which wakes us up again.
+                    self.conn.on_throttled();
+                    return Ok(Async::NotReady);
                 },
+                // Whatever the next command is waiting on wakes us up again once it's answered.
+                Dispatched::Waiting => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+impl<T, S, P> Future for Pipeline<T, S, P>
+where
+    T: Sink<SinkItem = BytesMut, SinkError = io::Error> + Stream<Item = P::Message, Error = ProtocolError>,
+    S: Service<AssignedRequests<P::Message>>,
+    S::Error: Display,
+    S::Response: IntoIterator<Item = AssignedResponse<P::Message>>,
+    P: Processor + Clone,
+    P::Message: Message + Clone,
+{
+    type Error = ();
+    type Item = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.drive() {
+            Ok(Async::Ready(())) => {
+                self.conn.on_close();
+                Ok(Async::Ready(()))
+            },
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                self.conn.on_error(&e);
+                Err(())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::redis::RedisProcessor;
+    use common::MessageResponse;
+    use futures::{
+        future::{empty, ok, FutureResult},
+        task,
+    };
+    use metrics::capture;
+    use protocol::redis::{RedisMessage, RedisTransport, RedisTransportConfig};
+    use service::{ClientRegistry, SloTable};
+    use std::{
+        collections::HashMap,
+        io::{self, Read, Write},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::Duration,
+    };
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_evacuate::Evacuate;
+    use util::FdTracker;
+
+    /// A client that sends a fixed script of requests, all at once, and then hangs up.
+    struct ScriptedClient {
+        input: io::Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for ScriptedClient {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.input.read(buf) }
+    }
+
+    impl AsyncRead for ScriptedClient {}
+
+    impl Write for ScriptedClient {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.output.lock().unwrap().write(buf) }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl AsyncWrite for ScriptedClient {
+        fn shutdown(&mut self) -> Poll<(), io::Error> { Ok(Async::Ready(())) }
+    }
+
+    /// A client that sends its script, and then hangs up before reading any of its responses.
+    struct HungUpClient {
+        input: io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for HungUpClient {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.input.read(buf) }
+    }
+
+    impl AsyncRead for HungUpClient {}
+
+    impl Write for HungUpClient {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> { Err(io::ErrorKind::BrokenPipe.into()) }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl AsyncWrite for HungUpClient {
+        fn shutdown(&mut self) -> Poll<(), io::Error> { Ok(Async::Ready(())) }
+    }
+
+    /// A backend that answers every request right away, based on the command and key.
+    struct ScriptedBackend;
+
+    impl Service<AssignedRequests<RedisMessage>> for ScriptedBackend {
+        type Error = String;
+        type Future = FutureResult<Self::Response, Self::Error>;
+        type Response = Vec<AssignedResponse<RedisMessage>>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> { Ok(Async::Ready(())) }
+
+        fn call(&mut self, req: AssignedRequests<RedisMessage>) -> Self::Future {
+            let responses = req
+                .into_iter()
+                .map(|(id, msg)| {
+                    let response = match (msg.get_command(), msg.key()) {
+                        (Some(b"set"), _) => MessageResponse::Complete(RedisMessage::OK),
+                        (Some(b"del"), _) => MessageResponse::Complete(RedisMessage::from_integer(1)),
+                        (_, b"foo") => {
+                            MessageResponse::Complete(RedisMessage::Data(BytesMut::from(&b"$3\r\nbar\r\n"[..]), 4))
+                        },
+                        (_, b"broken") => MessageResponse::Failed,
+                        _ => MessageResponse::Complete(RedisMessage::Null),
+                    };
+                    (id, response)
+                })
+                .collect();
+            ok(responses)
+        }
+    }
+
+    /// A backend that answers like `ScriptedBackend`, but only after the given delay.
+    struct DelayedBackend(Duration);
+
+    impl Service<AssignedRequests<RedisMessage>> for DelayedBackend {
+        type Error = String;
+        type Future = FutureResult<Self::Response, Self::Error>;
+        type Response = Vec<AssignedResponse<RedisMessage>>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> { Ok(Async::Ready(())) }
+
+        fn call(&mut self, req: AssignedRequests<RedisMessage>) -> Self::Future {
+            thread::sleep(self.0);
+            ScriptedBackend.call(req)
+        }
+    }
+
+    /// A backend that answers like `ScriptedBackend`, but not until it's been asked for its answer
+    /// a second time, keeping track of the most requests it ever had outstanding at once.
+    #[derive(Clone, Default)]
+    struct CountingBackend {
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    struct CountingResponse {
+        inner: FutureResult<Vec<AssignedResponse<RedisMessage>>, String>,
+        count: usize,
+        polled: bool,
+        in_flight: Arc<AtomicUsize>,
+    }
+
+    impl Future for CountingResponse {
+        type Error = String;
+        type Item = Vec<AssignedResponse<RedisMessage>>;
+
+        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+            if !self.polled {
+                self.polled = true;
+                task::current().notify();
+                return Ok(Async::NotReady);
             }
+
+            self.in_flight.fetch_sub(self.count, Ordering::SeqCst);
+            self.inner.poll()
+        }
+    }
+
+    impl Service<AssignedRequests<RedisMessage>> for CountingBackend {
+        type Error = String;
+        type Future = CountingResponse;
+        type Response = Vec<AssignedResponse<RedisMessage>>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> { Ok(Async::Ready(())) }
+
+        fn call(&mut self, req: AssignedRequests<RedisMessage>) -> Self::Future {
+            let count = req.len();
+            let in_flight = self.in_flight.fetch_add(count, Ordering::SeqCst) + count;
+            if in_flight > self.max_in_flight.load(Ordering::SeqCst) {
+                self.max_in_flight.store(in_flight, Ordering::SeqCst);
+            }
+
+            CountingResponse {
+                inner: ScriptedBackend.call(req),
+                count,
+                polled: false,
+                in_flight: self.in_flight.clone(),
+            }
+        }
+    }
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
         }
+        buf
+    }
+
+    /// Gets what `ScriptedBackend` answers the given command with, as it goes out to the client.
+    fn backend_reply(cmd: &str) -> Vec<u8> {
+        let mut responses = ScriptedBackend.call(vec![(0, RedisMessage::from_inline(cmd))]).wait().unwrap();
+        match responses.remove(0).1 {
+            MessageResponse::Complete(msg) => msg.into_resp().to_vec(),
+            _ => panic!("backend didn't answer '{}'", cmd),
+        }
+    }
+
+    fn error_reply(error: &str) -> Vec<u8> { RedisMessage::from_error_str(error).into_resp().to_vec() }
+
+    #[test]
+    fn test_golden_client_session() {
+        // A pipelined mix of plain commands, fragmented commands, a command over the fragment
+        // limit, a backend failure, and an invalid command, after which the client is hung up on.
+        // Anything the client sent after that is never read.
+        let script = vec![
+            command(&["get", "foo"]),
+            command(&["set", "foo", "baz"]),
+            command(&["mget", "foo", "missing"]),
+            command(&["mget", "a", "b", "c", "d"]),
+            command(&["get", "broken"]),
+            command(&["del", "foo", "missing"]),
+            command(&["flushall"]),
+            command(&["get", "foo"]),
+        ];
+
+        let (sink, capture) = capture();
+        let sink = sink.scoped(&["listeners", "golden"]);
+        let registry = Arc::new(ClientRegistry::new());
+        let fds = Arc::new(FdTracker::new(sink.clone()));
+        let fd = FdTracker::try_acquire(&fds).unwrap();
+        let (warden, _evacuate) = Evacuate::new(empty::<(), ()>(), 0);
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let limits = FragmentLimits {
+            max_per_command: Some(3),
+            max_concurrent: None,
+        };
+        let conn = ClientConnection::new(addr, &registry, fd, warden, sink).set_fragment_limits(limits);
+        assert_eq!(registry.list(None, 10).0, 1);
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let client = ScriptedClient {
+            input: io::Cursor::new(script.concat()),
+            output: output.clone(),
+        };
+        let transport = RedisTransport::new(client, RedisTransportConfig::default(), None);
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let pipeline = Pipeline::new(transport, ScriptedBackend, processor, conn, BatchConfiguration::default());
+
+        // Once the client is gone, everything its connection held should have been let go of.
+        assert_eq!(pipeline.wait(), Ok(()));
+        assert_eq!(registry.list(None, 10).0, 0);
+        assert_eq!(fds.used(), 0);
+
+        // Every command up to the invalid one is answered, in order, with what the backend said for
+        // it, or with why it never got there.  Fragmented commands have their keys answered one at
+        // a time, and gathered back up into a single reply.  The transport answers the invalid
+        // command itself and ends the stream, so it's neither sent anywhere nor counted as a
+        // client error.  Deletes are totalled up across their keys, each of which the backend deleted.
+        let deleted = RedisMessage::from_integer(1).into_resp().to_vec();
+        assert!(backend_reply("del foo") == deleted && backend_reply("del missing") == deleted);
+        let replies = vec![
+            backend_reply("get foo"),
+            backend_reply("set foo baz"),
+            [b"*2\r\n".to_vec(), backend_reply("get foo"), backend_reply("get missing")].concat(),
+            error_reply("command has 4 keys, which is more than the limit of 3"),
+            error_reply("failed to receive response"),
+            RedisMessage::from_integer(2).into_resp().to_vec(),
+            error_reply("command not valid"),
+        ];
+        let expected = replies.concat();
+        assert_eq!(String::from_utf8_lossy(&output.lock().unwrap()), String::from_utf8_lossy(&expected));
+
+        // Everything read counts as received, except that the invalid command counts as the error it
+        // was answered with in place of it.  Nothing after it is read at all.
+        let received = script[..6].iter().map(Vec::len).sum::<usize>() + error_reply("command not valid").len();
+
+        let counts = capture.counts();
+        let get = |name: &str| counts.get(name).cloned().unwrap_or(0);
+        assert_eq!(get("listeners.golden.clients_connected"), 0);
+        assert_eq!(get("listeners.golden.client_errors"), 0);
+        assert_eq!(get("listeners.golden.client.messages_received"), replies.len() as i64);
+        assert_eq!(get("listeners.golden.client.bytes_received"), received as i64);
+        assert_eq!(get("listeners.golden.client.messages_sent"), replies.len() as i64);
+        assert_eq!(get("listeners.golden.client.bytes_sent"), expected.len() as i64);
+        assert_eq!(get("listeners.golden.client.fragment_rejections"), 1);
+        assert_eq!(get("listeners.golden.client.fragment_throttles"), 0);
+    }
+
+    #[test]
+    fn test_concurrent_fragment_limit() {
+        // A pipeline of fragmented commands that, all together, go well over the limit on how many
+        // fragments the client can have outstanding.
+        let keys = (0..10).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        let mut args = vec!["mget"];
+        args.extend(keys.iter().map(|key| key.as_str()));
+        let script = (0..20).map(|_| command(&args)).collect::<Vec<_>>();
+
+        let (sink, capture) = capture();
+        let sink = sink.scoped(&["listeners", "throttled"]);
+        let registry = Arc::new(ClientRegistry::new());
+        let fds = Arc::new(FdTracker::new(sink.clone()));
+        let fd = FdTracker::try_acquire(&fds).unwrap();
+        let (warden, _evacuate) = Evacuate::new(empty::<(), ()>(), 0);
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let limits = FragmentLimits {
+            max_per_command: None,
+            max_concurrent: Some(25),
+        };
+        let conn = ClientConnection::new(addr, &registry, fd, warden, sink).set_fragment_limits(limits);
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let client = ScriptedClient {
+            input: io::Cursor::new(script.concat()),
+            output: output.clone(),
+        };
+        let transport = RedisTransport::new(client, RedisTransportConfig::default(), None);
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let backend = CountingBackend::default();
+        let pipeline = Pipeline::new(transport, backend.clone(), processor, conn, BatchConfiguration::default());
+        assert_eq!(pipeline.wait(), Ok(()));
+
+        // Every command was answered in full, but the backend never had more than two commands'
+        // worth of fragments outstanding for the client at once.
+        let response = [b"*10\r\n".to_vec(), backend_reply("get key0").repeat(10)].concat();
+        let expected = response.repeat(20);
+        assert_eq!(String::from_utf8_lossy(&output.lock().unwrap()), String::from_utf8_lossy(&expected));
+
+        let max_in_flight = backend.max_in_flight.load(Ordering::SeqCst);
+        assert!(max_in_flight <= 25, "{} fragments were outstanding at once", max_in_flight);
+        assert!(max_in_flight > 10, "commands were never sent concurrently");
+
+        let counts = capture.counts();
+        assert!(counts.get("listeners.throttled.client.fragment_throttles").cloned().unwrap_or(0) > 0);
+    }
+
+    #[test]
+    fn test_client_hangs_up_mid_pipeline() {
+        // The client pipelines a few commands and goes away before we can answer any of them,
+        // which is just a client disconnecting, and not something to raise an error over.
+        let script = vec![
+            command(&["get", "foo"]),
+            command(&["mget", "foo", "missing"]),
+            command(&["set", "foo", "baz"]),
+        ];
+
+        let (sink, capture) = capture();
+        let sink = sink.scoped(&["listeners", "hangup"]);
+        let registry = Arc::new(ClientRegistry::new());
+        let fds = Arc::new(FdTracker::new(sink.clone()));
+        let fd = FdTracker::try_acquire(&fds).unwrap();
+        let (warden, _evacuate) = Evacuate::new(empty::<(), ()>(), 0);
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let conn = ClientConnection::new(addr, &registry, fd, warden, sink);
+
+        let client = HungUpClient {
+            input: io::Cursor::new(script.concat()),
+        };
+        let transport = RedisTransport::new(client, RedisTransportConfig::default(), None);
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let pipeline = Pipeline::new(transport, ScriptedBackend, processor, conn, BatchConfiguration::default());
+
+        assert_eq!(pipeline.wait(), Err(()));
+        assert_eq!(registry.list(None, 10).0, 0);
+        assert_eq!(fds.used(), 0);
+
+        let counts = capture.counts();
+        let get = |name: &str| counts.get(name).cloned().unwrap_or(0);
+        assert_eq!(get("listeners.hangup.clients_connected"), 0);
+        assert_eq!(get("listeners.hangup.client_errors"), 0);
+        assert_eq!(get("listeners.hangup.client.hangups"), 1);
+    }
+
+    #[test]
+    fn test_local_commands() {
+        // Commands we answer ourselves are answered in order with everything else, and quitting
+        // still gets everything before it answered before the client is hung up on.
+        let script = vec![
+            command(&["ping"]),
+            command(&["Ping", "hi"]),
+            command(&["echo", "there"]),
+            command(&["get", "foo"]),
+            command(&["Quit"]),
+            command(&["get", "foo"]),
+        ];
+
+        let (sink, capture) = capture();
+        let sink = sink.scoped(&["listeners", "local"]);
+        let registry = Arc::new(ClientRegistry::new());
+        let fds = Arc::new(FdTracker::new(sink.clone()));
+        let fd = FdTracker::try_acquire(&fds).unwrap();
+        let (warden, _evacuate) = Evacuate::new(empty::<(), ()>(), 0);
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let conn = ClientConnection::new(addr, &registry, fd, warden, sink);
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let client = ScriptedClient {
+            input: io::Cursor::new(script.concat()),
+            output: output.clone(),
+        };
+        let transport = RedisTransport::new(client, RedisTransportConfig::default(), None);
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let pipeline = Pipeline::new(transport, ScriptedBackend, processor, conn, BatchConfiguration::default());
+        assert_eq!(pipeline.wait(), Ok(()));
+
+        let expected = [
+            b"+PONG\r\n".to_vec(),
+            b"$2\r\nhi\r\n".to_vec(),
+            b"$5\r\nthere\r\n".to_vec(),
+            backend_reply("get foo"),
+            b"+OK\r\n".to_vec(),
+        ]
+        .concat();
+        assert_eq!(String::from_utf8_lossy(&output.lock().unwrap()), String::from_utf8_lossy(&expected));
+
+        let counts = capture.counts();
+        let get = |name: &str| counts.get(name).cloned().unwrap_or(0);
+        assert_eq!(get("listeners.local.client.local_commands"), 4);
+        assert_eq!(get("listeners.local.client.messages_sent"), 5);
+    }
+
+    #[test]
+    fn test_subscriptions_unavailable() {
+        // Without anywhere to relay subscriptions to, subscribing is refused like any other command
+        // we can't handle, and the client carries on as normal.
+        let script = vec![command(&["subscribe", "news"]), command(&["get", "foo"])];
+
+        let (sink, capture) = capture();
+        let sink = sink.scoped(&["listeners", "pubsub"]);
+        let registry = Arc::new(ClientRegistry::new());
+        let fds = Arc::new(FdTracker::new(sink.clone()));
+        let fd = FdTracker::try_acquire(&fds).unwrap();
+        let (warden, _evacuate) = Evacuate::new(empty::<(), ()>(), 0);
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let conn = ClientConnection::new(addr, &registry, fd, warden, sink);
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let client = ScriptedClient {
+            input: io::Cursor::new(script.concat()),
+            output: output.clone(),
+        };
+        let transport = RedisTransport::new(client, RedisTransportConfig::default(), None);
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let pipeline = Pipeline::new(transport, ScriptedBackend, processor, conn, BatchConfiguration::default())
+            .set_subscriptions(None);
+        assert_eq!(pipeline.wait(), Ok(()));
+
+        let expected = [error_reply("pub/sub is not supported by this listener"), backend_reply("get foo")].concat();
+        assert_eq!(String::from_utf8_lossy(&output.lock().unwrap()), String::from_utf8_lossy(&expected));
+
+        let counts = capture.counts();
+        let get = |name: &str| counts.get(name).cloned().unwrap_or(0);
+        assert_eq!(get("listeners.pubsub.client.messages_sent"), 2);
+        assert_eq!(get("listeners.pubsub.client_errors"), 0);
+    }
+
+    #[test]
+    fn test_slo_breaches() {
+        // Every command is read, and answered, together, so they all take at least as long as the
+        // backend does, which is well over the threshold for gets, and well under it for sets.
+        let script = vec![
+            command(&["get", "foo"]),
+            command(&["set", "foo", "baz"]),
+            command(&["get", "missing"]),
+            command(&["mget", "foo", "missing"]),
+        ];
+
+        let (sink, capture) = capture();
+        let sink = sink.scoped(&["listeners", "slo"]);
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let mut config = ListenerConfiguration::default();
+        let mut thresholds = HashMap::new();
+        thresholds.insert("get".to_owned(), 5);
+        thresholds.insert("set".to_owned(), 60_000);
+        thresholds.insert("default".to_owned(), 5);
+        config.slo = Some(thresholds);
+        let slo = SloTable::from_config(&config, &processor, &sink).unwrap().map(Arc::new);
+
+        let registry = Arc::new(ClientRegistry::new());
+        let fds = Arc::new(FdTracker::new(sink.clone()));
+        let fd = FdTracker::try_acquire(&fds).unwrap();
+        let (warden, _evacuate) = Evacuate::new(empty::<(), ()>(), 0);
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let conn = ClientConnection::new(addr, &registry, fd, warden, sink).set_slo_table(slo);
+
+        let client = ScriptedClient {
+            input: io::Cursor::new(script.concat()),
+            output: Arc::new(Mutex::new(Vec::new())),
+        };
+        let transport = RedisTransport::new(client, RedisTransportConfig::default(), None);
+        let backend = DelayedBackend(Duration::from_millis(25));
+        let pipeline = Pipeline::new(transport, backend, processor, conn, BatchConfiguration::default());
+        assert_eq!(pipeline.wait(), Ok(()));
+
+        let counts = capture.counts();
+        let get = |name: &str| counts.get(name).cloned().unwrap_or(0);
+        assert_eq!(get("listeners.slo.client.messages_sent"), 4);
+        assert_eq!(get("listeners.slo.slo.get.requests"), 2);
+        assert_eq!(get("listeners.slo.slo.get.slo_breaches"), 2);
+        assert_eq!(get("listeners.slo.slo.set.requests"), 1);
+        assert_eq!(get("listeners.slo.slo.set.slo_breaches"), 0);
+        assert_eq!(get("listeners.slo.slo.default.requests"), 1);
+        assert_eq!(get("listeners.slo.slo.default.slo_breaches"), 1);
+    }
+
+    #[test]
+    fn test_fragment_limits_from_config() {
+        let mut config = ListenerConfiguration::default();
+        let limits = FragmentLimits::from_config(&config).unwrap();
+        assert_eq!(limits.max_per_command, None);
+        assert_eq!(limits.max_concurrent, None);
+
+        config.max_fragments_per_command = Some(100);
+        config.max_concurrent_fragments_per_client = Some(1000);
+        let limits = FragmentLimits::from_config(&config).unwrap();
+        assert_eq!(limits.max_per_command, Some(100));
+        assert_eq!(limits.max_concurrent, Some(1000));
+
+        config.max_fragments_per_command = Some(0);
+        assert!(FragmentLimits::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_batch_configuration_from_config() {
+        // Unless told otherwise, batches are what they've always been.
+        let mut config = ListenerConfiguration::default();
+        let batching = BatchConfiguration::from_config(&config).unwrap();
+        assert_eq!(batching.size, 128);
+        assert_eq!(batching.linger, None);
+
+        config.batch_size = Some(16);
+        config.batch_linger_us = Some(500);
+        let batching = BatchConfiguration::from_config(&config).unwrap();
+        assert_eq!(batching.size, 16);
+        assert_eq!(batching.linger, Some(Duration::from_micros(500)));
+
+        config.batch_linger_us = Some(0);
+        assert_eq!(BatchConfiguration::from_config(&config).unwrap().linger, None);
+
+        config.batch_size = Some(0);
+        assert!(BatchConfiguration::from_config(&config).is_err());
     }
 }
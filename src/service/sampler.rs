@@ -0,0 +1,371 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::ListenerConfiguration;
+use errors::CreationError;
+use futures::prelude::*;
+use rand::{thread_rng, Rng};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+
+// Prefixes are only looked for this far into a key, so that a key without a delimiter near its
+// start can't have most of itself exported as its "prefix".
+const MAX_PREFIX_LEN: usize = 64;
+
+// What keys without a usable prefix are counted under.
+const NO_PREFIX: &str = "<none>";
+
+// How often counts are halved, so that the table follows what traffic looks like now rather than
+// what it looked like when the listener started.
+const DECAY_INTERVAL_SECS: u64 = 600;
+
+// How often the most common prefixes are logged, and how many of them are.
+const SUMMARY_INTERVAL_SECS: u64 = 3600;
+const SUMMARY_PREFIXES: usize = 10;
+
+lazy_static! {
+    static ref SAMPLERS: Mutex<HashMap<String, Arc<KeySampler>>> = Mutex::new(HashMap::new());
+}
+
+/// Registers the key sampler for a listener, replacing that of any previous version of it.
+///
+/// Listeners that don't sample keys register `None`, so that a reload which turns sampling off
+/// stops exposing the old table.
+pub fn register_key_sampler(listener: &str, sampler: Option<Arc<KeySampler>>) {
+    let mut samplers = SAMPLERS.lock().unwrap();
+    match sampler {
+        Some(sampler) => samplers.insert(listener.to_owned(), sampler),
+        None => samplers.remove(listener),
+    };
+}
+
+/// Gets the key samplers of every listener that samples keys.
+pub fn get_key_samplers() -> Vec<(String, Arc<KeySampler>)> {
+    let samplers = SAMPLERS.lock().unwrap();
+    samplers
+        .iter()
+        .map(|(listener, sampler)| (listener.clone(), sampler.clone()))
+        .collect()
+}
+
+/// Logs the most common prefixes seen by a key sampler every hour, until `close` resolves.
+pub fn log_key_samples<F: Future>(
+    listener: String, sampler: Arc<KeySampler>, close: F,
+) -> impl Future<Item = (), Error = ()> {
+    let interval = Duration::from_secs(SUMMARY_INTERVAL_SECS);
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| error!("[key sampler] timer failed: {}", e))
+        .for_each(move |_| {
+            let sample = sampler.get_sample();
+            let prefixes = sample
+                .prefixes
+                .iter()
+                .take(SUMMARY_PREFIXES)
+                .map(|p| format!("{}={:.0}", p.prefix, p.estimated))
+                .collect::<Vec<_>>();
+            info!(
+                "[key sampler] top key prefixes on listener '{}' ({} keys sampled): {}",
+                listener,
+                sample.sampled,
+                prefixes.join(", ")
+            );
+            Ok(())
+        })
+        .select2(close)
+        .then(|_| Ok::<(), ()>(()))
+}
+
+/// How a listener should sample the keys its clients use.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeySamplerConfiguration {
+    pub sample_rate: f64,
+    pub delimiter: u8,
+    pub max_prefixes: usize,
+}
+
+impl KeySamplerConfiguration {
+    /// Gets the key sampling configuration for the given listener, if it wants to sample keys.
+    pub fn from_config(config: &ListenerConfiguration) -> Result<Option<KeySamplerConfiguration>, CreationError> {
+        let sample_rate = match config.key_sample_rate {
+            Some(rate) => rate,
+            None => return Ok(None),
+        };
+        if sample_rate <= 0.0 || sample_rate > 1.0 {
+            return Err(CreationError::InvalidParameter("key_sample_rate".to_string()));
+        }
+
+        let delimiter = match config.key_sample_delimiter.as_ref().map(|s| s.as_bytes()) {
+            None => b':',
+            Some(&[delimiter]) => delimiter,
+            Some(_) => return Err(CreationError::InvalidParameter("key_sample_delimiter".to_string())),
+        };
+
+        let max_prefixes = config.key_sample_max_prefixes.unwrap_or(100);
+        if max_prefixes == 0 {
+            return Err(CreationError::InvalidParameter("key_sample_max_prefixes".to_string()));
+        }
+
+        Ok(Some(KeySamplerConfiguration {
+            sample_rate,
+            delimiter,
+            max_prefixes,
+        }))
+    }
+}
+
+/// A key prefix, and roughly how often it's been seen.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PrefixCount {
+    pub prefix: String,
+
+    /// How many times the prefix was sampled, decayed over time.
+    pub samples: f64,
+
+    /// How many keys with the prefix we think went through, based on the sample rate.
+    pub estimated: f64,
+}
+
+/// The most common key prefixes seen by a listener, and how much was sampled to find them.
+#[derive(Clone, Debug, Serialize)]
+pub struct KeySample {
+    pub sample_rate: f64,
+    pub sampled: usize,
+    pub prefixes: Vec<PrefixCount>,
+}
+
+struct PrefixTable {
+    counts: HashMap<String, f64>,
+    last_decay: Instant,
+}
+
+/// Samples the keys used by a listener's clients, keeping count of their prefixes.
+///
+/// Only a key's prefix, up to the first delimiter, is ever kept.  The number of prefixes tracked
+/// is bounded: once the table is full, a new prefix takes the place of the least common one.
+/// Counts are halved every so often, so that prefixes which are no longer used fall out and make
+/// room for ones that are.
+pub struct KeySampler {
+    config: KeySamplerConfiguration,
+    sampled: AtomicUsize,
+    table: Mutex<PrefixTable>,
+}
+
+impl KeySampler {
+    pub fn new(config: KeySamplerConfiguration) -> KeySampler {
+        KeySampler {
+            config,
+            sampled: AtomicUsize::new(0),
+            table: Mutex::new(PrefixTable {
+                counts: HashMap::new(),
+                last_decay: Instant::now(),
+            }),
+        }
+    }
+
+    /// Samples the given key, if it's picked.
+    pub fn sample(&self, key: &[u8]) {
+        if self.config.sample_rate < 1.0 && !thread_rng().gen_bool(self.config.sample_rate) {
+            return;
+        }
+
+        self.record(key);
+    }
+
+    /// Gets the most common prefixes, most common first.
+    pub fn get_sample(&self) -> KeySample {
+        let table = self.table.lock().unwrap();
+        let mut prefixes = table
+            .counts
+            .iter()
+            .map(|(prefix, samples)| {
+                PrefixCount {
+                    prefix: prefix.clone(),
+                    samples: *samples,
+                    estimated: samples / self.config.sample_rate,
+                }
+            })
+            .collect::<Vec<_>>();
+        prefixes.sort_by(|a, b| b.samples.partial_cmp(&a.samples).unwrap().then_with(|| a.prefix.cmp(&b.prefix)));
+
+        KeySample {
+            sample_rate: self.config.sample_rate,
+            sampled: self.sampled.load(Ordering::Relaxed),
+            prefixes,
+        }
+    }
+
+    fn record(&self, key: &[u8]) {
+        self.sampled.fetch_add(1, Ordering::Relaxed);
+        let prefix = get_prefix(key, self.config.delimiter);
+
+        let mut table = self.table.lock().unwrap();
+        if table.last_decay.elapsed() >= Duration::from_secs(DECAY_INTERVAL_SECS) {
+            table.decay();
+        }
+        table.increment(prefix, self.config.max_prefixes);
+    }
+}
+
+impl PrefixTable {
+    fn increment(&mut self, prefix: String, max_prefixes: usize) {
+        if let Some(count) = self.counts.get_mut(&prefix) {
+            *count += 1.0;
+            return;
+        }
+
+        if self.counts.len() >= max_prefixes {
+            let evicted = self
+                .counts
+                .iter()
+                .min_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+                .map(|(prefix, _)| prefix.clone())
+                .expect("full prefix table should not be empty");
+            self.counts.remove(&evicted);
+        }
+        self.counts.insert(prefix, 1.0);
+    }
+
+    fn decay(&mut self) {
+        for count in self.counts.values_mut() {
+            *count /= 2.0;
+        }
+        self.counts.retain(|_, count| *count >= 0.5);
+        self.last_decay = Instant::now();
+    }
+}
+
+/// Gets the prefix of the given key: everything before the first delimiter.
+fn get_prefix(key: &[u8], delimiter: u8) -> String {
+    let search_len = key.len().min(MAX_PREFIX_LEN + 1);
+    match key[..search_len].iter().position(|b| *b == delimiter) {
+        Some(pos) if pos > 0 => String::from_utf8_lossy(&key[..pos]).into_owned(),
+        _ => NO_PREFIX.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn get_sampler(sample_rate: f64, max_prefixes: usize) -> KeySampler {
+        KeySampler::new(KeySamplerConfiguration {
+            sample_rate,
+            delimiter: b':',
+            max_prefixes,
+        })
+    }
+
+    fn get_counts(sampler: &KeySampler) -> Vec<(String, f64)> {
+        sampler
+            .get_sample()
+            .prefixes
+            .into_iter()
+            .map(|count| (count.prefix, count.samples))
+            .collect()
+    }
+
+    #[test]
+    fn test_prefix_truncation() {
+        assert_eq!(get_prefix(b"user:1234:profile", b':'), "user");
+        assert_eq!(get_prefix(b"session|abcd", b'|'), "session");
+
+        // Keys without a prefix are never exported whole.
+        assert_eq!(get_prefix(b"user1234", b':'), NO_PREFIX);
+        assert_eq!(get_prefix(b":1234", b':'), NO_PREFIX);
+        assert_eq!(get_prefix(b"", b':'), NO_PREFIX);
+
+        // Neither are keys whose delimiter is too far in to be a prefix.
+        let mut key = vec![b'a'; MAX_PREFIX_LEN];
+        key.push(b':');
+        assert_eq!(get_prefix(&key, b':').len(), MAX_PREFIX_LEN);
+        key.insert(0, b'a');
+        assert_eq!(get_prefix(&key, b':'), NO_PREFIX);
+    }
+
+    #[test]
+    fn test_cardinality_is_bounded() {
+        let sampler = get_sampler(1.0, 3);
+        for _ in 0..10 {
+            sampler.record(b"user:1");
+        }
+        for _ in 0..5 {
+            sampler.record(b"session:1");
+        }
+        sampler.record(b"cart:1");
+
+        // Every new prefix past the limit takes the place of the least common one, so a flood of
+        // rare prefixes can't push out the common ones.
+        for i in 0..100 {
+            sampler.record(format!("unique{}:1", i).as_bytes());
+        }
+
+        let counts = get_counts(&sampler);
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts[0], ("user".to_owned(), 10.0));
+        assert_eq!(counts[1], ("session".to_owned(), 5.0));
+        assert_eq!(counts[2], ("unique99".to_owned(), 1.0));
+    }
+
+    #[test]
+    fn test_decay() {
+        let sampler = get_sampler(1.0, 10);
+        for _ in 0..4 {
+            sampler.record(b"user:1");
+        }
+        sampler.record(b"cart:1");
+
+        sampler.table.lock().unwrap().decay();
+        assert_eq!(get_counts(&sampler), vec![("user".to_owned(), 2.0), ("cart".to_owned(), 0.5)]);
+
+        // Prefixes that have faded away are dropped.
+        sampler.table.lock().unwrap().decay();
+        assert_eq!(get_counts(&sampler), vec![("user".to_owned(), 1.0)]);
+    }
+
+    #[test]
+    fn test_sampling_math() {
+        let sample_rate = 0.1;
+        let sampler = get_sampler(sample_rate, 10);
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let total = 100_000;
+        for _ in 0..total {
+            if rng.gen_bool(sample_rate) {
+                sampler.record(b"user:1");
+            }
+        }
+
+        // Scaling the samples back up by the sample rate should get us close to the real count.
+        let sample = sampler.get_sample();
+        assert_eq!(sample.prefixes.len(), 1);
+        assert_eq!(sample.prefixes[0].samples, sample.sampled as f64);
+
+        let estimated = sample.prefixes[0].estimated;
+        let error = (estimated - total as f64).abs() / total as f64;
+        assert!(error < 0.05, "estimate of {} is too far from {}", estimated, total);
+    }
+}
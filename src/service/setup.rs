@@ -0,0 +1,251 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::ListenerConfiguration;
+use errors::CreationError;
+use libc;
+use metrics::{register_latencies, LatencyBuckets};
+use std::{os::unix::io::RawFd, sync::Arc, time::Instant};
+use util::clock::saturating_duration_since;
+
+/// The latency histograms that a listener's clients record into.
+///
+/// Latencies are recorded along with the request they were measured for, as its exemplar, unless
+/// they're over before the client has sent any request at all, as the first byte can be, and a TLS
+/// handshake always is.
+pub struct ClientLatencies {
+    /// How long it takes to service each batch of commands, from reading it to sending the last
+    /// of its responses.
+    pub batch: Arc<LatencyBuckets>,
+
+    /// How long each command takes, from the batch it came in being read to its own response
+    /// being sent.
+    pub message: Arc<LatencyBuckets>,
+
+    /// How long clients take to send anything at all after we accept them.
+    pub first_byte: Arc<LatencyBuckets>,
+
+    /// How long it takes from the first byte a client sends to its first command being parsed.
+    pub first_command: Arc<LatencyBuckets>,
+
+    /// How long it takes from a client's first command being parsed to its first response being sent.
+    pub first_response: Arc<LatencyBuckets>,
+
+    /// How long clients take to finish their TLS handshake after we accept them, if the listener
+    /// terminates TLS.
+    pub tls_handshake: Option<Arc<LatencyBuckets>>,
+}
+
+impl ClientLatencies {
+    pub fn from_config(listener: &str, config: &ListenerConfiguration) -> Result<ClientLatencies, CreationError> {
+        let bounds_us = LatencyBuckets::from_config(config)?;
+        Ok(ClientLatencies {
+            batch: register_latencies(listener, "batch", bounds_us.clone()),
+            message: register_latencies(listener, "message", bounds_us.clone()),
+            first_byte: register_latencies(listener, "first_byte", bounds_us.clone()),
+            first_command: register_latencies(listener, "first_command", bounds_us.clone()),
+            first_response: register_latencies(listener, "first_response", bounds_us.clone()),
+            tls_handshake: config
+                .tls
+                .as_ref()
+                .map(|_| register_latencies(listener, "tls_handshake", bounds_us)),
+        })
+    }
+}
+
+/// How far along a client is in setting up its connection.
+///
+/// Each step is timed from the one before it, so that slow connection setup can be pinned on
+/// the client being slow to talk, slow to finish its first command, or us being slow to answer.
+pub struct ConnectionSetup {
+    fd: Option<RawFd>,
+    accepted: Instant,
+    first_byte: Option<Instant>,
+    first_command: Option<Instant>,
+
+    // The identifier of the client's first request, and the hash of its first key.
+    first_request: Option<(usize, u64)>,
+}
+
+impl ConnectionSetup {
+    /// Starts timing the setup of the client connection on `fd`, which was just accepted.
+    ///
+    /// Without a socket to watch, the first byte can only be seen along with the first command.
+    /// That's the case for clients using TLS: what's waiting on their socket is TLS records, not
+    /// anything they've sent us.
+    pub fn new(fd: Option<RawFd>) -> ConnectionSetup {
+        ConnectionSetup {
+            fd,
+            accepted: Instant::now(),
+            first_byte: None,
+            first_command: None,
+            first_request: None,
+        }
+    }
+
+    /// Checks whether the client has sent us anything yet, without reading it.
+    ///
+    /// This has to be checked before the transport gets a chance to read from the client, so
+    /// that the first byte is seen before it's parsed.
+    pub fn poll_first_byte(&mut self, latencies: &ClientLatencies) {
+        if self.first_byte.is_none() && self.fd.map_or(false, has_pending_data) {
+            let now = Instant::now();
+            latencies.first_byte.record(saturating_duration_since(now, self.accepted));
+            self.first_byte = Some(now);
+        }
+    }
+
+    /// Handles the client's first command being parsed, as part of the request with the given
+    /// identifier, whose first key has the given hash.
+    ///
+    /// If we never saw the first byte by itself, it arrived along with the command.
+    pub fn on_first_command(&mut self, latencies: &ClientLatencies, request_id: usize, key_hash: u64) {
+        if self.first_command.is_some() {
+            return;
+        }
+
+        let now = Instant::now();
+        let first_byte = match self.first_byte {
+            Some(first_byte) => first_byte,
+            None => {
+                let latency = saturating_duration_since(now, self.accepted);
+                latencies.first_byte.record_exemplar(latency, request_id, key_hash);
+                self.first_byte = Some(now);
+                now
+            },
+        };
+
+        let latency = saturating_duration_since(now, first_byte);
+        latencies.first_command.record_exemplar(latency, request_id, key_hash);
+        self.first_command = Some(now);
+        self.first_request = Some((request_id, key_hash));
+    }
+
+    /// Handles the client's first response being sent, which finishes setting up the connection.
+    pub fn on_first_response(&mut self, latencies: &ClientLatencies) {
+        if let (Some(first_command), Some((request_id, key_hash))) = (self.first_command, self.first_request) {
+            let latency = saturating_duration_since(Instant::now(), first_command);
+            latencies.first_response.record_exemplar(latency, request_id, key_hash);
+        }
+    }
+}
+
+/// Whether or not there's anything waiting to be read on the given socket.
+fn has_pending_data(fd: RawFd) -> bool {
+    let mut buf = [0u8; 1];
+    let flags = libc::MSG_PEEK | libc::MSG_DONTWAIT;
+    unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), flags) > 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+        os::unix::io::AsRawFd,
+        thread,
+        time::Duration,
+    };
+
+    fn get_latencies() -> ClientLatencies {
+        let bounds_us = vec![1_000, 10_000, 100_000, 1_000_000];
+        ClientLatencies {
+            batch: Arc::new(LatencyBuckets::new(bounds_us.clone())),
+            message: Arc::new(LatencyBuckets::new(bounds_us.clone())),
+            first_byte: Arc::new(LatencyBuckets::new(bounds_us.clone())),
+            first_command: Arc::new(LatencyBuckets::new(bounds_us.clone())),
+            first_response: Arc::new(LatencyBuckets::new(bounds_us)),
+            tls_handshake: None,
+        }
+    }
+
+    fn count(histogram: &LatencyBuckets) -> usize { histogram.cumulative().last().unwrap().1 }
+
+    #[test]
+    fn test_setup_is_timed_step_by_step() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let latencies = get_latencies();
+        let mut setup = ConnectionSetup::new(Some(server.as_raw_fd()));
+
+        // Nothing's been sent yet, so there's no first byte to see.
+        thread::sleep(Duration::from_millis(20));
+        setup.poll_first_byte(&latencies);
+        assert_eq!(count(&latencies.first_byte), 0);
+
+        client.write_all(b"*1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(20));
+        setup.poll_first_byte(&latencies);
+        setup.poll_first_byte(&latencies);
+        assert_eq!(count(&latencies.first_byte), 1);
+        assert!(latencies.first_byte.sum_us() >= 40_000);
+
+        thread::sleep(Duration::from_millis(20));
+        setup.on_first_command(&latencies, 1, 0xfeed);
+        setup.on_first_response(&latencies);
+        assert_eq!(count(&latencies.first_command), 1);
+        assert_eq!(count(&latencies.first_response), 1);
+        assert!(latencies.first_command.sum_us() >= 20_000);
+    }
+
+    #[test]
+    fn test_first_byte_with_first_command() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        // If the command shows up before we look for the first byte, they arrived together.
+        let latencies = get_latencies();
+        let mut setup = ConnectionSetup::new(Some(server.as_raw_fd()));
+        setup.on_first_command(&latencies, 1, 0xfeed);
+        assert_eq!(count(&latencies.first_byte), 1);
+        assert_eq!(count(&latencies.first_command), 1);
+        assert_eq!(count(&latencies.first_response), 0);
+
+        // Both were recorded for the first request, and so is the first response.
+        setup.on_first_response(&latencies);
+        for histogram in &[&latencies.first_byte, &latencies.first_command, &latencies.first_response] {
+            let exemplar = histogram.exemplars().into_iter().flatten().next().unwrap();
+            assert_eq!((exemplar.request_id, exemplar.key_hash), (1, 0xfeed));
+        }
+    }
+
+    #[test]
+    fn test_first_byte_without_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_server, _) = listener.accept().unwrap();
+
+        // Whatever is waiting on the socket isn't looked at, so the first byte comes with the
+        // first command.
+        client.write_all(b"*1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        let latencies = get_latencies();
+        let mut setup = ConnectionSetup::new(None);
+        setup.poll_first_byte(&latencies);
+        assert_eq!(count(&latencies.first_byte), 0);
+
+        setup.on_first_command(&latencies, 1, 0xfeed);
+        assert_eq!(count(&latencies.first_byte), 1);
+    }
+}
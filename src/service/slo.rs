@@ -0,0 +1,200 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use backend::processor::Processor;
+use conf::ListenerConfiguration;
+use errors::CreationError;
+use metrics::MetricSink;
+use std::time::Duration;
+use util::clock::duration_as_us;
+
+/// The name of the class that commands without a threshold of their own fall into.
+const DEFAULT_CLASS: &str = "default";
+
+/// A latency threshold, and the metrics of the requests held to it.
+struct SloClass {
+    threshold_us: u64,
+    sink: MetricSink,
+}
+
+/// Per-command latency objectives for a listener.
+///
+/// Every request held to an objective is counted under `slo.<command>.requests`, and those that
+/// took longer than the threshold are counted again under `slo.<command>.slo_breaches`, so the
+/// rate at which an objective is being missed is just the ratio of the two.  Commands without a
+/// threshold of their own are held to the `default` threshold, if there is one, and counted
+/// under it.
+///
+/// Thresholds are resolved to a table indexed by command identifier when the listener is built,
+/// so finding the threshold for a request never involves looking up its command by name.
+pub struct SloTable {
+    commands: Vec<Option<usize>>,
+    default: Option<usize>,
+    classes: Vec<SloClass>,
+}
+
+impl SloTable {
+    /// Builds the latency objectives for the given listener, if it has any.
+    pub fn from_config<P>(
+        config: &ListenerConfiguration, processor: &P, sink: &MetricSink,
+    ) -> Result<Option<SloTable>, CreationError>
+    where
+        P: Processor,
+    {
+        let thresholds = match config.slo.as_ref() {
+            Some(thresholds) => thresholds,
+            None => return Ok(None),
+        };
+
+        let mut table = SloTable {
+            commands: vec![None; processor.get_command_count()],
+            default: None,
+            classes: Vec::new(),
+        };
+
+        for (name, threshold_ms) in thresholds {
+            if *threshold_ms == 0 {
+                return Err(CreationError::InvalidParameter(format!("slo.{}", name)));
+            }
+
+            let name = name.to_lowercase();
+            let class = SloClass {
+                threshold_us: threshold_ms * 1000,
+                sink: sink.scoped(&["slo", name.as_str()]),
+            };
+            let class_id = table.classes.len();
+
+            // Commands are matched case-insensitively, so the same one can't be given twice.
+            let slot = if name == DEFAULT_CLASS {
+                &mut table.default
+            } else {
+                match processor.find_command_id(&name) {
+                    Some(id) => &mut table.commands[id],
+                    None => return Err(CreationError::InvalidParameter(format!("slo.{}", name))),
+                }
+            };
+            if slot.replace(class_id).is_some() {
+                return Err(CreationError::InvalidParameter(format!("slo.{}", name)));
+            }
+            table.classes.push(class);
+        }
+
+        Ok(Some(table))
+    }
+
+    /// Gets the class of requests running the given command, if they're held to a threshold.
+    pub fn get_class(&self, command_id: Option<usize>) -> Option<usize> {
+        command_id.and_then(|id| self.commands.get(id).and_then(|class| *class)).or(self.default)
+    }
+
+    /// Records a request of the given class having taken the given time to be answered.
+    pub fn record(&self, class_id: usize, latency: Duration) {
+        let class = &self.classes[class_id];
+        class.sink.increment("requests");
+        if duration_as_us(latency) > class.threshold_us {
+            class.sink.increment("slo_breaches");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::redis::RedisProcessor;
+    use metrics::capture;
+    use protocol::redis::RedisTransportConfig;
+    use std::collections::HashMap;
+
+    fn get_config(thresholds: &[(&str, u64)]) -> ListenerConfiguration {
+        let mut config = ListenerConfiguration::default();
+        let slo = thresholds
+            .iter()
+            .map(|(name, threshold)| (name.to_string(), *threshold))
+            .collect::<HashMap<_, _>>();
+        config.slo = Some(slo);
+        config
+    }
+
+    #[test]
+    fn test_from_config() {
+        let (sink, _capture) = capture();
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+
+        let config = ListenerConfiguration::default();
+        assert!(SloTable::from_config(&config, &processor, &sink).unwrap().is_none());
+
+        let config = get_config(&[("get", 5), ("SET", 10)]);
+        let table = SloTable::from_config(&config, &processor, &sink).unwrap().unwrap();
+        let get = table.get_class(processor.find_command_id("get"));
+        let set = table.get_class(processor.find_command_id("set"));
+        assert!(get.is_some());
+        assert!(set.is_some());
+        assert_ne!(get, set);
+
+        // Without a default, nothing else is held to a threshold.
+        assert_eq!(table.get_class(processor.find_command_id("mget")), None);
+        assert_eq!(table.get_class(None), None);
+
+        let config = get_config(&[("get", 5), ("default", 20)]);
+        let table = SloTable::from_config(&config, &processor, &sink).unwrap().unwrap();
+        let get = table.get_class(processor.find_command_id("get"));
+        let default = table.get_class(processor.find_command_id("mget"));
+        assert!(default.is_some());
+        assert_ne!(get, default);
+        assert_eq!(table.get_class(None), default);
+
+        let config = get_config(&[("info", 5)]);
+        assert!(SloTable::from_config(&config, &processor, &sink).is_err());
+
+        let config = get_config(&[("get", 5), ("GET", 10)]);
+        assert!(SloTable::from_config(&config, &processor, &sink).is_err());
+
+        let config = get_config(&[("get", 0)]);
+        assert!(SloTable::from_config(&config, &processor, &sink).is_err());
+    }
+
+    #[test]
+    fn test_record() {
+        let (sink, capture) = capture();
+        let processor = RedisProcessor::new(RedisTransportConfig::default());
+        let config = get_config(&[("get", 5), ("set", 10), ("default", 20)]);
+        let table = SloTable::from_config(&config, &processor, &sink).unwrap().unwrap();
+
+        let get = table.get_class(processor.find_command_id("get")).unwrap();
+        let set = table.get_class(processor.find_command_id("set")).unwrap();
+        let default = table.get_class(processor.find_command_id("del")).unwrap();
+
+        // Right on the threshold isn't a breach, but anything over it is.
+        table.record(get, Duration::from_millis(1));
+        table.record(get, Duration::from_millis(5));
+        table.record(get, Duration::from_micros(5001));
+        table.record(set, Duration::from_millis(9));
+        table.record(set, Duration::from_millis(50));
+        table.record(default, Duration::from_millis(15));
+
+        let counts = capture.counts();
+        let get = |name: &str| counts.get(name).cloned().unwrap_or(0);
+        assert_eq!(get("slo.get.requests"), 3);
+        assert_eq!(get("slo.get.slo_breaches"), 1);
+        assert_eq!(get("slo.set.requests"), 2);
+        assert_eq!(get("slo.set.slo_breaches"), 1);
+        assert_eq!(get("slo.default.requests"), 1);
+        assert_eq!(get("slo.default.slo_breaches"), 0);
+    }
+}
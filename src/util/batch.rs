@@ -19,7 +19,11 @@
 // SOFTWARE.
 use super::Sizable;
 use futures::{prelude::*, stream::Fuse};
-use std::mem;
+use std::{
+    mem,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
 
 /// An adapter for batching up items in a stream opportunistically.
 ///
@@ -27,6 +31,10 @@ use std::mem;
 /// underlying stream reports that it is not ready.  Any items returned during this loop will be
 /// stored and forwarded on either when the batch capacity is met or when the underlying stream
 /// signals that it has no available items.
+///
+/// With a linger, a batch that isn't full when the underlying stream stops being ready is held on
+/// to for up to that long, in case more items arrive to fill it out, trading a little latency for
+/// fewer, larger batches.
 #[derive(Debug)]
 #[must_use = "streams do nothing unless polled"]
 pub struct Batch<S>
@@ -38,6 +46,8 @@ where
     size: usize,
     err: Option<S::Error>,
     stream: Fuse<S>,
+    linger: Option<Duration>,
+    lingering: Option<Delay>,
 }
 
 impl<S> Batch<S>
@@ -45,7 +55,10 @@ where
     S: Stream,
     S::Item: Sizable,
 {
-    pub fn new(s: S, capacity: usize) -> Batch<S> {
+    pub fn new(s: S, capacity: usize) -> Batch<S> { Batch::with_linger(s, capacity, None) }
+
+    /// Creates a batched stream that holds on to a partial batch for up to `linger`, if given.
+    pub fn with_linger(s: S, capacity: usize, linger: Option<Duration>) -> Batch<S> {
         assert!(capacity > 0);
 
         Batch {
@@ -53,10 +66,34 @@ where
             size: 0,
             err: None,
             stream: s.fuse(),
+            linger,
+            lingering: None,
+        }
+    }
+
+    /// Whether or not a partial batch should be held on to for a little longer.
+    ///
+    /// The linger starts with the first time the underlying stream stops being ready, and isn't
+    /// extended by anything arriving after that.
+    fn poll_linger(&mut self) -> bool {
+        let linger = match self.linger {
+            Some(linger) => linger,
+            None => return false,
+        };
+
+        let lingering = self
+            .lingering
+            .get_or_insert_with(|| Delay::new(Instant::now() + linger));
+        match lingering.poll() {
+            Ok(Async::NotReady) => true,
+            // A timer that's gone away can't tell us when to stop, so we stop now.
+            _ => false,
         }
     }
 
     fn take(&mut self) -> (Vec<S::Item>, usize) {
+        self.lingering = None;
+
         let cap = self.items.capacity();
         let items = mem::replace(&mut self.items, Vec::with_capacity(cap));
         let size = mem::replace(&mut self.size, 0);
@@ -82,10 +119,11 @@ where
         loop {
             match self.stream.poll() {
                 // If the underlying stream isn't ready any more, and we have items queued up,
-                // simply return them to the caller and zero out our internal buffer.  If we have
-                // no items, then tell the caller we aren't ready.
+                // simply return them to the caller and zero out our internal buffer, unless we're
+                // lingering in the hopes of filling the batch out.  If we have no items, then tell
+                // the caller we aren't ready.
                 Ok(Async::NotReady) => {
-                    return if self.items.is_empty() {
+                    return if self.items.is_empty() || self.poll_linger() {
                         Ok(Async::NotReady)
                     } else {
                         Ok(Some(self.take()).into())
@@ -146,3 +184,123 @@ where
 
     fn close(&mut self) -> Poll<(), S::SinkError> { self.stream.close() }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future::lazy, stream::iter_ok, sync::mpsc::unbounded};
+    use test::Bencher;
+    use tokio::runtime::current_thread::Runtime;
+
+    impl Sizable for &'static str {
+        fn size(&self) -> usize { self.len() }
+    }
+
+    /// A stream that yields a fixed script of items, with a pause wherever there's a `None`.
+    struct Scripted(Vec<Option<&'static str>>);
+
+    impl Stream for Scripted {
+        type Error = ();
+        type Item = &'static str;
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+            if self.0.is_empty() {
+                return Ok(Async::Ready(None));
+            }
+
+            match self.0.remove(0) {
+                Some(item) => Ok(Async::Ready(Some(item))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    fn collect_batches<S>(mut batch: Batch<S>) -> Vec<(Vec<&'static str>, usize)>
+    where
+        S: Stream<Item = &'static str, Error = ()>,
+    {
+        // Nothing here needs to be woken up, so a pause in the underlying stream just means
+        // polling again.
+        let mut batches = Vec::new();
+        loop {
+            match batch.poll() {
+                Ok(Async::Ready(Some(items))) => batches.push(items),
+                Ok(Async::Ready(None)) => return batches,
+                Ok(Async::NotReady) => {},
+                Err(()) => panic!("unexpected error"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_batch_empty_stream() {
+        let batches = collect_batches(Batch::new(iter_ok::<_, ()>(Vec::new()), 4));
+        assert!(batches.is_empty());
+    }
+
+    #[test]
+    fn test_batch_never_empty() {
+        // Pauses with nothing buffered, including back to back, never produce an empty batch.
+        let script = vec![None, None, Some("a"), Some("bb"), None, None, Some("ccc"), None];
+        let batches = collect_batches(Batch::new(Scripted(script), 4));
+        assert_eq!(batches, vec![(vec!["a", "bb"], 3), (vec!["ccc"], 3)]);
+    }
+
+    #[test]
+    fn test_batch_capacity() {
+        let batches = collect_batches(Batch::new(iter_ok::<_, ()>(vec!["a", "b", "c"]), 2));
+        assert_eq!(batches, vec![(vec!["a", "b"], 2), (vec!["c"], 1)]);
+    }
+
+    #[test]
+    fn test_batch_linger() {
+        let (tx, rx) = unbounded();
+        let mut batch = Batch::with_linger(rx, 4, Some(Duration::from_millis(50)));
+        let mut runtime = Runtime::new().unwrap();
+
+        // A partial batch is held on to while we linger...
+        tx.unbounded_send("a").unwrap();
+        let started = Instant::now();
+        let polled = runtime.block_on(lazy(|| Ok::<_, ()>(batch.poll()))).unwrap();
+        assert!(polled.unwrap().is_not_ready());
+
+        // ...and anything arriving in the meantime joins it, until the linger runs out.
+        tx.unbounded_send("bb").unwrap();
+        let (items, rest) = runtime.block_on(batch.into_future()).map_err(|_| ()).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(items, Some((vec!["a", "bb"], 3)));
+
+        // A full batch doesn't wait around.
+        for item in vec!["c", "d", "e", "f"] {
+            tx.unbounded_send(item).unwrap();
+        }
+        let started = Instant::now();
+        let (items, _) = runtime.block_on(rest.into_future()).map_err(|_| ()).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert_eq!(items, Some((vec!["c", "d", "e", "f"], 4)));
+    }
+
+    // How long a lone item takes to make it through, which is all latency when nothing else is
+    // coming along to fill out its batch.
+    fn bench_lone_item(b: &mut Bencher, linger: Option<Duration>) {
+        let (tx, rx) = unbounded();
+        let mut batch = Some(Batch::with_linger(rx, 128, linger));
+        let mut runtime = Runtime::new().unwrap();
+
+        b.iter(|| {
+            tx.unbounded_send("a").unwrap();
+            let (items, rest) = runtime
+                .block_on(batch.take().unwrap().into_future())
+                .map_err(|_| ())
+                .unwrap();
+            batch = Some(rest);
+            items
+        });
+    }
+
+    #[bench]
+    fn bench_lone_item_no_linger(b: &mut Bencher) { bench_lone_item(b, None); }
+
+    #[bench]
+    fn bench_lone_item_linger_500us(b: &mut Bencher) { bench_lone_item(b, Some(Duration::from_micros(500))); }
+}
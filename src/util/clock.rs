@@ -0,0 +1,243 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use futures::prelude::*;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::timer::Delay;
+
+#[cfg(test)]
+use futures::{
+    future::poll_fn,
+    task::{self, Task},
+};
+#[cfg(test)]
+use std::sync::Mutex;
+
+/// A future that resolves once a clock has reached a given time.
+pub type ClockDelay = Box<Future<Item = (), Error = ()> + Send>;
+
+/// A clock that can be shared between everything that runs on it.
+pub type SharedClock = Arc<Clock>;
+
+/// Where the time comes from, for anything whose behavior depends on time passing.
+///
+/// Cooloffs, health checks, and anything else that waits on time to pass should get the time, and
+/// wait for it, through a clock rather than with `Instant::now` and tokio's timers, so that their
+/// tests can run on a `VirtualClock` and move time along as they need to, instead of sleeping.
+pub trait Clock: Send + Sync {
+    /// Gets the current time.
+    fn now(&self) -> Instant;
+
+    /// Gets a future that resolves once the given time has passed.
+    fn delay(&self, deadline: Instant) -> ClockDelay;
+}
+
+/// The real clock, backed by tokio's timers.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant { Instant::now() }
+
+    fn delay(&self, deadline: Instant) -> ClockDelay { Box::new(Delay::new(deadline).map_err(|_| ())) }
+}
+
+/// Gets a handle to the real clock.
+pub fn system_clock() -> SharedClock { Arc::new(SystemClock) }
+
+/// The longest a single request can be measured as taking before we consider the measurement bogus.
+pub const MAX_REQUEST_DURATION_SECS: u64 = 3600;
+
+/// Gets the duration between two instants, or zero if `later` is actually before `earlier`.
+///
+/// Durations are only ever measured with `Instant`, so a wall clock being stepped can't affect
+/// them, but subtracting a later instant from an earlier one panics, and `Instant` has been known to
+/// go backwards on some platforms.
+pub fn saturating_duration_since(later: Instant, earlier: Instant) -> Duration {
+    if later > earlier {
+        later - earlier
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+/// Gets the time that has passed since `start`.
+pub fn elapsed(start: Instant) -> Duration { saturating_duration_since(Instant::now(), start) }
+
+/// Checks the measured duration of a single request.
+///
+/// No request should take anywhere near an hour, so anything longer than
+/// `MAX_REQUEST_DURATION_SECS` is a bad measurement, and `None` is returned so the caller can flag
+/// it rather than report it.
+pub fn request_duration(d: Duration) -> Option<Duration> {
+    if d > Duration::from_secs(MAX_REQUEST_DURATION_SECS) {
+        None
+    } else {
+        Some(d)
+    }
+}
+
+/// Gets the given duration in whole milliseconds, saturating at `u64::max_value()`.
+pub fn duration_as_ms(d: Duration) -> u64 {
+    d.as_secs()
+        .saturating_mul(1_000)
+        .saturating_add(u64::from(d.subsec_millis()))
+}
+
+/// Gets the given duration in whole microseconds, saturating at `u64::max_value()`.
+pub fn duration_as_us(d: Duration) -> u64 {
+    d.as_secs()
+        .saturating_mul(1_000_000)
+        .saturating_add(u64::from(d.subsec_micros()))
+}
+
+/// Gets the current wall-clock time as seconds since the Unix epoch.
+///
+/// This is only for display: a clock set to before the epoch gives zero rather than an error.
+pub fn unix_timestamp_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Gets the current wall-clock time as milliseconds since the Unix epoch.
+///
+/// Like `unix_timestamp_secs`, a clock set to before the epoch gives zero rather than an error.
+pub fn unix_timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(duration_as_ms)
+        .unwrap_or(0)
+}
+
+/// A clock whose time only moves when it's told to.
+///
+/// Delays resolve as soon as the clock is advanced past their deadline, however long that would
+/// have taken in real time.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct VirtualClock {
+    time: Arc<Mutex<VirtualTime>>,
+}
+
+#[cfg(test)]
+struct VirtualTime {
+    now: Instant,
+    waiting: Vec<(Instant, Task)>,
+}
+
+#[cfg(test)]
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock {
+            time: Arc::new(Mutex::new(VirtualTime {
+                now: Instant::now(),
+                waiting: Vec::new(),
+            })),
+        }
+    }
+
+    /// Moves the clock forward, waking up anything waiting on a delay that has now passed.
+    pub fn advance(&self, by: Duration) {
+        let mut time = self.time.lock().unwrap();
+        time.now += by;
+
+        let now = time.now;
+        let (done, waiting): (Vec<_>, Vec<_>) = time.waiting.drain(..).partition(|(deadline, _)| *deadline <= now);
+        time.waiting = waiting;
+        for (_, task) in done {
+            task.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant { self.time.lock().unwrap().now }
+
+    fn delay(&self, deadline: Instant) -> ClockDelay {
+        let clock = self.clone();
+        Box::new(poll_fn(move || {
+            let mut time = clock.time.lock().unwrap();
+            if time.now >= deadline {
+                return Ok(Async::Ready(()));
+            }
+
+            time.waiting.push((deadline, task::current()));
+            Ok(Async::NotReady)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saturating_duration_since() {
+        let earlier = Instant::now();
+        let later = earlier + Duration::from_millis(5);
+
+        assert_eq!(saturating_duration_since(later, earlier), Duration::from_millis(5));
+        assert_eq!(saturating_duration_since(earlier, later), Duration::from_secs(0));
+        assert_eq!(saturating_duration_since(earlier, earlier), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_request_duration() {
+        let max = Duration::from_secs(MAX_REQUEST_DURATION_SECS);
+
+        assert_eq!(request_duration(Duration::from_millis(3)), Some(Duration::from_millis(3)));
+        assert_eq!(request_duration(max), Some(max));
+        assert_eq!(request_duration(max + Duration::from_millis(1)), None);
+    }
+
+    #[test]
+    fn test_duration_conversions() {
+        let d = Duration::new(2, 345_678_901);
+        assert_eq!(duration_as_ms(d), 2_345);
+        assert_eq!(duration_as_us(d), 2_345_678);
+
+        let huge = Duration::from_secs(u64::max_value());
+        assert_eq!(duration_as_ms(huge), u64::max_value());
+        assert_eq!(duration_as_us(huge), u64::max_value());
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::new();
+        let started = clock.now();
+        let mut delay = clock.delay(started + Duration::from_secs(10));
+
+        // Time only passes when we say so, and delays only resolve once it has.
+        let poll = |delay: &mut ClockDelay| poll_fn(|| Ok::<_, ()>(Async::Ready(delay.poll()))).wait().unwrap();
+        assert_eq!(poll(&mut delay), Ok(Async::NotReady));
+        assert_eq!(clock.now(), started);
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(poll(&mut delay), Ok(Async::NotReady));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.now(), started + Duration::from_secs(10));
+        assert_eq!(poll(&mut delay), Ok(Async::Ready(())));
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+#[cfg(unix)]
+use libc;
+use metrics::MetricSink;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+lazy_static! {
+    static ref TRACKERS: Mutex<HashMap<String, Arc<FdTracker>>> = Mutex::new(HashMap::new());
+}
+
+/// Gets the file descriptor tracker for the given listener, creating it if it doesn't exist yet.
+///
+/// Like client registries, trackers are keyed by listener name, so that clients and backend
+/// connections still draining from a previous version of a listener count against its limit.
+pub fn get_fd_tracker(listener: &str, sink: &MetricSink) -> Arc<FdTracker> {
+    let mut trackers = TRACKERS.lock().unwrap();
+    trackers
+        .entry(listener.to_owned())
+        .or_insert_with(|| Arc::new(FdTracker::new(sink.clone())))
+        .clone()
+}
+
+/// Gets the soft limit on the number of file descriptors this process can have open.
+#[cfg(unix)]
+pub fn get_fd_limit() -> io::Result<u64> {
+    let mut limit: libc::rlimit = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(limit.rlim_cur as u64)
+}
+
+#[cfg(windows)]
+pub fn get_fd_limit() -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Other, "file descriptor limits are not supported"))
+}
+
+/// Tracks the file descriptors in use by a listener, against an optional limit.
+///
+/// Client connections only get a descriptor if the listener is under its limit, while backend
+/// connections always get one, but still count towards the limit.  The number of descriptors in
+/// use, and the limit, are reported as the `fds` and `max_fds` gauges.
+pub struct FdTracker {
+    used: AtomicUsize,
+    limit: AtomicUsize,
+    sink: MetricSink,
+}
+
+impl FdTracker {
+    pub fn new(sink: MetricSink) -> FdTracker {
+        FdTracker {
+            used: AtomicUsize::new(0),
+            limit: AtomicUsize::new(0),
+            sink,
+        }
+    }
+
+    /// Sets the most file descriptors that client connections may bring us up to.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        let limit = limit.unwrap_or(0);
+        self.limit.store(limit, Ordering::SeqCst);
+        self.sink.update_gauge("max_fds", limit as u64);
+    }
+
+    /// Gets the number of file descriptors in use.
+    pub fn used(&self) -> usize { self.used.load(Ordering::SeqCst) }
+
+    /// Takes a file descriptor for a client connection, if we're under our limit.
+    pub fn try_acquire(tracker: &Arc<FdTracker>) -> Option<FdGuard> {
+        let mut used = tracker.used.load(Ordering::SeqCst);
+        loop {
+            let limit = tracker.limit.load(Ordering::SeqCst);
+            if limit != 0 && used >= limit {
+                return None;
+            }
+
+            match tracker
+                .used
+                .compare_exchange_weak(used, used + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(actual) => used = actual,
+            }
+        }
+
+        tracker.sink.update_gauge("fds", (used + 1) as u64);
+        Some(FdGuard {
+            tracker: tracker.clone(),
+        })
+    }
+
+    /// Takes a file descriptor for a backend connection, regardless of our limit.
+    pub fn acquire(tracker: &Arc<FdTracker>) -> FdGuard {
+        let used = tracker.used.fetch_add(1, Ordering::SeqCst) + 1;
+        tracker.sink.update_gauge("fds", used as u64);
+        FdGuard {
+            tracker: tracker.clone(),
+        }
+    }
+}
+
+/// A file descriptor taken from a tracker, which is given back when dropped.
+pub struct FdGuard {
+    tracker: Arc<FdTracker>,
+}
+
+impl Drop for FdGuard {
+    fn drop(&mut self) {
+        let used = self.tracker.used.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.tracker.sink.update_gauge("fds", used as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::get_sink;
+
+    #[test]
+    fn test_limit() {
+        let tracker = Arc::new(FdTracker::new(get_sink()));
+        tracker.set_limit(Some(2));
+
+        let client1 = FdTracker::try_acquire(&tracker).unwrap();
+        let _backend = FdTracker::acquire(&tracker);
+        assert_eq!(tracker.used(), 2);
+
+        // Backend connections still count, so there's no room left for another client.
+        assert!(FdTracker::try_acquire(&tracker).is_none());
+        drop(client1);
+        assert_eq!(tracker.used(), 1);
+        let _client2 = FdTracker::try_acquire(&tracker).unwrap();
+
+        // Backend connections go over the limit if they have to.
+        let _backend2 = FdTracker::acquire(&tracker);
+        assert_eq!(tracker.used(), 3);
+
+        // Without a limit, anything goes.
+        tracker.set_limit(None);
+        let _client3 = FdTracker::try_acquire(&tracker).unwrap();
+        assert_eq!(tracker.used(), 4);
+    }
+
+    #[test]
+    fn test_get_fd_limit() { assert!(get_fd_limit().unwrap() > 0); }
+}
@@ -19,6 +19,7 @@
 // SOFTWARE.
 use futures::prelude::*;
 use protocol::errors::ProtocolError;
+use slog::Logger;
 use tokio::net::tcp::TcpStream;
 
 /// Wraps any future that does protocol operations and hands back a TCP stream.
@@ -41,3 +42,29 @@ impl Future for ProcessFuture {
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> { self.inner.poll() }
 }
+
+/// Wraps a future so that anything it logs carries the context of the given logger.
+///
+/// Tasks are polled from whatever thread the runtime picks, so the logger is set as the scoped
+/// logger for the duration of each poll rather than once when the task is created.
+pub struct LogScoped<F> {
+    logger: Logger,
+    inner: F,
+}
+
+impl<F> LogScoped<F> {
+    pub fn new(logger: Logger, inner: F) -> LogScoped<F> { LogScoped { logger, inner } }
+}
+
+impl<F> Future for LogScoped<F>
+where
+    F: Future,
+{
+    type Error = F::Error;
+    type Item = F::Item;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = &mut self.inner;
+        slog_scope::scope(&self.logger, || inner.poll())
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use futures::prelude::*;
+use log::Level;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+use util::clock::{duration_as_ms, elapsed};
+
+/// How many times an error is logged in full, per kind, in each interval.
+const DEFAULT_BURST: usize = 5;
+
+/// How long an interval is, after which a kind of error gets to be logged in full again.
+const DEFAULT_INTERVAL_MS: u64 = 10_000;
+
+/// How often limiters are checked for suppressed errors whose interval has ended.
+const SUMMARY_CHECK_INTERVAL_MS: u64 = 1_000;
+
+lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+    static ref LIMITERS: Mutex<Vec<Weak<LimiterState>>> = Mutex::new(Vec::new());
+}
+
+/// What a log site should do about a single occurrence of an error.
+#[derive(Debug, PartialEq)]
+pub struct LogDecision {
+    /// Whether or not the error itself should be logged.
+    pub log: bool,
+
+    /// How many errors were suppressed, and over how many seconds, since the last time a summary
+    /// was given, if any were.
+    pub summary: Option<(usize, u64)>,
+}
+
+/// Counts for a single kind of error at a log site.
+struct LogCounter {
+    window: AtomicUsize,
+    logged: AtomicUsize,
+    suppressed: AtomicUsize,
+    level: AtomicUsize,
+}
+
+impl LogCounter {
+    fn new(window: usize) -> LogCounter {
+        LogCounter {
+            window: AtomicUsize::new(window),
+            logged: AtomicUsize::new(0),
+            suppressed: AtomicUsize::new(0),
+            level: AtomicUsize::new(Level::Error as usize),
+        }
+    }
+
+    fn level(&self) -> Level {
+        match self.level.load(Ordering::Relaxed) {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+struct LimiterState {
+    site: &'static str,
+    burst: usize,
+    interval_ms: u64,
+    kinds: RwLock<HashMap<&'static str, Arc<LogCounter>>>,
+}
+
+/// Limits how often the same error is logged from a single place.
+///
+/// When something like a backend goes down, the same error can come up thousands of times a
+/// second, and logging every one of them does more harm than good.  Each kind of error is logged
+/// in full the first few times in an interval, and anything past that is only counted.  How many
+/// were suppressed is summarized once the interval is over: by the first error of that kind in a
+/// later interval, or by `run_log_summaries` if the errors have stopped coming.
+///
+/// Counting is done with atomics, and the kinds a site has seen before are only ever read, so this
+/// is cheap enough to sit on a hot path.
+pub struct LogLimiter {
+    state: Arc<LimiterState>,
+}
+
+impl LogLimiter {
+    /// Creates a limiter for the log site with the given name, which prefixes summary lines.
+    pub fn new(site: &'static str) -> LogLimiter { LogLimiter::with_limits(site, DEFAULT_BURST, DEFAULT_INTERVAL_MS) }
+
+    pub fn with_limits(site: &'static str, burst: usize, interval_ms: u64) -> LogLimiter {
+        let state = Arc::new(LimiterState {
+            site,
+            burst,
+            interval_ms: interval_ms.max(1),
+            kinds: RwLock::new(HashMap::new()),
+        });
+        LIMITERS.lock().unwrap().push(Arc::downgrade(&state));
+
+        LogLimiter { state }
+    }
+
+    /// Logs an error of the given kind at the given level, unless too many have been logged
+    /// recently, in which case it's only counted.
+    pub fn log(&self, level: Level, kind: &'static str, args: fmt::Arguments) {
+        let decision = self.check(kind);
+        if let Some(counter) = self.state.kinds.read().unwrap().get(kind) {
+            counter.level.store(level as usize, Ordering::Relaxed);
+        }
+
+        if let Some((suppressed, secs)) = decision.summary {
+            self.state.log_summary(level, kind, suppressed, secs);
+        }
+
+        if decision.log {
+            log!(level, "{}", args);
+        }
+    }
+
+    /// Decides what to do about an error of the given kind happening now.
+    pub fn check(&self, kind: &'static str) -> LogDecision { self.check_at(kind, duration_as_ms(elapsed(*STARTED))) }
+
+    /// Decides what to do about an error of the given kind happening at `now_ms`, which is
+    /// measured from any fixed point in time.
+    pub fn check_at(&self, kind: &'static str, now_ms: u64) -> LogDecision {
+        let counter = self.state.get_counter(kind, self.state.window(now_ms));
+        self.state.decide(&counter, now_ms)
+    }
+}
+
+impl LimiterState {
+    fn window(&self, now_ms: u64) -> usize { (now_ms / self.interval_ms) as usize }
+
+    fn decide(&self, counter: &LogCounter, now_ms: u64) -> LogDecision {
+        let summary = self.advance(counter, now_ms);
+        let log = counter.logged.fetch_add(1, Ordering::AcqRel) < self.burst;
+        if !log {
+            counter.suppressed.fetch_add(1, Ordering::AcqRel);
+        }
+
+        LogDecision { log, summary }
+    }
+
+    /// Moves the counter into the window `now_ms` falls in, if it isn't there already.
+    ///
+    /// Whoever moves the counter into a new window is the one that gives the summary of the last
+    /// one, and resets how many have been logged.
+    fn advance(&self, counter: &LogCounter, now_ms: u64) -> Option<(usize, u64)> {
+        let window = self.window(now_ms);
+        let current = counter.window.load(Ordering::Acquire);
+        if window <= current
+            || counter
+                .window
+                .compare_exchange(current, window, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+        {
+            return None;
+        }
+
+        counter.logged.store(0, Ordering::Release);
+        let suppressed = counter.suppressed.swap(0, Ordering::AcqRel);
+        if suppressed == 0 {
+            return None;
+        }
+
+        let since_ms = (current as u64).saturating_mul(self.interval_ms);
+        Some((suppressed, now_ms.saturating_sub(since_ms) / 1_000))
+    }
+
+    /// Takes the summaries of every kind of error that had some suppressed in an interval that has
+    /// ended by `now_ms`, as the kind, the level it was logged at, how many were suppressed, and
+    /// over how many seconds.
+    fn flush_at(&self, now_ms: u64) -> Vec<(&'static str, Level, usize, u64)> {
+        let kinds = self.kinds.read().unwrap();
+        kinds
+            .iter()
+            .filter(|(_, counter)| counter.suppressed.load(Ordering::Acquire) > 0)
+            .filter_map(|(kind, counter)| {
+                self.advance(counter, now_ms)
+                    .map(|(suppressed, secs)| (*kind, counter.level(), suppressed, secs))
+            })
+            .collect()
+    }
+
+    fn log_summary(&self, level: Level, kind: &str, suppressed: usize, secs: u64) {
+        log!(
+            level,
+            "[{}] suppressed {} similar errors ({}) in the last {}s",
+            self.site,
+            suppressed,
+            kind,
+            secs
+        );
+    }
+
+    fn get_counter(&self, kind: &'static str, window: usize) -> Arc<LogCounter> {
+        if let Some(counter) = self.kinds.read().unwrap().get(kind) {
+            return counter.clone();
+        }
+
+        self.kinds
+            .write()
+            .unwrap()
+            .entry(kind)
+            .or_insert_with(|| Arc::new(LogCounter::new(window)))
+            .clone()
+    }
+}
+
+/// Logs the summaries of suppressed errors whose interval has ended, for every log limiter, until
+/// `shutdown` resolves.
+///
+/// Without this, errors that stop coming right after a burst would never have their suppressed
+/// count logged, since it's otherwise only given by the next error of the same kind.
+pub fn run_log_summaries<F: Future>(shutdown: F) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), Duration::from_millis(SUMMARY_CHECK_INTERVAL_MS))
+        .map_err(|e| error!("[log limiter] timer failed: {}", e))
+        .for_each(|_| {
+            let now_ms = duration_as_ms(elapsed(*STARTED));
+            let mut limiters = LIMITERS.lock().unwrap();
+            limiters.retain(|limiter| limiter.upgrade().is_some());
+            for state in limiters.iter().filter_map(|limiter| limiter.upgrade()) {
+                for (kind, level, suppressed, secs) in state.flush_at(now_ms) {
+                    state.log_summary(level, kind, suppressed, secs);
+                }
+            }
+            Ok(())
+        })
+        .select2(shutdown)
+        .then(|_| Ok::<(), ()>(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged(log: bool) -> LogDecision { LogDecision { log, summary: None } }
+
+    #[test]
+    fn test_suppresses_past_burst() {
+        let limiter = LogLimiter::with_limits("test", 3, 10_000);
+        assert_eq!(limiter.check_at("io", 0), logged(true));
+        assert_eq!(limiter.check_at("io", 1), logged(true));
+        assert_eq!(limiter.check_at("io", 2), logged(true));
+        for i in 3..100 {
+            assert_eq!(limiter.check_at("io", i), logged(false));
+        }
+
+        // Other kinds of errors have their own allowance.
+        assert_eq!(limiter.check_at("protocol", 100), logged(true));
+    }
+
+    #[test]
+    fn test_summarizes_suppressed() {
+        let limiter = LogLimiter::with_limits("test", 2, 10_000);
+        for i in 0..10 {
+            limiter.check_at("io", i);
+        }
+
+        // The first error in the next interval is logged, along with the ones we held back.
+        assert_eq!(
+            limiter.check_at("io", 12_000),
+            LogDecision {
+                log: true,
+                summary: Some((8, 12)),
+            }
+        );
+        assert_eq!(limiter.check_at("io", 12_001), logged(true));
+        assert_eq!(limiter.check_at("io", 12_002), logged(false));
+
+        // Nothing was suppressed in an interval with no errors, so there's nothing to summarize
+        // after it, but what was suppressed before it still is.
+        assert_eq!(
+            limiter.check_at("io", 35_000),
+            LogDecision {
+                log: true,
+                summary: Some((1, 25)),
+            }
+        );
+        assert_eq!(limiter.check_at("io", 45_000), logged(true));
+    }
+
+    #[test]
+    fn test_summary_given_once() {
+        let limiter = LogLimiter::with_limits("test", 0, 1_000);
+        assert_eq!(limiter.check_at("io", 0), logged(false));
+        assert_eq!(limiter.check_at("io", 1), logged(false));
+
+        let decision = limiter.check_at("io", 1_500);
+        assert_eq!(decision.summary, Some((2, 1)));
+        assert!(!decision.log);
+
+        // A clock that goes backwards doesn't bring back an old interval.
+        assert_eq!(limiter.check_at("io", 500), logged(false));
+        assert_eq!(limiter.check_at("io", 1_600).summary, None);
+    }
+
+    #[test]
+    fn test_flush_summarizes_without_more_errors() {
+        let limiter = LogLimiter::with_limits("test", 1, 1_000);
+        for i in 0..5 {
+            limiter.check_at("io", i);
+        }
+        limiter.check_at("protocol", 10);
+
+        // Nothing is flushed until the interval is over, and then only what was suppressed.
+        assert!(limiter.state.flush_at(900).is_empty());
+        assert_eq!(limiter.state.flush_at(1_500), vec![("io", Level::Error, 4, 1)]);
+        assert!(limiter.state.flush_at(1_600).is_empty());
+
+        // Once flushed, the next error doesn't give the same summary again.
+        assert_eq!(limiter.check_at("io", 2_500), logged(true));
+    }
+}
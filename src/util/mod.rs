@@ -18,16 +18,33 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 use futures::{future::Future, stream::Stream};
+use std::time::Duration;
 
 mod batch;
 pub use self::batch::Batch;
 
 mod helpers;
-pub use self::helpers::ProcessFuture;
+pub use self::helpers::{LogScoped, ProcessFuture};
 
 mod container;
 pub use self::container::IntegerMappedVec;
 
+pub mod clock;
+pub mod runtime;
+pub mod watchdog;
+
+mod fds;
+pub use self::fds::{get_fd_limit, get_fd_tracker, FdGuard, FdTracker};
+
+mod limited;
+pub use self::limited::{run_log_summaries, LogLimiter};
+
+mod ownership;
+pub use self::ownership::{claim_address, get_fingerprint, AddressClaim};
+
+mod tls;
+pub use self::tls::{ClientStream, TlsTerminator};
+
 impl<T: ?Sized> StreamExt for T where T: Stream {}
 
 /// An extension trait for `Stream`s that provides necessary combinators specific to synchrotron.
@@ -52,6 +69,19 @@ pub trait StreamExt: Stream {
     {
         batch::Batch::new(self, capacity)
     }
+
+    /// Converts this stream into a batched stream, like `batch`, that holds on to a batch which
+    /// isn't yet full for up to `linger` after the underlying stream stops being ready.
+    ///
+    /// Anything that arrives while lingering joins the batch, so a trickle of items can be
+    /// gathered into fewer batches, at the cost of the first of them waiting a little longer.
+    fn batch_with_linger(self, capacity: usize, linger: Duration) -> batch::Batch<Self>
+    where
+        Self: Sized,
+        Self::Item: Sizable,
+    {
+        batch::Batch::with_linger(self, capacity, Some(linger))
+    }
 }
 
 pub fn typeless<F>(f: F) -> impl Future<Item = (), Error = ()>
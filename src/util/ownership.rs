@@ -0,0 +1,371 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use pruefung::fnv::fnv64::Fnv64a;
+use serde::Serialize;
+use serde_json;
+use std::{
+    collections::HashMap,
+    hash::Hasher,
+    net::SocketAddr,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT},
+        Mutex,
+    },
+};
+
+lazy_static! {
+    static ref CLAIMS: Mutex<HashMap<SocketAddr, AddressClaims>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_CLAIM_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// What a process says about the listener it has bound to an address, when asked.
+#[derive(Clone, Debug, PartialEq)]
+struct ClaimInfo {
+    pid: u32,
+    fingerprint: u64,
+    shared: bool,
+}
+
+impl ClaimInfo {
+    fn encode(&self) -> String { format!("{} {:016x} {}\n", self.pid, self.fingerprint, self.shared) }
+
+    fn decode(line: &str) -> Option<ClaimInfo> {
+        let mut parts = line.trim_end().split(' ');
+        let pid = parts.next()?.parse().ok()?;
+        let fingerprint = u64::from_str_radix(parts.next()?, 16).ok()?;
+        let shared = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(ClaimInfo {
+            pid,
+            fingerprint,
+            shared,
+        })
+    }
+
+    /// Whether or not splitting connections with the given listener, in another process, is a mistake.
+    fn conflicts_with(&self, other: &ClaimInfo) -> bool {
+        other.pid != self.pid && other.fingerprint != self.fingerprint && !(self.shared && other.shared)
+    }
+}
+
+struct LocalClaim {
+    id: usize,
+    name: String,
+    info: ClaimInfo,
+}
+
+#[derive(Default)]
+struct AddressClaims {
+    claims: Vec<LocalClaim>,
+    owner: Option<imp::Owner>,
+}
+
+/// Gets a fingerprint of the given configuration.
+///
+/// The fingerprint only depends on the configuration itself, so any two processes running the
+/// same configuration get the same fingerprint, no matter what order it was loaded in.
+pub fn get_fingerprint<T: Serialize>(config: &T) -> u64 {
+    // Maps are sorted by key once they're a `Value`, which takes care of our `HashMap`s.
+    let canonical = serde_json::to_value(config)
+        .map(|value| value.to_string())
+        .unwrap_or_default();
+
+    let mut hasher = Fnv64a::default();
+    hasher.write(canonical.as_bytes());
+    hasher.finish()
+}
+
+/// A listener's claim on the address it's bound to, given up when dropped.
+pub struct AddressClaim {
+    addr: SocketAddr,
+    id: usize,
+}
+
+impl Drop for AddressClaim {
+    fn drop(&mut self) {
+        let mut claims = CLAIMS.lock().unwrap();
+        let is_empty = match claims.get_mut(&self.addr) {
+            Some(state) => {
+                state.claims.retain(|claim| claim.id != self.id);
+                state.claims.is_empty()
+            },
+            None => false,
+        };
+
+        if is_empty {
+            if let Some(owner) = claims.remove(&self.addr).and_then(|state| state.owner) {
+                owner.stop();
+            }
+        }
+    }
+}
+
+/// Claims the given address on behalf of the named listener.
+///
+/// Listeners bound to the same address with `SO_REUSEPORT` split its connections between them,
+/// which is only ever what's wanted when they're running the same configuration, such as during
+/// an upgrade, or when they've all said that sharing the address is on purpose.  Anything else
+/// bound to the address is logged loudly.
+///
+/// Other listeners in this process are checked directly.  Other processes are asked over a unix
+/// socket named after the address, which the first listener in each process to claim the address
+/// answers on.  This is all best-effort: not being able to ask never stops a listener.
+pub fn claim_address(addr: SocketAddr, name: &str, fingerprint: u64, shared: bool) -> AddressClaim {
+    let info = ClaimInfo {
+        pid: process::id(),
+        fingerprint,
+        shared,
+    };
+
+    let mut claims = CLAIMS.lock().unwrap();
+    let state = claims.entry(addr).or_insert_with(AddressClaims::default);
+
+    // Newer versions of the same listener replace older ones, so they're expected to overlap.
+    for other in &state.claims {
+        if other.name != name && !(shared && other.info.shared) {
+            warn!(
+                "[listener] listener '{}' is bound to {}, which listener '{}' is also bound to: connections will be \
+                 split between them",
+                name, addr, other.name
+            );
+        }
+    }
+
+    if state.owner.is_none() {
+        match imp::Owner::bind(addr) {
+            Ok(owner) => state.owner = Some(owner),
+            Err(Some(other)) => {
+                if info.conflicts_with(&other) {
+                    warn!(
+                        "[listener] listener '{}' is bound to {}, which process {} is also listening on with a \
+                         different configuration: connections will be split between them, and some will be served \
+                         with the wrong configuration",
+                        name, addr, other.pid
+                    );
+                } else if other.pid != info.pid {
+                    info!(
+                        "[listener] listener '{}' is sharing {} with process {}, which is running the same \
+                         configuration",
+                        name, addr, other.pid
+                    );
+                }
+            },
+            Err(None) => debug!("[listener] unable to tell if anything else is listening on {}", addr),
+        }
+    }
+
+    let id = NEXT_CLAIM_ID.fetch_add(1, Ordering::Relaxed);
+    state.claims.push(LocalClaim {
+        id,
+        name: name.to_owned(),
+        info,
+    });
+
+    AddressClaim { addr, id }
+}
+
+/// Gets the most recent claim on the given address in this process, which is what we tell others about.
+#[cfg(unix)]
+fn get_current_claim(addr: &SocketAddr) -> Option<ClaimInfo> {
+    let claims = CLAIMS.lock().unwrap();
+    claims
+        .get(addr)
+        .and_then(|state| state.claims.last())
+        .map(|claim| claim.info.clone())
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::{get_current_claim, ClaimInfo};
+    use std::{
+        env, fs,
+        io::{self, BufRead, BufReader, Write},
+        net::SocketAddr,
+        os::unix::net::{UnixListener, UnixStream},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    const PROBE_TIMEOUT_MS: u64 = 500;
+
+    /// The socket that this process answers questions about an address on.
+    pub struct Owner {
+        path: PathBuf,
+        stopped: Arc<AtomicBool>,
+    }
+
+    impl Owner {
+        /// Starts answering questions about the given address.
+        ///
+        /// If another process already is, what it says about itself is returned instead, if it
+        /// says anything at all.
+        pub fn bind(addr: SocketAddr) -> Result<Owner, Option<ClaimInfo>> {
+            let path = get_claim_path(&addr);
+
+            // A socket left behind by a process that's gone refuses connections, so we can safely
+            // take it over.  Anything else, we leave alone.
+            let listener = match UnixListener::bind(&path) {
+                Ok(listener) => listener,
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {
+                    match probe(&path) {
+                        Ok(info) => return Err(Some(info)),
+                        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                            let _ = fs::remove_file(&path);
+                            UnixListener::bind(&path).map_err(|_| None)?
+                        },
+                        Err(_) => return Err(None),
+                    }
+                },
+                Err(_) => return Err(None),
+            };
+
+            let stopped = Arc::new(AtomicBool::new(false));
+            let stopped2 = stopped.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped2.load(Ordering::Acquire) {
+                        break;
+                    }
+
+                    if let (Ok(mut stream), Some(info)) = (stream, get_current_claim(&addr)) {
+                        let _ = stream.write_all(info.encode().as_bytes());
+                    }
+                }
+            });
+
+            Ok(Owner { path, stopped })
+        }
+
+        /// Stops answering questions about the address.
+        pub fn stop(self) {
+            // Wake up the thread answering questions so it can see it's been stopped.  The socket
+            // is removed here, rather than by the thread, so that it's already gone by the time
+            // anything in this process might want to claim the address again.
+            self.stopped.store(true, Ordering::Release);
+            let _ = UnixStream::connect(&self.path);
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn get_claim_path(addr: &SocketAddr) -> PathBuf { env::temp_dir().join(format!("synchrotron-{}.sock", addr)) }
+
+    fn probe(path: &Path) -> io::Result<ClaimInfo> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(Duration::from_millis(PROBE_TIMEOUT_MS)))?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        ClaimInfo::decode(&line).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid claim"))
+    }
+
+    #[cfg(test)]
+    pub fn probe_address(addr: &SocketAddr) -> io::Result<ClaimInfo> { probe(&get_claim_path(addr)) }
+}
+
+#[cfg(windows)]
+mod imp {
+    use super::ClaimInfo;
+    use std::net::SocketAddr;
+
+    /// Other processes can't be asked about an address on this platform.
+    pub struct Owner;
+
+    impl Owner {
+        pub fn bind(_addr: SocketAddr) -> Result<Owner, Option<ClaimInfo>> { Err(None) }
+
+        pub fn stop(self) {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(pid: u32, fingerprint: u64, shared: bool) -> ClaimInfo {
+        ClaimInfo {
+            pid,
+            fingerprint,
+            shared,
+        }
+    }
+
+    #[test]
+    fn test_claim_info_encoding() {
+        let claim = info(1234, 0xdead_beef, true);
+        assert_eq!(ClaimInfo::decode(&claim.encode()), Some(claim));
+        assert_eq!(ClaimInfo::decode(""), None);
+        assert_eq!(ClaimInfo::decode("1234 zzz false\n"), None);
+        assert_eq!(ClaimInfo::decode("1234 00000000deadbeef false extra\n"), None);
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let ours = info(1, 42, false);
+        assert!(ours.conflicts_with(&info(2, 43, false)));
+        assert!(ours.conflicts_with(&info(2, 43, true)));
+        assert!(!ours.conflicts_with(&info(2, 42, false)));
+        assert!(!ours.conflicts_with(&info(1, 43, false)));
+
+        let ours = info(1, 42, true);
+        assert!(!ours.conflicts_with(&info(2, 43, true)));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut a = HashMap::new();
+        let mut b = HashMap::new();
+        for i in 0..32 {
+            a.insert(format!("key{}", i), i);
+            b.insert(format!("key{}", 31 - i), 31 - i);
+        }
+        assert_eq!(get_fingerprint(&a), get_fingerprint(&b));
+
+        b.insert("key0".to_owned(), 100);
+        assert_ne!(get_fingerprint(&a), get_fingerprint(&b));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_claim_answers_probes() {
+        let addr = "127.0.0.1:61001".parse().unwrap();
+        let first = claim_address(addr, "first", 42, false);
+        assert_eq!(imp::probe_address(&addr).unwrap(), info(process::id(), 42, false));
+
+        // The newest claim is the one that's answered with, until every claim is given up.
+        let second = claim_address(addr, "first", 43, false);
+        assert_eq!(imp::probe_address(&addr).unwrap(), info(process::id(), 43, false));
+
+        drop(second);
+        assert_eq!(imp::probe_address(&addr).unwrap(), info(process::id(), 42, false));
+
+        drop(first);
+        assert!(imp::probe_address(&addr).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::RuntimeConfiguration;
+use futures::{prelude::*, sync::oneshot};
+use num_cpus;
+use std::{
+    fmt, io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+use tokio_io_pool::{Builder, Runtime};
+
+/// What worker threads are named, followed by their index, unless configured otherwise.
+const DEFAULT_THREAD_NAME_PREFIX: &str = "synchrotron-";
+
+/// How many threads blocking work is spread over, unless configured otherwise.
+const DEFAULT_MAX_BLOCKING_THREADS: usize = 16;
+
+static MAX_BLOCKING_THREADS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_BLOCKING_THREADS);
+
+lazy_static! {
+    static ref BLOCKING: BlockingPool = BlockingPool::new(MAX_BLOCKING_THREADS.load(Ordering::SeqCst));
+}
+
+type BlockingJob = Box<FnMut() + Send>;
+
+/// A fixed set of threads that blocking work is handed off to, so that it never ties up the
+/// worker threads driving I/O.
+///
+/// The threads are started the first time anything is run on them.
+struct BlockingPool {
+    jobs: Mutex<mpsc::Sender<BlockingJob>>,
+}
+
+impl BlockingPool {
+    fn new(threads: usize) -> BlockingPool {
+        let (tx, rx) = mpsc::channel::<BlockingJob>();
+        let rx = Arc::new(Mutex::new(rx));
+        for idx in 0..threads {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("blocking-{}", idx))
+                .spawn(move || {
+                    loop {
+                        let job = rx.lock().unwrap().recv();
+                        match job {
+                            Ok(mut job) => job(),
+                            Err(_) => break,
+                        }
+                    }
+                })
+                .expect("failed to start blocking thread");
+        }
+
+        BlockingPool { jobs: Mutex::new(tx) }
+    }
+}
+
+/// Runs the given blocking work on the blocking pool, resolving to its result once it's done.
+///
+/// At most `max_blocking_threads` pieces of work run at once, and anything past that waits its
+/// turn.  Nothing run this way should itself wait on the blocking pool.
+pub fn run_blocking<F, T>(f: F) -> impl Future<Item = T, Error = io::Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let mut work = Some((f, tx));
+    let job: BlockingJob = Box::new(move || {
+        if let Some((f, tx)) = work.take() {
+            let _ = tx.send(f());
+        }
+    });
+    let _ = BLOCKING.jobs.lock().unwrap().send(job);
+
+    rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "blocking work panicked"))
+}
+
+/// The settings the runtime is actually built with, once defaults are filled in.
+#[derive(Debug, PartialEq)]
+pub struct RuntimeSettings {
+    pub worker_threads: usize,
+    pub thread_name_prefix: String,
+    pub max_blocking_threads: usize,
+}
+
+impl RuntimeSettings {
+    pub fn from_config(config: Option<&RuntimeConfiguration>) -> RuntimeSettings {
+        let default = RuntimeConfiguration::default();
+        let config = config.unwrap_or(&default);
+
+        RuntimeSettings {
+            worker_threads: config.worker_threads.unwrap_or_else(num_cpus::get),
+            thread_name_prefix: config
+                .thread_name_prefix
+                .clone()
+                .unwrap_or_else(|| DEFAULT_THREAD_NAME_PREFIX.to_owned()),
+            max_blocking_threads: config.max_blocking_threads.unwrap_or(DEFAULT_MAX_BLOCKING_THREADS),
+        }
+    }
+
+    /// Builds a runtime with these settings.
+    ///
+    /// Every worker thread drives its own I/O and timers.  Anything that has to block, like
+    /// resolving hostnames, is handed off to the blocking pool instead, which is sized here, and
+    /// so has to be built before anything is run on it.
+    pub fn build(&self) -> io::Result<Runtime> {
+        MAX_BLOCKING_THREADS.store(self.max_blocking_threads, Ordering::SeqCst);
+        Builder::default()
+            .pool_size(self.worker_threads)
+            .name_prefix(self.thread_name_prefix.clone())
+            .build()
+    }
+}
+
+impl fmt::Display for RuntimeSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} worker threads named '{}N', and up to {} blocking threads",
+            self.worker_threads, self.thread_name_prefix, self.max_blocking_threads
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::lazy;
+    use std::{sync::mpsc, thread, time::Duration};
+
+    #[test]
+    fn test_defaults() {
+        let settings = RuntimeSettings::from_config(None);
+        assert_eq!(settings.worker_threads, num_cpus::get());
+        assert_eq!(settings.thread_name_prefix, DEFAULT_THREAD_NAME_PREFIX);
+        assert_eq!(settings.max_blocking_threads, DEFAULT_MAX_BLOCKING_THREADS);
+    }
+
+    #[test]
+    fn test_thread_names() {
+        let config = RuntimeConfiguration {
+            worker_threads: Some(2),
+            thread_name_prefix: Some("sync-test-".to_owned()),
+            max_blocking_threads: None,
+        };
+        let settings = RuntimeSettings::from_config(Some(&config));
+        assert_eq!(settings.worker_threads, 2);
+
+        let runtime = settings.build().unwrap();
+        let (tx, rx) = mpsc::channel();
+        runtime
+            .spawn(lazy(move || {
+                let _ = tx.send(thread::current().name().map(|name| name.to_owned()));
+                Ok(())
+            }))
+            .unwrap();
+
+        let name = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert!(name.starts_with("sync-test-"), "unexpected thread name '{}'", name);
+        runtime.shutdown_on_idle();
+    }
+
+    #[test]
+    fn test_run_blocking() {
+        let results = (0..4)
+            .map(|i| run_blocking(move || thread::current().name().map(|name| (i, name.to_owned()))))
+            .collect::<Vec<_>>();
+
+        for (i, result) in results.into_iter().enumerate() {
+            let (j, name) = result.wait().unwrap().unwrap();
+            assert_eq!(i, j);
+            assert!(name.starts_with("blocking-"), "unexpected thread name '{}'", name);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use conf::TlsConfiguration;
+use errors::CreationError;
+use futures::prelude::*;
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::SocketAddr,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    timer::Timeout,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+/// How long clients have to finish their TLS handshake, by default.
+const DEFAULT_TLS_HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+/// A client connection, which may or may not be using TLS.
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl ClientStream {
+    fn get_tcp(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(stream) => stream,
+            ClientStream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> { self.get_tcp().local_addr() }
+
+    /// Gets the socket underneath, if what can be read from it is what the client sent.
+    ///
+    /// For TLS streams, the socket only has TLS records on it, so there's nothing to be learned
+    /// about the client's data from peeking at it.
+    pub fn plaintext_fd(&self) -> Option<RawFd> {
+        match self {
+            ClientStream::Plain(stream) => Some(stream.as_raw_fd()),
+            ClientStream::Tls(_) => None,
+        }
+    }
+}
+
+impl AsRawFd for ClientStream {
+    fn as_raw_fd(&self) -> RawFd { self.get_tcp().as_raw_fd() }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.read(buf),
+            ClientStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.write(buf),
+            ClientStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(stream) => stream.flush(),
+            ClientStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {}
+
+impl AsyncWrite for ClientStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            ClientStream::Plain(stream) => AsyncWrite::shutdown(stream),
+            ClientStream::Tls(stream) => stream.shutdown(),
+        }
+    }
+}
+
+/// Terminates TLS for the clients of a listener.
+pub struct TlsTerminator {
+    acceptor: TlsAcceptor,
+    handshake_timeout: Duration,
+}
+
+impl TlsTerminator {
+    pub fn from_config(config: &TlsConfiguration) -> Result<TlsTerminator, CreationError> {
+        let verifier = match config.client_ca_path {
+            Some(ref path) => AllowAnyAuthenticatedClient::new(load_roots(path)?),
+            None => NoClientAuth::new(),
+        };
+
+        let mut server_config = ServerConfig::new(verifier);
+        server_config
+            .set_single_cert(load_certs(&config.cert_path)?, load_private_key(&config.key_path)?)
+            .map_err(|e| CreationError::InvalidResource(format!("TLS certificate doesn't match its key: {}", e)))?;
+
+        let handshake_timeout_ms = config.handshake_timeout_ms.unwrap_or(DEFAULT_TLS_HANDSHAKE_TIMEOUT_MS);
+        if handshake_timeout_ms == 0 {
+            return Err(CreationError::InvalidParameter("tls.handshake_timeout_ms".to_string()));
+        }
+
+        Ok(TlsTerminator {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            handshake_timeout: Duration::from_millis(handshake_timeout_ms),
+        })
+    }
+
+    /// Performs the TLS handshake with the given client.
+    ///
+    /// Clients that stall partway through are given up on once the handshake timeout passes.
+    pub fn accept(&self, client: TcpStream) -> impl Future<Item = ClientStream, Error = io::Error> {
+        Timeout::new(self.acceptor.accept(client), self.handshake_timeout)
+            .map(ClientStream::Tls)
+            .map_err(|e| {
+                if e.is_elapsed() {
+                    io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")
+                } else {
+                    e.into_inner()
+                        .unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "timer failed during handshake"))
+                }
+            })
+    }
+}
+
+fn open_pem(path: &str) -> Result<BufReader<File>, CreationError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| CreationError::InvalidResource(format!("failed to open '{}': {}", path, e)))
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, CreationError> {
+    match certs(&mut open_pem(path)?) {
+        Ok(ref certs) if certs.is_empty() => {
+            Err(CreationError::InvalidResource(format!("no certificates found in '{}'", path)))
+        },
+        Ok(certs) => Ok(certs),
+        Err(_) => Err(CreationError::InvalidResource(format!("invalid certificates in '{}'", path))),
+    }
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, CreationError> {
+    // Keys can be in either PKCS #8 or the older RSA-specific format, so try both.
+    let mut keys = pkcs8_private_keys(&mut open_pem(path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open_pem(path)?).unwrap_or_default();
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| CreationError::InvalidResource(format!("no private key found in '{}'", path)))
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore, CreationError> {
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut open_pem(path)?) {
+        Ok((valid, _)) if valid > 0 => Ok(roots),
+        _ => Err(CreationError::InvalidResource(format!("no valid client CA certificates in '{}'", path))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    const CERT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/synchrotron-test/fixtures/tls/server.crt");
+    const KEY_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/synchrotron-test/fixtures/tls/server.key");
+
+    fn get_config(cert_path: &str, key_path: &str) -> TlsConfiguration {
+        TlsConfiguration {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_from_config() {
+        assert!(TlsTerminator::from_config(&get_config(CERT_PATH, KEY_PATH)).is_ok());
+
+        // Any certificate will do as an authority for client certificates.
+        let mut config = get_config(CERT_PATH, KEY_PATH);
+        config.client_ca_path = Some(CERT_PATH.to_string());
+        assert!(TlsTerminator::from_config(&config).is_ok());
+    }
+
+    #[test]
+    fn test_from_config_invalid() {
+        let path = env::temp_dir().join(format!("synchrotron-tls-{}.pem", process::id()));
+        fs::write(&path, "not a certificate").unwrap();
+        let garbage = path.to_str().unwrap();
+
+        assert!(TlsTerminator::from_config(&get_config("/nonexistent/server.crt", KEY_PATH)).is_err());
+        assert!(TlsTerminator::from_config(&get_config(CERT_PATH, "/nonexistent/server.key")).is_err());
+        assert!(TlsTerminator::from_config(&get_config(garbage, KEY_PATH)).is_err());
+        assert!(TlsTerminator::from_config(&get_config(CERT_PATH, garbage)).is_err());
+
+        // A key that's a certificate isn't a key, and the wrong way around is no better.
+        assert!(TlsTerminator::from_config(&get_config(CERT_PATH, CERT_PATH)).is_err());
+        assert!(TlsTerminator::from_config(&get_config(KEY_PATH, CERT_PATH)).is_err());
+
+        let mut config = get_config(CERT_PATH, KEY_PATH);
+        config.client_ca_path = Some(garbage.to_string());
+        assert!(TlsTerminator::from_config(&config).is_err());
+
+        let mut config = get_config(CERT_PATH, KEY_PATH);
+        config.handshake_timeout_ms = Some(0);
+        assert!(TlsTerminator::from_config(&config).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use futures::{
+    future::{ExecuteError, Executor},
+    prelude::*,
+};
+use metrics::MetricSink;
+use slab::Slab;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+use util::clock::{duration_as_ms, elapsed};
+
+/// How long a task can go without a heartbeat, unless it says otherwise, before it's stalled.
+pub const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 10_000;
+
+// How often the watchdog looks for stalled tasks.
+const WATCHDOG_CHECK_INTERVAL_MS: u64 = 1000;
+
+lazy_static! {
+    static ref TASKS: TaskRegistry = TaskRegistry::new();
+}
+
+type Tasks = Arc<Mutex<Slab<Arc<TaskState>>>>;
+
+/// Gets the registry that long-lived tasks register themselves with.
+pub fn get_task_registry() -> &'static TaskRegistry { &TASKS }
+
+/// Registers a long-lived task with the watchdog.
+///
+/// The task is stalled if it goes longer than `interval` without calling `beat` on the returned
+/// heartbeat, and stays registered until the heartbeat is dropped.
+pub fn register<S: Into<String>>(name: S, interval: Duration) -> Heartbeat { TASKS.register(name, interval) }
+
+/// Wraps a future so that it's registered with the watchdog for as long as it runs.
+///
+/// The future beats every time it's polled, and is woken up often enough that it will beat well
+/// within `interval` even when it has nothing else to do.  If it fails, it's left registered, so
+/// that the watchdog reports it as stalled until something takes its place.
+pub fn watch<S: Into<String>, F: Future>(name: S, interval: Duration, inner: F) -> Watched<F> {
+    let wakeup = (interval / 4).max(Duration::from_millis(1));
+    Watched {
+        inner,
+        heartbeat: Some(register(name, interval)),
+        wakeups: Interval::new(Instant::now() + wakeup, wakeup),
+    }
+}
+
+/// Runs the watchdog until `shutdown` resolves.
+///
+/// Stalled tasks are logged when they're first noticed, and counted in the `stalls` counter, while
+/// the `stalled_tasks` gauge tracks how many tasks are stalled right now.
+pub fn run_watchdog<F: Future>(sink: MetricSink, shutdown: F) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), Duration::from_millis(WATCHDOG_CHECK_INTERVAL_MS))
+        .map_err(|e| error!("[watchdog] timer failed: {}", e))
+        .for_each(move |_| {
+            let result = TASKS.check();
+            for (name, silent_ms) in &result.newly_stalled {
+                error!("[watchdog] task '{}' has stalled: no heartbeat for {}ms", name, silent_ms);
+                sink.increment("stalls");
+            }
+            for name in &result.recovered {
+                info!("[watchdog] task '{}' is making progress again", name);
+            }
+
+            sink.update_gauge("stalled_tasks", result.stalled as u64);
+            Ok(())
+        })
+        .select2(shutdown)
+        .then(|_| Ok::<(), ()>(()))
+}
+
+/// What the watchdog found during a check.
+pub struct CheckResult {
+    /// How many tasks are stalled.
+    pub stalled: usize,
+
+    /// Tasks that were not stalled as of the last check, and how long they've been silent for.
+    pub newly_stalled: Vec<(String, u64)>,
+
+    /// Tasks that were stalled as of the last check, but have since beat.
+    pub recovered: Vec<String>,
+}
+
+struct TaskState {
+    name: String,
+    interval_ms: u64,
+    last_beat_ms: AtomicUsize,
+    stalled: AtomicBool,
+    failed: AtomicBool,
+}
+
+impl TaskState {
+    fn silent_ms(&self, now_ms: u64) -> u64 { now_ms.saturating_sub(self.last_beat_ms.load(Ordering::Relaxed) as u64) }
+
+    fn is_stalled(&self, now_ms: u64) -> bool { self.silent_ms(now_ms) > self.interval_ms }
+}
+
+/// Long-lived tasks, and when we last heard from each of them.
+pub struct TaskRegistry {
+    epoch: Instant,
+    tasks: Tasks,
+}
+
+impl TaskRegistry {
+    fn new() -> TaskRegistry {
+        TaskRegistry {
+            epoch: Instant::now(),
+            tasks: Arc::new(Mutex::new(Slab::new())),
+        }
+    }
+
+    pub fn register<S: Into<String>>(&self, name: S, interval: Duration) -> Heartbeat {
+        let name = name.into();
+        let state = Arc::new(TaskState {
+            name,
+            interval_ms: duration_as_ms(interval),
+            last_beat_ms: AtomicUsize::new(self.now_ms() as usize),
+            stalled: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+        });
+
+        let mut tasks = self.tasks.lock().unwrap();
+
+        // A failed task is reported until a new task with the same name replaces it, such as when
+        // a listener is reloaded.
+        let replaced = tasks
+            .iter()
+            .filter(|(_, task)| task.failed.load(Ordering::Relaxed) && task.name == state.name)
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        for key in replaced {
+            tasks.remove(key);
+        }
+
+        let key = tasks.insert(state.clone());
+        Heartbeat {
+            key,
+            state,
+            tasks: self.tasks.clone(),
+            epoch: self.epoch,
+            failed: false,
+        }
+    }
+
+    /// Gets the names of the tasks that are stalled right now.
+    pub fn stalled(&self) -> Vec<String> {
+        let now_ms = self.now_ms();
+        let tasks = self.tasks.lock().unwrap();
+        tasks
+            .iter()
+            .filter(|(_, task)| task.is_stalled(now_ms))
+            .map(|(_, task)| task.name.clone())
+            .collect()
+    }
+
+    /// Checks every task for a recent heartbeat, noting which ones have stalled or recovered
+    /// since the last check.
+    pub fn check(&self) -> CheckResult { self.check_at(self.now_ms()) }
+
+    fn check_at(&self, now_ms: u64) -> CheckResult {
+        let mut result = CheckResult {
+            stalled: 0,
+            newly_stalled: Vec::new(),
+            recovered: Vec::new(),
+        };
+
+        let tasks = self.tasks.lock().unwrap();
+        for (_, task) in tasks.iter() {
+            if task.is_stalled(now_ms) {
+                result.stalled += 1;
+                if !task.stalled.swap(true, Ordering::Relaxed) {
+                    result.newly_stalled.push((task.name.clone(), task.silent_ms(now_ms)));
+                }
+            } else if task.stalled.swap(false, Ordering::Relaxed) {
+                result.recovered.push(task.name.clone());
+            }
+        }
+
+        result
+    }
+
+    fn now_ms(&self) -> u64 { duration_as_ms(elapsed(self.epoch)) }
+}
+
+/// A registered task's connection to the watchdog.
+///
+/// Dropping the heartbeat unregisters the task, unless it has been marked as failed.
+pub struct Heartbeat {
+    key: usize,
+    state: Arc<TaskState>,
+    tasks: Tasks,
+    epoch: Instant,
+    failed: bool,
+}
+
+impl Heartbeat {
+    /// Lets the watchdog know that the task is still making progress.
+    pub fn beat(&self) {
+        let now_ms = duration_as_ms(elapsed(self.epoch));
+        self.state.last_beat_ms.store(now_ms as usize, Ordering::Relaxed);
+    }
+
+    /// Marks the task as having died, so that it stays registered, and stalls, once dropped.
+    pub fn fail(mut self) {
+        self.failed = true;
+        self.state.failed.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        if !self.failed {
+            self.tasks.lock().unwrap().remove(self.key);
+        }
+    }
+}
+
+/// A future that beats a heartbeat every time it's polled.
+pub struct Watched<F> {
+    inner: F,
+    heartbeat: Option<Heartbeat>,
+    wakeups: Interval,
+}
+
+impl<F> Watched<F> {
+    pub fn into_inner(self) -> F { self.inner }
+}
+
+impl<F: Future> Future for Watched<F> {
+    type Error = F::Error;
+    type Item = F::Item;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Drain our wakeups, which makes sure we'll be polled again soon even if the inner future
+        // has nothing to do for a while.
+        while let Ok(Async::Ready(Some(_))) = self.wakeups.poll() {}
+
+        if let Some(heartbeat) = self.heartbeat.as_ref() {
+            heartbeat.beat();
+        }
+
+        self.inner.poll().map_err(|e| {
+            if let Some(heartbeat) = self.heartbeat.take() {
+                error!("[watchdog] task '{}' failed", heartbeat.state.name);
+                heartbeat.fail();
+            }
+            e
+        })
+    }
+}
+
+/// An executor that registers everything it runs with the watchdog.
+pub struct WatchedExecutor<E> {
+    name: String,
+    interval: Duration,
+    inner: E,
+}
+
+impl<E> WatchedExecutor<E> {
+    pub fn new<S: Into<String>>(name: S, interval: Duration, inner: E) -> WatchedExecutor<E> {
+        WatchedExecutor {
+            name: name.into(),
+            interval,
+            inner,
+        }
+    }
+}
+
+impl<E, F> Executor<F> for WatchedExecutor<E>
+where
+    F: Future<Item = (), Error = ()>,
+    E: Executor<Watched<F>>,
+{
+    fn execute(&self, future: F) -> Result<(), ExecuteError<F>> {
+        self.inner
+            .execute(watch(self.name.clone(), self.interval, future))
+            .map_err(|e| ExecuteError::new(e.kind(), e.into_future().into_inner()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stall_and_recover() {
+        let registry = TaskRegistry::new();
+        let heartbeat = registry.register("accept", Duration::from_millis(100));
+        let start_ms = heartbeat.state.last_beat_ms.load(Ordering::Relaxed) as u64;
+
+        // Right up to its interval, a task is fine.
+        let result = registry.check_at(start_ms + 100);
+        assert_eq!(result.stalled, 0);
+
+        // Past that, it's stalled, but only reported as newly stalled once.
+        let result = registry.check_at(start_ms + 101);
+        assert_eq!(result.stalled, 1);
+        assert_eq!(result.newly_stalled, vec![("accept".to_owned(), 101)]);
+
+        let result = registry.check_at(start_ms + 200);
+        assert_eq!(result.stalled, 1);
+        assert!(result.newly_stalled.is_empty());
+
+        // Once it beats again, it has recovered.
+        heartbeat.state.last_beat_ms.store((start_ms + 200) as usize, Ordering::Relaxed);
+        let result = registry.check_at(start_ms + 250);
+        assert_eq!(result.stalled, 0);
+        assert_eq!(result.recovered, vec!["accept".to_owned()]);
+    }
+
+    #[test]
+    fn test_failed_tasks_stay_until_replaced() {
+        let registry = TaskRegistry::new();
+
+        // Tasks that finish normally are simply forgotten.
+        let heartbeat = registry.register("pool", Duration::from_millis(0));
+        drop(heartbeat);
+        assert_eq!(registry.tasks.lock().unwrap().len(), 0);
+
+        // Tasks that die stick around, and stall, until something takes their place.
+        let heartbeat = registry.register("pool", Duration::from_millis(0));
+        heartbeat.fail();
+        assert_eq!(registry.tasks.lock().unwrap().len(), 1);
+
+        let result = registry.check_at(registry.now_ms() + 1);
+        assert_eq!(result.stalled, 1);
+
+        let _heartbeat = registry.register("pool", Duration::from_millis(100));
+        let result = registry.check_at(registry.now_ms() + 1);
+        assert_eq!(result.stalled, 0);
+        assert_eq!(registry.tasks.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,31 @@
+#!/usr/bin/env bash
+#
+# Captures the raw CLUSTER SLOTS and CLUSTER SHARDS replies of a single-node Redis cluster on
+# 127.0.0.1:7000, which owns every slot, into slots.resp and shards.resp.  The node ID is replaced
+# with NODE_ID, since the tests swap in the one we derive from the same address.
+#
+# Needs redis-server and redis-cli 7.0 or later on the PATH, as CLUSTER SHARDS is new in 7.0.
+set -euo pipefail
+
+cd "$(dirname "$0")"
+workdir=$(mktemp -d)
+trap 'redis-cli -p 7000 shutdown nosave >/dev/null 2>&1 || true; rm -rf "$workdir"' EXIT
+
+redis-server --port 7000 --cluster-enabled yes --cluster-config-file "$workdir/nodes.conf" \
+    --dir "$workdir" --save "" --appendonly no --daemonize yes >/dev/null
+until redis-cli -p 7000 ping >/dev/null 2>&1; do sleep 0.1; done
+
+redis-cli -p 7000 cluster addslotsrange 0 16383 >/dev/null
+until redis-cli -p 7000 cluster info | grep -q 'cluster_state:ok'; do sleep 0.1; done
+node_id=$(redis-cli -p 7000 cluster myid)
+
+capture() {
+    exec 3<>/dev/tcp/127.0.0.1/7000
+    printf 'CLUSTER %s\r\n' "$1" >&3
+    timeout 1 cat <&3 | sed "s/$node_id/NODE_ID/g" > "$2" || true
+    exec 3<&-
+}
+
+capture SLOTS slots.resp
+capture SHARDS shards.resp
+echo "captured from $(redis-server --version)"
@@ -1,77 +1,265 @@
 use std::str;
 use std::env;
 use std::fs::File;
-use std::io::{Error, Write};
-use std::process::{Command, Child, Stdio};
+use std::io::{Error, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Command, Child, ExitStatus, Output, Stdio};
 use tempfile::{Builder, TempDir};
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-static PORT_OFFSET: AtomicUsize = AtomicUsize::new(0);
+/// Gets as many ports as asked for that nothing else is using, by having the OS pick them.
+///
+/// Every port is held on to until all of them have been picked, so none of them comes up twice,
+/// but they're only free until someone else takes them, so they should be put to use right away.
+fn get_free_ports(count: usize) -> Vec<u16> {
+    let reserved = (0..count)
+        .map(|_| TcpListener::bind(("127.0.0.1", 0)).unwrap())
+        .collect::<Vec<_>>();
 
-fn get_redis_config(stats_port: u16, listen1_port: u16, listen2_port: u16, redis1_port: u16, redis2_port: u16) -> String {
-    format!(r#"
-        {{
-            "stats_addr": "127.0.0.1:{stats_port}",
-            "listeners": {{
-                "fixed": {{
+    reserved.iter().map(|listener| listener.local_addr().unwrap().port()).collect()
+}
+
+fn get_free_port() -> u16 { get_free_ports(1)[0] }
+
+fn quote(value: &str) -> String { format!("\"{}\"", value) }
+
+fn render_object(fields: &[(String, String)]) -> String {
+    let fields = fields.iter()
+        .map(|(key, value)| format!("\"{}\": {}", key, value))
+        .collect::<Vec<_>>();
+
+    format!("{{ {} }}", fields.join(", "))
+}
+
+/// A Redis listener for Synchrotron to run, described by its pools and how it routes to them.
+///
+/// Settings are given as JSON, while pool and routing options are given as the strings they're
+/// always configured as.  The listener's address is left out: it's handed a port by `Config`.
+struct Listener {
+    name: String,
+    settings: Vec<(String, String)>,
+    pools: Vec<(String, Vec<(String, String)>)>,
+    routing: Vec<(String, String)>,
+}
+
+impl Listener {
+    fn new(name: &str, route_type: &str) -> Listener {
+        Listener {
+            name: name.to_owned(),
+            settings: Vec::new(),
+            pools: Vec::new(),
+            routing: vec![("type".to_owned(), quote(route_type))],
+        }
+    }
+
+    fn setting(mut self, key: &str, value: &str) -> Listener {
+        self.settings.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    fn route_option(mut self, key: &str, value: &str) -> Listener {
+        self.routing.push((key.to_owned(), quote(value)));
+        self
+    }
+
+    fn pool(mut self, name: &str, backends: &[u16], options: &[(&str, &str)]) -> Listener {
+        let addresses = backends.iter()
+            .map(|port| quote(&format!("127.0.0.1:{}", port)))
+            .collect::<Vec<_>>();
+
+        let mut fields = vec![("addresses".to_owned(), format!("[{}]", addresses.join(", ")))];
+        if !options.is_empty() {
+            let options = options.iter()
+                .map(|(key, value)| (key.to_string(), quote(value)))
+                .collect::<Vec<_>>();
+            fields.push(("options".to_owned(), render_object(&options)));
+        }
+
+        self.pools.push((name.to_owned(), fields));
+        self
+    }
+
+    /// Adds a setting, given as JSON, to the pool that was added last.
+    fn pool_setting(mut self, key: &str, value: &str) -> Listener {
+        let (_, fields) = self.pools.last_mut().expect("no pool to add the setting to");
+        fields.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    fn render(&self, port: u16) -> String {
+        let pools = self.pools.iter()
+            .map(|(name, fields)| (name.clone(), render_object(fields)))
+            .collect::<Vec<_>>();
+
+        let mut fields = vec![
+            ("protocol".to_owned(), quote("redis")),
+            ("address".to_owned(), quote(&format!("127.0.0.1:{}", port))),
+        ];
+        fields.extend(self.settings.iter().cloned());
+        fields.push(("pools".to_owned(), render_object(&pools)));
+        fields.push(("routing".to_owned(), render_object(&self.routing)));
+        render_object(&fields)
+    }
+}
+
+/// Synchrotron's configuration for a test: the listeners it runs, and anything set for all of them.
+///
+/// Every listener, and the stats server, is handed a port of its own when the configuration is
+/// put together.  Listeners are numbered in the order they were given in.
+struct Config {
+    settings: Vec<(String, String)>,
+    listeners: Vec<(Listener, u16)>,
+    stats_port: u16,
+}
+
+impl Config {
+    fn new(listeners: Vec<Listener>) -> Config {
+        let mut ports = get_free_ports(listeners.len() + 1);
+        let stats_port = ports.pop().unwrap();
+
+        Config {
+            settings: Vec::new(),
+            listeners: listeners.into_iter().zip(ports).collect(),
+            stats_port: stats_port,
+        }
+    }
+
+    /// Adds a setting, given as JSON, for all listeners.  Setting `stats_addr` replaces the address
+    /// the stats server would otherwise be given.
+    fn setting(mut self, key: &str, value: &str) -> Config {
+        self.settings.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    fn get_port(&self, listener: usize) -> u16 { self.listeners[listener].1 }
+
+    fn get_stats_port(&self) -> u16 { self.stats_port }
+
+    fn render(&self) -> String {
+        let mut fields = Vec::new();
+        if !self.settings.iter().any(|(key, _)| key == "stats_addr") {
+            fields.push(("stats_addr".to_owned(), quote(&format!("127.0.0.1:{}", self.stats_port))));
+        }
+        fields.extend(self.settings.iter().cloned());
+
+        let listeners = self.listeners.iter()
+            .map(|(listener, port)| (listener.name.clone(), listener.render(*port)))
+            .collect::<Vec<_>>();
+        fields.push(("listeners".to_owned(), render_object(&listeners)));
+        render_object(&fields)
+    }
+
+    /// Launches Synchrotron with this configuration, without waiting for it to start listening.
+    fn launch(&self) -> StrictSynchrotronRunner {
+        StrictSynchrotronRunner::new(self.get_port(0), self.render()).unwrap()
+    }
+}
+
+/// Writes out the given configuration, getting back the directory it lives in, which goes away
+/// along with it, and the path Synchrotron should be pointed at to load it.
+fn write_config(full_config: &str) -> Result<(TempDir, PathBuf), Error> {
+    let conf_dir = Builder::new()
+        .prefix("synchrotron-test-")
+        .tempdir()?;
+
+    let file_path = conf_dir.path().join("synchrotron");
+    let file_path_w_ext = conf_dir.path().join("synchrotron.json");
+    File::create(file_path_w_ext)?.write_all(full_config.as_bytes())?;
+
+    Ok((conf_dir, file_path))
+}
+
+fn get_redis_listeners(redis1_port: u16, redis2_port: u16) -> Vec<Listener> {
+    let migration = r#"{
+        "from_distribution": "modulo",
+        "from_hash": "fnv1a_64",
+        "to_distribution": "modulo",
+        "to_hash": "md5",
+        "read_fallback": true,
+        "delete_old": true
+    }"#;
+
+    vec![
+        Listener::new("fixed", "fixed")
+            .setting("pretend_cluster", "true")
+            .setting("allow_client_kill", "true")
+            .pool("default", &[redis1_port, redis2_port], &[("cooloff_timeout_ms", "2000"), ("timeout_ms", "100")]),
+        Listener::new("shadow", "shadow")
+            .pool("default", &[redis1_port], &[])
+            .pool("shadow", &[redis2_port], &[]),
+        Listener::new("ttl", "fixed")
+            .setting("max_fds", "8")
+            .pool("default", &[redis1_port], &[
+                ("require_ttl", "true"),
+                ("require_ttl_policy", "apply_default"),
+                ("default_ttl_secs", "600"),
+            ]),
+        Listener::new("single", "fixed")
+            .setting("max_fragments_per_command", "10000")
+            .setting("max_concurrent_fragments_per_client", "2000")
+            .setting("allow_debug_simulation", "true")
+            .pool("default", &[redis1_port], &[("conns_per_backend", "1"), ("allow_blocking", "true")]),
+        Listener::new("migrating", "fixed")
+            .pool("default", &[redis1_port, redis2_port], &[])
+            .pool_setting("migration", migration),
+    ]
+}
+
+fn get_broken_config() -> String {
+    // Every listener here is wrong in at least one way, and all of them should be reported.
+    r#"
+        {
+            "listeners": {
+                "broken": {
                     "protocol": "redis",
-                    "address": "127.0.0.1:{listen1_port}",
-                    "pools": {{
-                        "default": {{
-                            "addresses": ["127.0.0.1:{redis1_port}", "127.0.0.1:{redis2_port}"],
-                            "options": {{
-                                "cooloff_timeout_ms": "2000",
-                                "timeout_ms": "100"
-                            }}
-                        }}
-                    }},
-                    "routing": {{
+                    "address": "127.0.0.1:notaport",
+                    "pools": {
+                        "shadow": {
+                            "addresses": [],
+                            "options": {
+                                "hash": "nope"
+                            }
+                        }
+                    },
+                    "routing": {
                         "type": "fixed"
-                    }}
-                }},
-                "shadow": {{
-                    "protocol": "redis",
-                    "address": "127.0.0.1:{listen2_port}",
-                    "pools": {{
-                        "default": {{
-                            "addresses": ["127.0.0.1:{redis1_port}"]
-                        }},
-                        "shadow": {{
-                            "addresses": ["127.0.0.1:{redis2_port}"]
-                        }}
-                    }},
-                    "routing": {{
-                        "type": "shadow"
-                    }}
-                }}
-            }}
-        }}
-    "#, stats_port = stats_port, listen1_port = listen1_port, listen2_port = listen2_port, redis1_port = redis1_port, redis2_port = redis2_port)
+                    }
+                },
+                "unknown": {
+                    "protocol": "gopher",
+                    "address": "127.0.0.1:6379",
+                    "pools": {
+                        "default": {
+                            "addresses": ["127.0.0.1:6380"]
+                        }
+                    },
+                    "routing": {
+                        "type": "sideways"
+                    }
+                }
+            }
+        }
+    "#.to_owned()
 }
 
 pub struct SynchrotronRunner {
     handle: Child,
     port: u16,
+    stats_port: u16,
     fixed_conn_str: String,
     shadow_conn_str: String,
+    ttl_conn_str: String,
+    single_conn_str: String,
+    migrating_conn_str: String,
     conf_dir: Option<TempDir>,
 }
 
 impl SynchrotronRunner {
-    pub fn new_redis(stats_port: u16, listen1_port: u16, listen2_port: u16, redis1_port: u16, redis2_port: u16) -> Result<SynchrotronRunner, Error> {
-        let full_config = get_redis_config(stats_port, listen1_port, listen2_port, redis1_port, redis2_port);
-
-        // Create our configuration file from the data we got.
-        let conf_dir = Builder::new()
-            .prefix("synchrotron-test-")
-            .tempdir()?;
-
-        let file_path = conf_dir.path().join("synchrotron");
-        let file_path_w_ext = conf_dir.path().join("synchrotron.json");
-        let mut conf_file = File::create(file_path_w_ext)?;
-        conf_file.write(full_config.as_bytes())?;
+    pub fn new_redis(redis1_port: u16, redis2_port: u16) -> Result<SynchrotronRunner, Error> {
+        let config = Config::new(get_redis_listeners(redis1_port, redis2_port));
+        let (conf_dir, file_path) = write_config(&config.render())?;
 
         // Now try and launch Synchrotron.
         let handle = Command::new("../target/debug/synchrotron")
@@ -80,14 +268,20 @@ impl SynchrotronRunner {
             .stderr(Stdio::null())
             .spawn()?;
 
-        wait_until(|| check_synchrotron(listen1_port));
-        wait_until(|| check_synchrotron(listen2_port));
+        for (_, port) in &config.listeners {
+            wait_until(|| check_synchrotron(*port));
+        }
 
+        let conn_str = |listener| format!("redis://127.0.0.1:{}", config.get_port(listener));
         Ok(SynchrotronRunner {
             handle: handle,
-            port: listen1_port,
-            fixed_conn_str: format!("redis://127.0.0.1:{}", listen1_port),
-            shadow_conn_str: format!("redis://127.0.0.1:{}", listen2_port),
+            port: config.get_port(0),
+            stats_port: config.get_stats_port(),
+            fixed_conn_str: conn_str(0),
+            shadow_conn_str: conn_str(1),
+            ttl_conn_str: conn_str(2),
+            single_conn_str: conn_str(3),
+            migrating_conn_str: conn_str(4),
             conf_dir: Some(conf_dir),
         })
     }
@@ -99,6 +293,206 @@ impl SynchrotronRunner {
     pub fn get_shadow_conn_str(&self) -> &str {
         self.shadow_conn_str.as_str()
     }
+
+    pub fn get_ttl_conn_str(&self) -> &str {
+        self.ttl_conn_str.as_str()
+    }
+
+    pub fn get_single_conn_str(&self) -> &str {
+        self.single_conn_str.as_str()
+    }
+
+    pub fn get_migrating_conn_str(&self) -> &str {
+        self.migrating_conn_str.as_str()
+    }
+
+    pub fn get_stats(&self) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        conn.write_all(b"GET /stats HTTP/1.0\r\n\r\n")?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn get_overview(&self) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        conn.write_all(b"GET / HTTP/1.0\r\n\r\n")?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn get_pool_overview(&self, pool: &str) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        conn.write_all(format!("GET /pools/{} HTTP/1.0\r\n\r\n", pool).as_bytes())?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn get_stats_delta(&self, window: &str) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        conn.write_all(format!("GET /stats/delta?window={} HTTP/1.0\r\n\r\n", window).as_bytes())?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn get_metrics(&self) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        conn.write_all(b"GET /metrics HTTP/1.0\r\n\r\n")?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn get_health(&self) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        conn.write_all(b"GET /health HTTP/1.0\r\n\r\n")?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn kill_clients(&self, listener: &str, addr: &str) -> Result<String, Error> {
+        self.kill_clients_matching(listener, &format!("addr={}", addr))
+    }
+
+    pub fn kill_clients_by_name(&self, listener: &str, name: &str) -> Result<String, Error> {
+        self.kill_clients_matching(listener, &format!("name={}", name))
+    }
+
+    fn kill_clients_matching(&self, listener: &str, query: &str) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        let request = format!("POST /listeners/{}/clients/kill?{} HTTP/1.0\r\nContent-Length: 0\r\n\r\n", listener, query);
+        conn.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn list_clients(&self, listener: &str, query: &str) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        let request = format!("GET /listeners/{}/clients?{} HTTP/1.0\r\n\r\n", listener, query);
+        conn.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn kill_client_by_id(&self, listener: &str, id: u64) -> Result<String, Error> {
+        self.kill_clients_matching(listener, &format!("id={}", id))
+    }
+
+    /// Rewrites our configuration file, replacing `from` with `to`, for the next reload to pick up.
+    pub fn update_config(&self, from: &str, to: &str) -> Result<(), Error> {
+        let file_path = self.conf_dir.as_ref().unwrap().path().join("synchrotron.json");
+        let mut config = String::new();
+        File::open(&file_path)?.read_to_string(&mut config)?;
+        assert!(config.contains(from), "configuration doesn't contain {}", from);
+
+        File::create(&file_path)?.write_all(config.replace(from, to).as_bytes())
+    }
+
+    pub fn listener_command(&self, listener: &str, action: &str) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        let request = format!("POST /listeners/{}/{} HTTP/1.0\r\nContent-Length: 0\r\n\r\n", listener, action);
+        conn.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn request_reload(&self, wait: Option<&str>) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        let query = wait.map(|wait| format!("?wait={}", wait)).unwrap_or_default();
+        let request = format!("POST /reload{} HTTP/1.0\r\nContent-Length: 0\r\n\r\n", query);
+        conn.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn get_reload_status(&self) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        conn.write_all(b"GET /reload HTTP/1.0\r\n\r\n")?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn set_backend_weight(&self, listener: &str, pool: &str, backend: &str, weight: usize) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        let request = format!("POST /pools/{}/{}/backends/{}/weight?weight={} HTTP/1.0\r\nContent-Length: 0\r\n\r\n", listener, pool, backend, weight);
+        conn.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn drain_backend(&self, listener: &str, pool: &str, backend: &str, quiet_ms: u64) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        let request = format!("POST /pools/{}/{}/backends/{}/drain?quiet_ms={} HTTP/1.0\r\nContent-Length: 0\r\n\r\n", listener, pool, backend, quiet_ms);
+        conn.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn restore_backend(&self, listener: &str, pool: &str, backend: &str) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        let request = format!("POST /pools/{}/{}/backends/{}/restore HTTP/1.0\r\nContent-Length: 0\r\n\r\n", listener, pool, backend);
+        conn.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn reload_preserving_drains(&self) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        conn.write_all(b"POST /reload?wait=5s&preserve_drains=true HTTP/1.0\r\nContent-Length: 0\r\n\r\n")?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    pub fn get_events(&self, since: u64) -> Result<String, Error> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port))?;
+        let request = format!("GET /events?since={} HTTP/1.0\r\n\r\n", since);
+        conn.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    /// Gets the value of the first metric whose name starts with `name`.
+    pub fn get_stat(&self, name: &str) -> Option<i64> {
+        let stats = self.get_stats().ok()?;
+        let start = stats.find(&format!("\"{}", name))?;
+        let rest = &stats[start..];
+        let value_start = rest.find("\":")? + 2;
+        let value = rest[value_start..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit() || *c == '-')
+            .collect::<String>();
+        value.parse().ok()
+    }
 }
 
 impl Drop for SynchrotronRunner {
@@ -111,6 +505,120 @@ impl Drop for SynchrotronRunner {
     }
 }
 
+pub struct StrictSynchrotronRunner {
+    handle: Child,
+    port: u16,
+    conn_str: String,
+    log_path: PathBuf,
+    conf_dir: Option<TempDir>,
+}
+
+impl StrictSynchrotronRunner {
+    pub fn new(listen_port: u16, full_config: String) -> Result<StrictSynchrotronRunner, Error> {
+        let (conf_dir, file_path) = write_config(&full_config)?;
+
+        // Launch Synchrotron, but don't wait for it: whether or not it ever starts listening is
+        // exactly what the caller wants to find out.  We hang on to its logs in case the caller
+        // wants to know what it had to say along the way.
+        let log_path = conf_dir.path().join("synchrotron.log");
+        let log_file = File::create(&log_path)?;
+        let handle = Command::new("../target/debug/synchrotron")
+            .env("SYNC_CONFIG", file_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::from(log_file))
+            .spawn()?;
+
+        Ok(StrictSynchrotronRunner {
+            handle: handle,
+            port: listen_port,
+            conn_str: format!("redis://127.0.0.1:{}", listen_port),
+            log_path: log_path,
+            conf_dir: Some(conf_dir),
+        })
+    }
+
+    pub fn get_conn_str(&self) -> &str {
+        self.conn_str.as_str()
+    }
+
+    pub fn wait_until_listening(&self) {
+        wait_until(|| check_synchrotron(self.port));
+    }
+
+    pub fn is_listening(&self) -> bool {
+        TcpStream::connect(("127.0.0.1", self.port)).is_ok()
+    }
+
+    pub fn interrupt(&self) {
+        let status = Command::new("kill")
+            .arg("-INT")
+            .arg(self.handle.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    pub fn upgrade(&self) {
+        let status = Command::new("kill")
+            .arg("-USR2")
+            .arg(self.handle.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Gets the process that we handed off to, once we've said so.
+    pub fn get_successor_pid(&self) -> Option<u32> {
+        let output = self.get_output();
+        let start = output.find("handed off listeners to pid ")? + "handed off listeners to pid ".len();
+        output[start..].split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+    }
+
+    pub fn get_output(&self) -> String {
+        let mut output = String::new();
+        File::open(&self.log_path).unwrap().read_to_string(&mut output).unwrap();
+        output
+    }
+
+    pub fn wait_for_output(&self, needle: &str, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if self.get_output().contains(needle) {
+                return true;
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        false
+    }
+
+    pub fn wait_for_exit(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.handle.try_wait().unwrap() {
+                return Some(status);
+            }
+
+            if Instant::now() >= deadline {
+                return None;
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for StrictSynchrotronRunner {
+    fn drop(&mut self) {
+        // We may have already exited on our own, so don't sweat it if there's nothing to kill.
+        let _ = self.handle.kill();
+        self.conf_dir.take().unwrap().close().unwrap();
+
+        println!("Synchrotron ({}) killed!", self.port);
+    }
+}
+
 pub struct RedisRunner {
     handle: Child,
     port: u16,
@@ -118,22 +626,31 @@ pub struct RedisRunner {
 }
 
 impl RedisRunner {
-    pub fn new(port: u16) -> Result<RedisRunner, Error> {
+    pub fn new(port: u16) -> Result<RedisRunner, Error> { RedisRunner::launch(port, None) }
+
+    pub fn new_with_password(port: u16, password: &str) -> Result<RedisRunner, Error> {
+        RedisRunner::launch(port, Some(password))
+    }
+
+    fn launch(port: u16, password: Option<&str>) -> Result<RedisRunner, Error> {
         let redis_bin = match env::var("REDIS_BIN") {
             Ok(s) => s,
             Err(_) => "/usr/local/bin/redis-server".to_owned(),
         };
 
-        // Launch Redis on the specified port.
-        let handle = Command::new(redis_bin)
-            .arg("--port")
-            .arg(port.to_string())
+        // Launch Redis on the specified port, requiring a password if we were given one.
+        let mut command = Command::new(redis_bin);
+        command.arg("--port").arg(port.to_string());
+        if let Some(password) = password {
+            command.arg("--requirepass").arg(password);
+        }
+        let handle = command
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .spawn()?;
 
         // Wait for the instance to be ready.
-        wait_until(|| check_redis(port));
+        wait_until(|| check_redis(port, password));
 
         Ok(RedisRunner {
             handle: handle,
@@ -145,6 +662,29 @@ impl RedisRunner {
     pub fn get_conn_str(&self) -> &str {
         self.conn_str.as_str()
     }
+
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Stops the instance in its tracks, leaving its connections open but unanswered.
+    pub fn pause(&self) {
+        self.signal("-STOP");
+    }
+
+    /// Picks the instance back up after a pause.
+    pub fn resume(&self) {
+        self.signal("-CONT");
+    }
+
+    fn signal(&self, signal: &str) {
+        let status = Command::new("kill")
+            .arg(signal)
+            .arg(self.handle.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
 }
 
 impl Drop for RedisRunner {
@@ -175,9 +715,14 @@ fn wait_until<F>(f: F)
     }
 }
 
-fn check_redis(port: u16) -> bool {
-    let result = Command::new("redis-cli")
-        .args(&["-h", "localhost", "-p", port.to_string().as_str(), "ping"])
+fn check_redis(port: u16, password: Option<&str>) -> bool {
+    let mut command = Command::new("redis-cli");
+    command.args(&["-h", "localhost", "-p", port.to_string().as_str()]);
+    if let Some(password) = password {
+        command.args(&["-a", password]);
+    }
+    let result = command
+        .arg("ping")
         .output()
         .expect("failed to run redis-cli");
 
@@ -224,17 +769,283 @@ fn check_synchrotron(port: u16) -> bool {
 }
 
 pub fn get_redis_daemons() -> (SynchrotronRunner, RedisRunner, RedisRunner) {
-    let offset = PORT_OFFSET.fetch_add(1, Ordering::SeqCst) as u16;
+    let redis1 = RedisRunner::new(get_free_port()).unwrap();
+    let redis2 = RedisRunner::new(get_free_port()).unwrap();
+    let synchrotron = SynchrotronRunner::new_redis(redis1.get_port(), redis2.get_port()).unwrap();
 
-    let synchrotron_stats_port = 43000 + offset;
-    let synchrotron_listen1_port = 44000 + offset;
-    let synchrotron_listen2_port = 45000 + offset;
-    let redis1_port = 46000 + offset;
-    let redis2_port = 47000 + offset;
+    (synchrotron, redis1, redis2)
+}
+
+pub fn get_strict_redis_daemons(redis_running: bool) -> (StrictSynchrotronRunner, Option<RedisRunner>) {
+    // When asked for a downed backend, we still launch Redis so we know the port was real, and
+    // then stop it before Synchrotron gets a chance to connect.
+    let redis = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("strict", "fixed")
+        .pool("default", &[redis.get_port()], &[
+            ("require_backends_at_startup", "true"),
+            ("startup_connect_timeout_ms", "500"),
+        ]);
+    let config = Config::new(vec![listener]);
+
+    let redis = if redis_running { Some(redis) } else { None };
+    (config.launch(), redis)
+}
+
+pub fn get_startup_daemons(listeners: &[(bool, &str)]) -> (StrictSynchrotronRunner, Vec<TcpListener>) {
+    // Nothing ever talks to the backend, so it doesn't need to be running.
+    let redis_port = get_free_port();
+    let config = Config::new(listeners.iter().enumerate().map(|(i, (_, distribution))| {
+        Listener::new(&format!("startup{}", i), "fixed")
+            .pool("default", &[redis_port], &[("distribution", *distribution)])
+    }).collect());
+
+    // Those listeners that are meant to conflict have their ports bound out from under them
+    // before Synchrotron starts.
+    let conflicts = listeners.iter().enumerate()
+        .filter(|(_, (conflict, _))| *conflict)
+        .map(|(i, _)| TcpListener::bind(("127.0.0.1", config.get_port(i))).unwrap())
+        .collect();
+
+    (config.launch(), conflicts)
+}
+
+pub fn get_stats_daemons(stats_conflict: bool, stats_enabled: bool) -> (StrictSynchrotronRunner, RedisRunner, u16, Option<TcpListener>) {
+    let redis = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("stats", "fixed").pool("default", &[redis.get_port()], &[]);
+    let config = Config::new(vec![listener]);
+    let config = if stats_enabled {
+        config.setting("stats_bind_retry_ms", "100")
+    } else {
+        config.setting("stats_addr", "\"\"")
+    };
+
+    // If asked, the stats port is taken out from under Synchrotron before it starts, and stays
+    // taken until the caller lets go of it.
+    let stats_port = config.get_stats_port();
+    let conflict = if stats_conflict {
+        Some(TcpListener::bind(("127.0.0.1", stats_port)).unwrap())
+    } else {
+        None
+    };
+
+    (config.launch(), redis, stats_port, conflict)
+}
+
+pub fn get_reject_ttl_daemons() -> (StrictSynchrotronRunner, RedisRunner) {
+    let redis = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("reject_ttl", "fixed")
+        .pool("default", &[redis.get_port()], &[("require_ttl", "true"), ("require_ttl_policy", "reject")]);
+    let synchrotron = Config::new(vec![listener]).launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, redis)
+}
+
+pub fn get_split_daemons() -> (StrictSynchrotronRunner, RedisRunner, RedisRunner) {
+    let writes = RedisRunner::new(get_free_port()).unwrap();
+    let reads = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("split", "split")
+        .pool("writes", &[writes.get_port()], &[])
+        .pool("reads", &[reads.get_port()], &[]);
+    let synchrotron = Config::new(vec![listener]).launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, writes, reads)
+}
+
+pub fn get_health_check_daemons() -> (StrictSynchrotronRunner, RedisRunner, RedisRunner) {
+    let redis1 = RedisRunner::new(get_free_port()).unwrap();
+    let redis2 = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("health", "fixed")
+        .pool("default", &[redis1.get_port(), redis2.get_port()], &[
+            ("health_check_interval_ms", "100"),
+            ("health_check_eject_after", "2"),
+            ("health_check_restore_after", "2"),
+        ]);
+    let synchrotron = Config::new(vec![listener]).launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, redis1, redis2)
+}
+
+pub fn get_tls_daemons() -> (StrictSynchrotronRunner, RedisRunner, u16, u16) {
+    // Our certificate is self-signed, and only good for talking to ourselves.
+    let fixtures = env::current_dir().unwrap().join("fixtures").join("tls");
+    let tls = format!(r#"{{ "cert_path": "{}", "key_path": "{}", "handshake_timeout_ms": 500 }}"#,
+        fixtures.join("server.crt").display(), fixtures.join("server.key").display());
+
+    // Only the plaintext listener can tell us when we're up, but both start together.
+    let redis = RedisRunner::new(get_free_port()).unwrap();
+    let config = Config::new(vec![
+        Listener::new("plain", "fixed").pool("default", &[redis.get_port()], &[]),
+        Listener::new("secure", "fixed").setting("tls", &tls).pool("default", &[redis.get_port()], &[]),
+    ]);
+    let synchrotron = config.launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, redis, config.get_port(1), config.get_stats_port())
+}
+
+pub fn get_auth_daemons(password: &str) -> (StrictSynchrotronRunner, RedisRunner, u16) {
+    // Pinging the protected listener won't get us anywhere, so the open one tells us when we're up.
+    let redis = RedisRunner::new(get_free_port()).unwrap();
+    let config = Config::new(vec![
+        Listener::new("open", "fixed").pool("default", &[redis.get_port()], &[]),
+        Listener::new("protected", "fixed")
+            .setting("password", &quote(password))
+            .pool("default", &[redis.get_port()], &[]),
+    ]);
+    let synchrotron = config.launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, redis, config.get_port(1))
+}
+
+pub fn get_backend_auth_daemons(password: &str, db: u32) -> (StrictSynchrotronRunner, RedisRunner, u16, u16) {
+    let redis = RedisRunner::new_with_password(get_free_port(), password).unwrap();
+    let config = Config::new(vec![
+        Listener::new("authed", "fixed")
+            .pool("default", &[redis.get_port()], &[("redis_auth", password), ("redis_db", &db.to_string())]),
+        Listener::new("wrong", "fixed")
+            .pool("default", &[redis.get_port()], &[("redis_auth", "swordfish")]),
+    ]);
+    let synchrotron = config.launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, redis, config.get_port(0), config.get_port(1))
+}
 
-    let redis1 = RedisRunner::new(redis1_port).unwrap();
-    let redis2 = RedisRunner::new(redis2_port).unwrap();
-    let synchrotron = SynchrotronRunner::new_redis(synchrotron_stats_port, synchrotron_listen1_port, synchrotron_listen2_port, redis1_port, redis2_port).unwrap();
+pub fn get_timeout_daemons() -> (StrictSynchrotronRunner, RedisRunner, RedisRunner) {
+    // Backends aren't taken out of rotation for timing out, so keys stay where they are.
+    let redis1 = RedisRunner::new(get_free_port()).unwrap();
+    let redis2 = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("timeout", "fixed")
+        .pool("default", &[redis1.get_port(), redis2.get_port()], &[
+            ("timeout_ms", "200"),
+            ("cooloff_enabled", "false"),
+        ]);
+    let synchrotron = Config::new(vec![listener]).launch();
+    synchrotron.wait_until_listening();
 
     (synchrotron, redis1, redis2)
 }
+
+pub fn get_failover_daemons() -> (StrictSynchrotronRunner, RedisRunner, RedisRunner) {
+    let default = RedisRunner::new(get_free_port()).unwrap();
+    let failover = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("failover", "failover")
+        .route_option("max_retries", "1")
+        .route_option("failover_reads_only", "false")
+        .pool("default", &[default.get_port()], &[("timeout_ms", "200"), ("cooloff_enabled", "false")])
+        .pool("failover", &[failover.get_port()], &[]);
+    let synchrotron = Config::new(vec![listener]).launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, default, failover)
+}
+
+pub fn get_split_percentage_daemons(percentage: u64) -> (StrictSynchrotronRunner, RedisRunner, RedisRunner) {
+    let old = RedisRunner::new(get_free_port()).unwrap();
+    let new = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("split_percentage", "split_percentage")
+        .route_option("percentage", &percentage.to_string())
+        .pool("old", &[old.get_port()], &[])
+        .pool("new", &[new.get_port()], &[]);
+    let synchrotron = Config::new(vec![listener]).launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, old, new)
+}
+
+pub fn get_shutdown_timeout_daemons(shutdown_timeout_ms: u64) -> (StrictSynchrotronRunner, RedisRunner) {
+    // The timeout is set for every listener, rather than on the listener itself, so that it has to
+    // find its way down to the listener.
+    let redis = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("shutdown_timeout", "fixed").pool("default", &[redis.get_port()], &[]);
+    let synchrotron = Config::new(vec![listener])
+        .setting("shutdown_timeout_ms", &shutdown_timeout_ms.to_string())
+        .launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, redis)
+}
+
+pub fn get_max_clients_daemons(max_clients: usize, behavior: &str) -> (StrictSynchrotronRunner, RedisRunner, u16) {
+    let redis = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("limited", "fixed")
+        .setting("max_clients", &max_clients.to_string())
+        .setting("max_clients_behavior", &quote(behavior))
+        .pool("default", &[redis.get_port()], &[]);
+    let config = Config::new(vec![listener]);
+    let synchrotron = config.launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, redis, config.get_stats_port())
+}
+
+pub fn get_idle_timeout_daemons(idle_timeout_ms: u64) -> (StrictSynchrotronRunner, RedisRunner) {
+    let redis = RedisRunner::new(get_free_port()).unwrap();
+    let listener = Listener::new("idle", "fixed")
+        .setting("client_idle_timeout_ms", &idle_timeout_ms.to_string())
+        .pool("default", &[redis.get_port()], &[]);
+    let synchrotron = Config::new(vec![listener]).launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, redis)
+}
+
+/// Gets a backend that never lets anyone finish connecting to it.
+///
+/// Once a listener's accept queue is full, anyone else connecting is left waiting on a SYN that's
+/// never answered, so we fill it up ourselves, by connecting until one of our own connections
+/// doesn't go through.  Nothing is ever accepted, and the connections that filled the queue have
+/// to be held on to for as long as the backend is needed.
+fn get_blackhole() -> (TcpListener, Vec<TcpStream>) {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let mut fillers = Vec::new();
+    while let Ok(filler) = TcpStream::connect_timeout(&address, Duration::from_millis(100)) {
+        fillers.push(filler);
+        assert!(fillers.len() < 10_000, "accept queue never filled up");
+    }
+
+    (listener, fillers)
+}
+
+pub fn get_blackhole_daemons() -> (StrictSynchrotronRunner, (TcpListener, Vec<TcpStream>), u16) {
+    // Connecting is given far less time than anything else, and the first failure is enough to
+    // cool the backend off for longer than any test runs.
+    let blackhole = get_blackhole();
+    let backend_port = blackhole.0.local_addr().unwrap().port();
+    let listener = Listener::new("blackhole", "fixed")
+        .pool("default", &[backend_port], &[
+            ("timeout_ms", "5000"),
+            ("connect_timeout_ms", "100"),
+            ("cooloff_timeout_ms", "30000"),
+            ("cooloff_error_limit", "1"),
+        ]);
+    let config = Config::new(vec![listener]);
+    let synchrotron = config.launch();
+    synchrotron.wait_until_listening();
+
+    (synchrotron, blackhole, config.get_stats_port())
+}
+
+/// Runs Synchrotron's configuration check against the given configuration, which never starts it.
+fn check_config(full_config: String) -> Output {
+    let (_conf_dir, file_path) = write_config(&full_config).unwrap();
+
+    Command::new("../target/debug/synchrotron")
+        .arg("--check")
+        .env("SYNC_CONFIG", file_path)
+        .output()
+        .unwrap()
+}
+
+pub fn check_redis_config() -> Output {
+    // Nothing is ever connected to, so the backends don't need to be running.
+    check_config(Config::new(get_redis_listeners(6379, 6380)).render())
+}
+
+pub fn check_broken_config() -> Output { check_config(get_broken_config()) }
@@ -9,12 +9,53 @@ fn main() {
 
 #[cfg(test)]
 mod redis_tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpStream;
+    use std::process::{Command, Stdio};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use redis::cmd as redis_cmd;
+    use redis::pipe as redis_pipe;
     use redis::Client as RedisClient;
-    use redis::{Commands, RedisResult, ErrorKind as RedisErrorKind};
-    use daemons::get_redis_daemons;
+    use redis::{Commands, RedisResult, Value as RedisValue, ErrorKind as RedisErrorKind};
+    use daemons::{check_broken_config, check_redis_config, get_auth_daemons, get_backend_auth_daemons, get_blackhole_daemons, get_failover_daemons, get_health_check_daemons, get_idle_timeout_daemons, get_max_clients_daemons, get_redis_daemons, get_reject_ttl_daemons, get_shutdown_timeout_daemons, get_split_daemons, get_split_percentage_daemons, get_startup_daemons, get_stats_daemons, get_strict_redis_daemons, get_timeout_daemons, get_tls_daemons, RedisRunner};
+
+    #[test]
+    fn test_capabilities() {
+        // No configuration is needed just to describe what the binary supports.
+        let output = Command::new("../target/debug/synchrotron")
+            .arg("--capabilities")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let capabilities = String::from_utf8(output.stdout).unwrap();
+        assert!(capabilities.contains("\"protocols\""));
+        assert!(capabilities.contains("\"redis\""));
+        assert!(capabilities.contains("\"modulo\""));
+        assert!(capabilities.contains("\"fnv1a_64\""));
+    }
+
+    #[test]
+    fn test_check_config() {
+        // A good configuration passes without anything being started.
+        let output = check_redis_config();
+        assert!(output.status.success());
+        assert!(String::from_utf8(output.stdout).unwrap().contains("configuration is valid (5 listener(s))"));
+
+        // A broken one has every problem reported, each with where it is in the configuration.
+        let output = check_broken_config();
+        assert_eq!(output.status.code(), Some(1));
+        let problems = String::from_utf8(output.stderr).unwrap();
+        assert!(problems.contains("listeners.broken.address: '127.0.0.1:notaport' is not a valid address"));
+        assert!(problems.contains("listeners.broken.pools.default: required by the fixed router, but not configured"));
+        assert!(problems.contains("listeners.broken.pools.shadow.addresses: no backends configured"));
+        assert!(problems.contains("listeners.broken.pools.shadow: invalid resource: unknown hash type nope"));
+        assert!(problems.contains("listeners.unknown.protocol: unknown protocol 'gopher'"));
+        assert!(problems.contains("listeners.unknown.routing.type: unknown route type 'sideways'"));
+    }
 
     #[test]
     fn test_set_get() {
@@ -42,6 +83,85 @@ mod redis_tests {
         assert_eq!(value, vec![42, 43, 44]);
     }
 
+    #[test]
+    fn test_mget_across_backends() {
+        let (sd, rd1, rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        // Write enough keys through Synchrotron that both backends end up holding some of them.
+        let keys: Vec<String> = (0..20).map(|i| format!("mget_across_{}", i)).collect();
+        for (i, key) in keys.iter().enumerate() {
+            let _: () = conn.set(key.as_str(), i).unwrap();
+        }
+
+        let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
+        let r1conn = r1client.get_connection().unwrap();
+        let r2client = RedisClient::open(rd2.get_conn_str()).unwrap();
+        let r2conn = r2client.get_connection().unwrap();
+
+        let r1_keys: Vec<String> = r1conn.keys("mget_across_*").unwrap();
+        let r2_keys: Vec<String> = r2conn.keys("mget_across_*").unwrap();
+        assert!(!r1_keys.is_empty());
+        assert!(!r2_keys.is_empty());
+        assert_eq!(r1_keys.len() + r2_keys.len(), keys.len());
+
+        // Ask for every key, in reverse, with missing keys and a key of the wrong type mixed in.
+        // Values have to come back in the order we asked for them, with nil for anything that
+        // isn't a string.
+        let _: () = conn.hset("mget_across_hash", "field", 1).unwrap();
+        let mut requested = Vec::new();
+        let mut expected = Vec::new();
+        for (i, key) in keys.iter().enumerate().rev() {
+            requested.push(key.clone());
+            expected.push(Some(i as isize));
+            if i % 5 == 0 {
+                requested.push(format!("mget_missing_{}", i));
+                expected.push(None);
+            }
+        }
+        requested.insert(3, "mget_across_hash".to_owned());
+        expected.insert(3, None);
+
+        let values: Vec<Option<isize>> = redis_cmd("mGeT").arg(&requested).query(&conn).unwrap();
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn test_multi_key_writes_across_backends() {
+        let (sd, rd1, rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        // A single MSET lands every key on the backend that owns it, and is answered with one OK.
+        let pairs: Vec<(String, usize)> = (0..20).map(|i| (format!("mset_across_{}", i), i)).collect();
+        let result: String = redis_cmd("MSET").arg(&pairs).query(&conn).unwrap();
+        assert_eq!(result, "OK");
+
+        let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
+        let r1conn = r1client.get_connection().unwrap();
+        let r2client = RedisClient::open(rd2.get_conn_str()).unwrap();
+        let r2conn = r2client.get_connection().unwrap();
+
+        let r1_keys: Vec<String> = r1conn.keys("mset_across_*").unwrap();
+        let r2_keys: Vec<String> = r2conn.keys("mset_across_*").unwrap();
+        assert!(!r1_keys.is_empty());
+        assert!(!r2_keys.is_empty());
+        assert_eq!(r1_keys.len() + r2_keys.len(), pairs.len());
+
+        // Deletes are counted up across both backends, and keys that don't exist aren't counted.
+        let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+        let deleted: isize = redis_cmd("DEL").arg(&keys[..10]).arg("mset_missing").query(&conn).unwrap();
+        assert_eq!(deleted, 10);
+        let unlinked: isize = redis_cmd("unlink").arg(&keys).query(&conn).unwrap();
+        assert_eq!(unlinked, 10);
+
+        let remaining: Vec<Option<isize>> = redis_cmd("MGET").arg(&keys).query(&conn).unwrap();
+        assert!(remaining.iter().all(Option::is_none));
+    }
+
     #[test]
     fn test_invalid_commands() {
         let (sd, _rd1, _rd2) = get_redis_daemons();
@@ -76,6 +196,285 @@ mod redis_tests {
         assert!(ping_result2.is_ok());
     }
 
+    #[test]
+    fn test_transactions() {
+        let (sd, rd1, rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        // A transaction runs as a whole on the backend that owns its first key, even for keys that
+        // would otherwise live somewhere else.
+        let keys: Vec<String> = (0..10).map(|i| format!("txn_{}", i)).collect();
+        let mut pipe = redis_pipe();
+        pipe.atomic();
+        for key in &keys {
+            pipe.cmd("INCR").arg(key.as_str());
+        }
+        pipe.cmd("INCRBY").arg(keys[0].as_str()).arg(41);
+        let values: Vec<isize> = pipe.query(&conn).unwrap();
+        assert_eq!(values, vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 42]);
+
+        let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
+        let r1conn = r1client.get_connection().unwrap();
+        let r2client = RedisClient::open(rd2.get_conn_str()).unwrap();
+        let r2conn = r2client.get_connection().unwrap();
+
+        let r1_keys: Vec<String> = r1conn.keys("txn_*").unwrap();
+        let r2_keys: Vec<String> = r2conn.keys("txn_*").unwrap();
+        assert!(r1_keys.len() == keys.len() || r2_keys.len() == keys.len());
+
+        // Misplaced transaction commands are answered just as Redis would answer them.
+        let exec: RedisResult<()> = redis_cmd("EXEC").query(&conn);
+        assert!(exec.is_err());
+        let multi: String = redis_cmd("MULTI").query(&conn).unwrap();
+        assert_eq!(multi, "OK");
+        let nested: RedisResult<String> = redis_cmd("MULTI").query(&conn);
+        assert!(nested.is_err());
+        let queued: String = redis_cmd("INCR").arg("txn_discarded").query(&conn).unwrap();
+        assert_eq!(queued, "QUEUED");
+        let discarded: String = redis_cmd("DISCARD").query(&conn).unwrap();
+        assert_eq!(discarded, "OK");
+
+        // Nothing from a discarded transaction runs, and the client carries on as normal.
+        let missing: Option<isize> = conn.get("txn_discarded").unwrap();
+        assert_eq!(missing, None);
+    }
+
+    #[test]
+    fn test_blocking_commands() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Blocking commands are refused by default, without ever reaching a backend, and the client
+        // carries on as normal.
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let blpop: RedisResult<()> = redis_cmd("BLPOP").arg("blocking_refused").arg(1).query(&conn);
+        assert!(blpop.unwrap_err().to_string().contains("command not supported by proxy"));
+        let wait: RedisResult<()> = redis_cmd("WAIT").arg(0).arg(0).query(&conn);
+        assert!(wait.is_err());
+
+        let _: () = conn.set("blocking_after", 1).unwrap();
+        let after: isize = conn.get("blocking_after").unwrap();
+        assert_eq!(after, 1);
+
+        // Pools that allow them run them on a connection of their own, so everything else keeps
+        // moving over the one connection the pool shares, even while they're blocked.
+        let conn_str = sd.get_single_conn_str().to_owned();
+        let blocked = thread::spawn(move || {
+            let client = RedisClient::open(conn_str.as_str()).unwrap();
+            let conn = client.get_connection().unwrap();
+            let popped: (String, String) = redis_cmd("BLPOP").arg("blocking_list").arg(5).query(&conn).unwrap();
+            popped
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let client = RedisClient::open(sd.get_single_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("blocking_shared", 1).unwrap();
+        let shared: isize = conn.get("blocking_shared").unwrap();
+        assert_eq!(shared, 1);
+
+        let _: () = conn.rpush("blocking_list", "value").unwrap();
+        let popped = blocked.join().unwrap();
+        assert_eq!(popped, ("blocking_list".to_owned(), "value".to_owned()));
+
+        let mut stats = (None, None);
+        for _ in 0..20 {
+            stats = (
+                sd.get_stat("listeners.fixed.pools.default.blocking_rejected"),
+                sd.get_stat("listeners.single.pools.default.blocking_dedicated"),
+            );
+            if stats == (Some(2), Some(1)) {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(stats, (Some(2), Some(1)));
+    }
+
+    #[test]
+    fn test_local_commands() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // We're pinged while starting up, too, so only what's served from here on is ours.
+        let before = sd.get_stat("listeners.fixed.client.local_commands").unwrap_or(0);
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let pong: String = redis_cmd("PING").query(&conn).unwrap();
+        assert_eq!(pong, "PONG");
+        let ping: String = redis_cmd("PING").arg("hello").query(&conn).unwrap();
+        assert_eq!(ping, "hello");
+        let echo: String = redis_cmd("ECHO").arg("hello").query(&conn).unwrap();
+        assert_eq!(echo, "hello");
+
+        let count: isize = redis_cmd("COMMAND").arg("COUNT").query(&conn).unwrap();
+        let commands: Vec<RedisValue> = redis_cmd("COMMAND").query(&conn).unwrap();
+        assert_eq!(commands.len() as isize, count);
+
+        // Quitting answers everything that came before it, and then hangs up.
+        let mut conn = TcpStream::connect(sd.get_fixed_conn_str().trim_left_matches("redis://")).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        conn.write_all(b"*2\r\n$4\r\nECHO\r\n$3\r\nbye\r\n*1\r\n$4\r\nQuit\r\n").unwrap();
+        let mut buf = Vec::new();
+        conn.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], &b"$3\r\nbye\r\n+OK\r\n"[..]);
+
+        let mut local = 0;
+        for _ in 0..20 {
+            local = sd.get_stat("listeners.fixed.client.local_commands").unwrap_or(0) - before;
+            if local == 7 {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(local, 7);
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Anything we can't parse gets an error of its own, and whatever comes after it is still
+        // served as normal.
+        let mut conn = TcpStream::connect(sd.get_fixed_conn_str().trim_left_matches("redis://")).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        conn.write_all(b"*3\r\n$3\r\nSET\r\n$15\r\nprotocol_errors\r\n$2\r\nok\r\nhello\r\n").unwrap();
+        conn.write_all(b"*2\r\n$3\r\nGET\r\n$15\r\nprotocol_errors\r\n$x\r\n").unwrap();
+        conn.write_all(b"*2\r\n$3\r\nGET\r\n$15\r\nprotocol_errors\r\n*1\r\n$4\r\nQUIT\r\n").unwrap();
+        let mut buf = Vec::new();
+        conn.read_to_end(&mut buf).unwrap();
+
+        let mut expected = b"+OK\r\n-ERR Protocol error: expected type sigil at offset 0\r\n$2\r\nok\r\n".to_vec();
+        expected.extend_from_slice(b"-ERR Protocol error: expected bulk length at offset 1\r\n$2\r\nok\r\n+OK\r\n");
+        assert_eq!(String::from_utf8_lossy(&buf), String::from_utf8_lossy(&expected));
+    }
+
+    #[test]
+    fn test_request_limits() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Declaring a value far bigger than we'd accept gets the client hung up on straight away,
+        // rather than us waiting around for it to arrive.
+        let mut conn = TcpStream::connect(sd.get_fixed_conn_str().trim_left_matches("redis://")).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        conn.write_all(b"*3\r\n$3\r\nSET\r\n$14\r\nrequest_limits\r\n$629145600\r\nfoo").unwrap();
+        let mut buf = Vec::new();
+        conn.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], &b"-ERR Protocol error: invalid bulk length\r\n"[..]);
+    }
+
+    #[test]
+    fn test_pubsub() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Subscribers hear about anything published to their channels, which is routed like any
+        // other command.
+        let client = RedisClient::open(sd.get_single_conn_str()).unwrap();
+        let mut pubsub = client.get_pubsub().unwrap();
+        pubsub.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        pubsub.subscribe("pubsub_news").unwrap();
+
+        let conn = client.get_connection().unwrap();
+        let mut receivers: isize = 0;
+        for _ in 0..20 {
+            receivers = redis_cmd("PUBLISH").arg("pubsub_news").arg("hello").query(&conn).unwrap();
+            if receivers == 1 {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(receivers, 1);
+
+        let msg = pubsub.get_message().unwrap();
+        assert_eq!(msg.get_channel_name(), "pubsub_news");
+        assert_eq!(msg.get_payload::<String>().unwrap(), "hello");
+
+        // Once a client has unsubscribed from everything, it goes back to sending commands as normal.
+        let mut conn = TcpStream::connect(sd.get_single_conn_str().trim_left_matches("redis://")).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        conn.write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$12\r\npubsub_other\r\n").unwrap();
+        let subscribed = b"*3\r\n$9\r\nsubscribe\r\n$12\r\npubsub_other\r\n:1\r\n";
+        let mut buf = vec![0; subscribed.len()];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &subscribed[..]);
+
+        let script = [
+            &b"*2\r\n$11\r\nUNSUBSCRIBE\r\n$12\r\npubsub_other\r\n"[..],
+            &b"*2\r\n$3\r\nGET\r\n$14\r\npubsub_missing\r\n"[..],
+        ];
+        conn.write_all(&script.concat()).unwrap();
+        let expected = b"*3\r\n$11\r\nunsubscribe\r\n$12\r\npubsub_other\r\n:0\r\n$-1\r\n";
+        let mut buf = vec![0; expected.len()];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &expected[..]);
+
+        // Patterns could match channels on any backend, so they can't be relayed to just one.
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let mut pubsub = client.get_pubsub().unwrap();
+        pubsub.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        pubsub.psubscribe("pubsub_*").unwrap();
+        let err = pubsub.get_message().unwrap_err();
+        assert!(err.to_string().contains("pattern subscriptions are not supported"));
+
+        let mut stats = (None, None);
+        for _ in 0..20 {
+            stats = (
+                sd.get_stat("listeners.single.pubsub.subscriptions"),
+                sd.get_stat("listeners.fixed.pubsub.subscriptions_rejected"),
+            );
+            if stats == (Some(2), Some(1)) {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(stats, (Some(2), Some(1)));
+    }
+
+    #[test]
+    fn test_cluster_topology() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // The fixed listener pretends to be a cluster, so it should claim every slot for itself.
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let slots: RedisValue = redis_cmd("CLUSTER").arg("SLOTS").query(&conn).unwrap();
+        match slots {
+            RedisValue::Bulk(ref ranges) => {
+                assert_eq!(ranges.len(), 1);
+                match ranges[0] {
+                    RedisValue::Bulk(ref range) => {
+                        assert_eq!(range[0], RedisValue::Int(0));
+                        assert_eq!(range[1], RedisValue::Int(16383));
+                    },
+                    _ => panic!("slot range should be an array"),
+                }
+            },
+            _ => panic!("CLUSTER SLOTS should return an array"),
+        }
+
+        // Regular commands still work on the same connection afterwards.
+        let _: () = conn.set("cluster_key", 42).unwrap();
+        let value: isize = conn.get("cluster_key").unwrap();
+        assert_eq!(value, 42);
+
+        // The shadow listener doesn't pretend, and says so.
+        let client2 = RedisClient::open(sd.get_shadow_conn_str()).unwrap();
+        let conn2 = client2.get_connection().unwrap();
+        let slots_result: RedisResult<RedisValue> = redis_cmd("CLUSTER").arg("SLOTS").query(&conn2);
+        assert!(slots_result.is_err());
+    }
+
     #[test]
     fn test_null_key() {
         let (sd, _rd1, _rd2) = get_redis_daemons();
@@ -214,63 +613,1942 @@ mod redis_tests {
     }
 
     #[test]
-    fn test_backend_cooloff() {
+    fn test_shadow_mirrors_writes() {
         let (sd, rd1, rd2) = get_redis_daemons();
 
-        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let client = RedisClient::open(sd.get_shadow_conn_str()).unwrap();
         let conn = client.get_connection().unwrap();
 
-        // Set values directly on both Redis servers so we can distinguish between nodes when
-        // we eventually go through Synchrotron.
         let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
         let r1conn = r1client.get_connection().unwrap();
 
-        let _: () = r1conn.set("two", 1).unwrap();
-        let value1: isize = r1conn.get("two").unwrap();
-        assert_eq!(value1, 1);
-
         let r2client = RedisClient::open(rd2.get_conn_str()).unwrap();
         let r2conn = r2client.get_connection().unwrap();
 
-        let _: () = r2conn.set("two", 2).unwrap();
-        let value2: isize = r2conn.get("two").unwrap();
-        assert_eq!(value2, 2);
+        for i in 0..20 {
+            let _: () = conn.set(format!("mirrored-{}", i), i).unwrap();
+        }
 
-        // Now grab the value through Synchrotron so we have our baseline value.
-        let baseline: isize = conn.get("two").unwrap();
+        // Give the shadow pool a moment to catch up, since nobody waits on it.
+        thread::sleep(Duration::from_millis(50));
 
-        // Now kill whichever server was the one that the key routed to.
-        if baseline == 1 {
-            drop(rd1);
-        } else {
-            drop(rd2);
+        for i in 0..20 {
+            let key = format!("mirrored-{}", i);
+            let primary: isize = r1conn.get(&key).unwrap();
+            assert_eq!(primary, i);
+
+            let shadow: isize = r2conn.get(&key).unwrap();
+            assert_eq!(shadow, i);
         }
 
-        // Wait for a hot second just to make sure things are dead.  250ms should do it.
-        thread::sleep(Duration::from_millis(250));
+        // Reads only ever come from the default pool, even if the shadow has drifted from it.
+        let _: () = r2conn.set("mirrored-0", 100).unwrap();
+        let value: isize = conn.get("mirrored-0").unwrap();
+        assert_eq!(value, 0);
+    }
 
-        // Now, try to ask Synchrotron for the value, five times.  Should be all errors.
-        for _ in 0..5 {
-            let iclient = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
-            let iconn = iclient.get_connection().unwrap();
-            let result: RedisResult<isize> = iconn.get("two");
-            match result {
-                Ok(_) => panic!("should have been error after killing redis node"),
-                Err(inner_err) => assert_eq!(inner_err.kind(), RedisErrorKind::ResponseError),
+    #[test]
+    fn test_shadow_outage_is_invisible() {
+        let (sd, rd1, rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_shadow_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
+        let r1conn = r1client.get_connection().unwrap();
+
+        // Make sure the shadow pool is connected before we pull the rug out from under it.
+        let _: () = conn.set("outage", 0).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let connects_before = sd.get_stat("listeners.shadow.pools.shadow.backend.connects").unwrap_or(0);
+
+        drop(rd2);
+
+        // With the shadow backend gone, clients shouldn't notice anything: every operation should
+        // still succeed, against the default pool, and without waiting on the shadow.
+        for i in 1..50 {
+            let start = Instant::now();
+            let _: () = conn.set("outage", i).unwrap();
+            let value: isize = conn.get("outage").unwrap();
+            assert_eq!(value, i);
+            assert!(start.elapsed() < Duration::from_millis(250));
+
+            let primary: isize = r1conn.get("outage").unwrap();
+            assert_eq!(primary, i);
+        }
+
+        // The only sign of the outage is the shadow pool trying, and failing, to reconnect.
+        thread::sleep(Duration::from_millis(50));
+        let connects_after = sd.get_stat("listeners.shadow.pools.shadow.backend.connects").unwrap_or(0);
+        assert!(connects_after > connects_before);
+    }
+
+    #[test]
+    fn test_split_routes_reads_and_writes() {
+        let (sd, wd, rd) = get_split_daemons();
+
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let wclient = RedisClient::open(wd.get_conn_str()).unwrap();
+        let wconn = wclient.get_connection().unwrap();
+
+        let rclient = RedisClient::open(rd.get_conn_str()).unwrap();
+        let rconn = rclient.get_connection().unwrap();
+
+        // Writes only land on the writes pool.
+        let _: () = conn.set("split", 1).unwrap();
+        let written: Option<isize> = wconn.get("split").unwrap();
+        assert_eq!(written, Some(1));
+        let replicated: Option<isize> = rconn.get("split").unwrap();
+        assert_eq!(replicated, None);
+
+        // Reads only come from the reads pool, even when it disagrees with the writes pool.
+        let _: () = rconn.set("split", 2).unwrap();
+        let value: isize = conn.get("split").unwrap();
+        assert_eq!(value, 2);
+        let ttl: isize = conn.ttl("split").unwrap();
+        assert_eq!(ttl, -1);
+
+        // A pipeline mixing reads and writes is split across both pools, but still answered in order.
+        let mut pipe = redis_pipe();
+        for i in 0..20 {
+            pipe.cmd("INCR").arg("split-counter");
+            pipe.cmd("GET").arg("split");
+            pipe.cmd("EXISTS").arg(format!("split-missing-{}", i));
+        }
+        let results: Vec<RedisValue> = pipe.query(&conn).unwrap();
+        assert_eq!(results.len(), 60);
+        for (i, chunk) in results.chunks(3).enumerate() {
+            assert_eq!(chunk[0], RedisValue::Int(i as i64 + 1));
+            assert_eq!(chunk[1], RedisValue::Data(b"2".to_vec()));
+            assert_eq!(chunk[2], RedisValue::Int(0));
+        }
+        let counter: isize = wconn.get("split-counter").unwrap();
+        assert_eq!(counter, 20);
+    }
+
+    #[test]
+    fn test_require_ttl_rejects_creators() {
+        let (sd, rd) = get_reject_ttl_daemons();
+
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let rclient = RedisClient::open(rd.get_conn_str()).unwrap();
+        let rconn = rclient.get_connection().unwrap();
+
+        // The sources go in behind our back, since writing them through us would be rejected too.
+        let _: () = rconn.sadd("ttl_src_set", "member").unwrap();
+        let _: () = rconn.sadd("ttl_other_set", "member").unwrap();
+        let _: () = rconn.zadd("ttl_src_zset", "member", 1).unwrap();
+        let _: () = rconn.zadd("ttl_other_zset", "member", 1).unwrap();
+        let _: () = rconn.rpush("ttl_src_list", "value").unwrap();
+        let _: () = redis_cmd("PFADD").arg("ttl_src_hll").arg("value").query(&rconn).unwrap();
+
+        // Every one of these would create the destination key, with no TTL of its own.
+        let creators = vec![
+            redis_cmd("MSET").arg("ttl_dest_mset").arg(1).clone(),
+            redis_cmd("MSET").arg("ttl_dest_mset").arg(1).arg("ttl_dest_mset2").arg(2).clone(),
+            redis_cmd("SUNIONSTORE").arg("ttl_dest_sunion").arg("ttl_src_set").arg("ttl_other_set").clone(),
+            redis_cmd("SINTERSTORE").arg("ttl_dest_sinter").arg("ttl_src_set").arg("ttl_other_set").clone(),
+            redis_cmd("SDIFFSTORE").arg("ttl_dest_sdiff").arg("ttl_src_set").arg("ttl_missing_set").clone(),
+            redis_cmd("ZUNIONSTORE").arg("ttl_dest_zunion").arg(2).arg("ttl_src_zset").arg("ttl_other_zset").clone(),
+            redis_cmd("ZINTERSTORE").arg("ttl_dest_zinter").arg(2).arg("ttl_src_zset").arg("ttl_other_zset").clone(),
+            redis_cmd("PFMERGE").arg("ttl_dest_pfmerge").arg("ttl_src_hll").clone(),
+            redis_cmd("SMOVE").arg("ttl_src_set").arg("ttl_dest_smove").arg("member").clone(),
+            redis_cmd("RPOPLPUSH").arg("ttl_src_list").arg("ttl_dest_rpoplpush").clone(),
+        ];
+        for creator in &creators {
+            let result: RedisResult<RedisValue> = creator.query(&conn);
+            let err = result.unwrap_err();
+            assert!(err.to_string().contains("key must be written with a TTL"), "unexpected error: {}", err);
+        }
+
+        // None of them made it to the backend, so the sources are untouched and nothing was created.
+        let created: Vec<String> = rconn.keys("ttl_dest_*").unwrap();
+        assert!(created.is_empty(), "keys were created: {:?}", created);
+        let members: isize = rconn.scard("ttl_src_set").unwrap();
+        assert_eq!(members, 1);
+        let len: isize = rconn.llen("ttl_src_list").unwrap();
+        assert_eq!(len, 1);
+    }
+
+    #[test]
+    fn test_require_ttl_applies_default() {
+        let (sd, rd1, _rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_ttl_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
+        let r1conn = r1client.get_connection().unwrap();
+
+        // Every one of these creates a key with no TTL of its own.
+        let _: () = conn.set("ttl_set", 1).unwrap();
+        let _: () = conn.incr("ttl_incr", 1).unwrap();
+        let _: () = conn.hset("ttl_hset", "field", "value").unwrap();
+        let _: () = conn.lpush("ttl_lpush", "value").unwrap();
+        let _: () = conn.sadd("ttl_sadd", "member").unwrap();
+        let _: () = conn.zadd("ttl_zadd", "member", 1).unwrap();
+
+        // Moving something into a key creates that key, not the one it was moved out of.
+        let _: () = r1conn.sadd("ttl_smove_src", vec!["member", "other"]).unwrap();
+        let _: () = redis_cmd("SMOVE").arg("ttl_smove_src").arg("ttl_smove").arg("member").query(&conn).unwrap();
+
+        // A key written with an explicit TTL should keep it.
+        let _: () = redis_cmd("SET").arg("ttl_explicit").arg(1).arg("EX").arg(30).query(&conn).unwrap();
+
+        // The follow-ups aren't waited on by the client, so give them a hot second to land.
+        thread::sleep(Duration::from_millis(50));
+
+        for key in &["ttl_set", "ttl_incr", "ttl_hset", "ttl_lpush", "ttl_sadd", "ttl_zadd", "ttl_smove"] {
+            let ttl: isize = r1conn.ttl(*key).unwrap();
+            assert!(ttl > 0 && ttl <= 600, "key '{}' has unexpected TTL {}", key, ttl);
+        }
+
+        let ttl: isize = r1conn.ttl("ttl_explicit").unwrap();
+        assert!(ttl > 0 && ttl <= 30);
+
+        let ttl: isize = r1conn.ttl("ttl_smove_src").unwrap();
+        assert_eq!(ttl, -1);
+
+        // Existing TTLs shouldn't be touched by later writes that can't carry a TTL.
+        let _: () = conn.hset("ttl_hset", "field2", "value").unwrap();
+        let _: () = r1conn.expire("ttl_hset", 5).unwrap();
+        let _: () = conn.hset("ttl_hset", "field3", "value").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let ttl: isize = r1conn.ttl("ttl_hset").unwrap();
+        assert!(ttl > 0 && ttl <= 5);
+    }
+
+    #[test]
+    fn test_pool_hit_ratio() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // The TTL listener has a single backend, so multi-key lookups all land on the same server.
+        let client = RedisClient::open(sd.get_ttl_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("hit_one", 1).unwrap();
+        let _: () = conn.set("hit_two", 2).unwrap();
+
+        // One hit, then one miss.
+        let _: Option<isize> = conn.get("hit_one").unwrap();
+        let _: Option<isize> = conn.get("miss_one").unwrap();
+
+        // MGET counts each key: two hits and a miss.
+        let _: Vec<Option<isize>> = conn.get(&["hit_one", "hit_two", "miss_two"]).unwrap();
+
+        // EXISTS counts each key too: one hit and one miss.
+        let _: isize = redis_cmd("EXISTS").arg("hit_two").arg("miss_three").query(&conn).unwrap();
+
+        // Metrics are aggregated in the background, so give them a moment to show up.
+        let prefix = "listeners.ttl.pools.default";
+        let mut stats = (None, None, None);
+        for _ in 0..20 {
+            stats = (
+                sd.get_stat(&format!("{}.hits", prefix)),
+                sd.get_stat(&format!("{}.misses", prefix)),
+                sd.get_stat(&format!("{}.hit_ratio_bps", prefix)),
+            );
+            if stats == (Some(4), Some(3), Some(5714)) {
+                break;
             }
+
+            thread::sleep(Duration::from_millis(100));
         }
 
-        // Next one should work as it switches over to the new server.
-        let failover: isize = conn.get("two").unwrap();
-        assert!(failover != baseline);
+        assert_eq!(stats, (Some(4), Some(3), Some(5714)));
+    }
 
-        // Now wait for the cooloff to expire.
-        thread::sleep(Duration::from_millis(2500));
+    #[test]
+    fn test_fragment_limits() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
 
-        // And make sure we get errors again.  We can't easily restart the killed Redis
-        // daemon again but we'll know the cooloff happened if we get an error because
-        // it means it tried the downed server again.
-        let result: RedisResult<isize> = conn.get("two");
-        assert!(result.is_err());
+        let client = RedisClient::open(sd.get_single_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        for i in 0..1000 {
+            let _: () = conn.set(format!("frag_{}", i), i).unwrap();
+        }
+
+        // The single listener won't split a command into more than 10,000 fragments.
+        let keys = (0..50000).map(|i| format!("frag_{}", i)).collect::<Vec<_>>();
+        let result: RedisResult<Vec<Option<isize>>> = redis_cmd("MGET").arg(&keys).query(&conn);
+        match result {
+            Ok(_) => panic!("should have been an error for a 50k-key MGET"),
+            Err(inner_err) => assert_eq!(inner_err.kind(), RedisErrorKind::ResponseError),
+        }
+
+        // A pipeline of 1k-key MGETs goes over the limit of 2,000 outstanding fragments, so it gets
+        // throttled, but every response still comes back complete and in order.
+        let keys = (0..1000).map(|i| format!("frag_{}", i)).collect::<Vec<_>>();
+        let expected = (0..1000).map(Some).collect::<Vec<Option<isize>>>();
+        let mut pipe = redis_pipe();
+        for _ in 0..20 {
+            pipe.cmd("MGET").arg(&keys);
+        }
+        let results: Vec<Vec<Option<isize>>> = pipe.query(&conn).unwrap();
+        assert_eq!(results.len(), 20);
+        for result in results {
+            assert_eq!(result, expected);
+        }
+
+        let mut throttles = None;
+        for _ in 0..20 {
+            throttles = sd.get_stat("listeners.single.client.fragment_throttles");
+            if throttles.unwrap_or(0) > 0 {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert!(throttles.unwrap_or(0) > 0);
+    }
+
+    #[test]
+    fn test_migration_read_fallback() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // The fixed listener places keys the way the migrating listener used to, so anything
+        // written through it sits at the old placement.
+        let old_client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let old_conn = old_client.get_connection().unwrap();
+        for i in 0..100 {
+            let _: () = old_conn.set(format!("migrate_{}", i), i).unwrap();
+        }
+
+        // Every key should still be found after the switch, whether or not it has moved.
+        let client = RedisClient::open(sd.get_migrating_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        for i in 0..100 {
+            let value: Option<isize> = conn.get(format!("migrate_{}", i)).unwrap();
+            assert_eq!(value, Some(i));
+        }
+
+        // Writes land on the new placement and clear out the old one, so we never read a stale value.
+        for i in 0..100 {
+            let _: () = conn.set(format!("migrate_{}", i), i + 1000).unwrap();
+        }
+        for i in 0..100 {
+            let value: Option<isize> = conn.get(format!("migrate_{}", i)).unwrap();
+            assert_eq!(value, Some(i + 1000));
+        }
+
+        // With two backends, some keys must have moved, and been found at their old placement.
+        let mut fallback_hits = None;
+        for _ in 0..20 {
+            fallback_hits = sd.get_stat("listeners.migrating.pools.default.migration_fallback_hits");
+            if fallback_hits.unwrap_or(0) > 0 {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert!(fallback_hits.unwrap_or(0) > 0);
+    }
+
+    #[test]
+    fn test_listener_fd_limit() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Hold open more connections than the TTL listener is allowed.
+        let addr = sd.get_ttl_conn_str().trim_left_matches("redis://");
+        let mut conns = (0..12).map(|_| TcpStream::connect(addr).unwrap()).collect::<Vec<_>>();
+        thread::sleep(Duration::from_millis(250));
+
+        // Anyone over the limit gets hung up on straight away, while everyone else stays connected.
+        let mut rejected = 0;
+        for conn in &mut conns {
+            conn.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+            let mut buf = [0u8; 1];
+            if let Ok(0) = conn.read(&mut buf) {
+                rejected += 1;
+            }
+        }
+        assert!(rejected >= 4, "only {} connections were rejected", rejected);
+        assert!(rejected < 12);
+
+        // Other listeners don't care.
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("fd_limit", 1).unwrap();
+
+        // Once connections go away, there's room again.
+        drop(conns);
+        thread::sleep(Duration::from_millis(250));
+
+        let ttl_client = RedisClient::open(sd.get_ttl_conn_str()).unwrap();
+        let ttl_conn = ttl_client.get_connection().unwrap();
+        let _: () = ttl_conn.set("fd_limit", 2).unwrap();
+        let value: isize = ttl_conn.get("fd_limit").unwrap();
+        assert_eq!(value, 2);
+
+        // The limit shows up alongside the usage.
+        let mut max_fds = None;
+        for _ in 0..20 {
+            max_fds = sd.get_stat("listeners.ttl.max_fds");
+            if max_fds.is_some() && sd.get_stat("listeners.ttl.fds").is_some() {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(max_fds, Some(8));
+    }
+
+    #[test]
+    fn test_listener_survives_hangups() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+        let addr = sd.get_fixed_conn_str().trim_left_matches("redis://").to_owned();
+
+        // Clients that hang up as soon as they've connected, from a few threads at once, are what
+        // load tests and port scanners look like.
+        let scanners = (0..4)
+            .map(|_| {
+                let addr = addr.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        if let Ok(conn) = TcpStream::connect(addr.as_str()) {
+                            drop(conn);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for scanner in scanners {
+            scanner.join().unwrap();
+        }
+
+        // The listener is still there for everyone else afterwards.
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("survived_hangups", 1).unwrap();
+        let value: isize = conn.get("survived_hangups").unwrap();
+        assert_eq!(value, 1);
+    }
+
+    #[test]
+    fn test_concurrent_pipelines_stay_ordered() {
+        const CLIENTS: usize = 200;
+        const KEYS: usize = 10;
+        const ROUNDS: usize = 20;
+
+        let (sd, rd1, _rd2) = get_redis_daemons();
+
+        // Seed every client's keys directly, so each one has values only it should ever see.
+        let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
+        let r1conn = r1client.get_connection().unwrap();
+        let mut seed = redis_pipe();
+        for i in 0..CLIENTS {
+            for j in 0..KEYS {
+                seed.cmd("SET").arg(format!("ordering:{}:{}", i, j)).arg(format!("value:{}:{}", i, j)).ignore();
+            }
+        }
+        let _: () = seed.query(&r1conn).unwrap();
+
+        // Now hammer the single backend connection with all of the clients at once, each sending
+        // its lookups in tight pipelines.
+        let conn_str = sd.get_single_conn_str().to_owned();
+        let handles = (0..CLIENTS)
+            .map(|i| {
+                let conn_str = conn_str.clone();
+                thread::spawn(move || {
+                    let client = RedisClient::open(conn_str.as_str()).unwrap();
+                    let conn = client.get_connection().unwrap();
+                    for _ in 0..ROUNDS {
+                        let mut lookups = redis_pipe();
+                        for j in 0..KEYS {
+                            lookups.cmd("GET").arg(format!("ordering:{}:{}", i, j));
+                        }
+
+                        let values: Vec<String> = lookups.query(&conn).unwrap();
+                        for (j, value) in values.iter().enumerate() {
+                            assert_eq!(value, &format!("value:{}:{}", i, j), "client {} got another client's response", i);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_empty_lines_are_ignored() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        let mut conn = TcpStream::connect(sd.get_fixed_conn_str().trim_left_matches("redis://")).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // Runs of bare line endings, of every length, before, between, and after real commands,
+        // all sent at once so they land in every possible spot of the reads on the other end.
+        let mut script = Vec::new();
+        let mut expected = Vec::new();
+        for i in 0..1000 {
+            for _ in 0..(i % 7) {
+                script.extend_from_slice(b"\r\n");
+            }
+            if i % 3 == 0 {
+                script.extend_from_slice(b"\n");
+            }
+
+            let key = format!("blank:{}", i);
+            let value = i.to_string();
+            script.extend_from_slice(
+                format!("*3\r\n$3\r\nset\r\n${}\r\n{}\r\n${}\r\n{}\r\n", key.len(), key, value.len(), value).as_bytes(),
+            );
+            script.extend_from_slice(format!("*2\r\n$3\r\nget\r\n${}\r\n{}\r\n", key.len(), key).as_bytes());
+            expected.extend_from_slice(format!("+OK\r\n${}\r\n{}\r\n", value.len(), value).as_bytes());
+        }
+        for _ in 0..1000 {
+            script.extend_from_slice(b"\r\n");
+        }
+        script.extend_from_slice(b"PING\r\n");
+        expected.extend_from_slice(b"+PONG\r\n");
+
+        conn.write_all(&script).unwrap();
+        let mut responses = vec![0u8; expected.len()];
+        conn.read_exact(&mut responses).unwrap();
+        assert_eq!(String::from_utf8_lossy(&responses), String::from_utf8_lossy(&expected));
+    }
+
+    #[test]
+    fn test_kill_client() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Our victim talks raw RESP so we know exactly which address it's connecting from.
+        let mut victim = TcpStream::connect(sd.get_fixed_conn_str().trim_left_matches("redis://")).unwrap();
+        victim.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let victim_addr = victim.local_addr().unwrap();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("bystander", 1).unwrap();
+
+        // Pipeline a few requests and make sure they're being served.
+        victim.write_all(b"*3\r\n$3\r\nset\r\n$6\r\nvictim\r\n$1\r\n1\r\n*2\r\n$3\r\nget\r\n$6\r\nvictim\r\n").unwrap();
+        let mut buf = [0u8; 11];
+        victim.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"+OK\r\n$1\r\n1\r\n");
+
+        let response = sd.kill_clients("fixed", &victim_addr.to_string()).unwrap();
+        assert!(response.contains("\"killed\":1"), "unexpected response: {}", response);
+
+        // The victim should now see its connection closed.
+        let mut rest = Vec::new();
+        assert_eq!(victim.read_to_end(&mut rest).unwrap(), 0);
+
+        // Everyone else carries on as usual.
+        let value: isize = conn.get("bystander").unwrap();
+        assert_eq!(value, 1);
+
+        // Nobody else is connected from the victim's address anymore.
+        let response = sd.kill_clients("fixed", &victim_addr.to_string()).unwrap();
+        assert!(response.contains("\"killed\":0"), "unexpected response: {}", response);
+    }
+
+    #[test]
+    fn test_kill_client_mid_pipeline() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        let mut victim = TcpStream::connect(sd.get_single_conn_str().trim_left_matches("redis://")).unwrap();
+        victim.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let victim_addr = victim.local_addr().unwrap();
+
+        let client = RedisClient::open(sd.get_single_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("bystander", 1).unwrap();
+
+        // Park a sleep in the middle of the pipeline so there are still responses owed when the
+        // kill lands.
+        let start = Instant::now();
+        victim
+            .write_all(
+                b"*3\r\n$3\r\nset\r\n$6\r\nvictim\r\n$1\r\n1\r\n*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$1\r\n1\r\n\
+                  *2\r\n$3\r\nget\r\n$6\r\nvictim\r\n",
+            ).unwrap();
+        let mut buf = [0u8; 5];
+        victim.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"+OK\r\n");
+
+        let response = sd.kill_clients("single", &victim_addr.to_string()).unwrap();
+        assert!(response.contains("\"killed\":1"), "unexpected response: {}", response);
+
+        // Other clients on the same backend connection aren't held up by the victim going away.
+        let bystander_start = Instant::now();
+        let value: isize = conn.get("bystander").unwrap();
+        assert_eq!(value, 1);
+        assert!(bystander_start.elapsed() < Duration::from_millis(500));
+
+        // The victim still gets every response it was owed, and only then sees its connection closed.
+        let mut rest = Vec::new();
+        victim.read_to_end(&mut rest).unwrap();
+        assert_eq!(&rest[..], &b"+OK\r\n$1\r\n1\r\n"[..]);
+        assert!(start.elapsed() >= Duration::from_millis(1000), "sleep finished early: {:?}", start.elapsed());
+
+        // The bystander is still going after the victim is gone.
+        let value: isize = conn.get("bystander").unwrap();
+        assert_eq!(value, 1);
+    }
+
+    #[test]
+    fn test_kill_clients_by_name() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+        let conn_str = sd.get_fixed_conn_str();
+        let connect_named = |name: &str| {
+            let mut conn = TcpStream::connect(conn_str.trim_left_matches("redis://")).unwrap();
+            conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let setname = format!("*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n${}\r\n{}\r\n", name.len(), name);
+            conn.write_all(setname.as_bytes()).unwrap();
+            let mut buf = [0u8; 5];
+            conn.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"+OK\r\n");
+            conn
+        };
+
+        let mut victims = vec![connect_named("victim"), connect_named("victim")];
+        let mut bystander = connect_named("bystander");
+
+        // Every client going by the name is killed through the admin API, and nobody else is.
+        let response = sd.kill_clients_by_name("fixed", "victim").unwrap();
+        assert!(response.contains("\"killed\":2"), "unexpected response: {}", response);
+        for victim in &mut victims {
+            let mut rest = Vec::new();
+            assert_eq!(victim.read_to_end(&mut rest).unwrap(), 0);
+        }
+
+        // Clients can do the same to each other, by name or by address.
+        let mut victim = connect_named("victim");
+        let victim_addr = victim.local_addr().unwrap().to_string();
+        bystander.write_all(b"*4\r\n$6\r\nCLIENT\r\n$4\r\nKILL\r\n$4\r\nNAME\r\n$6\r\nvictim\r\n").unwrap();
+        let mut buf = [0u8; 4];
+        bystander.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b":1\r\n");
+        let mut rest = Vec::new();
+        assert_eq!(victim.read_to_end(&mut rest).unwrap(), 0);
+
+        let kill_addr = format!("*3\r\n$6\r\nCLIENT\r\n$4\r\nKILL\r\n${}\r\n{}\r\n", victim_addr.len(), victim_addr);
+        bystander.write_all(kill_addr.as_bytes()).unwrap();
+        let mut buf = [0u8; 21];
+        bystander.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"-ERR No such client\r\n");
+
+        // The bystander carried on throughout.
+        bystander.write_all(b"*2\r\n$3\r\nget\r\n$9\r\nbystander\r\n").unwrap();
+        let mut buf = [0u8; 5];
+        bystander.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"$-1\r\n");
+    }
+
+    #[test]
+    fn test_list_clients() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Name ourselves and run a command so there's something to see.
+        let mut listed = TcpStream::connect(sd.get_fixed_conn_str().trim_left_matches("redis://")).unwrap();
+        listed.write_all(b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$6\r\nlisted\r\n*2\r\n$3\r\nget\r\n$6\r\nlisted\r\n").unwrap();
+        let mut buf = [0u8; 10];
+        listed.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"+OK\r\n$-1\r\n");
+
+        let response = sd.list_clients("fixed", "limit=100").unwrap();
+        assert!(response.contains("\"listener\":\"fixed\""), "unexpected response: {}", response);
+        let name_pos = response.find("\"name\":\"listed\"").expect("named client should be listed");
+        assert!(response[name_pos..].contains("\"commands\":2"), "unexpected response: {}", response);
+
+        // The listed ID is the one the kill operation takes.
+        let id_pos = response[..name_pos].rfind("\"id\":").unwrap() + "\"id\":".len();
+        let id: u64 = response[id_pos..]
+            .split(|c: char| !c.is_digit(10))
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let response = sd.kill_client_by_id("fixed", id).unwrap();
+        assert!(response.contains("\"killed\":1"), "unexpected response: {}", response);
+
+        let mut rest = Vec::new();
+        assert_eq!(listed.read_to_end(&mut rest).unwrap(), 0);
+
+        // Limits cap how many clients come back.
+        let response = sd.list_clients("fixed", "limit=0").unwrap();
+        assert!(response.contains("\"clients\":[]"), "unexpected response: {}", response);
+    }
+
+    #[test]
+    fn test_drain_and_reload_listener() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("independent", 1).unwrap();
+
+        // Draining the shadow listener shouldn't affect the fixed listener at all.
+        let response = sd.listener_command("shadow", "drain").unwrap();
+        assert!(response.contains("\"action\":\"drain\""), "unexpected response: {}", response);
+        thread::sleep(Duration::from_millis(250));
+
+        let shadow_client = RedisClient::open(sd.get_shadow_conn_str()).unwrap();
+        assert!(shadow_client.get_connection().is_err());
+
+        let value: isize = conn.get("independent").unwrap();
+        assert_eq!(value, 1);
+
+        // Reloading it should bring it right back.
+        let response = sd.listener_command("shadow", "reload").unwrap();
+        assert!(response.contains("\"action\":\"reload\""), "unexpected response: {}", response);
+        thread::sleep(Duration::from_millis(250));
+
+        let shadow_conn = shadow_client.get_connection().unwrap();
+        let _: () = shadow_conn.set("reloaded", 2).unwrap();
+        let value: isize = shadow_conn.get("reloaded").unwrap();
+        assert_eq!(value, 2);
+
+        // The reload was applied as a new generation of the configuration, with a new version of
+        // the listener, and the stats say as much.
+        let mut stats = (None, None);
+        for _ in 0..20 {
+            stats = (
+                sd.get_stat("supervisor.config_generation"),
+                sd.get_stat("listeners.shadow.version"),
+            );
+            if stats == (Some(2), Some(1)) {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(stats, (Some(2), Some(1)));
+
+        // Clients can ask the listener the same thing.
+        let info: String = redis_cmd("INFO").arg("proxy").query(&shadow_conn).unwrap();
+        assert!(info.contains("listener_version:1\r\n"), "unexpected info: {}", info);
+        assert!(info.contains("config_gen:2\r\n"), "unexpected info: {}", info);
+    }
+
+    #[test]
+    fn test_bad_distributor_leaves_other_listeners_up() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Break one listener's configuration, and try to bring it up with it.
+        sd.update_config("\"timeout_ms\": \"100\"", "\"timeout_ms\": \"100\", \"distribution\": \"bogus\"")
+            .unwrap();
+        sd.listener_command("fixed", "reload").unwrap();
+
+        // It should say what was wrong, rather than falling over.
+        let mut events = String::new();
+        for _ in 0..20 {
+            events = sd.get_events(0).unwrap();
+            if events.contains("\"reload_failed\"") {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(events.contains("unknown distributor type bogus"), "failure missing from events: {}", events);
+
+        // Reloading everything fails the same way, and is reported as having failed.
+        let response = sd.request_reload(Some("5s")).unwrap();
+        assert!(response.contains(" 500 "), "unexpected response: {}", response);
+        assert!(response.contains("\"failed\":true"), "unexpected response: {}", response);
+        let status = sd.get_reload_status().unwrap();
+        assert!(status.contains("\"last_reload_completed\":0"), "unexpected status: {}", status);
+        assert!(status.contains("\"last_reload_failed\":2"), "unexpected status: {}", status);
+
+        // Every other listener is still up, and so is the broken one, on the configuration it had.
+        for conn_str in &[sd.get_fixed_conn_str(), sd.get_shadow_conn_str(), sd.get_ttl_conn_str()] {
+            let client = RedisClient::open(*conn_str).unwrap();
+            let conn = client.get_connection().unwrap();
+            let _: () = conn.set("bad_distributor", 1).unwrap();
+            let value: isize = conn.get("bad_distributor").unwrap();
+            assert_eq!(value, 1);
+        }
+    }
+
+    #[test]
+    fn test_debug_sleep_simulation() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // The single listener has just the one backend connection, so if a sleep were sent along
+        // to the backend, every other client would be stuck waiting behind it.
+        let client = RedisClient::open(sd.get_single_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("sleepy_key", 42).unwrap();
+
+        let sleeper_client = RedisClient::open(sd.get_single_conn_str()).unwrap();
+        let sleeper = thread::spawn(move || {
+            let sleeper_conn = sleeper_client.get_connection().unwrap();
+            let start = Instant::now();
+            let result: String = redis_cmd("DEBUG").arg("SLEEP").arg(1).query(&sleeper_conn).unwrap();
+            (result, start.elapsed())
+        });
+
+        // Give the sleep a head start, and make sure we don't have to wait for it.
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        let value: isize = conn.get("sleepy_key").unwrap();
+        assert_eq!(value, 42);
+        assert!(start.elapsed() < Duration::from_millis(500), "GET waited on the sleep: {:?}", start.elapsed());
+
+        let (result, elapsed) = sleeper.join().unwrap();
+        assert_eq!(result, "OK");
+        assert!(elapsed >= Duration::from_millis(1000), "sleep finished early: {:?}", elapsed);
+
+        // Other DEBUG commands are still off limits.
+        let other_conn = client.get_connection().unwrap();
+        let result: RedisResult<String> = redis_cmd("DEBUG").arg("OBJECT").arg("sleepy_key").query(&other_conn);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_reload_completes_after_old_clients_drain() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Keep a client on the current version of the listener busy for a while.
+        let sleeper_client = RedisClient::open(sd.get_single_conn_str()).unwrap();
+        let sleeper = thread::spawn(move || {
+            let sleeper_conn = sleeper_client.get_connection().unwrap();
+            let result: String = redis_cmd("DEBUG").arg("SLEEP").arg(1).query(&sleeper_conn).unwrap();
+            assert_eq!(result, "OK");
+            Instant::now()
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let response = sd.request_reload(None).unwrap();
+        assert!(response.contains(" 202 "), "unexpected response: {}", response);
+        assert!(response.contains("\"token\":1"), "unexpected response: {}", response);
+
+        // Watch for the reload completing while the old version is still draining.
+        let status_sd = Arc::new(sd);
+        let watcher_sd = status_sd.clone();
+        let watcher = thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while Instant::now() < deadline {
+                let status = watcher_sd.get_reload_status().unwrap();
+                if !status.contains("\"last_reload_completed\":0") {
+                    return Some((Instant::now(), status));
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            None
+        });
+
+        // Waiting on a reload can time out while the old version has clients.
+        let response = status_sd.request_reload(Some("100ms")).unwrap();
+        assert!(response.contains(" 504 "), "unexpected response: {}", response);
+        assert!(response.contains("\"completed\":false"), "unexpected response: {}", response);
+
+        // Nothing completes until the busy client has been answered and gone away.
+        let drained_at = sleeper.join().unwrap();
+        let (completed_at, status) = watcher.join().unwrap().expect("reload never completed");
+        assert!(completed_at >= drained_at, "reload completed before the old client drained: {}", status);
+        assert!(status.contains("\"last_reload_requested\":2"), "unexpected status: {}", status);
+
+        // With nothing left to drain, waiting on a reload sees it through.
+        let response = status_sd.request_reload(Some("5s")).unwrap();
+        assert!(response.contains(" 200 "), "unexpected response: {}", response);
+        assert!(response.contains("\"token\":3"), "unexpected response: {}", response);
+
+        let status = status_sd.get_reload_status().unwrap();
+        assert!(status.contains("\"last_reload_completed\":3"), "unexpected status: {}", status);
+        assert!(status.contains("\"single\":3"), "unexpected status: {}", status);
+    }
+
+    #[test]
+    fn test_health() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        let response = sd.get_health().unwrap();
+        assert!(response.contains(" 200 "), "unexpected response: {}", response);
+        assert!(response.contains("\"stalled_tasks\":0"), "unexpected response: {}", response);
+
+        // Tasks that are shut down on purpose aren't stalled, they're just gone.
+        let response = sd.listener_command("shadow", "drain").unwrap();
+        assert!(response.contains("\"action\":\"drain\""), "unexpected response: {}", response);
+        thread::sleep(Duration::from_millis(250));
+
+        let response = sd.get_health().unwrap();
+        assert!(response.contains(" 200 "), "unexpected response: {}", response);
+        assert!(response.contains("\"healthy\":true"), "unexpected response: {}", response);
+    }
+
+    #[test]
+    fn test_backend_weight() {
+        let (sd, rd1, rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
+        let r1conn = r1client.get_connection().unwrap();
+        let r2client = RedisClient::open(rd2.get_conn_str()).unwrap();
+        let r2conn = r2client.get_connection().unwrap();
+
+        // Drain the first backend, and everything should land on the second one.
+        let backend = rd1.get_conn_str().trim_left_matches("redis://");
+        let response = sd.set_backend_weight("fixed", "default", backend, 0).unwrap();
+        assert!(response.contains("\"weight\":0"), "unexpected response: {}", response);
+
+        for i in 0..50 {
+            let _: () = conn.set(format!("weighted-{}", i), i).unwrap();
+        }
+
+        let r1_keys: isize = redis_cmd("DBSIZE").query(&r1conn).unwrap();
+        let r2_keys: isize = redis_cmd("DBSIZE").query(&r2conn).unwrap();
+        assert_eq!(r1_keys, 0);
+        assert_eq!(r2_keys, 50);
+
+        // Now give it a much bigger share than the second one, and it should take most of them.
+        let response = sd.set_backend_weight("fixed", "default", backend, 9).unwrap();
+        assert!(response.contains("\"weight\":9"), "unexpected response: {}", response);
+
+        for i in 50..150 {
+            let _: () = conn.set(format!("weighted-{}", i), i).unwrap();
+        }
+
+        let r1_keys: isize = redis_cmd("DBSIZE").query(&r1conn).unwrap();
+        let r2_keys: isize = redis_cmd("DBSIZE").query(&r2conn).unwrap();
+        assert!(r1_keys > 50, "expected most keys on the reweighted backend, got {}", r1_keys);
+        assert_eq!(r1_keys + r2_keys, 150);
+
+        // Weights are bounded.
+        let response = sd.set_backend_weight("fixed", "default", backend, 1000).unwrap();
+        assert!(!response.contains("\"weight\""), "unexpected response: {}", response);
+    }
+
+    #[test]
+    fn test_drain_backend() {
+        let (sd, rd1, rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
+        let r1conn = r1client.get_connection().unwrap();
+        let r2client = RedisClient::open(rd2.get_conn_str()).unwrap();
+        let r2conn = r2client.get_connection().unwrap();
+
+        // Send some traffic to both backends before we start draining one of them.
+        for i in 0..20 {
+            let _: () = conn.set(format!("draining-{}", i), i).unwrap();
+        }
+        let _: () = redis_cmd("FLUSHALL").query(&r1conn).unwrap();
+        let _: () = redis_cmd("FLUSHALL").query(&r2conn).unwrap();
+
+        let backend = rd1.get_conn_str().trim_left_matches("redis://");
+        let response = sd.drain_backend("fixed", "default", backend, 500).unwrap();
+        assert!(response.contains("\"phase\":\"draining\""), "unexpected response: {}", response);
+
+        // Everything lands on the survivor while the drain is underway.
+        for i in 0..50 {
+            let _: () = conn.set(format!("draining-{}", i), i).unwrap();
+        }
+
+        let r1_keys: isize = redis_cmd("DBSIZE").query(&r1conn).unwrap();
+        let r2_keys: isize = redis_cmd("DBSIZE").query(&r2conn).unwrap();
+        assert_eq!(r1_keys, 0);
+        assert_eq!(r2_keys, 50);
+
+        // Asking again doesn't start the drain over, and once the backend has been quiet for long
+        // enough, we're told it's safe to remove.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let response = sd.drain_backend("fixed", "default", backend, 500).unwrap();
+            if response.contains("\"safe_to_remove\":true") {
+                assert!(response.contains("\"phase\":\"drained\""), "unexpected response: {}", response);
+                break;
+            }
+
+            assert!(Instant::now() < deadline, "backend never drained: {}", response);
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let stats = sd.get_stats().unwrap();
+        assert!(stats.contains("drained"), "drain missing from stats: {}", stats);
+
+        // Both ends of the drain show up as lifecycle events.
+        let events = sd.get_events(0).unwrap();
+        assert!(events.contains("\"drain_started\""), "drain missing from events: {}", events);
+        assert!(events.contains("\"drain_completed\""), "drain missing from events: {}", events);
+
+        // Backends that aren't in the pool can't be drained.
+        let response = sd.drain_backend("fixed", "default", "127.0.0.1:1", 500).unwrap();
+        assert!(response.contains(" 404 "), "unexpected response: {}", response);
+    }
+
+    #[test]
+    fn test_restore_backend() {
+        let (sd, rd1, rd2) = get_redis_daemons();
+
+        let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
+        let r1conn = r1client.get_connection().unwrap();
+        let r2client = RedisClient::open(rd2.get_conn_str()).unwrap();
+        let r2conn = r2client.get_connection().unwrap();
+
+        let backend = rd1.get_conn_str().trim_left_matches("redis://");
+        let response = sd.drain_backend("fixed", "default", backend, 500).unwrap();
+        assert!(response.contains("\"phase\":\"draining\""), "unexpected response: {}", response);
+
+        // Drains outlast a reload when we ask for them to.  We only connect once the reload is
+        // done, so that we're talking to the new version of the listener.
+        let response = sd.reload_preserving_drains().unwrap();
+        assert!(response.contains("\"completed\":true"), "unexpected response: {}", response);
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        for i in 0..50 {
+            let _: () = conn.set(format!("restoring-{}", i), i).unwrap();
+        }
+
+        let r1_keys: isize = redis_cmd("DBSIZE").query(&r1conn).unwrap();
+        assert_eq!(r1_keys, 0);
+
+        // Once it's restored, the backend gets its share of keys again.
+        let response = sd.restore_backend("fixed", "default", backend).unwrap();
+        assert!(response.contains("\"weight\":1"), "unexpected response: {}", response);
+        let _: () = redis_cmd("FLUSHALL").query(&r2conn).unwrap();
+        for i in 0..50 {
+            let _: () = conn.set(format!("restoring-{}", i), i).unwrap();
+        }
+
+        let r1_keys: isize = redis_cmd("DBSIZE").query(&r1conn).unwrap();
+        let r2_keys: isize = redis_cmd("DBSIZE").query(&r2conn).unwrap();
+        assert!(r1_keys > 0);
+        assert_eq!(r1_keys + r2_keys, 50);
+
+        let events = sd.get_events(0).unwrap();
+        assert!(events.contains("backend was restored"), "restore missing from events: {}", events);
+
+        // Backends that aren't in the pool can't be restored.
+        let response = sd.restore_backend("fixed", "default", "127.0.0.1:1").unwrap();
+        assert!(response.contains(" 404 "), "unexpected response: {}", response);
+    }
+
+    #[test]
+    fn test_strict_startup_with_backends() {
+        let (mut sd, _rd) = get_strict_redis_daemons(true);
+
+        // Our only backend is up, so we should start listening as normal.
+        sd.wait_until_listening();
+        assert!(sd.wait_for_exit(Duration::from_millis(100)).is_none());
+
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("strict_key", 42).unwrap();
+        let value: isize = conn.get("strict_key").unwrap();
+        assert_eq!(value, 42);
+    }
+
+    #[test]
+    fn test_strict_startup_without_backends() {
+        let (mut sd, _rd) = get_strict_redis_daemons(false);
+
+        // Our only backend is down, so we should give up after the startup timeout and exit
+        // without ever binding the listener.
+        let status = sd.wait_for_exit(Duration::from_secs(10)).expect("synchrotron should have exited");
+        assert!(!status.success());
+        assert!(!sd.is_listening());
+    }
+
+    #[test]
+    fn test_health_check_ejects_and_restores() {
+        let (sd, _rd1, rd2) = get_health_check_daemons();
+
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        // Take our second backend down.  Once the health checks notice, everything goes to the
+        // first backend, and nothing fails for having been sent to the one that's gone.
+        let redis2_port = rd2.get_port();
+        drop(rd2);
+        assert!(sd.wait_for_output("ejecting backend", Duration::from_secs(10)));
+
+        for i in 0..20 {
+            let _: () = conn.set(format!("health_check_{}", i), i).unwrap();
+        }
+
+        // Bring it back, and once it's been restored, it gets its share of keys again.
+        let rd2 = RedisRunner::new(redis2_port).unwrap();
+        assert!(sd.wait_for_output("restoring backend", Duration::from_secs(10)));
+
+        for i in 20..40 {
+            let _: () = conn.set(format!("health_check_{}", i), i).unwrap();
+        }
+
+        let r2client = RedisClient::open(rd2.get_conn_str()).unwrap();
+        let r2conn = r2client.get_connection().unwrap();
+        let r2_keys: isize = redis_cmd("DBSIZE").query(&r2conn).unwrap();
+        assert!(r2_keys > 0, "expected the restored backend to get some keys");
+    }
+
+    // Exit codes for listeners that fail to start, from sysexits.h.
+    const EXIT_BIND_FAILED: i32 = 75;
+    const EXIT_INVALID_CONFIG: i32 = 78;
+
+    #[test]
+    fn test_startup_bind_conflict() {
+        // Something else already has our address, which is worth trying again later.
+        let (mut sd, _conflicts) = get_startup_daemons(&[(true, "modulo")]);
+
+        let status = sd.wait_for_exit(Duration::from_secs(10)).expect("synchrotron should have exited");
+        assert_eq!(status.code(), Some(EXIT_BIND_FAILED));
+    }
+
+    #[test]
+    fn test_startup_bad_distributor() {
+        // A distributor that doesn't exist won't start to exist if we try again.
+        let (mut sd, _conflicts) = get_startup_daemons(&[(false, "bogus")]);
+
+        let status = sd.wait_for_exit(Duration::from_secs(10)).expect("synchrotron should have exited");
+        assert_eq!(status.code(), Some(EXIT_INVALID_CONFIG));
+        assert!(!sd.is_listening());
+
+        // It should say what was wrong with the configuration, rather than falling over.
+        let output = sd.get_output();
+        assert!(output.contains("unknown distributor type bogus"));
+        assert!(!output.contains("panicked"));
+    }
+
+    #[test]
+    fn test_startup_reports_worst_failure() {
+        // When one listener can't bind and another is misconfigured, the misconfiguration is what
+        // needs someone to look at it, no matter which listener is built first.
+        let (mut sd, _conflicts) = get_startup_daemons(&[(true, "modulo"), (false, "bogus")]);
+
+        let status = sd.wait_for_exit(Duration::from_secs(10)).expect("synchrotron should have exited");
+        assert_eq!(status.code(), Some(EXIT_INVALID_CONFIG));
+    }
+
+    fn get_health(stats_port: u16) -> Option<String> {
+        let mut conn = TcpStream::connect(("127.0.0.1", stats_port)).ok()?;
+        conn.write_all(b"GET /health HTTP/1.0\r\n\r\n").ok()?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response).ok()?;
+        Some(response)
+    }
+
+    #[test]
+    fn test_connection_setup_latencies() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Take our time at every step of setting up the connection, so that each step shows up.
+        let mut conn = TcpStream::connect(sd.get_fixed_conn_str().trim_left_matches("redis://")).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        conn.write_all(b"*2\r\n$3\r\nGET\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        conn.write_all(b"$9\r\nsetup_key\r\n").unwrap();
+
+        let mut response = [0; 5];
+        conn.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"$-1\r\n");
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while sd.get_stat("listeners.fixed.client.first_response_latency_count").unwrap_or(0) == 0 {
+            assert!(Instant::now() < deadline, "connection setup never showed up in stats");
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        // Only our connection ever sent anything, so each step was timed just the once.
+        let first_byte_us = sd.get_stat("listeners.fixed.client.first_byte_latency_sum_us").unwrap();
+        let first_command_us = sd.get_stat("listeners.fixed.client.first_command_latency_sum_us").unwrap();
+        let first_response_us = sd.get_stat("listeners.fixed.client.first_response_latency_sum_us").unwrap();
+        assert_eq!(sd.get_stat("listeners.fixed.client.first_byte_latency_count"), Some(1));
+        assert_eq!(sd.get_stat("listeners.fixed.client.first_command_latency_count"), Some(1));
+        assert!(first_byte_us >= 100_000 && first_byte_us < 5_000_000, "first byte took {}us", first_byte_us);
+        assert!(first_command_us >= 100_000 && first_command_us < 5_000_000, "first command took {}us", first_command_us);
+        assert!(first_response_us > 0 && first_response_us < 5_000_000, "first response took {}us", first_response_us);
+    }
+
+    #[test]
+    fn test_prometheus_metrics() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        // Keep traffic flowing while we scrape, and wait for it to show up.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let metrics = loop {
+            for i in 0..20 {
+                let _: () = conn.set(format!("scraped-{}", i), i).unwrap();
+            }
+
+            let response = sd.get_metrics().unwrap();
+            if response.contains("synchrotron_client_messages_received_total{listener=\"fixed\"}") {
+                break response;
+            }
+
+            assert!(Instant::now() < deadline, "traffic never showed up: {}", response);
+            thread::sleep(Duration::from_millis(100));
+        };
+
+        assert!(metrics.contains("text/plain; version=0.0.4"), "unexpected response: {}", metrics);
+        assert!(metrics.contains("# TYPE synchrotron_client_batch_latency_seconds histogram"));
+        assert!(metrics.contains("synchrotron_client_batch_latency_seconds_bucket{listener=\"fixed\",le=\"+Inf\"}"));
+
+        // Every sample should be a metric name, maybe some labels, and a number.
+        let body = &metrics[metrics.find("\r\n\r\n").unwrap() + 4..];
+        for line in body.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let (series, value) = line.split_at(line.rfind(' ').expect("sample without a value"));
+            assert!(value.trim().parse::<f64>().is_ok(), "bad sample value: {}", line);
+
+            let name = series.split('{').next().unwrap();
+            assert!(name.starts_with("synchrotron_"), "bad sample name: {}", line);
+            assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "bad sample name: {}", line);
+            assert!(!series.contains('{') || series.ends_with('}'), "bad sample labels: {}", line);
+        }
+    }
+
+    #[test]
+    fn test_stats_overview() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("overview", 1).unwrap();
+
+        // Message counts come from the stats, which lag a little behind the traffic itself.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let overview = loop {
+            let response = sd.get_overview().unwrap();
+            if response.contains("\"messages_sent\":1") {
+                break response;
+            }
+
+            assert!(Instant::now() < deadline, "traffic never showed up: {}", response);
+            thread::sleep(Duration::from_millis(100));
+        };
+
+        assert!(overview.contains("\"version\":"), "unexpected response: {}", overview);
+        assert!(overview.contains("\"uptime_secs\":"), "unexpected response: {}", overview);
+        assert!(overview.contains("\"fixed\":{\"clients_connected\":1"), "unexpected response: {}", overview);
+        assert!(overview.contains("\"p999_us\":"), "unexpected response: {}", overview);
+        assert!(overview.contains("\"state\":\"healthy\""), "unexpected response: {}", overview);
+
+        // Every listener has a default pool, while only one has a shadow pool.
+        let pool = sd.get_pool_overview("shadow").unwrap();
+        assert!(pool.contains(" 200 "), "unexpected response: {}", pool);
+        assert!(pool.contains("{\"shadow\":{\"backends\":["), "unexpected response: {}", pool);
+        let pool = sd.get_pool_overview("default").unwrap();
+        assert!(pool.contains("\"fixed\":{\"backends\":["), "unexpected response: {}", pool);
+        assert!(pool.contains("\"ttl\":{\"backends\":["), "unexpected response: {}", pool);
+
+        let missing = sd.get_pool_overview("missing").unwrap();
+        assert!(missing.contains(" 404 "), "unexpected response: {}", missing);
+    }
+
+    #[test]
+    fn test_stats_delta() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        // Snapshots are taken every second, so it takes a couple of them before there's a window
+        // to work a delta out over.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let delta = loop {
+            for i in 0..20 {
+                let _: () = conn.set(format!("delta-{}", i), i).unwrap();
+            }
+
+            let response = sd.get_stats_delta("1s").unwrap();
+            if response.contains("\"listeners.fixed.client.messages_received\"") {
+                break response;
+            }
+
+            assert!(Instant::now() < deadline, "traffic never showed up: {}", response);
+            thread::sleep(Duration::from_millis(100));
+        };
+
+        assert!(delta.contains("\"window_ms\":1000"), "unexpected response: {}", delta);
+        assert!(delta.contains("\"elapsed_ms\":"), "unexpected response: {}", delta);
+        assert!(delta.contains("listeners.fixed.client.batch_latency_count"), "unexpected response: {}", delta);
+
+        // Windows have to fit in the history we keep, and can't be shorter than a snapshot apart.
+        let too_long = sd.get_stats_delta("3600s").unwrap();
+        assert!(!too_long.contains("window_ms"), "unexpected response: {}", too_long);
+        let too_short = sd.get_stats_delta("10ms").unwrap();
+        assert!(!too_short.contains("window_ms"), "unexpected response: {}", too_short);
+    }
+
+    #[test]
+    fn test_stats_bind_retry() {
+        let (sd, _rd, stats_port, conflict) = get_stats_daemons(true, true);
+
+        // Someone else has our stats port, but that shouldn't stop us from serving traffic.
+        sd.wait_until_listening();
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("stats_key", 42).unwrap();
+        let value: isize = conn.get("stats_key").unwrap();
+        assert_eq!(value, 42);
+        assert!(sd.wait_for_output("failed to bind stats server", Duration::from_secs(5)));
+
+        // Once the port is given back, we should pick it up on our own.
+        drop(conflict);
+        assert!(sd.wait_for_output("serving metric data on", Duration::from_secs(10)));
+        let health = get_health(stats_port).expect("stats server should be reachable");
+        assert!(health.contains("\"healthy\""), "unexpected response: {}", health);
+    }
+
+    #[test]
+    fn test_stats_disabled() {
+        let (sd, _rd, stats_port, _conflict) = get_stats_daemons(false, false);
+
+        // With an empty stats address, we run just the same, minus the stats server, and say so.
+        sd.wait_until_listening();
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("stats_key", 42).unwrap();
+        assert!(sd.wait_for_output("health endpoints are disabled", Duration::from_secs(5)));
+        assert!(get_health(stats_port).is_none());
+    }
+
+    #[test]
+    fn test_backend_cooloff() {
+        let (sd, rd1, rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        // Set values directly on both Redis servers so we can distinguish between nodes when
+        // we eventually go through Synchrotron.
+        let r1client = RedisClient::open(rd1.get_conn_str()).unwrap();
+        let r1conn = r1client.get_connection().unwrap();
+
+        let _: () = r1conn.set("two", 1).unwrap();
+        let value1: isize = r1conn.get("two").unwrap();
+        assert_eq!(value1, 1);
+
+        let r2client = RedisClient::open(rd2.get_conn_str()).unwrap();
+        let r2conn = r2client.get_connection().unwrap();
+
+        let _: () = r2conn.set("two", 2).unwrap();
+        let value2: isize = r2conn.get("two").unwrap();
+        assert_eq!(value2, 2);
+
+        // Now grab the value through Synchrotron so we have our baseline value.
+        let baseline: isize = conn.get("two").unwrap();
+
+        // Now kill whichever server was the one that the key routed to.
+        if baseline == 1 {
+            drop(rd1);
+        } else {
+            drop(rd2);
+        }
+
+        // Wait for a hot second just to make sure things are dead.  250ms should do it.
+        thread::sleep(Duration::from_millis(250));
+
+        // Now, try to ask Synchrotron for the value, five times.  Should be all errors.
+        for _ in 0..5 {
+            let iclient = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+            let iconn = iclient.get_connection().unwrap();
+            let result: RedisResult<isize> = iconn.get("two");
+            match result {
+                Ok(_) => panic!("should have been error after killing redis node"),
+                Err(inner_err) => assert_eq!(inner_err.kind(), RedisErrorKind::ResponseError),
+            }
+        }
+
+        // Next one should work as it switches over to the new server.
+        let failover: isize = conn.get("two").unwrap();
+        assert!(failover != baseline);
+
+        // Now wait for the cooloff to expire.
+        thread::sleep(Duration::from_millis(2500));
+
+        // And make sure we get errors again.  We can't easily restart the killed Redis
+        // daemon again but we'll know the cooloff happened if we get an error because
+        // it means it tried the downed server again.
+        let result: RedisResult<isize> = conn.get("two");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_shutdown_phases() {
+        let (sd, _rd) = get_strict_redis_daemons(true);
+        sd.wait_until_listening();
+
+        // Keep a few clients busy right up until we're told to shut down, and for as long as
+        // they're allowed to stay connected afterwards.
+        let done = Arc::new(AtomicBool::new(false));
+        let workers = (0..4)
+            .map(|i| {
+                let done = done.clone();
+                let conn_str = sd.get_conn_str().to_owned();
+                thread::spawn(move || {
+                    let client = RedisClient::open(conn_str.as_str()).unwrap();
+                    let conn = client.get_connection().unwrap();
+                    let key = format!("shutdown_key_{}", i);
+                    while !done.load(Ordering::SeqCst) {
+                        let result: RedisResult<()> = conn.set(key.as_str(), 42);
+                        if result.is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        thread::sleep(Duration::from_millis(250));
+        sd.interrupt();
+        let finished = sd.wait_for_output("shutdown complete", Duration::from_secs(30));
+        done.store(true, Ordering::SeqCst);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert!(finished, "shutdown never completed");
+
+        // Every phase should have run, and in order.
+        let output = sd.get_output();
+        let phases = ["stop_accepting", "drain_clients", "stop_routers", "drain_backends", "stop_pools", "flush_metrics", "stop_admin"];
+        let mut last = 0;
+        for phase in phases.iter() {
+            let started = output
+                .find(&format!("phase '{}' started", phase))
+                .unwrap_or_else(|| panic!("phase '{}' never started", phase));
+            assert!(started > last, "phase '{}' started out of order", phase);
+            last = started;
+        }
+
+        // Our pools should have stuck around for as long as our clients did.
+        assert!(!output.contains("[client] error from"));
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        // A client that never leaves holds up shutdown for as long as we're told to wait, but no
+        // longer.
+        let (sd, _rd) = get_shutdown_timeout_daemons(500);
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("shutdown_timeout", 42).unwrap();
+
+        sd.interrupt();
+        assert!(sd.wait_for_output("shutdown complete", Duration::from_secs(10)), "shutdown never completed");
+        let output = sd.get_output();
+        assert!(output.contains("gave up on clients of listener 'shutdown_timeout' draining"));
+        assert!(output.contains("(shutdown), with 1 client(s) still connected"));
+
+        // Without any clients, there's nothing to wait for, even when we'd wait forever.
+        let (sd, _rd) = get_shutdown_timeout_daemons(0);
+        sd.interrupt();
+        assert!(sd.wait_for_output("shutdown complete", Duration::from_secs(10)), "shutdown never completed");
+        assert!(sd.get_output().contains("clients of listener 'shutdown_timeout' drained"));
+    }
+
+    #[test]
+    fn test_upgrade_hands_off_listeners() {
+        let (mut sd, _rd) = get_strict_redis_daemons(true);
+        sd.wait_until_listening();
+
+        // Keep a workload going the whole way through the upgrade.  Every command gets a new
+        // connection, so that any moment without something accepting on our address shows up.
+        let done = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let done = done.clone();
+            let conn_str = sd.get_conn_str().to_owned();
+            thread::spawn(move || {
+                let mut sent = 0;
+                let mut errors = Vec::new();
+                while !done.load(Ordering::SeqCst) {
+                    let result: RedisResult<()> = RedisClient::open(conn_str.as_str())
+                        .and_then(|client| client.get_connection())
+                        .and_then(|conn| conn.set(format!("upgrade_key_{}", sent), sent));
+                    if let Err(e) = result {
+                        errors.push(e.to_string());
+                    }
+                    sent += 1;
+                }
+                (sent, errors)
+            })
+        };
+
+        thread::sleep(Duration::from_millis(250));
+        sd.upgrade();
+
+        // Once the new process is up, the old one drains and exits like it was interrupted.
+        let status = sd.wait_for_exit(Duration::from_secs(30));
+        let successor = sd.get_successor_pid();
+
+        // Give the new process some of the workload all to itself before we call it.
+        thread::sleep(Duration::from_millis(250));
+        done.store(true, Ordering::SeqCst);
+        let (sent, errors) = worker.join().unwrap();
+        let stopped_successor = successor.map(|pid| {
+            Command::new("kill")
+                .arg("-INT")
+                .arg(pid.to_string())
+                .status()
+                .unwrap()
+                .success()
+        });
+
+        assert!(status.expect("old process never exited").success());
+        assert_eq!(stopped_successor, Some(true), "no successor to stop: {}", sd.get_output());
+        assert!(sent > 0);
+        assert!(errors.is_empty(), "{} of {} commands failed: {:?}", errors.len(), sent, errors);
+
+        let output = sd.get_output();
+        assert!(output.contains("inherited 1 listening socket(s)"), "nothing was inherited: {}", output);
+    }
+
+    #[test]
+    fn test_double_interrupt_forces_exit() {
+        let (mut sd, _rd) = get_strict_redis_daemons(true);
+        sd.wait_until_listening();
+
+        // Busy clients hold the drain open, so the first interrupt alone won't get us out.
+        let done = Arc::new(AtomicBool::new(false));
+        let workers = (0..4)
+            .map(|i| {
+                let done = done.clone();
+                let conn_str = sd.get_conn_str().to_owned();
+                thread::spawn(move || {
+                    let client = RedisClient::open(conn_str.as_str()).unwrap();
+                    let conn = client.get_connection().unwrap();
+                    let key = format!("double_interrupt_key_{}", i);
+                    while !done.load(Ordering::SeqCst) {
+                        let result: RedisResult<()> = conn.set(key.as_str(), 42);
+                        if result.is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        thread::sleep(Duration::from_millis(250));
+        sd.interrupt();
+        thread::sleep(Duration::from_millis(100));
+        sd.interrupt();
+
+        // Well inside the drain timeout, so only the forced exit could have gotten us here.
+        let status = sd.wait_for_exit(Duration::from_secs(5));
+        done.store(true, Ordering::SeqCst);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let status = status.expect("synchrotron should have been forced to exit");
+        assert_eq!(status.code(), Some(130));
+
+        // We should have said why we left, and where shutdown had gotten to, exactly once.
+        let output = sd.get_output();
+        assert!(output.contains("interrupted again while shutting down, forcing exit"));
+        assert_eq!(output.matches("[shutdown] forced exit in phase").count(), 1);
+        assert_eq!(output.matches("phase 'stop_accepting' started").count(), 1);
+        assert!(!output.contains("shutdown complete"));
+    }
+
+    fn get_stats(stats_port: u16) -> Option<String> {
+        let mut conn = TcpStream::connect(("127.0.0.1", stats_port)).ok()?;
+        conn.write_all(b"GET /stats HTTP/1.0\r\n\r\n").ok()?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response).ok()?;
+        Some(response)
+    }
+
+    #[test]
+    fn test_tls_listener() {
+        let (sd, _rd, tls_port, stats_port) = get_tls_daemons();
+
+        // Plaintext listeners carry on as if nothing's changed.
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("tls_key", "plain").unwrap();
+
+        // Speaking TLS gets us to the very same data.
+        let mut tls_client = Command::new("openssl")
+            .args(&["s_client", "-quiet", "-connect", &format!("127.0.0.1:{}", tls_port)])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        tls_client.stdin.as_mut().unwrap().write_all(b"*2\r\n$3\r\nGET\r\n$7\r\ntls_key\r\n").unwrap();
+        let mut lines = BufReader::new(tls_client.stdout.take().unwrap()).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "$5\r");
+        assert_eq!(lines.next().unwrap().unwrap(), "plain\r");
+        tls_client.kill().unwrap();
+
+        // Clients that don't speak TLS get nowhere.
+        let mut plain = TcpStream::connect(("127.0.0.1", tls_port)).unwrap();
+        plain.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        plain.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        let mut response = Vec::new();
+        let _ = plain.read_to_end(&mut response);
+        assert!(!String::from_utf8_lossy(&response).contains("PONG"));
+
+        // Clients that never finish their handshake are dropped once their time is up.
+        let mut silent = TcpStream::connect(("127.0.0.1", tls_port)).unwrap();
+        silent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let started = Instant::now();
+        let mut response = Vec::new();
+        assert_eq!(silent.read_to_end(&mut response).unwrap(), 0);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // Both failures are counted, and the handshake that worked was timed.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut stats = String::new();
+        while Instant::now() < deadline {
+            stats = get_stats(stats_port).unwrap_or_default();
+            if stats.contains("\"listeners.secure.tls_handshake_failures\":2") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(stats.contains("\"listeners.secure.tls_handshake_failures\":2"), "unexpected stats: {}", stats);
+        assert!(stats.contains("\"listeners.secure.client.tls_handshake_latency_count\":1"));
+        assert!(!stats.contains("listeners.plain.client.tls_handshake"));
+
+        // Failed handshakes are nothing out of the ordinary, so they aren't logged as such.
+        assert!(!sd.get_output().contains("TLS handshake"));
+    }
+
+    #[test]
+    fn test_listener_auth() {
+        let (_sd, _rd, auth_port) = get_auth_daemons("hunter2");
+
+        let request = |stream: &mut TcpStream, command: &[u8]| {
+            stream.write_all(command).unwrap();
+            let mut response = [0; 64];
+            let n = stream.read(&mut response).unwrap();
+            String::from_utf8_lossy(&response[..n]).into_owned()
+        };
+
+        // Nothing gets through without the password, and the wrong one doesn't help.
+        let mut stream = TcpStream::connect(("127.0.0.1", auth_port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let get = b"*2\r\n$3\r\nGET\r\n$8\r\nauth_key\r\n";
+        assert_eq!(request(&mut stream, get), "-NOAUTH Authentication required.\r\n");
+        assert_eq!(request(&mut stream, b"*2\r\n$4\r\nAUTH\r\n$7\r\nhunter3\r\n"), "-ERR invalid password\r\n");
+        assert_eq!(request(&mut stream, get), "-NOAUTH Authentication required.\r\n");
+
+        // The right one lets the client carry on as usual.
+        assert_eq!(request(&mut stream, b"*2\r\n$4\r\nAUTH\r\n$7\r\nhunter2\r\n"), "+OK\r\n");
+        assert_eq!(request(&mut stream, get), "$-1\r\n");
+
+        // Clients that put the password in their URL authenticate as soon as they connect.
+        let client = RedisClient::open(format!("redis://:hunter2@127.0.0.1:{}", auth_port).as_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("auth_key", "protected").unwrap();
+        let value: String = conn.get("auth_key").unwrap();
+        assert_eq!(value, "protected");
+
+        let client = RedisClient::open(format!("redis://:hunter3@127.0.0.1:{}", auth_port).as_str()).unwrap();
+        assert!(client.get_connection().is_err());
+
+        // Being authenticated doesn't carry over to a new connection.
+        let mut stream = TcpStream::connect(("127.0.0.1", auth_port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(request(&mut stream, get), "-NOAUTH Authentication required.\r\n");
+    }
+    #[test]
+    fn test_backend_auth() {
+        let (sd, rd, listen_port, wrong_port) = get_backend_auth_daemons("hunter2", 2);
+
+        // Connections to the backend authenticate and select their database before any requests
+        // are sent over them.
+        let client = RedisClient::open(format!("redis://127.0.0.1:{}", listen_port).as_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("backend_auth_key", "selected").unwrap();
+        let value: String = conn.get("backend_auth_key").unwrap();
+        assert_eq!(value, "selected");
+
+        let backend_port = rd.get_port();
+        let db2 = RedisClient::open(format!("redis://:hunter2@127.0.0.1:{}/2", backend_port).as_str()).unwrap();
+        let value: String = db2.get_connection().unwrap().get("backend_auth_key").unwrap();
+        assert_eq!(value, "selected");
+
+        let db0 = RedisClient::open(format!("redis://:hunter2@127.0.0.1:{}/0", backend_port).as_str()).unwrap();
+        let value: Option<String> = db0.get_connection().unwrap().get("backend_auth_key").unwrap();
+        assert_eq!(value, None);
+
+        // A backend that won't take our password never gets any requests.
+        let client = RedisClient::open(format!("redis://127.0.0.1:{}", wrong_port).as_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let result: RedisResult<Option<String>> = conn.get("backend_auth_key");
+        assert!(result.is_err());
+
+        // Whatever went wrong, we never said what either password was.
+        let output = sd.get_output();
+        assert!(!output.contains("hunter2"));
+        assert!(!output.contains("swordfish"));
+    }
+
+    #[test]
+    fn test_backend_timeout() {
+        let (sd, _rd1, rd2) = get_timeout_daemons();
+
+        let mut conn = TcpStream::connect(sd.get_conn_str().trim_left_matches("redis://")).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // One command with keys on both backends, and then each of those keys on its own.
+        let keys = (0..20).map(|i| format!("timeout:{}", i)).collect::<Vec<_>>();
+        let mut script = format!("*{}\r\n$4\r\nmget\r\n", keys.len() + 1);
+        for key in &keys {
+            script.push_str(&format!("${}\r\n{}\r\n", key.len(), key));
+        }
+        for key in &keys {
+            script.push_str(&format!("*2\r\n$3\r\nget\r\n${}\r\n{}\r\n", key.len(), key));
+        }
+
+        // Once the second backend stops answering, whatever was sent to it is answered with a
+        // timeout, and everything behind it still gets through.
+        rd2.pause();
+        conn.write_all(script.as_bytes()).unwrap();
+        let mut reader = BufReader::new(conn);
+        let replies = (0..keys.len() + 1)
+            .map(|_| {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                line
+            })
+            .collect::<Vec<_>>();
+        rd2.resume();
+
+        // The command that spanned both backends fails as a whole, but only that command does.
+        assert_eq!(replies[0], "-ERR proxy timeout\r\n");
+        let timeouts = replies[1..].iter().filter(|reply| *reply == "-ERR proxy timeout\r\n").count();
+        let misses = replies[1..].iter().filter(|reply| *reply == "$-1\r\n").count();
+        assert_eq!(timeouts + misses, keys.len(), "replies: {:?}", replies);
+        assert!(timeouts > 0 && misses > 0, "replies: {:?}", replies);
+    }
+    #[test]
+    fn test_failover_routes_around_stalled_pool() {
+        let (sd, dd, fd) = get_failover_daemons();
+
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let fclient = RedisClient::open(fd.get_conn_str()).unwrap();
+        let fconn = fclient.get_connection().unwrap();
+
+        // While the default pool is healthy, nothing reaches the failover pool.
+        let _: () = conn.set("failover", 1).unwrap();
+        let value: isize = conn.get("failover").unwrap();
+        assert_eq!(value, 1);
+        let missing: Option<isize> = fconn.get("failover").unwrap();
+        assert_eq!(missing, None);
+
+        // Once the default pool stops answering, requests are retried against the failover pool,
+        // writes included, since this listener allows them to be.
+        dd.pause();
+        let _: () = conn.set("failover", 2).unwrap();
+        let value: isize = conn.get("failover").unwrap();
+        dd.resume();
+
+        assert_eq!(value, 2);
+        let failed_over: isize = fconn.get("failover").unwrap();
+        assert_eq!(failed_over, 2);
+    }
+    #[test]
+    fn test_split_percentage_routes_by_key() {
+        let (sd, od, nd) = get_split_percentage_daemons(50);
+
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let oclient = RedisClient::open(od.get_conn_str()).unwrap();
+        let oconn = oclient.get_connection().unwrap();
+        let nclient = RedisClient::open(nd.get_conn_str()).unwrap();
+        let nconn = nclient.get_connection().unwrap();
+
+        // Setting many keys at once still sends each key to its own side.
+        let keys = (0..50).map(|i| format!("percentage:{}", i)).collect::<Vec<_>>();
+        let pairs = keys.iter().map(|key| (key.as_str(), 1)).collect::<Vec<_>>();
+        let _: () = conn.set_multiple(&pairs).unwrap();
+
+        let mut on_old = 0;
+        let mut on_new = 0;
+        for key in &keys {
+            let old: Option<isize> = oconn.get(key.as_str()).unwrap();
+            let new: Option<isize> = nconn.get(key.as_str()).unwrap();
+            assert!(old.is_some() != new.is_some(), "key {} should be on exactly one side", key);
+            if old.is_some() {
+                on_old += 1;
+            } else {
+                on_new += 1;
+            }
+        }
+        assert!(on_old > 0 && on_new > 0);
+
+        // Reading them all back at once gets each one from the side it was written to.
+        let values: Vec<isize> = conn.get(&keys).unwrap();
+        assert_eq!(values, vec![1; keys.len()]);
+    }
+
+    #[test]
+    fn test_connect_timeout_backs_off() {
+        let (sd, _blackhole, stats_port) = get_blackhole_daemons();
+
+        let client = RedisClient::open(sd.get_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        // A backend that never lets us connect fails requests as soon as connecting times out,
+        // rather than once the requests themselves do.
+        let started = Instant::now();
+        let result: RedisResult<Option<isize>> = conn.get("blackhole");
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?} to fail", started.elapsed());
+
+        let connect_timeouts = "pools.default.backend.connect_timeouts";
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while get_listener_stat(stats_port, "blackhole", connect_timeouts).unwrap_or(0) < 1 {
+            assert!(Instant::now() < deadline, "connect timeout was never counted");
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        // That was enough to cool the backend off, so nothing else tries connecting to it until
+        // the cooloff is over.
+        let connects = get_listener_stat(stats_port, "blackhole", "pools.default.backend.connects");
+        assert!(connects.unwrap_or(0) >= 1);
+        for _ in 0..5 {
+            let result: RedisResult<Option<isize>> = conn.get("blackhole");
+            assert!(result.is_err());
+        }
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(get_listener_stat(stats_port, "blackhole", "pools.default.backend.connects"), connects);
+    }
+
+    fn get_listener_stat(stats_port: u16, listener: &str, name: &str) -> Option<i64> {
+        let mut conn = TcpStream::connect(("127.0.0.1", stats_port)).ok()?;
+        conn.write_all(b"GET /stats HTTP/1.0\r\n\r\n").ok()?;
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response).ok()?;
+        let needle = format!("\"listeners.{}.{}\":", listener, name);
+        let start = response.find(&needle)? + needle.len();
+        response[start..].chars().take_while(|c| c.is_ascii_digit()).collect::<String>().parse().ok()
+    }
+
+    fn get_limited_stat(stats_port: u16, name: &str) -> Option<i64> { get_listener_stat(stats_port, "limited", name) }
+
+    fn wait_for_limited_stat(stats_port: u16, name: &str, value: i64) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if get_limited_stat(stats_port, name) == Some(value) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    fn connect_with_timeout(conn_str: &str) -> TcpStream {
+        let conn = TcpStream::connect(conn_str.trim_left_matches("redis://")).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        conn
+    }
+
+    fn ping(conn: &mut TcpStream) {
+        conn.write_all(b"PING\r\n").unwrap();
+        let mut response = [0; 7];
+        conn.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"+PONG\r\n");
+    }
+
+    #[test]
+    fn test_max_clients_reject() {
+        let (sd, _rd, stats_port) = get_max_clients_daemons(5, "reject");
+
+        // Make sure whoever checked that we were listening is gone, so that every slot is ours.
+        assert!(wait_for_limited_stat(stats_port, "clients", 0));
+        assert!(wait_for_limited_stat(stats_port, "max_clients", 5));
+
+        let mut served = (0..5).map(|_| connect_with_timeout(sd.get_conn_str())).collect::<Vec<_>>();
+        for conn in served.iter_mut() {
+            ping(conn);
+        }
+        assert!(wait_for_limited_stat(stats_port, "clients", 5));
+
+        // Everyone past the limit is told why they're being turned away, and then disconnected.
+        for _ in 0..10 {
+            let mut conn = connect_with_timeout(sd.get_conn_str());
+            let mut response = String::new();
+            conn.read_to_string(&mut response).unwrap();
+            assert_eq!(response, "-ERR max clients reached\r\n");
+        }
+        assert!(wait_for_limited_stat(stats_port, "clients_rejected_max_clients", 10));
+
+        // The clients we took on are none the worse for it.
+        for conn in served.iter_mut() {
+            ping(conn);
+        }
+
+        // Once someone leaves, there's room for someone else.
+        served.pop();
+        assert!(wait_for_limited_stat(stats_port, "clients", 4));
+        let mut conn = connect_with_timeout(sd.get_conn_str());
+        ping(&mut conn);
+    }
+
+    #[test]
+    fn test_max_clients_pause() {
+        let (sd, _rd, stats_port) = get_max_clients_daemons(5, "pause");
+        assert!(wait_for_limited_stat(stats_port, "clients", 0));
+
+        let mut served = (0..5).map(|_| connect_with_timeout(sd.get_conn_str())).collect::<Vec<_>>();
+        for conn in served.iter_mut() {
+            ping(conn);
+        }
+
+        // Everyone past the limit can connect, but they're left waiting to be accepted, so nothing
+        // they send gets answered.
+        let mut waiting = (0..10).map(|_| connect_with_timeout(sd.get_conn_str())).collect::<Vec<_>>();
+        for conn in waiting.iter_mut() {
+            conn.write_all(b"PING\r\n").unwrap();
+        }
+        let mut buf = [0; 7];
+        waiting[0].set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        assert!(waiting[0].read(&mut buf).is_err(), "client past the limit should not have been served");
+        waiting[0].set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert!(wait_for_limited_stat(stats_port, "clients", 5));
+        assert!(wait_for_limited_stat(stats_port, "accept_paused", 1));
+
+        // As clients leave, those waiting are served in turn, without ever going over the limit.
+        for batch in 0..2 {
+            served.clear();
+            assert!(wait_for_limited_stat(stats_port, "clients", 5));
+            for mut conn in waiting.drain(..5) {
+                conn.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf, b"+PONG\r\n", "waiting client in batch {} was not served", batch);
+                served.push(conn);
+            }
+        }
+        assert!(waiting.is_empty());
+        assert_eq!(get_limited_stat(stats_port, "clients"), Some(5));
+    }
+
+    #[test]
+    fn test_client_idle_timeout() {
+        let (sd, _rd) = get_idle_timeout_daemons(300);
+
+        // A client that keeps talking to us stays connected well past the timeout.
+        let mut busy = connect_with_timeout(sd.get_conn_str());
+        for _ in 0..10 {
+            ping(&mut busy);
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        // One that goes quiet is disconnected, but still gets the response to what it sent last.
+        let mut quiet = connect_with_timeout(sd.get_conn_str());
+        let started = Instant::now();
+        quiet.write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nidle\r\n$1\r\n1\r\n").unwrap();
+        let mut response = String::new();
+        quiet.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "+OK\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        ping(&mut busy);
     }
 }